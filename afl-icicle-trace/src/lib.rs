@@ -297,7 +297,7 @@ fn run_afl<T: FuzzTarget>(
         // Run the fuzz case
         let exit = match std::panic::catch_unwind::<_, anyhow::Result<_>>(
             std::panic::AssertUnwindSafe(|| {
-                vm.restore(&snapshot);
+                vm.restore(&snapshot)?;
                 target.set_input(&mut vm, input)?;
                 target.run(&mut vm)
             }),
@@ -1,6 +1,6 @@
 use std::cell::UnsafeCell;
 
-use icicle_mem::{perm, tlb::TranslationCache, MemResult, Mmu};
+use icicle_mem::{perm, tlb::TLBEntry, MemResult, Mmu};
 use pcode::PcodeDisplay;
 
 use crate::{
@@ -211,7 +211,7 @@ impl Arch {
 
 #[repr(C)]
 pub struct JitContext {
-    pub tlb_ptr: *mut TranslationCache,
+    pub tlb_ptr: *mut TLBEntry,
     pub tracer_mem: [*mut u8; trace::MAX_TRACER_MEM],
     pub hooks: [trace::HookData; trace::MAX_HOOKS],
 }
@@ -580,7 +580,7 @@ impl Cpu {
     /// This must be called before entering the JIT.
     pub fn update_jit_context(&mut self) {
         // @todo: optimize: this doesn't need to be done every time we enter the JIT.
-        self.jit_ctx.tlb_ptr = self.mem.tlb.as_mut();
+        self.jit_ctx.tlb_ptr = self.mem.tlb.as_mut_ptr();
         for (dst, src) in self.jit_ctx.tracer_mem.iter_mut().zip(self.trace.storage_ptr()) {
             *dst = src;
         }
@@ -285,7 +285,9 @@ impl ExceptionCode {
     pub fn from_load_error(err: icicle_mem::MemError) -> Self {
         use icicle_mem::MemError;
         match err {
-            MemError::Unmapped => Self::ReadUnmapped,
+            MemError::Unmapped | MemError::Reserved | MemError::GuardPage | MemError::PageFault => {
+                Self::ReadUnmapped
+            }
             MemError::Uninitalized => Self::ReadUninitialized,
             MemError::ReadViolation => Self::ReadPerm,
             MemError::Unaligned => Self::ReadUnaligned,
@@ -297,9 +299,11 @@ impl ExceptionCode {
     pub fn from_store_error(err: icicle_mem::MemError) -> Self {
         use icicle_mem::MemError;
         match err {
-            MemError::Unmapped => Self::WriteUnmapped,
+            MemError::Unmapped | MemError::Reserved | MemError::GuardPage | MemError::PageFault => {
+                Self::WriteUnmapped
+            }
             MemError::WriteViolation => Self::WritePerm,
-            MemError::Unaligned => Self::WriteUnaligned,
+            MemError::Unaligned | MemError::CrossesDeviceBoundary => Self::WriteUnaligned,
             MemError::WriteWatch => Self::WriteWatch,
             _ => Self::from(err),
         }
@@ -310,21 +314,30 @@ impl From<icicle_mem::MemError> for ExceptionCode {
     fn from(err: icicle_mem::MemError) -> Self {
         use icicle_mem::MemError;
         match err {
-            MemError::Unmapped => Self::ReadUnmapped,
+            MemError::Unmapped | MemError::Reserved | MemError::GuardPage | MemError::PageFault => {
+                Self::ReadUnmapped
+            }
             MemError::Uninitalized => Self::ReadUninitialized,
             MemError::ReadViolation => Self::ReadPerm,
             MemError::WriteViolation => Self::WritePerm,
-            MemError::ExecViolation => Self::ExecViolation,
+            MemError::ExecViolation | MemError::WriteExecViolation => Self::ExecViolation,
             MemError::ReadWatch => Self::ReadWatch,
             MemError::WriteWatch => Self::WriteWatch,
-            MemError::Unaligned => Self::ReadUnaligned,
+            MemError::Unaligned | MemError::CrossesDeviceBoundary => Self::ReadUnaligned,
             MemError::OutOfMemory => Self::OutOfMemory,
             MemError::SelfModifyingCode => Self::SelfModifyingCode,
             MemError::AddressOverflow => Self::AddressOverflow,
             MemError::UnmappedRegister => Self::UnmappedRegister,
 
             // These are errors that should be handled by the memory subsystem.
-            MemError::Unallocated | MemError::Unknown => Self::UnknownError,
+            MemError::Unallocated
+            | MemError::Unsupported
+            | MemError::NotContiguous
+            | MemError::AlreadyMapped
+            | MemError::LimitExceeded
+            | MemError::ReplayDiverged
+            | MemError::InvalidRange
+            | MemError::Unknown => Self::UnknownError,
         }
     }
 }
@@ -196,24 +196,24 @@ fn handle_relocation(
 ) -> Result<(), RelocationError> {
     match reloc.typ {
         IMAGE_REL_BASED_HIGH => {
-            let old = cpu.mem.read_u16(addr, perm::NONE)?;
+            let old = cpu.mem.read_u16_le(addr, perm::NONE)?;
             let new = old.wrapping_add((relocation_offset >> 16) as u16);
-            cpu.mem.write_u16(addr, new, perm::NONE)?;
+            cpu.mem.write_u16_le(addr, new, perm::NONE)?;
         }
         IMAGE_REL_BASED_LOW => {
-            let old = cpu.mem.read_u16(addr, perm::NONE)?;
+            let old = cpu.mem.read_u16_le(addr, perm::NONE)?;
             let new = old.wrapping_add(relocation_offset as u16);
-            cpu.mem.write_u16(addr, new, perm::NONE)?;
+            cpu.mem.write_u16_le(addr, new, perm::NONE)?;
         }
         IMAGE_REL_BASED_HIGHLOW => {
-            let old = cpu.mem.read_u32(addr, perm::NONE)?;
+            let old = cpu.mem.read_u32_le(addr, perm::NONE)?;
             let new = old.wrapping_add(relocation_offset as u32);
-            cpu.mem.write_u32(addr, new, perm::NONE)?;
+            cpu.mem.write_u32_le(addr, new, perm::NONE)?;
         }
         IMAGE_REL_BASED_DIR64 => {
-            let old = cpu.mem.read_u64(addr, perm::NONE)?;
+            let old = cpu.mem.read_u64_le(addr, perm::NONE)?;
             let new = old.wrapping_add(relocation_offset);
-            cpu.mem.write_u64(addr, new, perm::NONE)?;
+            cpu.mem.write_u64_le(addr, new, perm::NONE)?;
         }
         typ => return Err(RelocationError::Unsupported(typ)),
     }
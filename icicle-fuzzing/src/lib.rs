@@ -498,7 +498,7 @@ where
     let snapshot = vm.snapshot();
     let mut map = BTreeMap::new();
     utils::input_visitor(dir, |path, input| {
-        vm.restore(&snapshot);
+        vm.restore(&snapshot)?;
 
         tracing::info!("resolving crashes for {}", path.display());
         target.set_input(&mut vm, &input)?;
@@ -91,7 +91,7 @@ where
     let mut output = vec![];
     source.visit(|tag, input| {
         vm.cpu.trace[new_cov].clear();
-        vm.restore(&snapshot);
+        vm.restore(&snapshot)?;
         runner.set_input(&mut vm, &input)?;
         vm.run();
         output.push(CoverageEntry { tag, new: vm.cpu.trace[new_cov].clone() });
@@ -433,7 +433,10 @@ impl<T: DynamicTarget> ext::monitor_cmd::MonitorCmd for VmState<T> {
             }
             Some("restore") => match self.snapshots.get(&None) {
                 Some(snapshot) => {
-                    self.vm.restore(&snapshot.vm);
+                    if let Err(e) = self.vm.restore(&snapshot.vm) {
+                        gdbstub::outputln!(out, "failed to restore snapshot: {e}");
+                        return Ok(());
+                    }
                     if let Some(trace) = snapshot.trace.as_ref() {
                         self.tracer.unwrap().restore(&mut self.vm, trace);
                         gdbstub::outputln!(out, "state restored from snapshot");
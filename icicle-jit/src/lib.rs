@@ -129,6 +129,7 @@ impl JIT {
         }
         translator_ctx.disable_jit_mem = std::env::var_os("ICICLE_DISABLE_JIT_MEM").is_some();
         translator_ctx.enable_shadow_stack = cpu.enable_shadow_stack;
+        translator_ctx.tlb_config = cpu.mem.tlb_config();
 
         Self {
             endianness,
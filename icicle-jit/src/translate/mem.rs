@@ -6,9 +6,9 @@ use cranelift::prelude::{
 };
 use cranelift_codegen::ir::{AliasRegion, Endianness};
 use icicle_cpu::mem::{
-    self, perm,
+    perm,
     physical::{PageData, OFFSET_BITS},
-    tlb::{TLBEntry, TLB_INDEX_BITS},
+    tlb::{TLBEntry, TlbConfig},
 };
 use memoffset::offset_of;
 
@@ -28,14 +28,22 @@ impl AccessKind {
         }
     }
 
-    fn tlb_offset(&self) -> i32 {
+    /// The offset of the first TLB entry for this kind of access. The JIT only checks the first way
+    /// of each set, which always contains the most recently used entry.
+    fn tlb_offset(&self, config: &TlbConfig) -> i32 {
         match self {
-            Self::Load => offset_of!(mem::tlb::TranslationCache, read).try_into().unwrap(),
-            Self::Store => offset_of!(mem::tlb::TranslationCache, write).try_into().unwrap(),
+            Self::Load => config.read_offset().try_into().unwrap(),
+            Self::Store => config.write_offset().try_into().unwrap(),
         }
     }
 }
 
+/// Generate code for loading the address space identifier that is included in the tag of every TLB
+/// entry (stored in the tag of the header entry of the TLB).
+fn load_tlb_asid(trans: &mut Translator, mem_flags: MemFlags) -> Value {
+    trans.builder.ins().load(types::I64, mem_flags, trans.tlb_ptr, 0)
+}
+
 /// Generate code for checking that `addr` is aligned to at least `bytes`
 fn check_alignment(trans: &mut Translator, addr: Value, bytes: u8, unaligned_block: Block) {
     if bytes == 1 {
@@ -91,18 +99,24 @@ fn tlb_lookup(trans: &mut Translator, addr: Value, kind: AccessKind, not_found:
 
     let tlb_entry_size_bits = tlb_entry_size.trailing_zeros() as usize;
     let index_shift = (OFFSET_BITS - tlb_entry_size_bits) as i64;
-    let index_mask = (((1_u64 << TLB_INDEX_BITS) - 1) as i64) << tlb_entry_size_bits;
-
-    let entry_offset = rshift_and_mask(trans, addr, index_shift, index_mask);
+    let index_mask = (trans.ctx.tlb_config.index_mask() as i64) << tlb_entry_size_bits;
+
+    // The set is selected using both the page number and the ASID (see `TlbConfig::index`).
+    let asid = load_tlb_asid(trans, mem_flags);
+    let page_number = trans.builder.ins().ushr_imm(addr, index_shift);
+    let asid_offset = trans.builder.ins().ishl_imm(asid, tlb_entry_size_bits as i64);
+    let set = trans.builder.ins().bxor(page_number, asid_offset);
+    let entry_offset = trans.builder.ins().band_imm(set, index_mask);
     let tlb_addr = trans.builder.ins().iadd(trans.tlb_ptr, entry_offset);
 
     // Load the tag
-    let kind_offset = kind.tlb_offset();
+    let kind_offset = kind.tlb_offset(&trans.ctx.tlb_config);
     let expected_tag = trans.builder.ins().load(types::I64, mem_flags, tlb_addr, kind_offset);
 
     // Check that the tag matches.
     let tag_mask = TLBEntry::tag_mask();
-    let tag = trans.builder.ins().band_imm(addr, tag_mask as i64);
+    let page = trans.builder.ins().band_imm(addr, tag_mask as i64);
+    let tag = trans.builder.ins().bor(page, asid);
     let cond = trans.builder.ins().icmp(IntCC::Equal, tag, expected_tag);
     trans.branch_zero(cond, not_found);
 
@@ -125,16 +139,23 @@ fn tlb_lookup_const(
     let tlb_entry_size: i64 = std::mem::size_of::<TLBEntry>().try_into().unwrap();
     assert_eq!(tlb_entry_size.count_ones(), 1);
 
-    let index = icicle_cpu::mem::tlb::TranslationCache::index(addr) as i64;
-    let entry_offset = trans.builder.ins().iconst(types::I64, index * tlb_entry_size);
+    // The set depends on the current ASID, so only the page number is known statically.
+    let tlb_entry_size_bits = tlb_entry_size.trailing_zeros() as i64;
+    let index_mask = (trans.ctx.tlb_config.index_mask() as i64) << tlb_entry_size_bits;
+    let page_offset = ((addr >> OFFSET_BITS) as i64) << tlb_entry_size_bits;
+
+    let asid = load_tlb_asid(trans, mem_flags);
+    let asid_offset = trans.builder.ins().ishl_imm(asid, tlb_entry_size_bits);
+    let set = trans.builder.ins().bxor_imm(asid_offset, page_offset);
+    let entry_offset = trans.builder.ins().band_imm(set, index_mask);
     let tlb_addr = trans.builder.ins().iadd(trans.tlb_ptr, entry_offset);
 
     // Load the tag
-    let kind_offset = kind.tlb_offset();
+    let kind_offset = kind.tlb_offset(&trans.ctx.tlb_config);
     let expected_tag = trans.builder.ins().load(types::I64, mem_flags, tlb_addr, kind_offset);
 
     // Check that the tag matches
-    let tag = trans.builder.ins().iconst(types::I64, TLBEntry::tag(addr) as i64);
+    let tag = trans.builder.ins().bor_imm(asid, TLBEntry::tag(addr) as i64);
     let cond = trans.builder.ins().icmp(IntCC::Equal, tag, expected_tag);
     trans.branch_zero(cond, not_found);
 
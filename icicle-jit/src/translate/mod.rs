@@ -312,6 +312,8 @@ pub(crate) struct TranslatorCtx {
     pub reload_after_mem: bool,
     /// Configures whether calls to push/pop shadow-stack are injected in the JIT.
     pub enable_shadow_stack: bool,
+    /// The geometry of the TLB that JIT'ed code performs lookups in.
+    pub tlb_config: icicle_cpu::mem::TlbConfig,
     page_size: u64,
     reg_pc: pcode::VarNode,
     endianness: Endianness,
@@ -336,6 +338,7 @@ impl TranslatorCtx {
             flush_before_mem: true,
             reload_after_mem: false,
             enable_shadow_stack: true,
+            tlb_config: icicle_cpu::mem::TlbConfig::default(),
             page_size: icicle_cpu::mem::physical::PAGE_SIZE as u64,
             endianness,
             local_blocks: HashMap::new(),
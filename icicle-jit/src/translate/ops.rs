@@ -730,7 +730,7 @@ mod test {
             cpu.write_var(self.b, b);
             cpu.write_trunc(self.out, 0xaaaa_aaaa_u32);
 
-            cpu.jit_ctx.tlb_ptr = cpu.mem.tlb.as_mut();
+            cpu.jit_ctx.tlb_ptr = cpu.mem.tlb.as_mut_ptr();
             unsafe {
                 (self.jit_fn)((*cpu).as_mut() as *mut Cpu, 0x0);
             }
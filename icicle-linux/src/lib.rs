@@ -119,7 +119,7 @@ impl LinuxMmu for mem::Mmu {
     }
 
     fn clone_virtual_map(&mut self) -> VirtualMemoryMap {
-        self.mapping.clone()
+        mem::Mmu::clone_virtual_mapping(self)
     }
 
     fn snapshot_virtual_map(&mut self) -> VirtualMemoryMap {
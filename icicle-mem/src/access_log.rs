@@ -0,0 +1,134 @@
+//! A log of recent memory accesses, used to understand the cause of a crash without re-running the
+//! input under a tracer (see [crate::Mmu::enable_access_log]).
+
+use std::collections::VecDeque;
+
+use crate::{AccessKind, MemError};
+
+/// A memory access recorded in the access log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessRecord {
+    /// The position of the access in the log, incremented for every access (including accesses
+    /// that were dropped).
+    pub seq: u64,
+
+    /// The context tag that was set when the access was performed (see
+    /// [crate::Mmu::set_access_context]).
+    pub context: u64,
+
+    /// The address of the access.
+    pub addr: u64,
+
+    /// The number of bytes accessed.
+    pub size: u8,
+
+    /// The kind of access.
+    pub kind: AccessKind,
+
+    /// The value read or written (in little-endian byte order). Zero for reads that failed.
+    pub value: u128,
+
+    /// The error returned by the access, if it failed.
+    pub error: Option<MemError>,
+}
+
+/// The contents of the access log returned by [crate::Mmu::access_log].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccessHistory {
+    /// The recorded accesses, from oldest to newest.
+    pub records: Vec<AccessRecord>,
+
+    /// The number of accesses that are not in `records`, either because they were discarded when
+    /// the log was full, or they were skipped because of sampling.
+    pub dropped: u64,
+}
+
+/// A fixed-size ring buffer of memory accesses.
+#[derive(Default)]
+pub(crate) struct AccessLog {
+    /// Set if accesses should be recorded, checked before every access.
+    pub enabled: bool,
+
+    /// The context tag attached to new records.
+    pub context: u64,
+
+    records: VecDeque<AccessRecord>,
+    capacity: usize,
+    dropped: u64,
+    next_seq: u64,
+
+    /// Only one in every `interval` successful accesses is recorded.
+    interval: u32,
+    countdown: u32,
+}
+
+impl AccessLog {
+    pub fn enable(&mut self, capacity: usize) {
+        self.enabled = capacity != 0;
+        self.capacity = capacity;
+        self.records = VecDeque::with_capacity(capacity);
+        self.interval = self.interval.max(1);
+        self.clear();
+    }
+
+    pub fn disable(&mut self) {
+        self.enabled = false;
+        self.capacity = 0;
+        self.records = VecDeque::new();
+        self.clear();
+    }
+
+    pub fn set_sampling(&mut self, interval: u32) {
+        self.interval = interval.max(1);
+        self.countdown = 0;
+    }
+
+    /// Removes all records from the log.
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.dropped = 0;
+        self.countdown = 0;
+    }
+
+    pub fn history(&self) -> AccessHistory {
+        AccessHistory { records: self.records.iter().copied().collect(), dropped: self.dropped }
+    }
+
+    /// Records an access of `kind` to `value.len()` bytes at `addr`, where `value` is truncated to
+    /// 16 bytes.
+    #[cold]
+    #[inline(never)]
+    pub fn record(&mut self, kind: AccessKind, addr: u64, value: &[u8], error: Option<MemError>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        // Failed accesses are always recorded, since they are usually the reason for looking at
+        // the log.
+        if error.is_none() && self.interval > 1 {
+            if self.countdown != 0 {
+                self.countdown -= 1;
+                self.dropped += 1;
+                return;
+            }
+            self.countdown = self.interval - 1;
+        }
+
+        if self.records.len() >= self.capacity {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+
+        let mut bytes = [0; 16];
+        let len = value.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&value[..len]);
+        self.records.push_back(AccessRecord {
+            seq,
+            context: self.context,
+            addr,
+            size: value.len() as u8,
+            kind,
+            value: u128::from_le_bytes(bytes),
+            error,
+        });
+    }
+}
@@ -0,0 +1,114 @@
+//! Limits on the number of pages that are allocated lazily for ranges of memory (see
+//! [crate::Mmu::create_page_budget]).
+//!
+//! A page is charged to the budget assigned to the address that caused it to be allocated, either
+//! when unallocated memory is first accessed or when a shared (e.g. zero or snapshot) page is
+//! copied on write. Each page is charged at most once and the charge is refunded when the page is
+//! unmapped. Pages that are allocated explicitly (e.g. [crate::Mmu::alloc_physical]) are never
+//! charged.
+
+use std::collections::BTreeMap;
+
+use crate::{MemError, MemResult, range_map::RangeMap};
+
+/// A handle to a budget created using [crate::Mmu::create_page_budget].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BudgetId(usize);
+
+/// The accounting state of every budget, captured by snapshots so that restoring a snapshot also
+/// restores the usage of each budget.
+#[derive(Clone, Default)]
+pub struct BudgetState {
+    /// The budget assigned to each range of memory.
+    ranges: RangeMap<BudgetId>,
+
+    /// The budget that each page (keyed by the address of the page) was charged to.
+    charged: BTreeMap<u64, BudgetId>,
+
+    /// The number of pages charged to each budget.
+    used: Vec<usize>,
+}
+
+#[derive(Default)]
+pub(crate) struct PageBudgets {
+    limits: Vec<usize>,
+    state: BudgetState,
+}
+
+impl PageBudgets {
+    pub fn create(&mut self, limit: usize) -> BudgetId {
+        self.limits.push(limit);
+        self.state.used.push(0);
+        BudgetId(self.limits.len() - 1)
+    }
+
+    pub fn set_limit(&mut self, id: BudgetId, limit: usize) -> bool {
+        match self.limits.get_mut(id.0) {
+            Some(entry) => {
+                *entry = limit;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn assign(&mut self, start: u64, end: u64, id: BudgetId) -> bool {
+        if id.0 >= self.limits.len() {
+            return false;
+        }
+        let _ = self.state.ranges.overlapping_mut::<_, ()>(start..=end, |_, _, entry| {
+            *entry = Some(id);
+            Ok(())
+        });
+        true
+    }
+
+    pub fn unassign(&mut self, start: u64, end: u64) {
+        self.state.ranges.remove_all(start..=end);
+    }
+
+    pub fn usage(&self, id: BudgetId) -> Option<usize> {
+        self.state.used.get(id.0).copied()
+    }
+
+    /// Checks whether a page allocated for `addr` (part of the page at `page`) can be charged,
+    /// returning the budget to charge.
+    pub fn check(&self, addr: u64, page: u64) -> MemResult<Option<BudgetId>> {
+        if self.state.charged.contains_key(&page) {
+            return Ok(None);
+        }
+        match self.state.ranges.get(addr) {
+            Some(id) if self.state.used[id.0] >= self.limits[id.0] => Err(MemError::OutOfMemory),
+            Some(id) => Ok(Some(*id)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn charge(&mut self, page: u64, id: BudgetId) {
+        self.state.used[id.0] += 1;
+        self.state.charged.insert(page, id);
+    }
+
+    /// Returns the addresses of the charged pages that overlap with `start..=end`.
+    pub fn charged_pages(&self, start: u64, end: u64, page_size: u64) -> Vec<u64> {
+        let first = start & !(page_size - 1);
+        self.state.charged.range(first..=end).map(|(page, _)| *page).collect()
+    }
+
+    pub fn refund(&mut self, page: u64) {
+        if let Some(id) = self.state.charged.remove(&page) {
+            self.state.used[id.0] -= 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> BudgetState {
+        self.state.clone()
+    }
+
+    /// Restores the accounting state from `state`. Budgets created after `state` was captured are
+    /// kept, but have no ranges assigned to them.
+    pub fn restore(&mut self, state: &BudgetState) {
+        self.state.clone_from(state);
+        self.state.used.resize(self.limits.len(), 0);
+    }
+}
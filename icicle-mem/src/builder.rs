@@ -0,0 +1,233 @@
+//! A builder for configuring an [Mmu] and its initial state in one place (see [Mmu::builder]).
+
+use std::collections::BTreeSet;
+
+use crate::{
+    IoHandler, IoMemory, IoMemoryAny, Mapping, MmuConfig, ReadAfterHook, ReadHook,
+    SelfModifyingCode, WriteHook, mmu::Mmu, perm, physical,
+};
+
+/// A region of memory that is mapped when the MMU is built.
+struct InitialRegion {
+    addr: u64,
+    len: u64,
+    perm: u8,
+    data: Vec<u8>,
+}
+
+enum InitialHook {
+    Read(u64, u64, Box<dyn ReadHook>),
+    ReadAfter(u64, u64, Box<dyn ReadAfterHook>),
+    Write(u64, u64, Box<dyn WriteHook>),
+}
+
+/// The error returned by [MmuBuilder::build] when the configuration is invalid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// The physical page capacity is smaller than the number of pages needed to store the initial
+    /// contents of the regions.
+    InsufficientCapacity { required: usize, capacity: usize },
+
+    /// The region starting at the address is empty, extends past the end of the address space, or
+    /// has more initial contents than its length.
+    InvalidRegion(u64),
+
+    /// The regions starting at the two addresses overlap.
+    Overlap(u64, u64),
+
+    /// The region starting at the address could not be mapped (e.g. because of
+    /// [Mmu::wx_policy]).
+    Map(u64),
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InsufficientCapacity { required, capacity } => {
+                write!(f, "{required} pages are required, but the capacity is {capacity} pages")
+            }
+            Self::InvalidRegion(addr) => write!(f, "region at {addr:#x} has an invalid range"),
+            Self::Overlap(a, b) => write!(f, "region at {a:#x} overlaps with region at {b:#x}"),
+            Self::Map(addr) => write!(f, "failed to map region at {addr:#x}"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Configures an [Mmu] along with the memory, I/O handlers and hooks that it starts with.
+#[derive(Default)]
+pub struct MmuBuilder {
+    config: MmuConfig,
+    capacity: Option<usize>,
+    track_uninitialized: bool,
+    self_modifying_code: Option<SelfModifyingCode>,
+    regions: Vec<InitialRegion>,
+    io: Vec<Box<dyn IoMemoryAny>>,
+    io_regions: Vec<(u64, u64, IoHandler)>,
+    hooks: Vec<InitialHook>,
+}
+
+impl MmuBuilder {
+    /// Sets the configuration the MMU is created with (see [Mmu::with_config]).
+    pub fn config(mut self, config: MmuConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets the maximum number of physical pages the MMU is allowed to allocate (see
+    /// [Mmu::set_capacity]).
+    ///
+    /// Note: the page size is currently fixed at [physical::PAGE_SIZE].
+    pub fn capacity(mut self, pages: usize) -> Self {
+        self.capacity = Some(pages);
+        self
+    }
+
+    /// Sets [Mmu::track_uninitialized].
+    pub fn track_uninitialized(mut self, enabled: bool) -> Self {
+        self.track_uninitialized = enabled;
+        self
+    }
+
+    /// Sets [Mmu::self_modifying_code], overriding [MmuConfig::detect_self_modifying_code].
+    pub fn self_modifying_code(mut self, policy: SelfModifyingCode) -> Self {
+        self.self_modifying_code = Some(policy);
+        self
+    }
+
+    /// Maps `len` bytes at `addr` with `perm`. The region is zeroed, and is treated as
+    /// uninitialized if [MmuBuilder::track_uninitialized] is set.
+    pub fn region(self, addr: u64, len: u64, perm: u8) -> Self {
+        self.region_with_data(addr, len, perm, vec![])
+    }
+
+    /// Maps `len` bytes at `addr` with `perm`, where the start of the region is initialized with
+    /// `data` and the remaining bytes are treated as in [MmuBuilder::region].
+    pub fn region_with_data(mut self, addr: u64, len: u64, perm: u8, data: Vec<u8>) -> Self {
+        self.regions.push(InitialRegion { addr, len, perm, data });
+        self
+    }
+
+    /// Registers `handler` with the MMU when it is built (see [Mmu::register_io_handler]).
+    ///
+    /// Handlers are registered in the order they are added to the builder, so the returned handle
+    /// refers to `handler` in the built MMU and can be passed to [MmuBuilder::io_region].
+    pub fn io_handler(&mut self, handler: impl IoMemory + 'static) -> IoHandler {
+        self.io.push(Box::new(handler));
+        IoHandler(self.io.len() - 1)
+    }
+
+    /// Maps `len` bytes at `addr` to the I/O handler `handler`.
+    pub fn io_region(mut self, addr: u64, len: u64, handler: IoHandler) -> Self {
+        self.io_regions.push((addr, len, handler));
+        self
+    }
+
+    /// Adds a read hook for `start..end` (see [Mmu::add_read_hook]).
+    pub fn read_hook(mut self, start: u64, end: u64, hook: Box<dyn ReadHook>) -> Self {
+        self.hooks.push(InitialHook::Read(start, end, hook));
+        self
+    }
+
+    /// Adds a read-after hook for `start..end` (see [Mmu::add_read_after_hook]).
+    pub fn read_after_hook(mut self, start: u64, end: u64, hook: Box<dyn ReadAfterHook>) -> Self {
+        self.hooks.push(InitialHook::ReadAfter(start, end, hook));
+        self
+    }
+
+    /// Adds a write hook for `start..end` (see [Mmu::add_write_hook]).
+    pub fn write_hook(mut self, start: u64, end: u64, hook: Box<dyn WriteHook>) -> Self {
+        self.hooks.push(InitialHook::Write(start, end, hook));
+        self
+    }
+
+    /// Checks that the regions are valid and do not overlap.
+    fn validate_regions(&self) -> Result<(), BuildError> {
+        let memory = self.regions.iter().map(|x| (x.addr, x.len, x.data.len() as u64));
+        let io = self.io_regions.iter().map(|&(addr, len, _)| (addr, len, 0));
+
+        let mut ranges = vec![];
+        for (addr, len, data_len) in memory.chain(io) {
+            if len == 0 || data_len > len {
+                return Err(BuildError::InvalidRegion(addr));
+            }
+            let end = addr.checked_add(len - 1).ok_or(BuildError::InvalidRegion(addr))?;
+            ranges.push((addr, end));
+        }
+        ranges.sort_unstable();
+        for pair in ranges.windows(2) {
+            if pair[1].0 <= pair[0].1 {
+                return Err(BuildError::Overlap(pair[0].0, pair[1].0));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of physical pages needed to store the initial contents of the regions.
+    fn required_pages(&self) -> usize {
+        let mut pages = BTreeSet::new();
+        for region in self.regions.iter().filter(|x| !x.data.is_empty()) {
+            let last = region.addr + (region.data.len() as u64 - 1);
+            let first_page = region.addr & !physical::PAGE_MASK;
+            pages.extend((first_page..=last).step_by(physical::PAGE_SIZE));
+        }
+        pages.len()
+    }
+
+    /// Creates the MMU, failing if any of the regions are invalid or overlap, or if the capacity
+    /// is too small to store the initial contents of the regions.
+    pub fn build(self) -> Result<Mmu, BuildError> {
+        self.validate_regions()?;
+        let required = self.required_pages();
+
+        let mut mmu = Mmu::with_config(self.config);
+        if let Some(capacity) = self.capacity {
+            if capacity < required || !mmu.set_capacity(capacity) {
+                return Err(BuildError::InsufficientCapacity { required, capacity });
+            }
+        }
+        else if mmu.capacity() < required {
+            return Err(BuildError::InsufficientCapacity { required, capacity: mmu.capacity() });
+        }
+
+        mmu.track_uninitialized = self.track_uninitialized;
+        if let Some(policy) = self.self_modifying_code {
+            mmu.self_modifying_code = policy;
+        }
+
+        for handler in self.io {
+            mmu.io.push(Some(handler));
+        }
+
+        let init = if self.track_uninitialized { perm::NONE } else { perm::INIT };
+        for region in &self.regions {
+            let mapping = Mapping { perm: region.perm | perm::MAP | init, value: 0 };
+            if !mmu.map_memory_len(region.addr, region.len, mapping) {
+                return Err(BuildError::Map(region.addr));
+            }
+            if !region.data.is_empty()
+                && mmu.write_bytes(region.addr, &region.data, perm::NONE).is_err()
+            {
+                return Err(BuildError::Map(region.addr));
+            }
+        }
+        for &(addr, len, handler) in &self.io_regions {
+            if handler.0 >= mmu.io.len() || !mmu.map_memory_len(addr, len, handler) {
+                return Err(BuildError::Map(addr));
+            }
+        }
+
+        for hook in self.hooks {
+            match hook {
+                InitialHook::Read(start, end, hook) => mmu.add_read_hook(start, end, hook),
+                InitialHook::ReadAfter(start, end, hook) => {
+                    mmu.add_read_after_hook(start, end, hook)
+                }
+                InitialHook::Write(start, end, hook) => mmu.add_write_hook(start, end, hook),
+            };
+        }
+
+        Ok(mmu)
+    }
+}
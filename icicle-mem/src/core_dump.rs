@@ -0,0 +1,298 @@
+//! Exporting the virtual address space as an ELF core file (see [crate::Mmu::write_core_dump]).
+
+use std::io::{self, Write};
+
+use crate::{FileMapping, MemoryMapping, Mmu, perm, physical, physical::PageData};
+
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_FILE: u32 = 0x4649_4c45;
+
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+
+/// Options for the ELF file written by [Mmu::write_core_dump_with].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CoreDumpOptions {
+    /// The value of the `e_machine` field of the header (e.g. `62` for x86-64). Defaults to
+    /// `EM_NONE`.
+    pub machine: u16,
+
+    /// Controls whether the headers are encoded as big-endian.
+    pub big_endian: bool,
+}
+
+/// A part of a segment, used to stream the contents of the segment without copying it.
+enum Piece {
+    Physical { index: physical::Index, offset: usize, len: u64 },
+    Fill { value: u8, len: u64 },
+    File { mapping: FileMapping, addr: u64, len: u64 },
+}
+
+impl Piece {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Physical { len, .. } | Self::Fill { len, .. } | Self::File { len, .. } => *len,
+        }
+    }
+}
+
+struct Segment {
+    addr: u64,
+    len: u64,
+    flags: u32,
+    pieces: Vec<Piece>,
+}
+
+impl Segment {
+    /// Returns the number of bytes of the segment stored in the file. Zero bytes at the end of the
+    /// segment that are not backed by a physical page are left out of the file.
+    fn file_size(&self) -> u64 {
+        let zeros: u64 = self
+            .pieces
+            .iter()
+            .rev()
+            .take_while(|piece| matches!(piece, Piece::Fill { value: 0, .. }))
+            .map(Piece::len)
+            .sum();
+        self.len - zeros
+    }
+}
+
+fn segment_flags(perm: u8) -> u32 {
+    let mut flags = 0;
+    if perm & perm::READ != 0 {
+        flags |= PF_R;
+    }
+    if perm & perm::WRITE != 0 {
+        flags |= PF_W;
+    }
+    if perm & perm::EXEC != 0 {
+        flags |= PF_X;
+    }
+    flags
+}
+
+/// Splits the mapping into segments, merging adjacent regions with the same permissions. I/O
+/// regions are not included, since they can not be read without side effects.
+fn segments(mmu: &Mmu) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = vec![];
+    let mut push = |addr: u64, flags: u32, piece: Piece| match segments.last_mut() {
+        Some(last) if last.addr.wrapping_add(last.len) == addr && last.flags == flags => {
+            last.len += piece.len();
+            last.pieces.push(piece);
+        }
+        _ => segments.push(Segment { addr, len: piece.len(), flags, pieces: vec![piece] }),
+    };
+
+    for (start, end, entry) in mmu.mapping.iter() {
+        let len = end - start + 1;
+        match entry {
+            MemoryMapping::Physical(entry) => {
+                // The permissions of a physical page are stored for each byte, so split the region
+                // wherever they change.
+                let offset = PageData::offset(start);
+                let perms = &mmu.get_physical(entry.index).data().perm[offset..][..len as usize];
+                let mut run_start = 0;
+                for i in 1..=perms.len() {
+                    let flags = segment_flags(perms[run_start]);
+                    if i == perms.len() || segment_flags(perms[i]) != flags {
+                        let piece = Piece::Physical {
+                            index: entry.index,
+                            offset: offset + run_start,
+                            len: (i - run_start) as u64,
+                        };
+                        push(start + run_start as u64, flags, piece);
+                        run_start = i;
+                    }
+                }
+            }
+            MemoryMapping::Unallocated(entry) => {
+                push(start, segment_flags(entry.perm), Piece::Fill { value: entry.value, len })
+            }
+            &MemoryMapping::File(mapping) => {
+                push(start, segment_flags(mapping.perm), Piece::File { mapping, addr: start, len })
+            }
+            MemoryMapping::Io(_) | MemoryMapping::Reserved(_) => {}
+        }
+    }
+    segments
+}
+
+/// Encodes the fields of the ELF headers using the configured byte order.
+struct Encoder {
+    buf: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Encoder {
+    fn u16(&mut self, value: u16) {
+        let bytes = if self.big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    fn u32(&mut self, value: u32) {
+        let bytes = if self.big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    fn u64(&mut self, value: u64) {
+        let bytes = if self.big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    fn align(&mut self, align: usize) {
+        self.buf.resize(self.buf.len().next_multiple_of(align), 0);
+    }
+}
+
+/// Builds the descriptor of an `NT_FILE` note for the file-backed regions in `segments`. The
+/// mapped files are not named, so each file is named after its handle (e.g. `[file 0]`).
+fn file_note(segments: &[Segment], big_endian: bool) -> Option<Vec<u8>> {
+    let files: Vec<_> = segments
+        .iter()
+        .flat_map(|segment| &segment.pieces)
+        .filter_map(|piece| match piece {
+            Piece::File { mapping, addr, len } => Some((mapping, *addr, *len)),
+            _ => None,
+        })
+        .collect();
+    if files.is_empty() {
+        return None;
+    }
+
+    let mut desc = Encoder { buf: vec![], big_endian };
+    desc.u64(files.len() as u64);
+    // Offsets are stored in units of the page size, so use a page size of 1 to allow offsets
+    // that are not page aligned.
+    desc.u64(1);
+    for &(mapping, addr, len) in &files {
+        desc.u64(addr);
+        desc.u64(addr.wrapping_add(len));
+        desc.u64(mapping.offset.wrapping_add(addr));
+    }
+    for (mapping, ..) in &files {
+        desc.buf.extend_from_slice(format!("[file {}]\0", mapping.file.0).as_bytes());
+    }
+
+    let mut note = Encoder { buf: vec![], big_endian };
+    note.u32(5);
+    note.u32(desc.buf.len() as u32);
+    note.u32(NT_FILE);
+    note.buf.extend_from_slice(b"CORE\0");
+    note.align(4);
+    note.buf.extend_from_slice(&desc.buf);
+    note.align(4);
+    Some(note.buf)
+}
+
+fn write_piece(mmu: &Mmu, writer: &mut impl Write, piece: &Piece, len: u64) -> io::Result<()> {
+    match piece {
+        Piece::Physical { index, offset, .. } => {
+            writer.write_all(&mmu.get_physical(*index).data().data[*offset..][..len as usize])
+        }
+        Piece::Fill { value, .. } => {
+            let chunk = [*value; 0x1000];
+            let mut remaining = len;
+            while remaining != 0 {
+                let n = remaining.min(chunk.len() as u64);
+                writer.write_all(&chunk[..n as usize])?;
+                remaining -= n;
+            }
+            Ok(())
+        }
+        Piece::File { mapping, addr, .. } => {
+            // Large file-backed regions are copied a page at a time, bytes past the end of the
+            // file are zero.
+            let mut addr = *addr;
+            let mut remaining = len;
+            while remaining != 0 {
+                let n = remaining.min(0x1000);
+                let data = crate::mmu::file_bytes(&mmu.files, mapping, addr, n as usize);
+                writer.write_all(data)?;
+                writer.write_all(&[0; 0x1000][..n as usize - data.len()])?;
+                addr = addr.wrapping_add(n);
+                remaining -= n;
+            }
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn write(mmu: &Mmu, mut writer: impl Write, options: CoreDumpOptions) -> io::Result<()> {
+    let segments = segments(mmu);
+    let note = file_note(&segments, options.big_endian);
+
+    let phnum = segments.len() + note.is_some() as usize;
+    let phnum: u16 = phnum
+        .try_into()
+        .ok()
+        .filter(|&n| n != u16::MAX)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "too many segments"))?;
+
+    let mut out = Encoder { buf: Vec::with_capacity(0x1000), big_endian: options.big_endian };
+    out.buf.extend_from_slice(b"\x7fELF");
+    out.buf.push(2); // ELFCLASS64
+    out.buf.push(if options.big_endian { 2 } else { 1 });
+    out.buf.push(1); // EV_CURRENT
+    out.align(16);
+    out.u16(ET_CORE);
+    out.u16(options.machine);
+    out.u32(1);
+    out.u64(0); // e_entry
+    out.u64(EHDR_SIZE); // e_phoff
+    out.u64(0); // e_shoff
+    out.u32(0); // e_flags
+    out.u16(EHDR_SIZE as u16);
+    out.u16(PHDR_SIZE as u16);
+    out.u16(phnum);
+    out.u16(0); // e_shentsize
+    out.u16(0); // e_shnum
+    out.u16(0); // e_shstrndx
+
+    let mut offset = EHDR_SIZE + PHDR_SIZE * phnum as u64;
+    if let Some(note) = &note {
+        out.u32(PT_NOTE);
+        out.u32(0);
+        out.u64(offset);
+        out.u64(0);
+        out.u64(0);
+        out.u64(note.len() as u64);
+        out.u64(0);
+        out.u64(4);
+        offset += note.len() as u64;
+    }
+    for segment in &segments {
+        let file_size = segment.file_size();
+        out.u32(PT_LOAD);
+        out.u32(segment.flags);
+        out.u64(offset);
+        out.u64(segment.addr);
+        out.u64(0);
+        out.u64(file_size);
+        out.u64(segment.len);
+        out.u64(1);
+        offset += file_size;
+    }
+    if let Some(note) = &note {
+        out.buf.extend_from_slice(note);
+    }
+    writer.write_all(&out.buf)?;
+
+    for segment in &segments {
+        let mut remaining = segment.file_size();
+        for piece in &segment.pieces {
+            if remaining == 0 {
+                break;
+            }
+            let len = piece.len().min(remaining);
+            write_piece(mmu, &mut writer, piece, len)?;
+            remaining -= len;
+        }
+    }
+    writer.flush()
+}
@@ -0,0 +1,159 @@
+//! A cursor for accessing guest memory using [std::io] traits (see [crate::Mmu::cursor]).
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::{MemError, Mmu, physical};
+
+impl From<MemError> for io::Error {
+    /// Converts `err` to an [io::Error] with a kind that matches the cause of the error. The
+    /// original error can be recovered by downcasting the inner error (see [io::Error::get_ref]).
+    fn from(err: MemError) -> Self {
+        let kind = match err {
+            MemError::Unallocated
+            | MemError::Unmapped
+            | MemError::UnmappedRegister
+            | MemError::Reserved => io::ErrorKind::NotFound,
+            MemError::ReadViolation
+            | MemError::WriteViolation
+            | MemError::ExecViolation
+            | MemError::GuardPage
+            | MemError::WriteExecViolation => io::ErrorKind::PermissionDenied,
+            MemError::Uninitalized => io::ErrorKind::InvalidData,
+            MemError::Unaligned | MemError::AddressOverflow => io::ErrorKind::InvalidInput,
+            MemError::OutOfMemory => io::ErrorKind::OutOfMemory,
+            MemError::Unsupported => io::ErrorKind::Unsupported,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Reads and writes guest memory starting at a fixed address, returned by [Mmu::cursor].
+///
+/// The position of the cursor is relative to the address it was created at. Accesses check the
+/// permissions the cursor was created with and behave like [Mmu::read_bytes] and
+/// [Mmu::write_bytes] (e.g. written bytes are marked as initialized and the modified pages are
+/// logged). An access that fails part way through returns the number of bytes transferred before
+/// the failure, and the error is returned by the next access.
+pub struct MemCursor<'a> {
+    mmu: &'a mut Mmu,
+    base: u64,
+    pos: u64,
+    perm: u8,
+
+    /// The number of bytes that can be accessed from `base`, or `None` if the cursor extends to
+    /// the end of the address space.
+    limit: Option<u64>,
+}
+
+impl<'a> MemCursor<'a> {
+    pub(crate) fn new(mmu: &'a mut Mmu, addr: u64, perm: u8) -> Self {
+        Self { mmu, base: addr, pos: 0, perm, limit: None }
+    }
+
+    /// Limits the cursor to the `len` bytes starting at the address the cursor was created at.
+    /// Reads past the limit return zero bytes and writes past the limit fail with
+    /// [io::ErrorKind::WriteZero].
+    pub fn with_limit(mut self, len: u64) -> Self {
+        self.limit = Some(len);
+        self
+    }
+
+    /// Gets the guest address the next access will be performed at.
+    pub fn addr(&self) -> u64 {
+        self.base.wrapping_add(self.pos)
+    }
+
+    /// Gets the position of the cursor, relative to the address it was created at.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Gets the number of bytes that can be accessed starting at the current position, limited to
+    /// `len`.
+    fn available(&self, len: usize) -> usize {
+        // The last offset that can be accessed, either because of the limit or the end of the
+        // address space. Note: the position after the last offset must fit in a `u64`, so a cursor
+        // at address zero cannot access the last byte of the address space.
+        let last = match self.limit {
+            Some(0) => return 0,
+            Some(limit) => (limit - 1).min(u64::MAX - self.base),
+            None => u64::MAX - self.base,
+        }
+        .min(u64::MAX - 1);
+        match self.pos <= last {
+            true => (last - self.pos).saturating_add(1).min(len as u64) as usize,
+            false => 0,
+        }
+    }
+
+    /// Performs an access of `len` bytes at the current position, split at page boundaries so
+    /// that a failure part way through the access can be reported as a partial access.
+    fn access(
+        &mut self,
+        len: usize,
+        mut f: impl FnMut(&mut Mmu, u64, std::ops::Range<usize>) -> Result<(), MemError>,
+    ) -> io::Result<usize> {
+        let mut done = 0;
+        while done < len {
+            let addr = self.addr();
+            let page_remaining = physical::PAGE_SIZE - (addr as usize & (physical::PAGE_SIZE - 1));
+            let chunk = page_remaining.min(len - done);
+            if let Err(e) = f(self.mmu, addr, done..done + chunk) {
+                if done == 0 {
+                    return Err(e.into());
+                }
+                break;
+            }
+            done += chunk;
+            self.pos += chunk as u64;
+        }
+        Ok(done)
+    }
+}
+
+impl Read for MemCursor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.available(buf.len());
+        let perm = self.perm;
+        self.access(len, |mmu, addr, range| mmu.read_bytes(addr, &mut buf[range], perm))
+    }
+}
+
+impl Write for MemCursor<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.available(buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        let perm = self.perm;
+        self.access(len, |mmu, addr, range| mmu.write_bytes(addr, &buf[range], perm))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemCursor<'_> {
+    /// Moves the cursor, failing with [io::ErrorKind::InvalidInput] if the new position is before
+    /// the start of the cursor or the address of the new position does not fit in the address
+    /// space. Seeking relative to the end requires a limit (see [MemCursor::with_limit]).
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(offset) => {
+                let limit = self.limit.ok_or(io::ErrorKind::Unsupported)?;
+                limit.checked_add_signed(offset)
+            }
+        };
+        match new {
+            Some(new) if self.base.checked_add(new).is_some() => {
+                self.pos = new;
+                Ok(new)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, MemError::AddressOverflow)),
+        }
+    }
+}
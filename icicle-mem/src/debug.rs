@@ -0,0 +1,187 @@
+//! Memory access for debuggers, following the semantics expected by the `gdbstub` crate for the
+//! RSP memory (`m`/`M`) and hardware watchpoint (`Z2`-`Z4`) packets.
+//!
+//! [DebugMemory::read_dbg] and [DebugMemory::write_dbg] map directly to
+//! `SingleThreadBase::read_addrs` and `SingleThreadBase::write_addrs`, and the watchpoint
+//! functions to the `HwWatchpoint` extension, where hits reported by
+//! [DebugMemory::take_watch_hits] are converted to `SingleThreadStopReason::Watch`.
+
+use crate::{
+    AccessKind, Mmu, perm,
+    watch::{WatchpointHit, WatchpointId, WatchpointKind},
+};
+
+/// The kind of accesses that trigger a watchpoint (equivalent to `gdbstub::target::ext::
+/// breakpoints::WatchKind`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    /// Triggered by writes (`Z2`).
+    Write,
+
+    /// Triggered by reads (`Z3`).
+    Read,
+
+    /// Triggered by both reads and writes (`Z4`).
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn to_watchpoint_kind(self) -> WatchpointKind {
+        match self {
+            Self::Write => WatchpointKind::Write,
+            Self::Read => WatchpointKind::Read,
+            Self::ReadWrite => WatchpointKind::Access,
+        }
+    }
+
+    fn from_watchpoint_kind(kind: WatchpointKind) -> Self {
+        match kind {
+            WatchpointKind::Write => Self::Write,
+            WatchpointKind::Read => Self::Read,
+            WatchpointKind::Access => Self::ReadWrite,
+        }
+    }
+}
+
+/// A handle to a watchpoint added using [DebugMemory::set_watchpoint].
+pub type WatchId = WatchpointId;
+
+/// An access that triggered a watchpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    /// The watchpoint that was triggered.
+    pub id: WatchId,
+
+    /// The kind of the watchpoint that was triggered.
+    pub watch: WatchKind,
+
+    /// The kind of access that triggered the watchpoint, either [AccessKind::Read] or
+    /// [AccessKind::Write].
+    pub kind: AccessKind,
+
+    /// The address of the access, which may start before the watched range.
+    pub addr: u64,
+
+    /// The size of the access in bytes.
+    pub size: usize,
+
+    /// The value of the watched bytes touched by the access before the access (see
+    /// [WatchpointHit::old]).
+    pub old: Vec<u8>,
+
+    /// The value of the watched bytes touched by the access after the access.
+    pub new: Vec<u8>,
+}
+
+impl WatchHit {
+    fn new(hit: WatchpointHit) -> Self {
+        Self {
+            id: hit.id,
+            watch: WatchKind::from_watchpoint_kind(hit.watch),
+            kind: hit.kind,
+            addr: hit.addr,
+            size: hit.size,
+            old: hit.old,
+            new: hit.new,
+        }
+    }
+}
+
+/// Side-effect-free memory access and watchpoints for debuggers.
+pub trait DebugMemory {
+    /// Reads the bytes at `addr` into `buf` ignoring permissions, returning the number of bytes
+    /// read before the first byte that could not be accessed (equivalent to the `m` packet).
+    ///
+    /// Reads never modify the state of memory (see [Mmu::peek_bytes]). I/O regions are read using
+    /// [crate::IoMemory::peek], so the read stops at any I/O region where the handler does not
+    /// support it.
+    fn read_dbg(&mut self, addr: u64, buf: &mut [u8]) -> usize;
+
+    /// Writes `buf` to `addr` ignoring permissions, returning the number of bytes written before
+    /// the first byte that could not be accessed (equivalent to the `M` packet).
+    ///
+    /// Written bytes are marked as initialized. Any cached code in the modified range is
+    /// invalidated, and memory hooks and watchpoints are not triggered.
+    fn write_dbg(&mut self, addr: u64, buf: &[u8]) -> usize;
+
+    /// Adds a watchpoint for the `len` bytes starting at `addr` that is triggered by accesses of
+    /// `kind`, returning `None` if the range is empty or overflows the address space.
+    fn set_watchpoint(&mut self, addr: u64, len: u64, kind: WatchKind) -> Option<WatchId>;
+
+    /// Removes a watchpoint added using [DebugMemory::set_watchpoint].
+    fn remove_watchpoint(&mut self, id: WatchId) -> bool;
+
+    /// Returns the watchpoint for exactly `addr`, `len` and `kind`, since `gdbstub` removes
+    /// watchpoints using the same arguments they were added with.
+    fn find_watchpoint(&self, addr: u64, len: u64, kind: WatchKind) -> Option<WatchId>;
+
+    /// Returns (and clears) the accesses that triggered watchpoints, in the order they occurred.
+    fn take_watch_hits(&mut self) -> Vec<WatchHit>;
+}
+
+/// Returns the regions that overlap `len` bytes starting at `addr` (stopping at the end of the
+/// address space), ordered by address.
+fn regions(mmu: &Mmu, addr: u64, len: usize) -> Vec<(u64, usize)> {
+    let end = addr.saturating_add(len as u64 - 1);
+    let mut regions: Vec<_> = mmu
+        .mapping
+        .overlapping_iter(addr..=end)
+        .map(|(start, len, _)| (start, len as usize))
+        .collect();
+    regions.sort_unstable_by_key(|(start, ..)| *start);
+    regions
+}
+
+impl DebugMemory for Mmu {
+    fn read_dbg(&mut self, addr: u64, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+
+        let mut read = 0;
+        for (start, len) in regions(self, addr, buf.len()) {
+            if self.peek_bytes(start, &mut buf[read..read + len]).is_err() {
+                break;
+            }
+            read += len;
+        }
+        read
+    }
+
+    fn write_dbg(&mut self, addr: u64, buf: &[u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+
+        let mut written = 0;
+        for (start, len) in regions(self, addr, buf.len()) {
+            let chunk = &buf[written..written + len];
+            let result = self.with_code_patching(start, len as u64, |mmu| {
+                mmu.write_bytes(start, chunk, perm::NONE)
+            });
+            if !matches!(result, Ok(patch) if patch.result.is_ok()) {
+                break;
+            }
+            written += len;
+        }
+
+        written
+    }
+
+    fn set_watchpoint(&mut self, addr: u64, len: u64, kind: WatchKind) -> Option<WatchId> {
+        self.add_watchpoint(addr, len, kind.to_watchpoint_kind())
+    }
+
+    fn remove_watchpoint(&mut self, id: WatchId) -> bool {
+        Mmu::remove_watchpoint(self, id)
+    }
+
+    fn find_watchpoint(&self, addr: u64, len: u64, kind: WatchKind) -> Option<WatchId> {
+        let end = addr.checked_add(len.checked_sub(1)?)?;
+        self.watchpoints.find(addr, end, kind.to_watchpoint_kind())
+    }
+
+    fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        self.take_watchpoint_hits().into_iter().map(WatchHit::new).collect()
+    }
+}
@@ -0,0 +1,158 @@
+//! A read-only view of guest memory that can be shared with other threads (see
+//! [crate::Mmu::freeze]).
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hasher},
+    sync::Arc,
+};
+
+use crate::{
+    FileData, MemError, MemResult, MemoryMapping, Region,
+    mmu::{file_bytes, regions_of},
+    perm,
+    physical::{self, PageData},
+    range_map::RangeMap,
+};
+
+/// The state of guest memory at the point [crate::Mmu::freeze] was called.
+///
+/// The view holds references to the data of the physical pages that were mapped, so creating it
+/// does not copy memory, and pages are only copied once they are next modified by the MMU. All
+/// methods take `&self` and have no side effects, so the view can be shared between threads.
+pub struct FrozenMemory {
+    mapping: RangeMap<MemoryMapping>,
+    pages: HashMap<physical::Index, Arc<PageData>>,
+
+    /// The data of the files referenced by `mapping`, indexed by [crate::FileHandle].
+    files: Vec<FileData>,
+
+    /// The permissions added to unallocated memory, i.e. [perm::INIT] unless
+    /// [crate::Mmu::track_uninitialized] was set.
+    init: u8,
+    address_mask: u64,
+}
+
+impl FrozenMemory {
+    pub(crate) fn new(
+        mapping: RangeMap<MemoryMapping>,
+        pages: HashMap<physical::Index, Arc<PageData>>,
+        files: Vec<FileData>,
+        init: u8,
+        address_mask: u64,
+    ) -> Self {
+        Self { mapping, pages, files, init, address_mask }
+    }
+
+    /// Reads bytes from `addr` checking that the permissions specified by `perm` are set (see
+    /// [crate::Mmu::read_bytes]). Reading from I/O regions fails with [MemError::Unsupported].
+    pub fn read_bytes(&self, addr: u64, buf: &mut [u8], perm: u8) -> MemResult<()> {
+        self.for_each_chunk(addr, buf.len(), |start, entry, range| {
+            self.read_entry(start, entry, &mut buf[range], perm)
+        })
+    }
+
+    /// Reads a NUL terminated string starting at `addr` into `buf`, returning the address of the
+    /// terminator (see [crate::Mmu::read_cstr]).
+    pub fn read_cstr(&self, addr: u64, buf: &mut Vec<u8>) -> MemResult<u64> {
+        let mut addr = addr & self.address_mask;
+        loop {
+            let mut byte = [0];
+            self.read_bytes(addr, &mut byte, perm::READ)?;
+            match byte[0] {
+                0 => break,
+                x => buf.push(x),
+            }
+            addr = addr.wrapping_add(1) & self.address_mask;
+        }
+        Ok(addr)
+    }
+
+    /// Get the permission bits associated with the byte at `addr` (see [crate::Mmu::get_perm]).
+    pub fn get_perm(&self, addr: u64) -> u8 {
+        match self.mapping.get(addr & self.address_mask) {
+            Some(MemoryMapping::Physical(entry)) => {
+                self.pages[&entry.index].perm[PageData::offset(addr)]
+            }
+            Some(MemoryMapping::Unallocated(entry)) => entry.perm,
+            Some(MemoryMapping::File(entry)) => entry.perm,
+            Some(MemoryMapping::Io(entry)) => entry.perm,
+            Some(MemoryMapping::Reserved(_)) | None => perm::NONE,
+        }
+    }
+
+    /// Returns the regions of the address space ordered by address (see [crate::Mmu::regions]).
+    pub fn regions(&self) -> impl Iterator<Item = Region> + '_ {
+        regions_of(&self.mapping, |index| &*self.pages[&index])
+    }
+
+    /// Computes a hash of the data of the `len` bytes starting at `addr`, ignoring permissions.
+    /// Ranges with the same data always have the same hash, even if they were read from different
+    /// views.
+    pub fn hash_range(&self, addr: u64, len: u64) -> MemResult<u64> {
+        let len = usize::try_from(len).map_err(|_| MemError::AddressOverflow)?;
+        let mut hasher = DefaultHasher::new();
+        let mut buf = vec![0; physical::PAGE_SIZE];
+        self.for_each_chunk(addr, len, |start, entry, range| {
+            let buf = &mut buf[..range.len()];
+            self.read_entry(start, entry, buf, perm::NONE)?;
+            hasher.write(buf);
+            Ok(())
+        })?;
+        Ok(hasher.finish())
+    }
+
+    /// Calls `f` with the address, mapping and the range of offsets from `addr` for each chunk of
+    /// the `len` bytes starting at `addr`, where chunks never span a page boundary.
+    fn for_each_chunk(
+        &self,
+        addr: u64,
+        len: usize,
+        mut f: impl FnMut(u64, &MemoryMapping, std::ops::Range<usize>) -> MemResult<()>,
+    ) -> MemResult<()> {
+        let mut done = 0;
+        while done < len {
+            let start = addr.wrapping_add(done as u64) & self.address_mask;
+            let (_, end, entry) = self.mapping.get_with_range(start).ok_or(MemError::Unmapped)?;
+            let page_remaining = physical::PAGE_SIZE - PageData::offset(start);
+            let entry_remaining = (end - start).min(physical::PAGE_SIZE as u64) as usize + 1;
+            let chunk = page_remaining.min(entry_remaining).min(len - done);
+            f(start, entry, done..done + chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes from `addr` that are all part of `entry` and the same page.
+    fn read_entry(
+        &self,
+        addr: u64,
+        entry: &MemoryMapping,
+        buf: &mut [u8],
+        perm: u8,
+    ) -> MemResult<()> {
+        let offset = PageData::offset(addr);
+        match entry {
+            MemoryMapping::Physical(entry) => {
+                let page = &self.pages[&entry.index];
+                for byte_perm in &page.perm[offset..offset + buf.len()] {
+                    perm::check(*byte_perm, perm)?;
+                }
+                buf.copy_from_slice(&page.data[offset..offset + buf.len()]);
+            }
+            MemoryMapping::Unallocated(entry) => {
+                perm::check(entry.perm | perm::MAP | self.init, perm)?;
+                buf.fill(entry.value);
+            }
+            MemoryMapping::File(entry) => {
+                perm::check(entry.perm | perm::MAP, perm)?;
+                let data = file_bytes(&self.files, entry, addr, buf.len());
+                buf[..data.len()].copy_from_slice(data);
+                buf[data.len()..].fill(0);
+            }
+            MemoryMapping::Io(_) => return Err(MemError::Unsupported),
+            MemoryMapping::Reserved(_) => return Err(MemError::Unmapped),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+//! A simple allocator for guest heaps (e.g. for implementing `malloc` and `free` hooks), that
+//! manages chunks of memory within an arena reserved from the guest address space (see
+//! [GuestHeap::new]).
+//!
+//! All metadata used by the allocator is stored on the host, so the guest can not corrupt the
+//! state of the heap by writing past the end of a chunk. Parts of the arena that have never been
+//! allocated, and the padding and guard pages around each chunk, are reserved, so accesses to them
+//! fail with [MemError::Reserved]. Freed chunks can be kept inaccessible for a while (see
+//! [GuestHeap::quarantine_size]) to detect use-after-free bugs.
+//!
+//! The state of the heap is not part of [crate::Mmu::snapshot], instead [GuestHeap::snapshot]
+//! should be captured (and restored) alongside the snapshot of the MMU.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{
+    AllocLayout, MapErrorKind, Mapping, MemError, MemResult, MemoryMapping, Mmu,
+    mmu::checked_align_up, perm,
+};
+
+/// The minimum alignment of every chunk.
+pub const MIN_ALIGN: u64 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Chunk {
+    /// The size requested for the chunk.
+    size: u64,
+
+    /// The alignment requested for the chunk.
+    align: u64,
+
+    /// The permissions the chunk was allocated with.
+    perm: u8,
+
+    /// The (exclusive) range of the arena used by the chunk, including padding and guard pages.
+    span: (u64, u64),
+}
+
+#[derive(Clone, Default)]
+struct HeapState {
+    /// Allocated chunks, keyed by their address.
+    chunks: BTreeMap<u64, Chunk>,
+
+    /// The (exclusive) ranges of the arena that are free, keyed by the start of the range.
+    free: BTreeMap<u64, u64>,
+
+    /// Freed chunks that are kept inaccessible, ordered from oldest to newest.
+    quarantine: VecDeque<(u64, Chunk)>,
+
+    /// The total size of the chunks in `quarantine`.
+    quarantined_bytes: u64,
+}
+
+/// A copy of the state of a heap, captured by [GuestHeap::snapshot].
+#[derive(Clone)]
+pub struct HeapSnapshot {
+    arena: (u64, u64),
+    state: HeapState,
+}
+
+/// A heap within a region of guest memory.
+pub struct GuestHeap {
+    /// The number of guard pages to reserve before and after each chunk. The bytes between the
+    /// end of a chunk and the guard pages after it are also reserved, so even an overflow of a
+    /// single byte faults.
+    pub guard_pages: u64,
+
+    /// If set, freed chunks are filled with this value.
+    pub poison: Option<u8>,
+
+    /// The maximum number of bytes of freed chunks to keep in quarantine. Quarantined chunks are
+    /// made inaccessible (i.e. [perm::NONE]) and are not reused until they are evicted from the
+    /// quarantine, oldest first.
+    pub quarantine_size: u64,
+
+    /// The permissions of newly allocated chunks (a combination of [perm::READ], [perm::WRITE]
+    /// and [perm::EXEC]).
+    pub perm: u8,
+
+    /// The (exclusive) range of the arena.
+    arena: (u64, u64),
+
+    state: HeapState,
+}
+
+impl GuestHeap {
+    /// Creates a heap using an arena of `base_layout.size` bytes of address space, placed
+    /// according to `base_layout` (see [Mmu::find_free_memory]). The arena is reserved (see
+    /// [Mmu::reserve]), and memory is only mapped for chunks as they are allocated.
+    pub fn new(mmu: &mut Mmu, base_layout: AllocLayout) -> MemResult<Self> {
+        let start = mmu.find_free_memory(base_layout)?;
+        if !mmu.reserve(start, base_layout.size) {
+            return Err(MemError::OutOfMemory);
+        }
+        mmu.advance_alloc_rng();
+        let end = start + base_layout.size;
+
+        let mut state = HeapState::default();
+        state.free.insert(start, end);
+        Ok(Self {
+            guard_pages: 0,
+            poison: None,
+            quarantine_size: 0,
+            perm: perm::READ | perm::WRITE,
+            arena: (start, end),
+            state,
+        })
+    }
+
+    /// Returns the (inclusive) range of addresses used by the heap.
+    pub fn arena(&self) -> (u64, u64) {
+        (self.arena.0, self.arena.1 - 1)
+    }
+
+    /// Returns the size of the chunk allocated at `addr`, or `None` if there is no chunk at
+    /// `addr`.
+    pub fn usable_size(&self, addr: u64) -> Option<u64> {
+        self.state.chunks.get(&addr).map(|chunk| chunk.size)
+    }
+
+    /// Returns the total number of bytes in chunks that are currently allocated.
+    pub fn allocated_bytes(&self) -> u64 {
+        self.state.chunks.values().map(|chunk| chunk.size).sum()
+    }
+
+    /// Allocates a chunk of `size` bytes aligned to `align` (rounded up to a power of two, and to
+    /// at least [MIN_ALIGN]), returning the address of the chunk. Newly allocated chunks are
+    /// zeroed.
+    ///
+    /// Returns [MemError::InvalidRange] if `size` is zero, and [MemError::OutOfMemory] if there
+    /// is no free region of the arena that is large enough.
+    pub fn alloc(&mut self, mmu: &mut Mmu, size: u64, align: u64) -> MemResult<u64> {
+        if size == 0 {
+            return Err(MemError::InvalidRange);
+        }
+
+        let page_size = mmu.page_size();
+        let (granule, guard_len) = match self.guard_pages {
+            0 => (MIN_ALIGN, 0),
+            pages => (page_size, pages.checked_mul(page_size).ok_or(MemError::OutOfMemory)?),
+        };
+        let align = align.checked_next_power_of_two().ok_or(MemError::OutOfMemory)?.max(MIN_ALIGN);
+        let body_len = checked_align_up(size, granule).ok_or(MemError::OutOfMemory)?;
+
+        // Find the first free range that the chunk (and its guard pages) fits in.
+        let fit = |(&free_start, &free_end): (&u64, &u64)| {
+            let addr = checked_align_up(free_start.checked_add(guard_len)?, align.max(granule))?;
+            let span_end = addr.checked_add(body_len)?.checked_add(guard_len)?;
+            (span_end <= free_end).then_some((free_start, free_end, addr, span_end))
+        };
+        let (free_start, free_end, addr, span_end) =
+            self.state.free.iter().find_map(fit).ok_or(MemError::OutOfMemory)?;
+        let span = (addr - guard_len, span_end);
+
+        // Map the chunk, reserving the rest of the span. Existing mappings in the span (e.g. from
+        // chunks that were previously freed) are replaced.
+        let owner = self.arena.0;
+        let mapping = Mapping { perm: self.perm | perm::MAP | perm::INIT, value: 0 };
+        let mut regions = vec![(addr, size, mapping.into())];
+        if span.0 < addr {
+            regions.push((span.0, addr - span.0, MemoryMapping::Reserved(owner)));
+        }
+        if addr + size < span.1 {
+            regions.push((addr + size, span.1 - (addr + size), MemoryMapping::Reserved(owner)));
+        }
+        mmu.map_regions(&regions, true).map_err(|err| match err.kind {
+            MapErrorKind::WriteExecViolation => MemError::WriteExecViolation,
+            _ => MemError::OutOfMemory,
+        })?;
+
+        self.state.free.remove(&free_start);
+        if free_start < span.0 {
+            self.state.free.insert(free_start, span.0);
+        }
+        if span.1 < free_end {
+            self.state.free.insert(span.1, free_end);
+        }
+        self.state.chunks.insert(addr, Chunk { size, align, perm: self.perm, span });
+        Ok(addr)
+    }
+
+    /// Frees the chunk at `addr`, returning [MemError::Unallocated] if there is no chunk
+    /// allocated at `addr` (e.g. because it has already been freed).
+    ///
+    /// The chunk is filled with [GuestHeap::poison] (if set), then either placed in quarantine or
+    /// released so that it can be reused by later allocations. Released chunks remain accessible
+    /// until they are reused.
+    pub fn free(&mut self, mmu: &mut Mmu, addr: u64) -> MemResult<()> {
+        let chunk = self.state.chunks.remove(&addr).ok_or(MemError::Unallocated)?;
+        if let Some(value) = self.poison {
+            mmu.fill_mem(addr, chunk.size, value)?;
+        }
+        if self.quarantine_size == 0 {
+            self.release(addr, chunk);
+            return Ok(());
+        }
+
+        mmu.set_protection(addr, chunk.size, perm::NONE)?;
+        self.state.quarantine.push_back((addr, chunk));
+        self.state.quarantined_bytes += chunk.size;
+        while self.state.quarantined_bytes > self.quarantine_size {
+            self.evict(mmu)?;
+        }
+        Ok(())
+    }
+
+    /// Resizes the chunk at `addr` to `size` bytes, returning the address of the resized chunk.
+    ///
+    /// The chunk is always moved to a new allocation (with the same alignment) and the old chunk
+    /// is freed, so stale pointers to the old chunk are detected by the quarantine. If `size` is
+    /// larger than the old size, the extra bytes are zeroed.
+    pub fn realloc(&mut self, mmu: &mut Mmu, addr: u64, size: u64) -> MemResult<u64> {
+        let chunk = *self.state.chunks.get(&addr).ok_or(MemError::Unallocated)?;
+        let new_addr = self.alloc(mmu, size, chunk.align)?;
+
+        let mut buf = vec![0; chunk.size.min(size) as usize];
+        mmu.read_bytes(addr, &mut buf, perm::NONE)?;
+        mmu.write_bytes(new_addr, &buf, perm::NONE)?;
+
+        self.free(mmu, addr)?;
+        Ok(new_addr)
+    }
+
+    /// Releases every chunk in quarantine.
+    pub fn flush_quarantine(&mut self, mmu: &mut Mmu) -> MemResult<()> {
+        while !self.state.quarantine.is_empty() {
+            self.evict(mmu)?;
+        }
+        Ok(())
+    }
+
+    /// Releases the oldest chunk in quarantine, restoring the permissions of the chunk.
+    fn evict(&mut self, mmu: &mut Mmu) -> MemResult<()> {
+        let Some((addr, chunk)) = self.state.quarantine.pop_front()
+        else {
+            return Ok(());
+        };
+        self.state.quarantined_bytes -= chunk.size;
+        mmu.set_protection(addr, chunk.size, chunk.perm)?;
+        self.release(addr, chunk);
+        Ok(())
+    }
+
+    /// Returns the span used by `chunk` to the free list, merging it with adjacent free ranges.
+    fn release(&mut self, addr: u64, chunk: Chunk) {
+        tracing::trace!("heap: releasing {addr:#x} ({:#x?})", chunk.span);
+        let (mut start, mut end) = chunk.span;
+        if let Some((&prev_start, &prev_end)) = self.state.free.range(..start).next_back() {
+            if prev_end == start {
+                self.state.free.remove(&prev_start);
+                start = prev_start;
+            }
+        }
+        if let Some(next_end) = self.state.free.remove(&end) {
+            end = next_end;
+        }
+        self.state.free.insert(start, end);
+    }
+
+    /// Captures the current state of the heap.
+    pub fn snapshot(&self) -> HeapSnapshot {
+        HeapSnapshot { arena: self.arena, state: self.state.clone() }
+    }
+
+    /// Restores the state of the heap from `snapshot`, which must have been captured from this
+    /// heap. The memory of the heap is not modified, so the snapshot of the MMU that was captured
+    /// at the same time should also be restored.
+    pub fn restore(&mut self, snapshot: &HeapSnapshot) {
+        assert_eq!(snapshot.arena, self.arena, "snapshot was captured from a different heap");
+        self.state = snapshot.state.clone();
+    }
+}
@@ -0,0 +1,168 @@
+//! A pool of page data backed by anonymous memory mapped from the host (see
+//! [crate::physical::PageStore::Mmap]).
+//!
+//! The pool reserves address space for a fixed number of slots when it is created, but the host
+//! only commits memory for a slot once it is written to. Slots that are no longer referenced are
+//! returned to the host by [HostPool::release_free].
+
+use std::{
+    cell::{Cell, RefCell},
+    ptr::NonNull,
+    rc::Rc,
+};
+
+use crate::physical::PageData;
+
+const SLOT_SIZE: usize = std::mem::size_of::<PageData>();
+
+pub(crate) struct HostPool {
+    base: NonNull<PageData>,
+    slots: usize,
+
+    /// The number of references to each slot.
+    counts: Box<[Cell<u32>]>,
+
+    /// Set for slots that may contain non-zero data, i.e., slots that have been used since they
+    /// were last released to the host.
+    dirty: Box<[Cell<bool>]>,
+
+    /// Slots that are not currently referenced.
+    free: RefCell<Vec<u32>>,
+}
+
+impl HostPool {
+    /// Reserves address space for `slots` pages, returning `None` if the reservation fails.
+    pub fn new(slots: usize) -> Option<Rc<Self>> {
+        let len = slots.checked_mul(SLOT_SIZE)?;
+        if len == 0 {
+            return None;
+        }
+
+        // Safety: creating a new private anonymous mapping does not affect any existing memory.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            tracing::warn!("failed to reserve {len:#x} bytes for physical memory");
+            return None;
+        }
+
+        Some(Rc::new(Self {
+            base: NonNull::new(ptr.cast()).unwrap(),
+            slots,
+            counts: (0..slots).map(|_| Cell::new(0)).collect(),
+            dirty: (0..slots).map(|_| Cell::new(false)).collect(),
+            free: RefCell::new((0..slots as u32).rev().collect()),
+        }))
+    }
+
+    /// Allocates a zeroed slot, returning `None` if every slot is in use.
+    pub fn alloc(self: &Rc<Self>) -> Option<PooledData> {
+        let slot = self.free.borrow_mut().pop()?;
+        self.counts[slot as usize].set(1);
+        let data = PooledData { pool: self.clone(), slot };
+        if self.dirty[slot as usize].replace(true) {
+            // Safety: the slot was free, so there are no other references to it.
+            unsafe { data.ptr().as_ptr().write_bytes(0, 1) };
+        }
+        Some(data)
+    }
+
+    /// Returns the memory used by slots that are not referenced to the host.
+    pub fn release_free(&self) {
+        let mut free: Vec<_> = self.free.borrow().iter().copied().collect();
+        free.retain(|slot| self.dirty[*slot as usize].replace(false));
+        free.sort_unstable();
+
+        let mut i = 0;
+        while i < free.len() {
+            // Release adjacent slots with a single call.
+            let start = free[i];
+            let mut end = start;
+            while free.get(i + 1) == Some(&(end + 1)) {
+                end += 1;
+                i += 1;
+            }
+            i += 1;
+
+            let count = (end - start + 1) as usize;
+            // Safety: the slots are free, so they are not referenced by any page.
+            unsafe {
+                let ptr = self.base.as_ptr().add(start as usize).cast();
+                libc::madvise(ptr, count * SLOT_SIZE, libc::MADV_DONTNEED);
+            }
+        }
+    }
+
+    /// Returns the number of host pages that are currently committed for the pool.
+    pub fn committed_pages(&self) -> usize {
+        // Safety: `sysconf` has no preconditions.
+        let host_page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = self.slots * SLOT_SIZE;
+        let mut residency = vec![0_u8; len.div_ceil(host_page_size)];
+        // Safety: `residency` has an entry for every host page in the mapping.
+        let result =
+            unsafe { libc::mincore(self.base.as_ptr().cast(), len, residency.as_mut_ptr().cast()) };
+        if result != 0 {
+            return 0;
+        }
+        residency.iter().filter(|x| *x & 1 != 0).count()
+    }
+}
+
+impl Drop for HostPool {
+    fn drop(&mut self) {
+        // Safety: every `PooledData` holds a reference to the pool, so no slots are in use.
+        unsafe { libc::munmap(self.base.as_ptr().cast(), self.slots * SLOT_SIZE) };
+    }
+}
+
+/// A reference counted handle to the data stored in a slot of a [HostPool].
+pub(crate) struct PooledData {
+    pool: Rc<HostPool>,
+    slot: u32,
+}
+
+impl PooledData {
+    pub fn ptr(&self) -> NonNull<PageData> {
+        // Safety: `slot` is always in bounds.
+        unsafe { NonNull::new_unchecked(self.pool.base.as_ptr().add(self.slot as usize)) }
+    }
+
+    pub fn pool(&self) -> &Rc<HostPool> {
+        &self.pool
+    }
+
+    pub fn ref_count(&self) -> u32 {
+        self.pool.counts[self.slot as usize].get()
+    }
+
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.pool, &other.pool) && self.slot == other.slot
+    }
+}
+
+impl Clone for PooledData {
+    fn clone(&self) -> Self {
+        let count = &self.pool.counts[self.slot as usize];
+        count.set(count.get() + 1);
+        Self { pool: self.pool.clone(), slot: self.slot }
+    }
+}
+
+impl Drop for PooledData {
+    fn drop(&mut self) {
+        let count = &self.pool.counts[self.slot as usize];
+        count.set(count.get() - 1);
+        if count.get() == 0 {
+            self.pool.free.borrow_mut().push(self.slot);
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! Loading memory images described by a list of segments (see [crate::Mmu::load_image]).
+//!
+//! # Manifest format
+//!
+//! [crate::Mmu::load_image_from_reader] reads segments from a binary manifest, where all integers
+//! are little-endian:
+//!
+//! | Field         | Size         | Description                                              |
+//! |---------------|--------------|----------------------------------------------------------|
+//! | magic         | 4            | [MANIFEST_MAGIC]                                         |
+//! | version       | 4            | [MANIFEST_VERSION]                                       |
+//! | count         | 4            | The number of segments                                   |
+//! | reserved      | 4            | Must be zero                                             |
+//! | segment table | 32 * `count` | An entry for each segment (see below)                    |
+//! | contents      |              | The name followed by the data of each segment, in order  |
+//!
+//! Each entry of the segment table is made up of:
+//!
+//! | Field    | Size | Description                                                          |
+//! |----------|------|----------------------------------------------------------------------|
+//! | addr     | 8    | The address of the segment                                           |
+//! | size     | 8    | The number of bytes mapped for the segment                           |
+//! | data_len | 8    | The number of bytes of data, which must not be greater than `size`   |
+//! | name_len | 4    | The length of the (UTF-8) name of the segment                        |
+//! | perm     | 4    | A combination of [perm::READ], [perm::WRITE] and [perm::EXEC]        |
+//!
+//! Manifests can be created using [write_manifest].
+
+use std::{
+    borrow::Cow,
+    io::{self, Read, Write},
+};
+
+use crate::{MapErrorKind, Mapping, MemError, MemoryMapping, Mmu, perm};
+
+/// The magic bytes at the start of a manifest.
+pub const MANIFEST_MAGIC: [u8; 4] = *b"ICIM";
+
+/// The version of the manifest format supported by [crate::Mmu::load_image_from_reader].
+pub const MANIFEST_VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 32;
+
+/// A region of memory to load.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment<'a> {
+    /// The address of the first byte of the segment.
+    pub addr: u64,
+
+    /// The number of bytes to map. Bytes past the end of `data` are zero (e.g. for `.bss`).
+    pub size: u64,
+
+    /// The initial contents of the segment.
+    pub data: Cow<'a, [u8]>,
+
+    /// The permissions of the segment, a combination of [perm::READ], [perm::WRITE] and
+    /// [perm::EXEC].
+    pub perm: u8,
+
+    /// The name of the segment, used for reporting.
+    pub name: String,
+}
+
+impl<'a> Segment<'a> {
+    /// Creates a segment where the number of bytes mapped is the same as the length of `data`.
+    pub fn new(
+        name: impl Into<String>,
+        addr: u64,
+        data: impl Into<Cow<'a, [u8]>>,
+        perm: u8,
+    ) -> Self {
+        let data = data.into();
+        Self { addr, size: data.len() as u64, data, perm, name: name.into() }
+    }
+
+    /// Returns the last address of the segment, or `None` if the segment is empty or extends past
+    /// the end of the address space.
+    fn end(&self) -> Option<u64> {
+        self.addr.checked_add(self.size.checked_sub(1)?)
+    }
+}
+
+/// A segment that was loaded by [crate::Mmu::load_image].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoadedSegment {
+    /// The name of the segment.
+    pub name: String,
+
+    /// The first address of the segment.
+    pub start: u64,
+
+    /// The last address of the segment (inclusive).
+    pub end: u64,
+
+    /// The number of bytes copied from the data of the segment, the remaining bytes are zero.
+    pub data_len: u64,
+}
+
+/// Describes the regions mapped by [crate::Mmu::load_image].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// The segments that were loaded, ordered by address.
+    pub segments: Vec<LoadedSegment>,
+
+    /// The names of zero-length segments, which are not mapped.
+    pub skipped: Vec<String>,
+
+    /// The (inclusive) ranges of bytes that were mapped without any permissions, in order to
+    /// page-align the regions mapped for the segments.
+    pub padding: Vec<(u64, u64)>,
+}
+
+/// The error returned when an image could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The data of the segment is larger than its size, or the segment extends past the end of
+    /// the address space.
+    InvalidSegment(String),
+
+    /// The segment has permissions other than [perm::READ], [perm::WRITE] and [perm::EXEC].
+    InvalidPerm(String),
+
+    /// The two segments overlap with each other.
+    Overlap(String, String),
+
+    /// The segment overlaps with memory that is already mapped.
+    AlreadyMapped(String),
+
+    /// The segment could not be mapped (e.g. because of [crate::Mmu::wx_policy]).
+    Map(String, MapErrorKind),
+
+    /// The data of the segment could not be written to memory.
+    Write(String, MemError),
+
+    /// The manifest is not valid.
+    InvalidManifest(&'static str),
+
+    /// The manifest could not be read.
+    Io(io::Error),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidSegment(name) => write!(f, "segment `{name}` has an invalid range"),
+            Self::InvalidPerm(name) => write!(f, "segment `{name}` has invalid permissions"),
+            Self::Overlap(a, b) => write!(f, "segment `{a}` overlaps with segment `{b}`"),
+            Self::AlreadyMapped(name) => write!(f, "segment `{name}` overlaps with mapped memory"),
+            Self::Map(name, kind) => write!(f, "failed to map segment `{name}`: {kind:?}"),
+            Self::Write(name, err) => write!(f, "failed to write segment `{name}`: {err}"),
+            Self::InvalidManifest(reason) => write!(f, "invalid manifest: {reason}"),
+            Self::Io(err) => write!(f, "failed to read manifest: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Self::InvalidManifest("unexpected end of file"),
+            _ => Self::Io(err),
+        }
+    }
+}
+
+/// Removes the ranges in `used` (sorted and non-overlapping) from `start..=end`.
+fn subtract(start: u64, end: u64, used: &[(u64, u64)], out: &mut Vec<(u64, u64)>) {
+    let mut next = Some(start);
+    for &(used_start, used_end) in used {
+        let Some(current) = next
+        else {
+            return;
+        };
+        if used_end < current || end < used_start {
+            continue;
+        }
+        if current < used_start {
+            out.push((current, used_start - 1));
+        }
+        next = used_end.checked_add(1);
+    }
+    if let Some(current) = next.filter(|x| *x <= end) {
+        out.push((current, end));
+    }
+}
+
+pub(crate) fn load(mmu: &mut Mmu, segments: &[Segment]) -> Result<LoadReport, LoadError> {
+    let mut report = LoadReport::default();
+
+    // Validate every segment before anything is mapped.
+    let mut ranges = vec![];
+    for (index, segment) in segments.iter().enumerate() {
+        if segment.perm & !(perm::READ | perm::WRITE | perm::EXEC) != 0 {
+            return Err(LoadError::InvalidPerm(segment.name.clone()));
+        }
+        if segment.size == 0 && segment.data.is_empty() {
+            report.skipped.push(segment.name.clone());
+            continue;
+        }
+        let end = segment
+            .end()
+            .filter(|_| segment.data.len() as u64 <= segment.size)
+            .ok_or_else(|| LoadError::InvalidSegment(segment.name.clone()))?;
+        ranges.push((segment.addr, end, index));
+    }
+    ranges.sort_unstable();
+    for pair in ranges.windows(2) {
+        if pair[1].0 <= pair[0].1 {
+            let (a, b) = (&segments[pair[0].2].name, &segments[pair[1].2].name);
+            return Err(LoadError::Overlap(a.clone(), b.clone()));
+        }
+    }
+    for &(start, end, index) in &ranges {
+        if mmu.mapping.overlapping_iter(start..=end).any(|(.., x)| x.is_some()) {
+            return Err(LoadError::AlreadyMapped(segments[index].name.clone()));
+        }
+    }
+
+    // The rest of each page that contains a segment is mapped without any permissions, unless it
+    // is part of another segment or is already mapped.
+    let page_mask = mmu.page_size() - 1;
+    let mut pages: Vec<(u64, u64)> = vec![];
+    for &(start, end, _) in &ranges {
+        let (page_start, page_end) = (start & !page_mask, end | page_mask);
+        match pages.last_mut() {
+            Some(last) if page_start <= last.1 => last.1 = page_end,
+            _ => pages.push((page_start, page_end)),
+        }
+    }
+    let used: Vec<_> = ranges.iter().map(|&(start, end, _)| (start, end)).collect();
+    for (page_start, page_end) in pages {
+        for (start, len, entry) in mmu.mapping.overlapping_iter(page_start..=page_end) {
+            if entry.is_none() {
+                subtract(start, start + (len - 1), &used, &mut report.padding);
+            }
+        }
+    }
+    report.padding.sort_unstable();
+
+    let mut regions: Vec<_> = ranges
+        .iter()
+        .map(|&(start, end, index)| {
+            let mapping = Mapping { perm: segments[index].perm | perm::INIT, value: 0 };
+            (start, end - start + 1, MemoryMapping::from(mapping))
+        })
+        .collect();
+    let padding = Mapping { perm: perm::NONE, value: 0 };
+    regions.extend(
+        report.padding.iter().map(|&(start, end)| (start, end - start + 1, padding.into())),
+    );
+    mmu.map_regions(&regions, false).map_err(|err| {
+        let name = match ranges.get(err.index) {
+            Some(&(.., index)) => segments[index].name.clone(),
+            None => format!("padding at {:#x}", regions[err.index].0),
+        };
+        match err.kind {
+            MapErrorKind::OverlapsMapping => LoadError::AlreadyMapped(name),
+            kind => LoadError::Map(name, kind),
+        }
+    })?;
+
+    // Write the data of each segment, ignoring permissions so that read-only segments can be
+    // initialized.
+    for &(start, end, index) in &ranges {
+        let segment = &segments[index];
+        mmu.write_bytes(start, &segment.data, perm::NONE)
+            .map_err(|err| LoadError::Write(segment.name.clone(), err))?;
+        report.segments.push(LoadedSegment {
+            name: segment.name.clone(),
+            start,
+            end,
+            data_len: segment.data.len() as u64,
+        });
+    }
+
+    Ok(report)
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads the segments from a manifest (see the [module documentation](self)).
+pub(crate) fn read_manifest(mut reader: impl Read) -> Result<Vec<Segment<'static>>, LoadError> {
+    let mut header = [0; HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    if header[..4] != MANIFEST_MAGIC {
+        return Err(LoadError::InvalidManifest("invalid magic"));
+    }
+    if u32_at(&header, 4) != MANIFEST_VERSION {
+        return Err(LoadError::InvalidManifest("unsupported version"));
+    }
+    if u32_at(&header, 12) != 0 {
+        return Err(LoadError::InvalidManifest("reserved field is not zero"));
+    }
+
+    let count = u32_at(&header, 8) as usize;
+    let mut table = vec![];
+    (&mut reader).take((count * ENTRY_SIZE) as u64).read_to_end(&mut table)?;
+    if table.len() != count * ENTRY_SIZE {
+        return Err(LoadError::InvalidManifest("unexpected end of file"));
+    }
+
+    let mut segments = Vec::with_capacity(count);
+    for entry in table.chunks_exact(ENTRY_SIZE) {
+        let (addr, size, data_len) = (u64_at(entry, 0), u64_at(entry, 8), u64_at(entry, 16));
+        let (name_len, perm) = (u32_at(entry, 24), u32_at(entry, 28));
+        if data_len > size {
+            return Err(LoadError::InvalidManifest("segment data is larger than the segment"));
+        }
+        let perm = u8::try_from(perm).map_err(|_| LoadError::InvalidManifest("invalid perm"))?;
+
+        // Read using `take` to avoid allocating a buffer of an arbitrary size before the data has
+        // been read.
+        let mut name = vec![];
+        (&mut reader).take(name_len as u64).read_to_end(&mut name)?;
+        let mut data = vec![];
+        (&mut reader).take(data_len).read_to_end(&mut data)?;
+        if name.len() != name_len as usize || data.len() as u64 != data_len {
+            return Err(LoadError::InvalidManifest("unexpected end of file"));
+        }
+        let name =
+            String::from_utf8(name).map_err(|_| LoadError::InvalidManifest("invalid name"))?;
+        segments.push(Segment { addr, size, data: Cow::Owned(data), perm, name });
+    }
+    Ok(segments)
+}
+
+/// Writes `segments` to `writer` as a manifest that can be loaded with
+/// [crate::Mmu::load_image_from_reader].
+pub fn write_manifest(mut writer: impl Write, segments: &[Segment]) -> io::Result<()> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let count = u32::try_from(segments.len()).map_err(|_| invalid("too many segments"))?;
+
+    let mut header = Vec::with_capacity(HEADER_SIZE + segments.len() * ENTRY_SIZE);
+    header.extend_from_slice(&MANIFEST_MAGIC);
+    header.extend_from_slice(&MANIFEST_VERSION.to_le_bytes());
+    header.extend_from_slice(&count.to_le_bytes());
+    header.extend_from_slice(&0_u32.to_le_bytes());
+    for segment in segments {
+        let name_len = u32::try_from(segment.name.len()).map_err(|_| invalid("name too long"))?;
+        header.extend_from_slice(&segment.addr.to_le_bytes());
+        header.extend_from_slice(&segment.size.to_le_bytes());
+        header.extend_from_slice(&(segment.data.len() as u64).to_le_bytes());
+        header.extend_from_slice(&name_len.to_le_bytes());
+        header.extend_from_slice(&(segment.perm as u32).to_le_bytes());
+    }
+    writer.write_all(&header)?;
+
+    for segment in segments {
+        writer.write_all(segment.name.as_bytes())?;
+        writer.write_all(&segment.data)?;
+    }
+    writer.flush()
+}
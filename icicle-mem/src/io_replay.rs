@@ -0,0 +1,295 @@
+//! Recording and replaying the values read from I/O regions (see
+//! [crate::Mmu::start_io_recording]).
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::{IoHandler, perm::MemError};
+
+/// The size (in bytes) of each record in a stream.
+const RECORD_SIZE: u64 = 48;
+
+/// A read from an I/O handler that was recorded by [crate::Mmu::start_io_recording].
+///
+/// Records are stored in the stream as 48 bytes, containing each field (in the order they are
+/// declared) in little-endian byte order, with the handler stored as a `u64`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoRecord {
+    /// The handler that serviced the read.
+    pub handler: IoHandler,
+
+    /// The address of the read.
+    pub addr: u64,
+
+    /// The number of bytes read.
+    pub size: u64,
+
+    /// The value that was read (in little-endian byte order), truncated to 16 bytes.
+    pub value: u128,
+
+    /// The position of the record in the stream.
+    pub seq: u64,
+}
+
+impl IoRecord {
+    fn to_bytes(self) -> [u8; RECORD_SIZE as usize] {
+        let mut bytes = [0; RECORD_SIZE as usize];
+        bytes[0..8].copy_from_slice(&(self.handler.0 as u64).to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.addr.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.size.to_le_bytes());
+        bytes[24..40].copy_from_slice(&self.value.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.seq.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; RECORD_SIZE as usize]) -> Self {
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        Self {
+            handler: IoHandler(u64_at(0) as usize),
+            addr: u64_at(8),
+            size: u64_at(16),
+            value: u128::from_le_bytes(bytes[24..40].try_into().unwrap()),
+            seq: u64_at(40),
+        }
+    }
+}
+
+/// The reason that replaying a stream of I/O reads failed (see [crate::Mmu::take_io_replay_error]).
+#[derive(Debug)]
+pub enum IoReplayError {
+    /// The read performed by the guest does not match the next read in the stream.
+    Diverged {
+        /// The next read in the stream.
+        expected: IoRecord,
+
+        /// The handler the guest read from.
+        handler: IoHandler,
+
+        /// The address the guest read from.
+        addr: u64,
+
+        /// The number of bytes the guest read.
+        size: u64,
+    },
+
+    /// The guest performed more reads than were recorded in the stream.
+    EndOfStream { seq: u64 },
+
+    /// An error occurred reading from the stream.
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for IoReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Diverged { expected, handler, addr, size } => write!(
+                f,
+                "I/O replay diverged at read {}: expected a {} byte read from handler {} at \
+                 {:#x}, but the guest performed a {size} byte read from handler {} at {addr:#x}",
+                expected.seq, expected.size, expected.handler.0, expected.addr, handler.0
+            ),
+            Self::EndOfStream { seq } => {
+                write!(f, "I/O replay diverged at read {seq}: the stream has no more reads")
+            }
+            Self::Io(err) => write!(f, "failed to read I/O replay stream: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for IoReplayError {}
+
+trait WriteSeek: Write + Seek {}
+
+impl<T: Write + Seek> WriteSeek for T {}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+enum Stream {
+    Record(Box<dyn WriteSeek>),
+    Replay(Box<dyn ReadSeek>),
+}
+
+/// The state of the active I/O recording or replay.
+pub(crate) struct IoReplay {
+    stream: Option<Stream>,
+
+    /// The offset of the first record in the stream.
+    base: u64,
+
+    /// The number of reads that have been recorded or replayed.
+    pos: u64,
+
+    /// The first error that occurred writing to the stream, after which nothing more is recorded.
+    record_error: Option<std::io::Error>,
+
+    /// The error that stopped the replay, every read fails until the replay is stopped or a
+    /// snapshot is restored.
+    replay_error: Option<IoReplayError>,
+}
+
+impl IoReplay {
+    pub fn new() -> Self {
+        Self { stream: None, base: 0, pos: 0, record_error: None, replay_error: None }
+    }
+
+    #[inline(always)]
+    pub fn is_active(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    #[inline]
+    pub fn is_replaying(&self) -> bool {
+        matches!(self.stream, Some(Stream::Replay(_)))
+    }
+
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn start_recording(
+        &mut self,
+        mut writer: impl Write + Seek + 'static,
+    ) -> std::io::Result<()> {
+        self.base = writer.stream_position()?;
+        self.start(Stream::Record(Box::new(writer)));
+        Ok(())
+    }
+
+    pub fn start_replay(&mut self, mut reader: impl Read + Seek + 'static) -> std::io::Result<()> {
+        self.base = reader.stream_position()?;
+        self.start(Stream::Replay(Box::new(reader)));
+        Ok(())
+    }
+
+    fn start(&mut self, stream: Stream) {
+        self.stream = Some(stream);
+        self.pos = 0;
+        self.record_error = None;
+        self.replay_error = None;
+    }
+
+    /// Stops recording, returning the offset of the end of the last record in the stream.
+    pub fn stop_recording(&mut self) -> std::io::Result<u64> {
+        let Some(Stream::Record(mut writer)) = self.stream.take()
+        else {
+            return Err(std::io::Error::other("I/O reads are not being recorded"));
+        };
+        if let Some(err) = self.record_error.take() {
+            return Err(err);
+        }
+        writer.flush()?;
+        Ok(self.end_offset())
+    }
+
+    pub fn stop_replay(&mut self) {
+        if self.is_replaying() {
+            self.stream = None;
+        }
+    }
+
+    pub fn take_replay_error(&mut self) -> Option<IoReplayError> {
+        self.replay_error.take()
+    }
+
+    fn end_offset(&self) -> u64 {
+        self.base + self.pos * RECORD_SIZE
+    }
+
+    /// Records a read of `value` from `addr`.
+    #[cold]
+    pub fn record(&mut self, id: usize, addr: u64, value: &[u8]) {
+        let Some(Stream::Record(writer)) = self.stream.as_mut()
+        else {
+            return;
+        };
+        if self.record_error.is_some() {
+            return;
+        }
+
+        let mut bytes = [0; 16];
+        let len = value.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&value[..len]);
+        let record = IoRecord {
+            handler: IoHandler(id),
+            addr,
+            size: value.len() as u64,
+            value: u128::from_le_bytes(bytes),
+            seq: self.pos,
+        };
+        match writer.write_all(&record.to_bytes()) {
+            Ok(()) => self.pos += 1,
+            Err(err) => self.record_error = Some(err),
+        }
+    }
+
+    /// Replays a read from `addr` into `buf`, returning [MemError::ReplayDiverged] if the read
+    /// does not match the next read in the stream.
+    #[cold]
+    pub fn replay(&mut self, id: usize, addr: u64, buf: &mut [u8]) -> Result<(), MemError> {
+        let Some(Stream::Replay(reader)) = self.stream.as_mut()
+        else {
+            return Ok(());
+        };
+        if self.replay_error.is_some() {
+            return Err(MemError::ReplayDiverged);
+        }
+
+        let mut bytes = [0; RECORD_SIZE as usize];
+        let record = match reader.read_exact(&mut bytes) {
+            Ok(()) => IoRecord::from_bytes(&bytes),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return self.diverged(IoReplayError::EndOfStream { seq: self.pos });
+            }
+            Err(err) => return self.diverged(IoReplayError::Io(err)),
+        };
+
+        let size = buf.len() as u64;
+        if record.handler.0 != id || record.addr != addr || record.size != size {
+            return self.diverged(IoReplayError::Diverged {
+                expected: record,
+                handler: IoHandler(id),
+                addr,
+                size,
+            });
+        }
+        if record.seq != self.pos {
+            let err =
+                format!("expected read {}, but the stream contains read {}", self.pos, record.seq);
+            return self.diverged(IoReplayError::Io(std::io::Error::other(err)));
+        }
+
+        let value = record.value.to_le_bytes();
+        let len = buf.len().min(value.len());
+        buf[..len].copy_from_slice(&value[..len]);
+        buf[len..].fill(0);
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn diverged(&mut self, err: IoReplayError) -> Result<(), MemError> {
+        tracing::warn!("{err}");
+        self.replay_error = Some(err);
+        Err(MemError::ReplayDiverged)
+    }
+
+    /// Moves the position in the stream to `pos` (e.g. after restoring a snapshot), so that the
+    /// next read is recorded or replayed as the read at `pos`.
+    pub fn seek(&mut self, pos: u64) {
+        self.pos = pos;
+        self.replay_error = None;
+        let offset = self.end_offset();
+        let result = match self.stream.as_mut() {
+            Some(Stream::Record(writer)) => writer.seek(SeekFrom::Start(offset)),
+            Some(Stream::Replay(reader)) => reader.seek(SeekFrom::Start(offset)),
+            None => return,
+        };
+        if let Err(err) = result {
+            match self.stream {
+                Some(Stream::Record(_)) => self.record_error = Some(err),
+                _ => self.replay_error = Some(IoReplayError::Io(err)),
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Tracing of accesses to I/O regions.
+
+use std::collections::VecDeque;
+
+use crate::IoHandler;
+
+/// The maximum number of events kept in the trace by default.
+const DEFAULT_IO_TRACE_CAPACITY: usize = 4096;
+
+/// An access to an I/O region that was recorded while tracing was enabled for the handler (see
+/// [crate::Mmu::set_io_trace]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IoTraceEvent {
+    /// The handler that was accessed.
+    pub handler: IoHandler,
+
+    /// The address of the access.
+    pub addr: u64,
+
+    /// The number of bytes accessed.
+    pub size: u64,
+
+    /// The value read from or written to the handler (in little-endian byte order). For accesses
+    /// that fill a region (see [crate::Mmu::fill_mem]), this is the byte the region was filled
+    /// with.
+    pub value: u128,
+
+    /// Whether the access was a write.
+    pub is_write: bool,
+
+    /// The position of the event in the trace, incremented for every event recorded.
+    pub seq: u64,
+}
+
+/// A bounded buffer of I/O trace events, where the oldest events are discarded once the buffer is
+/// full.
+pub(crate) struct IoTrace {
+    /// Whether tracing is enabled for each handler.
+    enabled: Vec<bool>,
+
+    /// Set if tracing is enabled for at least one handler.
+    any_enabled: bool,
+
+    events: VecDeque<IoTraceEvent>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+impl IoTrace {
+    pub fn new() -> Self {
+        Self {
+            enabled: vec![],
+            any_enabled: false,
+            events: VecDeque::new(),
+            capacity: DEFAULT_IO_TRACE_CAPACITY,
+            next_seq: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) {
+        if self.enabled.len() <= id {
+            self.enabled.resize(id + 1, false);
+        }
+        self.enabled[id] = enabled;
+        self.any_enabled = self.enabled.iter().any(|x| *x);
+    }
+
+    #[inline(always)]
+    pub fn is_enabled(&self, id: usize) -> bool {
+        self.any_enabled && self.enabled.get(id).copied().unwrap_or(false)
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.events.len() > capacity {
+            self.events.pop_front();
+        }
+    }
+
+    /// Records an access of `value` (which is truncated to 16 bytes) at `addr`.
+    #[cold]
+    pub fn record(&mut self, id: usize, addr: u64, value: &[u8], is_write: bool) {
+        let mut bytes = [0; 16];
+        let len = value.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&value[..len]);
+        self.push(id, addr, value.len() as u64, u128::from_le_bytes(bytes), is_write);
+    }
+
+    /// Records a fill of the `len` bytes starting at `addr` with `value`.
+    #[cold]
+    pub fn record_fill(&mut self, id: usize, addr: u64, len: u64, value: u8) {
+        self.push(id, addr, len, value as u128, true);
+    }
+
+    fn push(&mut self, id: usize, addr: u64, size: u64, value: u128, is_write: bool) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(IoTraceEvent {
+            handler: IoHandler(id),
+            addr,
+            size,
+            value,
+            is_write,
+            seq,
+        });
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = IoTraceEvent> + '_ {
+        self.events.drain(..)
+    }
+}
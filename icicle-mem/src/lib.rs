@@ -2,8 +2,29 @@ pub mod perm;
 pub mod physical;
 pub mod tlb;
 
+mod access_log;
+pub mod budget;
+mod builder;
+mod core_dump;
+mod cursor;
+pub mod debug;
+mod frozen;
+#[cfg(feature = "heap")]
+pub mod heap;
+#[cfg(all(unix, feature = "mmap"))]
+mod host_pool;
+pub mod image;
+mod io_replay;
+mod io_trace;
 mod mmu;
+pub mod page_set;
+mod page_table;
 pub mod range_map;
+mod router;
+pub mod tags;
+pub mod unicorn_compat;
+mod walker;
+pub mod watch;
 
 #[cfg(test)]
 mod tests;
@@ -12,12 +33,35 @@ use std::any::Any;
 
 use crate::range_map::RangeMap;
 
-/// The value used to fill uninitalized memory
+/// The default value used to fill uninitalized memory (see [Mmu::uninit_value]).
 pub const UNINIT_VALUE: u8 = 0xaa;
 
 pub use crate::{
-    mmu::{Mmu, ReadAfterHook, ReadHook, WriteHook},
+    access_log::{AccessHistory, AccessRecord},
+    builder::{BuildError, MmuBuilder},
+    core_dump::CoreDumpOptions,
+    cursor::MemCursor,
+    frozen::FrozenMemory,
+    io_replay::{IoRecord, IoReplayError},
+    io_trace::IoTraceEvent,
+    mmu::{
+        AccessKind, AddressSpacePolicy, AlignmentPolicy, AllocKind, AllocPolicy, CapacityEvent,
+        CodeInvalidationHandler, CodePatch, DirtyPage, Endianness, GcBudget, GcReport, GuardFault,
+        GuardHandler, IoPermPolicy, MapError, MapErrorKind, MappingChange, MappingChangeCallback,
+        MappingChangeKind, MemoryStats, Mmu, MmuConfig, PageHeat, PartialWriteError,
+        PermRangeError, Poke, ReadAfterHook, ReadHook, ReadHookResult, Region, RegionKind,
+        ReplayWritePolicy, RestoreError, SelfModifyingCode, SnapshotPolicy, SubscriptionId,
+        TlbCounters, TraversalEnd, UninitHandler, UninitReport, WriteHook, WxPolicy,
+    },
+    page_table::{
+        PageFault, PageFaultReason, PageTableMemory, PageTableWalker, PageTranslation, WalkRequest,
+        X86_64Walker,
+    },
     perm::{MemError, MemResult},
+    physical::PageStore,
+    router::{BusRouter, BusSnapshot, DomainId},
+    tlb::TlbConfig,
+    walker::{PageChunk, PageWalker},
 };
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -47,6 +91,7 @@ pub trait Resettable {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PhysicalMapping {
     /// The starting address to prevent two distinct virtual mappings to the same physical address
     /// from being merged.
@@ -58,6 +103,7 @@ pub struct PhysicalMapping {
 }
 
 #[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MemoryMapping {
     /// Represents a region of memory backed by a physical page.
     Physical(PhysicalMapping),
@@ -66,7 +112,16 @@ pub enum MemoryMapping {
     Unallocated(UnallocatedMemory),
 
     /// Represents a region of memory handled externally.
-    Io(usize),
+    Io(IoMapping),
+
+    /// Represents a region of memory that is lazily initialized from a file.
+    File(FileMapping),
+
+    /// Represents a region of address space that is reserved, preventing it from being mapped by
+    /// anything else, but that can not be accessed. The value identifies the owner of the
+    /// reservation (e.g., the address of the allocation a guard region belongs to, see
+    /// [Mmu::alloc_memory_with_guards]).
+    Reserved(u64),
 }
 
 impl std::fmt::Debug for MemoryMapping {
@@ -74,16 +129,74 @@ impl std::fmt::Debug for MemoryMapping {
         match self {
             Self::Physical(inner) => write!(f, "{:?}", inner.index),
             Self::Unallocated(inner) => write!(f, "{}", inner),
-            Self::Io(i) => write!(f, "io[{}]", i),
+            Self::Io(inner) => write!(f, "io[{}] {}", inner.handler, perm::display(inner.perm)),
+            Self::File(inner) => write!(f, "{}", inner),
+            Self::Reserved(owner) => write!(f, "reserved[{:#x}]", owner),
         }
     }
 }
 
+/// The attributes of an access to an I/O handler (see [IoMemory::read_access]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoAccess {
+    /// The address of the access.
+    pub addr: u64,
+
+    /// The number of bytes accessed. Accesses that span the boundary of an I/O region only include
+    /// the bytes inside of the region.
+    pub size: usize,
+
+    /// The kind of access, where [AccessKind::Execute] is used for instruction fetches.
+    pub kind: AccessKind,
+
+    /// The value set using [Mmu::set_io_context] when the access was performed (e.g. the privilege
+    /// level or program counter of the CPU).
+    pub context: u64,
+}
+
 /// Used for regions of memory that need custom behaviour for every read/write.
+///
+/// Errors returned by the handler are propagated to the access without modification, so handlers
+/// can distinguish between holes in their window (e.g. [MemError::Unmapped]) and accesses that are
+/// rejected by the device (e.g. [MemError::ReadViolation] or [MemError::WriteViolation]).
 pub trait IoMemory {
     fn read(&mut self, addr: u64, buf: &mut [u8]) -> MemResult<()>;
     fn write(&mut self, addr: u64, value: &[u8]) -> MemResult<()>;
 
+    /// Reads `buf.len()` bytes from the device for an access with the attributes in `access`. By
+    /// default, this calls [IoMemory::read].
+    fn read_access(&mut self, access: &IoAccess, buf: &mut [u8]) -> MemResult<()> {
+        self.read(access.addr, buf)
+    }
+
+    /// Writes `value` to the device for an access with the attributes in `access`. By default,
+    /// this calls [IoMemory::write].
+    fn write_access(&mut self, access: &IoAccess, value: &[u8]) -> MemResult<()> {
+        self.write(access.addr, value)
+    }
+
+    /// Fills the `len` bytes starting at `addr` with `value` (see [Mmu::fill_mem]).
+    ///
+    /// By default, this performs a sequence of writes that are naturally aligned and at most 8
+    /// bytes in size. Handlers that do not support being filled can return an error instead.
+    fn fill(&mut self, mut addr: u64, len: u64, value: u8) -> MemResult<()> {
+        let end = addr.checked_add(len).ok_or(MemError::AddressOverflow)?;
+        while addr < end {
+            let size = (1 << addr.trailing_zeros().min(3)).min(end - addr);
+            let size = 1 << (63 - size.leading_zeros());
+            self.write(addr, &[value; 8][..size as usize])?;
+            addr += size;
+        }
+        Ok(())
+    }
+
+    /// Reads from the device without causing any side effects (e.g. for debuggers). By default
+    /// this is not supported.
+    fn peek(&mut self, addr: u64, buf: &mut [u8]) -> MemResult<()> {
+        let _ = (addr, buf);
+        Err(MemError::Unsupported)
+    }
+
     fn snapshot(&mut self) -> Box<dyn Any> {
         Box::new(())
     }
@@ -91,11 +204,18 @@ pub trait IoMemory {
     fn restore(&mut self, snapshot: &Box<dyn Any>) {
         let _ = snapshot;
     }
+
+    /// Resets the device to its initial state. Called when a snapshot taken before the handler was
+    /// registered is restored (see [Mmu::restore]). By default this does nothing.
+    fn reset(&mut self) {}
 }
 
 pub trait IoMemoryAny: IoMemory {
     fn as_any(&self) -> &dyn Any;
     fn as_mut_any(&mut self) -> &mut dyn Any;
+
+    /// Returns the name of the concrete type of the handler.
+    fn type_name(&self) -> &'static str;
 }
 
 impl<T: IoMemory + 'static> IoMemoryAny for T {
@@ -106,6 +226,10 @@ impl<T: IoMemory + 'static> IoMemoryAny for T {
     fn as_mut_any(&mut self) -> &mut dyn Any {
         self
     }
+
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
 }
 
 pub struct NullMemory;
@@ -120,20 +244,81 @@ impl IoMemory for NullMemory {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct IoHandler(usize);
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IoHandler(pub(crate) usize);
+
+/// Identifies the kind of device an I/O handler implements, captured by snapshots to check that the
+/// state of each handler is restored to a compatible handler (see [Mmu::restore]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IoHandlerIdentity {
+    /// The concrete type of the handler.
+    pub type_id: std::any::TypeId,
+
+    /// The name of the concrete type of the handler, used for error messages.
+    pub type_name: &'static str,
+
+    /// The name the handler was registered with (see [Mmu::register_named_io_handler]).
+    pub name: Option<String>,
+}
+
+impl std::fmt::Display for IoHandlerIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name} ({})", self.type_name),
+            None => f.write_str(self.type_name),
+        }
+    }
+}
+
+impl IoHandler {
+    /// Returns a mapping of the handler with the permissions in `perm` (a combination of
+    /// [perm::READ], [perm::WRITE] and [perm::EXEC]).
+    pub fn with_perm(self, perm: u8) -> IoMapping {
+        IoMapping { handler: self.0, perm: perm & IoMapping::PERM_MASK }
+    }
+}
 
 impl From<IoHandler> for MemoryMapping {
     fn from(value: IoHandler) -> Self {
-        MemoryMapping::Io(value.0)
+        MemoryMapping::Io(value.with_perm(IoMapping::DEFAULT_PERM))
     }
 }
 
+impl From<IoMapping> for MemoryMapping {
+    fn from(value: IoMapping) -> Self {
+        MemoryMapping::Io(value)
+    }
+}
+
+/// Represents a region of memory handled by an I/O handler (see [Mmu::register_io_handler]).
+///
+/// Accesses to the region are checked against `perm` before the handler is invoked, in the same
+/// way as accesses to regular memory (the region is always treated as initialized).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IoMapping {
+    /// The index of the handler for the region.
+    pub handler: usize,
+
+    /// The permissions of the region, a combination of [perm::READ], [perm::WRITE] and
+    /// [perm::EXEC].
+    pub perm: u8,
+}
+
+impl IoMapping {
+    /// The permission bits that are stored for I/O regions.
+    pub const PERM_MASK: u8 = perm::READ | perm::WRITE | perm::EXEC;
+
+    /// The permissions of an I/O region mapped using [IoHandler].
+    pub const DEFAULT_PERM: u8 = perm::READ | perm::WRITE;
+}
+
 pub type Mapping = UnallocatedMemory;
 
 /// Represents a region of memory that has no physical backing, and does not need to be page
 /// aligned.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UnallocatedMemory {
     pub perm: u8,
     pub value: u8,
@@ -164,6 +349,76 @@ impl From<UnallocatedMemory> for MemoryMapping {
     }
 }
 
+/// An identifier for a virtual address space created by [Mmu::create_address_space].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Asid(pub(crate) usize);
+
+impl Asid {
+    /// The address space that is active when the MMU is created.
+    pub const DEFAULT: Self = Self(0);
+}
+
+/// The data of a file registered with [Mmu::register_file].
+pub type FileData = std::sync::Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// A handle to the data of a file registered with [Mmu::register_file].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct FileHandle(pub(crate) usize);
+
+/// Represents a region of memory that has no physical backing, where pages are initialized from
+/// the contents of a file the first time they are accessed. Bytes that are past the end of the file
+/// are initialized to zero.
+///
+/// Writes to the region are never written back to the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileMapping {
+    pub file: FileHandle,
+
+    /// The offset within the file of the first byte of the mapping.
+    ///
+    /// Once the mapping has been inserted into the memory map, this is rebased to be the offset
+    /// within the file of virtual address zero (wrapping), so that the mapping remains valid when
+    /// the region is split or merged.
+    pub(crate) offset: u64,
+
+    pub perm: u8,
+}
+
+impl FileMapping {
+    /// Creates a mapping of `file` where the first byte of the mapped region is initialized from
+    /// the byte at `offset` within the file.
+    pub fn new(file: FileHandle, offset: u64, perm: u8) -> Self {
+        Self { file, offset, perm }
+    }
+
+    /// Returns the offset within the file of the byte mapped at `addr`, for a mapping that was
+    /// returned from the memory map.
+    pub fn file_offset(&self, addr: u64) -> u64 {
+        self.offset.wrapping_add(addr)
+    }
+}
+
+impl std::fmt::Display for FileMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "File {{ file: {}, perm: {} ({:#0b}), offset: {:#0x} }}",
+            self.file.0,
+            perm::display(self.perm),
+            self.perm,
+            self.offset
+        )
+    }
+}
+
+impl From<FileMapping> for MemoryMapping {
+    fn from(v: FileMapping) -> Self {
+        Self::File(v)
+    }
+}
+
 pub type Snapshot = std::sync::Arc<SnapshotData>;
 
 pub type VirtualMemoryMap = RangeMap<MemoryMapping>;
@@ -178,9 +433,33 @@ pub struct SnapshotData {
     /// The parent of this snapshot.
     pub parent: Option<Snapshot>,
 
-    /// The snapshot state of all peripherals.
-    // @todo: need to handle dynamic adding of I/O handlers.
-    pub io: Vec<Box<dyn Any>>,
+    /// The last address of the address space (see [Mmu::set_address_space_bits]).
+    pub address_space_end: u64,
+
+    /// The address space that was active (see [Mmu::switch_address_space]).
+    pub asid: Asid,
+
+    /// The mappings of every address space indexed by ASID, the entry for the active address space
+    /// is empty (it is stored in `mapping`).
+    pub address_spaces: Vec<VirtualMemoryMap>,
+
+    /// The usage of every page budget (see [Mmu::create_page_budget]).
+    pub budgets: budget::BudgetState,
+
+    /// The snapshot state of all peripherals, or `None` for handlers that are excluded from
+    /// snapshots (see [mmu::SnapshotPolicy]).
+    pub io: Vec<Option<Box<dyn Any>>>,
+
+    /// The identity of the handler that each entry of `io` was captured from, or `None` if the
+    /// handler had been unregistered. Entries of `io` without an identity are restored without
+    /// being validated.
+    pub io_handlers: Vec<Option<IoHandlerIdentity>>,
+
+    /// The position in the stream of recorded I/O reads (see [Mmu::io_stream_position]).
+    pub io_stream_pos: u64,
+
+    /// The seed and state of the random number generator used for [mmu::AllocPolicy::Random].
+    pub alloc_rng: (u64, u64),
 }
 
 impl SnapshotData {
@@ -189,7 +468,14 @@ impl SnapshotData {
             mapping: VirtualMemoryMap::new(),
             physical: physical::PhysicalMemory::new(0),
             parent: None,
+            address_space_end: u64::MAX,
+            asid: Asid::DEFAULT,
+            address_spaces: vec![VirtualMemoryMap::new()],
+            budgets: budget::BudgetState::default(),
             io: vec![],
+            io_handlers: vec![],
+            io_stream_pos: 0,
+            alloc_rng: (0, 0),
         }
     }
 }
@@ -200,6 +486,37 @@ impl Default for SnapshotData {
     }
 }
 
+/// A snapshot of a subrange of the virtual address space created by [Mmu::snapshot_range].
+pub struct RangeSnapshot {
+    /// The first address covered by the snapshot.
+    start: u64,
+
+    /// The last address (inclusive) covered by the snapshot.
+    end: u64,
+
+    /// The mapped regions within the range at the time the snapshot was taken.
+    regions: Vec<(u64, u64, RangeSnapshotEntry)>,
+}
+
+impl RangeSnapshot {
+    /// Returns the range of addresses (inclusive) covered by the snapshot.
+    pub fn range(&self) -> (u64, u64) {
+        (self.start, self.end)
+    }
+}
+
+enum RangeSnapshotEntry {
+    /// A region backed by a physical page. The page shares its data with the page that was mapped
+    /// at the time of the snapshot so taking the snapshot does not require the page to be copied.
+    Physical(physical::Page),
+
+    /// A region with no physical backing (either unallocated or file-backed memory).
+    Unallocated(MemoryMapping),
+
+    /// A region handled by an I/O handler (I/O state is never captured by range snapshots).
+    Io,
+}
+
 #[derive(Copy, Clone, Default, Debug)]
 pub struct AllocLayout {
     /// The preferred address of the allocation
@@ -1,22 +1,305 @@
-use ahash::AHashSet as HashSet;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
 
 use tracing::debug;
 
 use crate::{
-    Addr, AllocLayout, IoHandler, IoMemory, IoMemoryAny, MemoryMapping, PhysicalMapping, Snapshot,
-    SnapshotData, VirtualMemoryMap,
+    AccessHistory, Addr, AllocLayout, Asid, CoreDumpOptions, FileData, FileHandle, FileMapping,
+    FrozenMemory, IoAccess, IoHandler, IoHandlerIdentity, IoMapping, IoMemory, IoMemoryAny,
+    MemCursor, MemoryMapping, PageWalker, PhysicalMapping, RangeSnapshot, RangeSnapshotEntry,
+    Snapshot, SnapshotData, UnallocatedMemory, VirtualMemoryMap,
+    access_log::AccessLog,
+    budget::{BudgetId, PageBudgets},
+    builder::MmuBuilder,
+    image::{LoadError, LoadReport, Segment},
+    io_replay::{IoReplay, IoReplayError},
+    io_trace::{IoTrace, IoTraceEvent},
+    page_set::PageSet,
+    page_table::{PageFault, PageTableMemory, PageTableWalker, WalkRequest},
     perm::{self, MemError, MemResult},
-    physical::{self, PageData, PhysicalAddr},
+    physical::{self, PageData, PageStore, PhysicalAddr},
     range_map::RangeMap,
-    tlb,
+    tags::{Tag, TagId, TagLabel, Tags},
+    tlb::{self, TlbConfig},
+    watch::{WatchpointHit, WatchpointId, WatchpointKind, Watchpoints},
 };
 
+/// The default value of [MmuConfig::detect_self_modifying_code].
 pub const DETECT_SELF_MODIFYING_CODE: bool = true;
+
+/// The default value of [MmuConfig::zero_page_optimization].
 pub const ENABLE_ZERO_PAGE_OPTIMIZATION: bool = true;
+
+/// The default value of [MmuConfig::memory_hooks].
 pub const ENABLE_MEMORY_HOOKS: bool = true;
 
+/// The default value of [Mmu::traversal_limit].
+pub const DEFAULT_TRAVERSAL_LIMIT: usize = 1 << 20;
+
+/// The size (in bytes) of the largest single access (used by bulk operations).
+const MAX_ACCESS_SIZE: u64 = 16;
+
+/// Controls how writes that modify code that has already been translated are handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfModifyingCode {
+    /// Translated code is not tracked, so writes to it are not detected.
+    Ignore,
+
+    /// Writes that modify translated code fail with [MemError::SelfModifyingCode].
+    Fault,
+
+    /// Writes that modify translated code succeed, and the code invalidation handler is notified
+    /// of the page that was modified (see [Mmu::set_code_invalidation_handler]).
+    Invalidate,
+}
+
+impl Default for SelfModifyingCode {
+    fn default() -> Self {
+        match DETECT_SELF_MODIFYING_CODE {
+            true => Self::Fault,
+            false => Self::Ignore,
+        }
+    }
+}
+
+/// The byte order used by the typed accessors (e.g. [Mmu::read_u32] and [Mmu::write_u32]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+/// Controls how requests for memory that is both writable and executable are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WxPolicy {
+    /// Memory can be both writable and executable.
+    #[default]
+    Allow,
+
+    /// Requests for memory that is both writable and executable are rejected (see
+    /// [Mmu::update_perm], [Mmu::map_memory_len] and [Mmu::ensure_executable]).
+    Deny,
+
+    /// Requests for memory that is both writable and executable succeed, but the memory is made
+    /// read-only and a warning is logged. This is useful for discovering which regions of memory a
+    /// target requires to be both writable and executable.
+    StripWrite,
+}
+
+/// Controls how guest accesses to addresses that are not a multiple of the size of the access are
+/// handled. Bulk operations that operate on bytes (e.g. [Mmu::read_bytes], [Mmu::write_bytes] and
+/// [Mmu::read_cstr]) are never affected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlignmentPolicy {
+    /// Unaligned accesses are split into individual bytes.
+    #[default]
+    Transparent,
+
+    /// Unaligned accesses fail with [MemError::Unaligned] without accessing any memory.
+    Fault,
+
+    /// Unaligned accesses larger than the given number of bytes fail with [MemError::Unaligned],
+    /// smaller accesses are split into individual bytes.
+    FaultAbove(usize),
+}
+
+impl AlignmentPolicy {
+    /// Returns whether an unaligned access of `size` bytes is allowed.
+    fn allows(self, size: usize) -> bool {
+        match self {
+            Self::Transparent => true,
+            Self::Fault => false,
+            Self::FaultAbove(max) => size <= max,
+        }
+    }
+}
+
+/// Controls where [Mmu::find_free_memory] places allocations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// Allocations are placed at the lowest free address at or above the preferred address.
+    #[default]
+    BottomUp,
+
+    /// Allocations are placed at the highest free address below `ceiling`, similar to the mmap
+    /// region of most operating systems. The preferred address is used if it is free.
+    TopDown { ceiling: u64 },
+
+    /// Allocations are placed at a free address below `ceiling` chosen uniformly at random, using
+    /// a deterministic random number generator seeded with `seed`. The preferred address is used
+    /// if it is free.
+    ///
+    /// The generator is advanced by each allocation made by the MMU (e.g. [Mmu::alloc_memory]),
+    /// and its state is captured by snapshots, so restoring a snapshot replays the same sequence
+    /// of addresses.
+    Random { seed: u64, ceiling: u64 },
+}
+
+/// Controls how requests to map memory past the end of the address space (see
+/// [Mmu::set_address_space_bits]) are handled. Memory allocated by [Mmu::find_free_memory] is
+/// always within the address space.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressSpacePolicy {
+    /// Memory can be mapped anywhere.
+    #[default]
+    Allow,
+
+    /// Memory can be mapped anywhere, but a warning is logged for mappings past the end of the
+    /// address space.
+    Warn,
+
+    /// Requests to map memory past the end of the address space are rejected (see
+    /// [Mmu::map_memory_len] and [Mmu::map_regions]).
+    Deny,
+}
+
+/// Controls how I/O regions are treated by [Mmu::get_perm_range] and [Mmu::check_perm_range].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IoPermPolicy {
+    /// I/O regions are treated as unmapped.
+    #[default]
+    Deny,
+
+    /// I/O regions are treated as initialized, with the permissions of the mapping (see
+    /// [IoMapping]).
+    Allow,
+}
+
+/// Controls whether the state of an I/O handler is captured by [Mmu::snapshot] and rolled back by
+/// [Mmu::restore] (see [Mmu::register_io_handler_with_policy]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SnapshotPolicy {
+    /// The state of the handler is captured and restored with the rest of memory.
+    #[default]
+    Include,
+
+    /// The state of the handler is never captured, restored or reset by snapshots. This is useful
+    /// for handlers that model host-side resources (e.g. a console or a log sink).
+    Exclude,
+}
+
+/// Controls whether writes reach I/O handlers while I/O reads are being replayed (see
+/// [Mmu::start_io_replay]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplayWritePolicy {
+    /// Writes are passed to the handlers (e.g. so that output devices still produce output).
+    #[default]
+    Forward,
+
+    /// Writes (including fills) are discarded, so the handlers are never accessed.
+    Discard,
+}
+
+/// Error returned by [Mmu::check_perm_range].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PermRangeError {
+    /// The address of the first byte that failed the check.
+    pub addr: u64,
+
+    /// The reason the byte failed the check.
+    pub error: MemError,
+}
+
+/// Error returned by [Mmu::write_bytes_partial].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartialWriteError {
+    /// The number of bytes at the start of the buffer that were written before the failure.
+    pub written: usize,
+
+    /// The reason the write failed.
+    pub error: MemError,
+}
+
+/// The permissions of a run of bytes, see `Mmu::perm_runs`.
+enum PermRun<'a> {
+    /// Every byte has the same permissions.
+    Uniform(u8),
+
+    /// The permissions of each byte, from a physical page.
+    Bytes(&'a [u8]),
+}
+
+/// The data of a run of bytes, see `Mmu::byte_runs`.
+#[derive(Clone, Copy)]
+enum ByteRun<'a> {
+    /// Every byte has the same value.
+    Fill(u8, usize),
+
+    /// The value of each byte, from a physical page or a file.
+    Bytes(&'a [u8]),
+}
+
+impl<'a> ByteRun<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Self::Fill(_, len) => *len,
+            Self::Bytes(data) => data.len(),
+        }
+    }
+
+    /// Returns the `len` bytes of the run starting at `offset`.
+    fn slice(&self, offset: usize, len: usize) -> ByteRun<'a> {
+        match self {
+            Self::Fill(value, _) => Self::Fill(*value, len),
+            Self::Bytes(data) => Self::Bytes(&data[offset..][..len]),
+        }
+    }
+
+    /// Returns the offset of the first byte that differs between `self` and `other` (which must
+    /// have the same length).
+    fn mismatch(&self, other: &ByteRun) -> Option<usize> {
+        match (*self, *other) {
+            (ByteRun::Fill(a, _), ByteRun::Fill(b, _)) => (a != b).then_some(0),
+            (ByteRun::Fill(value, _), ByteRun::Bytes(data))
+            | (ByteRun::Bytes(data), ByteRun::Fill(value, _)) => {
+                data.iter().position(|byte| *byte != value)
+            }
+            // Compare the entire slice first, since this is much faster than finding the position
+            // of the first difference in the common case where the data matches.
+            (ByteRun::Bytes(a), ByteRun::Bytes(b)) if a == b => None,
+            (ByteRun::Bytes(a), ByteRun::Bytes(b)) => a.iter().zip(b).position(|(a, b)| a != b),
+        }
+    }
+}
+
 pub trait ReadHook {
     fn read(&mut self, mem: &mut Mmu, addr: u64, size: u8) -> Option<u64>;
+
+    /// Determines how the value of a read of `size` bytes at `addr` is produced. By default, the
+    /// value returned by [ReadHook::read] replaces the entire value.
+    fn read_partial(&mut self, mem: &mut Mmu, addr: u64, size: u8) -> ReadHookResult {
+        match self.read(mem, addr, size) {
+            Some(value) => ReadHookResult::Value(value),
+            None => ReadHookResult::Memory,
+        }
+    }
+
+    /// Called with the value of a read after [ReadHook::read_partial] returned
+    /// [ReadHookResult::Modify], allowing the hook to change the value returned by the read.
+    fn modify(&mut self, _mem: &mut Mmu, _addr: u64, _value: &mut [u8]) {}
+}
+
+/// The result of [ReadHook::read_partial].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadHookResult {
+    /// The value is read from memory.
+    Memory,
+
+    /// The value is replaced by the value returned by the hook (truncated to the size of the
+    /// read). Memory is not read and read-after hooks are not called.
+    Value(u64),
+
+    /// The bytes of `value` (in little-endian order) selected by `mask`, where bit `i` selects
+    /// byte `i`, replace the corresponding bytes read from memory. Only the bytes that are read
+    /// from memory are checked for [perm::INIT].
+    Partial { value: u64, mask: u8 },
+
+    /// The value is read from memory, then passed to [ReadHook::modify] before it is returned.
+    Modify,
 }
 
 impl ReadHook for () {
@@ -38,6 +321,15 @@ pub trait ReadAfterHook {
     fn read(&mut self, mem: &mut Mmu, addr: u64, value: &[u8]);
 }
 
+impl<T> ReadAfterHook for T
+where
+    T: FnMut(&mut Mmu, u64, &[u8]),
+{
+    fn read(&mut self, mem: &mut Mmu, addr: u64, value: &[u8]) {
+        self(mem, addr, value);
+    }
+}
+
 pub trait WriteHook {
     fn write(&mut self, mem: &mut Mmu, addr: u64, value: &[u8]);
 }
@@ -55,6 +347,28 @@ where
     }
 }
 
+/// Notified whenever memory that may contain cached code is invalidated (e.g. because it was
+/// unmapped), so that the owner of the code cache can discard any translations for the range.
+pub trait CodeInvalidationHandler {
+    /// Called with the (inclusive) range of virtual addresses that was invalidated.
+    fn invalidate(&mut self, start: u64, end: u64);
+}
+
+impl<T> CodeInvalidationHandler for T
+where
+    T: FnMut(u64, u64),
+{
+    fn invalidate(&mut self, start: u64, end: u64) {
+        self(start, end);
+    }
+}
+
+/// A hook for the addresses in `start..end`. If `end` is less than `start` the range wraps around
+/// the end of the address space, i.e., the hook applies to `start..=u64::MAX` and `0..end`.
+///
+/// The hook is called for every access where any of the accessed bytes are in the range, and is
+/// passed the address and size of the entire access (which may start before or end after the
+/// range).
 pub struct HookEntry<T: ?Sized> {
     pub start: u64,
     pub end: u64,
@@ -62,12 +376,29 @@ pub struct HookEntry<T: ?Sized> {
 }
 
 impl<T: ?Sized> HookEntry<T> {
-    // @fixme: Handle case where self.end + page_size overflows.
-    fn range(&self, page_size: u64) -> std::ops::RangeInclusive<u64> {
-        let alignment_mask = !(page_size - 1);
-        let start = self.start & alignment_mask;
-        let end = (self.end + page_size) & alignment_mask;
-        start..=end
+    /// Returns the (inclusive) ranges of addresses covered by the hook.
+    fn ranges(&self) -> [Option<(u64, u64)>; 2] {
+        match self.start <= self.end {
+            true => [(self.start < self.end).then(|| (self.start, self.end - 1)), None],
+            false => [Some((self.start, u64::MAX)), self.end.checked_sub(1).map(|end| (0, end))],
+        }
+    }
+
+    /// Returns whether the hook applies to any of the `len` bytes starting at `addr`.
+    fn overlaps_access(&self, addr: u64, len: usize) -> bool {
+        let last = addr.saturating_add(len as u64 - 1);
+        self.ranges().into_iter().flatten().any(|(start, end)| start <= last && addr <= end)
+    }
+
+    /// Returns whether the hook applies to any address in the page containing `addr`, or to any
+    /// address that could be reached by an access that starts in the page.
+    fn overlaps_page(&self, addr: u64, page_size: u64) -> bool {
+        let page_start = addr & !(page_size - 1);
+        let page_end = (page_start | (page_size - 1)).saturating_add(MAX_ACCESS_SIZE - 1);
+        self.ranges()
+            .into_iter()
+            .flatten()
+            .any(|(start, end)| start <= page_end && page_start <= end)
     }
 }
 
@@ -82,12 +413,19 @@ impl<T: ?Sized> HookStore<T> {
     }
 
     fn add(&mut self, start: u64, end: u64, handler: Box<T>) -> u32 {
+        let entry = HookEntry { start, end, handler: Some(handler) };
+
         // Check if there is a dead slot that can be reused.
-        let id = self.hooks.iter().position(|x| x.handler.is_none()).unwrap_or_else(|| {
-            let id = self.hooks.len();
-            self.hooks.push(HookEntry { start, end, handler: Some(handler) });
-            id
-        });
+        let id = match self.hooks.iter().position(|x| x.handler.is_none()) {
+            Some(id) => {
+                self.hooks[id] = entry;
+                id
+            }
+            None => {
+                self.hooks.push(entry);
+                self.hooks.len() - 1
+            }
+        };
         id.try_into().expect("too many hooks")
     }
 
@@ -104,18 +442,32 @@ impl<T: ?Sized> HookStore<T> {
 
     /// Check if any of the hooks overlap with the page containing `addr`.
     fn contains_address(&self, addr: u64, page_size: u64) -> bool {
-        self.hooks.iter().any(|x| x.handler.is_some() && x.range(page_size).contains(&addr))
+        self.hooks.iter().any(|x| x.handler.is_some() && x.overlaps_page(addr, page_size))
+    }
+
+    /// Check if any of the hooks apply to an address in `addr..addr + len`.
+    fn overlaps(&self, addr: u64, len: u64) -> bool {
+        let Some(last) = len.checked_sub(1)
+        else {
+            return false;
+        };
+        let last = addr.saturating_add(last);
+        self.hooks.iter().any(|x| {
+            x.handler.is_some()
+                && x.ranges().into_iter().flatten().any(|(start, end)| start <= last && addr <= end)
+        })
     }
 }
 
 macro_rules! active_hooks {
-    ($addr:expr, $list:expr, $action:expr) => {{
+    ($addr:expr, $len:expr, $list:expr, $action:expr) => {{
         if !$list.hooks.is_empty() {
-            let addr = $addr;
+            let (addr, len) = ($addr, $len);
             let mut hooks = std::mem::take(&mut $list.hooks);
             for hook in &mut hooks {
+                let contains = hook.overlaps_access(addr, len);
                 if let Some(handler) = hook.handler.as_deref_mut() {
-                    if hook.start <= addr && addr < hook.end {
+                    if contains {
                         ($action)(handler);
                     }
                 }
@@ -126,24 +478,385 @@ macro_rules! active_hooks {
     }};
 }
 
+/// Details about a read that failed because memory was uninitialized, recorded when
+/// [Mmu::uninit_diagnostics] is enabled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UninitReport {
+    /// The address of the read.
+    pub addr: u64,
+
+    /// The size of the read in bytes.
+    pub size: usize,
+
+    /// The virtual address that the physical page containing the first uninitialized byte was
+    /// originally mapped at, or `None` if the byte was not backed by a physical page.
+    pub page_mapped_at: Option<u64>,
+
+    /// The permission bits of each byte of the read.
+    pub perm_bits: Vec<u8>,
+
+    /// The tags covering the first uninitialized byte (or the address of the read if no byte was
+    /// found), ordered by when they were added (see [Mmu::tag_range]).
+    pub tags: Vec<Tag>,
+}
+
+/// A function called whenever an uninitialized read is reported.
+pub type UninitHandler = Box<dyn FnMut(&mut Mmu, &UninitReport)>;
+
+/// The kind of access performed on memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+/// The kind of mapping that backs a [Region].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Memory backed by physical pages.
+    Physical,
+
+    /// Memory that has not been allocated yet.
+    Unallocated,
+
+    /// Memory that is lazily initialized from a file.
+    File(FileHandle),
+
+    /// Memory handled by an I/O handler.
+    Io(IoHandler),
+
+    /// Address space that is reserved but can not be accessed.
+    Reserved,
+}
+
+/// A range of memory with the same effective permissions and backing, returned by
+/// [Mmu::regions].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    /// The first address of the region.
+    pub start: u64,
+
+    /// The last address of the region (inclusive).
+    pub end: u64,
+
+    /// The effective permissions of the region, a combination of [perm::READ], [perm::WRITE]
+    /// and [perm::EXEC]. I/O regions are always readable and writable.
+    pub perm: u8,
+
+    /// The kind of mapping that backs the region.
+    pub kind: RegionKind,
+}
+
+/// Details about an access that touched a guarded byte (see [perm::GUARD]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GuardFault {
+    /// The address of the first guarded byte touched by the access.
+    pub addr: u64,
+
+    /// The kind of access that caused the fault.
+    pub kind: AccessKind,
+
+    /// The tags covering the faulting address, ordered by when they were added (see
+    /// [Mmu::tag_range]).
+    pub tags: Vec<Tag>,
+}
+
+/// A function called whenever an access touches a guarded byte. Returns whether the access should
+/// be retried, which is only done if the handler removed the guard from the faulting byte.
+pub type GuardHandler = Box<dyn FnMut(&mut Mmu, &GuardFault) -> bool>;
+
+/// The kind of change made to the virtual address space (see [Mmu::on_mapping_change]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MappingChangeKind {
+    /// Memory was mapped to the range.
+    Mapped,
+
+    /// The range was unmapped.
+    Unmapped,
+
+    /// The permissions of the range were changed.
+    PermissionChanged,
+
+    /// The memory in the range was moved to the range starting at `dst`.
+    Moved { dst: u64 },
+
+    /// The mapping of the range was replaced without the individual changes being reported (e.g.
+    /// when a snapshot is restored or the address space is switched), so anything derived from
+    /// the mapping of the range should be discarded.
+    Replaced,
+}
+
+/// A change to the virtual address space reported to callbacks registered with
+/// [Mmu::on_mapping_change].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MappingChange {
+    /// The kind of change.
+    pub kind: MappingChangeKind,
+
+    /// The first address of the affected range.
+    pub start: u64,
+
+    /// The last address of the affected range (inclusive).
+    pub end: u64,
+}
+
+/// A callback notified about changes to the virtual address space.
+///
+/// Callbacks do not have access to the MMU, so they can not modify the mapping while a change is
+/// being reported. Consumers that need to react to a change by accessing the MMU should record the
+/// change and handle it after the operation that caused it has returned.
+pub type MappingChangeCallback = Box<dyn FnMut(&MappingChange)>;
+
+/// A handle to a callback registered using [Mmu::on_mapping_change].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u32);
+
+/// The reason that a region passed to [Mmu::map_regions] could not be mapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapErrorKind {
+    /// The region is empty or extends past the end of the address space.
+    InvalidRange,
+
+    /// The region overlaps with the region at the given position in the batch.
+    OverlapsRegion(usize),
+
+    /// The region overlaps with memory that is already mapped.
+    OverlapsMapping,
+
+    /// The region is writable and executable, and was rejected by [Mmu::wx_policy].
+    WriteExecViolation,
+
+    /// The region extends past the end of the address space, and was rejected by
+    /// [Mmu::address_space_policy].
+    OutOfAddressSpace,
+}
+
+/// Error returned by [Mmu::map_regions].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MapError {
+    /// The position of the region that could not be mapped.
+    pub index: usize,
+
+    /// The reason the region could not be mapped.
+    pub kind: MapErrorKind,
+}
+
+/// Error returned by [Mmu::restore] when a snapshot is not compatible with the I/O handlers
+/// registered with the MMU.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RestoreError {
+    /// The snapshot contains the state of a handler that has never been registered with the MMU
+    /// (e.g. because the snapshot was taken from a different MMU).
+    MissingHandler(IoHandler),
+
+    /// The handler registered with the MMU is not the same kind of device as the handler that the
+    /// snapshot was taken from (e.g. because handlers were registered in a different order).
+    HandlerMismatch { handler: IoHandler, expected: IoHandlerIdentity, found: IoHandlerIdentity },
+
+    /// The handler registered with the MMU has a different [SnapshotPolicy] to the handler that
+    /// the snapshot was taken from.
+    PolicyMismatch { handler: IoHandler, expected: SnapshotPolicy, found: SnapshotPolicy },
+}
+
+impl std::fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingHandler(handler) => {
+                write!(f, "snapshot contains state for unknown I/O handler {}", handler.0)
+            }
+            Self::HandlerMismatch { handler, expected, found } => write!(
+                f,
+                "I/O handler {} is {found}, but the snapshot was taken from {expected}",
+                handler.0
+            ),
+            Self::PolicyMismatch { handler, expected, found } => write!(
+                f,
+                "I/O handler {} has snapshot policy {found:?}, but the snapshot was taken with \
+                 {expected:?}",
+                handler.0
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+/// The result of [Mmu::with_code_patching].
+#[derive(Debug)]
+pub struct CodePatch<R> {
+    /// The value returned by the patching function.
+    pub result: R,
+
+    /// The (inclusive) ranges of code that were modified by the patching function.
+    pub modified: Vec<(u64, u64)>,
+}
+
+/// Describes where a traversal of guest pointers stopped (see [Mmu::read_ptr_array] and
+/// [Mmu::follow_list]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraversalEnd {
+    /// A NULL pointer was reached.
+    Null,
+
+    /// The maximum number of entries was reached before a NULL pointer.
+    Limit,
+
+    /// The node at the address was reached a second time.
+    Cycle(u64),
+
+    /// The pointer at `addr` could not be read.
+    Fault { addr: u64, error: MemError },
+}
+
+/// A write performed by [Mmu::poke_bytes], recording the previous contents of memory so that the
+/// write can be undone using [Mmu::undo_poke].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Poke {
+    /// The address of the write.
+    pub addr: u64,
+
+    /// The bytes that were overwritten.
+    pub old: Vec<u8>,
+}
+
+/// A page recorded in the page modification log.
+#[derive(Clone, Copy)]
+pub struct DirtyPage<'a> {
+    /// The (page-aligned) virtual address of the page.
+    pub addr: u64,
+
+    /// The data and permissions of the physical page currently mapped at `addr`, or `None` if the
+    /// page has been unmapped since it was modified.
+    pub data: Option<&'a PageData>,
+}
+
+/// The memory management unit, responsible for translating virtual addresses and enforcing
+/// permissions.
+///
+/// # Sub-page mappings
+///
+/// Regions can be mapped at byte granularity, so a single page may contain multiple regions with
+/// different permissions and backing kinds. Every access and bulk operation (`read_bytes`,
+/// `write_bytes`, `fill_mem`, `update_perm`, `ensure_executable`, `move_region_len`, and range
+/// snapshots) behaves as if it was performed one byte at a time, with each byte handled by the
+/// region that contains it. If an operation fails, the error is the one reported for the first
+/// byte that fails, however bytes before that point may or may not have been modified.
+///
+/// This is implemented by ensuring that a physical page only grants permissions for the bytes of
+/// the regions that are mapped to it, so accesses that cross into a different region always fail
+/// the permission check on the fast path and are split at the region boundary by the slow path.
 pub struct Mmu {
-    // @fixme: actually keep track of memory that has currently been translated.
+    /// Set whenever a region of code is invalidated (e.g. by [Mmu::flush_code_range]), allowing
+    /// the owner of the code cache to cheaply check whether any translations might be stale.
+    /// This is never cleared by the MMU.
     pub invalidate_icache: bool,
 
-    // @fixme: this currently triggers to many false positives (e.g. due to vectorized loads which
-    // are later masked)
+    /// Controls whether newly mapped memory is treated as uninitialized.
+    ///
+    /// Note: loads where some of the bytes are later discarded (e.g. masked vector loads) should
+    /// use [Mmu::read_allow_uninit] to avoid false positives.
     pub track_uninitialized: bool,
 
-    /// @fixme: handle self-modifying code more carefully.
-    pub detect_self_modifying_code: bool,
+    /// The value used to fill the bytes of a newly allocated physical page that are not part of
+    /// any mapping (and the bytes poisoned by [Mmu::poison_on_unmap]). Defaults to
+    /// [crate::UNINIT_VALUE].
+    pub uninit_value: u8,
+
+    /// Controls whether [Mmu::unmap_memory_len] overwrites the data of unmapped bytes with
+    /// [Mmu::uninit_value], so that stale data cannot be observed if the underlying physical page
+    /// is mapped again (e.g. using [Mmu::map_physical]). Pages shared with other mappings are not
+    /// modified.
+    pub poison_on_unmap: bool,
+
+    /// Controls how writes to code that has been translated are handled.
+    pub self_modifying_code: SelfModifyingCode,
+
+    /// Controls whether reads from pages that have not been allocated and are entirely zero are
+    /// mapped to a shared zero page instead of allocating a new page (see
+    /// [MmuConfig::zero_page_optimization]).
+    pub zero_page_optimization: bool,
+
+    /// Controls whether memory hooks are called (see [MmuConfig::memory_hooks]).
+    pub memory_hooks: bool,
+
+    /// The byte order of the guest, used by the typed accessors (see [MmuConfig::endianness]).
+    pub endianness: Endianness,
+
+    /// Controls whether memory is allowed to be writable and executable at the same time.
+    pub wx_policy: WxPolicy,
+
+    /// Controls whether unaligned accesses are allowed.
+    pub alignment_policy: AlignmentPolicy,
+
+    /// Controls where memory allocated without a fixed address is placed.
+    pub alloc_policy: AllocPolicy,
+
+    /// The seed and the current state of the random number generator used for
+    /// [AllocPolicy::Random].
+    alloc_rng: (u64, u64),
+
+    /// The last address of the address space (see [Mmu::set_address_space_bits]).
+    address_space_end: u64,
+
+    /// Controls whether memory is allowed to be mapped past the end of the address space.
+    pub address_space_policy: AddressSpacePolicy,
+
+    /// The mask applied to the address of every access (see [Mmu::set_address_mask]).
+    address_mask: u64,
+
+    /// The size of a guest pointer in bytes (see [Mmu::set_pointer_size]).
+    pointer_size: usize,
+
+    /// The maximum number of steps taken by helpers that follow guest data (e.g. the length of a
+    /// string read by [Mmu::read_cstr] or the number of nodes visited by [Mmu::follow_list]),
+    /// protecting against unbounded or cyclic traversals of corrupted guest data.
+    pub traversal_limit: usize,
+
+    /// Controls whether I/O regions pass permission checks for ranges (see
+    /// [Mmu::check_perm_range]).
+    pub io_perm_policy: IoPermPolicy,
+
+    /// Controls whether writes reach I/O handlers while I/O reads are being replayed.
+    pub replay_write_policy: ReplayWritePolicy,
+
+    /// Controls the behaviour of accesses that span a boundary between an I/O region and any other
+    /// region. By default the access is split at the boundary (see [Mmu::read_split]), if this is
+    /// set the access instead fails with [MemError::CrossesDeviceBoundary].
+    pub fault_on_io_boundary: bool,
 
-    pub tlb_hit_count: u64,
-    pub tlb_miss_count: u64,
+    /// Controls whether a report is recorded for reads that fail because memory is uninitialized
+    /// (see [Mmu::take_uninit_report]).
+    pub uninit_diagnostics: bool,
+
+    /// Whether labels are tracked for each byte of memory (see [Mmu::enable_shadow]).
+    shadow_enabled: bool,
+
+    /// Controls whether TLB hits and the pages that miss in the TLB are recorded in
+    /// [Mmu::tlb_counters] (see [Mmu::top_missing_pages]). Misses are always counted.
+    pub profile_tlb: bool,
+
+    /// Counts lookups in the TLB since the counters were last reset (see [Mmu::reset_counters]).
+    pub tlb_counters: TlbCounters,
+
+    /// The number of TLB misses for each (page-aligned) address, recorded if
+    /// [Mmu::profile_tlb] is set.
+    tlb_miss_pages: HashMap<u64, u64>,
+
+    /// Set whenever the virtual address space is changed. Consumers are responsible for clearing
+    /// this, so it can only be used by a single consumer.
+    #[deprecated(note = "use `Mmu::on_mapping_change` or `Mmu::mapping_generation` instead")]
     pub mapping_changed: bool,
 
+    /// Incremented whenever the virtual address space is changed (see [Mmu::mapping_generation]).
+    mapping_generation: u64,
+
+    /// Callbacks registered using [Mmu::on_mapping_change].
+    mapping_listeners: Vec<Option<MappingChangeCallback>>,
+
     /// The set of virtual (page-aligned) addresses that have been modified since this was last
     /// cleared.
-    pub modified: HashSet<u64>,
+    pub modified: PageSet,
 
     /// The translation lookahead buffer for the MMU.
     ///
@@ -155,66 +868,839 @@ pub struct Mmu {
     // @fixme: This should not be public, since changes to this require that the `tlb` is flushed.
     pub mapping: RangeMap<MemoryMapping>,
 
+    /// The identifier of the active address space (see [Mmu::switch_address_space]).
+    asid: Asid,
+
+    /// The mappings of every address space indexed by ASID. The entry for the active address space
+    /// is empty, since its mapping is stored in `mapping`.
+    address_spaces: Vec<VirtualMemoryMap>,
+
+    /// The state used for translating addresses with guest page tables, or `None` if the mapping
+    /// is used to translate virtual addresses (see [Mmu::set_page_table_walker]).
+    page_tables: Option<PageTableState>,
+
+    /// The most recent access that failed because of a page table walk.
+    page_fault: Option<PageFault>,
+
     /// Unicorn style memory hooks.
     read_hooks: HookStore<dyn ReadHook>,
     read_after_hooks: HookStore<dyn ReadAfterHook>,
     write_hooks: HookStore<dyn WriteHook>,
 
+    /// Watchpoints added using [Mmu::add_watchpoint].
+    pub(crate) watchpoints: Watchpoints,
+
+    /// Tags attached to ranges of memory using [Mmu::tag_range].
+    tags: Tags,
+
+    /// Budgets that limit the pages allocated for ranges of memory (see
+    /// [Mmu::create_page_budget]).
+    budgets: PageBudgets,
+
     /// The underlying physical memory.
     physical: physical::PhysicalMemory,
 
     /// The parent snapshot for the MMU.
     parent_state: Snapshot,
 
-    /// Registed handlers for I/O memory
-    io: Vec<Box<dyn IoMemoryAny>>,
+    /// Accesses to I/O handlers that have been recorded.
+    io_trace: IoTrace,
+
+    /// The active recording or replay of I/O reads (see [Mmu::start_io_recording]).
+    io_replay: IoReplay,
+
+    /// A log of recent accesses (see [Mmu::enable_access_log]).
+    access_log: AccessLog,
+
+    /// The number of accesses to each (page-aligned) address, if the heatmap is enabled (see
+    /// [Mmu::enable_heatmap]).
+    heatmap: Option<HashMap<u64, PageHeat>>,
+
+    /// Set if either the access log or the heatmap is enabled, checked before every access.
+    record_accesses: bool,
+
+    /// Registed handlers for I/O memory. Handlers that have been unregistered are kept as empty
+    /// slots, so that the position of each handler (which snapshots depend on) never changes.
+    pub(crate) io: Vec<Option<Box<dyn IoMemoryAny>>>,
+
+    /// The names that I/O handlers were registered with (see [Mmu::register_named_io_handler]).
+    io_names: Vec<Option<String>>,
+
+    /// The snapshot policy of each I/O handler (see [Mmu::register_io_handler_with_policy]).
+    /// Handlers without an entry use [SnapshotPolicy::Include].
+    io_snapshot_policies: Vec<SnapshotPolicy>,
+
+    /// The context passed to I/O handlers (see [Mmu::set_io_context]).
+    io_context: u64,
+
+    /// The data of files registered for file-backed mappings.
+    pub(crate) files: Vec<FileData>,
+
+    /// Handler notified whenever a region of code is invalidated.
+    code_invalidation_handler: Option<Box<dyn CodeInvalidationHandler>>,
+
+    /// Ranges of code (inclusive) that have been invalidated while no handler was registered.
+    invalidated_code: Vec<(u64, u64)>,
+
+    /// The number of allocated physical pages that generates a [CapacityEvent] (see
+    /// [Mmu::set_capacity_limits]).
+    soft_capacity: usize,
+
+    /// Events generated since the last call to [Mmu::take_capacity_events].
+    capacity_events: Vec<CapacityEvent>,
+
+    /// The pages that contain translated code, and the physical page backing each of them (see
+    /// [Mmu::translated_code_pages]).
+    code_pages: BTreeMap<u64, physical::Index>,
+
+    /// The report for the first uninitialized read since the report was last taken.
+    uninit_report: Option<UninitReport>,
+
+    /// Handler called whenever an uninitialized read is reported.
+    uninit_handler: Option<UninitHandler>,
+
+    /// The most recent access that failed because it touched a guarded byte.
+    guard_fault: Option<GuardFault>,
+
+    /// Handler called whenever an access touches a guarded byte.
+    guard_handler: Option<GuardHandler>,
 
     /// Last IO memory region read -- IO reads are not currently translatable in the JIT, so always
     /// trigger tlb misses. To mitigate some of the performance impact of repeat accesses to the
     /// same address, we keep track of the last IO handler used and check if it matches the address
     /// before doing a search for the region.
-    last_io_handler: Option<(u64, u64, IoHandler)>,
+    last_io_handler: Option<(u64, u64, IoMapping)>,
+
+    /// The position the garbage collector will resume from on the next call to
+    /// [Mmu::collect_garbage].
+    gc_cursor: GcCursor,
+
+    /// The number of virtual mappings returned from [Mmu::take_virtual_mapping],
+    /// [Mmu::snapshot_virtual_mapping] or [Mmu::clone_virtual_mapping] that have not been restored
+    /// and refer to each physical page (indexed by slot). These pages may not be reachable from
+    /// the current mapping, so they are never freed while they are referenced.
+    detached_refs: Vec<u32>,
+
+    /// The virtual addresses that each physical page is mapped at (see [Mmu::virtual_addrs_of]).
+    reverse_index: RefCell<ReverseIndex>,
 }
 
-impl crate::Resettable for Mmu {
-    fn new() -> Self {
-        Self::new()
-    }
+/// Configuration options that are applied when an [Mmu] is created.
+#[derive(Clone, Debug)]
+pub struct MmuConfig {
+    /// Touch all of the storage used by the TLB when the MMU is created.
+    pub prefault_tlb: bool,
 
-    fn reset(&mut self) {
-        self.clear();
-    }
+    /// The number of physical pages to allocate up front (limited by the page capacity). Reserved
+    /// pages are handed out before any new pages are allocated.
+    ///
+    /// This moves the cost of allocating (and zeroing) pages to startup.
+    pub prereserve_pages: usize,
+
+    /// The number of sets and ways used for the TLB.
+    ///
+    /// A smaller TLB is faster to clear (e.g. when a snapshot is restored), while more sets or
+    /// ways reduce conflict misses between frequently accessed pages.
+    pub tlb: TlbConfig,
+
+    /// Controls whether writes to translated code are detected, setting the initial value of
+    /// [Mmu::self_modifying_code] to [SelfModifyingCode::Fault] (or [SelfModifyingCode::Ignore]).
+    pub detect_self_modifying_code: bool,
+
+    /// The initial value of [Mmu::zero_page_optimization]. Disabling this ensures that every page
+    /// is backed by its own physical page, which can help with debugging page aliasing issues.
+    pub zero_page_optimization: bool,
+
+    /// The initial value of [Mmu::memory_hooks]. Hooks can still be added while memory hooks are
+    /// disabled, but they are never called.
+    pub memory_hooks: bool,
+
+    /// The initial value of [Mmu::endianness]. Accessors with an explicit byte order (e.g.
+    /// [Mmu::read_u32_be]) and raw accesses (e.g. [Mmu::read]) are unaffected.
+    pub endianness: Endianness,
+
+    /// Where the data of physical pages is stored on the host. [PageStore::Mmap] is useful for
+    /// guests with a large capacity, since memory is only committed for pages that are written to
+    /// and is returned to the host when the MMU is cleared or restored.
+    pub page_store: PageStore,
 }
 
-impl Default for Mmu {
+impl Default for MmuConfig {
     fn default() -> Self {
-        Self::new()
+        Self {
+            prefault_tlb: false,
+            prereserve_pages: 0,
+            tlb: TlbConfig::default(),
+            detect_self_modifying_code: DETECT_SELF_MODIFYING_CODE,
+            zero_page_optimization: ENABLE_ZERO_PAGE_OPTIMIZATION,
+            memory_hooks: ENABLE_MEMORY_HOOKS,
+            endianness: Endianness::Little,
+            page_store: PageStore::Heap,
+        }
     }
 }
 
-impl Mmu {
-    pub fn new() -> Self {
-        Self {
-            invalidate_icache: false,
-            track_uninitialized: false,
-            detect_self_modifying_code: DETECT_SELF_MODIFYING_CODE,
-            tlb_hit_count: 0,
-            tlb_miss_count: 0,
-            mapping_changed: false,
-            modified: HashSet::new(),
-            tlb: Box::new(tlb::TranslationCache::new()),
-            mapping: RangeMap::new(),
-            physical: physical::PhysicalMemory::new(physical::MAX_PAGES),
+/// Memory usage statistics for an [Mmu].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// The number of physical pages that are currently in use (includes pages referenced by
+    /// snapshots).
+    pub allocated_pages: usize,
+
+    /// The number of physical pages that are allocated on the host, but are not currently in use
+    /// (e.g. pages reserved by [MmuConfig::prereserve_pages]).
+    pub reserved_pages: usize,
+
+    /// The maximum number of physical pages the MMU is allowed to allocate.
+    pub capacity: usize,
+
+    /// The number of bytes used for the TLB.
+    pub tlb_bytes: usize,
+
+    /// The number of host pages committed for storing page data, if physical memory is backed by
+    /// [PageStore::Mmap].
+    pub committed_host_pages: Option<usize>,
+
+    /// The number of virtual pages that are mapped to a zero page or a shared fill page (see
+    /// [Mmu::map_fill_region]).
+    pub zero_pages: usize,
+
+    /// The number of physical pages in use that share their data with a snapshot or another
+    /// mapping, and will be copied before they are next modified.
+    pub shared_pages: usize,
+
+    /// The number of bytes of virtual memory that are mapped (saturating at `u64::MAX`).
+    pub mapped_bytes: u64,
+
+    /// The number of contiguous ranges of mapped virtual memory.
+    pub regions: usize,
+
+    /// The number of regions that are mapped to I/O handlers.
+    pub io_regions: usize,
+
+    /// The total number of TLB hits (see [TlbCounters::hits]).
+    pub tlb_hits: u64,
+
+    /// The total number of TLB misses (see [TlbCounters::misses]).
+    pub tlb_misses: u64,
+}
+
+impl MemoryStats {
+    /// The fraction of TLB lookups that were hits, or `None` if there were no lookups.
+    pub fn tlb_hit_rate(&self) -> Option<f64> {
+        let total = self.tlb_hits + self.tlb_misses;
+        (total != 0).then(|| self.tlb_hits as f64 / total as f64)
+    }
+}
+
+/// The kind of allocation that caused the number of allocated physical pages to reach the soft
+/// limit (see [Mmu::set_capacity_limits]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocKind {
+    /// A page was allocated when unallocated memory was first accessed.
+    LazyFault,
+
+    /// Pages were explicitly allocated using [Mmu::alloc_physical].
+    Explicit,
+
+    /// A shared page was copied before being modified.
+    CopyOnWrite,
+}
+
+/// An event generated when the number of allocated physical pages reaches the soft limit (see
+/// [Mmu::take_capacity_events]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapacityEvent {
+    /// The kind of allocation that reached the limit.
+    pub kind: AllocKind,
+
+    /// The address that was being accessed by the allocation, if any.
+    pub addr: Option<u64>,
+
+    /// The soft limit at the time of the allocation.
+    pub soft_limit: usize,
+
+    /// The memory usage after the allocation.
+    pub stats: MemoryStats,
+}
+
+/// The number of lookups in the TLB, split by the kind of access.
+///
+/// Hits are only counted while [Mmu::profile_tlb] is set. Accesses to pages that are never cached
+/// in the TLB (e.g. I/O regions and pages with read hooks) are always counted as misses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TlbCounters {
+    pub read_hits: u64,
+    pub read_misses: u64,
+    pub write_hits: u64,
+    pub write_misses: u64,
+}
+
+impl TlbCounters {
+    /// The total number of lookups that hit in the TLB.
+    pub fn hits(&self) -> u64 {
+        self.read_hits + self.write_hits
+    }
+
+    /// The total number of lookups that missed in the TLB.
+    pub fn misses(&self) -> u64 {
+        self.read_misses + self.write_misses
+    }
+}
+
+/// The number of accesses to a page recorded by the heatmap (see [Mmu::heatmap_top]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageHeat {
+    /// The address of the start of the page.
+    pub page: u64,
+
+    /// The number of reads (including instruction fetches) from the page.
+    pub reads: u64,
+
+    /// The number of writes to the page.
+    pub writes: u64,
+}
+
+impl PageHeat {
+    /// The total number of accesses to the page.
+    pub fn total(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+/// Limits the amount of work done by a single call to [Mmu::collect_garbage].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GcBudget {
+    /// The maximum amount of time (in milliseconds) to spend collecting garbage.
+    pub max_millis: u64,
+
+    /// The maximum number of physical pages to examine.
+    pub max_pages_examined: usize,
+}
+
+impl GcBudget {
+    /// A budget that always allows a full collection cycle to complete.
+    pub const UNLIMITED: Self = Self { max_millis: u64::MAX, max_pages_examined: usize::MAX };
+}
+
+impl Default for GcBudget {
+    fn default() -> Self {
+        Self::UNLIMITED
+    }
+}
+
+/// The work done by a call to [Mmu::collect_garbage].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// The number of pages that were examined (pages are examined once by each pass).
+    pub pages_examined: usize,
+
+    /// The number of pages freed because they were no longer referenced by the virtual address
+    /// space.
+    pub unreachable_pages: usize,
+
+    /// The number of pages freed by replacing them with the zero page.
+    pub zero_pages: usize,
+
+    /// Whether the final pass of a collection cycle was completed.
+    pub cycle_complete: bool,
+}
+
+impl GcReport {
+    /// The total number of pages that were freed.
+    pub fn reclaimed_pages(&self) -> usize {
+        self.unreachable_pages + self.zero_pages
+    }
+}
+
+/// The passes performed by the garbage collector, in the order that they are run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum GcPass {
+    #[default]
+    Unreachable,
+    ZeroPages,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct GcCursor {
+    pass: GcPass,
+    slot: usize,
+}
+
+/// An index from physical pages to the (page-aligned) virtual addresses they are mapped at in the
+/// current address space, rebuilt from the mapping whenever the mapping has been modified since
+/// the index was last used.
+#[derive(Default)]
+struct ReverseIndex {
+    /// The generation of the mapping the index was built from (see [RangeMap::generation]).
+    generation: u64,
+    pages: HashMap<physical::Index, Vec<u64>>,
+}
+
+/// How a physical page is referenced by the virtual address space.
+#[derive(Clone, Copy)]
+enum PageUse {
+    /// The page is on the free list.
+    Free,
+
+    /// The page is not referenced by the current mapping.
+    Unreferenced,
+
+    /// The page is referenced by a single mapping entry.
+    Single { start: u64, len: u64 },
+
+    /// The page is referenced by multiple mapping entries.
+    Shared,
+}
+
+/// The state of the MMU while addresses are translated using guest page tables.
+struct PageTableState {
+    /// The walker used for translating addresses, taken while a walk is in progress.
+    walker: Option<Box<dyn PageTableWalker>>,
+
+    /// The physical address of the root page table.
+    root: u64,
+
+    /// Whether accesses are performed in user mode.
+    user: bool,
+
+    /// The (page-aligned) physical addresses of the tables used by translations since the TLB was
+    /// last flushed. These pages are never cached in the TLB for writing, so that writes to the
+    /// page tables can invalidate the translations that depend on them.
+    tables: HashSet<u64>,
+}
+
+impl crate::Resettable for Mmu {
+    fn new() -> Self {
+        Self::new()
+    }
+
+    fn reset(&mut self) {
+        self.clear();
+    }
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mmu {
+    pub fn new() -> Self {
+        Self::with_config(MmuConfig::default())
+    }
+
+    /// Returns a builder for configuring a new MMU along with its initial memory, I/O handlers and
+    /// hooks.
+    pub fn builder() -> MmuBuilder {
+        MmuBuilder::default()
+    }
+
+    #[allow(deprecated)]
+    pub fn with_config(config: MmuConfig) -> Self {
+        let mut mmu = Self {
+            invalidate_icache: false,
+            track_uninitialized: false,
+            uninit_value: crate::UNINIT_VALUE,
+            poison_on_unmap: false,
+            self_modifying_code: match config.detect_self_modifying_code {
+                true => SelfModifyingCode::Fault,
+                false => SelfModifyingCode::Ignore,
+            },
+            zero_page_optimization: config.zero_page_optimization,
+            memory_hooks: config.memory_hooks,
+            endianness: config.endianness,
+            wx_policy: WxPolicy::default(),
+            alignment_policy: AlignmentPolicy::default(),
+            alloc_policy: AllocPolicy::default(),
+            alloc_rng: (0, 0),
+            address_space_end: u64::MAX,
+            address_space_policy: AddressSpacePolicy::default(),
+            io_perm_policy: IoPermPolicy::default(),
+            replay_write_policy: ReplayWritePolicy::default(),
+            address_mask: u64::MAX,
+            pointer_size: 8,
+            traversal_limit: DEFAULT_TRAVERSAL_LIMIT,
+            fault_on_io_boundary: false,
+            uninit_diagnostics: false,
+            shadow_enabled: false,
+            profile_tlb: false,
+            tlb_counters: TlbCounters::default(),
+            tlb_miss_pages: HashMap::new(),
+            mapping_changed: false,
+            mapping_generation: 0,
+            mapping_listeners: vec![],
+            modified: PageSet::new(),
+            tlb: Box::new(tlb::TranslationCache::with_config(config.tlb)),
+            mapping: RangeMap::new(),
+            asid: Asid::DEFAULT,
+            address_spaces: vec![RangeMap::new()],
+            page_tables: None,
+            page_fault: None,
+            physical: physical::PhysicalMemory::with_store(physical::MAX_PAGES, config.page_store),
             parent_state: Snapshot::new(SnapshotData::new()),
             io: vec![],
+            io_names: vec![],
+            io_snapshot_policies: vec![],
+            io_context: 0,
+            files: vec![],
+            io_trace: IoTrace::new(),
+            io_replay: IoReplay::new(),
+            access_log: AccessLog::default(),
+            heatmap: None,
+            record_accesses: false,
 
             read_hooks: HookStore::new(),
             read_after_hooks: HookStore::new(),
             write_hooks: HookStore::new(),
+            watchpoints: Watchpoints::default(),
+            tags: Tags::default(),
+            budgets: PageBudgets::default(),
+            code_invalidation_handler: None,
+            invalidated_code: vec![],
+            soft_capacity: usize::MAX,
+            capacity_events: vec![],
+            code_pages: BTreeMap::new(),
+            uninit_report: None,
+            uninit_handler: None,
+            guard_fault: None,
+            guard_handler: None,
             last_io_handler: None,
+            gc_cursor: GcCursor::default(),
+            detached_refs: vec![],
+            reverse_index: RefCell::default(),
+        };
+
+        if config.prefault_tlb {
+            mmu.tlb.prefault();
+        }
+        mmu.physical.reserve(config.prereserve_pages);
+
+        mmu
+    }
+
+    /// Initializes any lazily allocated internal state, so that after the process is forked, the
+    /// children share as much (already initialized) memory with the parent as possible.
+    pub fn prepare_for_fork(&mut self) {
+        self.tlb.prefault();
+
+        // Ensure that the first write to any of the existing pages (including pages that are
+        // allocated lazily) does not require storage to be allocated in the modification log.
+        for (start, end, entry) in self.mapping.iter() {
+            match entry {
+                MemoryMapping::Physical(_)
+                | MemoryMapping::Unallocated(_)
+                | MemoryMapping::File(_) => self.modified.reserve(start, end),
+                MemoryMapping::Io(_) | MemoryMapping::Reserved(_) => {}
+            }
+        }
+    }
+
+    /// Returns statistics about the memory used by the MMU.
+    ///
+    /// This iterates over the virtual address space and the physical pages that are in use, but
+    /// does not access the content of any memory.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats {
+            allocated_pages: self.physical.allocated_pages(),
+            reserved_pages: self.physical.free_pages(),
+            capacity: self.physical.capacity(),
+            tlb_bytes: self.tlb.config().size_in_bytes(),
+            committed_host_pages: self.physical.committed_host_pages(),
+            shared_pages: self.physical.shared_pages(),
+            tlb_hits: self.tlb_counters.hits(),
+            tlb_misses: self.tlb_counters.misses(),
+            ..MemoryStats::default()
+        };
+
+        let mut prev_end: Option<u64> = None;
+        for (start, end, entry) in self.mapping.iter() {
+            stats.mapped_bytes = stats.mapped_bytes.saturating_add((end - start).saturating_add(1));
+            if prev_end.and_then(|end| end.checked_add(1)) != Some(start) {
+                stats.regions += 1;
+            }
+            prev_end = Some(end);
+
+            match entry {
+                MemoryMapping::Physical(x) if self.physical.is_fill_page(x.index) => {
+                    stats.zero_pages += 1
+                }
+                MemoryMapping::Io(_) => stats.io_regions += 1,
+                _ => {}
+            }
+        }
+
+        stats
+    }
+
+    /// Resets [Mmu::tlb_counters] and the per-page miss counts (see [Mmu::top_missing_pages]).
+    ///
+    /// Note: the counters are not captured by snapshots, so restoring a snapshot does not reset
+    /// them.
+    pub fn reset_counters(&mut self) {
+        self.tlb_counters = TlbCounters::default();
+        self.tlb_miss_pages.clear();
+    }
+
+    /// Returns up to `n` pages with the most TLB misses as `(page_addr, misses)`, ordered by the
+    /// number of misses (highest first). Misses are only recorded for each page while
+    /// [Mmu::profile_tlb] is set.
+    pub fn top_missing_pages(&self, n: usize) -> Vec<(u64, u64)> {
+        let mut pages: Vec<_> = self.tlb_miss_pages.iter().map(|(addr, n)| (*addr, *n)).collect();
+        pages.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pages.truncate(n);
+        pages
+    }
+
+    /// Records a TLB miss for an access at `addr`.
+    #[inline(always)]
+    fn record_tlb_miss(&mut self, addr: u64, is_write: bool) {
+        match is_write {
+            true => self.tlb_counters.write_misses += 1,
+            false => self.tlb_counters.read_misses += 1,
         }
+        if self.profile_tlb {
+            *self.tlb_miss_pages.entry(self.page_aligned(addr)).or_default() += 1;
+        }
+    }
+
+    /// Frees physical pages that are not needed to represent the current state of memory, stopping
+    /// once `budget` is exhausted. Each call resumes from where the previous call stopped.
+    ///
+    /// The collector runs the following passes (cheapest first):
+    ///
+    /// 1. Pages that are not referenced by the virtual address space are freed (including
+    ///    copy-on-write pages that were only shared with mappings that have since been replaced,
+    ///    e.g. by [Mmu::restore_virtual_mapping]), and copy-on-write pages that are only referenced
+    ///    once are marked as no longer being shared.
+    /// 2. Pages that only contain zeroes (with the same permissions as a zero page) are replaced
+    ///    with the zero page.
+    ///
+    /// Pages allocated by [Mmu::alloc_physical] (which are owned by the caller), and pages
+    /// referenced by a mapping returned by [Mmu::take_virtual_mapping],
+    /// [Mmu::snapshot_virtual_mapping] or [Mmu::clone_virtual_mapping] that has not been restored
+    /// are skipped by both passes.
+    ///
+    /// Pages captured by a [Snapshot] do not need to be tracked, since restoring a snapshot also
+    /// restores the state of the physical allocator.
+    ///
+    /// This does not change the content or permissions of any memory, and pages that are mapped
+    /// at multiple locations or that contain translated code are never reclaimed, so it is safe to
+    /// call between any two guest operations.
+    pub fn collect_garbage(&mut self, budget: GcBudget) -> GcReport {
+        let start_time = std::time::Instant::now();
+        let mut report = GcReport::default();
+
+        let mut uses = self.page_uses();
+        loop {
+            if report.pages_examined >= budget.max_pages_examined
+                || start_time.elapsed().as_millis() >= budget.max_millis as u128
+            {
+                break;
+            }
+
+            let GcCursor { pass, slot } = self.gc_cursor;
+            if slot >= uses.len() {
+                self.gc_cursor = match pass {
+                    GcPass::Unreachable => GcCursor { pass: GcPass::ZeroPages, slot: 0 },
+                    GcPass::ZeroPages => {
+                        report.cycle_complete = true;
+                        GcCursor::default()
+                    }
+                };
+                if report.cycle_complete {
+                    break;
+                }
+                continue;
+            }
+            self.gc_cursor.slot += 1;
+
+            let index = physical::Index::from_slot(slot);
+            if self.physical.is_fill_page(index) || matches!(uses[slot], PageUse::Free) {
+                continue;
+            }
+            report.pages_examined += 1;
+
+            let reclaimed = match pass {
+                GcPass::Unreachable => self.reclaim_unreachable_page(index, uses[slot]),
+                GcPass::ZeroPages => self.reclaim_zero_page(index, uses[slot]),
+            };
+            if reclaimed {
+                uses[slot] = PageUse::Free;
+                match pass {
+                    GcPass::Unreachable => report.unreachable_pages += 1,
+                    GcPass::ZeroPages => report.zero_pages += 1,
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Determines how each physical page is referenced by the virtual address space.
+    fn page_uses(&self) -> Vec<PageUse> {
+        let mut uses = vec![PageUse::Unreferenced; self.physical.slots()];
+        for index in self.physical.free_list() {
+            uses[index.slot()] = PageUse::Free;
+        }
+        for (start, end, entry) in self.mapping.iter() {
+            if let MemoryMapping::Physical(mapping) = entry {
+                let page_use = &mut uses[mapping.index.slot()];
+                *page_use = match page_use {
+                    PageUse::Unreferenced => PageUse::Single { start, len: end - start + 1 },
+                    _ => PageUse::Shared,
+                };
+            }
+        }
+        // Pages referenced by other address spaces are never modified by the collector.
+        for (_, mapping) in self.inactive_address_spaces() {
+            for (_, _, entry) in mapping.iter() {
+                if let MemoryMapping::Physical(mapping) = entry {
+                    uses[mapping.index.slot()] = PageUse::Shared;
+                }
+            }
+        }
+        uses
+    }
+
+    /// Merges physical pages that have identical content and permissions, returning the number of
+    /// pages that were freed.
+    ///
+    /// Duplicate pages are remapped to a single page that is marked as copy-on-write, so a later
+    /// write to any of the merged pages creates a private copy again. Only pages that are mapped
+    /// at a single location are merged: pages that are shared (copy-on-write or aliased), contain
+    /// translated code or have labels are skipped, as are pages owned by the caller of
+    /// [Mmu::alloc_physical] and pages referenced by a mapping that has been detached from the MMU
+    /// (see [Mmu::collect_garbage]).
+    ///
+    /// This scans every allocated page, so it is intended to be called occasionally (e.g., when
+    /// [Mmu::total_pages] approaches [Mmu::capacity]).
+    pub fn dedup_pages(&mut self) -> usize {
+        let mut groups: HashMap<u64, Vec<(physical::Index, u64)>> = HashMap::new();
+        for (slot, page_use) in self.page_uses().into_iter().enumerate() {
+            let PageUse::Single { start, len } = page_use
+            else {
+                continue;
+            };
+            let index = physical::Index::from_slot(slot);
+            let page = self.physical.get(index);
+            if index.is_zero_page()
+                || len != self.page_size()
+                || page.copy_on_write
+                || page.executed
+                || page.aliased
+                || page.owned
+                || self.is_detached(index)
+                || page.has_shadow()
+                || page.is_shared_memory()
+            {
+                continue;
+            }
+
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            page.data().data.hash(&mut hasher);
+            page.data().perm.hash(&mut hasher);
+            groups.entry(hasher.finish()).or_default().push((index, start));
+        }
+
+        let mut freed = 0;
+        for pages in groups.into_values().filter(|pages| pages.len() > 1) {
+            let mut remaining = pages;
+            while let Some((keep, keep_start)) = remaining.pop() {
+                let (duplicates, unique): (Vec<_>, Vec<_>) =
+                    remaining.into_iter().partition(|(index, _)| {
+                        let (a, b) =
+                            (self.physical.get(keep).data(), self.physical.get(*index).data());
+                        a.data == b.data && a.perm == b.perm
+                    });
+                remaining = unique;
+                if duplicates.is_empty() {
+                    continue;
+                }
+
+                self.physical.get_mut(keep).copy_on_write = true;
+                self.tlb.remove(keep_start);
+                for (index, start) in duplicates {
+                    tracing::trace!("dedup_pages: replacing {index:?} ({start:#x}) with {keep:?}");
+                    let mapping =
+                        MemoryMapping::Physical(PhysicalMapping { index: keep, addr: start });
+                    let end = start + (self.page_size() - 1);
+                    let _ = self.mapping.overlapping_mut::<_, ()>(start..=end, |_, _, entry| {
+                        *entry = Some(mapping.clone());
+                        Ok(())
+                    });
+                    self.tlb.remove(start);
+                    self.physical.free(index);
+                    freed += 1;
+                }
+            }
+        }
+
+        if freed != 0 {
+            self.notify_mapping_change(MappingChangeKind::Replaced, 0, u64::MAX);
+        }
+        freed
+    }
+
+    fn reclaim_unreachable_page(&mut self, index: physical::Index, page_use: PageUse) -> bool {
+        let detached = self.is_detached(index);
+        let page = self.physical.get_mut(index);
+        match page_use {
+            PageUse::Unreferenced if !page.executed && !page.owned && !detached => {
+                self.physical.free(index);
+                true
+            }
+            PageUse::Single { start, .. } if page.copy_on_write && !detached => {
+                // The page is no longer shared, so future writes can modify it in-place.
+                tracing::trace!("collect_garbage: {index:?} ({start:#x}) is no longer shared");
+                page.copy_on_write = false;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn reclaim_zero_page(&mut self, index: physical::Index, page_use: PageUse) -> bool {
+        let PageUse::Single { start, len } = page_use
+        else {
+            return false;
+        };
+        let page = self.physical.get(index);
+        if !self.zero_page_optimization
+            || len != self.page_size()
+            || page.copy_on_write
+            || page.owned
+            || self.is_detached(index)
+            || page.executed
+            || page.has_shadow()
+            || page.is_shared_memory()
+        {
+            return false;
+        }
+
+        let data = page.data();
+        let Some(zero_page) = self.physical.get_zero_page(data.perm[0])
+        else {
+            return false;
+        };
+        if data.data.iter().any(|x| *x != 0) || data.perm.iter().any(|p| *p != data.perm[0]) {
+            return false;
+        }
+
+        tracing::trace!("collect_garbage: replacing {index:?} ({start:#x}) with {zero_page:?}");
+        let mapping = MemoryMapping::Physical(PhysicalMapping { index: zero_page, addr: start });
+        let _ = self.mapping.overlapping_mut::<_, ()>(start..=start + (len - 1), |_, _, entry| {
+            *entry = Some(mapping.clone());
+            Ok(())
+        });
+        self.tlb.remove(start);
+        self.physical.free(index);
+        true
     }
 
+    /// Adds a hook that is called for writes to `start..end` (see [HookEntry] for how the range
+    /// is interpreted). Every range is valid, so this currently always returns `Some`.
     pub fn add_write_hook(
         &mut self,
         start: u64,
@@ -234,6 +1720,7 @@ impl Mmu {
         &mut self.write_hooks.hooks[id as usize]
     }
 
+    /// Adds a hook that is called for reads from `start..end` (see [Mmu::add_write_hook]).
     pub fn add_read_hook(&mut self, start: u64, end: u64, hook: Box<dyn ReadHook>) -> Option<u32> {
         self.tlb.clear();
         Some(self.read_hooks.add(start, end, hook))
@@ -248,6 +1735,7 @@ impl Mmu {
         &mut self.read_hooks.hooks[id as usize]
     }
 
+    /// Adds a hook that is called after reads from `start..end` (see [Mmu::add_write_hook]).
     pub fn add_read_after_hook(
         &mut self,
         start: u64,
@@ -267,1022 +1755,6043 @@ impl Mmu {
         &mut self.read_after_hooks.hooks[id as usize]
     }
 
-    pub fn clear(&mut self) {
+    /// Adds a watchpoint for the `len` bytes starting at `start` that is triggered by accesses of
+    /// `kind`, returning `None` if the range is empty or overflows the address space.
+    ///
+    /// Unlike memory hooks, hits are only reported for accesses that touch a watched byte,
+    /// including accesses that start before the watched range. Accesses that ignore permissions
+    /// (i.e. with [perm::NONE]) and instruction fetches never trigger watchpoints. Watchpoints are
+    /// not part of snapshots, so they are kept when a snapshot is restored.
+    pub fn add_watchpoint(
+        &mut self,
+        start: u64,
+        len: u64,
+        kind: WatchpointKind,
+    ) -> Option<WatchpointId> {
+        if len == 0 {
+            return None;
+        }
+        let end = start.checked_add(len - 1)?;
         self.tlb.clear();
-        self.write_hooks.hooks.clear();
-        self.read_hooks.hooks.clear();
-        self.read_after_hooks.hooks.clear();
-        self.mapping = RangeMap::new();
-        self.physical.clear();
-        self.last_io_handler = None;
+        Some(self.watchpoints.add(start, end, kind))
     }
 
-    /// Get size (in bytes) of a single page in physical memory.
-    #[inline]
-    pub fn page_size(&self) -> u64 {
-        self.physical.page_size()
+    /// Removes a watchpoint added using [Mmu::add_watchpoint].
+    pub fn remove_watchpoint(&mut self, id: WatchpointId) -> bool {
+        self.watchpoints.remove(id)
     }
 
-    /// Get the offset within a page of an address
-    #[inline]
-    pub fn page_offset(&self, addr: u64) -> usize {
-        physical::PageData::offset(addr)
+    /// Returns (and clears) the accesses that triggered watchpoints, in the order they occurred.
+    pub fn take_watchpoint_hits(&mut self) -> Vec<WatchpointHit> {
+        self.watchpoints.take_hits()
     }
 
-    /// Align `addr` to a page boundary for the current physical memory configuration.
-    #[inline]
-    pub fn page_aligned(&self, addr: u64) -> u64 {
-        self.physical.page_aligned(addr)
+    /// Attaches `label` to the `len` bytes starting at `start`, returning `None` if the range is
+    /// empty or overflows the address space.
+    ///
+    /// Tags are only used for diagnostics: they are included in fault details (e.g.
+    /// [GuardFault::tags] and [UninitReport::tags]) and can be queried using [Mmu::tags_at] and
+    /// [Mmu::regions_with_tags]. Ranges can have any number of (possibly overlapping) tags, and
+    /// tags are independent of the mapping of the range, so they are kept if the range is
+    /// unmapped. Tags are not part of snapshots, so they are kept when a snapshot is restored.
+    pub fn tag_range(&mut self, start: u64, len: u64, label: impl Into<TagLabel>) -> Option<TagId> {
+        if len == 0 {
+            return None;
+        }
+        let end = start.checked_add(len - 1)?;
+        Some(self.tags.add(start, end, label.into()))
     }
 
-    /// Returns the total number of allocated pages (includes pages referenced by snapshots)
-    pub fn total_pages(&self) -> usize {
-        self.physical.allocated_pages()
+    /// Removes a tag added using [Mmu::tag_range].
+    pub fn untag(&mut self, id: TagId) -> bool {
+        self.tags.remove(id)
     }
 
-    /// Gets the current physical memory page limit.
-    pub fn capacity(&self) -> usize {
-        self.physical.capacity()
+    /// Returns the tags covering `addr`, ordered by when they were added.
+    pub fn tags_at(&self, addr: u64) -> impl Iterator<Item = &Tag> {
+        self.tags.at(addr)
     }
 
-    /// Sets the maximum number of physical pages the mmu is allowed to allocate.
-    ///
-    /// Note: If `new_capacity` is smaller than the current number of allocated pages, then the
-    /// capacity is set to the number of allocated pages.
-    pub fn set_capacity(&mut self, new_capacity: usize) -> bool {
-        self.physical.set_capacity(new_capacity)
+    /// Returns the same regions as [Mmu::regions], along with the tags that overlap with each
+    /// region ordered by when they were added.
+    pub fn regions_with_tags(&self) -> impl Iterator<Item = (Region, Vec<&Tag>)> {
+        self.regions()
+            .map(|region| (region, self.tags.overlapping(region.start, region.end).collect()))
     }
 
-    /// Read bytes from `addr` checking that the permissions specified by `perm` are set
-    pub fn read_bytes(&mut self, mut addr: u64, buf: &mut [u8], perm: u8) -> MemResult<()> {
-        if buf.len() > 16 {
-            return self.read_bytes_large(addr, buf, perm);
-        }
+    /// Creates a budget that limits the number of pages allocated for the ranges assigned to it
+    /// (see [Mmu::assign_budget]) to `limit`.
+    ///
+    /// Pages are charged to a budget when they are allocated lazily, i.e. when unallocated memory
+    /// is first accessed or when a shared page is copied on write, and are refunded when they are
+    /// unmapped. Once a budget is exhausted, accesses that need to allocate a page for one of its
+    /// ranges fail with [MemError::OutOfMemory], without affecting other ranges. The usage of every
+    /// budget is part of snapshots, so it is rolled back when a snapshot is restored.
+    pub fn create_page_budget(&mut self, limit: usize) -> BudgetId {
+        self.budgets.create(limit)
+    }
 
-        for byte in buf {
-            *byte = self.read::<1>(addr, perm)?[0];
-            addr = addr.wrapping_add(1);
-        }
-        Ok(())
+    /// Changes the limit of `id`. Pages that are already charged to the budget are kept even if
+    /// the usage of the budget exceeds the new limit.
+    pub fn set_budget_limit(&mut self, id: BudgetId, limit: usize) -> bool {
+        self.budgets.set_limit(id, limit)
     }
 
-    /// Read bytes from `addr` checking that the permissions specified by `perm` are set
-    #[cold]
-    pub fn read_bytes_large(&mut self, mut addr: u64, buf: &mut [u8], perm: u8) -> MemResult<()> {
-        // Read unaligned bytes at the start
-        let aligned_addr = crate::align_up(addr, 16); // @fixme: possible integer overflow
-        let (start, buf) = buf.split_at_mut(((aligned_addr - addr) as usize).min(buf.len()));
-        for byte in start {
-            *byte = self.read::<1>(addr, perm)?[0];
-            addr = addr.wrapping_add(1);
+    /// Assigns the `len` bytes starting at `start` to the budget `id`, replacing any budget that
+    /// was previously assigned to the range. Returns `false` if the range is empty or overflows the
+    /// address space, or `id` is not a valid budget.
+    ///
+    /// The assignment is removed when the range is unmapped. Pages that were allocated before the
+    /// range was assigned are not charged until they are next copied on write.
+    pub fn assign_budget(&mut self, start: u64, len: u64, id: BudgetId) -> bool {
+        if len == 0 {
+            return false;
         }
+        let Some(end) = start.checked_add(len - 1)
+        else {
+            return false;
+        };
+        self.budgets.assign(start, end, id)
+    }
 
-        // Read aligned chunks
-        let mut chunks = buf.chunks_exact_mut(16);
-        for chunk in &mut chunks {
-            chunk.copy_from_slice(&self.read::<16>(addr, perm)?);
-            addr = addr.wrapping_add(16);
+    /// Returns the number of pages currently charged to `id`, or `None` if `id` is not a valid
+    /// budget.
+    pub fn budget_usage(&self, id: BudgetId) -> Option<usize> {
+        self.budgets.usage(id)
+    }
+
+    pub fn clear(&mut self) {
+        self.tlb.clear();
+        self.write_hooks.hooks.clear();
+        self.read_hooks.hooks.clear();
+        self.read_after_hooks.hooks.clear();
+        self.watchpoints = Watchpoints::default();
+        self.tags.clear();
+        self.budgets = PageBudgets::default();
+        self.mapping = RangeMap::new();
+        self.asid = Asid::DEFAULT;
+        self.address_spaces = vec![RangeMap::new()];
+        self.tlb.set_asid(0);
+        // Drop the reference to the last snapshot so that the pages it uses can be released.
+        self.parent_state = Snapshot::default();
+        self.physical.clear();
+        self.invalidated_code.clear();
+        self.code_pages.clear();
+        self.last_io_handler = None;
+        self.gc_cursor = GcCursor::default();
+    }
+
+    /// Registers a handler that is notified whenever a range of (previously executed) code is
+    /// invalidated. Any ranges that were invalidated before the handler was registered are passed
+    /// to the handler immediately.
+    pub fn set_code_invalidation_handler(&mut self, handler: Box<dyn CodeInvalidationHandler>) {
+        let handler = self.code_invalidation_handler.insert(handler);
+        for (start, end) in self.invalidated_code.drain(..) {
+            handler.invalidate(start, end);
         }
+    }
 
-        // Read unaligned bytes at the end
-        for byte in chunks.into_remainder() {
-            *byte = self.read::<1>(addr, perm)?[0];
-            addr = addr.wrapping_add(1);
+    /// Removes the current code invalidation handler (if any), returning it.
+    pub fn take_code_invalidation_handler(&mut self) -> Option<Box<dyn CodeInvalidationHandler>> {
+        self.code_invalidation_handler.take()
+    }
+
+    /// Returns the (inclusive) ranges of code that have been invalidated since the last call to
+    /// this function. Ranges are only queued here if there is no code invalidation handler.
+    pub fn take_invalidated_code_ranges(&mut self) -> Vec<(u64, u64)> {
+        std::mem::take(&mut self.invalidated_code)
+    }
+
+    /// Returns the pages that contain translated code (i.e. code checked by
+    /// [Mmu::ensure_executable] that has not been invalidated since), along with the physical page
+    /// backing each of them, ordered by address.
+    ///
+    /// A page is invalidated when its code is modified (with [SelfModifyingCode::Invalidate]),
+    /// flushed, unmapped, moved, or has its permissions changed, or when it is backed by a
+    /// different (or modified) physical page after the virtual mapping is replaced (e.g. by
+    /// [Mmu::restore]). The page is reported to the code invalidation handler (or queued for
+    /// [Mmu::take_invalidated_code_ranges]) and is not returned here until the code is translated
+    /// again.
+    pub fn translated_code_pages(&self) -> impl Iterator<Item = (u64, physical::Index)> + '_ {
+        self.code_pages.iter().map(|(addr, index)| (*addr, *index))
+    }
+
+    /// Invalidates the translated code pages that overlap with `start..=end`.
+    fn invalidate_code_pages(&mut self, start: u64, end: u64) {
+        let first = self.page_aligned(start);
+        let pages: Vec<_> = self.code_pages.range(first..=end).map(|(addr, _)| *addr).collect();
+        self.invalidate_translated_pages(pages);
+    }
+
+    /// Reports each of `pages` (sorted by address) as invalidated code, merging adjacent pages so
+    /// that the owner of the code cache is notified once for each contiguous range.
+    fn invalidate_translated_pages(&mut self, pages: Vec<u64>) {
+        let page_mask = self.page_size() - 1;
+        let mut ranges: Vec<(u64, u64)> = vec![];
+        for addr in pages {
+            match ranges.last_mut() {
+                Some((_, end)) if end.checked_add(1) == Some(addr) => *end = addr + page_mask,
+                _ => ranges.push((addr, addr + page_mask)),
+            }
         }
+        for (start, end) in ranges {
+            self.invalidate_code(start, end);
+        }
+    }
 
-        Ok(())
+    /// Invalidates the translated code pages that will not be backed by the same physical page
+    /// once the virtual mapping is replaced with `mapping`, or (if `physical` is set) whose page
+    /// does not have the same data in `physical`.
+    fn invalidate_replaced_code_pages(
+        &mut self,
+        mapping: &VirtualMemoryMap,
+        physical: Option<&physical::PhysicalMemory>,
+    ) {
+        let current = &self.physical;
+        let mut invalidated = vec![];
+        self.code_pages.retain(|addr, index| {
+            let same_page = matches!(
+                mapping.get(*addr),
+                Some(MemoryMapping::Physical(entry)) if entry.index == *index
+            );
+            let same_data = physical.is_none_or(|physical| {
+                index.slot() < physical.slots()
+                    && current.get(*index).shares_data_with(physical.get(*index))
+            });
+            if !(same_page && same_data) {
+                invalidated.push(*addr);
+                return false;
+            }
+            true
+        });
+        self.invalidate_translated_pages(invalidated);
     }
 
-    /// Write bytes bytes `addr` checking that the permission specified by `perm` are set and
-    /// marking the range written with the `INIT` permission bit.
-    pub fn write_bytes(&mut self, mut addr: u64, buf: &[u8], perm: u8) -> MemResult<()> {
+    /// Notifies the owner of the code cache that any code in `start..=end` is no longer valid.
+    fn invalidate_code(&mut self, start: u64, end: u64) {
+        debug!("invalidate_code: start={start:#0x}, end={end:#0x}");
+        self.invalidate_icache = true;
+        let first = self.page_aligned(start);
+        let pages: Vec<_> = self.code_pages.range(first..=end).map(|(addr, _)| *addr).collect();
+        for addr in pages {
+            self.code_pages.remove(&addr);
+        }
+        match self.code_invalidation_handler.as_mut() {
+            Some(handler) => handler.invalidate(start, end),
+            None if self.invalidated_code.last() == Some(&(start, end)) => {}
+            None => self.invalidated_code.push((start, end)),
+        }
+    }
+
+    /// Get size (in bytes) of a single page in physical memory.
+    #[inline]
+    pub fn page_size(&self) -> u64 {
+        self.physical.page_size()
+    }
+
+    /// Get the offset within a page of an address
+    #[inline]
+    pub fn page_offset(&self, addr: u64) -> usize {
+        physical::PageData::offset(addr)
+    }
+
+    /// Align `addr` to a page boundary for the current physical memory configuration.
+    #[inline]
+    pub fn page_aligned(&self, addr: u64) -> u64 {
+        self.physical.page_aligned(addr)
+    }
+
+    /// Returns the total number of allocated pages (includes pages referenced by snapshots)
+    pub fn total_pages(&self) -> usize {
+        self.physical.allocated_pages()
+    }
+
+    /// Gets the current physical memory page limit.
+    pub fn capacity(&self) -> usize {
+        self.physical.capacity()
+    }
+
+    /// Sets the maximum number of physical pages the mmu is allowed to allocate.
+    ///
+    /// Note: If `new_capacity` is smaller than the current number of allocated pages, then the
+    /// capacity is set to the number of allocated pages.
+    pub fn set_capacity(&mut self, new_capacity: usize) -> bool {
+        self.physical.set_capacity(new_capacity)
+    }
+
+    /// Sets the maximum number of physical pages the mmu is allowed to allocate to `hard` (see
+    /// [Mmu::set_capacity]), and the number of pages that generates a [CapacityEvent] to `soft`.
+    ///
+    /// An event is queued whenever an allocation causes the number of allocated pages to reach
+    /// the soft limit, giving the caller a chance to free memory (e.g. by dropping snapshots or
+    /// calling [Mmu::collect_garbage]) or to stop the guest before the hard limit is reached.
+    /// Events are queued rather than delivered to a callback, so the state of the mmu can be
+    /// freely modified when handling them.
+    pub fn set_capacity_limits(&mut self, soft: usize, hard: usize) -> bool {
+        self.soft_capacity = soft.min(hard);
+        self.set_capacity(hard)
+    }
+
+    /// Returns the number of allocated physical pages that generates a [CapacityEvent] (see
+    /// [Mmu::set_capacity_limits]).
+    pub fn soft_capacity(&self) -> usize {
+        self.soft_capacity
+    }
+
+    /// Returns (and clears) the events generated when the soft capacity limit was reached since
+    /// the last call to this function.
+    pub fn take_capacity_events(&mut self) -> Vec<CapacityEvent> {
+        std::mem::take(&mut self.capacity_events)
+    }
+
+    /// Queues a [CapacityEvent] if the number of allocated pages has reached the soft limit since
+    /// there were `prev_allocated` pages.
+    fn check_soft_capacity(&mut self, prev_allocated: usize, kind: AllocKind, addr: Option<u64>) {
+        let allocated = self.physical.allocated_pages();
+        if prev_allocated < self.soft_capacity && allocated >= self.soft_capacity {
+            tracing::debug!("reached soft capacity limit ({allocated} pages) with {kind:?}");
+            let stats = self.memory_stats();
+            self.capacity_events.push(CapacityEvent {
+                kind,
+                addr,
+                soft_limit: self.soft_capacity,
+                stats,
+            });
+        }
+    }
+
+    /// Read bytes from `addr` checking that the permissions specified by `perm` are set
+    pub fn read_bytes(&mut self, mut addr: u64, buf: &mut [u8], perm: u8) -> MemResult<()> {
         if buf.len() > 16 {
-            return self.write_bytes_large(addr, buf, perm);
+            return self.read_bytes_large(addr, buf, perm);
         }
 
         for byte in buf {
-            self.write(addr, [*byte], perm)?;
+            *byte = self.read::<1>(addr, perm)?[0];
             addr = addr.wrapping_add(1);
         }
         Ok(())
     }
 
-    /// Write bytes bytes `addr` checking that the permission specified by `perm` are set and
-    /// marking the range written with the `INIT` permission bit.
+    /// Read bytes from `addr` checking that the permissions specified by `perm` are set
     #[cold]
-    pub fn write_bytes_large(&mut self, mut addr: u64, buf: &[u8], perm: u8) -> MemResult<()> {
-        // Write unaligned bytes at the start
-        let aligned_addr = crate::align_up(addr, 16); // @fixme: possible integer overflow
-        let (start, buf) = buf.split_at(((aligned_addr - addr) as usize).min(buf.len()));
+    pub fn read_bytes_large(&mut self, mut addr: u64, buf: &mut [u8], perm: u8) -> MemResult<()> {
+        // Read unaligned bytes at the start. Note: the number of bytes until the next aligned
+        // address is computed with wrapping arithmetic, so for addresses near the end of
+        // the address space the aligned chunks start after wrapping around to zero.
+        let unaligned_len = (addr.wrapping_neg() & 15) as usize;
+        let (start, buf) = buf.split_at_mut(unaligned_len.min(buf.len()));
         for byte in start {
-            self.write(addr, [*byte], perm)?;
+            *byte = self.read::<1>(addr, perm)?[0];
             addr = addr.wrapping_add(1);
         }
 
-        // Write aligned chunks
-        let mut chunks = buf.chunks_exact(16);
+        // Read aligned chunks
+        let mut chunks = buf.chunks_exact_mut(16);
         for chunk in &mut chunks {
-            self.write::<16>(addr, chunk.try_into().unwrap(), perm)?;
+            chunk.copy_from_slice(&self.read::<16>(addr, perm)?);
             addr = addr.wrapping_add(16);
         }
 
-        // Write unaligned bytes at the end
-        for byte in chunks.remainder() {
-            self.write(addr, [*byte], perm)?;
+        // Read unaligned bytes at the end
+        for byte in chunks.into_remainder() {
+            *byte = self.read::<1>(addr, perm)?[0];
             addr = addr.wrapping_add(1);
         }
 
         Ok(())
     }
 
-    /// Register a handler function that can be mapped to memory locations
-    pub fn register_io_handler(&mut self, handler: impl IoMemory + 'static) -> IoHandler {
-        let id = self.io.len();
-        self.io.push(Box::new(handler));
-        IoHandler(id)
+    /// Returns a cursor for reading and writing memory starting at `addr` using the [std::io]
+    /// traits, checking that the permissions specified by `perm` are set for every access (see
+    /// [MemCursor]).
+    pub fn cursor(&mut self, addr: u64, perm: u8) -> MemCursor<'_> {
+        MemCursor::new(self, addr, perm)
     }
 
-    /// Get the memory associated with an I/O handle
-    pub fn get_io_memory_mut(&mut self, handler: IoHandler) -> &mut dyn IoMemoryAny {
-        &mut *self.io[handler.0]
+    /// Returns a walker that visits the `len` bytes starting at `addr` in chunks, checking that
+    /// the permissions specified by `perm` are set for every byte (see [PageWalker]). The range is
+    /// walked for writing if `perm` contains [perm::WRITE], and for reading otherwise.
+    ///
+    /// Chunks of regular memory are returned as slices that refer directly to the data of the
+    /// page, with the bookkeeping for the access (e.g. copy-on-write and logging modified pages)
+    /// performed once per chunk. All other chunks (including chunks that would fail to be
+    /// accessed) must be accessed using the regular access functions, which handle I/O, hooks,
+    /// watchpoints and self-modifying code.
+    pub fn walk_range(&mut self, addr: u64, len: usize, perm: u8) -> PageWalker<'_> {
+        PageWalker::new(self, addr, len, perm)
     }
 
-    #[deprecated(
-        note = "The behavior of this function may change in the future. Use `map_memory_len"
-    )]
-    pub fn map_memory(&mut self, start: u64, end: u64, mapping: impl Into<MemoryMapping>) -> bool {
-        self.map_memory_len(start, end - start, mapping)
+    /// Returns the last address of the entry of the virtual mapping that contains `addr`.
+    pub(crate) fn mapping_end(&self, addr: u64) -> Option<u64> {
+        self.mapping.get_with_range(addr).map(|(_, end, _)| end)
     }
 
-    /// Attempts to maps a region of memory starting between `start` and `start + len` to `mapping`.
-    /// If `start + len` is greater than u64::MAX, memory will wrap around to zero.
+    /// Reads bytes from `addr` without any side effects, for use by debuggers.
     ///
-    /// Returns `true` if the memory was succesfully mapped.
-    pub fn map_memory_len(
-        &mut self,
-        start: u64,
-        len: u64,
-        mapping: impl Into<MemoryMapping>,
-    ) -> bool {
-        if len == 0 {
-            return false; // @todo: should mapping nothing count as being valid?
+    /// Permissions are not checked, and the state of memory (e.g., the allocation of pages and
+    /// the initialization state of bytes) is never modified. Memory hooks are not called, and
+    /// I/O regions are read using [IoMemory::peek].
+    pub fn peek_bytes(&mut self, addr: u64, buf: &mut [u8]) -> MemResult<()> {
+        if buf.is_empty() {
+            return Ok(());
         }
-        let Some(end) = start.checked_add(len - 1)
+        let Some(end) = addr.checked_add(buf.len() as u64 - 1)
         else {
-            return false;
+            // Split reads that wrap around the end of the address space at the boundary.
+            let (first, rest) = buf.split_at_mut(addr.wrapping_neg() as usize);
+            self.peek_bytes(addr, first)?;
+            return self.peek_bytes(0, rest);
         };
-        let mapping = mapping.into();
-        debug!("map_memory: start={:#0x}, end={:#0x}, mapping={:?}", start, end, mapping);
 
-        if let Err(e) = self.mapping.insert(start..=end, mapping) {
-            debug!("map_memory: failed: {:0x?}", e);
-            return false;
+        let mut regions: Vec<_> = self.mapping.overlapping_iter(addr..=end).collect();
+        regions.reverse();
+        for (start, len, entry) in regions {
+            let buf = &mut buf[(start - addr) as usize..][..len as usize];
+            match entry.ok_or(MemError::Unmapped)? {
+                MemoryMapping::Physical(entry) => {
+                    let offset = PageData::offset(start);
+                    let data = &self.physical.get(entry.index).data().data;
+                    buf.copy_from_slice(&data[offset..offset + buf.len()]);
+                }
+                MemoryMapping::Unallocated(entry) => buf.fill(entry.value),
+                MemoryMapping::Io(io) => get_io(&mut self.io, io.handler)?.peek(start, buf)?,
+                MemoryMapping::File(entry) => {
+                    let data = file_bytes(&self.files, entry, start, buf.len());
+                    buf[..data.len()].copy_from_slice(data);
+                    buf[data.len()..].fill(0);
+                }
+                MemoryMapping::Reserved(_) => return Err(MemError::Unmapped),
+            }
         }
-        self.mapping_changed = true;
-        self.tlb.remove_range(start, len);
-        self.last_io_handler = None;
-
-        true
+        Ok(())
     }
 
-    pub fn map_physical(&mut self, addr: u64, index: physical::Index) -> bool {
-        self.map_memory_len(
-            addr,
-            self.page_size(),
-            MemoryMapping::Physical(PhysicalMapping { index, addr }),
-        )
-    }
+    /// Compares the bytes at `addr` with `expected`, returning the address of the first byte that
+    /// differs, or `None` if the entire range matches.
+    ///
+    /// Every byte in the range must have the permissions in `perm`, otherwise the error contains
+    /// the address of the first byte that failed the check (see [Mmu::check_perm_range]). The data
+    /// of memory is compared in place: unallocated regions are compared against their fill value
+    /// without being allocated, and the state of memory (including the initialization state of
+    /// bytes, the modification log and the TLB) is never modified. Memory hooks are not called,
+    /// and I/O regions are not supported ([MemError::Unsupported] is returned).
+    pub fn compare_bytes(
+        &self,
+        addr: u64,
+        expected: &[u8],
+        perm: u8,
+    ) -> Result<Option<u64>, PermRangeError> {
+        let len = expected.len();
+        self.check_perm_range(addr, len as u64, perm)?;
+        if len == 0 {
+            return Ok(None);
+        }
 
-    /// Unmaps the region of memory between `start` and `start+len`
-    #[deprecated(
-        note = "The behavior of this function may change in the future. Use `unmap_memory_len`"
-    )]
-    pub fn unmap_memory(&mut self, start: u64, end: u64) -> bool {
-        self.unmap_memory_len(start, start - end)
+        for (start, run) in self.byte_runs(addr, addr + (len as u64 - 1))? {
+            let offset = (start - addr) as usize;
+            let expected = ByteRun::Bytes(&expected[offset..][..run.len()]);
+            if let Some(i) = run.mismatch(&expected) {
+                return Ok(Some(start + i as u64));
+            }
+        }
+        Ok(None)
     }
 
-    /// Unmaps the region of memory between `start` and `start+len`
-    pub fn unmap_memory_len(&mut self, start: u64, len: u64) -> bool {
+    /// Compares the `len` bytes at `a` with the `len` bytes at `b`, returning the offset of the
+    /// first byte that differs, or `None` if the ranges match.
+    ///
+    /// Both ranges are checked and compared in place in the same way as [Mmu::compare_bytes].
+    pub fn compare_ranges(
+        &self,
+        a: u64,
+        b: u64,
+        len: u64,
+        perm: u8,
+    ) -> Result<Option<u64>, PermRangeError> {
+        self.check_perm_range(a, len, perm)?;
+        self.check_perm_range(b, len, perm)?;
         if len == 0 {
-            return false; // @todo: should unmapping nothing count as being valid?
+            return Ok(None);
         }
-        let Some(end) = start.checked_add(len - 1)
-        else {
-            return false;
-        };
 
-        debug!("unmap_memory: start={:#0x}, end={:#0x}", start, end);
-        self.mapping_changed = true;
+        let a_runs = self.byte_runs(a, a + (len - 1))?;
+        let b_runs = self.byte_runs(b, b + (len - 1))?;
+        let (mut a_runs, mut b_runs) = (a_runs.iter().peekable(), b_runs.iter().peekable());
+        let (mut a_offset, mut b_offset) = (0, 0);
+        let mut offset = 0;
+        while let (Some((_, a_run)), Some((_, b_run))) = (a_runs.peek(), b_runs.peek()) {
+            // Compare the overlapping part of the current run of each range.
+            let chunk_len = (a_run.len() - a_offset).min(b_run.len() - b_offset);
+            let a_chunk = a_run.slice(a_offset, chunk_len);
+            let b_chunk = b_run.slice(b_offset, chunk_len);
+            if let Some(i) = a_chunk.mismatch(&b_chunk) {
+                return Ok(Some(offset + i as u64));
+            }
 
-        let physical = &mut self.physical;
-        let tlb = &mut self.tlb;
-        let mut partially_unmapped = false;
-        
-        let _ = self.mapping.overlapping_mut::<_, ()>(start..=end, |start, len, entry| {
-            tracing::trace!("unmap: ({:#0x}, {:#0x}): {:0x?}", start, len, entry);
-            match entry.take() {
-                Some(MemoryMapping::Physical(inner)) => {
-                    tlb.remove_range(start, len);
-                    if len == physical.page_size() {
-                        return Ok(());
+            offset += chunk_len as u64;
+            a_offset += chunk_len;
+            b_offset += chunk_len;
+            if a_offset == a_run.len() {
+                a_runs.next();
+                a_offset = 0;
+            }
+            if b_offset == b_run.len() {
+                b_runs.next();
+                b_offset = 0;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the data of the bytes between `start` and `end` (inclusive) as a list of
+    /// `(start, run)` ordered by address, without allocating or initializing memory.
+    fn byte_runs(&self, start: u64, end: u64) -> Result<Vec<(u64, ByteRun<'_>)>, PermRangeError> {
+        let mut runs = vec![];
+        for (start, len, entry) in self.mapping.overlapping_iter(start..=end) {
+            let len = len as usize;
+            let error = match entry {
+                Some(MemoryMapping::Physical(entry)) => {
+                    let offset = PageData::offset(start);
+                    let data = &self.physical.get(entry.index).data().data;
+                    runs.push((start, ByteRun::Bytes(&data[offset..][..len])));
+                    continue;
+                }
+                Some(MemoryMapping::Unallocated(entry)) => {
+                    runs.push((start, ByteRun::Fill(entry.value, len)));
+                    continue;
+                }
+                Some(MemoryMapping::File(entry)) => {
+                    // Bytes past the end of the file read as zero.
+                    let data = file_bytes(&self.files, entry, start, len);
+                    if data.len() < len {
+                        runs.push((start + data.len() as u64, ByteRun::Fill(0, len - data.len())));
                     }
+                    if !data.is_empty() {
+                        runs.push((start, ByteRun::Bytes(data)));
+                    }
+                    continue;
+                }
+                Some(MemoryMapping::Io(_)) => MemError::Unsupported,
+                Some(MemoryMapping::Reserved(_)) | None => MemError::Unmapped,
+            };
+            return Err(PermRangeError { addr: start, error });
+        }
+        // Note: the iterator returns regions in reverse order.
+        runs.reverse();
+        Ok(runs)
+    }
 
-                    // Clear permissions for the unmapped region.
-                    //
-                    // @fixme: this page could potentially be mapped in multiple locations,
-                    // resulting in mapping issues.
-                    let page = physical.get_mut(inner.index);
-                    assert!(!page.executed, "Unmapped cached code page. Currently unsupported");
+    /// Writes `buf` to `addr` for use by debuggers and other tooling, returning a [Poke] that can
+    /// be passed to [Mmu::undo_poke] to restore the previous contents of memory.
+    ///
+    /// Only the data of memory is modified: the permission bits of the written bytes are not
+    /// changed (so bytes are not marked as initialized), modified pages are not recorded in the
+    /// modification log, and memory hooks, watchpoints and the TLB counters are unaffected. Unless
+    /// `ignore_protection` is set (e.g. to patch read-only code) every byte must be writable. Any
+    /// cached code in the range is invalidated.
+    ///
+    /// Unallocated regions are allocated and shared pages are copied before they are modified.
+    /// I/O regions and ranges that wrap around the end of the address space are not supported
+    /// ([MemError::AddressOverflow] is returned for the latter). The range is checked before any
+    /// memory is modified, so if an error is returned (other than [MemError::OutOfMemory]) memory
+    /// is unchanged.
+    pub fn poke_bytes(
+        &mut self,
+        addr: u64,
+        buf: &[u8],
+        ignore_protection: bool,
+    ) -> MemResult<Poke> {
+        let mut old = vec![0; buf.len()];
+        if buf.is_empty() {
+            return Ok(Poke { addr, old });
+        }
+        let end = addr.checked_add(buf.len() as u64 - 1).ok_or(MemError::AddressOverflow)?;
 
+        for (start, len, entry) in self.mapping.overlapping_iter(addr..=end) {
+            let perm = match entry.ok_or(MemError::Unmapped)? {
+                MemoryMapping::Physical(entry) => {
                     let offset = PageData::offset(start);
-                    page.data_mut().perm[offset..offset + len as usize].fill(perm::NONE);
+                    let perm = &self.physical.get(entry.index).data().perm;
+                    perm[offset..offset + len as usize].iter().fold(perm::ALL, |acc, x| acc & x)
                 }
-                Some(_) => {}
+                MemoryMapping::Unallocated(entry) => entry.perm | perm::MAP,
+                MemoryMapping::File(entry) => entry.perm | perm::MAP,
+                MemoryMapping::Io(_) => return Err(MemError::Unsupported),
+                MemoryMapping::Reserved(_) => return Err(MemError::Unmapped),
+            };
+            if !ignore_protection {
+                perm::check(perm, perm::WRITE)?;
+            }
+        }
+        self.peek_bytes(addr, &mut old)?;
+
+        let mut invalidated = false;
+        let mut offset = 0;
+        while offset < buf.len() {
+            let start = addr + offset as u64;
+            let page_start = self.page_aligned(start);
+            let page_offset = PageData::offset(start);
+            let len = (self.page_size() as usize - page_offset).min(buf.len() - offset);
+
+            // Allocate the page unless every byte in the range is already backed by it.
+            let index = match self.physical_backing(start, start + (len as u64 - 1)) {
+                Some(index) => index,
+                None => self.init_physical(start, true).ok_or(MemError::OutOfMemory)?,
+            };
 
-                // Attempted to unmap region that wasn't mapped
-                None => partially_unmapped = true,
+            let page = self.physical.get(index);
+            let moves_data = page.is_shared();
+            let index = match page.copy_on_write {
+                true => self.copy_on_write(index, page_start)?,
+                false => index,
+            };
+            if moves_data {
+                self.tlb.remove_read(page_start);
             }
 
-            Ok(())
-        });
+            let page = self.physical.get_mut(index);
+            if page.executed {
+                invalidated |= page.clear_code_cache(page_offset, len);
+            }
+            page.data_mut().data[page_offset..page_offset + len]
+                .copy_from_slice(&buf[offset..offset + len]);
+            offset += len;
+        }
 
-        !partially_unmapped
+        if invalidated {
+            self.invalidate_code(addr, end);
+        }
+        Ok(Poke { addr, old })
     }
 
-    /// Allocates `count` physical pages, returning an error if we are out of memory.
-    pub fn alloc_physical(&mut self, count: usize) -> MemResult<Vec<physical::Index>> {
-        debug!("alloc_physical: count={count}");
-        (0..count).map(|_| self.physical.alloc().ok_or(MemError::OutOfMemory)).collect()
+    /// Restores the memory modified by a call to [Mmu::poke_bytes]. Pages that were allocated by
+    /// the write remain allocated.
+    pub fn undo_poke(&mut self, poke: &Poke) -> MemResult<()> {
+        self.poke_bytes(poke.addr, &poke.old, true).map(drop)
     }
 
-    /// Finds a free region of memory satisfying `layout` then map it to `mapping`
-    pub fn alloc_memory(
+    /// Copies the `len` bytes at `src_addr` in `src` to `dst_addr`, including whether each byte
+    /// is initialized (e.g. to transfer memory between the MMUs of separate cores).
+    ///
+    /// Every source byte must be readable and every destination byte must be writable, otherwise
+    /// the error of the first byte that failed the check is returned (see
+    /// [Mmu::check_perm_range]). Memory is copied directly between the pages of each MMU:
+    /// unallocated source regions are not allocated, and shared destination pages are copied
+    /// before they are modified. Write hooks in the destination are called in the same way as
+    /// other bulk writes, and any cached code in the destination is invalidated. I/O regions are
+    /// not supported ([MemError::Unsupported] is returned).
+    ///
+    /// Both ranges are checked before any memory is modified, so if an error is returned (other
+    /// than [MemError::OutOfMemory]) the destination is unchanged.
+    pub fn copy_from(
         &mut self,
-        layout: AllocLayout,
-        mapping: impl Into<MemoryMapping>,
-    ) -> MemResult<u64> {
-        let mapping = mapping.into();
-        debug!("alloc_memory: layout={layout:0x?}, mapping={mapping:?}");
-
-        let start = self.find_free_memory(layout)?;
-        self.map_memory_len(start, layout.size, mapping);
-        Ok(start)
-    }
-
-    /// Finds a free region of memory satisfying `layout`
-    pub fn find_free_memory(&self, layout: AllocLayout) -> MemResult<u64> {
-        // Compute the length that we will end up with if we add the padding necessary to meet
-        // alignment constraints
-        let align = layout.align.checked_next_power_of_two().unwrap();
-        let aligned_length = crate::align_up(layout.size, align);
+        dst_addr: u64,
+        src: &mut Mmu,
+        src_addr: u64,
+        len: u64,
+    ) -> MemResult<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let src_end = src_addr.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+        let dst_end = dst_addr.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+        debug!("copy_from: dst={dst_addr:#x}, src={src_addr:#x}, len={len:#x}");
+
+        src.check_perm_range(src_addr, len, perm::READ).map_err(|err| err.error)?;
+        src.byte_runs(src_addr, src_end).map_err(|err| err.error)?;
+        self.check_perm_range(dst_addr, len, perm::WRITE).map_err(|err| err.error)?;
+        if self
+            .mapping
+            .overlapping_iter(dst_addr..=dst_end)
+            .any(|(.., entry)| matches!(entry, Some(MemoryMapping::Io(_))))
+        {
+            return Err(MemError::Unsupported);
+        }
+        if self.self_modifying_code == SelfModifyingCode::Fault {
+            let in_code_cache =
+                self.perm_runs(dst_addr, dst_end).into_iter().any(|(_, _, run)| match run {
+                    PermRun::Uniform(perm) => perm & perm::IN_CODE_CACHE != 0,
+                    PermRun::Bytes(perms) => perms.iter().any(|p| p & perm::IN_CODE_CACHE != 0),
+                });
+            if in_code_cache {
+                tracing::error!("attempted to copy over code at {dst_addr:#x}..={dst_end:#x}");
+                return Err(MemError::SelfModifyingCode);
+            }
+        }
 
-        // Either use the preferred address specified in the layout or start at the lowest address
-        // available.
-        let mut start_addr = crate::align_up(layout.addr.unwrap_or(0), align);
+        let page_size = self.page_size().min(src.page_size()) as usize;
+        let mut data = vec![0; page_size];
+        let mut perms = vec![0; page_size];
+        let mut written = (self.memory_hooks && self.write_hooks.overlaps(dst_addr, len))
+            .then(|| Vec::with_capacity(len as usize));
+        let mut invalidated = false;
+        let mut offset = 0;
+        while offset < len {
+            // Copy the largest chunk that does not cross a page boundary in either range.
+            let (src_start, dst_start) = (src_addr + offset, dst_addr + offset);
+            let chunk_len = (page_size - (src_start as usize & (page_size - 1)))
+                .min(page_size - (dst_start as usize & (page_size - 1)))
+                .min((len - offset) as usize);
+            let (data, perms) = (&mut data[..chunk_len], &mut perms[..chunk_len]);
+            src.read_data_and_perms(src_start, data, perms)?;
+            invalidated |= self.write_data_and_init(dst_start, data, perms)?;
+            if let Some(written) = written.as_mut() {
+                written.extend_from_slice(data);
+            }
+            offset += chunk_len as u64;
+        }
 
-        while let Some((_, end)) = self.mapping.get_range(
-            start_addr..=start_addr.checked_add(aligned_length - 1).ok_or(MemError::OutOfMemory)?,
-        ) {
-            start_addr = crate::align_up(end + 1, align);
+        if invalidated {
+            self.invalidate_code(dst_addr, dst_end);
         }
+        if let Some(written) = written {
+            self.call_bulk_write_hooks(dst_addr, len, |offset, buf| {
+                buf.copy_from_slice(&written[offset as usize..][..buf.len()])
+            });
+        }
+        Ok(())
+    }
 
-        Ok(start_addr)
+    /// Reads the data and permissions of the bytes at `addr` into `data` and `perms`, without
+    /// allocating or initializing memory.
+    fn read_data_and_perms(&self, addr: u64, data: &mut [u8], perms: &mut [u8]) -> MemResult<()> {
+        let end = addr + (data.len() as u64 - 1);
+        for (start, run) in self.byte_runs(addr, end).map_err(|err| err.error)? {
+            let out = &mut data[(start - addr) as usize..][..run.len()];
+            match run {
+                ByteRun::Fill(value, _) => out.fill(value),
+                ByteRun::Bytes(bytes) => out.copy_from_slice(bytes),
+            }
+        }
+        for (start, end, run) in self.perm_runs(addr, end) {
+            let out = &mut perms[(start - addr) as usize..=(end - addr) as usize];
+            match run {
+                PermRun::Uniform(perm) => out.fill(perm),
+                PermRun::Bytes(bytes) => out.copy_from_slice(bytes),
+            }
+        }
+        Ok(())
     }
 
-    /// Updates the mapping value associated with a region of memory
-    pub fn update_perm(&mut self, addr: u64, count: u64, perm: u8) -> MemResult<()> {
-        let end = addr.checked_add(count - 1).ok_or(MemError::AddressOverflow)?;
-        let perm =
-            perm | perm::MAP | if self.track_uninitialized { perm::NONE } else { perm::INIT };
-        debug!("update_perm: addr={addr:#0x}, count={count:#0x}, perm={}", perm::display(perm));
+    /// Writes `data` to `addr` (which must not cross a page boundary), copying the [perm::INIT]
+    /// bit of each byte from `perms`. Returns whether any cached code was invalidated.
+    fn write_data_and_init(&mut self, addr: u64, data: &[u8], perms: &[u8]) -> MemResult<bool> {
+        let page_start = self.page_aligned(addr);
+        let offset = PageData::offset(addr);
+        let len = data.len();
 
-        self.mapping_changed = true;
+        // Allocate the page unless every byte in the range is already backed by it.
+        let index = match self.physical_backing(addr, addr + (len as u64 - 1)) {
+            Some(index) => index,
+            None => self.init_physical(addr, true).ok_or(MemError::OutOfMemory)?,
+        };
 
-        let physical = &mut self.physical;
-        let tlb = &mut self.tlb;
-        self.mapping.overlapping_mut(addr..=end, |start, len, entry| {
-            match entry.as_mut().ok_or(MemError::Unmapped)? {
-                MemoryMapping::Physical(entry) => 'physical: {
-                    tlb.remove_range(start, len);
+        let page = self.physical.get(index);
+        let moves_data = page.is_shared();
+        let index = match page.copy_on_write {
+            true => self.copy_on_write(index, page_start)?,
+            false => index,
+        };
+        if moves_data {
+            self.tlb.remove_read(page_start);
+        }
 
-                    let offset = PageData::offset(start);
-                    let len = len as usize;
+        let page = self.physical.get_mut(index);
+        let invalidated = page.executed && page.clear_code_cache(offset, len);
+        if !page.modified {
+            self.modified.insert(page_start);
+        }
+        page.modified = true;
 
-                    if offset == 0 && len == physical::PAGE_SIZE && entry.index.is_zero_page() {
-                        if let Some(zero_page) = physical.get_zero_page(perm) {
-                            debug!("updating zero page: {:?} -> {zero_page:?}", entry.index);
-                            entry.index = zero_page;
-                            break 'physical;
-                        }
-                    }
+        let page_data = page.data_mut();
+        page_data.data[offset..offset + len].copy_from_slice(data);
+        for (perm, src) in page_data.perm[offset..offset + len].iter_mut().zip(perms) {
+            *perm = (*perm & !perm::INIT) | (src & perm::INIT);
+        }
+        Ok(invalidated)
+    }
 
-                    let page = physical.get_mut(entry.index);
-                    if page.executed {
-                        tracing::error!("Changed perms of code page. JIT cache may now be invalid");
-                    }
-                    page.data_mut().perm[offset..offset + len].fill(perm);
-                }
-                MemoryMapping::Unallocated(entry) => entry.perm = perm,
-                MemoryMapping::Io(_) => {
-                    unimplemented!("attempted to update permission of I/O region")
-                }
-            }
+    /// Writes the virtual address space to `writer` as an ELF core file, with one `PT_LOAD`
+    /// segment for each range of adjacent regions with the same permissions.
+    ///
+    /// The data of each segment is streamed from the underlying memory. Zero-filled regions at
+    /// the end of a segment that have not been allocated are not stored in the file, and I/O
+    /// regions are not included. If any file-backed regions have not been loaded, their location
+    /// is described by an `NT_FILE` note.
+    pub fn write_core_dump(&self, writer: impl std::io::Write) -> std::io::Result<()> {
+        self.write_core_dump_with(writer, CoreDumpOptions::default())
+    }
 
-            Ok(())
-        })
+    /// Writes the virtual address space to `writer` as an ELF core file, using `options` for the
+    /// header of the file (see [Mmu::write_core_dump]).
+    pub fn write_core_dump_with(
+        &self,
+        writer: impl std::io::Write,
+        options: CoreDumpOptions,
+    ) -> std::io::Result<()> {
+        crate::core_dump::write(self, writer, options)
     }
 
-    /// Fill a region of memory with `value`
-    pub fn fill_mem(&mut self, addr: u64, count: u64, value: u8) -> MemResult<()> {
-        if count == 0 {
-            return Ok(());
+    /// Maps and initializes each of `segments`, returning a report of the regions that were
+    /// mapped.
+    ///
+    /// The bytes of each segment are mapped with the permissions of the segment, and the rest of
+    /// any page that contains a segment is mapped without any permissions (unless it is already
+    /// mapped). Zero-length segments are skipped. Segments are validated before anything is
+    /// mapped, so if they overlap with each other or with existing mappings nothing is modified.
+    pub fn load_image(&mut self, segments: &[Segment]) -> Result<LoadReport, LoadError> {
+        crate::image::load(self, segments)
+    }
+
+    /// Reads segments from a manifest (see [crate::image]) and loads them using
+    /// [Mmu::load_image].
+    pub fn load_image_from_reader(
+        &mut self,
+        reader: impl std::io::Read,
+    ) -> Result<LoadReport, LoadError> {
+        let segments = crate::image::read_manifest(reader)?;
+        self.load_image(&segments)
+    }
+
+    /// Write bytes bytes `addr` checking that the permission specified by `perm` are set and
+    /// marking the range written with the `INIT` permission bit.
+    ///
+    /// The write stops at the first byte that fails, but the bytes before it remain written (see
+    /// [Mmu::write_bytes_partial] and [Mmu::write_bytes_atomic]).
+    pub fn write_bytes(&mut self, mut addr: u64, buf: &[u8], perm: u8) -> MemResult<()> {
+        if buf.len() > 16 {
+            return self.write_bytes_large(addr, buf, perm);
         }
-        let end = addr.checked_add(count - 1).ok_or(MemError::AddressOverflow)?;
-        debug!("fill_mem: addr={:#0x}, count={:#0x}, value={:#0x}", addr, count, value);
 
-        let physical = &mut self.physical;
-        let tlb = &mut self.tlb;
-        self.mapping.overlapping_mut(addr..=end, |start, len, entry| {
-            match entry.as_mut().ok_or(MemError::Unmapped)? {
-                MemoryMapping::Physical(entry) => {
-                    tlb.remove_range(start, len);
-                    let page = physical.get_mut(entry.index);
-                    if page.executed && self.detect_self_modifying_code {
-                        check_self_modifying_memset(page.data(), start, len, value)?;
-                    }
+        for byte in buf {
+            self.write(addr, [*byte], perm)?;
+            addr = addr.wrapping_add(1);
+        }
+        Ok(())
+    }
 
-                    let offset = PageData::offset(start);
+    /// Write bytes bytes `addr` checking that the permission specified by `perm` are set and
+    /// marking the range written with the `INIT` permission bit.
+    ///
+    /// If any write hooks overlap with the range, each hook is called once for every page written
+    /// to, with the bytes written to the part of the page covered by the hook (see
+    /// [Mmu::call_bulk_write_hooks]).
+    #[cold]
+    pub fn write_bytes_large(&mut self, addr: u64, buf: &[u8], perm: u8) -> MemResult<()> {
+        self.write_bytes_large_partial(addr, buf, perm).map_err(|(_, e)| e)
+    }
 
-                    // Check whether we a simply overwritting a zero page with zeros.
-                    let write_zero_to_zero_page = value == 0
-                        && offset == 0
-                        && len as usize == physical::PAGE_SIZE
-                        && entry.index.is_zero_page();
+    /// Equivalent to [Mmu::write_bytes], except that on failure the error reports how many bytes
+    /// were written before the byte that failed.
+    pub fn write_bytes_partial(
+        &mut self,
+        mut addr: u64,
+        buf: &[u8],
+        perm: u8,
+    ) -> Result<(), PartialWriteError> {
+        if buf.len() > 16 {
+            return self
+                .write_bytes_large_partial(addr, buf, perm)
+                .map_err(|(written, error)| PartialWriteError { written, error });
+        }
 
-                    if !write_zero_to_zero_page {
-                        let page = page.data_mut();
-                        page.data[offset..offset + len as usize].fill(value);
-                        page.add_perm(offset, len as usize, perm::INIT);
-                    }
-                }
-                MemoryMapping::Unallocated(entry) => {
-                    entry.value = value;
-                    entry.perm |= perm::INIT;
-                }
-                MemoryMapping::Io(_) => {
-                    unimplemented!("attempted to memset an I/O region")
-                }
-            }
-            Ok(())
-        })
+        for (written, byte) in buf.iter().enumerate() {
+            self.write(addr, [*byte], perm)
+                .map_err(|error| PartialWriteError { written, error })?;
+            addr = addr.wrapping_add(1);
+        }
+        Ok(())
     }
 
-    #[deprecated(
-        note = "The behavior of this function may change in the future. Use `move_region_len`"
-    )]
-    pub fn move_region(&mut self, start: u64, end: u64, dst: u64) -> MemResult<()> {
-        self.move_region_len(start, end - start, dst)
+    /// Writes `buf` to `addr` only if the entire write succeeds, otherwise memory is left
+    /// unmodified.
+    ///
+    /// Permissions are checked for the entire range (see [Mmu::check_perm_range]) before anything
+    /// is written. If the write still fails part way through (e.g. because a page budget is
+    /// exhausted or a watchpoint is hit), the contents of the bytes that were written are restored
+    /// (see [Mmu::poke_bytes]), however the bytes remain marked as initialized and any write hooks
+    /// have already observed the write. Ranges that contain I/O regions are rejected with
+    /// [MemError::Unsupported], since writes to them can not be undone.
+    pub fn write_bytes_atomic(&mut self, addr: u64, buf: &[u8], perm: u8) -> MemResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let len = buf.len() as u64;
+        if self.translates_page_tables() {
+            return Err(MemError::Unsupported);
+        }
+        self.check_perm_range(addr, len, perm).map_err(|e| e.error)?;
+        if !self.is_regular_region(addr, len) {
+            return Err(MemError::Unsupported);
+        }
+
+        let mut old = vec![0; buf.len()];
+        self.peek_bytes(addr, &mut old)?;
+        if let Err(e) = self.write_bytes_partial(addr, buf, perm) {
+            self.poke_bytes(addr, &old[..e.written], true)?;
+            return Err(e.error);
+        }
+        Ok(())
     }
 
-    pub fn move_region_len(&mut self, start: u64, len: u64, dst: u64) -> MemResult<()> {
-        let offset = dst as i64 - start as i64;
-        let mut end = start.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+    /// Implementation of [Mmu::write_bytes_large]. On failure, returns the number of bytes that
+    /// were written before the error.
+    fn write_bytes_large_partial(
+        &mut self,
+        addr: u64,
+        buf: &[u8],
+        perm: u8,
+    ) -> Result<(), (usize, MemError)> {
+        if perm == perm::NONE
+            || !self.memory_hooks
+            || !self.write_hooks.overlaps(addr, buf.len() as u64)
+        {
+            return self.write_bytes_chunked(addr, buf, perm);
+        }
 
-        while start < end {
-            let (prev, (overlap_start, overlap_end)) =
-                self.mapping.remove_last(start..=end).ok_or(MemError::Unmapped)?;
+        self.memory_hooks = false;
+        let result = self.write_bytes_chunked(addr, buf, perm);
+        self.memory_hooks = true;
 
-            if overlap_end < end {
-                return Err(MemError::Unmapped);
-            }
+        let written = match result {
+            Ok(()) => buf.len(),
+            Err((written, _)) => written,
+        };
+        self.call_bulk_write_hooks(addr, written as u64, |offset, out| {
+            out.copy_from_slice(&buf[offset as usize..offset as usize + out.len()])
+        });
+        result
+    }
 
-            self.tlb.remove_range(overlap_start, (overlap_end - overlap_start) + 1);
-            self.last_io_handler = None;
+    /// Writes `buf` to `addr` using 16-byte writes where possible. On failure, returns the number
+    /// of bytes that were written before the error.
+    fn write_bytes_chunked(
+        &mut self,
+        mut addr: u64,
+        buf: &[u8],
+        perm: u8,
+    ) -> Result<(), (usize, MemError)> {
+        let mut written = 0;
+
+        // Write unaligned bytes at the start (see `read_bytes_large`).
+        let unaligned_len = (addr.wrapping_neg() & 15) as usize;
+        let (start, buf) = buf.split_at(unaligned_len.min(buf.len()));
+        for byte in start {
+            self.write(addr, [*byte], perm).map_err(|e| (written, e))?;
+            addr = addr.wrapping_add(1);
+            written += 1;
+        }
 
-            let shifted_start = (overlap_start as i64 + offset) as u64;
-            let shifted_end = (overlap_end as i64 + offset) as u64;
-            self.mapping.insert((shifted_start, shifted_end), prev).unwrap();
+        // Write aligned chunks
+        let mut chunks = buf.chunks_exact(16);
+        for chunk in &mut chunks {
+            self.write::<16>(addr, chunk.try_into().unwrap(), perm).map_err(|e| (written, e))?;
+            addr = addr.wrapping_add(16);
+            written += 16;
+        }
 
-            end = overlap_start
+        // Write unaligned bytes at the end
+        for byte in chunks.remainder() {
+            self.write(addr, [*byte], perm).map_err(|e| (written, e))?;
+            addr = addr.wrapping_add(1);
+            written += 1;
         }
+
         Ok(())
     }
 
-    /// Clear the translation lookahead buffer.
-    pub fn clear_tlb(&mut self) {
-        self.tlb.clear();
-        self.last_io_handler = None;
+    /// Calls the write hooks for a bulk write of `len` bytes at `addr`, where `data` is called to
+    /// get the bytes written at each offset from `addr`.
+    ///
+    /// Bulk writes call each hook once for every page written to (instead of once for every
+    /// access), with the bytes written to the part of the page covered by the hook. This ensures
+    /// that hooks observe every byte written by bulk operations, regardless of how the write is
+    /// split into accesses.
+    #[cold]
+    fn call_bulk_write_hooks(&mut self, addr: u64, len: u64, mut data: impl FnMut(u64, &mut [u8])) {
+        if len == 0 || !self.memory_hooks || self.write_hooks.hooks.is_empty() {
+            return;
+        }
+
+        let page_size = self.page_size();
+        let mut buf = vec![0; page_size as usize];
+        let mut hooks = std::mem::take(&mut self.write_hooks.hooks);
+        let mut offset = 0;
+        while offset < len {
+            let page_addr = addr.wrapping_add(offset);
+            let page_len = (page_size - (page_addr & (page_size - 1))).min(len - offset);
+            let page_end = page_addr + (page_len - 1);
+            let bytes = &mut buf[..page_len as usize];
+            data(offset, bytes);
+
+            for hook in &mut hooks {
+                let ranges = hook.ranges();
+                let Some(handler) = hook.handler.as_deref_mut()
+                else {
+                    continue;
+                };
+                for (start, end) in ranges.into_iter().flatten() {
+                    let (start, end) = (start.max(page_addr), end.min(page_end));
+                    if start <= end {
+                        let range = (start - page_addr) as usize..=(end - page_addr) as usize;
+                        handler.write(self, start, &bytes[range]);
+                    }
+                }
+            }
+            offset += page_len;
+        }
+        debug_assert!(self.write_hooks.hooks.is_empty());
+        self.write_hooks.hooks = hooks;
     }
 
-    /// Obtain a raw pointer to the translation lookahead buffer.
+    /// Calls `f` with a slice that refers directly to the `len` bytes at `addr`, checking that the
+    /// permissions specified by `perm` are set for every byte.
     ///
-    /// Safety: Avoid any operation except reading/writing to initialized memory locations while
-    /// this pointer is active.
-    pub fn tlb_ptr(&mut self) -> *const tlb::TranslationCache {
-        self.tlb.as_ref() as *const _
+    /// This avoids copying the bytes when the range is stored contiguously, i.e., it is contained
+    /// within a single page of regular memory. If the range crosses a page boundary, is mapped to
+    /// an I/O region, or accesses to the range need to be observed (by memory hooks, watchpoints,
+    /// or the access log), this fails with [MemError::NotContiguous] and the caller should fall
+    /// back to [Mmu::read_bytes].
+    pub fn with_slice<R>(
+        &mut self,
+        addr: u64,
+        len: usize,
+        perm: u8,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> MemResult<R> {
+        if len == 0 {
+            return Ok(f(&[]));
+        }
+        Ok(f(self.slice(addr & self.address_mask, len, perm)?))
     }
 
-    /// Invalidate an entry in the TLB.
-    pub fn invalidate_page(&mut self, addr: u64) {
-        self.tlb.remove(addr);
+    /// Returns the slice used by [Mmu::with_slice] for a non-empty range.
+    pub(crate) fn slice(&mut self, addr: u64, len: usize, perm: u8) -> MemResult<&[u8]> {
+        let index = self.contiguous_page(addr, len, perm, false)?;
+
+        let data = self.physical.get(index).data();
+        let offset = PageData::offset(addr);
+        // Safety: `contiguous_page` ensures that `offset..offset + len` is within the page.
+        perm::check(unsafe { data.get_perm_unchecked(offset, len) }, perm | perm::MAP)?;
+        Ok(&data.data[offset..offset + len])
     }
 
-    /// Create a full snapshot of memory that can later be restored
-    pub fn snapshot(&mut self) -> Snapshot {
-        // TLB is invalidated whenever we clone the physical memory state.
-        self.tlb.clear();
+    /// Calls `f` with a mutable slice that refers directly to the `len` bytes at `addr`, checking
+    /// that the permissions specified by `perm` are set for every byte and marking the range with
+    /// the `INIT` permission bit.
+    ///
+    /// The range is treated as if it was written to by [Mmu::write_bytes] (whether or not `f`
+    /// modifies it). Fails with [MemError::NotContiguous] in the same cases as [Mmu::with_slice],
+    /// and additionally if the range contains code that has been executed, in which case the
+    /// caller should fall back to [Mmu::write_bytes].
+    pub fn with_slice_mut<R>(
+        &mut self,
+        addr: u64,
+        len: usize,
+        perm: u8,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> MemResult<R> {
+        if len == 0 {
+            return Ok(f(&mut []));
+        }
+        Ok(f(self.slice_mut(addr & self.address_mask, len, perm)?))
+    }
 
-        let snapshot = SnapshotData {
-            mapping: self.mapping.clone(),
-            physical: self.physical.snapshot(),
-            parent: Some(self.parent_state.clone()),
-            io: self.io.iter_mut().map(|x| x.snapshot()).collect(),
-        };
+    /// Returns the slice used by [Mmu::with_slice_mut] for a non-empty range, after performing the
+    /// bookkeeping for a write to the range.
+    pub(crate) fn slice_mut(&mut self, addr: u64, len: usize, perm: u8) -> MemResult<&mut [u8]> {
+        let mut index = self.contiguous_page(addr, len, perm, true)?;
 
-        // Reconfigure the current modification state to be tracked based on the new snapshot
-        self.parent_state = std::sync::Arc::new(snapshot);
-        self.parent_state.clone()
-    }
+        let page_start = self.page_aligned(addr);
+        let page_size = self.page_size();
+        let offset = PageData::offset(addr);
 
-    /// Restore the full memory state from `snapshot`
-    pub fn restore(&mut self, snapshot: Snapshot) {
-        self.tlb.clear();
-        self.last_io_handler = None;
+        let mut page = self.physical.get_mut(index);
+        // Safety: `contiguous_page` ensures that `offset..offset + len` is within the page.
+        perm::check(unsafe { page.data().get_perm_unchecked(offset, len) }, perm | perm::MAP)?;
+
+        // Writes to cached code need to be checked for self-modifying code, which requires the old
+        // value of the range, so they are left to the copying path.
+        let in_code_cache = page.executed
+            && self.self_modifying_code != SelfModifyingCode::Ignore
+            && page.data().perm[offset..offset + len].iter().any(|p| p & perm::IN_CODE_CACHE != 0);
+        if in_code_cache {
+            return Err(MemError::NotContiguous);
+        }
 
-        self.modified.clear();
-        self.mapping_changed = true;
+        // Copy the page (or its data) if it is shared, as in `write_physical`.
+        let moves_data = page.is_shared();
+        if page.copy_on_write {
+            index = self.copy_on_write(index, page_start)?;
+            page = self.physical.get_mut(index);
+        }
+        if moves_data {
+            self.tlb.remove_read(page_start);
+        }
 
-        self.physical.restore(&snapshot.physical);
-        self.io.iter_mut().zip(&snapshot.io).for_each(|(io, snapshot)| io.restore(snapshot));
+        if !page.modified {
+            self.modified.insert(page_start);
+        }
+        page.modified = true;
 
-        // Configure our state to match the snapshot
-        self.mapping.clone_from(&snapshot.mapping);
-        self.parent_state = snapshot;
+        page.data_mut().add_perm(offset, len, perm::INIT);
+
+        let uncachable = self.write_hooks.contains_address(addr, page_size)
+            || self.watchpoints.overlaps_page(addr, page_size)
+            || (page.has_cached_code() && self.self_modifying_code != SelfModifyingCode::Ignore)
+            || page.aliased
+            || page.is_shared_memory();
+        if !uncachable {
+            // Safety: `page.data_mut()` ensures the page is a unique copy of the underlying data.
+            self.tlb.insert_write(page_start, unsafe { page.write_ptr() });
+        }
+
+        Ok(&mut self.physical.get_mut(index).data_mut().data[offset..offset + len])
     }
 
-    /// Create a snapshot of just the virtual address space
-    pub fn snapshot_virtual_mapping(&mut self) -> VirtualMemoryMap {
-        // Clear the TLB to ensure that no writes will be missed.
-        self.tlb.clear();
-        self.last_io_handler = None;
+    /// Resolves the physical page that stores all `len` bytes at `addr` (see [Mmu::with_slice]),
+    /// allocating the page if required.
+    fn contiguous_page(
+        &mut self,
+        addr: u64,
+        len: usize,
+        perm: u8,
+        is_write: bool,
+    ) -> MemResult<physical::Index> {
+        let last = addr.checked_add(len as u64 - 1).ok_or(MemError::AddressOverflow)?;
+        if self.page_aligned(addr) != self.page_aligned(last)
+            || self.translates_page_tables()
+            || self.record_accesses
+        {
+            return Err(MemError::NotContiguous);
+        }
 
-        // Mark all physical pages in the mapping as copy-on-write.
-        for (_, _, entry) in self.mapping.iter() {
-            if let MemoryMapping::Physical(mapping) = entry {
-                self.physical.get_mut(mapping.index).copy_on_write = true;
+        if perm != perm::NONE {
+            let kind = if is_write { AccessKind::Write } else { read_kind(perm) };
+            let hooked = self.memory_hooks
+                && match is_write {
+                    true => self.write_hooks.overlaps(addr, len as u64),
+                    false => {
+                        self.read_hooks.overlaps(addr, len as u64)
+                            || self.read_after_hooks.overlaps(addr, len as u64)
+                    }
+                };
+            if hooked || self.watchpoints.should_check(addr, len, kind) {
+                return Err(MemError::NotContiguous);
             }
         }
 
-        self.mapping.clone()
+        match self.mapping.get_with_range(addr).ok_or(MemError::Unmapped)? {
+            (_, end, _) if end < last => Err(MemError::NotContiguous),
+            (_, _, MemoryMapping::Physical(entry)) => Ok(entry.index),
+            (_, _, &MemoryMapping::Unallocated(UnallocatedMemory { perm: mapping_perm, .. }))
+            | (_, _, &MemoryMapping::File(FileMapping { perm: mapping_perm, .. })) => {
+                perm::check(mapping_perm | perm::MAP, perm)?;
+                self.init_physical(addr, is_write).ok_or(MemError::OutOfMemory)
+            }
+            (_, _, MemoryMapping::Io(_)) => Err(MemError::NotContiguous),
+            (_, _, MemoryMapping::Reserved(_)) => Err(MemError::Reserved),
+        }
     }
 
-    /// Take the underlying virtual address space.
-    pub fn take_virtual_mapping(&mut self) -> VirtualMemoryMap {
-        self.tlb.clear();
-        self.last_io_handler = None;
-        self.mapping_changed = true;
-        std::mem::take(&mut self.mapping)
+    /// Registers the data of a file that can be lazily mapped to memory locations (see
+    /// [FileMapping]). Typically `data` is a memory mapped file, allowing large files to be mapped
+    /// without reading them into memory.
+    pub fn register_file(&mut self, data: FileData) -> FileHandle {
+        let id = self.files.len();
+        self.files.push(data);
+        FileHandle(id)
     }
 
-    /// Restore just the virtual address space
-    pub fn restore_virtual_mapping(&mut self, mapping: VirtualMemoryMap) {
-        self.mapping = mapping;
-        self.tlb.clear();
-        self.last_io_handler = None;
+    /// Register a handler function that can be mapped to memory locations
+    pub fn register_io_handler(&mut self, handler: impl IoMemory + 'static) -> IoHandler {
+        let id = self.io.len();
+        self.io.push(Some(Box::new(handler)));
+        IoHandler(id)
+    }
 
-        self.modified.clear();
-        self.mapping_changed = true;
+    /// Registers a handler in the same way as [Mmu::register_io_handler], using `policy` to
+    /// control whether the state of the handler is captured by snapshots.
+    ///
+    /// Snapshots record the policy of every handler, and handlers keep their position in the
+    /// snapshot regardless of their policy, so handlers registered later are never affected.
+    pub fn register_io_handler_with_policy(
+        &mut self,
+        handler: impl IoMemory + 'static,
+        policy: SnapshotPolicy,
+    ) -> IoHandler {
+        let handler = self.register_io_handler(handler);
+        self.io_snapshot_policies.resize(handler.0 + 1, SnapshotPolicy::Include);
+        self.io_snapshot_policies[handler.0] = policy;
+        handler
     }
 
-    /// Reset the the virtual address space
-    pub fn reset_virtual(&mut self) {
-        self.mapping.clear();
-        self.tlb.clear();
-        self.last_io_handler = None;
+    /// Returns the snapshot policy of the handler registered at `id`.
+    fn io_snapshot_policy(&self, id: usize) -> SnapshotPolicy {
+        self.io_snapshot_policies.get(id).copied().unwrap_or_default()
+    }
 
-        self.modified.clear();
-        self.mapping_changed = true;
+    /// Registers a handler in the same way as [Mmu::register_io_handler], associating it with
+    /// `name`. Snapshots record the name, and only restore the state of the handler to a handler
+    /// with the same name (see [Mmu::restore]).
+    pub fn register_named_io_handler(
+        &mut self,
+        name: impl Into<String>,
+        handler: impl IoMemory + 'static,
+    ) -> IoHandler {
+        let handler = self.register_io_handler(handler);
+        self.io_names.resize(handler.0 + 1, None);
+        self.io_names[handler.0] = Some(name.into());
+        handler
     }
 
-    /// Clear the page modification log
-    pub fn clear_page_modification_log(&mut self) {
-        self.tlb.clear_write();
-        self.last_io_handler = None;
-        self.modified.clear();
+    /// Returns the identity of the handler registered at `id`, or `None` if it was unregistered.
+    fn io_identity(&self, id: usize) -> Option<IoHandlerIdentity> {
+        let handler = self.io.get(id)?.as_deref()?;
+        Some(IoHandlerIdentity {
+            type_id: handler.as_any().type_id(),
+            type_name: handler.type_name(),
+            name: self.io_names.get(id).cloned().flatten(),
+        })
     }
 
-    /// Get the permission bits associated with the byte at `addr`
-    pub fn get_perm(&self, addr: u64) -> u8 {
-        let entry = match self.mapping.get(addr) {
-            Some(entry) => entry,
-            None => return perm::NONE,
-        };
-        match entry {
-            MemoryMapping::Physical(entry) => {
-                let page = self.physical.get(entry.index).data();
-                let (offset, _) = PageData::offset_and_len(addr, addr + 1);
-                page.perm[offset]
-            }
-            MemoryMapping::Unallocated(metadata) => metadata.perm,
-            MemoryMapping::Io(_) => {
-                // @fixme?
-                perm::NONE
-            }
+    /// Unregisters an I/O handler, returning it if it was registered.
+    ///
+    /// This fails (returning `None`) if the handler is still mapped in the current address space.
+    /// Accesses to the handler from mappings that are restored afterwards (e.g. from a snapshot
+    /// taken before the handler was unregistered) fail with [MemError::Unmapped].
+    ///
+    /// Note: the identifier of an unregistered handler is never reused.
+    pub fn unregister_io_handler(&mut self, handler: IoHandler) -> Option<Box<dyn IoMemoryAny>> {
+        let is_mapped = self
+            .mapping
+            .iter()
+            .any(|(_, _, entry)| matches!(entry, MemoryMapping::Io(io) if io.handler == handler.0));
+        if is_mapped {
+            debug!("unregister_io_handler: {handler:?} is still mapped");
+            return None;
         }
+        if self.last_io_handler.is_some_and(|(_, _, last)| last.handler == handler.0) {
+            self.last_io_handler = None;
+        }
+        self.io.get_mut(handler.0)?.take()
     }
 
-    /// Check that the region of memory between addr..addr+len is initialized and executable, and
-    /// ensure that if it is ever written to in the future it will be detected.
-    pub fn ensure_executable(&mut self, start: u64, len: u64) -> bool {
-        let Some(end) = start.checked_add(len - 1)
-        else {
-            return false;
-        };
-
-        let tlb = &mut self.tlb;
-        let physical = &mut self.physical;
-        self.mapping
-            .overlapping_mut::<_, MemError>(start..=end, |start, len, entry| match entry {
-                Some(MemoryMapping::Physical(mapping)) => {
-                    let page = physical.get_mut(mapping.index);
-
-                    // Check whether the code is actually executable.
-                    let offset = PageData::offset(start);
-                    let len = len as usize;
-                    let perm =
-                        unsafe { page.write_ptr().ptr.as_mut().get_perm_unchecked(offset, len) };
-                    perm::check(perm, perm::INIT | perm::EXEC)?;
-
-                    // Mark the page as executed
-                    page.executed = true;
+    /// Replaces the I/O handler associated with `handler`, returning the previous handler (or
+    /// `None` if the handler had been unregistered).
+    ///
+    /// All existing mappings of `handler` will use the new handler. Snapshots taken before the
+    /// handler was replaced will restore the new handler with the state captured from the previous
+    /// handler (see [IoMemory::restore]).
+    pub fn replace_io_handler(
+        &mut self,
+        handler: IoHandler,
+        new: impl IoMemory + 'static,
+    ) -> Option<Box<dyn IoMemoryAny>> {
+        self.io[handler.0].replace(Box::new(new))
+    }
 
-                    // Prevent writes to the region we are executing (we don't currently support
-                    // self modifying code).
-                    if self.detect_self_modifying_code {
-                        unsafe {
-                            page.write_ptr().ptr.as_mut().add_perm_unchecked(
-                                offset,
-                                len,
-                                perm::IN_CODE_CACHE,
-                            );
-                        };
-                    }
+    /// Enables or disables tracing of the accesses to `handler` (see [Mmu::drain_io_trace]).
+    ///
+    /// Accesses performed by debuggers (see [Mmu::peek_bytes]) are never traced.
+    pub fn set_io_trace(&mut self, handler: IoHandler, enabled: bool) {
+        self.io_trace.set_enabled(handler.0, enabled);
+    }
 
-                    tlb.remove_write(mapping.addr);
-                    Ok(())
-                }
-                _ => Err(MemError::ExecViolation),
-            })
-            .is_ok()
+    /// Sets the maximum number of events kept in the I/O trace, once the trace is full the oldest
+    /// events are discarded.
+    pub fn set_io_trace_capacity(&mut self, capacity: usize) {
+        self.io_trace.set_capacity(capacity);
     }
 
-    /// Clears the executable bit from uninitialized memory.
+    /// Removes all events from the I/O trace, returning them in the order they were recorded.
     ///
-    /// @fixme: this was used a workaround for `track_uninitialized` returning to many false
-    /// positives in some cases.
-    pub fn clear_uninitialized_exec_bytes(&mut self) {
-        let physical = &mut self.physical;
-        for (start, end, entry) in self.mapping.iter_mut() {
-            match entry {
-                MemoryMapping::Physical(entry) => {
-                    let (offset, len) = PageData::offset_and_len(start, end + 1);
-                    let page = physical.get_mut(entry.index);
-                    page.data_mut().perm[offset..offset + len].iter_mut().for_each(|p| {
-                        if *p & perm::INIT == 0 {
-                            *p &= !perm::EXEC;
-                        }
-                    });
-                }
-                MemoryMapping::Unallocated(x) => x.perm &= !perm::EXEC,
-                MemoryMapping::Io(_) => {}
-            }
-        }
+    /// Note: the trace is not captured by snapshots.
+    pub fn drain_io_trace(&mut self) -> impl Iterator<Item = IoTraceEvent> + '_ {
+        self.io_trace.drain()
     }
 
-    /// Initialize a new physical page and map it such that it contains `addr`.
+    /// Starts recording every read serviced by an I/O handler to `writer` (see
+    /// [IoRecord](crate::IoRecord)), so that the reads can later be replayed using
+    /// [Mmu::start_io_replay]. Any active recording or replay is stopped.
     ///
-    /// Returns the index of the new page in physical memory (or `None` if we are out of memory)
-    fn init_physical(&mut self, addr: u64, is_write: bool) -> Option<physical::Index> {
-        let page_start = self.page_aligned(addr);
-        let page_size = self.page_size();
-        let page_end = page_start + (page_size - 1);
+    /// Only reads that succeed are recorded, and accesses performed by debuggers (see
+    /// [Mmu::peek_bytes]) are never recorded. Snapshots capture the position in the stream (see
+    /// [Mmu::io_stream_position]): restoring a snapshot moves the writer back to the record after
+    /// the last read before the snapshot was taken, so that the stream always describes a single
+    /// execution.
+    pub fn start_io_recording(
+        &mut self,
+        writer: impl std::io::Write + std::io::Seek + 'static,
+    ) -> std::io::Result<()> {
+        self.io_replay.start_recording(writer)
+    }
 
-        let range = page_start..=page_end;
-        // If we are only reading from this page and the entire region is entirely zero, then map it
-        // to a zero page.
-        if ENABLE_ZERO_PAGE_OPTIMIZATION && !is_write {
-            if let Some(zero_page) = self.get_zero_page(page_start, page_size) {
-                tracing::trace!("init_physical: addr={page_start:#0x}, index={zero_page:?}");
+    /// Stops recording I/O reads, returning the offset of the end of the last record in the
+    /// stream, or the first error that occurred while writing to the stream.
+    ///
+    /// Note: if a snapshot was restored during the recording, records of the reads that were
+    /// rolled back may remain after the returned offset, so the stream should be truncated to it.
+    pub fn stop_io_recording(&mut self) -> std::io::Result<u64> {
+        self.io_replay.stop_recording()
+    }
 
-                let _ = self.mapping.overlapping_mut::<_, ()>(range, |_, _, entry| {
-                    *entry = Some(MemoryMapping::Physical(PhysicalMapping {
-                        index: zero_page,
-                        addr: page_start,
-                    }));
-                    Ok(())
-                });
-                return Some(zero_page);
-            }
-        }
+    /// Starts replaying the reads recorded by [Mmu::start_io_recording] from `reader`. Any active
+    /// recording or replay is stopped.
+    ///
+    /// While replaying, every read from an I/O handler returns the value of the next read in the
+    /// stream instead of accessing the handler. The handler, address and size of each read must
+    /// match the recorded read, otherwise the read fails with [MemError::ReplayDiverged] (as does
+    /// every subsequent read, until the replay is stopped or a snapshot is restored) and the
+    /// reason is available from [Mmu::take_io_replay_error]. Writes are handled according to
+    /// [Mmu::replay_write_policy].
+    pub fn start_io_replay(
+        &mut self,
+        reader: impl std::io::Read + std::io::Seek + 'static,
+    ) -> std::io::Result<()> {
+        self.io_replay.start_replay(reader)
+    }
 
-        let index = self.physical.alloc()?;
-        self.tlb.remove(page_start);
+    /// Stops replaying I/O reads, subsequent reads are serviced by the handlers.
+    pub fn stop_io_replay(&mut self) {
+        self.io_replay.stop_replay();
+    }
 
-        tracing::trace!("init_physical: addr={:#0x}, index={:?}", page_start, index);
-        let new_mapping = PhysicalMapping { index, addr: page_start };
+    /// Returns (and clears) the reason that the active replay diverged from the recorded reads.
+    pub fn take_io_replay_error(&mut self) -> Option<IoReplayError> {
+        self.io_replay.take_replay_error()
+    }
 
-        let init_perm = if self.track_uninitialized { perm::NONE } else { perm::INIT };
+    /// Returns the number of reads that have been recorded or replayed since the recording or
+    /// replay started, i.e. the sequence number of the next read in the stream.
+    pub fn io_stream_position(&self) -> u64 {
+        self.io_replay.pos()
+    }
 
-        let physical = &mut self.physical;
-        let _ = self.mapping.overlapping_mut::<_, ()>(range, |start, len, entry| {
-            let len = len as usize;
+    /// Returns whether writes to I/O handlers are currently discarded (see
+    /// [Mmu::replay_write_policy]).
+    fn discards_io_writes(&self) -> bool {
+        self.replay_write_policy == ReplayWritePolicy::Discard && self.io_replay.is_replaying()
+    }
 
-            // Determine how this region of the page should be initalized.
-            let (value, perm) = match entry {
-                Some(MemoryMapping::Unallocated(x)) => {
-                    tracing::trace!("Replacing unallocated region (start={start:#x}, len={len:#x}) with physical mapping.");
-                    let init = (x.value, x.perm | perm::MAP | init_perm);
-                    *entry = Some(MemoryMapping::Physical(new_mapping));
-                    init
-                }
-                Some(MemoryMapping::Physical(existing)) => {
-                    // Rare case where there was an existing page map at this location. This should
-                    // only occur when a page is partially mapped. Copy any memory that could be
-                    // lost when we replace this mapping.
-                    //
-                    // @fixme: handle this better.
+    /// Enables recording the most recent `capacity` accesses performed using [Mmu::read] and
+    /// [Mmu::write] (including accesses that fail), discarding any existing records. Once the log
+    /// is full, the oldest records are discarded.
+    ///
+    /// Accesses that ignore permissions (i.e. with [perm::NONE]) are not recorded. Since every
+    /// access checks whether the log is enabled, the log is intended for triaging crashes rather
+    /// than for use while fuzzing.
+    pub fn enable_access_log(&mut self, capacity: usize) {
+        self.access_log.enable(capacity);
+        self.update_record_accesses();
+    }
 
-                    tracing::trace!(
-                        "copy {len:#0x} bytes at {start:#0x} from: {:?}",
-                        existing.index
-                    );
+    /// Disables the access log, freeing the buffer used for the records.
+    pub fn disable_access_log(&mut self) {
+        self.access_log.disable();
+        self.update_record_accesses();
+    }
 
-                    let offset = (start - page_start) as usize;
+    /// Configures the access log to only record one in every `interval` successful accesses to
+    /// reduce the overhead of logging. Failed accesses are always recorded.
+    pub fn set_access_log_sampling(&mut self, interval: u32) {
+        self.access_log.set_sampling(interval);
+    }
 
-                    let (old_page, new_page) = physical.get_pair_mut(existing.index, index);
-                    let (old, new) = (old_page.data(), new_page.data_mut());
-                    new.data[offset..offset + len].copy_from_slice(&old.data[offset..offset + len]);
-                    new.perm[offset..offset + len].copy_from_slice(&old.perm[offset..offset + len]);
+    /// Sets the context tag attached to accesses recorded in the access log (e.g. the address of
+    /// the instruction being executed).
+    pub fn set_access_context(&mut self, context: u64) {
+        self.access_log.context = context;
+    }
 
-                    *entry = Some(MemoryMapping::Physical(new_mapping));
-                    return Ok(());
-                }
-                Some(MemoryMapping::Io(_)) => (crate::UNINIT_VALUE, perm::NONE),
-                None => (crate::UNINIT_VALUE, perm::NONE),
-            };
+    /// Sets the context passed to I/O handlers as part of every access (see [IoAccess::context]).
+    pub fn set_io_context(&mut self, context: u64) {
+        self.io_context = context;
+    }
 
-            let page = physical.get_mut(index).data_mut();
-            let offset = PageData::offset(start);
-            page.data[offset..offset + len].fill(value);
-            page.perm[offset..offset + len].fill(perm);
+    /// Returns the accesses recorded in the access log, from oldest to newest.
+    ///
+    /// Note: the log is not captured by snapshots, and is cleared when a snapshot is restored.
+    pub fn access_log(&self) -> AccessHistory {
+        self.access_log.history()
+    }
 
-            Ok(())
-        });
+    /// Enables counting the number of accesses to each page performed using [Mmu::read] and
+    /// [Mmu::write] (see [Mmu::heatmap_top]). Accesses that span multiple pages are counted for
+    /// the page that contains the first byte of the access, and accesses that ignore permissions
+    /// (i.e. with [perm::NONE]) are not counted.
+    ///
+    /// Note: the counts are not captured by snapshots, so restoring a snapshot does not reset them.
+    pub fn enable_heatmap(&mut self) {
+        self.heatmap.get_or_insert_with(HashMap::new);
+        self.update_record_accesses();
+    }
 
-        Some(index)
+    /// Disables the heatmap, discarding all counts.
+    pub fn disable_heatmap(&mut self) {
+        self.heatmap = None;
+        self.update_record_accesses();
     }
 
-    /// Checks whether the memory is zero page compatible, returning the index of the zero page.
-    fn get_zero_page(&self, start: u64, len: u64) -> Option<physical::Index> {
-        let end = start.checked_add(len - 1)?;
-        let mut perm = None;
-        for (_, _, entry) in self.mapping.overlapping_iter(start..=end) {
-            match entry {
-                Some(MemoryMapping::Unallocated(x)) if x.is_zero() => {
-                    if perm.map_or(false, |perm| x.perm != perm) {
-                        return None;
-                    }
-                    perm = Some(x.perm);
-                }
-                _ => return None,
-            }
+    /// Resets the access counts of the heatmap (see [Mmu::enable_heatmap]).
+    pub fn reset_heatmap(&mut self) {
+        if let Some(heatmap) = self.heatmap.as_mut() {
+            heatmap.clear();
         }
-        perm.and_then(|perm| self.physical.get_zero_page(perm))
     }
 
-    /// Checks whether the memory range entirely consists of mapped regular memory.
-    pub fn is_regular_region(&self, start: u64, len: u64) -> bool {
-        let Some(end) = start.checked_add(len - 1)
+    /// Returns up to `n` pages with the most accesses, ordered by the total number of accesses
+    /// (highest first). Instruction fetches are counted as reads.
+    pub fn heatmap_top(&self, n: usize) -> Vec<PageHeat> {
+        let Some(heatmap) = self.heatmap.as_ref()
         else {
-            return false;
+            return vec![];
         };
-        for (_, _, entry) in self.mapping.overlapping_iter((start, end)) {
-            match entry {
-                Some(MemoryMapping::Physical(_) | MemoryMapping::Unallocated(_)) => {}
-                _ => return false,
-            }
-        }
-        true
+        let mut pages: Vec<_> = heatmap.values().copied().collect();
+        pages.sort_unstable_by(|a, b| b.total().cmp(&a.total()).then(a.page.cmp(&b.page)));
+        pages.truncate(n);
+        pages
     }
 
-    /// Gets the physical address assocated with a virtual address, returning `None` if `addr` is
-    /// unmapped or unallocated
-    pub fn get_physical_addr(&self, addr: u64) -> Option<PhysicalAddr> {
-        self.resolve_vaddr(addr).map(|entry| entry.phys)
+    fn update_record_accesses(&mut self) {
+        self.record_accesses = self.access_log.enabled || self.heatmap.is_some();
     }
 
-    pub fn resolve_vaddr(&self, vaddr: u64) -> Option<Addr> {
-        match self.mapping.get(vaddr)? {
-            MemoryMapping::Physical(entry) => {
-                Some(Addr { virt: vaddr, phys: self.physical.address_of(vaddr, entry.index) })
+    /// Records an access in the access log and the heatmap (if they are enabled).
+    #[cold]
+    #[inline(never)]
+    fn record_access(
+        &mut self,
+        kind: AccessKind,
+        addr: u64,
+        value: &[u8],
+        error: Option<MemError>,
+    ) {
+        if self.access_log.enabled {
+            self.access_log.record(kind, addr, value, error);
+        }
+        let page = self.page_aligned(addr);
+        if let Some(heatmap) = self.heatmap.as_mut() {
+            let heat = heatmap.entry(page).or_insert(PageHeat { page, reads: 0, writes: 0 });
+            match kind {
+                AccessKind::Write => heat.writes += 1,
+                _ => heat.reads += 1,
             }
-            _ => None,
         }
     }
 
-    /// Get the index of physical page mapped at `addr`.
-    pub fn get_physical_index(&self, addr: u64) -> Option<physical::Index> {
-        match self.mapping.get(addr)? {
-            MemoryMapping::Physical(entry) => Some(entry.index),
-            _ => None,
-        }
+    /// Get the memory associated with an I/O handle
+    pub fn get_io_memory_mut(&mut self, handler: IoHandler) -> &mut dyn IoMemoryAny {
+        self.io[handler.0].as_deref_mut().expect("I/O handler was unregistered")
     }
 
-    pub fn get_physical(&self, index: physical::Index) -> &physical::Page {
-        self.physical.get(index)
+    /// Registers `callback` to be called whenever the virtual address space is changed, e.g. when
+    /// memory is mapped or unmapped, permissions are changed, or a snapshot is restored (see
+    /// [MappingChangeCallback]).
+    pub fn on_mapping_change(&mut self, callback: MappingChangeCallback) -> SubscriptionId {
+        let id = match self.mapping_listeners.iter().position(|x| x.is_none()) {
+            Some(id) => {
+                self.mapping_listeners[id] = Some(callback);
+                id
+            }
+            None => {
+                self.mapping_listeners.push(Some(callback));
+                self.mapping_listeners.len() - 1
+            }
+        };
+        SubscriptionId(id.try_into().expect("too many mapping change callbacks"))
     }
 
-    pub fn get_physical_mut(&mut self, index: physical::Index) -> &mut physical::Page {
-        // @fixme: this may invalidate the TLB
-        self.physical.get_mut(index)
+    /// Removes a callback registered using [Mmu::on_mapping_change], returning whether the
+    /// callback was found.
+    pub fn remove_mapping_change_callback(&mut self, id: SubscriptionId) -> bool {
+        self.mapping_listeners.get_mut(id.0 as usize).and_then(|x| x.take()).is_some()
     }
 
-    fn read_physical<const N: usize>(
-        &mut self,
-        index: physical::Index,
-        addr: u64,
-        perm: u8,
-    ) -> MemResult<[u8; N]> {
-        let page_size = self.page_size();
-        let page = self.physical.get_mut(index);
-        let result = page.data().read(addr, perm)?;
+    /// Returns a value that is incremented whenever the virtual address space is changed, allowing
+    /// each consumer to detect changes by comparing against the last value it observed.
+    pub fn mapping_generation(&self) -> u64 {
+        self.mapping_generation
+    }
 
-        // If there is no memory hook set on the current page, cache the translated address in the
-        // TLB.
-        let uncachable = self.read_hooks.contains_address(addr, page_size)
-            || self.read_after_hooks.contains_address(addr, page_size);
-        if !uncachable {
-            self.tlb.insert_read(addr, unsafe { page.read_ptr() });
+    /// Records a change to the virtual address space and notifies any registered callbacks.
+    #[allow(deprecated)]
+    fn notify_mapping_change(&mut self, kind: MappingChangeKind, start: u64, end: u64) {
+        self.mapping_changed = true;
+        self.mapping_generation += 1;
+        if !self.mapping_listeners.is_empty() {
+            let change = MappingChange { kind, start, end };
+            self.mapping_listeners.iter_mut().flatten().for_each(|callback| callback(&change));
         }
-        Ok(result)
     }
 
-    fn write_physical<const N: usize>(
-        &mut self,
-        index: physical::Index,
-        addr: u64,
-        value: [u8; N],
-        perm: u8,
-    ) -> MemResult<()> {
-        let page_start = self.page_aligned(addr);
-        let page_size = self.page_size();
+    #[deprecated(
+        note = "The behavior of this function may change in the future. Use `map_memory_len"
+    )]
+    pub fn map_memory(&mut self, start: u64, end: u64, mapping: impl Into<MemoryMapping>) -> bool {
+        let Some(len) = end.checked_sub(start)
+        else {
+            return false;
+        };
+        self.map_memory_len(start, len, mapping)
+    }
 
-        let mut page = self.physical.get_mut(index);
-        if page.executed && self.detect_self_modifying_code {
-            check_self_modifying_write(page.data(), addr, &value)?;
-        }
+    /// Maps `len` bytes starting at `start` as initialized memory where every byte is `value`, with
+    /// the permissions in `perm` (e.g. for flash memory that is erased to `0xff`).
+    ///
+    /// Pages in the region are not allocated until they are first accessed. If
+    /// [Mmu::zero_page_optimization] is enabled, reads from pages that are entirely filled with
+    /// `value` are backed by a page that is shared with every other region with the same value and
+    /// permissions, and pages are only copied once they are written to.
+    ///
+    /// Returns `true` if the memory was succesfully mapped.
+    pub fn map_fill_region(&mut self, start: u64, len: u64, value: u8, perm: u8) -> bool {
+        let mapping = UnallocatedMemory { perm: perm | perm::MAP | perm::INIT, value };
+        self.map_memory_len(start, len, mapping)
+    }
 
+    /// Attempts to maps a region of memory starting between `start` and `start + len` to `mapping`.
+    /// If `start + len` is greater than u64::MAX, memory will wrap around to zero (the region is
+    /// mapped as two separate regions, and either both or neither are mapped).
+    ///
+    /// Returns `true` if the memory was succesfully mapped. Mapping zero bytes always succeeds
+    /// without modifying the memory map.
+    pub fn map_memory_len(
+        &mut self,
+        start: u64,
+        len: u64,
+        mapping: impl Into<MemoryMapping>,
+    ) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let mapping = mapping.into();
+        let Some(end) = start.checked_add(len - 1)
+        else {
+            return self.map_wrapping(start, len, mapping);
+        };
+        debug!("map_memory: start={:#0x}, end={:#0x}, mapping={:?}", start, end, mapping);
+
+        let Ok(mapping) = self.prepare_mapping(start, end, mapping)
+        else {
+            return false;
+        };
+        if let Err(e) = self.mapping.insert(start..=end, mapping) {
+            debug!("map_memory: failed: {:0x?}", e);
+            return false;
+        }
+        self.notify_mapping_change(MappingChangeKind::Mapped, start, end);
+        self.tlb.remove_range(start, len);
+        self.last_io_handler = None;
+
+        true
+    }
+
+    /// Maps a region that wraps around the end of the address space, checking that the part of the
+    /// region starting at zero can be mapped before mapping either part.
+    fn map_wrapping(&mut self, start: u64, len: u64, mapping: MemoryMapping) -> bool {
+        let first_len = start.wrapping_neg();
+        let rest_end = len - first_len - 1;
+        let rest_mapped = self.mapping.overlapping_iter(0..=rest_end).any(|(_, _, x)| x.is_some());
+        if rest_mapped || self.prepare_mapping(0, rest_end, mapping.clone()).is_err() {
+            return false;
+        }
+        self.map_memory_len(start, first_len, mapping.clone())
+            && self.map_memory_len(0, rest_end + 1, mapping)
+    }
+
+    /// Applies [Mmu::wx_policy] and [Mmu::address_space_policy] to a mapping that is about to be
+    /// inserted at `start..=end`, converting it to the form stored in the memory map.
+    fn prepare_mapping(
+        &self,
+        start: u64,
+        end: u64,
+        mut mapping: MemoryMapping,
+    ) -> MemResult<MemoryMapping> {
+        self.check_address_space(start, end)?;
+
+        match &mut mapping {
+            MemoryMapping::Unallocated(entry) => {
+                entry.perm = self.check_wx(start, end, entry.perm)?
+            }
+            MemoryMapping::File(entry) => {
+                entry.perm = self.check_wx(start, end, entry.perm)? | perm::MAP | perm::INIT;
+                entry.offset = entry.offset.wrapping_sub(start);
+            }
+            MemoryMapping::Physical(_) | MemoryMapping::Io(_) | MemoryMapping::Reserved(_) => {}
+        }
+        Ok(mapping)
+    }
+
+    /// Checks whether `start..=end` can be mapped according to [Mmu::address_space_policy].
+    fn check_address_space(&self, start: u64, end: u64) -> MemResult<()> {
+        if end > self.address_space_end {
+            match self.address_space_policy {
+                AddressSpacePolicy::Allow => {}
+                AddressSpacePolicy::Warn => tracing::warn!(
+                    "{start:#x}..={end:#x} is mapped past the end of the address space ({:#x})",
+                    self.address_space_end
+                ),
+                AddressSpacePolicy::Deny => return Err(MemError::AddressOverflow),
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps each `(start, len, mapping)` in `regions`, either mapping all of the regions or
+    /// (if any region is invalid) none of them.
+    ///
+    /// Regions must not overlap with each other. If `replace` is set, then any existing mappings
+    /// that overlap with the regions are unmapped first (see [Mmu::unmap_memory_len]), otherwise
+    /// overlapping with an existing mapping is an error.
+    pub fn map_regions(
+        &mut self,
+        regions: &[(u64, u64, MemoryMapping)],
+        replace: bool,
+    ) -> Result<(), MapError> {
+        debug!("map_regions: count={}, replace={replace}", regions.len());
+
+        let mut prepared = Vec::with_capacity(regions.len());
+        for (index, (start, len, mapping)) in regions.iter().enumerate() {
+            let error = |kind| MapError { index, kind };
+            let end = len
+                .checked_sub(1)
+                .and_then(|last| start.checked_add(last))
+                .ok_or(error(MapErrorKind::InvalidRange))?;
+            let mapping =
+                self.prepare_mapping(*start, end, mapping.clone()).map_err(|err| match err {
+                    MemError::AddressOverflow => error(MapErrorKind::OutOfAddressSpace),
+                    _ => error(MapErrorKind::WriteExecViolation),
+                })?;
+            if !replace && self.mapping.overlapping_iter(*start..=end).any(|(.., x)| x.is_some()) {
+                return Err(error(MapErrorKind::OverlapsMapping));
+            }
+            prepared.push((*start, end, mapping));
+        }
+
+        let mut order: Vec<_> = (0..prepared.len()).collect();
+        order.sort_unstable_by_key(|i| prepared[*i].0);
+        for pair in order.windows(2) {
+            let (a, b) = (pair[0].min(pair[1]), pair[0].max(pair[1]));
+            if prepared[pair[1]].0 <= prepared[pair[0]].1 {
+                return Err(MapError { index: b, kind: MapErrorKind::OverlapsRegion(a) });
+            }
+        }
+
+        for (start, end, mapping) in prepared {
+            if replace {
+                self.unmap_memory_len(start, end - start + 1);
+            }
+            self.mapping.insert(start..=end, mapping).unwrap();
+            self.tlb.remove_range(start, end - start + 1);
+            self.notify_mapping_change(MappingChangeKind::Mapped, start, end);
+        }
+        self.last_io_handler = None;
+
+        Ok(())
+    }
+
+    /// Maps the physical page at `index` to the page starting at `addr`.
+    ///
+    /// The same page may be mapped at multiple addresses (including in other address spaces, see
+    /// [Mmu::create_address_space]), in which case all aliases of the page remain coherent: writes
+    /// (and permission changes) through one alias are visible through every other alias, including
+    /// when the page is copied as part of copy-on-write. The permissions of bytes in an aliased
+    /// page are only cleared by [Mmu::unmap_memory_len] once the bytes are unmapped from every
+    /// alias. Aliased pages are never cached in the TLB.
+    pub fn map_physical(&mut self, addr: u64, index: physical::Index) -> bool {
+        let aliased = !self.physical.is_fill_page(index) && self.is_physical_mapped(index);
+        let mapping = MemoryMapping::Physical(PhysicalMapping { index, addr });
+        if !self.map_memory_len(addr, self.page_size(), mapping) {
+            return false;
+        }
+        if aliased {
+            debug!("map_physical: {index:?} is aliased at {addr:#x}");
+            self.physical.get_mut(index).aliased = true;
+            // Remove any translations cached before the page was aliased.
+            self.remap_aliases(index, index);
+        }
+        true
+    }
+
+    /// Returns whether the physical page at `index` is mapped anywhere in any address space.
+    fn is_physical_mapped(&self, index: physical::Index) -> bool {
+        self.all_address_spaces().any(|mapping| {
+            mapping.iter().any(
+                |(_, _, entry)| matches!(entry, MemoryMapping::Physical(x) if x.index == index),
+            )
+        })
+    }
+
+    /// Replaces every mapping of the aliased physical page at `old` with `new`, removing any cached
+    /// translations for the mappings.
+    fn remap_aliases(&mut self, old: physical::Index, new: physical::Index) {
+        let tlb = &mut self.tlb;
+        for (start, end, entry) in self.mapping.iter_mut() {
+            if let MemoryMapping::Physical(mapping) = entry {
+                if mapping.index == old {
+                    mapping.index = new;
+                    tlb.remove_range(start, end - start + 1);
+                }
+            }
+        }
+
+        for (asid, mapping) in self.address_spaces.iter_mut().enumerate() {
+            let mut remapped = false;
+            for (_, _, entry) in mapping.iter_mut() {
+                if let MemoryMapping::Physical(mapping) = entry {
+                    if mapping.index == old {
+                        mapping.index = new;
+                        remapped = true;
+                    }
+                }
+            }
+            if remapped {
+                tlb.invalidate_asid(asid as u64);
+            }
+        }
+    }
+
+    /// Maps the `len` bytes at `src_addr` in `src` to `addr`, sharing the underlying memory between
+    /// both MMUs (e.g. to model memory that is shared between cores). Both addresses and `len`
+    /// must be page aligned ([MemError::Unaligned] is returned otherwise), and the destination
+    /// must not already be mapped ([MemError::AlreadyMapped]).
+    ///
+    /// Every page of the source must be backed by regular memory ([MemError::Unmapped] is returned
+    /// for unmapped and reserved pages, and [MemError::Unsupported] for I/O regions), with a
+    /// single mapping covering the page ([MemError::NotContiguous] is returned otherwise).
+    /// Unallocated source pages are allocated, and source pages that are shared copy-on-write are
+    /// copied first.
+    ///
+    /// The data of shared pages is always modified in-place, so writes through either MMU are
+    /// immediately visible through the other, and the pages are never copied-on-write. Each MMU has
+    /// its own permissions for the region (starting with a copy of the permissions of the source),
+    /// so permission changes only apply to the MMU they were made in. Accesses to shared pages are
+    /// never cached in the TLB. Writes from the other MMU are not detected as self-modifying code.
+    /// Unmapping the region in one MMU only removes its reference to the memory, without modifying
+    /// the contents seen by the other MMU.
+    ///
+    /// A snapshot of either MMU captures the contents of the shared pages, but restoring the
+    /// snapshot only restores the permissions of the shared pages, since the data is also visible
+    /// to the other MMU. The data is written back by [Mmu::restore_shared_memory] (see also
+    /// [crate::BusRouter::restore]). Restoring a snapshot captured before the region was shared
+    /// ends the sharing for that MMU.
+    pub fn share_region(
+        &mut self,
+        addr: u64,
+        len: u64,
+        src: &mut Mmu,
+        src_addr: u64,
+    ) -> MemResult<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let page_size = self.page_size();
+        if src.page_size() != page_size {
+            return Err(MemError::Unsupported);
+        }
+        if (addr | src_addr | len) & (page_size - 1) != 0 {
+            return Err(MemError::Unaligned);
+        }
+        let end = addr.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+        let src_end = src_addr.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+        debug!("share_region: addr={addr:#x}, len={len:#x}, src_addr={src_addr:#x}");
+
+        if self.mapping.overlapping_iter(addr..=end).any(|(.., entry)| entry.is_some()) {
+            return Err(MemError::AlreadyMapped);
+        }
+        self.check_address_space(addr, end)?;
+        for (.., entry) in src.mapping.overlapping_iter(src_addr..=src_end) {
+            match entry {
+                Some(MemoryMapping::Io(_)) => return Err(MemError::Unsupported),
+                Some(MemoryMapping::Reserved(_)) | None => return Err(MemError::Unmapped),
+                _ => {}
+            }
+        }
+
+        // Find (or allocate) the source pages and allocate the destination pages before converting
+        // any source page to shared memory, so that the source is unchanged if an error occurs.
+        let mut src_pages = Vec::with_capacity((len / page_size) as usize);
+        for page_addr in (src_addr..=src_end).step_by(page_size as usize) {
+            let index = src.get_unique_physical(page_addr)?;
+            if src.physical_backing(page_addr, page_addr + (page_size - 1)) != Some(index) {
+                return Err(MemError::NotContiguous);
+            }
+            src_pages.push(index);
+        }
+        let mut dst_pages = Vec::with_capacity(src_pages.len());
+        for _ in &src_pages {
+            match self.physical.alloc() {
+                Some(index) => dst_pages.push(index),
+                None => {
+                    dst_pages.into_iter().for_each(|index| self.physical.free(index));
+                    return Err(MemError::OutOfMemory);
+                }
+            }
+        }
+
+        for ((page_addr, src_index), dst_index) in
+            (addr..=end).step_by(page_size as usize).zip(src_pages).zip(dst_pages)
+        {
+            if !src.physical.get(src_index).is_shared_memory() {
+                src.physical.get_mut(src_index).make_shared_memory();
+                // The data of the page has moved, so remove any pointers to the old data.
+                src.invalidate_tlb_for_physical(src_index);
+            }
+            self.physical.share_data(dst_index, src.physical.get(src_index));
+            let mapping =
+                MemoryMapping::Physical(PhysicalMapping { index: dst_index, addr: page_addr });
+            self.mapping.insert((page_addr, page_addr + (page_size - 1)), mapping).unwrap();
+        }
+        self.tlb.remove_range(addr, len);
+        self.last_io_handler = None;
+        self.notify_mapping_change(MappingChangeKind::Mapped, addr, end);
+        Ok(())
+    }
+
+    /// Unmaps the region of memory between `start` and `start+len`
+    #[deprecated(
+        note = "The behavior of this function may change in the future. Use `unmap_memory_len`"
+    )]
+    pub fn unmap_memory(&mut self, start: u64, end: u64) -> bool {
+        let Some(len) = end.checked_sub(start)
+        else {
+            return false;
+        };
+        self.unmap_memory_len(start, len)
+    }
+
+    /// Unmaps the region of memory between `start` and `start+len`, wrapping around to zero if
+    /// `start + len` is greater than u64::MAX. Unmapping zero bytes always succeeds.
+    ///
+    /// Physical pages that are no longer reachable from any mapping are returned to the physical
+    /// allocator, unless they are shared (copy-on-write), contain translated code, were allocated
+    /// by [Mmu::alloc_physical] or are referenced by a mapping that has been detached from the MMU
+    /// (see [Mmu::collect_garbage]).
+    pub fn unmap_memory_len(&mut self, start: u64, len: u64) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let Some(end) = start.checked_add(len - 1)
+        else {
+            let first_len = start.wrapping_neg();
+            let first = self.unmap_memory_len(start, first_len);
+            return self.unmap_memory_len(0, len - first_len) && first;
+        };
+
+        debug!("unmap_memory: start={:#0x}, end={:#0x}", start, end);
+        self.notify_mapping_change(MappingChangeKind::Unmapped, start, end);
+
+        let poison = self.poison_on_unmap.then_some(self.uninit_value);
+        let physical = &mut self.physical;
+        let mut tlb_removal = tlb::PendingRemoval::default();
+        let mut partially_unmapped = false;
+        let mut invalidated_code = vec![];
+        let mut unmapped_aliases = vec![];
+        let mut unused_pages = vec![];
+
+        let _ = self.mapping.overlapping_mut::<_, ()>(start..=end, |start, len, entry| {
+            tracing::trace!("unmap: ({:#0x}, {:#0x}): {:0x?}", start, len, entry);
+            match entry.take() {
+                Some(MemoryMapping::Physical(inner)) => {
+                    tlb_removal.add(start, len);
+
+                    let page = physical.get_mut(inner.index);
+                    if page.executed {
+                        invalidated_code.push((start, start + (len - 1)));
+                    }
+
+                    // The page may still be reachable from another alias, which is checked once
+                    // the entire range has been unmapped.
+                    if page.aliased {
+                        unmapped_aliases.push((inner.index, start, len));
+                        return Ok(());
+                    }
+                    if len == physical::PAGE_SIZE as u64 && is_unshared_page(inner.index, page) {
+                        unused_pages.push(inner.index);
+                    }
+                    release_physical_range(inner.index, page, start, len, poison);
+                }
+                Some(_) => {}
+
+                // Attempted to unmap region that wasn't mapped
+                None => partially_unmapped = true,
+            }
+
+            Ok(())
+        });
+        tlb_removal.apply(&mut self.tlb);
+
+        // Refund the pages charged to budgets that are no longer mapped.
+        let page_size = self.page_size();
+        for page in self.budgets.charged_pages(start, end, page_size) {
+            let page_end = page + (page_size - 1);
+            let mapped = self
+                .mapping
+                .overlapping_iter(page..=page_end)
+                .any(|(_, _, entry)| matches!(entry, Some(MemoryMapping::Physical(_))));
+            if !mapped {
+                self.budgets.refund(page);
+            }
+        }
+        self.budgets.unassign(start, end);
+
+        for (index, start, len) in unmapped_aliases {
+            // Check whether any of the unmapped bytes are still reachable from another alias.
+            let (first, last) = (PageData::offset(start), PageData::offset(start + (len - 1)));
+            let mut mapped = false;
+            let mut reachable = false;
+            for (start, end, entry) in self.all_address_spaces().flat_map(|x| x.iter()) {
+                if matches!(entry, MemoryMapping::Physical(x) if x.index == index) {
+                    mapped = true;
+                    reachable |= PageData::offset(start) <= last && first <= PageData::offset(end);
+                }
+            }
+            let page = self.physical.get_mut(index);
+            page.aliased &= mapped;
+            if !mapped && is_unshared_page(index, page) {
+                unused_pages.push(index);
+            }
+            if !reachable {
+                release_physical_range(index, page, start, len, poison);
+            }
+        }
+
+        // Note: pages referenced by a snapshot can be safely reused since the data of the page is
+        // copied before it is modified, however a detached mapping may still refer to the page.
+        unused_pages.sort_unstable_by_key(|index| index.slot());
+        unused_pages.dedup();
+        for index in unused_pages {
+            if !self.is_detached(index) {
+                tracing::trace!("unmap: freeing {index:?}");
+                self.physical.free(index);
+            }
+        }
+
+        // Merge adjacent ranges so that the handler is notified once for each unmapped region.
+        invalidated_code.sort_unstable();
+        invalidated_code.dedup_by(|next, prev| {
+            let adjacent = prev.1.checked_add(1) == Some(next.0);
+            if adjacent {
+                prev.1 = next.1;
+            }
+            adjacent
+        });
+        for (start, end) in invalidated_code {
+            self.invalidate_code(start, end);
+        }
+        // Translated pages outside of the regions reported above.
+        self.invalidate_code_pages(start, end);
+
+        !partially_unmapped
+    }
+
+    /// Allocates `count` physical pages, returning an error if we are out of memory.
+    ///
+    /// The pages are owned by the caller, so they are never freed by the MMU (e.g. when they are
+    /// unmapped or by [Mmu::collect_garbage]).
+    pub fn alloc_physical(&mut self, count: usize) -> MemResult<Vec<physical::Index>> {
+        debug!("alloc_physical: count={count}");
+        let prev_allocated = self.physical.allocated_pages();
+        let mut pages = Vec::with_capacity(count);
+        for _ in 0..count {
+            match self.physical.alloc() {
+                Some(index) => pages.push(index),
+                None => {
+                    pages.into_iter().for_each(|index| self.physical.free(index));
+                    return Err(MemError::OutOfMemory);
+                }
+            }
+        }
+        for index in &pages {
+            self.physical.get_mut(*index).owned = true;
+        }
+        self.check_soft_capacity(prev_allocated, AllocKind::Explicit, None);
+        Ok(pages)
+    }
+
+    /// Finds a free region of memory satisfying `layout` then map it to `mapping`
+    ///
+    /// Returns [MemError::InvalidRange] if `layout.size` is zero, and an error if the region could
+    /// not be mapped (e.g. because of [Mmu::wx_policy]).
+    pub fn alloc_memory(
+        &mut self,
+        layout: AllocLayout,
+        mapping: impl Into<MemoryMapping>,
+    ) -> MemResult<u64> {
+        let mapping = mapping.into();
+        debug!("alloc_memory: layout={layout:0x?}, mapping={mapping:?}");
+
+        let start = self.find_free_memory(layout)?;
+        self.map_regions(&[(start, layout.size, mapping)], false).map_err(|err| {
+            match err.kind {
+                MapErrorKind::WriteExecViolation => MemError::WriteExecViolation,
+                _ => MemError::OutOfMemory,
+            }
+        })?;
+        self.advance_alloc_rng();
+        Ok(start)
+    }
+
+    /// Finds a free region of memory satisfying `layout` then map it to `mapping`, surrounded by
+    /// `guard_pages_before` pages of reserved memory before the allocation and
+    /// `guard_pages_after` pages after it (see [MemoryMapping::Reserved]).
+    ///
+    /// The guard after the allocation starts at the first byte past the end of the allocation (so
+    /// an overflow of a single byte faults even if the size of the allocation is not a multiple of
+    /// the page size). The guard before the allocation is extended if necessary to keep the
+    /// allocation aligned.
+    ///
+    /// The allocation (and its guards) can be released with [Mmu::free_memory_with_guards].
+    /// Returns [MemError::InvalidRange] if `layout.size` is zero.
+    pub fn alloc_memory_with_guards(
+        &mut self,
+        layout: AllocLayout,
+        mapping: impl Into<MemoryMapping>,
+        guard_pages_before: u64,
+        guard_pages_after: u64,
+    ) -> MemResult<u64> {
+        let mapping = mapping.into();
+        debug!(
+            "alloc_memory_with_guards: layout={layout:0x?}, mapping={mapping:?}, \
+            guards=({guard_pages_before}, {guard_pages_after})"
+        );
+
+        if layout.size == 0 {
+            return Err(MemError::InvalidRange);
+        }
+        let page_size = self.page_size();
+        let align =
+            layout.align.checked_next_power_of_two().ok_or(MemError::OutOfMemory)?.max(page_size);
+        let guard_len = |pages: u64, align: u64| {
+            pages.checked_mul(page_size).and_then(|len| checked_align_up(len, align))
+        };
+        let before_len = guard_len(guard_pages_before, align).ok_or(MemError::OutOfMemory)?;
+        let after_len = guard_len(guard_pages_after, 1).ok_or(MemError::OutOfMemory)?;
+        let span_len = checked_align_up(layout.size, page_size)
+            .and_then(|len| len.checked_add(before_len)?.checked_add(after_len))
+            .ok_or(MemError::OutOfMemory)?;
+
+        let span_start = self.find_free_memory(AllocLayout {
+            addr: layout.addr.map(|addr| addr.saturating_sub(before_len)),
+            size: span_len,
+            align,
+        })?;
+        let start = span_start + before_len;
+        let span_end = span_start + (span_len - 1);
+
+        let mut regions = vec![(start, layout.size, mapping)];
+        if before_len != 0 {
+            regions.push((span_start, before_len, MemoryMapping::Reserved(start)));
+        }
+        if guard_pages_after != 0 {
+            let after_start = start + layout.size;
+            let len = span_end - after_start + 1;
+            regions.push((after_start, len, MemoryMapping::Reserved(start)));
+        }
+        self.map_regions(&regions, false).map_err(|err| match err.kind {
+            MapErrorKind::WriteExecViolation => MemError::WriteExecViolation,
+            _ => MemError::OutOfMemory,
+        })?;
+        self.advance_alloc_rng();
+
+        Ok(start)
+    }
+
+    /// Unmaps the `len` bytes starting at `addr` that were allocated by
+    /// [Mmu::alloc_memory_with_guards] along with the guard regions surrounding the allocation.
+    ///
+    /// Returns the (inclusive) range of addresses that were released, or [MemError::Unmapped] if
+    /// the allocation is not mapped. Since allocations are never empty, a `len` of zero is
+    /// rejected with [MemError::InvalidRange].
+    pub fn free_memory_with_guards(&mut self, addr: u64, len: u64) -> MemResult<(u64, u64)> {
+        if len == 0 {
+            return Err(MemError::InvalidRange);
+        }
+        let end = addr.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+        let is_allocated = self
+            .mapping
+            .overlapping_iter(addr..=end)
+            .all(|(.., entry)| entry.is_some_and(|x| !matches!(x, MemoryMapping::Reserved(_))));
+        if !is_allocated {
+            return Err(MemError::Unmapped);
+        }
+
+        let guard = |entry: Option<(u64, u64, &MemoryMapping)>| match entry {
+            Some((start, end, MemoryMapping::Reserved(owner))) if *owner == addr => {
+                Some((start, end))
+            }
+            _ => None,
+        };
+        let span_start = addr
+            .checked_sub(1)
+            .and_then(|prev| guard(self.mapping.get_with_range(prev)))
+            .map_or(addr, |(start, _)| start);
+        let span_end = end
+            .checked_add(1)
+            .and_then(|next| guard(self.mapping.get_with_range(next)))
+            .map_or(end, |(_, end)| end);
+
+        debug!("free_memory_with_guards: {span_start:#x}..={span_end:#x}");
+        self.unmap_memory_len(span_start, (span_end - span_start) + 1);
+        Ok((span_start, span_end))
+    }
+
+    /// Reserves the `len` bytes starting at `addr` without making them accessible (e.g. for
+    /// `mmap(PROT_NONE)` or `MEM_RESERVE`), returning `true` if the range was reserved.
+    ///
+    /// Like other mappings, the range can not be returned by [Mmu::find_free_memory] or mapped
+    /// again until it is unmapped, but accessing it fails with [MemError::Reserved] and physical
+    /// pages are never allocated for it. Parts of the reservation can be made accessible using
+    /// [Mmu::commit].
+    pub fn reserve(&mut self, addr: u64, len: u64) -> bool {
+        self.map_memory_len(addr, len, MemoryMapping::Reserved(addr))
+    }
+
+    /// Maps the `len` bytes starting at `addr`, which must be part of one or more reservations
+    /// (see [Mmu::reserve]), to zeroed memory with `perm` (as if mapped with [crate::Mapping]). The
+    /// rest of the reservations are not affected.
+    ///
+    /// Fails with [MemError::Unmapped] if any part of the range is unmapped, and with
+    /// [MemError::AlreadyMapped] if any part of the range is mapped but not reserved.
+    pub fn commit(&mut self, addr: u64, len: u64, perm: u8) -> MemResult<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = addr.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+        for (.., entry) in self.mapping.overlapping_iter(addr..=end) {
+            match entry {
+                Some(MemoryMapping::Reserved(_)) => {}
+                Some(_) => return Err(MemError::AlreadyMapped),
+                None => return Err(MemError::Unmapped),
+            }
+        }
+        debug!("commit: {addr:#x}..={end:#x}, perm={}", perm::display(perm));
+
+        let mapping = UnallocatedMemory { perm, value: 0 }.into();
+        let mapping = self.prepare_mapping(addr, end, mapping)?;
+        self.mapping.remove_all(addr..=end);
+        // Note: the range was fully reserved, so it is empty after removing the reservations.
+        let _ = self.mapping.insert(addr..=end, mapping);
+        self.notify_mapping_change(MappingChangeKind::Mapped, addr, end);
+        self.tlb.remove_range(addr, len);
+        Ok(())
+    }
+
+    /// Sets the size of the address space to `bits` bits. Memory allocated by
+    /// [Mmu::find_free_memory] is always placed below `1 << bits`, and mappings past the end of
+    /// the address space are handled according to [Mmu::address_space_policy].
+    ///
+    /// The size of the address space is saved and restored as part of snapshots.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bits` is not between 1 and 64.
+    pub fn set_address_space_bits(&mut self, bits: u32) {
+        assert!((1..=64).contains(&bits), "invalid address space size: {bits} bits");
+        self.address_space_end = u64::MAX >> (64 - bits);
+    }
+
+    /// Returns the last address of the address space (see [Mmu::set_address_space_bits]).
+    pub fn address_space_end(&self) -> u64 {
+        self.address_space_end
+    }
+
+    /// Sets the mask applied to the address of every access, allowing address arithmetic to wrap
+    /// at the word size of the guest (e.g. `0xffff_ffff` for 32-bit guests). Accesses that cross
+    /// the end of the masked address space continue at address zero.
+    ///
+    /// The mask is applied by [Mmu::read], [Mmu::write], [Mmu::read_allow_uninit],
+    /// [Mmu::read_bytes], [Mmu::write_bytes] and [Mmu::read_cstr] (and therefore to the
+    /// addresses seen by the TLB and memory hooks). Operations that manage the address space (e.g.
+    /// [Mmu::map_memory_len] or [Mmu::update_perm]) use addresses as-is.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mask` is not of the form `(1 << n) - 1`.
+    pub fn set_address_mask(&mut self, mask: u64) {
+        assert!(mask != 0 && mask & mask.wrapping_add(1) == 0, "invalid address mask: {mask:#x}");
+        if mask != self.address_mask {
+            self.address_mask = mask;
+            self.tlb.clear();
+        }
+        self.pointer_size = if mask > u32::MAX as u64 { 8 } else { 4 };
+    }
+
+    /// Returns the mask applied to the address of every access (see [Mmu::set_address_mask]).
+    pub fn address_mask(&self) -> u64 {
+        self.address_mask
+    }
+
+    /// Sets the size of a guest pointer read by [Mmu::read_ptr] to `size` bytes. The size is also
+    /// set by [Mmu::set_address_mask] (to 4 bytes for masks that fit in 32 bits, and 8 bytes
+    /// otherwise).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is not 4 or 8.
+    pub fn set_pointer_size(&mut self, size: usize) {
+        assert!(size == 4 || size == 8, "invalid pointer size: {size}");
+        self.pointer_size = size;
+    }
+
+    /// Returns the size of a guest pointer in bytes (see [Mmu::set_pointer_size]).
+    pub fn pointer_size(&self) -> usize {
+        self.pointer_size
+    }
+
+    /// Finds a free region of memory satisfying `layout`, placed according to
+    /// [Mmu::alloc_policy]. Returns [MemError::OutOfMemory] if there is no free region that is
+    /// large enough within the address space (see [Mmu::set_address_space_bits]), or
+    /// [MemError::InvalidRange] if `layout.size` is zero.
+    ///
+    /// This does not advance the random number generator used for [AllocPolicy::Random], so the
+    /// same address is returned until memory is allocated (e.g. by [Mmu::alloc_memory]).
+    pub fn find_free_memory(&self, layout: AllocLayout) -> MemResult<u64> {
+        if layout.size == 0 {
+            return Err(MemError::InvalidRange);
+        }
+
+        // Compute the length that we will end up with if we add the padding necessary to meet
+        // alignment constraints
+        let align = layout.align.checked_next_power_of_two().ok_or(MemError::OutOfMemory)?;
+        let aligned_length = checked_align_up(layout.size, align).ok_or(MemError::OutOfMemory)?;
+
+        let (ceiling, seed) = match self.alloc_policy {
+            AllocPolicy::BottomUp => {
+                // Either use the preferred address specified in the layout or start at the lowest
+                // address available.
+                let mut start_addr = checked_align_up(layout.addr.unwrap_or(0), align)
+                    .ok_or(MemError::OutOfMemory)?;
+
+                while let Some((_, end)) = self.mapping.get_range(
+                    start_addr
+                        ..=start_addr
+                            .checked_add(aligned_length - 1)
+                            .filter(|end| *end <= self.address_space_end)
+                            .ok_or(MemError::OutOfMemory)?,
+                ) {
+                    start_addr = end
+                        .checked_add(1)
+                        .and_then(|next| checked_align_up(next, align))
+                        .ok_or(MemError::OutOfMemory)?;
+                }
+
+                return Ok(start_addr);
+            }
+            AllocPolicy::TopDown { ceiling } => (ceiling, None),
+            AllocPolicy::Random { seed, ceiling } => (ceiling, Some(seed)),
+        };
+        let ceiling = ceiling.min(self.address_space_end.saturating_add(1));
+
+        if let Some(addr) = layout.addr.and_then(|addr| checked_align_up(addr, align)) {
+            let is_free = addr
+                .checked_add(aligned_length - 1)
+                .filter(|end| *end <= self.address_space_end)
+                .is_some_and(|end| {
+                    self.mapping.overlapping_iter(addr..=end).all(|(.., entry)| entry.is_none())
+                });
+            if is_free {
+                return Ok(addr);
+            }
+        }
+
+        // The range of aligned addresses within each free region that the allocation could start
+        // at.
+        let mut slots = self.free_regions(ceiling).into_iter().filter_map(|(start, end)| {
+            let first = checked_align_up(start, align)?;
+            let last = end.checked_sub(aligned_length - 1)? & !(align - 1);
+            (first <= last).then_some((first, last))
+        });
+
+        let Some(seed) = seed
+        else {
+            return slots.next_back().map(|(_, last)| last).ok_or(MemError::OutOfMemory);
+        };
+
+        let slots: Vec<_> = slots.collect();
+        let count = |(first, last): (u64, u64)| ((last - first) / align) as u128 + 1;
+        let total: u128 = slots.iter().map(|slot| count(*slot)).sum();
+        if total == 0 {
+            return Err(MemError::OutOfMemory);
+        }
+
+        let mut n = splitmix64(self.next_alloc_rng_state(seed)) as u128 % total;
+        for slot in slots {
+            if n < count(slot) {
+                return Ok(slot.0 + n as u64 * align);
+            }
+            n -= count(slot);
+        }
+        unreachable!()
+    }
+
+    /// Returns the (inclusive) ranges of addresses below `ceiling` that are not mapped, in
+    /// ascending order.
+    fn free_regions(&self, ceiling: u64) -> Vec<(u64, u64)> {
+        let mut regions = vec![];
+        let mut next = Some(0);
+        for (start, end, _) in self.mapping.iter() {
+            let Some(free) = next.filter(|free| *free < ceiling)
+            else {
+                break;
+            };
+            if start > free {
+                regions.push((free, (start - 1).min(ceiling - 1)));
+            }
+            next = end.checked_add(1);
+        }
+        if let Some(free) = next.filter(|free| *free < ceiling) {
+            regions.push((free, ceiling - 1));
+        }
+        regions
+    }
+
+    /// Gets the next state of the random number generator used for [AllocPolicy::Random]
+    /// (SplitMix64), reseeding the generator if `seed` has changed.
+    fn next_alloc_rng_state(&self, seed: u64) -> u64 {
+        let (current_seed, state) = self.alloc_rng;
+        let state = if current_seed != seed { seed } else { state };
+        state.wrapping_add(0x9e3779b97f4a7c15)
+    }
+
+    /// Advances the random number generator used for [AllocPolicy::Random] after memory has been
+    /// allocated at an address returned by [Mmu::find_free_memory].
+    pub(crate) fn advance_alloc_rng(&mut self) {
+        if let AllocPolicy::Random { seed, .. } = self.alloc_policy {
+            self.alloc_rng = (seed, self.next_alloc_rng_state(seed));
+        }
+    }
+
+    /// Updates the mapping value associated with a region of memory
+    ///
+    /// If `perm` includes [perm::GUARD] then every access to the region fails with
+    /// [MemError::GuardPage] until the permissions of the region are updated again (see
+    /// [Mmu::set_guard_handler]). Guarded regions are always backed by private physical pages.
+    ///
+    /// The permissions of I/O regions can not be changed, if the range overlaps with an I/O region
+    /// (or contains any unmapped memory) an error is returned without modifying any of the range.
+    /// Updating zero bytes is a no-op.
+    pub fn update_perm(&mut self, addr: u64, count: u64, perm: u8) -> MemResult<()> {
+        self.set_perm(addr, count, perm, perm::NONE)
+    }
+
+    /// Sets the [perm::READ], [perm::WRITE] and [perm::EXEC] bits of a region of memory to the
+    /// bits in `prot` (e.g. to implement `mprotect`), keeping all other bits of each byte
+    /// (including [perm::INIT] and [perm::IN_CODE_CACHE]).
+    ///
+    /// If [Mmu::self_modifying_code] is [SelfModifyingCode::Fault], making bytes that are part of
+    /// the code cache writable fails with [MemError::SelfModifyingCode]. Otherwise, writes to
+    /// these bytes are handled as usual when they occur.
+    ///
+    /// Like [Mmu::update_perm], an error is returned without modifying the range if it overlaps
+    /// with an I/O region or contains unmapped memory.
+    pub fn set_protection(&mut self, addr: u64, count: u64, prot: u8) -> MemResult<()> {
+        const PROT: u8 = perm::READ | perm::WRITE | perm::EXEC;
+        if count == 0 {
+            return Ok(());
+        }
+        let end = addr.checked_add(count - 1).ok_or(MemError::AddressOverflow)?;
+        if prot & perm::WRITE != 0 && self.self_modifying_code == SelfModifyingCode::Fault {
+            let in_code_cache =
+                self.perm_runs(addr, end).into_iter().any(|(_, _, run)| match run {
+                    PermRun::Uniform(perm) => perm & perm::IN_CODE_CACHE != 0,
+                    PermRun::Bytes(perms) => perms.iter().any(|p| p & perm::IN_CODE_CACHE != 0),
+                });
+            if in_code_cache {
+                tracing::error!("attempted to make code at {addr:#x}..={end:#x} writable");
+                return Err(MemError::SelfModifyingCode);
+            }
+        }
+        self.set_perm(addr, count, prot & PROT, !PROT)
+    }
+
+    /// Implements [Mmu::update_perm] and [Mmu::set_protection]. The bits in `keep` are kept from
+    /// the existing permissions of each byte instead of being replaced.
+    pub(crate) fn set_perm(&mut self, addr: u64, count: u64, perm: u8, keep: u8) -> MemResult<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let end = addr.checked_add(count - 1).ok_or(MemError::AddressOverflow)?;
+        let perm = self.check_wx(addr, end, perm)?;
+        let guard = perm & perm::GUARD == perm::GUARD;
+        let perm = match guard {
+            true => perm & !perm::MAP,
+            false => perm | perm::MAP,
+        } | if self.track_uninitialized { perm::NONE } else { perm::INIT };
+        debug!("update_perm: addr={addr:#0x}, count={count:#0x}, perm={}", perm::display(perm));
+        let new_perm = |old: u8| (perm & !keep) | (old & keep);
+
+        for (start, len, entry) in self.mapping.overlapping_iter(addr..=end) {
+            match entry {
+                None | Some(MemoryMapping::Reserved(_)) => return Err(MemError::Unmapped),
+                Some(MemoryMapping::Io(io)) if guard => {
+                    let end = start + (len - 1);
+                    let id = io.handler;
+                    debug!("update_perm: can not guard {start:#x}..={end:#x} (I/O handler {id})");
+                    return Err(MemError::Unsupported);
+                }
+                Some(_) => {}
+            }
+        }
+
+        if guard {
+            self.alloc_guard_pages(addr, end)?;
+        }
+        self.unshare_fill_pages(addr, end, |physical, value, perm| {
+            physical.get_fill_page(value, new_perm(perm)).is_some()
+        })?;
+
+        self.notify_mapping_change(MappingChangeKind::PermissionChanged, addr, end);
+        self.invalidate_code_pages(addr, end);
+        // The cached I/O mapping includes the permissions of the region.
+        self.last_io_handler = None;
+
+        let physical = &mut self.physical;
+        let mut tlb_removal = tlb::PendingRemoval::default();
+        let result = self.mapping.overlapping_mut(addr..=end, |start, len, entry| {
+            match entry.as_mut().ok_or(MemError::Unmapped)? {
+                MemoryMapping::Physical(entry) => 'physical: {
+                    tlb_removal.add(start, len);
+
+                    let offset = PageData::offset(start);
+                    let len = len as usize;
+
+                    // Swapping between fill pages with the same value only changes permissions,
+                    // so write hooks are not called.
+                    if offset == 0
+                        && len == physical::PAGE_SIZE
+                        && physical.is_fill_page(entry.index)
+                    {
+                        let data = physical.get(entry.index).data();
+                        let (value, perm) = (data.data[0], new_perm(data.perm[0]));
+                        if let Some(fill_page) = physical.get_fill_page(value, perm) {
+                            debug!("updating fill page: {:?} -> {fill_page:?}", entry.index);
+                            entry.index = fill_page;
+                            break 'physical;
+                        }
+                    }
+
+                    let page = physical.get_mut(entry.index);
+                    if page.executed {
+                        tracing::error!("Changed perms of code page. JIT cache may now be invalid");
+                    }
+                    let perm = &mut page.data_mut().perm[offset..offset + len];
+                    perm.iter_mut().for_each(|p| *p = new_perm(*p));
+                }
+                MemoryMapping::Unallocated(entry) => entry.perm = new_perm(entry.perm),
+                MemoryMapping::Io(io) => io.perm = new_perm(io.perm) & IoMapping::PERM_MASK,
+                MemoryMapping::File(entry) => entry.perm = new_perm(entry.perm) | perm::INIT,
+                MemoryMapping::Reserved(_) => return Err(MemError::Unmapped),
+            }
+
+            Ok(())
+        });
+        // Entries modified before an error still need to be removed.
+        tlb_removal.apply(&mut self.tlb);
+        result
+    }
+
+    /// Applies [Mmu::wx_policy] to a request for the memory in `start..=end` to have `perm`,
+    /// returning the permissions that should be used.
+    fn check_wx(&self, start: u64, end: u64, perm: u8) -> MemResult<u8> {
+        if perm & (perm::WRITE | perm::EXEC) != perm::WRITE | perm::EXEC {
+            return Ok(perm);
+        }
+        match self.wx_policy {
+            WxPolicy::Allow => Ok(perm),
+            WxPolicy::Deny => {
+                debug!("W^X: rejected writable and executable memory at {start:#x}..={end:#x}");
+                Err(MemError::WriteExecViolation)
+            }
+            WxPolicy::StripWrite => {
+                tracing::warn!("W^X: removed write permission from {start:#x}..={end:#x}");
+                Ok(perm & !perm::WRITE)
+            }
+        }
+    }
+
+    /// Ensures that every byte in `start..=end` that is not mapped to an I/O region is backed by a
+    /// private physical page, allowing guard permissions to be stored for each byte.
+    fn alloc_guard_pages(&mut self, start: u64, end: u64) -> MemResult<()> {
+        let mut regions = vec![];
+        for (start, len, entry) in self.mapping.overlapping_iter(start..=end) {
+            if !matches!(entry.ok_or(MemError::Unmapped)?, MemoryMapping::Io(_)) {
+                regions.push((start, start + (len - 1)));
+            }
+        }
+
+        for (mut addr, end) in regions {
+            loop {
+                self.get_unique_physical(addr)?;
+                match self.page_aligned(addr).checked_add(self.page_size()) {
+                    Some(next) if next <= end => addr = next,
+                    _ => break,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill a region of memory with `value`
+    ///
+    /// Parts of the region that are mapped to I/O regions are filled using [IoMemory::fill]. Write
+    /// hooks that overlap with the region are called once the region has been filled, in the same
+    /// way as [Mmu::write_bytes_large]. Filling zero bytes is a no-op.
+    pub fn fill_mem(&mut self, addr: u64, count: u64, value: u8) -> MemResult<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let end = addr.checked_add(count - 1).ok_or(MemError::AddressOverflow)?;
+        debug!("fill_mem: addr={:#0x}, count={:#0x}, value={:#0x}", addr, count, value);
+        self.unshare_fill_pages(addr, end, |_, fill, _| fill == value)?;
+
+        let discard_io_writes = self.discards_io_writes();
+        let physical = &mut self.physical;
+        let mut tlb_removal = tlb::PendingRemoval::default();
+        let io = &mut self.io;
+        let io_trace = &mut self.io_trace;
+        let smc = self.self_modifying_code;
+        let mut invalidated_code = vec![];
+        let result = self.mapping.overlapping_mut(addr..=end, |start, len, entry| {
+            match entry.as_mut().ok_or(MemError::Unmapped)? {
+                MemoryMapping::Physical(entry) => {
+                    tlb_removal.add(start, len);
+                    // Fill pages that were not copied above are already filled with `value`.
+                    let is_fill_page = physical.is_fill_page(entry.index);
+                    let page = physical.get_mut(entry.index);
+                    let offset = PageData::offset(start);
+
+                    if page.executed
+                        && smc != SelfModifyingCode::Ignore
+                        && modifies_cached_code_memset(page.data(), start, len, value)
+                    {
+                        check_self_modifying_code(smc, start)?;
+                        let perm = &mut page.data_mut().perm[offset..offset + len as usize];
+                        perm.iter_mut().for_each(|p| *p &= !perm::IN_CODE_CACHE);
+                        invalidated_code.push(start & !physical::PAGE_MASK);
+                    }
+
+                    if !is_fill_page {
+                        let page = page.data_mut();
+                        page.data[offset..offset + len as usize].fill(value);
+                        page.add_perm(offset, len as usize, perm::INIT);
+                    }
+                }
+                MemoryMapping::Unallocated(entry) => {
+                    entry.value = value;
+                    entry.perm |= perm::INIT;
+                }
+                MemoryMapping::Io(mapping) => {
+                    if !discard_io_writes {
+                        get_io(io, mapping.handler)?.fill(start, len, value)?;
+                    }
+                    if io_trace.is_enabled(mapping.handler) {
+                        io_trace.record_fill(mapping.handler, start, len, value);
+                    }
+                }
+                MemoryMapping::File(file) => {
+                    // The region is entirely overwritten so the file no longer needs to be read.
+                    let perm = file.perm;
+                    *entry = Some(MemoryMapping::Unallocated(UnallocatedMemory { perm, value }));
+                }
+                MemoryMapping::Reserved(_) => return Err(MemError::Unmapped),
+            }
+            Ok(())
+        });
+        // Entries modified before an error still need to be removed.
+        tlb_removal.apply(&mut self.tlb);
+
+        invalidated_code.dedup();
+        for page_start in invalidated_code.into_iter().rev() {
+            self.invalidate_code(page_start, page_start + physical::PAGE_MASK);
+        }
+
+        if result.is_ok() && self.memory_hooks && self.write_hooks.overlaps(addr, count) {
+            self.call_bulk_write_hooks(addr, count, |_, out| out.fill(value));
+        }
+        result
+    }
+
+    #[deprecated(
+        note = "The behavior of this function may change in the future. Use `move_region_len`"
+    )]
+    pub fn move_region(&mut self, start: u64, end: u64, dst: u64) -> MemResult<()> {
+        let len = end.checked_sub(start).ok_or(MemError::InvalidRange)?;
+        self.move_region_len(start, len, dst)
+    }
+
+    /// Moves the `len` bytes starting at `start` to `dst`.
+    ///
+    /// Every byte of the source must be mapped (otherwise this fails with [MemError::Unmapped]),
+    /// and the destination may only overlap with the source: if any other part of the destination
+    /// is mapped this fails with [MemError::AlreadyMapped]. The destination must not extend past
+    /// the end of the address space. Nothing is modified if the move fails for any of these
+    /// reasons, and moving zero bytes is a no-op.
+    ///
+    /// Regions that do not cover an entire physical page (or that are moved by an offset that is
+    /// not page aligned) are copied to newly allocated pages, so that the page backing the source
+    /// does not grant access to the moved bytes (see the notes on sub-page mappings in [Mmu]).
+    pub fn move_region_len(&mut self, start: u64, len: u64, dst: u64) -> MemResult<()> {
+        let Some(last) = len.checked_sub(1)
+        else {
+            return Ok(());
+        };
+        let end = start.checked_add(last).ok_or(MemError::AddressOverflow)?;
+        let dst_end = dst.checked_add(last).ok_or(MemError::AddressOverflow)?;
+        let offset = dst.wrapping_sub(start);
+
+        let mut regions = vec![];
+        for (start, len, entry) in self.mapping.overlapping_iter(start..=end) {
+            regions.push((start, len, entry.cloned().ok_or(MemError::Unmapped)?));
+        }
+
+        // Each entry returned by the iterator is contiguous, so any entry that is not entirely
+        // inside of the source belongs to another mapping.
+        let conflict = self
+            .mapping
+            .overlapping_iter(dst..=dst_end)
+            .any(|(x, len, entry)| entry.is_some() && (x < start || x + (len - 1) > end));
+        if conflict {
+            return Err(MemError::AlreadyMapped);
+        }
+
+        // Allocate the pages for regions that need to be copied before modifying the mapping, so
+        // that running out of memory leaves the source unchanged.
+        let aligned_offset = offset & physical::PAGE_MASK == 0;
+        let needs_copy = |mmu: &Self, len: u64, entry: &MemoryMapping| match entry {
+            MemoryMapping::Physical(mapping) => {
+                (!aligned_offset || len != mmu.page_size())
+                    && !mmu.physical.is_fill_page(mapping.index)
+            }
+            _ => false,
+        };
+        let mut needed = 0;
+        for (start, len, entry) in &regions {
+            if needs_copy(self, *len, entry) {
+                let shifted_start = start.wrapping_add(offset);
+                let first = self.page_aligned(shifted_start);
+                needed +=
+                    (self.page_aligned(shifted_start + (len - 1)) - first) / self.page_size() + 1;
+            }
+        }
+        let mut new_pages = Vec::with_capacity(needed as usize);
+        for _ in 0..needed {
+            match self.physical.alloc() {
+                Some(index) => new_pages.push(index),
+                None => {
+                    new_pages.into_iter().for_each(|index| self.physical.free(index));
+                    return Err(MemError::OutOfMemory);
+                }
+            }
+        }
+        let mut new_pages = new_pages.into_iter();
+
+        self.mapping.remove_all(start..=end);
+        self.tlb.remove_range(start, len);
+        self.tlb.remove_range(dst, len);
+        self.last_io_handler = None;
+        self.notify_mapping_change(MappingChangeKind::Moved { dst }, start, end);
+        self.invalidate_code_pages(start, end);
+
+        // Note: all regions are removed from the source before any are inserted at the destination,
+        // so the order regions are inserted in does not matter if the source and destination
+        // overlap.
+        for (start, len, entry) in regions {
+            let shifted_start = start.wrapping_add(offset);
+            let shifted_end = shifted_start + (len - 1);
+            match entry {
+                MemoryMapping::Physical(mapping) if !aligned_offset || len != self.page_size() => {
+                    self.relocate_physical(
+                        mapping.index,
+                        start,
+                        len,
+                        shifted_start,
+                        &mut new_pages,
+                    );
+                }
+                MemoryMapping::File(mut mapping) => {
+                    mapping.offset = mapping.offset.wrapping_sub(offset);
+                    let entry = MemoryMapping::File(mapping);
+                    self.mapping.insert((shifted_start, shifted_end), entry).unwrap();
+                }
+                entry => self.mapping.insert((shifted_start, shifted_end), entry).unwrap(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the `len` bytes at `start` (backed by the physical page at `index`) to pages taken
+    /// from `new_pages` mapped at `dst`, then removes access to the bytes from the original page.
+    /// `new_pages` must contain a page for every page `dst..dst + len` overlaps with.
+    fn relocate_physical(
+        &mut self,
+        index: physical::Index,
+        start: u64,
+        len: u64,
+        dst: u64,
+        new_pages: &mut impl Iterator<Item = physical::Index>,
+    ) {
+        let offset = PageData::offset(start);
+        let len = len as usize;
+
+        if self.physical.is_fill_page(index) {
+            // Fill pages are shared by other mappings so their permissions cannot be changed.
+            // Instead revert both the moved region and the remainder of the source page to
+            // unallocated memory.
+            let data = self.physical.get(index).data();
+            let (perm, value) = (data.perm[offset] & !perm::MAP, data.data[offset]);
+            let fill = MemoryMapping::Unallocated(UnallocatedMemory { perm, value });
+
+            let page_start = self.page_aligned(start);
+            let _ = self.mapping.overlapping_mut::<_, ()>(
+                page_start..=page_start + physical::PAGE_MASK,
+                |_, _, entry| {
+                    if matches!(entry, Some(MemoryMapping::Physical(x)) if x.index == index) {
+                        *entry = Some(fill.clone());
+                    }
+                    Ok(())
+                },
+            );
+            self.mapping.insert((dst, dst + (len as u64 - 1)), fill).unwrap();
+            return;
+        }
+
+        let page = self.physical.get_mut(index);
+        let invalidate_code = page.executed && page.clear_code_cache(offset, len);
+        let data = page.data().data[offset..offset + len].to_vec();
+        let perm = page.data().perm[offset..offset + len].to_vec();
+        let mut shadow = vec![0; len];
+        page.read_shadow(offset, &mut shadow);
+        page.data_mut().perm[offset..offset + len].fill(perm::NONE);
+
+        let mut copied = 0;
+        while copied < len {
+            let addr = dst + copied as u64;
+            let page_start = self.page_aligned(addr);
+            let offset = PageData::offset(addr);
+            let count = (len - copied).min(physical::PAGE_SIZE - offset);
+
+            let new_index = new_pages.next().expect("missing page for relocated region");
+            let page = self.physical.get_mut(new_index);
+            let new = page.data_mut();
+            new.perm.fill(perm::NONE);
+            new.data[offset..offset + count].copy_from_slice(&data[copied..copied + count]);
+            new.perm[offset..offset + count].copy_from_slice(&perm[copied..copied + count]);
+            page.write_shadow(offset, &shadow[copied..copied + count]);
+
+            if !page.modified {
+                self.modified.insert(page_start);
+            }
+            page.modified = true;
+
+            let mapping = PhysicalMapping { index: new_index, addr: page_start };
+            let end = addr + (count as u64 - 1);
+            self.mapping.insert((addr, end), MemoryMapping::Physical(mapping)).unwrap();
+            copied += count;
+        }
+
+        if invalidate_code {
+            self.invalidate_code(start, start + (len as u64 - 1));
+        }
+    }
+
+    /// Clear the translation lookahead buffer.
+    pub fn clear_tlb(&mut self) {
+        self.tlb.clear();
+        self.last_io_handler = None;
+    }
+
+    /// Obtain a raw pointer to the entries of the translation lookahead buffer. The layout of the
+    /// entries depends on [Mmu::tlb_config], and the tag of each entry includes the identifier of
+    /// the current address space (see [tlb::TranslationCache]).
+    ///
+    /// Safety: Avoid any operation except reading/writing to initialized memory locations while
+    /// this pointer is active.
+    pub fn tlb_ptr(&mut self) -> *const tlb::TLBEntry {
+        self.tlb.as_ptr()
+    }
+
+    /// Gets the geometry of the translation lookahead buffer.
+    pub fn tlb_config(&self) -> TlbConfig {
+        self.tlb.config()
+    }
+
+    /// Invalidate an entry in the TLB.
+    ///
+    /// When addresses are translated using guest page tables, `addr` is a virtual address and only
+    /// the translation for that page is removed (equivalent to `invlpg` on x86).
+    pub fn invalidate_page(&mut self, addr: u64) {
+        self.tlb.invalidate(addr);
+    }
+
+    /// Allocates physical pages for every page in `addr..addr + len` that is not backed by a
+    /// physical page yet, in the same way as the first access to the page would (including the
+    /// initialization state of bytes with [Mmu::track_uninitialized]). This allows the cost of
+    /// lazily allocating a large region to be paid up front (e.g. before running a workload that
+    /// touches the region in random order).
+    ///
+    /// If `for_write` is not set, unallocated pages where every byte has the same value and
+    /// permissions are mapped to a shared fill page (see [Mmu::zero_page_optimization]), as they
+    /// would be for a read. Pages that are already backed by a physical page are skipped, and
+    /// permissions are not checked. Returns the number of physical pages that were allocated.
+    ///
+    /// The range is checked before anything is allocated: if any part of it is unmapped or
+    /// reserved ([MemError::Unmapped]) or mapped to an I/O region ([MemError::Unsupported]), the
+    /// error contains the address of the first byte that failed the check. If physical memory (or
+    /// a budget, see [Mmu::create_page_budget]) is exhausted, the pages allocated before the
+    /// failure remain allocated.
+    pub fn populate(
+        &mut self,
+        addr: u64,
+        len: u64,
+        for_write: bool,
+    ) -> Result<usize, PermRangeError> {
+        let Some(last) = len.checked_sub(1)
+        else {
+            return Ok(0);
+        };
+        let error = |addr, error| PermRangeError { addr, error };
+        let end = addr.checked_add(last).ok_or(error(addr, MemError::AddressOverflow))?;
+        debug!("populate: addr={addr:#x}, len={len:#x}, for_write={for_write}");
+
+        let page_size = self.page_size();
+        let mut pages = vec![];
+        let mut first_error = None;
+        for (start, len, entry) in self.mapping.overlapping_iter(addr..=end) {
+            match entry {
+                Some(MemoryMapping::Unallocated(_) | MemoryMapping::File(_)) => {
+                    let last_page = self.page_aligned(start + (len - 1));
+                    let mut page = self.page_aligned(start);
+                    pages.push(page);
+                    while page != last_page {
+                        page += page_size;
+                        pages.push(page);
+                    }
+                }
+                Some(MemoryMapping::Physical(_)) => {}
+                // Note: the iterator returns regions in reverse order, so the last error is the
+                // first in the range.
+                Some(MemoryMapping::Io(_)) => {
+                    first_error = Some(error(start, MemError::Unsupported))
+                }
+                Some(MemoryMapping::Reserved(_)) | None => {
+                    first_error = Some(error(start, MemError::Unmapped))
+                }
+            }
+        }
+        if let Some(err) = first_error {
+            return Err(err);
+        }
+        pages.sort_unstable();
+        pages.dedup();
+
+        let prev_allocated = self.physical.allocated_pages();
+        let mut tlb_removal = tlb::PendingRemoval::default();
+        let mut result = Ok(());
+        for page in pages {
+            // The start of the page may be outside of the range.
+            let page_addr = page.max(addr);
+            if self.populate_page(page_addr, for_write).is_none() {
+                result = Err(error(page_addr, MemError::OutOfMemory));
+                break;
+            }
+            tlb_removal.add(page, page_size);
+        }
+        tlb_removal.apply(&mut self.tlb);
+        result.map(|_| self.physical.allocated_pages() - prev_allocated)
+    }
+
+    /// Inserts TLB entries for every page in each `(start, len, kind)` range, returning the number
+    /// of entries that were inserted. This is intended for warming up the TLB with a known working
+    /// set (e.g. after a snapshot is restored).
+    ///
+    /// Pages are resolved in the same way as the first access of `kind` would: unallocated pages
+    /// are allocated, and for [AccessKind::Write] copy-on-write pages are copied and the page is
+    /// marked as modified. No data is read or written and hooks are not called. Pages that do not
+    /// allow `kind` for the first byte in the range, and pages that would not be cached by a
+    /// regular access (e.g. I/O regions, or pages with hooks), are skipped.
+    ///
+    /// Nothing is inserted when addresses are translated using guest page tables.
+    pub fn prefetch_translations(&mut self, ranges: &[(u64, u64, AccessKind)]) -> usize {
+        if self.page_tables.is_some() {
+            return 0;
+        }
+        let mut inserted = 0;
+        for &(start, len, kind) in ranges {
+            if len == 0 {
+                continue;
+            }
+            let start = start & self.address_mask;
+            let end = start.saturating_add(len - 1);
+            let mut page = self.page_aligned(start);
+            loop {
+                if self.prefetch_page(page.max(start), kind) {
+                    inserted += 1;
+                }
+                match page.checked_add(self.page_size()) {
+                    Some(next) if next <= end => page = next,
+                    _ => break,
+                }
+            }
+        }
+        inserted
+    }
+
+    /// Inserts the TLB entry for an access of `kind` at `addr` (see
+    /// [Mmu::prefetch_translations]), returning whether an entry was inserted.
+    fn prefetch_page(&mut self, addr: u64, kind: AccessKind) -> bool {
+        let required = match kind {
+            AccessKind::Read => perm::READ,
+            AccessKind::Write => perm::WRITE,
+            AccessKind::Execute => perm::EXEC,
+        };
+        let is_write = kind == AccessKind::Write;
+        let index = match self.mapping.get(addr) {
+            Some(MemoryMapping::Physical(entry)) => {
+                let page_perm = self.physical.get(entry.index).data().perm[PageData::offset(addr)];
+                if perm::check(page_perm, required).is_err() {
+                    return false;
+                }
+                entry.index
+            }
+            Some(&MemoryMapping::Unallocated(UnallocatedMemory { perm, .. }))
+            | Some(&MemoryMapping::File(FileMapping { perm, .. })) => {
+                if perm::check(perm | perm::MAP, required).is_err() {
+                    return false;
+                }
+                match self.init_physical(addr, is_write) {
+                    Some(index) => index,
+                    None => return false,
+                }
+            }
+            Some(MemoryMapping::Io(_) | MemoryMapping::Reserved(_)) | None => return false,
+        };
+
+        let page_start = self.page_aligned(addr);
+        let page_size = self.page_size();
+        let page = self.physical.get(index);
+        if !is_write {
+            let uncachable = self.read_hooks.contains_address(addr, page_size)
+                || self.read_after_hooks.contains_address(addr, page_size)
+                || self.watchpoints.overlaps_page(addr, page_size)
+                || page.aliased
+                || page.is_shared_memory();
+            if !uncachable {
+                let page = self.physical.get_mut(index);
+                self.tlb.insert_read(addr, unsafe { page.read_ptr() });
+            }
+            return !uncachable;
+        }
+
+        let uncachable = self.write_hooks.contains_address(addr, page_size)
+            || self.watchpoints.overlaps_page(addr, page_size)
+            || (page.has_cached_code() && self.self_modifying_code != SelfModifyingCode::Ignore)
+            || page.aliased
+            || page.is_shared_memory();
+        if uncachable {
+            return false;
+        }
+        let index = match page.copy_on_write {
+            true => match self.copy_on_write(index, page_start) {
+                Ok(copy_index) => copy_index,
+                Err(_) => return false,
+            },
+            false => index,
+        };
+
+        self.tlb.remove_read(page_start);
+        let page = self.physical.get_mut(index);
+        if !page.modified {
+            self.modified.insert(page_start);
+        }
+        page.modified = true;
+        // Safety: `page.write_ptr()` ensures the page is a unique copy of the underlying data.
+        self.tlb.insert_write(page_start, unsafe { page.write_ptr() });
+        true
+    }
+
+    /// Create a full snapshot of memory that can later be restored
+    pub fn snapshot(&mut self) -> Snapshot {
+        // TLB is invalidated whenever we clone the physical memory state.
+        self.tlb.clear();
+
+        let snapshot = SnapshotData {
+            mapping: self.mapping.clone(),
+            physical: self.physical.snapshot(),
+            parent: Some(self.parent_state.clone()),
+            address_space_end: self.address_space_end,
+            asid: self.asid,
+            address_spaces: self.address_spaces.clone(),
+            budgets: self.budgets.snapshot(),
+            io: self
+                .io
+                .iter_mut()
+                .enumerate()
+                .map(|(id, x)| {
+                    if self.io_snapshot_policies.get(id) == Some(&SnapshotPolicy::Exclude) {
+                        return None;
+                    }
+                    Some(match x {
+                        Some(x) => x.snapshot(),
+                        None => Box::new(()),
+                    })
+                })
+                .collect(),
+            io_handlers: (0..self.io.len()).map(|id| self.io_identity(id)).collect(),
+            io_stream_pos: self.io_replay.pos(),
+            alloc_rng: self.alloc_rng,
+        };
+
+        // Reconfigure the current modification state to be tracked based on the new snapshot
+        self.parent_state = std::sync::Arc::new(snapshot);
+        self.parent_state.clone()
+    }
+
+    /// Writes the data of the pages shared with another MMU (see [Mmu::share_region]) that was
+    /// captured by `snapshot` back to the shared pages, making the restored data visible to every
+    /// MMU sharing the pages. This should be called after [Mmu::restore], once the other MMUs have
+    /// been restored.
+    pub fn restore_shared_memory(&mut self, snapshot: &Snapshot) {
+        self.physical.restore_shared_memory(&snapshot.physical);
+    }
+
+    /// Restore the full memory state from `snapshot`, equivalent to [Mmu::restore].
+    pub fn try_restore(&mut self, snapshot: Snapshot) -> Result<(), RestoreError> {
+        self.restore(snapshot)
+    }
+
+    /// Restore the full memory state from `snapshot`, failing without modifying any state if the
+    /// snapshot is not compatible with the I/O handlers registered with the MMU.
+    ///
+    /// The state of each handler is only restored to a handler of the same type (and name, see
+    /// [Mmu::register_named_io_handler]) as the handler it was captured from. Handlers that have
+    /// been unregistered since the snapshot was taken are skipped, and handlers that have been
+    /// registered since the snapshot was taken are reset using [IoMemory::reset]. Handlers
+    /// registered with [SnapshotPolicy::Exclude] are never modified.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), RestoreError> {
+        self.validate_snapshot(&snapshot)?;
+
+        self.tlb.clear();
+        self.last_io_handler = None;
+        self.access_log.clear();
+
+        // Note: the modification state of pages is reset as part of restoring physical memory.
+        self.modified.clear();
+        self.notify_mapping_change(MappingChangeKind::Replaced, 0, u64::MAX);
+        self.invalidate_replaced_code_pages(&snapshot.mapping, Some(&snapshot.physical));
+
+        self.physical.restore(&snapshot.physical);
+        for (io, snapshot) in self.io.iter_mut().zip(&snapshot.io) {
+            // Excluded handlers have no state in the snapshot.
+            if let (Some(io), Some(snapshot)) = (io, snapshot) {
+                io.restore(snapshot);
+            }
+        }
+        for id in snapshot.io.len()..self.io.len() {
+            if self.io_snapshot_policy(id) == SnapshotPolicy::Exclude {
+                continue;
+            }
+            if let Some(io) = self.io[id].as_mut() {
+                io.reset();
+            }
+        }
+
+        // Configure our state to match the snapshot
+        self.mapping.clone_from(&snapshot.mapping);
+        self.address_space_end = snapshot.address_space_end;
+        self.asid = snapshot.asid;
+        self.address_spaces.clone_from(&snapshot.address_spaces);
+        self.budgets.restore(&snapshot.budgets);
+        self.tlb.set_asid(self.asid.0 as u64);
+        self.io_replay.seek(snapshot.io_stream_pos);
+        self.alloc_rng = snapshot.alloc_rng;
+        self.parent_state = snapshot;
+        self.physical.release_unused();
+        Ok(())
+    }
+
+    /// Checks that the state of the I/O handlers captured by `snapshot` can be restored to the
+    /// handlers registered with the MMU.
+    pub(crate) fn validate_snapshot(&self, snapshot: &SnapshotData) -> Result<(), RestoreError> {
+        if snapshot.io.len() > self.io.len() {
+            return Err(RestoreError::MissingHandler(IoHandler(self.io.len())));
+        }
+        for (id, (expected, state)) in snapshot.io_handlers.iter().zip(&snapshot.io).enumerate() {
+            let (Some(expected), Some(found)) = (expected, self.io_identity(id))
+            else {
+                continue;
+            };
+            if *expected != found {
+                return Err(RestoreError::HandlerMismatch {
+                    handler: IoHandler(id),
+                    expected: expected.clone(),
+                    found,
+                });
+            }
+
+            let expected = match state {
+                Some(_) => SnapshotPolicy::Include,
+                None => SnapshotPolicy::Exclude,
+            };
+            let found = self.io_snapshot_policy(id);
+            if expected != found {
+                return Err(RestoreError::PolicyMismatch {
+                    handler: IoHandler(id),
+                    expected,
+                    found,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a snapshot of the mapping, data and permissions of the `len` bytes starting at
+    /// `start` that can later be restored with [Mmu::restore_range]. Ranges that extend past the
+    /// end of the address space are truncated.
+    ///
+    /// Unlike [Mmu::snapshot], memory outside of the range and the state of I/O handlers is not
+    /// captured.
+    pub fn snapshot_range(&mut self, start: u64, len: u64) -> RangeSnapshot {
+        let end = start.saturating_add(len.saturating_sub(1));
+        let mut snapshot = RangeSnapshot { start, end, regions: vec![] };
+        if len == 0 {
+            return snapshot;
+        }
+
+        // Pages are captured by sharing their data with the currently mapped page, so the TLB needs
+        // to be invalidated to ensure that future writes make a copy of the page.
+        self.tlb.remove_range(start, (end - start).saturating_add(1));
+
+        for (region_start, region_len, entry) in self.mapping.overlapping_iter(start..=end) {
+            let entry = match entry {
+                Some(MemoryMapping::Physical(mapping)) => {
+                    RangeSnapshotEntry::Physical(self.physical.get(mapping.index).clone())
+                }
+                Some(mapping @ (MemoryMapping::Unallocated(_) | MemoryMapping::File(_))) => {
+                    RangeSnapshotEntry::Unallocated(mapping.clone())
+                }
+                Some(MemoryMapping::Io(_)) => RangeSnapshotEntry::Io,
+                Some(MemoryMapping::Reserved(_)) | None => continue,
+            };
+            snapshot.regions.push((region_start, region_start + (region_len - 1), entry));
+        }
+        snapshot.regions.reverse();
+
+        snapshot
+    }
+
+    /// Restore the memory captured by [Mmu::snapshot_range] without modifying any memory outside
+    /// of the snapshot's range. Regions that were unmapped when the snapshot was taken are left
+    /// untouched.
+    ///
+    /// Memory is not modified if restoring fails, which happens with:
+    ///
+    /// - [MemError::Unmapped] if any region that was mapped when the snapshot was taken has since
+    ///   been unmapped or converted to an I/O region.
+    /// - [MemError::OutOfMemory] if a page could not be allocated for restoring the data.
+    /// - [MemError::SelfModifyingCode] if restoring would modify translated code and
+    ///   [SelfModifyingCode::Fault] is enabled.
+    pub fn restore_range(&mut self, snapshot: &RangeSnapshot) -> MemResult<()> {
+        for (start, end, entry) in &snapshot.regions {
+            if matches!(entry, RangeSnapshotEntry::Io) {
+                continue;
+            }
+            for (_, _, current) in self.mapping.overlapping_iter(*start..=*end) {
+                match current {
+                    Some(
+                        MemoryMapping::Physical(_)
+                        | MemoryMapping::Unallocated(_)
+                        | MemoryMapping::File(_),
+                    ) => {}
+                    Some(MemoryMapping::Io(_) | MemoryMapping::Reserved(_)) | None => {
+                        return Err(MemError::Unmapped);
+                    }
+                }
+            }
+        }
+
+        // Allocate all pages before restoring anything, so that memory is never partially
+        // restored.
+        for (start, end, entry) in &snapshot.regions {
+            if let RangeSnapshotEntry::Physical(page) = entry {
+                self.prepare_page_region(*start, *end, page)?;
+            }
+        }
+
+        for (start, end, entry) in &snapshot.regions {
+            match entry {
+                RangeSnapshotEntry::Physical(page) => {
+                    self.restore_page_region(*start, *end, page)?
+                }
+                RangeSnapshotEntry::Unallocated(mapping) => {
+                    let _ = self.mapping.overlapping_mut::<_, ()>(*start..=*end, |_, _, entry| {
+                        *entry = Some(mapping.clone());
+                        Ok(())
+                    });
+                    self.tlb.remove_range(*start, (end - start) + 1);
+                    self.notify_mapping_change(MappingChangeKind::Replaced, *start, *end);
+                }
+                RangeSnapshotEntry::Io => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ensures that the memory between `start..=end` (which must be contained within a single
+    /// page) is backed by unique physical pages and that copying the data from `saved` would not
+    /// fault because of self-modifying code. The content of memory is not modified.
+    fn prepare_page_region(
+        &mut self,
+        start: u64,
+        end: u64,
+        saved: &physical::Page,
+    ) -> MemResult<()> {
+        let mut addr = start;
+        loop {
+            let (_, entry_end, _) = self.mapping.get_with_range(addr).ok_or(MemError::Unmapped)?;
+            let chunk_end = entry_end.min(end);
+
+            let index = self.get_unique_physical(addr)?;
+            let page = self.physical.get(index);
+            if self.self_modifying_code == SelfModifyingCode::Fault
+                && page.executed
+                && !page.shares_data_with(saved)
+            {
+                let offset = PageData::offset(addr);
+                let len = (chunk_end - addr) as usize + 1;
+                let src = &saved.data().data[offset..offset + len];
+                if modifies_cached_code(page.data(), addr, src) {
+                    check_self_modifying_code(self.self_modifying_code, addr)?;
+                }
+            }
+
+            if chunk_end == end {
+                return Ok(());
+            }
+            addr = chunk_end + 1;
+        }
+    }
+
+    /// Copies the data, permissions and labels between `start..=end` (which must be contained
+    /// within a single page) from `saved` to the memory currently mapped at that location.
+    fn restore_page_region(
+        &mut self,
+        start: u64,
+        end: u64,
+        saved: &physical::Page,
+    ) -> MemResult<()> {
+        let mut addr = start;
+        loop {
+            let (_, entry_end, _) = self.mapping.get_with_range(addr).ok_or(MemError::Unmapped)?;
+            let chunk_end = entry_end.min(end);
+
+            let page_start = self.page_aligned(addr);
+            let index = self.get_unique_physical(addr)?;
+            let page = self.physical.get_mut(index);
+
+            // Avoid copying if the page has not been modified since the snapshot was taken.
+            if !page.shares_data_with(saved) {
+                let offset = PageData::offset(addr);
+                let len = (chunk_end - addr) as usize + 1;
+                let src = saved.data();
+
+                let smc = self.self_modifying_code;
+                let modifies_code = page.executed
+                    && smc != SelfModifyingCode::Ignore
+                    && modifies_cached_code(page.data(), addr, &src.data[offset..offset + len]);
+                if modifies_code {
+                    check_self_modifying_code(smc, addr)?;
+                }
+
+                let dst = page.data_mut();
+                for i in offset..offset + len {
+                    // Keep track of code that has already been translated, unless it is about to
+                    // be invalidated.
+                    let mut in_code_cache = dst.perm[i] & perm::IN_CODE_CACHE;
+                    if modifies_code && dst.data[i] != src.data[i] {
+                        in_code_cache = 0;
+                    }
+                    dst.perm[i] = src.perm[i] | in_code_cache;
+                }
+                dst.data[offset..offset + len].copy_from_slice(&src.data[offset..offset + len]);
+
+                if !page.modified {
+                    self.modified.insert(page_start);
+                }
+                page.modified = true;
+                self.tlb.remove(page_start);
+
+                if modifies_code {
+                    self.invalidate_code(page_start, page_start + physical::PAGE_MASK);
+                }
+            }
+
+            let offset = PageData::offset(addr);
+            let len = (chunk_end - addr) as usize + 1;
+            self.physical.get_mut(index).copy_shadow_from(saved, offset, len);
+
+            if chunk_end == end {
+                return Ok(());
+            }
+            addr = chunk_end + 1;
+        }
+    }
+
+    /// Ensures that `addr` is backed by a physical page that can be modified in-place without
+    /// affecting any other mapping, returning the index of the page.
+    fn get_unique_physical(&mut self, addr: u64) -> MemResult<physical::Index> {
+        let index = match self.mapping.get(addr).ok_or(MemError::Unmapped)? {
+            MemoryMapping::Physical(entry) => entry.index,
+            MemoryMapping::Unallocated(_) | MemoryMapping::File(_) => {
+                self.init_physical(addr, true).ok_or(MemError::OutOfMemory)?
+            }
+            MemoryMapping::Io(_) | MemoryMapping::Reserved(_) => return Err(MemError::Unmapped),
+        };
+        if !index.is_zero_page() && !self.physical.get(index).copy_on_write {
+            return Ok(index);
+        }
+
+        let page_start = self.page_aligned(addr);
+        let page_end = page_start + (self.page_size() - 1);
+        let prev_allocated = self.physical.allocated_pages();
+        let copy_index = self.physical.clone_page(index).ok_or(MemError::OutOfMemory)?;
+        self.check_soft_capacity(prev_allocated, AllocKind::CopyOnWrite, Some(addr));
+        let copy_mapping = PhysicalMapping { index: copy_index, addr: page_start };
+        tracing::trace!(
+            "{:?} ({:#0x}) copy for unique access -> {copy_index:?}",
+            index,
+            page_start
+        );
+
+        if self.physical.get(index).aliased {
+            self.remap_aliases(index, copy_index);
+            return Ok(copy_index);
+        }
+        let _ = self.mapping.overlapping_mut::<_, ()>(page_start..=page_end, |_, _, entry| {
+            if let Some(MemoryMapping::Physical(mapping)) = entry {
+                if mapping.index == index {
+                    *mapping = copy_mapping;
+                }
+            }
+            Ok(())
+        });
+        self.tlb.remove(page_start);
+
+        Ok(copy_index)
+    }
+
+    /// Create a snapshot of just the virtual address space
+    pub fn snapshot_virtual_mapping(&mut self) -> VirtualMemoryMap {
+        // Clear the TLB to ensure that no writes will be missed.
+        self.tlb.clear();
+        self.last_io_handler = None;
+
+        // Mark all physical pages in the mapping as copy-on-write (except for pages shared with
+        // another MMU, which are always modified in-place).
+        for (_, _, entry) in self.mapping.iter() {
+            if let MemoryMapping::Physical(mapping) = entry {
+                let page = self.physical.get_mut(mapping.index);
+                page.copy_on_write = !page.is_shared_memory();
+            }
+        }
+
+        self.add_detached_refs();
+        self.mapping.clone()
+    }
+
+    /// Returns a copy of the virtual address space that refers to the same physical pages as the
+    /// current mapping, so writes through either mapping modify the same memory (e.g. for threads
+    /// that share an address space).
+    ///
+    /// The pages referenced by the copy are not freed until it is passed to
+    /// [Mmu::restore_virtual_mapping].
+    pub fn clone_virtual_mapping(&mut self) -> VirtualMemoryMap {
+        self.add_detached_refs();
+        self.mapping.clone()
+    }
+
+    /// Records that the physical pages referenced by the current mapping are referenced by a
+    /// mapping that is about to be detached from the MMU.
+    fn add_detached_refs(&mut self) {
+        for (_, _, entry) in self.mapping.iter() {
+            if let MemoryMapping::Physical(mapping) = entry {
+                let slot = mapping.index.slot();
+                if slot >= self.detached_refs.len() {
+                    self.detached_refs.resize(slot + 1, 0);
+                }
+                self.detached_refs[slot] += 1;
+            }
+        }
+    }
+
+    /// Releases the references to physical pages recorded for `mapping` when it was detached.
+    fn release_detached_refs(&mut self, mapping: &VirtualMemoryMap) {
+        for (_, _, entry) in mapping.iter() {
+            if let MemoryMapping::Physical(mapping) = entry {
+                if let Some(refs) = self.detached_refs.get_mut(mapping.index.slot()) {
+                    *refs = refs.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    /// Returns whether `index` is referenced by a mapping that has been detached from the MMU.
+    fn is_detached(&self, index: physical::Index) -> bool {
+        self.detached_refs.get(index.slot()).is_some_and(|refs| *refs != 0)
+    }
+
+    /// Returns a read-only view of the current state of memory that can be sent to other threads,
+    /// e.g. to analyze memory while the MMU continues to be used (see [FrozenMemory]).
+    ///
+    /// The view shares the data of physical pages with the MMU (except for pages stored using
+    /// [PageStore::Mmap], which are copied), and the pages are copied the next time they are
+    /// modified so the view is never affected by later writes. The view shares the data of
+    /// registered files with the MMU, and I/O regions can not be read from the view.
+    pub fn freeze(&mut self) -> FrozenMemory {
+        // Clear the TLB to ensure that pages shared with the view are copied before they are next
+        // modified.
+        self.tlb.clear();
+
+        let mut pages = HashMap::new();
+        for (_, _, entry) in self.mapping.iter() {
+            if let MemoryMapping::Physical(entry) = entry {
+                let page = self.physical.get(entry.index);
+                pages.entry(entry.index).or_insert_with(|| page.share_data());
+            }
+        }
+
+        let init = if self.track_uninitialized { perm::NONE } else { perm::INIT };
+        let files = self.files.clone();
+        FrozenMemory::new(self.mapping.clone(), pages, files, init, self.address_mask)
+    }
+
+    /// Creates a new (empty) virtual address space, returning its identifier. The new address space
+    /// is not activated until [Mmu::switch_address_space] is called.
+    ///
+    /// Physical memory is shared by all address spaces, pages can be mapped in multiple address
+    /// spaces using [Mmu::map_physical].
+    ///
+    /// # Panics
+    ///
+    /// Panics if more than [tlb::MAX_ASIDS] address spaces are created.
+    pub fn create_address_space(&mut self) -> Asid {
+        assert!(self.address_spaces.len() < tlb::MAX_ASIDS, "too many address spaces");
+        self.address_spaces.push(RangeMap::new());
+        Asid(self.address_spaces.len() - 1)
+    }
+
+    /// Gets the identifier of the active address space.
+    pub fn current_address_space(&self) -> Asid {
+        self.asid
+    }
+
+    /// Makes `asid` the active address space, so that all subsequent operations use its mapping.
+    ///
+    /// Translations cached in the TLB are tagged with the address space they belong to, so
+    /// switching does not flush the TLB. The modification log ([Mmu::modified]) and
+    /// [Mmu::mapping_changed] are shared by all address spaces: the log contains the virtual
+    /// addresses of pages modified in any address space, and `mapping_changed` is also set when
+    /// switching to a different address space.
+    pub fn switch_address_space(&mut self, asid: Asid) {
+        assert!(asid.0 < self.address_spaces.len(), "unknown address space: {asid:?}");
+        if asid == self.asid {
+            return;
+        }
+        tracing::debug!("switching address space: {:?} -> {asid:?}", self.asid);
+
+        let next = std::mem::take(&mut self.address_spaces[asid.0]);
+        self.address_spaces[self.asid.0] = std::mem::replace(&mut self.mapping, next);
+        self.asid = asid;
+        self.tlb.set_asid(asid.0 as u64);
+        self.last_io_handler = None;
+        self.notify_mapping_change(MappingChangeKind::Replaced, 0, u64::MAX);
+    }
+
+    /// Removes all translations cached for `asid` from the TLB. This is required if the mapping of
+    /// the address space is modified directly (e.g. using [Mmu::get_mapping_mut]).
+    pub fn invalidate_asid(&mut self, asid: Asid) {
+        self.tlb.invalidate_asid(asid.0 as u64);
+        if asid == self.asid {
+            self.last_io_handler = None;
+        }
+    }
+
+    /// Returns the mapping of the active address space followed by the mappings of every other
+    /// address space.
+    fn all_address_spaces(&self) -> impl Iterator<Item = &VirtualMemoryMap> {
+        std::iter::once(&self.mapping).chain(self.inactive_address_spaces().map(|(_, x)| x))
+    }
+
+    /// Returns the mappings of every address space that is not active.
+    fn inactive_address_spaces(&self) -> impl Iterator<Item = (Asid, &VirtualMemoryMap)> {
+        let current = self.asid;
+        self.address_spaces
+            .iter()
+            .enumerate()
+            .map(|(i, mapping)| (Asid(i), mapping))
+            .filter(move |(asid, _)| *asid != current)
+    }
+
+    /// Sets the walker used to translate virtual addresses using page tables stored in guest
+    /// physical memory, or restores the default translation using the memory map if `walker` is
+    /// `None`.
+    ///
+    /// While a walker is set, the memory map describes the guest physical address space, so
+    /// functions that operate on regions of the map (e.g. [Mmu::map_memory], [Mmu::update_perm],
+    /// memory hooks, range snapshots, the modification log, and code invalidation) use physical
+    /// addresses. Accesses ([Mmu::read], [Mmu::write] and the functions built on them) and
+    /// [Mmu::ensure_executable] use virtual addresses, which are translated by walking the page
+    /// tables (starting from [Mmu::set_page_table_root]) whenever the translation is not cached in
+    /// the TLB. Accesses that can not be translated fail with [MemError::PageFault], and the
+    /// details of the fault can be retrieved using [Mmu::take_page_fault].
+    ///
+    /// Writes to pages that contain page tables used by cached translations flush the TLB. Any
+    /// modification of the memory map also flushes the TLB, since the virtual addresses that refer
+    /// to the modified region are unknown.
+    ///
+    /// Note: the execute permission granted by the page tables is checked by
+    /// [Mmu::ensure_executable], reads that include [perm::EXEC] are only checked when the
+    /// translation is not cached.
+    pub fn set_page_table_walker(&mut self, walker: Option<Box<dyn PageTableWalker>>) {
+        let (root, user) = self.page_tables.as_ref().map_or((0, false), |x| (x.root, x.user));
+        self.page_tables = walker.map(|walker| PageTableState {
+            walker: Some(walker),
+            root,
+            user,
+            tables: HashSet::new(),
+        });
+        self.tlb.set_flush_on_remove(self.page_tables.is_some());
+        self.tlb.clear();
+        self.last_io_handler = None;
+        self.notify_mapping_change(MappingChangeKind::Replaced, 0, u64::MAX);
+    }
+
+    /// Gets the physical address of the root page table, or `None` if addresses are not translated
+    /// using guest page tables.
+    pub fn page_table_root(&self) -> Option<u64> {
+        self.page_tables.as_ref().map(|x| x.root)
+    }
+
+    /// Sets the physical address of the root page table (e.g. the value of CR3 on x86), flushing
+    /// all translations from the TLB.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no page table walker has been set (see [Mmu::set_page_table_walker]).
+    pub fn set_page_table_root(&mut self, root: u64) {
+        let state = self.page_tables.as_mut().expect("page table walker not set");
+        state.root = root;
+        self.flush_page_table_translations();
+    }
+
+    /// Sets whether accesses translated using guest page tables are performed in user mode,
+    /// flushing all translations from the TLB if the mode changed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no page table walker has been set (see [Mmu::set_page_table_walker]).
+    pub fn set_page_table_user_mode(&mut self, user: bool) {
+        let state = self.page_tables.as_mut().expect("page table walker not set");
+        if state.user != user {
+            state.user = user;
+            self.flush_page_table_translations();
+        }
+    }
+
+    /// Takes the details of the most recent access that failed because the page table walk failed.
+    pub fn take_page_fault(&mut self) -> Option<PageFault> {
+        self.page_fault.take()
+    }
+
+    /// Removes all translations obtained from the guest page tables from the TLB.
+    fn flush_page_table_translations(&mut self) {
+        self.tlb.clear();
+        if let Some(state) = self.page_tables.as_mut() {
+            state.tables.clear();
+        }
+    }
+
+    /// Returns whether accesses use virtual addresses that are translated using the guest page
+    /// tables. This is not the case for accesses to page tables, or the physical accesses that
+    /// translated accesses are performed with (see [Mmu::with_physical_access]).
+    #[inline]
+    fn translates_page_tables(&self) -> bool {
+        self.page_tables.is_some() && self.tlb.asid() != tlb::PHYSICAL_ASID
+    }
+
+    /// Runs `f` with accesses using guest physical addresses. Translations cached in the TLB by `f`
+    /// are tagged with [tlb::PHYSICAL_ASID], so they are never used for virtual addresses.
+    fn with_physical_access<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let asid = self.tlb.asid();
+        self.tlb.set_asid(tlb::PHYSICAL_ASID);
+        // Watchpoints apply to virtual addresses, so they are not checked for physical accesses.
+        let suspended = std::mem::replace(&mut self.watchpoints.suspended, true);
+        let result = f(self);
+        self.watchpoints.suspended = suspended;
+        self.tlb.set_asid(asid);
+        result
+    }
+
+    /// Reads `N` bytes at the guest physical address `paddr` without checking permissions.
+    pub(crate) fn read_guest_physical<const N: usize>(&mut self, paddr: u64) -> MemResult<[u8; N]> {
+        self.with_physical_access(|mmu| mmu.read_unreported(paddr, perm::NONE))
+    }
+
+    /// Writes `value` to the guest physical address `paddr` without checking permissions.
+    pub(crate) fn write_guest_physical<const N: usize>(
+        &mut self,
+        paddr: u64,
+        value: [u8; N],
+    ) -> MemResult<()> {
+        self.with_physical_access(|mmu| mmu.write_unreported(paddr, value, perm::NONE))
+    }
+
+    /// Translates the virtual address `addr` to a guest physical address by walking the page
+    /// tables, recording the fault if the walk fails.
+    fn translate_page_tables(&mut self, addr: u64, kind: AccessKind) -> MemResult<u64> {
+        let state = self.page_tables.as_mut().unwrap();
+        let mut walker = state.walker.take().expect("recursive page table walk");
+        let request = WalkRequest { addr, kind, user: state.user };
+        let root = state.root;
+
+        let mut mem = PageTableMemory { mmu: self, tables: vec![] };
+        let result = walker.walk(&mut mem, root, request);
+        let tables = mem.tables;
+
+        let state = self.page_tables.as_mut().unwrap();
+        state.walker = Some(walker);
+        match result {
+            Ok(translation) => {
+                let mut new_tables = false;
+                for page in tables {
+                    new_tables |= state.tables.insert(page);
+                }
+                if new_tables {
+                    // Ensure that future writes to the tables are not handled by the TLB.
+                    self.tlb.clear_write();
+                }
+                Ok(translation.paddr)
+            }
+            Err(fault) => {
+                tracing::debug!("page fault: {fault:x?}");
+                self.page_fault = Some(fault);
+                Err(MemError::PageFault)
+            }
+        }
+    }
+
+    /// Handles a TLB miss for a read from the virtual address `addr` using guest page tables.
+    #[cold]
+    fn read_translated<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
+        let kind = if perm & perm::EXEC != 0 { AccessKind::Execute } else { AccessKind::Read };
+        let paddr = self.translate_page_tables(addr, kind)?;
+        let (result, page) = self.with_physical_access(|mmu| {
+            let result = mmu.read_unreported(paddr, perm);
+            (result, mmu.tlb.translate_read(paddr))
+        });
+        if let Some(page) = page {
+            if !self.watchpoints.overlaps_page(addr, self.page_size()) {
+                self.tlb.insert_read(addr, page);
+            }
+        }
+        result
+    }
+
+    /// Handles a TLB miss for a write to the virtual address `addr` using guest page tables.
+    #[cold]
+    fn write_translated<const N: usize>(
+        &mut self,
+        addr: u64,
+        value: [u8; N],
+        perm: u8,
+    ) -> MemResult<()> {
+        let paddr = self.translate_page_tables(addr, AccessKind::Write)?;
+        let (result, page) = self.with_physical_access(|mmu| {
+            let result = mmu.write_unreported(paddr, value, perm);
+            (result, mmu.tlb.translate_write(paddr))
+        });
+
+        let page_start = self.page_aligned(paddr);
+        if self.page_tables.as_ref().unwrap().tables.contains(&page_start) {
+            if result.is_ok() {
+                tracing::trace!("page table at {page_start:#x} modified by write to {addr:#x}");
+                self.flush_page_table_translations();
+            }
+        }
+        else if let Some(page) = page {
+            if !self.watchpoints.overlaps_page(addr, self.page_size()) {
+                self.tlb.insert_write(addr, page);
+            }
+        }
+        result
+    }
+
+    /// Implements [Mmu::ensure_executable] for virtual addresses translated using guest page
+    /// tables.
+    fn ensure_executable_translated(&mut self, start: u64, len: u64) -> bool {
+        let Some(end) = start.checked_add(len - 1)
+        else {
+            return false;
+        };
+        let mut addr = start;
+        loop {
+            let page_end = (self.page_aligned(addr) + (self.page_size() - 1)).min(end);
+            let Ok(paddr) = self.translate_page_tables(addr, AccessKind::Execute)
+            else {
+                return false;
+            };
+            let len = page_end - addr + 1;
+            if !self.with_physical_access(|mmu| mmu.ensure_executable(paddr, len)) {
+                return false;
+            }
+            if page_end == end {
+                return true;
+            }
+            addr = page_end + 1;
+        }
+    }
+
+    /// Take the underlying virtual address space.
+    pub fn take_virtual_mapping(&mut self) -> VirtualMemoryMap {
+        self.tlb.clear();
+        self.last_io_handler = None;
+        self.notify_mapping_change(MappingChangeKind::Replaced, 0, u64::MAX);
+        self.add_detached_refs();
+        std::mem::take(&mut self.mapping)
+    }
+
+    /// Restore just the virtual address space
+    ///
+    /// `mapping` is expected to have been returned by [Mmu::take_virtual_mapping],
+    /// [Mmu::snapshot_virtual_mapping] or [Mmu::clone_virtual_mapping], and the references it holds
+    /// to physical pages are released.
+    pub fn restore_virtual_mapping(&mut self, mapping: VirtualMemoryMap) {
+        self.clear_modified();
+        self.invalidate_replaced_code_pages(&mapping, None);
+        self.release_detached_refs(&mapping);
+        self.mapping = mapping;
+        self.tlb.clear();
+        self.last_io_handler = None;
+
+        self.notify_mapping_change(MappingChangeKind::Replaced, 0, u64::MAX);
+    }
+
+    /// Checks that every physical page, I/O handler and file referenced by `mapping` exists, e.g.
+    /// for a mapping that was deserialized. Returns [MemError::Unallocated] otherwise.
+    pub fn validate_mapping(&self, mapping: &VirtualMemoryMap) -> MemResult<()> {
+        let free: HashSet<_> = self.physical.free_list().iter().map(|x| x.slot()).collect();
+        for (_, _, entry) in mapping.iter() {
+            let exists = match entry {
+                MemoryMapping::Physical(entry) => {
+                    entry.index.slot() < self.physical.slots()
+                        && !free.contains(&entry.index.slot())
+                }
+                MemoryMapping::Io(io) => matches!(self.io.get(io.handler), Some(Some(_))),
+                MemoryMapping::File(entry) => entry.file.0 < self.files.len(),
+                MemoryMapping::Unallocated(_) | MemoryMapping::Reserved(_) => true,
+            };
+            if !exists {
+                return Err(MemError::Unallocated);
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the virtual address space (see [Mmu::restore_virtual_mapping]) after checking
+    /// that the mapping is valid (see [Mmu::validate_mapping]).
+    pub fn try_restore_virtual_mapping(&mut self, mapping: VirtualMemoryMap) -> MemResult<()> {
+        self.validate_mapping(&mapping)?;
+        self.restore_virtual_mapping(mapping);
+        Ok(())
+    }
+
+    /// Serializes the current virtual mapping as JSON (see [RangeMap] for the format).
+    #[cfg(feature = "serde")]
+    pub fn export_mapping_json(&self) -> String {
+        serde_json::to_string(&self.mapping).expect("failed to serialize mapping")
+    }
+
+    /// Reset the the virtual address space
+    pub fn reset_virtual(&mut self) {
+        self.clear_modified();
+        self.invalidate_code_pages(0, u64::MAX);
+        self.mapping.clear();
+        self.tlb.clear();
+        self.last_io_handler = None;
+
+        self.notify_mapping_change(MappingChangeKind::Replaced, 0, u64::MAX);
+    }
+
+    /// Clear the page modification log
+    pub fn clear_page_modification_log(&mut self) {
+        self.tlb.clear_write();
+        self.last_io_handler = None;
+        self.clear_modified();
+    }
+
+    /// Clears the set of modified pages, resetting the modification state of any page that is
+    /// still mapped so that future writes are logged again.
+    fn clear_modified(&mut self) {
+        let page_size = self.page_size();
+        for addr in self.modified.iter() {
+            for (_, _, entry) in self.mapping.overlapping_iter(addr..=addr + (page_size - 1)) {
+                if let Some(MemoryMapping::Physical(entry)) = entry {
+                    self.physical.get_mut(entry.index).modified = false;
+                }
+            }
+        }
+        self.modified.clear();
+    }
+
+    /// Returns an iterator over the addresses of all pages in the page modification log (in
+    /// ascending order).
+    pub fn dirty_pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.modified.iter()
+    }
+
+    /// Returns an iterator over all pages in the page modification log (in ascending order).
+    pub fn iter_dirty_pages(&self) -> impl Iterator<Item = DirtyPage<'_>> + '_ {
+        self.modified.iter().map(|addr| self.get_dirty_page(addr))
+    }
+
+    /// Returns an iterator over all pages in the page modification log (in ascending order),
+    /// clearing the log in the same way as [Mmu::clear_page_modification_log].
+    pub fn drain_dirty_pages(&mut self) -> impl Iterator<Item = DirtyPage<'_>> + '_ {
+        let modified: Vec<_> = self.modified.iter().collect();
+        self.clear_page_modification_log();
+
+        let this = &*self;
+        modified.into_iter().map(|addr| this.get_dirty_page(addr))
+    }
+
+    /// Resolves the page modified at `addr` through the current mapping.
+    fn get_dirty_page(&self, addr: u64) -> DirtyPage<'_> {
+        let page_end = addr + (self.page_size() - 1);
+        let data =
+            self.mapping.overlapping_iter(addr..=page_end).find_map(|(_, _, entry)| match entry? {
+                MemoryMapping::Physical(entry) => Some(self.physical.get(entry.index).data()),
+                _ => None,
+            });
+        DirtyPage { addr, data }
+    }
+
+    /// Returns the regions of the virtual address space ordered by address, where adjacent
+    /// regions with the same effective permissions and kind are merged.
+    ///
+    /// Unlike iterating over [Mmu::get_mapping], regions are split wherever the permissions of
+    /// the bytes of a physical page change (e.g. after [Mmu::update_perm] is used on part of a
+    /// page). Scanning the regions never allocates or initializes memory.
+    pub fn regions(&self) -> impl Iterator<Item = Region> + '_ {
+        regions_of(&self.mapping, |index| self.physical.get(index).data())
+    }
+
+    /// Get the permission bits associated with the byte at `addr`
+    pub fn get_perm(&self, addr: u64) -> u8 {
+        let entry = match self.mapping.get(addr) {
+            Some(entry) => entry,
+            None => return perm::NONE,
+        };
+        match entry {
+            MemoryMapping::Physical(entry) => {
+                let page = self.physical.get(entry.index).data();
+                page.perm[PageData::offset(addr)]
+            }
+            MemoryMapping::Unallocated(metadata) => metadata.perm,
+            MemoryMapping::File(metadata) => metadata.perm,
+            MemoryMapping::Io(io) => io.perm,
+            MemoryMapping::Reserved(_) => perm::NONE,
+        }
+    }
+
+    /// Returns the bitwise AND of the permissions of every byte in `addr..addr + len`, or
+    /// [perm::NONE] if any byte is unmapped (I/O regions are handled according to
+    /// [Mmu::io_perm_policy]). Unallocated regions report the permissions they will have once they
+    /// are allocated. Memory is never allocated or initialized.
+    ///
+    /// Returns every permission bit (except [perm::IN_CODE_CACHE]) if `len` is zero.
+    pub fn get_perm_range(&self, addr: u64, len: u64) -> u8 {
+        let mut result = perm::ALL & !perm::IN_CODE_CACHE;
+        let Some(end) = len.checked_sub(1)
+        else {
+            return result;
+        };
+        let Some(end) = addr.checked_add(end)
+        else {
+            return perm::NONE;
+        };
+        for (_, _, run) in self.perm_runs(addr, end) {
+            match run {
+                PermRun::Uniform(perm) => result &= perm,
+                PermRun::Bytes(perms) => perms.iter().for_each(|perm| result &= perm),
+            }
+            if result & perm::MAP == 0 {
+                return perm::NONE;
+            }
+        }
+        result
+    }
+
+    /// Checks that every byte in `addr..addr + len` has the permissions in `perm`, without
+    /// performing an access (see [Mmu::get_perm_range]). On failure, the error contains the
+    /// address of the first byte that failed the check.
+    pub fn check_perm_range(&self, addr: u64, len: u64, perm: u8) -> Result<(), PermRangeError> {
+        let Some(end) = len.checked_sub(1)
+        else {
+            return Ok(());
+        };
+        let end = addr
+            .checked_add(end)
+            .ok_or(PermRangeError { addr, error: MemError::AddressOverflow })?;
+
+        let mask = perm | perm::MAP;
+        for (start, _, run) in self.perm_runs(addr, end) {
+            let (offset, error) = match run {
+                PermRun::Uniform(byte) => match perm::check(byte, mask) {
+                    Ok(()) => continue,
+                    Err(error) => (0, error),
+                },
+                PermRun::Bytes(perms) => {
+                    // Every byte passes the check if the bits they have in common pass it, which
+                    // is much faster to check than each byte individually.
+                    if perm::check(perms.iter().fold(!0, |acc, byte| acc & byte), mask).is_ok() {
+                        continue;
+                    }
+                    match perms
+                        .iter()
+                        .enumerate()
+                        .find_map(|(i, byte)| Some((i, perm::check(*byte, mask).err()?)))
+                    {
+                        Some((i, error)) => (i as u64, error),
+                        None => continue,
+                    }
+                }
+            };
+            return Err(PermRangeError { addr: start + offset, error });
+        }
+        Ok(())
+    }
+
+    /// Returns the permissions of the bytes between `start` and `end` (inclusive) as a list of
+    /// `(start, end, run)` ordered by address, without allocating or initializing memory.
+    fn perm_runs(&self, start: u64, end: u64) -> Vec<(u64, u64, PermRun<'_>)> {
+        self.perm_runs_with(start, end, self.io_perm_policy)
+    }
+
+    /// Implements [Mmu::perm_runs], handling I/O regions according to `io_policy`.
+    fn perm_runs_with(
+        &self,
+        start: u64,
+        end: u64,
+        io_policy: IoPermPolicy,
+    ) -> Vec<(u64, u64, PermRun<'_>)> {
+        let init = if self.track_uninitialized { perm::NONE } else { perm::INIT };
+        let mut runs: Vec<_> = self
+            .mapping
+            .overlapping_iter(start..=end)
+            .map(|(start, len, entry)| {
+                let end = start + (len - 1);
+                let run = match entry {
+                    Some(MemoryMapping::Physical(entry)) => {
+                        let offset = PageData::offset(start);
+                        let perms = &self.physical.get(entry.index).data().perm;
+                        PermRun::Bytes(&perms[offset..][..len as usize])
+                    }
+                    Some(MemoryMapping::Unallocated(entry)) => {
+                        PermRun::Uniform(entry.perm | perm::MAP | init)
+                    }
+                    Some(MemoryMapping::File(entry)) => PermRun::Uniform(entry.perm | perm::MAP),
+                    Some(MemoryMapping::Io(io)) => match io_policy {
+                        IoPermPolicy::Allow => PermRun::Uniform(io.perm | perm::MAP | perm::INIT),
+                        IoPermPolicy::Deny => PermRun::Uniform(perm::NONE),
+                    },
+                    Some(MemoryMapping::Reserved(_)) | None => PermRun::Uniform(perm::NONE),
+                };
+                (start, end, run)
+            })
+            .collect();
+        // Note: the iterator returns regions in reverse order.
+        runs.reverse();
+        runs
+    }
+
+    /// Check that the region of memory between addr..addr+len is initialized and executable, and
+    /// ensure that if it is ever written to in the future it will be detected.
+    ///
+    /// If [Mmu::wx_policy] is not [WxPolicy::Allow], this also checks that the region is not
+    /// writable: with [WxPolicy::Deny] the check fails, and with [WxPolicy::StripWrite] write
+    /// permission is removed from the region. An empty region is always executable.
+    pub fn ensure_executable(&mut self, start: u64, len: u64) -> bool {
+        if len == 0 {
+            return true;
+        }
+        if self.translates_page_tables() {
+            return self.ensure_executable_translated(start, len);
+        }
+        let Some(end) = start.checked_add(len - 1)
+        else {
+            return false;
+        };
+
+        let tlb = &mut self.tlb;
+        let physical = &mut self.physical;
+        let code_pages = &mut self.code_pages;
+        let wx_policy = self.wx_policy;
+        self.mapping
+            .overlapping_mut::<_, MemError>(start..=end, |start, len, entry| match entry {
+                Some(MemoryMapping::Physical(mapping)) => {
+                    let page = physical.get_mut(mapping.index);
+
+                    // Check whether the code is actually executable.
+                    let offset = PageData::offset(start);
+                    let len = len as usize;
+                    let perm =
+                        unsafe { page.write_ptr().ptr.as_mut().get_perm_unchecked(offset, len) };
+                    perm::check(perm, perm::INIT | perm::EXEC)?;
+
+                    if wx_policy != WxPolicy::Allow {
+                        let data = page.data_mut();
+                        let perm = &mut data.perm[offset..offset + len];
+                        if perm.iter().any(|p| p & perm::WRITE != 0) {
+                            let end = start + (len as u64 - 1);
+                            match wx_policy {
+                                WxPolicy::Deny => {
+                                    debug!("W^X: {start:#x}..={end:#x} is writable");
+                                    return Err(MemError::WriteExecViolation);
+                                }
+                                _ => {
+                                    tracing::warn!(
+                                        "W^X: removed write permission from {start:#x}..={end:#x}"
+                                    );
+                                    perm.iter_mut().for_each(|p| *p &= !perm::WRITE);
+                                }
+                            }
+                        }
+                    }
+
+                    // Mark the page as executed
+                    page.executed = true;
+                    code_pages.insert(mapping.addr, mapping.index);
+
+                    // Prevent writes to the region we are executing (we don't currently support
+                    // self modifying code).
+                    if self.self_modifying_code != SelfModifyingCode::Ignore {
+                        unsafe {
+                            page.write_ptr().ptr.as_mut().add_perm_unchecked(
+                                offset,
+                                len,
+                                perm::IN_CODE_CACHE,
+                            );
+                        };
+                    }
+
+                    tlb.remove_write(mapping.addr);
+                    Ok(())
+                }
+                _ => Err(MemError::ExecViolation),
+            })
+            .is_ok()
+    }
+
+    /// Removes any code in the region of memory between `start` and `start+len` from the code
+    /// cache, notifying the code invalidation handler if the region contained any cached code.
+    ///
+    /// After this is called, the region can be modified without triggering self-modifying code
+    /// detection until the code is translated again.
+    pub fn flush_code_range(&mut self, start: u64, len: u64) -> MemResult<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = start.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+        debug!("flush_code_range: start={start:#0x}, end={end:#0x}");
+
+        let mut flushed = false;
+        for (region_start, region_len, entry) in self.mapping.overlapping_iter(start..=end) {
+            let Some(MemoryMapping::Physical(mapping)) = entry
+            else {
+                continue;
+            };
+            let page = self.physical.get_mut(mapping.index);
+            if page.executed
+                && page.clear_code_cache(PageData::offset(region_start), region_len as usize)
+            {
+                self.tlb.remove_write(region_start);
+                flushed = true;
+            }
+        }
+
+        if flushed {
+            self.invalidate_code(start, end);
+        }
+        Ok(())
+    }
+
+    /// Runs `f` with the self-modifying code protection temporarily removed from the region of
+    /// memory between `start` and `start+len`, allowing code in the region to be patched.
+    ///
+    /// If the region contains any cached code, the code invalidation handler is notified once for
+    /// the entire region before `f` is called. After `f` returns, protection is restored for all
+    /// bytes that still contain the original code, and the (inclusive) ranges of bytes that were
+    /// modified are returned so that they can be retranslated.
+    pub fn with_code_patching<R>(
+        &mut self,
+        start: u64,
+        len: u64,
+        f: impl FnOnce(&mut Mmu) -> R,
+    ) -> MemResult<CodePatch<R>> {
+        if len == 0 {
+            return Ok(CodePatch { result: f(self), modified: vec![] });
+        }
+        let end = start.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+
+        // Keep track of the value of every byte in the region that is part of the code cache.
+        let mut cached = vec![];
+        for (region_start, region_len, entry) in self.mapping.overlapping_iter(start..=end) {
+            if let MemoryMapping::Physical(mapping) = entry.ok_or(MemError::Unmapped)? {
+                let page = self.physical.get(mapping.index).data();
+                let offset = PageData::offset(region_start);
+                for i in offset..offset + region_len as usize {
+                    if page.perm[i] & perm::IN_CODE_CACHE != 0 {
+                        cached.push((region_start + (i - offset) as u64, page.data[i]));
+                    }
+                }
+            }
+        }
+        cached.sort_unstable();
+
+        if !cached.is_empty() {
+            for &(addr, _) in &cached {
+                if let Some((_, perm)) = self.physical_byte_mut(addr) {
+                    *perm &= !perm::IN_CODE_CACHE;
+                }
+            }
+            self.invalidate_code(start, end);
+        }
+
+        let result = f(self);
+
+        let mut modified: Vec<(u64, u64)> = vec![];
+        for &(addr, value) in &cached {
+            match self.physical_byte_mut(addr) {
+                Some((data, perm)) if *data == value => *perm |= perm::IN_CODE_CACHE,
+                // The byte was modified (or unmapped), so it is no longer part of the code cache.
+                _ => match modified.last_mut() {
+                    Some((_, end)) if *end + 1 == addr => *end = addr,
+                    _ => modified.push((addr, addr)),
+                },
+            }
+        }
+
+        Ok(CodePatch { result, modified })
+    }
+
+    /// Returns the physical page that backs every byte between `start` and `end` (inclusive), or
+    /// `None` if any of the bytes are not backed by the same physical page.
+    fn physical_backing(&self, start: u64, end: u64) -> Option<physical::Index> {
+        let mut indices =
+            self.mapping.overlapping_iter(start..=end).map(|(_, _, entry)| match entry {
+                Some(MemoryMapping::Physical(entry)) => Some(entry.index),
+                _ => None,
+            });
+        let first = indices.next()??;
+        indices.all(|x| x == Some(first)).then_some(first)
+    }
+
+    /// Returns mutable references to the data and permission bits of the byte at `addr` if it is
+    /// backed by physical memory, removing the page from the TLB.
+    fn physical_byte_mut(&mut self, addr: u64) -> Option<(&mut u8, &mut u8)> {
+        let index = match self.mapping.get(addr)? {
+            MemoryMapping::Physical(mapping) => mapping.index,
+            _ => return None,
+        };
+        self.tlb.remove(self.page_aligned(addr));
+
+        let page = self.physical.get_mut(index).data_mut();
+        let offset = PageData::offset(addr);
+        Some((&mut page.data[offset], &mut page.perm[offset]))
+    }
+
+    /// Clears the executable bit from uninitialized memory.
+    ///
+    /// @fixme: this was used a workaround for `track_uninitialized` returning to many false
+    /// positives in some cases.
+    pub fn clear_uninitialized_exec_bytes(&mut self) {
+        let physical = &mut self.physical;
+        for (start, end, entry) in self.mapping.iter_mut() {
+            match entry {
+                MemoryMapping::Physical(entry) => {
+                    let (offset, len) = PageData::offset_and_len(start, end + 1);
+                    let page = physical.get_mut(entry.index);
+                    page.data_mut().perm[offset..offset + len].iter_mut().for_each(|p| {
+                        if *p & perm::INIT == 0 {
+                            *p &= !perm::EXEC;
+                        }
+                    });
+                }
+                MemoryMapping::Unallocated(x) => x.perm &= !perm::EXEC,
+                MemoryMapping::Io(_) | MemoryMapping::File(_) | MemoryMapping::Reserved(_) => {}
+            }
+        }
+    }
+
+    /// Initialize a new physical page and map it such that it contains `addr`.
+    ///
+    /// Returns the index of the new page in physical memory (or `None` if we are out of memory)
+    fn init_physical(&mut self, addr: u64, is_write: bool) -> Option<physical::Index> {
+        let index = self.populate_page(addr, is_write)?;
+        self.tlb.remove(self.page_aligned(addr));
+        Some(index)
+    }
+
+    /// Implements [Mmu::init_physical] without removing the page from the TLB, which the caller
+    /// is responsible for.
+    fn populate_page(&mut self, addr: u64, is_write: bool) -> Option<physical::Index> {
+        let page_start = self.page_aligned(addr);
+        let page_size = self.page_size();
+        let page_end = page_start + (page_size - 1);
+
+        let range = page_start..=page_end;
+        let prev_allocated = self.physical.allocated_pages();
+        // If we are only reading from this page and every byte of the page has the same value, then
+        // map it to a shared fill page (e.g. the zero page).
+        if self.zero_page_optimization && !is_write {
+            if let Some(fill_page) = self.get_fill_page(page_start, page_size) {
+                tracing::trace!("init_physical: addr={page_start:#0x}, index={fill_page:?}");
+                // Fill pages are allocated the first time they are used.
+                self.check_soft_capacity(prev_allocated, AllocKind::LazyFault, Some(addr));
+
+                let _ = self.mapping.overlapping_mut::<_, ()>(range, |_, _, entry| {
+                    *entry = Some(MemoryMapping::Physical(PhysicalMapping {
+                        index: fill_page,
+                        addr: page_start,
+                    }));
+                    Ok(())
+                });
+                return Some(fill_page);
+            }
+        }
+
+        let budget = self.budgets.check(addr, page_start).ok()?;
+        let index = self.physical.alloc()?;
+        if let Some(budget) = budget {
+            self.budgets.charge(page_start, budget);
+        }
+        self.check_soft_capacity(prev_allocated, AllocKind::LazyFault, Some(addr));
+
+        tracing::trace!("init_physical: addr={:#0x}, index={:?}", page_start, index);
+        let new_mapping = PhysicalMapping { index, addr: page_start };
+
+        let init_perm = if self.track_uninitialized { perm::NONE } else { perm::INIT };
+        let uninit_value = self.uninit_value;
+
+        let physical = &mut self.physical;
+        let files = &self.files;
+        let _ = self.mapping.overlapping_mut::<_, ()>(range, |start, len, entry| {
+            let len = len as usize;
+
+            // Determine how this region of the page should be initalized.
+            let (value, perm) = match entry {
+                Some(MemoryMapping::Unallocated(x)) => {
+                    tracing::trace!("Replacing unallocated region (start={start:#x}, len={len:#x}) with physical mapping.");
+                    let init = (x.value, x.perm | perm::MAP | init_perm);
+                    *entry = Some(MemoryMapping::Physical(new_mapping));
+                    init
+                }
+                Some(MemoryMapping::Physical(existing)) => {
+                    // Rare case where there was an existing page map at this location. This should
+                    // only occur when a page is partially mapped. Copy any memory that could be
+                    // lost when we replace this mapping.
+                    //
+                    // @fixme: handle this better.
+
+                    tracing::trace!(
+                        "copy {len:#0x} bytes at {start:#0x} from: {:?}",
+                        existing.index
+                    );
+
+                    let offset = (start - page_start) as usize;
+
+                    let (old_page, new_page) = physical.get_pair_mut(existing.index, index);
+                    let (old, new) = (old_page.data(), new_page.data_mut());
+                    new.data[offset..offset + len].copy_from_slice(&old.data[offset..offset + len]);
+                    new.perm[offset..offset + len].copy_from_slice(&old.perm[offset..offset + len]);
+                    new_page.copy_shadow_from(old_page, offset, len);
+
+                    *entry = Some(MemoryMapping::Physical(new_mapping));
+                    return Ok(());
+                }
+                Some(MemoryMapping::File(x)) => {
+                    tracing::trace!("Replacing file region (start={start:#x}, len={len:#x}) with physical mapping.");
+                    let page = physical.get_mut(index).data_mut();
+                    let offset = PageData::offset(start);
+                    let data = file_bytes(files, x, start, len);
+                    page.data[offset..offset + data.len()].copy_from_slice(data);
+                    page.data[offset + data.len()..offset + len].fill(0);
+                    page.perm[offset..offset + len].fill(x.perm | perm::MAP);
+                    *entry = Some(MemoryMapping::Physical(new_mapping));
+                    return Ok(());
+                }
+                Some(MemoryMapping::Io(_) | MemoryMapping::Reserved(_)) | None => {
+                    (uninit_value, perm::NONE)
+                }
+            };
+
+            let page = physical.get_mut(index).data_mut();
+            let offset = PageData::offset(start);
+            page.data[offset..offset + len].fill(value);
+            page.perm[offset..offset + len].fill(perm);
+
+            Ok(())
+        });
+
+        Some(index)
+    }
+
+    /// Checks whether every byte of the memory has the same value and permissions, returning the
+    /// index of the fill page (see [physical::PhysicalMemory::get_fill_page]) that can be used for
+    /// reads.
+    ///
+    /// Note: bytes that are not part of any mapping are filled with [Mmu::uninit_value] instead of
+    /// zero when the page is allocated, so pages that are only partially mapped are never
+    /// compatible.
+    fn get_fill_page(&mut self, start: u64, len: u64) -> Option<physical::Index> {
+        let end = start.checked_add(len - 1)?;
+        let mut fill = None;
+        for (region_start, region_len, entry) in self.mapping.overlapping_iter(start..=end) {
+            let (value, perm) = match entry {
+                Some(MemoryMapping::Unallocated(x)) => (x.value, x.perm),
+                Some(MemoryMapping::File(x)) => {
+                    let readable = perm::check(x.perm | perm::MAP, perm::READ | perm::INIT).is_ok();
+                    let data = file_bytes(&self.files, x, region_start, region_len as usize);
+                    if !readable || data.iter().any(|byte| *byte != 0) {
+                        return None;
+                    }
+                    (0, x.perm)
+                }
+                _ => return None,
+            };
+            if fill.is_some_and(|fill| fill != (value, perm)) {
+                return None;
+            }
+            fill = Some((value, perm));
+        }
+        let (value, perm) = fill?;
+        self.physical.get_fill_page(value, perm)
+    }
+
+    /// Copies the shared fill pages (including the zero pages) mapped in `start..=end` that will
+    /// be modified, since fill pages are shared by unrelated mappings. `keep` is called with the
+    /// value and permissions of each fill page that is entirely covered by the range, returning
+    /// whether the page can be kept.
+    fn unshare_fill_pages(
+        &mut self,
+        start: u64,
+        end: u64,
+        mut keep: impl FnMut(&mut physical::PhysicalMemory, u8, u8) -> bool,
+    ) -> MemResult<()> {
+        let mut copies = vec![];
+        for (start, len, entry) in self.mapping.overlapping_iter(start..=end) {
+            let Some(MemoryMapping::Physical(x)) = entry
+            else {
+                continue;
+            };
+            if !self.physical.is_fill_page(x.index) {
+                continue;
+            }
+            let data = self.physical.get(x.index).data();
+            let (value, perm) = (data.data[0], data.perm[0]);
+            let whole = PageData::offset(start) == 0 && len as usize == physical::PAGE_SIZE;
+            if !whole || !keep(&mut self.physical, value, perm) {
+                copies.push(start);
+            }
+        }
+        for addr in copies {
+            self.get_unique_physical(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Checks whether the memory range entirely consists of mapped regular memory.
+    pub fn is_regular_region(&self, start: u64, len: u64) -> bool {
+        let Some(end) = start.checked_add(len - 1)
+        else {
+            return false;
+        };
+        for (_, _, entry) in self.mapping.overlapping_iter((start, end)) {
+            match entry {
+                Some(
+                    MemoryMapping::Physical(_)
+                    | MemoryMapping::Unallocated(_)
+                    | MemoryMapping::File(_),
+                ) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Checks whether every byte in `start..start + len` is mapped (including I/O regions) and
+    /// has the permissions in `perm`, e.g. to check whether an access would succeed before
+    /// performing it. Unlike [Mmu::check_perm_range], I/O regions are always checked against the
+    /// permissions of the mapping, regardless of [Mmu::io_perm_policy].
+    ///
+    /// An empty range is always accessible.
+    pub fn is_accessible_region(&self, start: u64, len: u64, perm: u8) -> bool {
+        let Some(last) = len.checked_sub(1)
+        else {
+            return true;
+        };
+        let Some(end) = start.checked_add(last)
+        else {
+            return false;
+        };
+        let mask = perm | perm::MAP;
+        self.perm_runs_with(start, end, IoPermPolicy::Allow).into_iter().all(
+            |(.., run)| match run {
+                PermRun::Uniform(byte) => perm::check(byte, mask).is_ok(),
+                PermRun::Bytes(bytes) => bytes.iter().all(|byte| perm::check(*byte, mask).is_ok()),
+            },
+        )
+    }
+
+    /// Gets the physical address assocated with a virtual address, returning `None` if `addr` is
+    /// unmapped or unallocated
+    pub fn get_physical_addr(&self, addr: u64) -> Option<PhysicalAddr> {
+        self.resolve_vaddr(addr).map(|entry| entry.phys)
+    }
+
+    pub fn resolve_vaddr(&self, vaddr: u64) -> Option<Addr> {
+        match self.mapping.get(vaddr)? {
+            MemoryMapping::Physical(entry) => {
+                Some(Addr { virt: vaddr, phys: self.physical.address_of(vaddr, entry.index) })
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the index of physical page mapped at `addr`.
+    pub fn get_physical_index(&self, addr: u64) -> Option<physical::Index> {
+        match self.mapping.get(addr)? {
+            MemoryMapping::Physical(entry) => Some(entry.index),
+            _ => None,
+        }
+    }
+
+    pub fn get_physical(&self, index: physical::Index) -> &physical::Page {
+        self.physical.get(index)
+    }
+
+    /// Gets mutable access to the physical page at `index`.
+    ///
+    /// The TLB stores pointers to the data of pages, so the data of a page that is mapped into the
+    /// address space must not be modified using this function, since cached translations are not
+    /// invalidated and the modification is not recorded (see [Mmu::with_physical_page_mut]).
+    pub fn get_physical_mut(&mut self, index: physical::Index) -> &mut physical::Page {
+        self.physical.get_mut(index)
+    }
+
+    /// Runs `f` with mutable access to the data of the physical page at `index`.
+    ///
+    /// Unlike [Mmu::get_physical_mut], this removes any translations for the page from the TLB and
+    /// records every virtual page mapped to the page in the modification log. If the page is
+    /// shared copy-on-write with another virtual address space it is copied first, so `f` only
+    /// modifies the copy used by the current address space. Code in the page that is modified by
+    /// `f` is removed from the code cache.
+    ///
+    /// The shared zero and fill pages can not be modified, so [MemError::Unsupported] is returned
+    /// if `index` is a zero or fill page.
+    pub fn with_physical_page_mut<R>(
+        &mut self,
+        index: physical::Index,
+        f: impl FnOnce(&mut PageData) -> R,
+    ) -> MemResult<R> {
+        if self.physical.is_fill_page(index) {
+            return Err(MemError::Unsupported);
+        }
+
+        let mut index = index;
+        if self.physical.get(index).copy_on_write {
+            if let Some(addr) = self.virtual_addrs_of(index).next() {
+                index = self.copy_on_write(index, addr)?;
+            }
+        }
+
+        let addrs: Vec<_> = self.virtual_addrs_of(index).collect();
+        self.invalidate_tlb_for_physical(index);
+        for &addr in &addrs {
+            self.modified.insert(addr);
+        }
+
+        let page = self.physical.get_mut(index);
+        page.modified = true;
+        let code = page.executed.then(|| Box::new(page.data().data));
+        let result = f(page.data_mut());
+
+        if let Some(old) = code {
+            let data = page.data_mut();
+            let mut modifies_code = false;
+            for ((perm, new), old) in data.perm.iter_mut().zip(&data.data).zip(old.iter()) {
+                if *perm & perm::IN_CODE_CACHE != 0 && new != old {
+                    *perm &= !perm::IN_CODE_CACHE;
+                    modifies_code = true;
+                }
+            }
+            if modifies_code {
+                for addr in addrs {
+                    self.invalidate_code(addr, addr + (self.page_size() - 1));
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the (page-aligned) virtual addresses in the current address space that are mapped
+    /// to the physical page at `index`, in ascending order.
+    ///
+    /// The lookup uses an index that is rebuilt the first time it is used after the mapping is
+    /// modified, so repeated lookups without changes to the mapping are cheap.
+    pub fn virtual_addrs_of(&self, index: physical::Index) -> std::vec::IntoIter<u64> {
+        let mut reverse = self.reverse_index.borrow_mut();
+        if reverse.generation != self.mapping.generation() {
+            reverse.pages.clear();
+            for (start, _, entry) in self.mapping.iter() {
+                if let MemoryMapping::Physical(x) = entry {
+                    let addrs = reverse.pages.entry(x.index).or_default();
+                    let addr = self.page_aligned(start);
+                    // Sub-page mappings may map the same page more than once.
+                    if addrs.last() != Some(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            }
+            reverse.generation = self.mapping.generation();
+        }
+        reverse.pages.get(&index).cloned().unwrap_or_default().into_iter()
+    }
+
+    /// Removes every translation for the physical page at `index` from the TLB, including
+    /// translations cached for inactive address spaces.
+    pub(crate) fn invalidate_tlb_for_physical(&mut self, index: physical::Index) {
+        let page_size = self.page_size();
+        for addr in self.virtual_addrs_of(index) {
+            self.tlb.remove_range(addr, page_size);
+        }
+        let mapped_at = |(_, _, entry): (u64, u64, &MemoryMapping)| matches!(entry, MemoryMapping::Physical(x) if x.index == index);
+        for (asid, mapping) in self.address_spaces.iter().enumerate() {
+            if mapping.iter().any(mapped_at) {
+                self.tlb.invalidate_asid(asid as u64);
+            }
+        }
+    }
+
+    fn read_physical<const N: usize>(
+        &mut self,
+        index: physical::Index,
+        addr: u64,
+        perm: u8,
+    ) -> MemResult<[u8; N]> {
+        let page_size = self.page_size();
+        let page = self.physical.get_mut(index);
+        let result = page.data().read(addr, perm)?;
+
+        // If there is no memory hook set on the current page, cache the translated address in the
+        // TLB. Aliased pages are never cached, since the entries for other aliases would not be
+        // updated if the data of the page is copied, and neither are pages shared with another MMU,
+        // since the permissions stored with the data depend on the MMU that accessed it last.
+        let uncachable = self.read_hooks.contains_address(addr, page_size)
+            || self.read_after_hooks.contains_address(addr, page_size)
+            || self.watchpoints.overlaps_page(addr, page_size)
+            || page.aliased
+            || page.is_shared_memory();
+        if !uncachable {
+            self.tlb.insert_read(addr, unsafe { page.read_ptr() });
+        }
+        Ok(result)
+    }
+
+    fn write_physical<const N: usize>(
+        &mut self,
+        index: physical::Index,
+        addr: u64,
+        value: [u8; N],
+        perm: u8,
+    ) -> MemResult<()> {
+        let page_start = self.page_aligned(addr);
+        let page_size = self.page_size();
+
+        let mut page = self.physical.get_mut(index);
+        let modifies_code = page.executed
+            && self.self_modifying_code != SelfModifyingCode::Ignore
+            && modifies_cached_code(page.data(), addr, &value);
+        if modifies_code {
+            check_self_modifying_code(self.self_modifying_code, addr)?;
+        }
+
+        // If the data of the page is shared, then either the page is copied here or `data_mut`
+        // creates a new copy of the data, so invalidate the read entry for the TLB cache.
+        let moves_data = page.is_shared();
         if page.copy_on_write {
-            // Make a copy and update the mapping to point to the new copy.
-            let copy_index = self.physical.clone_page(index).ok_or(MemError::OutOfMemory)?;
-            let copy_mapping = PhysicalMapping { index: copy_index, addr: page_start };
-            tracing::trace!("{:?} ({:#0x}) copy-on-write -> {copy_index:?}", index, page_start);
+            let copy_index = self.copy_on_write(index, page_start)?;
+            page = self.physical.get_mut(copy_index);
+        }
+        if moves_data {
+            self.tlb.remove_read(page_start);
+        }
+
+        // @todo: check the overhead of this hash operation.
+
+        if !page.modified {
+            self.modified.insert(page_start);
+        }
+        page.modified = true;
+        if modifies_code {
+            let offset = PageData::offset(addr);
+            let perm = &mut page.data_mut().perm[offset..offset + N];
+            perm.iter_mut().for_each(|p| *p &= !perm::IN_CODE_CACHE);
+        }
+        page.data_mut().write(addr, value, perm)?;
+
+        if modifies_code {
+            // Note: the TLB entry for the page is never inserted for pages with cached code, so
+            // there is no need to remove it here.
+            self.invalidate_code(page_start, page_start + (page_size - 1));
+            return Ok(());
+        }
+
+        // Note: writes to pages with cached code must always go through the slow path, since the
+        // TLB does not check for self-modifying code.
+        let uncachable = self.write_hooks.contains_address(addr, page_size)
+            || self.watchpoints.overlaps_page(addr, page_size)
+            || (page.has_cached_code() && self.self_modifying_code != SelfModifyingCode::Ignore)
+            || page.aliased
+            || page.is_shared_memory();
+        if !uncachable {
+            // Safety: `page.data_mut()` ensures the page is a unique copy of the underlying data.
+            self.tlb.insert_write(page_start, unsafe { page.write_ptr() });
+        }
+
+        Ok(())
+    }
+
+    /// Makes a copy of the copy-on-write page at `index` (mapped at `page_start`) and updates the
+    /// mapping to point to the new copy, returning the index of the copy.
+    fn copy_on_write(
+        &mut self,
+        index: physical::Index,
+        page_start: u64,
+    ) -> MemResult<physical::Index> {
+        let budget = self.budgets.check(page_start, page_start)?;
+        let prev_allocated = self.physical.allocated_pages();
+        let copy_index = self.physical.clone_page(index).ok_or(MemError::OutOfMemory)?;
+        self.check_soft_capacity(prev_allocated, AllocKind::CopyOnWrite, Some(page_start));
+        if let Some(budget) = budget {
+            self.budgets.charge(page_start, budget);
+        }
+        let copy_mapping = PhysicalMapping { index: copy_index, addr: page_start };
+        tracing::trace!("{:?} ({:#0x}) copy-on-write -> {copy_index:?}", index, page_start);
+
+        // The copy has the same data, so translated code in the page remains valid.
+        let aliased = self.physical.get(index).aliased;
+        for (addr, code_index) in &mut self.code_pages {
+            if *code_index == index && (aliased || *addr == page_start) {
+                *code_index = copy_index;
+            }
+        }
+
+        if aliased {
+            self.remap_aliases(index, copy_index);
+        }
+        else {
+            let page_end = page_start + (self.page_size() - 1);
+            self.mapping.overlapping_mut(page_start..=page_end, |_start, _end, entry| {
+                if let Some(mapping @ MemoryMapping::Physical(_)) = entry {
+                    *mapping = MemoryMapping::Physical(copy_mapping);
+                }
+                Ok(())
+            })?;
+        }
+        Ok(copy_index)
+    }
+
+    /// Applies [Mmu::alignment_policy] to an access of `N` bytes at `addr`.
+    #[inline]
+    fn check_alignment<const N: usize>(&self, addr: u64) -> MemResult<()> {
+        if N != 1 && !physical::is_aligned::<N>(addr) && !self.alignment_policy.allows(N) {
+            return Err(MemError::Unaligned);
+        }
+        Ok(())
+    }
+
+    #[cold]
+    fn read_unaligned<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
+        self.check_alignment::<N>(addr)?;
+        if perm != perm::NONE && self.watchpoints.should_check(addr, N, read_kind(perm)) {
+            return self.read_watched(addr, perm);
+        }
+
+        // Accesses that wrap around the end of the masked address space are always split into
+        // individual bytes.
+        let wraps = self.wraps_address_mask(addr, N);
+        if !wraps {
+            if let Some(value) = self.read_unaligned_cached(addr, perm) {
+                return Ok(value);
+            }
+            if !self.is_tlb_cached(addr, N, false) && self.overlaps_io(addr, N) {
+                return self.read_split(addr, perm);
+            }
+        }
+
+        let mut value = [0; N];
+        for (i, byte) in value.iter_mut().enumerate() {
+            let addr = addr.wrapping_add(i as u64) & self.address_mask;
+            *byte = self.read_unreported::<1>(addr, perm)?[0];
+        }
+        Ok(value)
+    }
+
+    #[cold]
+    fn write_unaligned<const N: usize>(
+        &mut self,
+        addr: u64,
+        value: [u8; N],
+        perm: u8,
+    ) -> MemResult<()> {
+        self.check_alignment::<N>(addr)?;
+        if perm != perm::NONE && self.watchpoints.should_check(addr, N, AccessKind::Write) {
+            return self.write_watched(addr, value, perm);
+        }
+
+        let wraps = self.wraps_address_mask(addr, N);
+        if !wraps {
+            if self.write_unaligned_cached(addr, &value, perm) {
+                return Ok(());
+            }
+            if !self.is_tlb_cached(addr, N, true) && self.overlaps_io(addr, N) {
+                return self.write_split(addr, value, perm);
+            }
+        }
+
+        for (i, &byte) in value.iter().enumerate() {
+            let addr = addr.wrapping_add(i as u64) & self.address_mask;
+            self.write_unreported(addr, [byte], perm)?;
+        }
+        Ok(())
+    }
+
+    /// Attempts to perform an unaligned read using the TLB, splitting the read at the page boundary
+    /// (if any) so that at most two lookups are required. Returns `None` if any part of the read
+    /// is not cached or fails, in which case the read must be performed one byte at a time so that
+    /// errors are reported for the correct byte.
+    #[inline]
+    fn read_unaligned_cached<const N: usize>(&mut self, addr: u64, perm: u8) -> Option<[u8; N]> {
+        let mut value = [0; N];
+        let split = N.min(physical::PAGE_SIZE - PageData::offset(addr));
+        let (first, second) = value.split_at_mut(split);
+        let next = addr.wrapping_add(split as u64) & self.address_mask;
+        // Safety: the TLB only contains pages that are valid.
+        unsafe {
+            self.tlb.read_slice(addr, first, perm).ok()?;
+            if !second.is_empty() {
+                self.tlb.read_slice(next, second, perm).ok()?;
+            }
+        }
+        if self.profile_tlb {
+            self.tlb_counters.read_hits += 1;
+        }
+        Some(value)
+    }
+
+    /// Attempts to perform an unaligned write using the TLB (see [Mmu::read_unaligned_cached]),
+    /// returning whether the write was completed.
+    ///
+    /// Note: if the write crosses a page boundary, the first part may be written even if the write
+    /// is not completed. This is not observable, since the same bytes are written again when the
+    /// write is retried one byte at a time.
+    #[inline]
+    fn write_unaligned_cached(&mut self, addr: u64, value: &[u8], perm: u8) -> bool {
+        let split = value.len().min(physical::PAGE_SIZE - PageData::offset(addr));
+        let (first, second) = value.split_at(split);
+        let next = addr.wrapping_add(split as u64) & self.address_mask;
+        // Safety: the TLB only contains pages that are valid.
+        let written = unsafe {
+            self.tlb.write_slice(addr, first, perm).is_ok()
+                && (second.is_empty() || self.tlb.write_slice(next, second, perm).is_ok())
+        };
+        if written && self.profile_tlb {
+            self.tlb_counters.write_hits += 1;
+        }
+        written
+    }
+
+    /// Handles an aligned access of `len` bytes at `addr` that failed because it straddles the
+    /// boundary between mappings that are not backed by the same physical page. If every mapping
+    /// that overlaps the access can be backed by physical memory, the mappings of the page are
+    /// merged into a single physical page (see [Mmu::init_physical]), which can be cached in the
+    /// TLB with the permissions of each byte enforcing the boundaries between the mappings.
+    ///
+    /// Returns the index of the merged page, or `None` if the access must be split.
+    fn merge_straddled_page(
+        &mut self,
+        addr: u64,
+        len: usize,
+        is_write: bool,
+    ) -> Option<physical::Index> {
+        let end = addr + (len as u64 - 1);
+        let mut indices = vec![];
+        for (_, _, entry) in self.mapping.overlapping_iter(addr..=end) {
+            match entry? {
+                MemoryMapping::Physical(entry) => {
+                    // Pages that are shared with the code cache or other mappings are not copied.
+                    let page = self.physical.get(entry.index);
+                    if page.executed || page.aliased {
+                        return None;
+                    }
+                    indices.push(Some(entry.index));
+                }
+                MemoryMapping::Unallocated(_) | MemoryMapping::File(_) => indices.push(None),
+                MemoryMapping::Io(_) | MemoryMapping::Reserved(_) => return None,
+            }
+        }
+        // If every byte is already backed by the same page, then the access failed because of
+        // the permissions of the bytes.
+        if indices.iter().all(|index| index.is_some() && *index == indices[0]) {
+            return None;
+        }
+        tracing::trace!(
+            "merging mappings of page {:#x} for access at {addr:#x}",
+            self.page_aligned(addr)
+        );
+        self.init_physical(addr, is_write)?;
+        self.physical_backing(addr, end)
+    }
+
+    /// Returns whether `addr..addr+len` wraps around the end of the masked address space (see
+    /// [Mmu::set_address_mask]).
+    fn wraps_address_mask(&self, addr: u64, len: usize) -> bool {
+        self.address_mask != u64::MAX
+            && (addr & self.address_mask) + (len as u64 - 1) > self.address_mask
+    }
+
+    /// Returns whether every byte in `addr..addr+len` is mapped by a page in the TLB. I/O regions
+    /// are never stored in the TLB, so this allows us to avoid checking the mapping for I/O
+    /// regions in the common case.
+    fn is_tlb_cached(&self, addr: u64, len: usize, is_write: bool) -> bool {
+        (0..len as u64).all(|i| {
+            let addr = addr.wrapping_add(i);
+            let page = match is_write {
+                true => self.tlb.translate_write(addr),
+                false => self.tlb.translate_read(addr),
+            };
+            // Safety: pages in the TLB are always valid.
+            page.is_some_and(|page| unsafe {
+                page.ptr.as_ref().perm[PageData::offset(addr)] & perm::MAP != 0
+            })
+        })
+    }
+
+    /// Returns whether any part of `addr..addr+len` is mapped to an I/O region.
+    fn overlaps_io(&self, addr: u64, len: usize) -> bool {
+        let Some(end) = addr.checked_add(len as u64 - 1)
+        else {
+            return false;
+        };
+        self.mapping
+            .overlapping_iter(addr..=end)
+            .any(|(_, _, entry)| matches!(entry, Some(MemoryMapping::Io(_))))
+    }
+
+    /// Splits `addr..addr+len` into the regions that should be accessed separately, returning the
+    /// offset and length of each region along with the I/O mapping (if any) of the region.
+    fn split_at_io_boundaries(
+        &self,
+        addr: u64,
+        len: usize,
+    ) -> MemResult<Vec<(usize, usize, Option<IoMapping>)>> {
+        let end = addr.checked_add(len as u64 - 1).ok_or(MemError::AddressOverflow)?;
+
+        let mut regions = vec![];
+        for (start, len, entry) in self.mapping.overlapping_iter(addr..=end) {
+            let (offset, len) = ((start - addr) as usize, len as usize);
+            match entry.ok_or(MemError::Unmapped)? {
+                MemoryMapping::Io(io) => regions.push((offset, len, Some(*io))),
+                MemoryMapping::Reserved(_) => return Err(MemError::Unmapped),
+                _ => regions.push((offset, len, None)),
+            }
+        }
+        regions.reverse();
 
-            let page_end = page_start + (page_size - 1);
-            self.mapping.overlapping_mut(page_start..=page_end, |_start, _end, entry| {
-                if let Some(mapping @ MemoryMapping::Physical(_)) = entry {
-                    *mapping = MemoryMapping::Physical(copy_mapping);
+        if regions.len() > 1 && self.fault_on_io_boundary {
+            return Err(MemError::CrossesDeviceBoundary);
+        }
+        Ok(regions)
+    }
+
+    /// Reads from the I/O region `io` after checking that it has the permissions in `perm`,
+    /// recording the access if tracing is enabled for the handler.
+    fn io_read(&mut self, io: IoMapping, addr: u64, buf: &mut [u8], perm: u8) -> MemResult<()> {
+        perm::check(io.perm | perm::MAP | perm::INIT, perm)?;
+        let (id, kind) = (io.handler, read_kind(perm));
+        if self.io_replay.is_replaying() {
+            self.io_replay.replay(id, addr, buf)?;
+        }
+        else {
+            let access = IoAccess { addr, size: buf.len(), kind, context: self.io_context };
+            get_io(&mut self.io, id)?.read_access(&access, buf)?;
+            if self.io_replay.is_active() {
+                self.io_replay.record(id, addr, buf);
+            }
+        }
+        if self.io_trace.is_enabled(id) {
+            self.io_trace.record(id, addr, buf, false);
+        }
+        Ok(())
+    }
+
+    /// Writes to the I/O region `io` after checking that it has the permissions in `perm`,
+    /// recording the access if tracing is enabled for the handler.
+    fn io_write(&mut self, io: IoMapping, addr: u64, value: &[u8], perm: u8) -> MemResult<()> {
+        perm::check(io.perm | perm::MAP | perm::INIT, perm)?;
+        let id = io.handler;
+        if !self.discards_io_writes() {
+            let access = IoAccess {
+                addr,
+                size: value.len(),
+                kind: AccessKind::Write,
+                context: self.io_context,
+            };
+            get_io(&mut self.io, id)?.write_access(&access, value)?;
+        }
+        if self.io_trace.is_enabled(id) {
+            self.io_trace.record(id, addr, value, true);
+        }
+        Ok(())
+    }
+
+    /// Reads from a region of memory that overlaps with an I/O region.
+    ///
+    /// If the access spans a boundary between an I/O region and another region, it is split at the
+    /// boundary: bytes outside of the I/O region are read from memory one at a time, while the I/O
+    /// handler receives a single access covering all bytes that are inside of the I/O region. If
+    /// [Mmu::fault_on_io_boundary] is set, then the access fails instead.
+    #[cold]
+    fn read_split<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
+        let mut value = [0; N];
+        for (offset, len, io) in self.split_at_io_boundaries(addr, N)? {
+            let start = addr + offset as u64;
+            let buf = &mut value[offset..offset + len];
+            match io {
+                Some(io) => self.io_read(io, start, buf, perm)?,
+                None => {
+                    for (i, byte) in buf.iter_mut().enumerate() {
+                        *byte = self.read_unreported::<1>(start + i as u64, perm)?[0];
+                    }
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    /// Writes to a region of memory that overlaps with an I/O region (see [Mmu::read_split]).
+    ///
+    /// Permissions for the part of the write outside of the I/O region are checked before the I/O
+    /// handler is invoked, and the memory is only modified if the I/O write succeeds.
+    #[cold]
+    fn write_split<const N: usize>(
+        &mut self,
+        addr: u64,
+        value: [u8; N],
+        perm: u8,
+    ) -> MemResult<()> {
+        let regions = self.split_at_io_boundaries(addr, N)?;
+
+        for &(offset, len, io) in &regions {
+            match io {
+                Some(io) => perm::check(io.perm | perm::MAP | perm::INIT, perm)?,
+                None => {
+                    for i in offset..offset + len {
+                        let byte_perm = self.get_perm(addr + i as u64);
+                        if perm::is_guard(byte_perm) {
+                            return Err(MemError::GuardPage);
+                        }
+                        perm::check(byte_perm | perm::MAP, perm)?;
+                    }
+                }
+            }
+        }
+
+        for &(offset, len, io) in &regions {
+            if let Some(io) = io {
+                self.io_write(io, addr + offset as u64, &value[offset..offset + len], perm)?;
+            }
+        }
+
+        for &(offset, len, io) in &regions {
+            if io.is_none() {
+                for (i, &byte) in value.iter().enumerate().skip(offset).take(len) {
+                    let byte_addr = addr + i as u64;
+                    if let Err(e) = self.write_unreported(byte_addr, [byte], perm) {
+                        tracing::warn!(
+                            "I/O write at {addr:#x} completed, but {byte_addr:#x} failed: {e}"
+                        );
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cold]
+    pub fn read_tlb_miss<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
+        self.check_alignment::<N>(addr)?;
+        if perm != perm::NONE && self.watchpoints.should_check(addr, N, read_kind(perm)) {
+            return self.read_watched(addr, perm);
+        }
+        if !physical::is_aligned::<N>(addr) {
+            return self.read_unaligned(addr, perm);
+        }
+
+        self.record_tlb_miss(addr, false);
+        if self.translates_page_tables() {
+            return self.read_translated(addr, perm);
+        }
+
+        // The bytes of the value provided by hooks (and a mask of the bytes that were provided),
+        // and the hooks that modify the value after it is read.
+        let mut partial: Option<([u8; N], u64)> = None;
+        let mut modify_hooks = vec![];
+        if perm != perm::NONE && self.memory_hooks && !self.read_hooks.hooks.is_empty() {
+            let mut hooks = std::mem::take(&mut self.read_hooks.hooks);
+            for (i, hook) in hooks.iter_mut().enumerate() {
+                let contains = hook.overlaps_access(addr, N);
+                if let Some(handler) = hook.handler.as_mut() {
+                    if contains {
+                        match handler.read_partial(self, addr, N as u8) {
+                            ReadHookResult::Memory => {}
+                            ReadHookResult::Value(result) => {
+                                let mut buf = [0; N];
+                                buf.copy_from_slice(&result.to_le_bytes()[..N]);
+                                self.read_hooks.hooks = hooks;
+                                return Ok(buf);
+                            }
+                            ReadHookResult::Partial { value, mask } => {
+                                let (bytes, provided) = partial.get_or_insert(([0; N], 0));
+                                for (j, byte) in value.to_le_bytes().into_iter().enumerate() {
+                                    if j < N && mask & (1 << j) != 0 {
+                                        bytes[j] = byte;
+                                        *provided |= 1 << j;
+                                    }
+                                }
+                            }
+                            ReadHookResult::Modify => modify_hooks.push(i),
+                        }
+                    }
+                }
+            }
+            debug_assert!(self.read_hooks.hooks.is_empty());
+            self.read_hooks.hooks = hooks;
+        }
+        if partial.is_some() || !modify_hooks.is_empty() {
+            return self.read_hooked(addr, perm, partial, &modify_hooks);
+        }
+
+        // Note: errors from I/O handlers are returned immediately, instead of being retried as a
+        // sequence of smaller accesses (see `IoMemory`).
+        macro_rules! handle_io {
+            ($io:expr) => {{
+                let mut buf = [0; N];
+                self.io_read($io, addr, &mut buf, perm)?;
+                Ok(buf)
+            }};
+        }
+
+        let last = addr + (N as u64 - 1);
+        let result = match self.last_io_handler.as_ref() {
+            Some(&(start, end, io)) if start <= addr && last <= end => {
+                handle_io!(io)
+            }
+            _ => {
+                tracing::trace!("read_tlb_miss: {:#0x}", self.page_aligned(addr));
+                match self.mapping.get_with_range(addr).ok_or(MemError::Unmapped)? {
+                    (_, _, MemoryMapping::Physical(entry)) => {
+                        self.read_physical(entry.index, addr, perm)
+                    }
+                    (_, _, &MemoryMapping::Unallocated(entry)) => {
+                        perm::check(entry.perm | perm::MAP, perm)?;
+                        let index = self.init_physical(addr, false).ok_or(MemError::OutOfMemory)?;
+                        self.read_physical(index, addr, perm)
+                    }
+                    (_, _, &MemoryMapping::File(entry)) => {
+                        perm::check(entry.perm | perm::MAP, perm)?;
+                        let index = self.init_physical(addr, false).ok_or(MemError::OutOfMemory)?;
+                        self.read_physical(index, addr, perm)
+                    }
+                    (_, end, MemoryMapping::Io(_)) if last > end => {
+                        return self.read_split(addr, perm);
+                    }
+                    (start, end, &MemoryMapping::Io(io)) => {
+                        self.last_io_handler = Some((start, end, io));
+                        handle_io!(io)
+                    }
+                    (_, _, MemoryMapping::Reserved(_)) => Err(MemError::Reserved),
+                }
+            }
+        };
+
+        // Since we allow byte-level memory memory mapping to be created, rarely we may have a read
+        // that crosses a mapping boundary which will result in a `Unmapped` error. To handle this
+        // case merge the mappings into a single page if possible, otherwise try again using
+        // `read_unaligned` which will read one byte at a time.
+        let result = match result {
+            Err(MemError::Unmapped) if N != 1 => match self.merge_straddled_page(addr, N, false) {
+                Some(index) => self.read_physical(index, addr, perm),
+                None => return self.read_unaligned(addr, perm),
+            },
+            result => result,
+        };
+
+        if let Ok(value) = result {
+            if perm != perm::NONE && self.memory_hooks {
+                active_hooks!(addr, N, self.read_after_hooks, |hook: &mut dyn ReadAfterHook| {
+                    hook.read(self, addr, &value)
+                })
+            }
+        }
+
+        result
+    }
+
+    /// Completes a read where some of the value was provided by read hooks (`partial`) or where
+    /// hooks modify the value read from memory (`modify_hooks`).
+    #[cold]
+    fn read_hooked<const N: usize>(
+        &mut self,
+        addr: u64,
+        perm: u8,
+        partial: Option<([u8; N], u64)>,
+        modify_hooks: &[usize],
+    ) -> MemResult<[u8; N]> {
+        let (mut value, provided) = partial.unwrap_or(([0; N], 0));
+        if provided.count_ones() as usize != N {
+            // Initialization is only checked for the bytes that are read from memory.
+            let mem_perm = if provided != 0 { perm & !perm::INIT } else { perm };
+            let memory = self.without_hooks(|mmu| mmu.read_tlb_miss::<N>(addr, mem_perm))?;
+            for (i, byte) in memory.into_iter().enumerate() {
+                if provided & (1 << i) != 0 {
+                    continue;
                 }
+                if mem_perm != perm && self.is_initialized(addr + i as u64) == Some(false) {
+                    return Err(MemError::Uninitalized);
+                }
+                value[i] = byte;
+            }
+        }
+
+        if !modify_hooks.is_empty() {
+            let mut hooks = std::mem::take(&mut self.read_hooks.hooks);
+            for &i in modify_hooks {
+                if let Some(handler) = hooks[i].handler.as_mut() {
+                    handler.modify(self, addr, &mut value);
+                }
+            }
+            debug_assert!(self.read_hooks.hooks.is_empty());
+            self.read_hooks.hooks = hooks;
+        }
+
+        active_hooks!(addr, N, self.read_after_hooks, |hook: &mut dyn ReadAfterHook| {
+            hook.read(self, addr, &value)
+        });
+        Ok(value)
+    }
+
+    #[cold]
+    pub fn write_tlb_miss<const N: usize>(
+        &mut self,
+        addr: u64,
+        value: [u8; N],
+        perm: u8,
+    ) -> MemResult<()> {
+        self.check_alignment::<N>(addr)?;
+        if perm != perm::NONE && self.watchpoints.should_check(addr, N, AccessKind::Write) {
+            return self.write_watched(addr, value, perm);
+        }
+        if !physical::is_aligned::<N>(addr) {
+            return self.write_unaligned(addr, value, perm);
+        }
+
+        tracing::trace!("write_tlb_miss: {:#0x}", self.page_aligned(addr));
+        self.record_tlb_miss(addr, true);
+        if self.translates_page_tables() {
+            return self.write_translated(addr, value, perm);
+        }
+
+        let result = match self.mapping.get_with_range(addr).ok_or(MemError::Unmapped)? {
+            (_, _, MemoryMapping::Physical(entry)) => {
+                self.write_physical(entry.index, addr, value, perm)
+            }
+            (_, _, &MemoryMapping::Unallocated(entry)) => {
+                perm::check(entry.perm | perm::MAP, perm)?;
+                let index = self.init_physical(addr, true).ok_or(MemError::OutOfMemory)?;
+                self.write_physical(index, addr, value, perm)
+            }
+            (_, _, &MemoryMapping::File(entry)) => {
+                perm::check(entry.perm | perm::MAP, perm)?;
+                let index = self.init_physical(addr, true).ok_or(MemError::OutOfMemory)?;
+                self.write_physical(index, addr, value, perm)
+            }
+            (_, end, MemoryMapping::Io(_)) if addr + (N as u64 - 1) > end => {
+                return self.write_split(addr, value, perm);
+            }
+            (_, _, &MemoryMapping::Io(io)) => {
+                // Errors from I/O handlers are returned without being retried (see
+                // `read_tlb_miss`).
+                self.io_write(io, addr, &value, perm)?;
                 Ok(())
-            })?;
+            }
+            (_, _, MemoryMapping::Reserved(_)) => Err(MemError::Reserved),
+        };
 
-            page = self.physical.get_mut(copy_index);
+        // Handle case where we are writing across a mapping boundary (see `read_tlb_miss`).
+        let result = match result {
+            Err(MemError::Unmapped) if N != 1 => match self.merge_straddled_page(addr, N, true) {
+                Some(index) => self.write_physical(index, addr, value, perm),
+                None => return self.write_unaligned(addr, value, perm),
+            },
+            result => result,
+        };
+
+        if perm != perm::NONE && self.memory_hooks {
+            active_hooks!(addr, N, self.write_hooks, |hook: &mut dyn WriteHook| {
+                hook.write(self, addr, &value)
+            })
         }
 
-        // `data_mut` may cause a new copy of page to be created, so invalidate the read entry for
-        // the TLB cache.
-        self.tlb.remove_read(page_start);
+        result
+    }
 
-        // @todo: check the overhead of this hash operation.
+    /// Performs a read that overlaps with a watchpoint, recording a hit for each watchpoint that
+    /// the read touches. Watchpoints are suspended during the read, so that the read is reported
+    /// once even if it is split into smaller accesses.
+    #[cold]
+    #[inline(never)]
+    fn read_watched<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
+        self.watchpoints.suspended = true;
+        let result = self.read_tlb_miss(addr, perm);
+        self.watchpoints.suspended = false;
+        if let Ok(value) = &result {
+            self.watchpoints.record(AccessKind::Read, addr, Some(value), value);
+        }
+        result
+    }
 
-        if !page.modified {
-            self.modified.insert(page_start);
+    /// Performs a write that overlaps with a watchpoint (see [Mmu::read_watched]).
+    #[cold]
+    #[inline(never)]
+    fn write_watched<const N: usize>(
+        &mut self,
+        addr: u64,
+        value: [u8; N],
+        perm: u8,
+    ) -> MemResult<()> {
+        let mut old = [0; N];
+        let old = self.peek_bytes(addr, &mut old).ok().map(|_| old);
+
+        self.watchpoints.suspended = true;
+        let result = self.write_tlb_miss(addr, value, perm);
+        self.watchpoints.suspended = false;
+        if result.is_ok() {
+            self.watchpoints.record(AccessKind::Write, addr, old.as_ref().map(|x| &x[..]), &value);
         }
-        page.modified = true;
-        page.data_mut().write(addr, value, perm)?;
+        result
+    }
 
-        let uncachable = self.write_hooks.contains_address(addr, page_size);
-        if !uncachable {
-            // Safety: `page.data_mut()` ensures the page is a unique copy of the underlying data.
-            self.tlb.insert_write(page_start, unsafe { page.write_ptr() });
+    /// Get a reference to the virtual address space's mapping.
+    pub fn get_mapping(&self) -> &VirtualMemoryMap {
+        &self.mapping
+    }
+
+    /// Get a mutable reference to the virtual address space's mapping.
+    pub fn get_mapping_mut(&mut self) -> &mut VirtualMemoryMap {
+        &mut self.mapping
+    }
+
+    #[inline(always)]
+    pub fn read<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
+        let addr = addr & self.address_mask;
+        let result = match self.read_unreported(addr, perm) {
+            Err(MemError::Uninitalized) if self.uninit_diagnostics => {
+                self.report_uninit_read(addr, N);
+                Err(MemError::Uninitalized)
+            }
+            // Note: retried accesses are recorded in the access log by the nested call.
+            Err(MemError::GuardPage) => return self.read_guarded(addr, perm),
+            x => x,
+        };
+        if self.record_accesses && perm != perm::NONE {
+            let value = result.unwrap_or([0; N]);
+            self.record_access(read_kind(perm), addr, &value, result.err());
+        }
+        result
+    }
+
+    /// Handles a read that touched a guarded byte, retrying the read if the fault was resolved by
+    /// the guard handler.
+    #[cold]
+    #[inline(never)]
+    fn read_guarded<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
+        let kind = read_kind(perm);
+        match self.handle_guard_fault(addr, N, kind) {
+            true => self.read(addr, perm),
+            false => {
+                if self.record_accesses && perm != perm::NONE {
+                    self.record_access(kind, addr, &[0; N], Some(MemError::GuardPage));
+                }
+                Err(MemError::GuardPage)
+            }
         }
+    }
 
-        Ok(())
+    /// Handles a write that touched a guarded byte (see [Mmu::read_guarded]).
+    #[cold]
+    #[inline(never)]
+    fn write_guarded<const N: usize>(
+        &mut self,
+        addr: u64,
+        value: [u8; N],
+        perm: u8,
+    ) -> MemResult<()> {
+        match self.handle_guard_fault(addr, N, AccessKind::Write) {
+            true => self.write(addr, value, perm),
+            false => {
+                if self.record_accesses && perm != perm::NONE {
+                    self.record_access(AccessKind::Write, addr, &value, Some(MemError::GuardPage));
+                }
+                Err(MemError::GuardPage)
+            }
+        }
+    }
+
+    /// Notifies the guard handler about an access of `size` bytes at `addr` that touched a guarded
+    /// byte, returning whether the access should be retried. If the fault is not resolved, it is
+    /// recorded so it can be retrieved with [Mmu::take_guard_fault].
+    fn handle_guard_fault(&mut self, addr: u64, size: usize, kind: AccessKind) -> bool {
+        let fault_addr = (0..size as u64)
+            .map(|i| addr.wrapping_add(i) & self.address_mask)
+            .find(|addr| perm::is_guard(self.get_perm(*addr)))
+            .unwrap_or(addr);
+        let tags = self.tags.at(fault_addr).cloned().collect();
+        let fault = GuardFault { addr: fault_addr, kind, tags };
+        tracing::debug!("guard page accessed: {fault:x?}");
+
+        if let Some(mut handler) = self.guard_handler.take() {
+            let retry = handler(self, &fault);
+            self.guard_handler.get_or_insert(handler);
+            // Only retry if the guard was removed, otherwise the access would fault again.
+            if retry && !perm::is_guard(self.get_perm(fault.addr)) {
+                return true;
+            }
+        }
+        self.guard_fault = Some(fault);
+        false
+    }
+
+    /// Takes the details of the most recent access that failed because it touched a guarded byte.
+    pub fn take_guard_fault(&mut self) -> Option<GuardFault> {
+        self.guard_fault.take()
+    }
+
+    /// Sets a function that is called whenever an access touches a guarded byte. The handler may
+    /// update the permissions of the guarded region (e.g. to grow a stack) and return `true` to
+    /// retry the access.
+    pub fn set_guard_handler(&mut self, handler: GuardHandler) {
+        self.guard_handler = Some(handler);
+    }
+
+    /// Equivalent to [Mmu::read] without recording diagnostics for uninitialized reads or notifying
+    /// the guard handler.
+    #[inline(always)]
+    fn read_unreported<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
+        match unsafe { self.tlb.read(addr, perm) } {
+            Err(MemError::Unmapped) => self.read_tlb_miss(addr, perm),
+            Err(MemError::Unaligned) if N != 1 => self.read_unaligned(addr, perm),
+            x => {
+                if self.profile_tlb {
+                    self.tlb_counters.read_hits += 1;
+                }
+                x
+            }
+        }
+    }
+
+    /// Reads `N` bytes from `addr` checking all permissions in `perm` except for [perm::INIT],
+    /// returning the bytes and a mask where bit `i` is set if byte `i` is initialized.
+    ///
+    /// This is intended for loads where some of the bytes may be discarded (e.g. vector loads that
+    /// are later masked), allowing the caller to only check the bytes it uses. The TLB only caches
+    /// translations (permissions are always checked against the page), so relaxed reads do not
+    /// affect the checks performed by strict reads.
+    #[inline(always)]
+    pub fn read_allow_uninit<const N: usize>(
+        &mut self,
+        addr: u64,
+        perm: u8,
+    ) -> MemResult<([u8; N], u64)> {
+        let addr = addr & self.address_mask;
+        let perm = perm & !perm::INIT;
+        match unsafe { self.tlb.read_allow_uninit(addr, perm) } {
+            Err(MemError::Unmapped | MemError::Unaligned) => {
+                let value = self.read::<N>(addr, perm)?;
+                Ok((value, self.init_mask(addr, N)))
+            }
+            x => {
+                if self.profile_tlb {
+                    self.tlb_counters.read_hits += 1;
+                }
+                x
+            }
+        }
+    }
+
+    /// Enables the shadow plane: a label for every byte of memory that is stored alongside the data
+    /// (see [Mmu::read_with_shadow] and [Mmu::write_with_shadow]). Before this is called, labels
+    /// are ignored and always read as zero.
+    ///
+    /// Labels default to zero and storage is only allocated for pages that contain a non-zero
+    /// label. Labels are captured by snapshots and are preserved when a page is copied.
+    ///
+    /// Note: writes that are not performed using [Mmu::write_with_shadow] do not modify labels.
+    pub fn enable_shadow(&mut self) {
+        self.shadow_enabled = true;
     }
 
-    #[cold]
-    fn read_unaligned<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
-        let mut value = [0; N];
-        for (i, byte) in value.iter_mut().enumerate() {
-            *byte = self.read_u8(addr + i as u64, perm)?;
+    /// Reads `N` bytes from `addr` checking that the permissions specified by `perm` are set,
+    /// returning the bytes along with their labels.
+    pub fn read_with_shadow<const N: usize>(
+        &mut self,
+        addr: u64,
+        perm: u8,
+    ) -> MemResult<([u8; N], [u8; N])> {
+        let value = self.read::<N>(addr, perm)?;
+        let mut labels = [0; N];
+        if self.shadow_enabled {
+            for (i, label) in labels.iter_mut().enumerate() {
+                *label = self.get_shadow(addr.wrapping_add(i as u64));
+            }
         }
-        Ok(value)
+        Ok((value, labels))
     }
 
-    #[cold]
-    fn write_unaligned<const N: usize>(
+    /// Writes `value` to `addr` checking that the permissions specified by `perm` are set, then
+    /// sets the labels of the written bytes to `labels`.
+    pub fn write_with_shadow<const N: usize>(
         &mut self,
         addr: u64,
         value: [u8; N],
+        labels: [u8; N],
         perm: u8,
     ) -> MemResult<()> {
-        for (i, &byte) in value.iter().enumerate() {
-            self.write_u8(addr + i as u64, byte, perm)?;
+        self.write(addr, value, perm)?;
+        for (i, label) in labels.into_iter().enumerate() {
+            self.set_shadow_range(addr.wrapping_add(i as u64), 1, label)?;
         }
         Ok(())
     }
 
-    #[cold]
-    pub fn read_tlb_miss<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
-        if !physical::is_aligned::<N>(addr) {
-            return self.read_unaligned(addr, perm);
+    /// Sets the label of the `len` bytes starting at `start` to `label`. Labels are not stored for
+    /// I/O regions.
+    pub fn set_shadow_range(&mut self, start: u64, len: u64, label: u8) -> MemResult<()> {
+        if len == 0 || !self.shadow_enabled {
+            return Ok(());
+        }
+        let end = start.checked_add(len - 1).ok_or(MemError::AddressOverflow)?;
+        if self.mapping.overlapping_iter(start..=end).any(|(_, _, entry)| entry.is_none()) {
+            return Err(MemError::Unmapped);
         }
 
-        if perm != perm::NONE && ENABLE_MEMORY_HOOKS && !self.read_hooks.hooks.is_empty() {
-            let mut hooks = std::mem::take(&mut self.read_hooks.hooks);
-            for hook in &mut hooks {
-                if let Some(handler) = hook.handler.as_mut() {
-                    if hook.start <= addr && addr < hook.end {
-                        if let Some(result) = handler.read(self, addr, N as u8) {
-                            let mut buf = [0; N];
-                            buf.copy_from_slice(&result.to_le_bytes()[..N]);
-                            self.read_hooks.hooks = hooks;
-                            return Ok(buf);
-                        }
-                    }
+        let mut addr = start;
+        loop {
+            let (_, entry_end, entry) = self.mapping.get_with_range(addr).unwrap();
+            let chunk_end = entry_end.min(end).min(self.page_aligned(addr) + physical::PAGE_MASK);
+
+            // Avoid allocating memory for pages that do not have any labels.
+            let skip = match entry {
+                MemoryMapping::Physical(entry) => {
+                    label == 0 && !self.physical.get(entry.index).has_shadow()
                 }
+                MemoryMapping::Unallocated(_) | MemoryMapping::File(_) => label == 0,
+                MemoryMapping::Io(_) | MemoryMapping::Reserved(_) => true,
+            };
+            if !skip {
+                let index = self.get_unique_physical(addr)?;
+                let len = (chunk_end - addr) as usize + 1;
+                self.physical.get_mut(index).fill_shadow(PageData::offset(addr), len, label);
             }
-            debug_assert!(self.read_hooks.hooks.is_empty());
-            self.read_hooks.hooks = hooks;
+
+            if chunk_end == end {
+                return Ok(());
+            }
+            addr = chunk_end + 1;
         }
+    }
 
-        macro_rules! handle_io {
-            ($id:expr) => {
-                (|| {
-                    let mut buf = [0; N];
-                    self.io[$id].read(addr, &mut buf)?;
-                    Ok(buf)
-                })()
-            };
+    /// Returns the label of the byte at `addr`.
+    fn get_shadow(&self, addr: u64) -> u8 {
+        let mut label = [0];
+        if let Some(MemoryMapping::Physical(entry)) = self.mapping.get(addr) {
+            self.physical.get(entry.index).read_shadow(PageData::offset(addr), &mut label);
         }
+        label[0]
+    }
 
-        let result = match self.last_io_handler.as_ref() {
-            Some((start, end, id)) if (*start..=*end).contains(&addr) => {
-                handle_io!(id.0)
+    /// Returns a mask where bit `i` is set if the byte at `addr + i` is initialized.
+    #[cold]
+    fn init_mask(&self, addr: u64, len: usize) -> u64 {
+        let mut mask = 0;
+        for i in 0..len {
+            let addr = addr.wrapping_add(i as u64) & self.address_mask;
+            let init = self.is_initialized(addr).unwrap_or(false);
+            mask |= (init as u64) << i;
+        }
+        mask
+    }
+
+    /// Returns whether the byte at `addr` is initialized, or `None` if `addr` is not mapped.
+    fn is_initialized(&self, addr: u64) -> Option<bool> {
+        Some(match self.mapping.get(addr)? {
+            MemoryMapping::Physical(entry) => {
+                let perm = self.physical.get(entry.index).data().perm[PageData::offset(addr)];
+                perm & perm::INIT != 0
             }
-            _ => {
-                tracing::trace!("read_tlb_miss: {:#0x}", self.page_aligned(addr));
-                self.tlb_miss_count += 1;
-                match self.mapping.get_with_range(addr).ok_or(MemError::Unmapped)? {
-                    (_, _, MemoryMapping::Physical(entry)) => {
-                        self.read_physical(entry.index, addr, perm)
-                    }
-                    (_, _, &MemoryMapping::Unallocated(entry)) => {
-                        perm::check(entry.perm | perm::MAP, perm)?;
-                        let index = self.init_physical(addr, false).ok_or(MemError::OutOfMemory)?;
-                        self.read_physical(index, addr, perm)
+            MemoryMapping::Unallocated(entry) => self.is_unallocated_initialized(entry),
+            MemoryMapping::Io(_) | MemoryMapping::File(_) => true,
+            MemoryMapping::Reserved(_) => return None,
+        })
+    }
+
+    /// Returns whether the bytes of an unallocated region are initialized.
+    fn is_unallocated_initialized(&self, entry: &UnallocatedMemory) -> bool {
+        !self.track_uninitialized || entry.perm & perm::INIT != 0
+    }
+
+    /// Returns the (inclusive) ranges of mapped memory within the `len` bytes starting at `start`
+    /// that are not initialized. Unmapped memory and I/O regions are never included. Ranges that
+    /// extend past the end of the address space are truncated.
+    ///
+    /// This is useful for checking whether a buffer has been fully populated, e.g. after a system
+    /// call has written to it.
+    pub fn uninit_ranges(&self, start: u64, len: u64) -> Vec<(u64, u64)> {
+        if len == 0 {
+            return vec![];
+        }
+        let end = start.saturating_add(len - 1);
+
+        let mut ranges = vec![];
+        for (start, len, entry) in self.mapping.overlapping_iter(start..=end) {
+            match entry {
+                Some(MemoryMapping::Unallocated(entry)) => {
+                    if !self.is_unallocated_initialized(entry) {
+                        ranges.push((start, start + (len - 1)));
                     }
-                    (start, end, MemoryMapping::Io(id)) => {
-                        self.last_io_handler = Some((start, end, IoHandler(*id)));
-                        handle_io!(*id)
+                }
+                Some(MemoryMapping::Physical(entry)) => {
+                    let page = self.physical.get(entry.index).data();
+                    let offset = PageData::offset(start);
+                    let perm = &page.perm[offset..offset + len as usize];
+                    for (i, _) in perm.iter().enumerate().filter(|(_, p)| *p & perm::INIT == 0) {
+                        let addr = start + i as u64;
+                        ranges.push((addr, addr));
                     }
                 }
+                Some(
+                    MemoryMapping::Io(_) | MemoryMapping::File(_) | MemoryMapping::Reserved(_),
+                )
+                | None => {}
             }
-        };
-
-        // Since we allow byte-level memory memory mapping to be created, rarely we may have a read
-        // that crosses a mapping boundary which will result in a `Unmapped` error. To handle this
-        // case try again using `read_unaligned` which will read one byte at a time.
-        if N != 1 && result == Err(MemError::Unmapped) {
-            return self.read_unaligned(addr, perm);
         }
 
-        if let Ok(value) = result {
-            if perm != perm::NONE && ENABLE_MEMORY_HOOKS {
-                active_hooks!(addr, self.read_after_hooks, |hook: &mut dyn ReadAfterHook| {
-                    hook.read(self, addr, &value)
-                })
+        // Merge adjacent ranges.
+        ranges.sort_unstable();
+        ranges.dedup_by(|next, prev| {
+            let adjacent = prev.1.checked_add(1) == Some(next.0);
+            if adjacent {
+                prev.1 = next.1;
             }
+            adjacent
+        });
+        ranges
+    }
+
+    /// Records diagnostics for a read of `size` bytes at `addr` that failed because some of the
+    /// bytes were uninitialized.
+    #[cold]
+    #[inline(never)]
+    fn report_uninit_read(&mut self, addr: u64, size: usize) {
+        if self.uninit_report.is_some() {
+            return;
+        }
+
+        let bytes = (0..size as u64).map(|i| addr.wrapping_add(i) & self.address_mask);
+        let perm_bits = bytes.clone().map(|addr| self.get_perm(addr)).collect();
+        let first_uninit = bytes.into_iter().find(|addr| self.is_initialized(*addr) == Some(false));
+        let tags = self.tags.at(first_uninit.unwrap_or(addr)).cloned().collect();
+        let page_mapped_at = first_uninit.and_then(|addr| match self.mapping.get(addr)? {
+            MemoryMapping::Physical(entry) => Some(entry.addr),
+            _ => None,
+        });
+
+        let report = UninitReport { addr, size, page_mapped_at, perm_bits, tags };
+        tracing::debug!("uninitialized read: {report:x?}");
+        if let Some(mut handler) = self.uninit_handler.take() {
+            handler(self, &report);
+            self.uninit_handler.get_or_insert(handler);
         }
+        self.uninit_report = Some(report);
+    }
+
+    /// Takes the report recorded for the first read of uninitialized memory since the last call to
+    /// this function (requires [Mmu::uninit_diagnostics] to be enabled).
+    pub fn take_uninit_report(&mut self) -> Option<UninitReport> {
+        self.uninit_report.take()
+    }
 
+    /// Sets a function that is called (with a report of the access) whenever a report is recorded
+    /// for a read of uninitialized memory.
+    pub fn set_uninit_handler(&mut self, handler: UninitHandler) {
+        self.uninit_handler = Some(handler);
+    }
+
+    #[inline(always)]
+    pub fn write<const N: usize>(&mut self, addr: u64, value: [u8; N], perm: u8) -> MemResult<()> {
+        let addr = addr & self.address_mask;
+        let result = match self.write_unreported(addr, value, perm) {
+            Err(MemError::GuardPage) => return self.write_guarded(addr, value, perm),
+            x => x,
+        };
+        if self.record_accesses && perm != perm::NONE {
+            self.record_access(AccessKind::Write, addr, &value, result.err());
+        }
         result
     }
 
-    #[cold]
-    pub fn write_tlb_miss<const N: usize>(
+    /// Equivalent to [Mmu::write] without notifying the guard handler.
+    #[inline(always)]
+    fn write_unreported<const N: usize>(
         &mut self,
         addr: u64,
         value: [u8; N],
         perm: u8,
     ) -> MemResult<()> {
-        if !physical::is_aligned::<N>(addr) {
-            return self.write_unaligned(addr, value, perm);
+        match unsafe { self.tlb.write(addr, value, perm) } {
+            Err(MemError::Unmapped) => self.write_tlb_miss(addr, value, perm),
+            Err(MemError::Unaligned) if N != 1 => self.write_unaligned(addr, value, perm),
+            x => {
+                if self.profile_tlb {
+                    self.tlb_counters.write_hits += 1;
+                }
+                x
+            }
         }
+    }
 
-        tracing::trace!("write_tlb_miss: {:#0x}", self.page_aligned(addr));
-        self.tlb_miss_count += 1;
-        let result = match self.mapping.get(addr).ok_or(MemError::Unmapped)? {
-            MemoryMapping::Physical(entry) => self.write_physical(entry.index, addr, value, perm),
-            &MemoryMapping::Unallocated(entry) => {
-                perm::check(entry.perm | perm::MAP, perm)?;
-                let index = self.init_physical(addr, true).ok_or(MemError::OutOfMemory)?;
-                self.write_physical(index, addr, value, perm)
+    /// Replaces the `N` bytes at `addr` with `new` if they are equal to `expected`, returning
+    /// `Ok(old)` if the value was replaced or `Err(actual)` if it was not (see
+    /// [Mmu::fetch_update]). Write hooks are not called if the value is not replaced.
+    pub fn compare_exchange<const N: usize>(
+        &mut self,
+        addr: u64,
+        expected: [u8; N],
+        new: [u8; N],
+        perm: u8,
+    ) -> MemResult<Result<[u8; N], [u8; N]>> {
+        let old = self.read_modify_write(addr, perm, |old| (old == expected).then_some(new))?;
+        Ok(if old == expected { Ok(old) } else { Err(old) })
+    }
+
+    /// Replaces the `N` bytes at `addr` with the result of calling `f` with their current value,
+    /// returning the previous value.
+    ///
+    /// Unlike a separate read and write, the permissions required for both the read and the write
+    /// are checked before memory is accessed, read hooks are not called, and write hooks are called
+    /// once with the final value, so hooks never observe an intermediate state.
+    ///
+    /// Note: for I/O regions the operation is performed as a separate read and write to the
+    /// handler (calling hooks for both), so it is not atomic with respect to the device.
+    pub fn fetch_update<const N: usize>(
+        &mut self,
+        addr: u64,
+        perm: u8,
+        f: impl FnOnce([u8; N]) -> [u8; N],
+    ) -> MemResult<[u8; N]> {
+        self.read_modify_write(addr, perm, |old| Some(f(old)))
+    }
+
+    /// Reads the `N` bytes at `addr` and writes back the value returned by `f` (if any), see
+    /// [Mmu::fetch_update].
+    fn read_modify_write<const N: usize>(
+        &mut self,
+        addr: u64,
+        perm: u8,
+        f: impl FnOnce([u8; N]) -> Option<[u8; N]>,
+    ) -> MemResult<[u8; N]> {
+        let addr = addr & self.address_mask;
+        if self.overlaps_io(addr, N) {
+            let old = self.read(addr, perm)?;
+            if let Some(new) = f(old) {
+                self.write(addr, new, perm)?;
             }
-            MemoryMapping::Io(id) => self.io[*id].write(addr, &value),
+            return Ok(old);
+        }
+
+        // Checking the write permission as part of the read ensures that the write cannot fail
+        // because of permissions after the read has been performed.
+        let check = match perm {
+            perm::NONE => perm::NONE,
+            _ => perm | perm::WRITE,
         };
+        let result = self.without_hooks(|mmu| {
+            let old = mmu.read::<N>(addr, check)?;
+            let new = f(old);
+            if let Some(new) = new {
+                mmu.write(addr, new, check)?;
+            }
+            Ok((old, new))
+        });
 
-        // Handle case where we are writing across a mapping boundary (see `read_tlb_miss`).
-        if N != 1 && result == Err(MemError::Unmapped) {
-            return self.write_unaligned(addr, value, perm);
+        if perm != perm::NONE {
+            let (old, new) = match result {
+                Ok(x) => x,
+                Err(e) => {
+                    if self.record_accesses {
+                        self.record_access(AccessKind::Write, addr, &[0; N], Some(e));
+                    }
+                    return Err(e);
+                }
+            };
+            if self.watchpoints.should_check(addr, N, AccessKind::Read) {
+                self.watchpoints.record(AccessKind::Read, addr, Some(&old), &old);
+            }
+            if let Some(new) = new {
+                if self.watchpoints.should_check(addr, N, AccessKind::Write) {
+                    self.watchpoints.record(AccessKind::Write, addr, Some(&old), &new);
+                }
+                if self.record_accesses {
+                    self.record_access(AccessKind::Write, addr, &new, None);
+                }
+                if self.memory_hooks {
+                    active_hooks!(addr, N, self.write_hooks, |hook: &mut dyn WriteHook| {
+                        hook.write(self, addr, &new)
+                    })
+                }
+            }
         }
 
-        if perm != perm::NONE && ENABLE_MEMORY_HOOKS {
-            active_hooks!(addr, self.write_hooks, |hook: &mut dyn WriteHook| {
-                hook.write(self, addr, &value)
-            })
-        }
+        result.map(|(old, _)| old)
+    }
 
+    /// Runs `f` without calling memory hooks, checking watchpoints or recording accesses.
+    fn without_hooks<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let hooks = std::mem::replace(&mut self.memory_hooks, false);
+        let suspended = std::mem::replace(&mut self.watchpoints.suspended, true);
+        let record_accesses = std::mem::replace(&mut self.record_accesses, false);
+        let result = f(self);
+        self.memory_hooks = hooks;
+        self.watchpoints.suspended = suspended;
+        self.record_accesses = record_accesses;
         result
     }
 
-    /// Get a reference to the virtual address space's mapping.
-    pub fn get_mapping(&self) -> &VirtualMemoryMap {
-        &self.mapping
+    /// Reads the NUL-terminated string at `addr` into `buf` (excluding the terminator), returning
+    /// the address of the terminator.
+    ///
+    /// Fails with [MemError::LimitExceeded] if the string is longer than [Mmu::traversal_limit].
+    /// On failure, the bytes read before the error remain in `buf`.
+    pub fn read_cstr(&mut self, addr: u64, buf: &mut Vec<u8>) -> MemResult<u64> {
+        let mut addr = addr & self.address_mask;
+        let mut len = 0;
+        loop {
+            match self.read_u8(addr, perm::READ)? {
+                0 => break,
+                _ if len == self.traversal_limit => return Err(MemError::LimitExceeded),
+                x => buf.push(x),
+            }
+            len += 1;
+            addr = addr.wrapping_add(1) & self.address_mask;
+        }
+        Ok(addr)
     }
 
-    /// Get a mutable reference to the virtual address space's mapping.
-    pub fn get_mapping_mut(&mut self) -> &mut VirtualMemoryMap {
-        &mut self.mapping
+    /// Reads a guest pointer (of [Mmu::pointer_size] bytes, using [Mmu::endianness]) from `addr`,
+    /// returning it zero-extended to 64 bits.
+    pub fn read_ptr(&mut self, addr: u64, perm: u8) -> MemResult<u64> {
+        match self.pointer_size {
+            4 => self.read_u32(addr, perm).map(u64::from),
+            _ => self.read_u64(addr, perm),
+        }
     }
 
-    #[inline(always)]
-    pub fn read<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
-        match unsafe { self.tlb.read(addr, perm) } {
-            Err(MemError::Unmapped) => self.read_tlb_miss(addr, perm),
-            Err(MemError::Unaligned) if N != 1 => self.read_unaligned(addr, perm),
-            x => x,
+    /// Reads the NULL-terminated array of guest pointers (e.g. `argv` or `envp`) at `addr`,
+    /// returning the pointers before the terminator and where the array ended.
+    ///
+    /// At most `max_entries` pointers (limited by [Mmu::traversal_limit]) are read, excluding the
+    /// terminator.
+    pub fn read_ptr_array(&mut self, addr: u64, max_entries: usize) -> (Vec<u64>, TraversalEnd) {
+        let limit = max_entries.min(self.traversal_limit);
+        let mut entries = vec![];
+        let mut addr = addr;
+        loop {
+            match self.read_ptr(addr, perm::READ) {
+                Ok(0) => return (entries, TraversalEnd::Null),
+                Ok(_) if entries.len() == limit => return (entries, TraversalEnd::Limit),
+                Ok(ptr) => entries.push(ptr),
+                Err(error) => return (entries, TraversalEnd::Fault { addr, error }),
+            }
+            addr = addr.wrapping_add(self.pointer_size as u64);
         }
     }
 
-    #[inline(always)]
-    pub fn write<const N: usize>(&mut self, addr: u64, value: [u8; N], perm: u8) -> MemResult<()> {
-        match unsafe { self.tlb.write(addr, value, perm) } {
-            Err(MemError::Unmapped) => self.write_tlb_miss(addr, value, perm),
-            Err(MemError::Unaligned) if N != 1 => self.write_unaligned(addr, value, perm),
-            x => x,
+    /// Walks the singly linked list starting at `head`, where the pointer to the next node is
+    /// stored `next_offset` bytes from the start of each node, calling `visit` with the address of
+    /// each node. Returns where the traversal stopped.
+    ///
+    /// At most `max_nodes` nodes (limited by [Mmu::traversal_limit]) are visited. A node that is
+    /// reached a second time stops the traversal (before it is visited again).
+    pub fn follow_list(
+        &mut self,
+        head: u64,
+        next_offset: u64,
+        max_nodes: usize,
+        mut visit: impl FnMut(&mut Self, u64),
+    ) -> TraversalEnd {
+        let limit = max_nodes.min(self.traversal_limit);
+        let mut seen = HashSet::new();
+        let mut node = head;
+        while node != 0 {
+            if !seen.insert(node) {
+                return TraversalEnd::Cycle(node);
+            }
+            if seen.len() > limit {
+                return TraversalEnd::Limit;
+            }
+            visit(self, node);
+
+            let addr = node.wrapping_add(next_offset);
+            node = match self.read_ptr(addr, perm::READ) {
+                Ok(next) => next,
+                Err(error) => return TraversalEnd::Fault { addr, error },
+            };
         }
+        TraversalEnd::Null
     }
+}
 
-    pub fn read_cstr(&mut self, mut addr: u64, buf: &mut Vec<u8>) -> MemResult<u64> {
-        loop {
-            match self.read_u8(addr, perm::READ)? {
-                0 => break,
-                x => buf.push(x),
+/// Returns the regions of `mapping` (see [Mmu::regions]), where `page_data` gets the data of the
+/// physical pages referenced by the mapping.
+pub(crate) fn regions_of<'a>(
+    mapping: &'a RangeMap<MemoryMapping>,
+    page_data: impl Fn(physical::Index) -> &'a PageData + 'a,
+) -> impl Iterator<Item = Region> + 'a {
+    const EFFECTIVE: u8 = perm::READ | perm::WRITE | perm::EXEC;
+
+    let mut parts = mapping.iter().flat_map(move |(start, end, entry)| {
+        let region =
+            |start, end, perm: u8, kind| Region { start, end, perm: perm & EFFECTIVE, kind };
+        let mut parts = vec![];
+        match entry {
+            MemoryMapping::Physical(entry) => {
+                // The permissions of a physical page are stored for each byte, so split the
+                // region wherever they change.
+                let offset = PageData::offset(start);
+                let len = (end - start) as usize + 1;
+                let perms = &page_data(entry.index).perm[offset..][..len];
+                let mut run_start = 0;
+                for i in 1..=len {
+                    if i == len || (perms[i] ^ perms[run_start]) & EFFECTIVE != 0 {
+                        let (run_start_addr, run_end) =
+                            (start + run_start as u64, start + (i as u64 - 1));
+                        parts.push(region(
+                            run_start_addr,
+                            run_end,
+                            perms[run_start],
+                            RegionKind::Physical,
+                        ));
+                        run_start = i;
+                    }
+                }
+            }
+            MemoryMapping::Unallocated(entry) => {
+                parts.push(region(start, end, entry.perm, RegionKind::Unallocated))
+            }
+            MemoryMapping::File(entry) => {
+                parts.push(region(start, end, entry.perm, RegionKind::File(entry.file)))
+            }
+            MemoryMapping::Io(io) => {
+                parts.push(region(start, end, io.perm, RegionKind::Io(IoHandler(io.handler))))
+            }
+            MemoryMapping::Reserved(_) => {
+                parts.push(region(start, end, perm::NONE, RegionKind::Reserved))
             }
-            addr += 1;
         }
-        Ok(addr)
+        parts
+    });
+
+    let mut current: Option<Region> = None;
+    std::iter::from_fn(move || {
+        for next in parts.by_ref() {
+            match &mut current {
+                Some(region)
+                    if region.end.checked_add(1) == Some(next.start)
+                        && region.perm == next.perm
+                        && region.kind == next.kind =>
+                {
+                    region.end = next.end
+                }
+                _ => {
+                    if let Some(region) = current.replace(next) {
+                        return Some(region);
+                    }
+                }
+            }
+        }
+        current.take()
+    })
+}
+
+/// Returns the kind of a read performed with `perm`.
+fn read_kind(perm: u8) -> AccessKind {
+    match perm & perm::EXEC != 0 {
+        true => AccessKind::Execute,
+        false => AccessKind::Read,
     }
 }
 
+/// Returns whether filling `len` bytes starting at `start` with `value` would modify any bytes that
+/// are part of the code cache.
 #[cold]
-fn check_self_modifying_memset(page: &PageData, start: u64, len: u64, value: u8) -> MemResult<()> {
+fn modifies_cached_code_memset(page: &PageData, start: u64, len: u64, value: u8) -> bool {
     let offset = PageData::offset(start);
-    for i in offset..offset + len as usize {
-        if page.perm[i] & perm::IN_CODE_CACHE != 0 && page.data[i] != value {
-            let addr = start + (i - offset) as u64;
+    (offset..offset + len as usize)
+        .any(|i| page.perm[i] & perm::IN_CODE_CACHE != 0 && page.data[i] != value)
+}
+
+/// Returns whether writing `value` to `addr` would modify any bytes that are part of the code
+/// cache.
+#[cold]
+fn modifies_cached_code(page: &PageData, addr: u64, value: &[u8]) -> bool {
+    let offset = PageData::offset(addr);
+    page.data[offset..]
+        .iter()
+        .zip(&page.perm[offset..])
+        .zip(value)
+        .any(|((old, perm), new)| perm & perm::IN_CODE_CACHE != 0 && *old != *new)
+}
+
+/// Checks whether a write at `addr` that modifies code in the code cache is allowed.
+#[cold]
+fn check_self_modifying_code(mode: SelfModifyingCode, addr: u64) -> MemResult<()> {
+    match mode {
+        SelfModifyingCode::Fault => {
             tracing::error!("Self modifying code detected at {addr:#x}. Currently unsupported.");
-            return Err(MemError::SelfModifyingCode);
+            Err(MemError::SelfModifyingCode)
+        }
+        SelfModifyingCode::Invalidate => {
+            debug!("Self modifying code detected at {addr:#x}");
+            Ok(())
         }
+        SelfModifyingCode::Ignore => Ok(()),
     }
-    Ok(())
 }
 
-#[cold]
-fn check_self_modifying_write(page: &PageData, addr: u64, value: &[u8]) -> MemResult<()> {
-    let offset = PageData::offset(addr);
-    for (i, ((old, perm), new)) in
-        page.data[offset..].iter().zip(&page.perm[offset..]).zip(value).enumerate()
-    {
-        if perm & perm::IN_CODE_CACHE != 0 && *old != *new {
-            tracing::error!(
-                "Self modifying code detected at {:#x}. Currently unsupported.",
-                addr + i as u64
-            );
-            return Err(MemError::SelfModifyingCode);
+/// Rounds `value` up to a multiple of `align` (which must be a power of two), returning `None` on
+/// overflow.
+pub(crate) fn checked_align_up(value: u64, align: u64) -> Option<u64> {
+    Some(value.checked_add(align - 1)? & !(align - 1))
+}
+
+/// Returns whether `page` can be freed once it is no longer mapped, i.e., it is not a zero page, is
+/// not shared with another mapping or the code cache, and is not owned by the caller of
+/// [Mmu::alloc_physical].
+fn is_unshared_page(index: physical::Index, page: &physical::Page) -> bool {
+    !index.is_zero_page() && !page.copy_on_write && !page.executed && !page.owned
+}
+
+/// Clears the state associated with the `len` bytes at `start` of a page that is no longer
+/// reachable from the region that was unmapped, overwriting the data of the bytes with `poison` if
+/// it is set.
+fn release_physical_range(
+    index: physical::Index,
+    page: &mut physical::Page,
+    start: u64,
+    len: u64,
+    poison: Option<u8>,
+) {
+    // Pages shared with another MMU are still reachable from the other MMU.
+    if page.is_shared_memory() {
+        return;
+    }
+    if let Some(value) = poison {
+        // Pages that are shared with other mappings (or owned by the caller) are still reachable,
+        // so they are not modified.
+        if !index.is_zero_page() && !page.copy_on_write && !page.owned {
+            if page.executed {
+                page.clear_code_cache(PageData::offset(start), len as usize);
+            }
+            let offset = PageData::offset(start);
+            let data = page.data_mut();
+            data.data[offset..offset + len as usize].fill(value);
+            data.perm[offset..offset + len as usize].fill(perm::NONE);
+            return;
+        }
+    }
+
+    if len == physical::PAGE_SIZE as u64 {
+        // The page is no longer reachable from this mapping, so just clear any code cache state to
+        // avoid leaking it if the page is reused.
+        if page.executed {
+            page.clear_code_cache(0, physical::PAGE_SIZE);
         }
+        return;
     }
-    Ok(())
+
+    // Clear permissions for the unmapped region.
+    let offset = PageData::offset(start);
+    page.data_mut().perm[offset..offset + len as usize].fill(perm::NONE);
+    if page.executed {
+        page.clear_code_cache(offset, len as usize);
+    }
+}
+
+/// Gets the I/O handler with `id`, returning an error if the handler has been unregistered.
+fn get_io(
+    io: &mut [Option<Box<dyn IoMemoryAny>>],
+    id: usize,
+) -> MemResult<&mut Box<dyn IoMemoryAny>> {
+    io[id].as_mut().ok_or(MemError::Unmapped)
+}
+
+/// The output function of the SplitMix64 random number generator.
+fn splitmix64(state: u64) -> u64 {
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Gets the bytes of the file backing `mapping` for the `len` bytes starting at `addr`. The slice
+/// is shorter than `len` if the region extends past the end of the file.
+pub(crate) fn file_bytes<'a>(
+    files: &'a [FileData],
+    mapping: &FileMapping,
+    addr: u64,
+    len: usize,
+) -> &'a [u8] {
+    let data = (*files[mapping.file.0]).as_ref();
+    let offset = mapping.file_offset(addr);
+    let start = usize::try_from(offset).unwrap_or(usize::MAX).min(data.len());
+    &data[start..start.saturating_add(len).min(data.len())]
 }
 
 macro_rules! impl_read_write {
@@ -1299,9 +7808,54 @@ macro_rules! impl_read_write {
             }
         }
     };
+
+    (
+        $ty:ty, $read_name:ident, $write_name:ident, $read_le:ident, $write_le:ident,
+        $read_be:ident, $write_be:ident
+    ) => {
+        impl Mmu {
+            /// Reads a value from `addr` in the byte order of the guest (see [Mmu::endianness]).
+            #[inline(always)]
+            pub fn $read_name(&mut self, addr: u64, perm: u8) -> MemResult<$ty> {
+                match self.endianness {
+                    Endianness::Little => self.$read_le(addr, perm),
+                    Endianness::Big => self.$read_be(addr, perm),
+                }
+            }
+
+            /// Writes `value` to `addr` in the byte order of the guest (see [Mmu::endianness]).
+            #[inline(always)]
+            pub fn $write_name(&mut self, addr: u64, value: $ty, perm: u8) -> MemResult<()> {
+                match self.endianness {
+                    Endianness::Little => self.$write_le(addr, value, perm),
+                    Endianness::Big => self.$write_be(addr, value, perm),
+                }
+            }
+
+            #[inline(always)]
+            pub fn $read_le(&mut self, addr: u64, perm: u8) -> MemResult<$ty> {
+                Ok(<$ty>::from_le_bytes(self.read(addr, perm)?))
+            }
+
+            #[inline(always)]
+            pub fn $write_le(&mut self, addr: u64, value: $ty, perm: u8) -> MemResult<()> {
+                self.write(addr, value.to_le_bytes(), perm)
+            }
+
+            #[inline(always)]
+            pub fn $read_be(&mut self, addr: u64, perm: u8) -> MemResult<$ty> {
+                Ok(<$ty>::from_be_bytes(self.read(addr, perm)?))
+            }
+
+            #[inline(always)]
+            pub fn $write_be(&mut self, addr: u64, value: $ty, perm: u8) -> MemResult<()> {
+                self.write(addr, value.to_be_bytes(), perm)
+            }
+        }
+    };
 }
 
 impl_read_write!(read_u8, write_u8, u8);
-impl_read_write!(read_u16, write_u16, u16);
-impl_read_write!(read_u32, write_u32, u32);
-impl_read_write!(read_u64, write_u64, u64);
+impl_read_write!(u16, read_u16, write_u16, read_u16_le, write_u16_le, read_u16_be, write_u16_be);
+impl_read_write!(u32, read_u32, write_u32, read_u32_le, write_u32_le, read_u32_be, write_u32_be);
+impl_read_write!(u64, read_u64, write_u64, read_u64_le, write_u64_le, read_u64_be, write_u64_be);
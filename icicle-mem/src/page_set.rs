@@ -0,0 +1,196 @@
+//! A sparse set of page-aligned addresses, used for tracking modified pages.
+
+use crate::physical::OFFSET_BITS;
+
+/// The number of bits used to index a page within a chunk.
+const CHUNK_BITS: usize = 12;
+
+/// The number of pages tracked by a single chunk.
+const PAGES_PER_CHUNK: usize = 1 << CHUNK_BITS;
+
+/// The number of 64-bit words in a chunk.
+const WORDS_PER_CHUNK: usize = PAGES_PER_CHUNK / 64;
+
+/// A bitmap for a contiguous range of `PAGES_PER_CHUNK` pages.
+struct Chunk {
+    /// The index of the first page covered by this chunk (shifted by `CHUNK_BITS`).
+    key: u64,
+
+    /// A summary of which entries in `words` are non-zero.
+    summary: u64,
+
+    /// One bit for every page in the chunk.
+    words: Box<[u64; WORDS_PER_CHUNK]>,
+}
+
+impl Chunk {
+    fn new(key: u64) -> Self {
+        Self { key, summary: 0, words: Box::new([0; WORDS_PER_CHUNK]) }
+    }
+
+    fn clear(&mut self) {
+        for word in BitIter(self.summary) {
+            self.words[word] = 0;
+        }
+        self.summary = 0;
+    }
+}
+
+/// A set of page-aligned addresses stored as a two-level bitmap.
+///
+/// Chunks are never deallocated, so after the set has been populated once, inserting and clearing
+/// do not allocate.
+#[derive(Default)]
+pub struct PageSet {
+    /// All chunks that have been allocated, sorted by key.
+    chunks: Vec<Chunk>,
+
+    /// The number of addresses in the set.
+    len: usize,
+
+    /// The index of the chunk that was last inserted into.
+    last_chunk: usize,
+}
+
+impl PageSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the page containing `addr` to the set, returning whether the page was newly inserted.
+    #[inline]
+    pub fn insert(&mut self, addr: u64) -> bool {
+        let (key, bit) = split_addr(addr);
+        let index = match self.chunks.get(self.last_chunk) {
+            Some(chunk) if chunk.key == key => self.last_chunk,
+            _ => self.get_or_insert_chunk(key),
+        };
+        self.last_chunk = index;
+
+        let chunk = &mut self.chunks[index];
+        let (word, mask) = (bit / 64, 1 << (bit % 64));
+        if chunk.words[word] & mask != 0 {
+            return false;
+        }
+        chunk.words[word] |= mask;
+        chunk.summary |= 1 << word;
+        self.len += 1;
+        true
+    }
+
+    #[cold]
+    fn get_or_insert_chunk(&mut self, key: u64) -> usize {
+        match self.chunks.binary_search_by_key(&key, |chunk| chunk.key) {
+            Ok(index) => index,
+            Err(index) => {
+                self.chunks.insert(index, Chunk::new(key));
+                index
+            }
+        }
+    }
+
+    /// Allocates the storage required to insert any page in `start..=end` without allocating.
+    pub fn reserve(&mut self, start: u64, end: u64) {
+        let (start_key, _) = split_addr(start);
+        let (end_key, _) = split_addr(end);
+        for key in start_key..=end_key {
+            self.get_or_insert_chunk(key);
+        }
+    }
+
+    /// Returns whether the page containing `addr` is in the set.
+    pub fn contains(&self, addr: u64) -> bool {
+        let (key, bit) = split_addr(addr);
+        match self.chunks.binary_search_by_key(&key, |chunk| chunk.key) {
+            Ok(index) => self.chunks[index].words[bit / 64] & (1 << (bit % 64)) != 0,
+            Err(_) => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Removes all addresses from the set, keeping the allocated chunks for reuse.
+    pub fn clear(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        self.chunks.iter_mut().for_each(Chunk::clear);
+        self.len = 0;
+    }
+
+    /// Returns an iterator over the (page-aligned) addresses in the set in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.chunks.iter().filter(|chunk| chunk.summary != 0).flat_map(|chunk| {
+            BitIter(chunk.summary).flat_map(move |word| {
+                BitIter(chunk.words[word]).map(move |bit| {
+                    let page = (chunk.key << CHUNK_BITS) | (word * 64 + bit) as u64;
+                    page << OFFSET_BITS
+                })
+            })
+        })
+    }
+}
+
+impl std::fmt::Debug for PageSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.iter().map(|addr| format!("{addr:#x}"))).finish()
+    }
+}
+
+/// Splits `addr` into the key of the chunk and the index of the bit within the chunk.
+#[inline]
+fn split_addr(addr: u64) -> (u64, usize) {
+    let page = addr >> OFFSET_BITS;
+    (page >> CHUNK_BITS, (page as usize) & (PAGES_PER_CHUNK - 1))
+}
+
+/// An iterator over the indices of the set bits in a word.
+struct BitIter(u64);
+
+impl Iterator for BitIter {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        if self.0 == 0 {
+            return None;
+        }
+        let bit = self.0.trailing_zeros() as usize;
+        self.0 &= self.0 - 1;
+        Some(bit)
+    }
+}
+
+#[test]
+fn insert_and_clear() {
+    let mut set = PageSet::new();
+    assert!(set.insert(0x5000));
+    assert!(set.insert(0x1234));
+    assert!(!set.insert(0x5fff));
+    assert!(set.insert(0xffff_ffff_ffff_f000));
+    assert!(set.insert(0x4000_0000));
+    assert_eq!(set.len(), 4);
+
+    assert!(set.contains(0x1000));
+    assert!(!set.contains(0x2000));
+    assert_eq!(set.iter().collect::<Vec<_>>(), [
+        0x1000,
+        0x5000,
+        0x4000_0000,
+        0xffff_ffff_ffff_f000
+    ]);
+
+    set.clear();
+    assert!(set.is_empty());
+    assert_eq!(set.iter().next(), None);
+    assert!(!set.contains(0x1000));
+
+    assert!(set.insert(0x1000));
+    assert_eq!(set.iter().collect::<Vec<_>>(), [0x1000]);
+}
@@ -0,0 +1,292 @@
+//! Translation of virtual addresses using page tables stored in guest memory (see
+//! [crate::Mmu::set_page_table_walker]).
+
+use crate::{AccessKind, MemError, MemResult, Mmu, perm};
+
+/// A translation request passed to a [PageTableWalker].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WalkRequest {
+    /// The virtual address to translate.
+    pub addr: u64,
+
+    /// The kind of access being performed.
+    pub kind: AccessKind,
+
+    /// Whether the access is performed in user mode (see [crate::Mmu::set_page_table_user_mode]).
+    pub user: bool,
+}
+
+/// The result of a successful page table walk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageTranslation {
+    /// The guest physical address that the requested address translates to.
+    pub paddr: u64,
+
+    /// The permissions granted by the page tables for the privilege level of the request, a
+    /// combination of [perm::READ], [perm::WRITE] and [perm::EXEC].
+    pub perm: u8,
+}
+
+/// The reason that a page table walk failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageFaultReason {
+    /// The entry at the faulting level is not present.
+    NotPresent,
+
+    /// The page is present, but the access is not allowed by the permissions of the page.
+    Protection,
+
+    /// The entry at the faulting level has a reserved bit set.
+    ReservedBit,
+
+    /// The address is not valid for the page table format (e.g. a non-canonical address on
+    /// x86-64).
+    InvalidAddress,
+
+    /// The entry at the faulting level could not be read from (or updated in) physical memory.
+    TableAccess(MemError),
+}
+
+/// Details about an access that failed because of a page table walk, recorded so that the CPU can
+/// raise the corresponding exception (see [crate::Mmu::take_page_fault]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageFault {
+    /// The virtual address that could not be translated.
+    pub addr: u64,
+
+    /// The kind of access that caused the fault.
+    pub kind: AccessKind,
+
+    /// Whether the access was performed in user mode.
+    pub user: bool,
+
+    /// The level of the page table that contains the entry that caused the fault. Levels are
+    /// numbered from the root table down to 1 for the last level table (e.g. on x86-64, 4 is the
+    /// PML4 and 1 is the page table).
+    pub level: u8,
+
+    /// The reason that the walk failed.
+    pub reason: PageFaultReason,
+}
+
+impl PageFault {
+    pub fn new(request: WalkRequest, level: u8, reason: PageFaultReason) -> Self {
+        Self { addr: request.addr, kind: request.kind, user: request.user, level, reason }
+    }
+
+    /// Returns the error code that an x86 processor pushes for the fault.
+    pub fn x86_error_code(&self) -> u32 {
+        let mut code = 0;
+        if !matches!(self.reason, PageFaultReason::NotPresent) {
+            code |= 1 << 0;
+        }
+        if self.kind == AccessKind::Write {
+            code |= 1 << 1;
+        }
+        if self.user {
+            code |= 1 << 2;
+        }
+        if self.reason == PageFaultReason::ReservedBit {
+            code |= 1 << 3;
+        }
+        if self.kind == AccessKind::Execute {
+            code |= 1 << 4;
+        }
+        code
+    }
+}
+
+/// Access to the page tables in guest physical memory during a walk.
+///
+/// Entries are accessed without checking permissions and without calling memory hooks. Accesses
+/// made through this struct are never treated as modifications of the page tables, so walkers can
+/// update status bits (e.g. accessed/dirty bits) without invalidating cached translations.
+pub struct PageTableMemory<'a> {
+    pub(crate) mmu: &'a mut Mmu,
+
+    /// The (page-aligned) physical addresses of the tables accessed during the walk.
+    pub(crate) tables: Vec<u64>,
+}
+
+impl<'a> PageTableMemory<'a> {
+    /// Reads the 8-byte (little-endian) entry at the guest physical address `paddr`.
+    pub fn read_u64(&mut self, paddr: u64) -> MemResult<u64> {
+        self.record_table(paddr);
+        self.mmu.read_guest_physical(paddr).map(u64::from_le_bytes)
+    }
+
+    /// Reads the 4-byte (little-endian) entry at the guest physical address `paddr`.
+    pub fn read_u32(&mut self, paddr: u64) -> MemResult<u32> {
+        self.record_table(paddr);
+        self.mmu.read_guest_physical(paddr).map(u32::from_le_bytes)
+    }
+
+    /// Writes `value` to the 8-byte (little-endian) entry at the guest physical address `paddr`.
+    pub fn write_u64(&mut self, paddr: u64, value: u64) -> MemResult<()> {
+        self.record_table(paddr);
+        self.mmu.write_guest_physical(paddr, value.to_le_bytes())
+    }
+
+    /// Writes `value` to the 4-byte (little-endian) entry at the guest physical address `paddr`.
+    pub fn write_u32(&mut self, paddr: u64, value: u32) -> MemResult<()> {
+        self.record_table(paddr);
+        self.mmu.write_guest_physical(paddr, value.to_le_bytes())
+    }
+
+    fn record_table(&mut self, paddr: u64) {
+        let page = self.mmu.page_aligned(paddr);
+        if !self.tables.contains(&page) {
+            self.tables.push(page);
+        }
+    }
+}
+
+/// Translates virtual addresses by walking page tables stored in guest physical memory.
+pub trait PageTableWalker {
+    /// Translates `request.addr` by walking the page tables starting from the table at the
+    /// physical address `root`.
+    fn walk(
+        &mut self,
+        mem: &mut PageTableMemory,
+        root: u64,
+        request: WalkRequest,
+    ) -> Result<PageTranslation, PageFault>;
+}
+
+/// A walker for x86-64 4-level paging, where `root` is the value of the CR3 register.
+///
+/// Accessed and dirty bits are set in the entries used for successful translations. Protection
+/// keys, SMEP/SMAP and the PAT/cache-control bits are not supported.
+#[derive(Clone, Copy, Debug)]
+pub struct X86_64Walker {
+    /// Controls whether supervisor writes to read-only pages fault (CR0.WP).
+    pub write_protect: bool,
+
+    /// Controls whether the execute-disable bit is supported (EFER.NXE). If not set, the bit is
+    /// treated as reserved.
+    pub nx_enabled: bool,
+
+    /// The number of bits in a physical address (MAXPHYADDR), bits above this in an entry are
+    /// reserved.
+    pub physical_address_bits: u32,
+}
+
+impl Default for X86_64Walker {
+    fn default() -> Self {
+        Self { write_protect: true, nx_enabled: true, physical_address_bits: 52 }
+    }
+}
+
+impl X86_64Walker {
+    pub const PRESENT: u64 = 1 << 0;
+    pub const WRITABLE: u64 = 1 << 1;
+    pub const USER: u64 = 1 << 2;
+    pub const ACCESSED: u64 = 1 << 5;
+    pub const DIRTY: u64 = 1 << 6;
+    pub const PAGE_SIZE: u64 = 1 << 7;
+    pub const NO_EXECUTE: u64 = 1 << 63;
+
+    const LEVELS: u8 = 4;
+
+    fn address_mask(&self) -> u64 {
+        ((1 << self.physical_address_bits) - 1) & !0xfff
+    }
+
+    /// Returns whether `entry` at `level` has any reserved bits set.
+    fn has_reserved_bits(&self, entry: u64, level: u8) -> bool {
+        let reserved_address = entry & !Self::NO_EXECUTE & !((1 << self.physical_address_bits) - 1);
+        if reserved_address & ((1 << 52) - 1) != 0 {
+            return true;
+        }
+        if !self.nx_enabled && entry & Self::NO_EXECUTE != 0 {
+            return true;
+        }
+        match level {
+            // The page size bit is reserved in the PML4.
+            4 => entry & Self::PAGE_SIZE != 0,
+            // For large pages, the bits between the PAT bit and the page frame are reserved.
+            2 | 3 if entry & Self::PAGE_SIZE != 0 => {
+                let page_mask = (1_u64 << (12 + 9 * (level as u32 - 1))) - 1;
+                entry & page_mask & !0x1fff != 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl PageTableWalker for X86_64Walker {
+    fn walk(
+        &mut self,
+        mem: &mut PageTableMemory,
+        root: u64,
+        request: WalkRequest,
+    ) -> Result<PageTranslation, PageFault> {
+        let addr = request.addr;
+        let fault = |level, reason| PageFault::new(request, level, reason);
+        if ((addr as i64) << 16 >> 16) as u64 != addr {
+            return Err(fault(Self::LEVELS, PageFaultReason::InvalidAddress));
+        }
+
+        let mut entries = [(0, 0); Self::LEVELS as usize];
+        let mut table = root & self.address_mask();
+        let (mut writable, mut user, mut executable) = (true, true, true);
+        for level in (1..=Self::LEVELS).rev() {
+            let shift = 12 + 9 * (level as u32 - 1);
+            let entry_addr = table + ((addr >> shift) & 0x1ff) * 8;
+            let entry = mem
+                .read_u64(entry_addr)
+                .map_err(|e| fault(level, PageFaultReason::TableAccess(e)))?;
+            if entry & Self::PRESENT == 0 {
+                return Err(fault(level, PageFaultReason::NotPresent));
+            }
+            if self.has_reserved_bits(entry, level) {
+                return Err(fault(level, PageFaultReason::ReservedBit));
+            }
+            entries[(Self::LEVELS - level) as usize] = (entry_addr, entry);
+
+            writable &= entry & Self::WRITABLE != 0;
+            user &= entry & Self::USER != 0;
+            executable &= entry & Self::NO_EXECUTE == 0;
+
+            if level != 1 && entry & Self::PAGE_SIZE == 0 {
+                table = entry & self.address_mask();
+                continue;
+            }
+
+            let mut perm = perm::READ;
+            if writable || (!request.user && !self.write_protect) {
+                perm |= perm::WRITE;
+            }
+            if executable {
+                perm |= perm::EXEC;
+            }
+            let required = match request.kind {
+                AccessKind::Read => perm::READ,
+                AccessKind::Write => perm::WRITE,
+                AccessKind::Execute => perm::EXEC,
+            };
+            if (request.user && !user) || perm & required == 0 {
+                return Err(fault(level, PageFaultReason::Protection));
+            }
+
+            // Set the accessed bit for every entry used by the translation, and the dirty bit for
+            // the final entry if the page is being written to.
+            let used = &entries[..=(Self::LEVELS - level) as usize];
+            for (i, &(entry_addr, entry)) in used.iter().enumerate() {
+                let mut updated = entry | Self::ACCESSED;
+                if i == used.len() - 1 && request.kind == AccessKind::Write {
+                    updated |= Self::DIRTY;
+                }
+                if updated != entry {
+                    mem.write_u64(entry_addr, updated)
+                        .map_err(|e| fault(level, PageFaultReason::TableAccess(e)))?;
+                }
+            }
+
+            let page_mask = (1 << shift) - 1;
+            let paddr = (entry & self.address_mask() & !page_mask) | (addr & page_mask);
+            return Ok(PageTranslation { paddr, perm });
+        }
+        unreachable!()
+    }
+}
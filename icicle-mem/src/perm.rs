@@ -13,6 +13,17 @@ pub enum MemError {
     OutOfMemory,
     SelfModifyingCode,
     AddressOverflow,
+    CrossesDeviceBoundary,
+    GuardPage,
+    WriteExecViolation,
+    Unsupported,
+    PageFault,
+    NotContiguous,
+    AlreadyMapped,
+    Reserved,
+    LimitExceeded,
+    ReplayDiverged,
+    InvalidRange,
     Unknown,
 }
 
@@ -34,6 +45,17 @@ impl std::str::FromStr for MemError {
             "OutOfMemory" => Self::OutOfMemory,
             "SelfModifyingCode" => Self::SelfModifyingCode,
             "AddressOverflow" => Self::AddressOverflow,
+            "CrossesDeviceBoundary" => Self::CrossesDeviceBoundary,
+            "GuardPage" => Self::GuardPage,
+            "WriteExecViolation" => Self::WriteExecViolation,
+            "Unsupported" => Self::Unsupported,
+            "PageFault" => Self::PageFault,
+            "NotContiguous" => Self::NotContiguous,
+            "AlreadyMapped" => Self::AlreadyMapped,
+            "Reserved" => Self::Reserved,
+            "LimitExceeded" => Self::LimitExceeded,
+            "ReplayDiverged" => Self::ReplayDiverged,
+            "InvalidRange" => Self::InvalidRange,
             _ => Self::Unknown,
         })
     }
@@ -55,6 +77,17 @@ impl MemError {
             Self::OutOfMemory => "OutOfMemory",
             Self::SelfModifyingCode => "SelfModifyingCode",
             Self::AddressOverflow => "AddressOverflow",
+            Self::CrossesDeviceBoundary => "CrossesDeviceBoundary",
+            Self::GuardPage => "GuardPage",
+            Self::WriteExecViolation => "WriteExecViolation",
+            Self::Unsupported => "Unsupported",
+            Self::PageFault => "PageFault",
+            Self::NotContiguous => "NotContiguous",
+            Self::AlreadyMapped => "AlreadyMapped",
+            Self::Reserved => "Reserved",
+            Self::LimitExceeded => "LimitExceeded",
+            Self::ReplayDiverged => "ReplayDiverged",
+            Self::InvalidRange => "InvalidRange",
             Self::Unknown => "Unknown",
         }
     }
@@ -74,6 +107,17 @@ impl MemError {
             Self::SelfModifyingCode => 0x1_000a,
             Self::AddressOverflow => 0x1_000b,
             Self::UnmappedRegister => 0x1_000c,
+            Self::CrossesDeviceBoundary => 0x1_000d,
+            Self::GuardPage => 0x1_000e,
+            Self::WriteExecViolation => 0x1_000f,
+            Self::Unsupported => 0x1_0010,
+            Self::PageFault => 0x1_0011,
+            Self::NotContiguous => 0x1_0012,
+            Self::AlreadyMapped => 0x1_0013,
+            Self::Reserved => 0x1_0014,
+            Self::LimitExceeded => 0x1_0015,
+            Self::ReplayDiverged => 0x1_0016,
+            Self::InvalidRange => 0x1_0017,
             Self::Unknown => 0x1_FFFF,
         }
     }
@@ -92,6 +136,17 @@ impl MemError {
             0x1_0009 => Self::OutOfMemory,
             0x1_000a => Self::SelfModifyingCode,
             0x1_000b => Self::AddressOverflow,
+            0x1_000d => Self::CrossesDeviceBoundary,
+            0x1_000e => Self::GuardPage,
+            0x1_000f => Self::WriteExecViolation,
+            0x1_0010 => Self::Unsupported,
+            0x1_0011 => Self::PageFault,
+            0x1_0012 => Self::NotContiguous,
+            0x1_0013 => Self::AlreadyMapped,
+            0x1_0014 => Self::Reserved,
+            0x1_0015 => Self::LimitExceeded,
+            0x1_0016 => Self::ReplayDiverged,
+            0x1_0017 => Self::InvalidRange,
             _ => Self::Unknown,
         }
     }
@@ -119,40 +174,58 @@ pub const ALL: u8 = MAP | INIT | READ | WRITE | EXEC | IN_CODE_CACHE;
 pub const READ_WATCH: u8 = 0b0010_0000;
 pub const WRITE_WATCH: u8 = 0b0100_0000;
 
+/// Marks a byte as a guard: every access to the byte fails with [MemError::GuardPage].
+///
+/// A guarded byte watches every kind of access, so it is represented by setting both watch bits.
+/// Guarded bytes are stored without the [MAP] bit, ensuring that accesses always fail the
+/// permission check (including accesses through the TLB).
+pub const GUARD: u8 = READ_WATCH | WRITE_WATCH;
+
+/// Returns whether the stored permission bits `perm` correspond to a guarded byte.
+#[inline(always)]
+pub fn is_guard(perm: u8) -> bool {
+    perm & (MAP | GUARD) == GUARD
+}
+
 #[inline(always)]
 pub fn check(perm: u8, mask: u8) -> MemResult<()> {
-    let perm = perm | !mask;
-    if perm & ALL != ALL {
-        return Err(get_error_kind(perm));
+    if (perm | !mask) & ALL != ALL {
+        return Err(get_error_kind(perm | !mask, mask & MAP != 0 && is_guard(perm)));
     }
     Ok(())
 }
 
 #[inline(always)]
-pub fn check_bytes<const N: usize>(mut perm: [u8; N], mask: u8) -> MemResult<()> {
-    for (byte, mask) in perm.iter_mut().zip([!mask & ALL; N]) {
+pub fn check_bytes<const N: usize>(perm: [u8; N], mask: u8) -> MemResult<()> {
+    let mut checked = perm;
+    for (byte, mask) in checked.iter_mut().zip([!mask & ALL; N]) {
         *byte |= mask;
     }
-    if perm != [ALL; N] {
-        return Err(get_error_kind_bytes(perm));
+    if checked != [ALL; N] {
+        return Err(get_error_kind_bytes(perm, mask));
     }
     Ok(())
 }
 
 #[inline(never)]
 #[cold]
-fn get_error_kind_bytes<const N: usize>(perm: [u8; N]) -> MemError {
+fn get_error_kind_bytes<const N: usize>(perm: [u8; N], mask: u8) -> MemError {
     let mut check = ALL;
     for byte in perm {
-        check &= byte;
+        check &= byte | !mask;
     }
-    get_error_kind(check)
+    get_error_kind(check, mask & MAP != 0 && perm.iter().any(|byte| is_guard(*byte)))
 }
 
+/// Determines the error for a failed permission check, where `guard` is set if the check failed
+/// because of a guarded byte.
 #[inline(never)]
 #[cold]
-fn get_error_kind(perm: u8) -> MemError {
-    if perm & MAP == 0 {
+fn get_error_kind(perm: u8, guard: bool) -> MemError {
+    if guard {
+        MemError::GuardPage
+    }
+    else if perm & MAP == 0 {
         MemError::Unmapped
     }
     else if perm & READ == 0 {
@@ -185,6 +258,9 @@ impl std::fmt::Display for Permission {
         let perm = self.0;
 
         let mut values = vec![];
+        if is_guard(perm) {
+            return f.write_str("Guard");
+        }
         if perm & MAP == 0 {
             return f.write_str("Unmapped");
         }
@@ -249,3 +325,17 @@ fn test_read_write_watch() {
     assert_eq!(check(WRITE, WRITE | WRITE_WATCH), Ok(()));
     assert_eq!(check(WRITE | WRITE_WATCH, WRITE | WRITE_WATCH), Err(MemError::WriteWatch));
 }
+
+#[test]
+fn test_guard() {
+    let guard = (READ | WRITE | INIT | GUARD) & !MAP;
+    assert_eq!(check(guard, MAP | READ), Err(MemError::GuardPage));
+    assert_eq!(check(guard, NONE), Ok(()));
+    assert_eq!(check(READ | MAP | GUARD, MAP | READ), Ok(()));
+
+    assert_eq!(check_bytes([MAP | READ, guard], MAP | READ), Err(MemError::GuardPage));
+    assert_eq!(check_bytes([NONE, guard], MAP | READ), Err(MemError::GuardPage));
+    assert_eq!(check_bytes([MAP | READ, NONE], MAP | READ), Err(MemError::Unmapped));
+
+    assert_eq!(MemError::from_code(MemError::GuardPage.code()), MemError::GuardPage);
+}
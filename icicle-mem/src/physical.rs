@@ -1,5 +1,12 @@
-use std::{cell::UnsafeCell, ptr::NonNull, rc::Rc};
-
+use std::{
+    cell::{Cell, UnsafeCell},
+    ptr::NonNull,
+    rc::Rc,
+    sync::Arc,
+};
+
+#[cfg(all(unix, feature = "mmap"))]
+use crate::host_pool::{HostPool, PooledData};
 use crate::{perm, MemError, MemResult};
 
 /// The number of bits required to represent any offset within a page.
@@ -20,7 +27,8 @@ pub const PAGE_MASK: u64 = (PAGE_SIZE - 1) as u64;
 pub const MAX_PAGES: usize = 50_000;
 
 /// Represents an opaque index into physical memory.
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
 pub struct Index(u32);
 
 impl std::fmt::Debug for Index {
@@ -37,6 +45,16 @@ impl Index {
     pub fn is_zero_page(&self) -> bool {
         self.0 == 0 || self.0 == 1
     }
+
+    /// Gets the index of the page stored at `slot` in physical memory.
+    pub(crate) fn from_slot(slot: usize) -> Self {
+        Self(slot.try_into().unwrap())
+    }
+
+    /// Gets the position of the page in physical memory.
+    pub(crate) fn slot(self) -> usize {
+        self.0 as usize
+    }
 }
 
 /// Represents an address in the guests physical memory.
@@ -49,11 +67,44 @@ impl std::fmt::Display for PhysicalAddr {
     }
 }
 
+/// Controls where the data of physical pages is stored on the host.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PageStore {
+    /// Each page is allocated separately using the global allocator.
+    #[default]
+    Heap,
+
+    /// Pages are stored in an anonymous memory mapping that reserves address space for every page
+    /// up to the capacity of physical memory. The host only commits memory for a page once it is
+    /// written to, and memory used by pages that are no longer referenced is returned to the host
+    /// when physical memory is cleared or restored from a snapshot.
+    ///
+    /// The mapping is created when the first page is allocated, using the capacity at that time.
+    /// Pages allocated beyond that capacity, or allocated when the mapping could not be created,
+    /// use the global allocator.
+    ///
+    /// Note: this requires the `mmap` feature and is only supported on Unix hosts, otherwise it is
+    /// equivalent to [PageStore::Heap].
+    Mmap,
+}
+
 pub struct PhysicalMemory {
     /// The maxmum number of pages that can be allocated.
     capacity: usize,
     allocated: Vec<Page>,
     free: Vec<Index>,
+    store: PageStore,
+    #[cfg(all(unix, feature = "mmap"))]
+    pool: Option<Rc<HostPool>>,
+
+    /// Shared pages where every byte is set to the same (non-zero) value, allocated on demand for
+    /// each combination of `(value, perm)` (see [PhysicalMemory::get_fill_page]).
+    fill_pages: Vec<(u8, u8, Index)>,
+
+    /// A copy of the data (and the permissions used by this MMU) of every page that is shared with
+    /// another MMU (see [Page::is_shared_memory]), captured when a snapshot is taken. Always empty
+    /// for memory that is not a snapshot.
+    shared_memory: Vec<(Index, Arc<PageData>)>,
 }
 
 impl PhysicalMemory {
@@ -62,9 +113,62 @@ impl PhysicalMemory {
     const READ_WRITE_ZERO_PERM: u8 = perm::MAP | perm::READ | perm::WRITE | perm::INIT;
 
     pub fn new(capacity: usize) -> Self {
+        Self::with_store(capacity, PageStore::Heap)
+    }
+
+    pub fn with_store(capacity: usize, store: PageStore) -> Self {
         let zero_page_read_only = Page::zero_page(Self::READ_ONLY_ZERO_PERM, false);
         let zero_page_read_write = Page::zero_page(Self::READ_WRITE_ZERO_PERM, true);
-        Self { capacity, allocated: vec![zero_page_read_only, zero_page_read_write], free: vec![] }
+        Self {
+            capacity,
+            allocated: vec![zero_page_read_only, zero_page_read_write],
+            free: vec![],
+            store,
+            #[cfg(all(unix, feature = "mmap"))]
+            pool: None,
+            fill_pages: vec![],
+            shared_memory: vec![],
+        }
+    }
+
+    /// Gets the store used for the data of new pages.
+    pub fn store(&self) -> PageStore {
+        self.store
+    }
+
+    /// Creates a new (zeroed) page using the configured store.
+    fn new_page(&mut self) -> Page {
+        #[cfg(all(unix, feature = "mmap"))]
+        if self.store == PageStore::Mmap {
+            if self.pool.is_none() {
+                self.pool = HostPool::new(self.capacity);
+                if self.pool.is_none() {
+                    self.store = PageStore::Heap;
+                }
+            }
+            if let Some(data) = self.pool.as_ref().and_then(|pool| pool.alloc()) {
+                return Page::with_data(PageBox::Pooled(data));
+            }
+        }
+        Page::new()
+    }
+
+    /// Returns memory used by pages that are no longer referenced (e.g. by a snapshot) to the host.
+    pub fn release_unused(&self) {
+        #[cfg(all(unix, feature = "mmap"))]
+        if let Some(pool) = &self.pool {
+            pool.release_free();
+        }
+    }
+
+    /// Gets the number of host pages that are committed for storing page data, or `None` if
+    /// physical memory is not backed by [PageStore::Mmap].
+    pub fn committed_host_pages(&self) -> Option<usize> {
+        #[cfg(all(unix, feature = "mmap"))]
+        if let Some(pool) = &self.pool {
+            return Some(pool.committed_pages());
+        }
+        None
     }
 
     #[inline]
@@ -93,14 +197,87 @@ impl PhysicalMemory {
                     tracing::warn!("Guest exceeded memory limit {}", self.capacity);
                     return None;
                 }
-                self.allocated.push(Page::new());
+                let page = self.new_page();
+                self.allocated.push(page);
                 Index((self.allocated.len() - 1).try_into().unwrap())
             }
         };
+        // The data of pages shared with another MMU is never reused.
+        if self.allocated[index.0 as usize].is_shared_memory() {
+            self.allocated[index.0 as usize] = self.new_page();
+        }
         self.allocated[index.0 as usize].clear();
         Some(index)
     }
 
+    /// Replaces the data of the (newly allocated) page at `index` with a reference to the data of
+    /// `page`, which must be a page from the physical memory of another MMU that was converted
+    /// using [Page::make_shared_memory]. The page starts with a copy of the permissions of `page`,
+    /// which are modified independently from the permissions of `page` from then on.
+    pub fn share_data(&mut self, index: Index, page: &Page) {
+        // Safety: the data is only cloned.
+        let PageBox::Shared(shared, _) = (unsafe { &*page.data.get() })
+        else {
+            panic!("page is not shared memory");
+        };
+        let perm = Box::new(page.data().perm);
+        // Safety: shared memory is only accessed from a single thread, and there are no active
+        // references to the saved permissions.
+        let perms = unsafe { &mut *shared.perms.get() };
+        perms.push(perm);
+        let data = PageBox::Shared(shared.clone(), perms.len() - 1);
+        self.allocated[index.0 as usize].data = UnsafeCell::new(data);
+    }
+
+    /// Allocates (and initializes) up to `count` pages that are kept in the free list so that
+    /// future calls to [PhysicalMemory::alloc] do not need to allocate. Returns the number of
+    /// pages that were reserved, which is limited by the capacity of physical memory.
+    pub fn reserve(&mut self, count: usize) -> usize {
+        let count = count.min(self.capacity.saturating_sub(self.allocated.len()));
+        self.allocated.reserve(count);
+        self.free.reserve(count);
+
+        let first = self.allocated.len();
+        for _ in 0..count {
+            let page = self.new_page();
+            self.allocated.push(page);
+        }
+
+        // Note: pages are added to the free list in reverse order so that pages are handed out in
+        // the same order as they would be if they were allocated on demand.
+        let indices = (first..first + count).rev().map(|i| Index(i.try_into().unwrap()));
+        self.free.extend(indices);
+        count
+    }
+
+    /// Gets the number of pages that are allocated on the host but are not currently in use.
+    #[inline]
+    pub fn free_pages(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Gets the number of pages that have been allocated on the host (including the zero pages and
+    /// any free pages).
+    #[inline]
+    pub fn slots(&self) -> usize {
+        self.allocated.len()
+    }
+
+    /// Gets the number of pages in use (excluding the zero and fill pages) that will be copied
+    /// before they are next modified (see [Page::is_shared]).
+    pub fn shared_pages(&self) -> usize {
+        let shared = (0..self.allocated.len())
+            .map(Index::from_slot)
+            .filter(|index| !self.is_fill_page(*index) && self.get(*index).is_shared())
+            .count();
+        shared - self.free.iter().filter(|index| self.get(**index).is_shared()).count()
+    }
+
+    /// Gets the indices of all pages that are currently free.
+    pub fn free_list(&self) -> &[Index] {
+        &self.free
+    }
+
     pub fn capacity(&self) -> usize {
         self.capacity
     }
@@ -127,6 +304,40 @@ impl PhysicalMemory {
         }
     }
 
+    /// Gets the shared page where every byte is `value` with `perm`, allocating it if this is the
+    /// first time it has been requested. Fill pages are always copied before they are modified.
+    ///
+    /// Fill pages (like the zero pages) are only available for memory that is initialized and
+    /// readable, and optionally writable. Returns `None` for other permissions, or if physical
+    /// memory is full.
+    pub fn get_fill_page(&mut self, value: u8, perm: u8) -> Option<Index> {
+        let zero_page = self.get_zero_page(perm)?;
+        if value == 0 {
+            return Some(zero_page);
+        }
+        if let Some(&(_, _, index)) =
+            self.fill_pages.iter().find(|(v, p, _)| *v == value && *p == perm)
+        {
+            return Some(index);
+        }
+
+        let index = self.alloc()?;
+        let page = self.get_mut(index);
+        page.copy_on_write = true;
+        let data = page.data_mut();
+        data.data.fill(value);
+        data.perm.fill(perm);
+        self.fill_pages.push((value, perm, index));
+        Some(index)
+    }
+
+    /// Returns whether `index` is one of the zero pages or a fill page, i.e., a page that is shared
+    /// between unrelated mappings and must never be modified.
+    #[inline]
+    pub fn is_fill_page(&self, index: Index) -> bool {
+        index.is_zero_page() || self.fill_pages.iter().any(|(_, _, x)| *x == index)
+    }
+
     #[inline]
     pub fn get(&self, index: Index) -> &Page {
         &self.allocated[index.0 as usize]
@@ -149,6 +360,8 @@ impl PhysicalMemory {
         let new_index = self.alloc()?;
         let (new, existing) = self.get_pair_mut(new_index, index);
         *new.data_mut() = existing.data().clone();
+        new.shadow = existing.shadow.clone();
+        new.aliased = existing.aliased;
         Some(new_index)
     }
 
@@ -168,22 +381,62 @@ impl PhysicalMemory {
         // Remove all allocated memory except the zero page.
         self.allocated.truncate(2);
         self.free.clear();
+        self.fill_pages.clear();
+        self.release_unused();
     }
 
     pub fn snapshot(&self) -> Self {
-        Self { capacity: self.capacity, allocated: self.allocated.clone(), free: self.free.clone() }
+        let mut allocated = self.allocated.clone();
+        // Pages are unmodified relative to the snapshot they are part of.
+        allocated.iter_mut().for_each(|page| page.modified = false);
+
+        // Pages shared with another MMU are modified in-place, so their data is copied.
+        let shared_memory = (0..allocated.len())
+            .map(Index::from_slot)
+            .filter(|index| self.get(*index).is_shared_memory() && !self.free.contains(index))
+            .map(|index| (index, Arc::new(self.get(index).data().clone())))
+            .collect();
+        Self {
+            capacity: self.capacity,
+            allocated,
+            free: self.free.clone(),
+            store: self.store,
+            #[cfg(all(unix, feature = "mmap"))]
+            pool: self.pool.clone(),
+            fill_pages: self.fill_pages.clone(),
+            shared_memory,
+        }
     }
 
+    /// Restores the state of physical memory from `snapshot`.
+    ///
+    /// Only the permissions of pages shared with another MMU are restored, since the data is also
+    /// visible to the other MMUs (see [PhysicalMemory::restore_shared_memory]).
     pub fn restore(&mut self, snapshot: &Self) {
         self.allocated.clone_from(&snapshot.allocated);
         self.free.clone_from(&snapshot.free);
+        self.fill_pages.clone_from(&snapshot.fill_pages);
+        for (index, data) in &snapshot.shared_memory {
+            self.get_mut(*index).data_mut().perm = data.perm;
+        }
+    }
+
+    /// Writes the data of the pages shared with another MMU that was captured by `snapshot` back
+    /// to the shared pages.
+    pub fn restore_shared_memory(&mut self, snapshot: &Self) {
+        for (index, data) in &snapshot.shared_memory {
+            let page = self.get_mut(*index);
+            if page.is_shared_memory() {
+                page.data_mut().data = data.data;
+            }
+        }
     }
 }
 
 // @todo: make: copy_on_write, modified, and executed bitflags
 pub struct Page {
     /// The content of the page.
-    data: UnsafeCell<Rc<PageData>>,
+    data: UnsafeCell<PageBox>,
 
     /// Keeps track of whether this page implements 'copy-on-write' semantics. (i.e. if true, then
     /// modifications to this page are should not be visible to other virtual address spaces
@@ -195,27 +448,49 @@ pub struct Page {
 
     /// Keeps track of whether code within this page has been lifted.
     pub executed: bool,
+
+    /// Keeps track of whether this page may be mapped at multiple virtual addresses (see
+    /// [crate::Mmu::map_physical]).
+    pub aliased: bool,
+
+    /// Keeps track of whether this page was allocated by [crate::Mmu::alloc_physical]. The caller
+    /// holds the index of the page, so the MMU never frees it.
+    pub owned: bool,
+
+    /// A label for each byte of the page, only allocated once a non-zero label is stored in the
+    /// page (a missing shadow page is equivalent to every label being zero).
+    shadow: Option<Rc<[u8; PAGE_SIZE]>>,
 }
 
 impl Clone for Page {
     fn clone(&self) -> Self {
         Self {
             // Safety: this method invalidates any active `PageRef` used for writing.
-            data: Rc::clone(unsafe { self.data.get().as_ref().unwrap() }).into(),
+            data: unsafe { self.data.get().as_ref().unwrap() }.clone().into(),
             copy_on_write: self.copy_on_write,
             modified: self.modified,
             executed: self.executed,
+            aliased: self.aliased,
+            owned: self.owned,
+            shadow: self.shadow.clone(),
         }
     }
 }
 
 impl Page {
     fn new() -> Self {
+        Self::with_data(PageBox::Heap(Arc::default()))
+    }
+
+    fn with_data(data: PageBox) -> Self {
         Self {
-            data: UnsafeCell::new(Rc::default()),
+            data: UnsafeCell::new(data),
             modified: false,
             copy_on_write: false,
             executed: false,
+            aliased: false,
+            owned: false,
+            shadow: None,
         }
     }
 
@@ -235,6 +510,49 @@ impl Page {
         self.modified = false;
         self.copy_on_write = false;
         self.executed = false;
+        self.aliased = false;
+        self.owned = false;
+        self.shadow = None;
+    }
+
+    /// Returns whether any labels have been stored in the shadow plane of the page.
+    #[inline]
+    pub fn has_shadow(&self) -> bool {
+        self.shadow.is_some()
+    }
+
+    /// Copies the labels of the bytes at `offset..offset+buf.len()` to `buf`.
+    pub fn read_shadow(&self, offset: usize, buf: &mut [u8]) {
+        match &self.shadow {
+            Some(shadow) => buf.copy_from_slice(&shadow[offset..offset + buf.len()]),
+            None => buf.fill(0),
+        }
+    }
+
+    /// Sets the labels of the bytes starting at `offset` to `labels`.
+    pub fn write_shadow(&mut self, offset: usize, labels: &[u8]) {
+        if self.shadow.is_none() && labels.iter().all(|x| *x == 0) {
+            return;
+        }
+        let shadow = Rc::make_mut(self.shadow.get_or_insert_with(|| Rc::new([0; PAGE_SIZE])));
+        shadow[offset..offset + labels.len()].copy_from_slice(labels);
+    }
+
+    /// Sets the labels of the bytes at `offset..offset+len` to `label`.
+    pub fn fill_shadow(&mut self, offset: usize, len: usize, label: u8) {
+        if self.shadow.is_none() && label == 0 {
+            return;
+        }
+        let shadow = Rc::make_mut(self.shadow.get_or_insert_with(|| Rc::new([0; PAGE_SIZE])));
+        shadow[offset..offset + len].fill(label);
+    }
+
+    /// Copies the labels of the bytes at `offset..offset+len` from `src`.
+    pub fn copy_shadow_from(&mut self, src: &Page, offset: usize, len: usize) {
+        match &src.shadow {
+            Some(shadow) => self.write_shadow(offset, &shadow[offset..offset + len]),
+            None => self.fill_shadow(offset, len, 0),
+        }
     }
 
     #[inline(always)]
@@ -243,12 +561,84 @@ impl Page {
         // references.
         //
         // @todo: check this
-        unsafe { self.data.get().as_ref().unwrap() }
+        unsafe { (*self.data.get()).as_ptr().as_ref() }
     }
 
     #[inline(always)]
     pub fn data_mut(&mut self) -> &mut PageData {
-        Rc::make_mut(self.data.get_mut())
+        self.data.get_mut().make_mut()
+    }
+
+    /// Returns whether any byte of the page is still in the code cache.
+    pub fn has_cached_code(&self) -> bool {
+        self.executed && self.data().perm.iter().any(|p| p & perm::IN_CODE_CACHE != 0)
+    }
+
+    /// Removes the bytes at `offset..offset+len` from the code cache, resetting `executed` if there
+    /// is no remaining code in the page. Returns whether any of the bytes were in the code cache.
+    pub fn clear_code_cache(&mut self, offset: usize, len: usize) -> bool {
+        let in_code_cache = |p: &u8| p & perm::IN_CODE_CACHE != 0;
+        if !self.data().perm[offset..offset + len].iter().any(in_code_cache) {
+            return false;
+        }
+
+        let data = self.data_mut();
+        data.perm[offset..offset + len].iter_mut().for_each(|p| *p &= !perm::IN_CODE_CACHE);
+        if !data.perm.iter().any(in_code_cache) {
+            self.executed = false;
+        }
+        true
+    }
+
+    /// Returns whether the data of the page is shared with a page in the physical memory of
+    /// another MMU (see [crate::Mmu::share_region]). Shared memory is always modified in-place,
+    /// so writes are visible to every MMU sharing the page, but each MMU has its own permissions.
+    pub fn is_shared_memory(&self) -> bool {
+        // Safety: we only check the variant of the data.
+        matches!(unsafe { &*self.data.get() }, PageBox::Shared(..))
+    }
+
+    /// Moves the data of the page to storage that can be shared with the physical memory of
+    /// other MMUs (see [PhysicalMemory::share_data]).
+    ///
+    /// Note: this moves the data of the page, invalidating any pointers to the data of the page.
+    pub fn make_shared_memory(&mut self) {
+        if !self.is_shared_memory() {
+            let shared = SharedPageData {
+                data: UnsafeCell::new(self.data().clone()),
+                owner: Cell::new(0),
+                perms: UnsafeCell::new(vec![Box::new([0; PAGE_SIZE])]),
+            };
+            self.data = UnsafeCell::new(PageBox::Shared(Rc::new(shared), 0));
+        }
+    }
+
+    /// Returns whether the data of the page is shared, either with another mapping (copy-on-write)
+    /// or with a copy of the page (e.g. a snapshot), so the data will be copied before it is next
+    /// modified.
+    pub fn is_shared(&self) -> bool {
+        // Safety: we only access the reference count of the data.
+        self.copy_on_write || unsafe { &*self.data.get() }.is_shared()
+    }
+
+    /// Returns whether `self` and `other` currently refer to the same underlying page data (i.e.
+    /// neither page has been modified since one was cloned from the other).
+    pub fn shares_data_with(&self, other: &Page) -> bool {
+        // Safety: we only compare the pointers and never dereference them.
+        unsafe { (*self.data.get()).ptr_eq(&*other.data.get()) }
+    }
+
+    /// Returns a reference to the data of the page that can be sent to other threads. The data is
+    /// shared with the page (so it is copied before the page is next modified), except for pages
+    /// stored in a host pool (see [PageStore::Mmap]) which are always copied.
+    pub fn share_data(&self) -> Arc<PageData> {
+        // Safety: the data is only cloned.
+        match unsafe { &*self.data.get() } {
+            PageBox::Heap(data) => data.clone(),
+            #[cfg(all(unix, feature = "mmap"))]
+            PageBox::Pooled(_) => Arc::new(self.data().clone()),
+            PageBox::Shared(..) => Arc::new(self.data().clone()),
+        }
     }
 
     /// Returns a pointer that can be used for reading/writing.
@@ -270,7 +660,111 @@ impl Page {
     /// after a call to [Drop::drop]).
     #[inline(always)]
     pub unsafe fn read_ptr(&mut self) -> PageRef {
-        PageRef::new(NonNull::new(Rc::as_ptr(self.data.get_mut()) as *mut _).unwrap())
+        PageRef::new(self.data.get_mut().as_ptr())
+    }
+}
+
+/// A reference counted pointer to the data of a page.
+#[derive(Clone)]
+enum PageBox {
+    Heap(Arc<PageData>),
+    #[cfg(all(unix, feature = "mmap"))]
+    Pooled(PooledData),
+    /// Data shared with other MMUs, which is never copied before it is modified, and the index of
+    /// the permissions of this page in [SharedPageData::perms].
+    Shared(Rc<SharedPageData>, usize),
+}
+
+/// The data of a page that is shared between the physical memory of multiple MMUs.
+///
+/// Every MMU has its own permissions for the page, but [PageData] stores the permissions next to
+/// the data, so the permissions of the MMU that last accessed the page are swapped into `data`
+/// before the page is accessed by a different MMU. Pointers to the data of a shared page are never
+/// cached (e.g. in the TLB), so they are always obtained after the permissions are swapped in.
+struct SharedPageData {
+    data: UnsafeCell<PageData>,
+
+    /// The index of the permissions that are currently stored in `data`.
+    owner: Cell<usize>,
+
+    /// The permissions of each page sharing the data (the entry for `owner` is out of date).
+    perms: UnsafeCell<Vec<Box<[u8; PAGE_SIZE]>>>,
+}
+
+impl SharedPageData {
+    /// Returns a pointer to the data of the page with the permissions at `id`.
+    fn activate(&self, id: usize) -> NonNull<PageData> {
+        let owner = self.owner.replace(id);
+        if owner != id {
+            // Safety: shared memory is only accessed from a single thread, and there are no active
+            // references to the data (see `Page::data`).
+            let (data, perms) = unsafe { (&mut *self.data.get(), &mut *self.perms.get()) };
+            *perms[owner] = data.perm;
+            data.perm = *perms[id];
+        }
+        NonNull::new(self.data.get()).unwrap()
+    }
+}
+
+impl PageBox {
+    #[inline(always)]
+    fn as_ptr(&self) -> NonNull<PageData> {
+        match self {
+            Self::Heap(data) => NonNull::new(Arc::as_ptr(data) as *mut _).unwrap(),
+            #[cfg(all(unix, feature = "mmap"))]
+            Self::Pooled(data) => data.ptr(),
+            Self::Shared(data, id) => data.activate(*id),
+        }
+    }
+
+    fn is_shared(&self) -> bool {
+        match self {
+            Self::Heap(data) => Arc::strong_count(data) > 1,
+            #[cfg(all(unix, feature = "mmap"))]
+            Self::Pooled(data) => data.ref_count() > 1,
+            Self::Shared(..) => false,
+        }
+    }
+
+    fn ptr_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Heap(a), Self::Heap(b)) => Arc::ptr_eq(a, b),
+            #[cfg(all(unix, feature = "mmap"))]
+            (Self::Pooled(a), Self::Pooled(b)) => a.ptr_eq(b),
+            (Self::Shared(a, a_id), Self::Shared(b, b_id)) => Rc::ptr_eq(a, b) && a_id == b_id,
+            _ => false,
+        }
+    }
+
+    /// Gets a mutable reference to the data, copying it first if it is shared (see
+    /// [Arc::make_mut]).
+    #[inline(always)]
+    fn make_mut(&mut self) -> &mut PageData {
+        #[cfg(all(unix, feature = "mmap"))]
+        if let Self::Pooled(data) = self {
+            if data.ref_count() > 1 {
+                // Safety: the existing data is not modified while it is being copied.
+                let existing = unsafe { data.ptr().as_ref() };
+                *self = match data.pool().alloc() {
+                    Some(copy) => {
+                        // Safety: the new slot is not referenced by any other page.
+                        unsafe { copy.ptr().as_ptr().copy_from_nonoverlapping(existing, 1) };
+                        Self::Pooled(copy)
+                    }
+                    None => Self::Heap(Arc::new(existing.clone())),
+                };
+            }
+        }
+
+        match self {
+            Self::Heap(data) => Arc::make_mut(data),
+            // Safety: the data is not shared with any other page.
+            #[cfg(all(unix, feature = "mmap"))]
+            Self::Pooled(data) => unsafe { data.ptr().as_mut() },
+            // Safety: shared memory is only accessed from a single thread, and there are no
+            // active references to the data (see `Page::data`).
+            Self::Shared(data, id) => unsafe { &mut *data.activate(*id).as_ptr() },
+        }
     }
 }
 
@@ -411,6 +905,23 @@ impl PageData {
         Ok(buf)
     }
 
+    /// Reads `N` bytes from `addr` without requiring the bytes to be initialized, returning the
+    /// bytes and a mask where bit `i` is set if byte `i` is initialized.
+    #[inline(always)]
+    pub fn read_allow_uninit<const N: usize>(
+        &self,
+        addr: u64,
+        perm: u8,
+    ) -> MemResult<([u8; N], u64)> {
+        let value = self.read::<N>(addr, perm & !perm::INIT)?;
+        let offset = PageData::offset(addr);
+        let mut mask = 0;
+        for (i, byte) in self.perm[offset..offset + N].iter().enumerate() {
+            mask |= ((byte & perm::INIT != 0) as u64) << i;
+        }
+        Ok((value, mask))
+    }
+
     #[inline(always)]
     pub fn write<const N: usize>(&mut self, addr: u64, value: [u8; N], perm: u8) -> MemResult<()> {
         assert!([1, 2, 4, 8, 16].contains(&N));
@@ -433,6 +944,34 @@ impl PageData {
 
         Ok(())
     }
+
+    /// Reads the bytes at `addr` into `buf` checking that every byte has `perm`, without requiring
+    /// `addr` to be aligned. The bytes must not extend past the end of the page.
+    #[inline]
+    pub fn read_slice(&self, addr: u64, buf: &mut [u8], perm: u8) -> MemResult<()> {
+        let offset = PageData::offset(addr);
+        assert!(offset + buf.len() <= PAGE_SIZE);
+        // Safety: we checked that `offset..offset + buf.len()` is in-bounds.
+        unsafe { perm::check(self.get_perm_unchecked(offset, buf.len()), perm | perm::MAP)? };
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    /// Writes `value` to `addr` checking that every byte has `perm`, without requiring `addr` to be
+    /// aligned. The bytes must not extend past the end of the page. Memory is only modified if
+    /// every byte passes the check.
+    #[inline]
+    pub fn write_slice(&mut self, addr: u64, value: &[u8], perm: u8) -> MemResult<()> {
+        let offset = PageData::offset(addr);
+        assert!(offset + value.len() <= PAGE_SIZE);
+        // Safety: we checked that `offset..offset + value.len()` is in-bounds.
+        unsafe {
+            perm::check(self.get_perm_unchecked(offset, value.len()), perm | perm::MAP)?;
+            self.add_perm_unchecked(offset, value.len(), perm::INIT);
+        }
+        self.data[offset..offset + value.len()].copy_from_slice(value);
+        Ok(())
+    }
 }
 
 #[repr(transparent)]
@@ -459,6 +998,18 @@ impl PageRef {
         self.ptr.as_ref().read::<N>(addr, perm)
     }
 
+    /// # Safety
+    ///
+    /// The underlying pointer must be valid.
+    #[inline]
+    pub unsafe fn read_allow_uninit<const N: usize>(
+        &self,
+        addr: u64,
+        perm: u8,
+    ) -> MemResult<([u8; N], u64)> {
+        self.ptr.as_ref().read_allow_uninit::<N>(addr, perm)
+    }
+
     /// # Safety
     ///
     /// The underlying pointer must be valid.
@@ -471,6 +1022,22 @@ impl PageRef {
     ) -> MemResult<()> {
         self.ptr.as_mut().write::<N>(addr, value, perm)
     }
+
+    /// # Safety
+    ///
+    /// The underlying pointer must be valid.
+    #[inline]
+    pub unsafe fn read_slice(&self, addr: u64, buf: &mut [u8], perm: u8) -> MemResult<()> {
+        self.ptr.as_ref().read_slice(addr, buf, perm)
+    }
+
+    /// # Safety
+    ///
+    /// The underlying pointer must be valid.
+    #[inline]
+    pub unsafe fn write_slice(&mut self, addr: u64, value: &[u8], perm: u8) -> MemResult<()> {
+        self.ptr.as_mut().write_slice(addr, value, perm)
+    }
 }
 
 #[inline(always)]
@@ -1,4 +1,7 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 /// A data structure where a range of integers is mapped to a specific value.
 pub type RangeMap<T> = VecRangeMap<T>;
@@ -383,28 +386,39 @@ fn get_overlapping_range_inclusive(
     }
 }
 
+/// The next value returned by [next_generation], shared by all maps so that maps with different
+/// contents never have the same generation.
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+fn next_generation() -> u64 {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
 pub struct VecRangeMap<T> {
     /// The starting value of all ranges in the map. Note: this is stored in a separate allocation
     /// to `data` to improve the cache locality of starts for the `find_range_before` method.
     starts: Vec<u64>,
     /// The ending address and metadata for each of the ranges.
     data: Vec<(u64, T)>,
+    /// Updated whenever the map may have been modified (see [VecRangeMap::generation]).
+    generation: u64,
 }
 
 impl<T> Default for VecRangeMap<T> {
     fn default() -> Self {
-        Self { starts: vec![], data: vec![] }
+        Self { starts: vec![], data: vec![], generation: next_generation() }
     }
 }
 
 impl<T: Clone> Clone for VecRangeMap<T> {
     fn clone(&self) -> Self {
-        Self { starts: self.starts.clone(), data: self.data.clone() }
+        Self { starts: self.starts.clone(), data: self.data.clone(), generation: self.generation }
     }
 
     fn clone_from(&mut self, source: &Self) {
         self.starts.clone_from(&source.starts);
         self.data.clone_from(&source.data);
+        self.generation = source.generation;
     }
 }
 
@@ -421,6 +435,55 @@ where
     }
 }
 
+/// A range in the serialized form of a [VecRangeMap].
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedRange<T> {
+    start: u64,
+    end: u64,
+    value: T,
+}
+
+/// Range maps are serialized as a sequence of `{ start, end, value }` entries ordered by address,
+/// where `end` is inclusive.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for VecRangeMap<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter().map(|(start, end, value)| SerializedRange {
+            start,
+            end,
+            value,
+        }))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T> serde::Deserialize<'de> for VecRangeMap<T>
+where
+    T: serde::Deserialize<'de> + Clone + Eq,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let mut map = Self::new();
+        for entry in Vec::<SerializedRange<T>>::deserialize(deserializer)? {
+            if entry.end < entry.start {
+                return Err(D::Error::custom(format_args!(
+                    "invalid range: {:#x}..={:#x}",
+                    entry.start, entry.end
+                )));
+            }
+            if let Err(err) = map.insert_inclusive((entry.start, entry.end), entry.value) {
+                let (start, end) = err.overlap;
+                return Err(D::Error::custom(format_args!(
+                    "overlapping range: {start:#x}..={end:#x}"
+                )));
+            }
+        }
+        Ok(map)
+    }
+}
+
 impl<T> VecRangeMap<T> {
     fn start_end(&self, i: usize) -> (u64, u64) {
         (self.starts[i], self.data[i].0)
@@ -441,6 +504,7 @@ impl<T> VecRangeMap<T> {
 
     /// Returns an iterator over all ranges in the map with mutable references to data.
     pub fn iter_mut(&mut self) -> impl Iterator<Item = (u64, u64, &mut T)> {
+        self.generation = next_generation();
         self.starts.iter_mut().zip(&mut self.data).map(|(start, (end, data))| (*start, *end, data))
     }
 }
@@ -454,10 +518,17 @@ where
     }
 
     pub fn clear(&mut self) {
+        self.generation = next_generation();
         self.starts.clear();
         self.data.clear();
     }
 
+    /// Returns a value that is changed whenever the map is modified. Maps with the same generation
+    /// (e.g. a map and an unmodified clone of it) always have the same contents.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     /// Returns the position such that all elements before this position end before `index`.
     #[inline(always)]
     fn lower_bound(&self, index: u64) -> usize {
@@ -507,6 +578,7 @@ where
         (start, end): (u64, u64),
         data: T,
     ) -> Result<(), OverlapError<T>> {
+        self.generation = next_generation();
         let i = self.find_range_before(start);
         let mut merged = false;
 
@@ -580,6 +652,7 @@ where
     }
 
     fn remove_subrange(&mut self, i: usize, overlap: RangeOverlap) -> Option<(T, (u64, u64))> {
+        self.generation = next_generation();
         match overlap {
             RangeOverlap::Partial(overlap_start, overlap_end) => {
                 let start = &mut self.starts[i];
@@ -617,6 +690,7 @@ where
     }
 
     pub fn remove_all_inclusive(&mut self, (target_start, target_end): (u64, u64)) {
+        self.generation = next_generation();
         // Elements before this index end before the target range starts.
         let mut lower_bound = self.lower_bound(target_start);
         // Elements after this index start after the target range ends.
@@ -686,6 +760,7 @@ where
     where
         F: FnMut(u64, u64, &mut Option<T>) -> Result<(), E>,
     {
+        self.generation = next_generation();
         let mut iter = VecRangeSplitIterMut::new(self, range.to_inclusive());
         let result = loop {
             match iter.step(&mut func) {
@@ -1106,3 +1181,19 @@ fn remove_range_all_complex() {
     map.remove_all(0x2000..0x3000);
     assert_eq!(map.iter().collect::<Vec<_>>(), vec![(0x1000, 0x1fff, &1), (0x4000, 0x4fff, &3)]);
 }
+
+#[test]
+fn generation() {
+    let mut map = RangeMap::new();
+    map.insert(0x1000..0x2000, 1).unwrap();
+    let generation = map.generation();
+    assert_eq!(map.clone().generation(), generation);
+    assert_ne!(RangeMap::<i32>::new().generation(), generation);
+
+    map.insert(0x5000..0x6000, 4).unwrap();
+    let inserted = map.generation();
+    assert_ne!(inserted, generation);
+
+    let _ = map.overlapping_mut::<_, ()>(0x1000..0x2000, |_, _, _| Ok(()));
+    assert_ne!(map.generation(), inserted);
+}
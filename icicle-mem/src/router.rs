@@ -0,0 +1,160 @@
+//! Support for systems with multiple independent memory buses.
+
+use crate::{MemError, MemResult, Mmu, RestoreError, Snapshot};
+
+/// Identifies a bus domain registered with a [BusRouter].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DomainId(usize);
+
+/// A snapshot of every domain in a [BusRouter].
+pub struct BusSnapshot {
+    domains: Vec<Snapshot>,
+}
+
+/// Owns the memory for multiple independent bus domains, with regions of memory that can be shared
+/// between domains.
+///
+/// Each domain has its own [Mmu], and shared regions are backed by the same physical memory in
+/// every domain they are mapped in (see [Mmu::share_region]), so any write to a shared region
+/// (including writes made directly to the [Mmu] of a domain) is immediately visible to every other
+/// domain. Each domain has its own permissions for a shared region.
+#[derive(Default)]
+pub struct BusRouter {
+    domains: Vec<(String, Mmu)>,
+}
+
+impl BusRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new domain with an empty address space.
+    pub fn add_domain(&mut self, name: impl Into<String>) -> DomainId {
+        self.domains.push((name.into(), Mmu::new()));
+        DomainId(self.domains.len() - 1)
+    }
+
+    /// Finds the domain registered with `name`.
+    pub fn get_domain_id(&self, name: &str) -> Option<DomainId> {
+        self.domains.iter().position(|(x, _)| x == name).map(DomainId)
+    }
+
+    /// Gets the name the domain was registered with.
+    pub fn domain_name(&self, id: DomainId) -> &str {
+        &self.domains[id.0].0
+    }
+
+    /// Gets a reference to the memory of a domain.
+    pub fn domain(&self, id: DomainId) -> &Mmu {
+        &self.domains[id.0].1
+    }
+
+    /// Gets a mutable reference to the memory of a domain.
+    pub fn domain_mut(&mut self, id: DomainId) -> &mut Mmu {
+        &mut self.domains[id.0].1
+    }
+
+    /// Makes the `len` bytes starting at `addr_a` in `domain_a` visible at `addr_b` in `domain_b`.
+    ///
+    /// The region must be mapped in `domain_a` and not mapped in `domain_b`, and the addresses and
+    /// `len` must be page aligned (see [Mmu::share_region] for the errors that are returned). Both
+    /// regions must be in different domains ([MemError::Unsupported] is returned otherwise).
+    pub fn share_region(
+        &mut self,
+        domain_a: DomainId,
+        addr_a: u64,
+        domain_b: DomainId,
+        addr_b: u64,
+        len: u64,
+    ) -> MemResult<()> {
+        let (a, b) = match domain_a.0.cmp(&domain_b.0) {
+            std::cmp::Ordering::Less => {
+                let (lower, upper) = self.domains.split_at_mut(domain_b.0);
+                (&mut lower[domain_a.0].1, &mut upper[0].1)
+            }
+            std::cmp::Ordering::Greater => {
+                let (lower, upper) = self.domains.split_at_mut(domain_a.0);
+                (&mut upper[0].1, &mut lower[domain_b.0].1)
+            }
+            std::cmp::Ordering::Equal => return Err(MemError::Unsupported),
+        };
+        b.share_region(addr_b, len, a, addr_a)
+    }
+
+    /// Read bytes from `addr` in `domain` checking that the permissions specified by `perm` are
+    /// set.
+    pub fn read_bytes(
+        &mut self,
+        domain: DomainId,
+        addr: u64,
+        buf: &mut [u8],
+        perm: u8,
+    ) -> MemResult<()> {
+        self.domain_mut(domain).read_bytes(addr, buf, perm)
+    }
+
+    /// Write bytes to `addr` in `domain` checking that the permissions specified by `perm` are set.
+    pub fn write_bytes(
+        &mut self,
+        domain: DomainId,
+        addr: u64,
+        buf: &[u8],
+        perm: u8,
+    ) -> MemResult<()> {
+        self.domain_mut(domain).write_bytes(addr, buf, perm)
+    }
+
+    pub fn read<const N: usize>(
+        &mut self,
+        domain: DomainId,
+        addr: u64,
+        perm: u8,
+    ) -> MemResult<[u8; N]> {
+        self.domain_mut(domain).read(addr, perm)
+    }
+
+    pub fn write<const N: usize>(
+        &mut self,
+        domain: DomainId,
+        addr: u64,
+        value: [u8; N],
+        perm: u8,
+    ) -> MemResult<()> {
+        self.domain_mut(domain).write(addr, value, perm)
+    }
+
+    /// Fill a region of memory in `domain` with `value`.
+    pub fn fill_mem(
+        &mut self,
+        domain: DomainId,
+        addr: u64,
+        count: u64,
+        value: u8,
+    ) -> MemResult<()> {
+        self.domain_mut(domain).fill_mem(addr, count, value)
+    }
+
+    /// Create a snapshot of the memory of every domain.
+    pub fn snapshot(&mut self) -> BusSnapshot {
+        BusSnapshot { domains: self.domains.iter_mut().map(|(_, mmu)| mmu.snapshot()).collect() }
+    }
+
+    /// Restore the memory of every domain that existed when `snapshot` was taken, including the
+    /// data of shared regions (which is not restored when the [Mmu] of a single domain is
+    /// restored).
+    ///
+    /// Fails without modifying any domain if the snapshot of any domain is not compatible with the
+    /// I/O handlers registered with the domain (see [Mmu::restore]).
+    pub fn restore(&mut self, snapshot: &BusSnapshot) -> Result<(), RestoreError> {
+        for ((_, mmu), snapshot) in self.domains.iter().zip(&snapshot.domains) {
+            mmu.validate_snapshot(snapshot)?;
+        }
+        for ((_, mmu), snapshot) in self.domains.iter_mut().zip(&snapshot.domains) {
+            mmu.restore(snapshot.clone())?;
+        }
+        for ((_, mmu), snapshot) in self.domains.iter_mut().zip(&snapshot.domains) {
+            mmu.restore_shared_memory(snapshot);
+        }
+        Ok(())
+    }
+}
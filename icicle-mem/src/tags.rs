@@ -0,0 +1,122 @@
+//! Labels attached to ranges of memory by the harness, e.g. to describe heap allocations or stacks
+//! when triaging a crash (see [crate::Mmu::tag_range]).
+//!
+//! Tags are stored separately from the virtual address space, so they never affect translation or
+//! permissions, and they are not part of the state captured by snapshots.
+
+use std::collections::BTreeMap;
+
+use crate::range_map::RangeMap;
+
+/// A handle to a tag added using [crate::Mmu::tag_range]. Ids are never reused, so tags that were
+/// added later always have a larger id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TagId(u64);
+
+/// The label of a tag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TagLabel {
+    /// A value defined by the harness (e.g. an allocation id).
+    Value(u64),
+
+    /// A description of the range.
+    Name(String),
+}
+
+impl From<u64> for TagLabel {
+    fn from(value: u64) -> Self {
+        Self::Value(value)
+    }
+}
+
+impl From<&str> for TagLabel {
+    fn from(name: &str) -> Self {
+        Self::Name(name.to_owned())
+    }
+}
+
+impl From<String> for TagLabel {
+    fn from(name: String) -> Self {
+        Self::Name(name)
+    }
+}
+
+impl std::fmt::Display for TagLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Value(value) => write!(f, "{value:#x}"),
+            Self::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A label attached to a range of memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tag {
+    /// The handle used to remove the tag.
+    pub id: TagId,
+
+    /// The first address of the range.
+    pub start: u64,
+
+    /// The last address of the range (inclusive).
+    pub end: u64,
+
+    /// The label of the tag.
+    pub label: TagLabel,
+}
+
+/// The tags attached to memory, where every range in `map` stores the ids of the tags that cover
+/// it in ascending order.
+#[derive(Default)]
+pub(crate) struct Tags {
+    entries: BTreeMap<TagId, Tag>,
+    map: RangeMap<Vec<TagId>>,
+    next_id: u64,
+}
+
+impl Tags {
+    pub fn add(&mut self, start: u64, end: u64, label: TagLabel) -> TagId {
+        let id = TagId(self.next_id);
+        self.next_id += 1;
+        let _ = self.map.overlapping_mut::<_, ()>(start..=end, |_, _, entry| {
+            // Note: `id` is larger than any existing id, so the list stays sorted.
+            entry.get_or_insert_with(Vec::new).push(id);
+            Ok(())
+        });
+        self.entries.insert(id, Tag { id, start, end, label });
+        id
+    }
+
+    pub fn remove(&mut self, id: TagId) -> bool {
+        let Some(tag) = self.entries.remove(&id)
+        else {
+            return false;
+        };
+        let _ = self.map.overlapping_mut::<_, ()>(tag.start..=tag.end, |_, _, entry| {
+            if let Some(ids) = entry {
+                ids.retain(|x| *x != id);
+                if ids.is_empty() {
+                    *entry = None;
+                }
+            }
+            Ok(())
+        });
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.map.clear();
+    }
+
+    /// Returns the tags that cover `addr`, ordered by id.
+    pub fn at(&self, addr: u64) -> impl Iterator<Item = &Tag> {
+        self.map.get(addr).into_iter().flatten().filter_map(|id| self.entries.get(id))
+    }
+
+    /// Returns the tags that overlap with `start..=end`, ordered by id.
+    pub fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = &Tag> {
+        self.entries.values().filter(move |tag| tag.start <= end && start <= tag.end)
+    }
+}
@@ -50,6 +50,168 @@ fn write_across_boundary() {
     assert_eq!(&output[..], &payload[..])
 }
 
+#[test]
+fn address_mask() {
+    let mapping = Mapping { perm: perm::READ | perm::WRITE, value: 0x0 };
+
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x0, 0x1000, mapping);
+    mmu.map_memory_len(0xffff_f000, 0x1000, mapping);
+    assert_eq!(mmu.read_u32(0x1_0000_0004, perm::READ), Err(MemError::Unmapped));
+    assert_eq!(mmu.write_u64(0xffff_fffc, 0x1, perm::WRITE), Err(MemError::Unmapped));
+
+    mmu.set_address_mask(0xffff_ffff);
+    assert_eq!(mmu.address_mask(), 0xffff_ffff);
+
+    // Addresses should be truncated.
+    mmu.write_u32(0xffff_fffc_u64.wrapping_add(8), 0x1234_5678, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u32(0x4, perm::READ), Ok(0x1234_5678));
+    assert_eq!(mmu.read_u32(0xdead_0000_0000_0004, perm::READ), Ok(0x1234_5678));
+
+    // Accesses that straddle the end of the address space should wrap around to zero.
+    mmu.write_u64(0xffff_fffc, 0x1122_3344_5566_7788, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u32(0xffff_fffc, perm::READ), Ok(0x5566_7788));
+    assert_eq!(mmu.read_u32(0x0, perm::READ), Ok(0x1122_3344));
+    assert_eq!(mmu.read_u64(0xffff_fffc, perm::READ), Ok(0x1122_3344_5566_7788));
+    assert_eq!(mmu.read_allow_uninit::<8>(0xffff_fffc, perm::READ).unwrap().1, 0xff);
+
+    // Bulk accesses should also wrap.
+    let payload: Vec<u8> = (0..0x40).collect();
+    mmu.write_bytes(0xffff_ffe0, &payload, perm::WRITE).unwrap();
+    let mut output = [0; 0x40];
+    mmu.read_bytes(0xffff_ffe0, &mut output, perm::READ).unwrap();
+    assert_eq!(&output[..], &payload[..]);
+    assert_eq!(mmu.read_u8(0x1f, perm::READ), Ok(0x3f));
+
+    mmu.write_bytes(0xffff_fffe, b"ab", perm::WRITE).unwrap();
+    mmu.write_bytes(0x0, b"c\0", perm::WRITE).unwrap();
+    let mut buf = vec![];
+    assert_eq!(mmu.read_cstr(0x1_ffff_fffe, &mut buf), Ok(0x1));
+    assert_eq!(buf, b"abc");
+
+    // Hooks should see the truncated address.
+    let hooked = std::rc::Rc::new(std::cell::Cell::new(None));
+    let hooked_ref = hooked.clone();
+    mmu.add_read_hook(
+        0x0,
+        0x10,
+        Box::new(move |_: &mut Mmu, addr: u64, _: u8| {
+            hooked_ref.set(Some(addr));
+            None
+        }),
+    );
+    mmu.read_u32(0x1_0000_0008, perm::READ).unwrap();
+    assert_eq!(hooked.get(), Some(0x8));
+}
+
+#[test]
+fn access_across_end_of_address_space() {
+    let mapping = Mapping { perm: perm::READ | perm::WRITE, value: 0x0 };
+    let top = 0xffff_ffff_ffff_e000;
+
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(top, 0x2000, mapping);
+    mmu.map_memory_len(0x0, 0x2000, mapping);
+
+    let writes = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let writes_ref = writes.clone();
+    mmu.add_write_hook(
+        0xffff_ffff_ffff_fff0,
+        0x10,
+        Box::new(move |_: &mut Mmu, addr: u64, value: &[u8]| {
+            writes_ref.borrow_mut().push((addr, value.len()))
+        }),
+    );
+
+    // Bulk accesses starting in the last 16 bytes of the address space.
+    let payload: Vec<u8> = (0..0x30).collect();
+    mmu.write_bytes(u64::MAX - 7, &payload, perm::WRITE).unwrap();
+    let mut output = [0; 0x30];
+    mmu.read_bytes(u64::MAX - 7, &mut output, perm::READ).unwrap();
+    assert_eq!(&output[..], &payload[..]);
+    assert_eq!(mmu.read_u8(u64::MAX, perm::READ), Ok(0x7));
+    assert_eq!(mmu.read_u8(0x0, perm::READ), Ok(0x8));
+
+    let mut output = [0; 16];
+    mmu.read_bytes(u64::MAX - 7, &mut output, perm::READ).unwrap();
+    assert_eq!(&output[..], &payload[..16]);
+    assert_eq!(mmu.read_u64(u64::MAX - 3, perm::READ), Ok(0x0b0a_0908_0706_0504));
+
+    // Only the writes within the range of the hook should have been reported, coalesced per page.
+    let hooked = std::mem::take(&mut *writes.borrow_mut());
+    assert_eq!(hooked, [(u64::MAX - 7, 8), (0x0, 16)]);
+
+    // Multi-page accesses across the end of the address space.
+    let payload: Vec<u8> = (0..0x3000).map(|i| i as u8).collect();
+    mmu.write_bytes(top + 0x800, &payload, perm::WRITE).unwrap();
+    let mut output = vec![0; 0x3000];
+    mmu.read_bytes(top + 0x800, &mut output, perm::READ).unwrap();
+    assert_eq!(output, payload);
+    assert_eq!(mmu.read_u8(0x17ff, perm::READ), Ok(0xff));
+    assert_eq!(mmu.read_u8(0x1800, perm::READ), Ok(0x0));
+    assert_eq!(writes.borrow().as_slice(), [(0xffff_ffff_ffff_fff0, 16), (0x0, 16)]);
+
+    // A hook that ends at the end of the address space should apply to the final page.
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(top, 0x2000, mapping);
+    let hooked = std::rc::Rc::new(std::cell::Cell::new(0));
+    let hooked_ref = hooked.clone();
+    mmu.add_read_hook(
+        top + 0x1000,
+        0x0,
+        Box::new(move |_: &mut Mmu, _: u64, _: u8| {
+            hooked_ref.set(hooked_ref.get() + 1);
+            None
+        }),
+    );
+    for _ in 0..2 {
+        mmu.read_u64(top + 0xff8, perm::READ).unwrap();
+        mmu.read_u64(u64::MAX - 7, perm::READ).unwrap();
+    }
+    assert_eq!(hooked.get(), 2);
+}
+
+#[test]
+fn map_across_end_of_address_space() {
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0x0 };
+    let top = u64::MAX - 0xfff;
+
+    // Mappings that extend past the end of the address space wrap around to zero.
+    let mut mmu = Mmu::new();
+    assert!(mmu.map_memory_len(top, 0x2000, rw));
+    let regions: Vec<_> = mmu.regions().map(|x| (x.start, x.end)).collect();
+    assert_eq!(regions, [(0x0, 0xfff), (top, u64::MAX)]);
+    assert!(!mmu.map_memory_len(u64::MAX, 0x10, rw));
+
+    let payload: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+    mmu.write_bytes(u64::MAX - 0x7f, &payload, perm::WRITE).unwrap();
+    let mut output = vec![0; 0x100];
+    mmu.read_bytes(u64::MAX - 0x7f, &mut output, perm::READ | perm::INIT).unwrap();
+    assert_eq!(output, payload);
+    mmu.peek_bytes(u64::MAX - 0x7f, &mut output).unwrap();
+    assert_eq!(output, payload);
+    assert_eq!(mmu.read_u16(u64::MAX, perm::READ), Ok(0x807f));
+
+    mmu.write_bytes(u64::MAX - 2, b"abcde\0", perm::WRITE).unwrap();
+    let mut buf = vec![];
+    assert_eq!(mmu.read_cstr(u64::MAX - 2, &mut buf), Ok(0x2));
+    assert_eq!(buf, b"abcde");
+
+    // Direct access to a range that wraps is not supported.
+    assert_eq!(mmu.with_slice(u64::MAX, 2, perm::READ, |_| ()), Err(MemError::AddressOverflow));
+    let mut walker = mmu.walk_range(u64::MAX - 0x7f, 0x100, perm::READ);
+    assert!(
+        matches!(walker.next_chunk(), Some(crate::PageChunk::Read { data, .. }) if data.len() == 0x80)
+    );
+    assert!(matches!(walker.next_chunk(), Some(crate::PageChunk::Read { addr: 0x0, .. })));
+    assert!(walker.next_chunk().is_none());
+
+    // Unmapping also wraps.
+    assert!(mmu.unmap_memory_len(u64::MAX, 0x1001));
+    assert_eq!(mmu.read_u8(0x0, perm::READ), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_u8(u64::MAX - 1, perm::READ), Ok(b'b'));
+}
+
 #[test]
 fn memset() {
     let mut mmu = Mmu::new();
@@ -120,6 +282,29 @@ fn permission_checks() {
     assert_eq!(&output[..], &payload[..])
 }
 
+#[test]
+fn write_code_after_data_on_same_page() {
+    let mut mmu = Mmu::new();
+    let rwx = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+    mmu.map_memory_len(0x1000, 0x1000, rwx);
+    mmu.write_bytes(0x1000, &[0x90; 4], perm::NONE).unwrap();
+    assert!(mmu.ensure_executable(0x1000, 4));
+
+    // A write to data on a page containing code must not allow later writes to the code to skip
+    // the self-modifying code check.
+    mmu.write::<4>(0x1800, [0xaa; 4], perm::WRITE).unwrap();
+    assert_eq!(mmu.write::<4>(0x1000, [0xcc; 4], perm::WRITE), Err(MemError::SelfModifyingCode));
+    assert_eq!(mmu.read::<4>(0x1000, perm::EXEC).unwrap(), [0x90; 4]);
+    assert!(mmu.tlb.translate_write(0x1000).is_none());
+
+    // Once the page no longer contains cached code, writes can be cached again.
+    let patch =
+        mmu.with_code_patching(0x1000, 4, |mmu| mmu.write::<4>(0x1000, [0xcc; 4], perm::WRITE));
+    assert_eq!(patch.unwrap().result, Ok(()));
+    mmu.write::<4>(0x1800, [0xbb; 4], perm::WRITE).unwrap();
+    assert!(mmu.tlb.translate_write(0x1000).is_some());
+}
+
 #[test]
 fn unmap() {
     let mut mmu = Mmu::new();
@@ -136,6 +321,91 @@ fn unmap() {
     assert_eq!(&mapping, &[(0x11000, 0x14fff), (0x16000, 0x1efff)]);
 }
 
+#[test]
+#[allow(deprecated)]
+fn unmap_deprecated() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x10000, 0x10000, Mapping { perm: perm::NONE, value: 0xAA });
+    assert!(mmu.unmap_memory(0x12000, 0x14000));
+
+    let mapping: Vec<_> = mmu.get_mapping().iter().map(|(start, end, _)| (start, end)).collect();
+    assert_eq!(&mapping, &[(0x10000, 0x11fff), (0x14000, 0x1ffff)]);
+}
+
+#[test]
+fn unmap_releases_pages() {
+    let mapping = Mapping { perm: perm::READ | perm::WRITE, value: 0x0 };
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x1000, mapping);
+    mmu.write_u8(0x1000, 0x1, perm::WRITE).unwrap();
+    let snapshot = mmu.snapshot();
+
+    for i in 0..10_000_u64 {
+        mmu.map_memory_len(0x10000, 0x4000, mapping);
+        for addr in (0x10000..0x14000).step_by(0x1000) {
+            mmu.write_u64(addr + 0x8, i, perm::WRITE).unwrap();
+        }
+        assert!(mmu.unmap_memory_len(0x10000, 0x4000));
+        assert!(mmu.total_pages() <= 2 + 1 + 4);
+    }
+
+    // Pages shared with a detached mapping (e.g. a thread that shares the address space) should
+    // not be freed.
+    mmu.map_memory_len(0x10000, 0x1000, mapping);
+    mmu.write_u8(0x10000, 0x5, perm::WRITE).unwrap();
+    let thread = mmu.clone_virtual_mapping();
+    assert!(mmu.unmap_memory_len(0x10000, 0x1000));
+    mmu.map_memory_len(0x20000, 0x1000, mapping);
+    mmu.write_u8(0x20000, 0x6, perm::WRITE).unwrap();
+    mmu.restore_virtual_mapping(thread);
+    assert_eq!(mmu.read_u8(0x10000, perm::READ), Ok(0x5));
+
+    // A detached mapping that is never restored should not prevent other pages from being freed.
+    assert!(mmu.unmap_memory_len(0x10000, 0x1000));
+    drop(mmu.take_virtual_mapping());
+    mmu.map_memory_len(0x1000, 0x1000, mapping);
+    mmu.write_u8(0x1000, 0x1, perm::WRITE).unwrap();
+    let pages = mmu.total_pages();
+    for _ in 0..4 {
+        mmu.map_memory_len(0x10000, 0x4000, mapping);
+        for addr in (0x10000..0x14000).step_by(0x1000) {
+            mmu.write_u8(addr, 0x1, perm::WRITE).unwrap();
+        }
+        assert!(mmu.unmap_memory_len(0x10000, 0x4000));
+        assert_eq!(mmu.total_pages(), pages);
+    }
+
+    // Pages that are still referenced by a snapshot should not be affected.
+    mmu.unmap_memory_len(0x1000, 0x1000);
+    for _ in 0..4 {
+        mmu.map_memory_len(0x1000, 0x1000, mapping);
+        mmu.write_u8(0x1000, 0x2, perm::WRITE).unwrap();
+        mmu.unmap_memory_len(0x1000, 0x1000);
+    }
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0x1));
+}
+
+#[test]
+fn unmap_keeps_owned_pages() {
+    let mut mmu = Mmu::new();
+    mmu.poison_on_unmap = true;
+    let index = mmu.alloc_physical(1).unwrap()[0];
+    assert!(mmu.map_physical(0x10000, index));
+    mmu.update_perm(0x10000, 0x1000, perm::READ | perm::WRITE).unwrap();
+    mmu.write_u32(0x10000, 0x11223344, perm::WRITE).unwrap();
+
+    // Pages allocated with `alloc_physical` are owned by the caller, so they should not be freed
+    // (or poisoned) when they are unmapped.
+    assert!(mmu.unmap_memory_len(0x10000, 0x1000));
+    mmu.map_memory_len(0x20000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    mmu.write_u32(0x20000, 0x55667788, perm::WRITE).unwrap();
+    assert_ne!(mmu.get_physical_index(0x20000), Some(index));
+
+    assert!(mmu.map_physical(0x30000, index));
+    assert_eq!(mmu.read_u32(0x30000, perm::READ), Ok(0x11223344));
+}
+
 #[test]
 fn map_partial() {
     let mut mmu = Mmu::new();
@@ -196,6 +466,115 @@ fn unmap_partial() {
     assert_eq!(err, MemError::Unmapped);
 }
 
+#[test]
+fn aliased_physical_pages() {
+    let mut mmu = Mmu::new();
+    let index = mmu.alloc_physical(1).unwrap()[0];
+    assert!(mmu.map_physical(0x10000, index));
+    assert!(mmu.map_physical(0x20000, index));
+    mmu.update_perm(0x10000, 0x1000, perm::READ | perm::WRITE).unwrap();
+
+    // Writes through one alias are visible through the other.
+    for _ in 0..2 {
+        mmu.write_u32(0x10010, 0x11223344, perm::WRITE).unwrap();
+        assert_eq!(mmu.read_u32(0x20010, perm::READ), Ok(0x11223344));
+        mmu.write_u32(0x20010, 0x55667788, perm::WRITE).unwrap();
+        assert_eq!(mmu.read_u32(0x10010, perm::READ), Ok(0x55667788));
+    }
+
+    // Aliases remain coherent after the page is copied on write.
+    let snapshot = mmu.snapshot();
+    mmu.write_u32(0x10010, 0xaabbccdd, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u32(0x20010, perm::READ), Ok(0xaabbccdd));
+    assert_eq!(mmu.get_physical_index(0x10000), mmu.get_physical_index(0x20000));
+
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u32(0x10010, perm::READ), Ok(0x55667788));
+    mmu.write_u32(0x20010, 0x01020304, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u32(0x10010, perm::READ), Ok(0x01020304));
+
+    // Permissions are only cleared once the bytes are unmapped from every alias.
+    let index = mmu.get_physical_index(0x10000).unwrap();
+    assert!(mmu.unmap_memory_len(0x10000, 0x100));
+    assert_eq!(mmu.read_u8(0x10000, perm::READ), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_u8(0x20000, perm::READ), Ok(0));
+    assert!(mmu.unmap_memory_len(0x10100, 0xf00));
+    assert_eq!(mmu.read_u32(0x20010, perm::READ), Ok(0x01020304));
+
+    assert_ne!(mmu.get_physical(index).data().perm[0x0], perm::NONE);
+    assert!(mmu.unmap_memory_len(0x20000, 0x100));
+    let page = mmu.get_physical(index);
+    assert_eq!(page.data().perm[0x0], perm::NONE);
+    assert_ne!(page.data().perm[0x100], perm::NONE);
+
+    assert!(mmu.unmap_memory_len(0x20100, 0xf00));
+    assert!(!mmu.get_physical(index).aliased);
+}
+
+#[test]
+fn map_regions() {
+    use crate::{MapError, MapErrorKind, MemoryMapping};
+
+    let rw: MemoryMapping = Mapping { perm: perm::READ | perm::WRITE, value: 0x0 }.into();
+    let ro: MemoryMapping = Mapping { perm: perm::READ, value: 0xaa }.into();
+
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x8000, 0x1000, rw.clone());
+    let initial = mmu.get_mapping().iter().count();
+
+    // A failure should not map any of the regions.
+    let check_error = |mmu: &mut Mmu, regions: &[(u64, u64, MemoryMapping)], index, kind| {
+        assert_eq!(mmu.map_regions(regions, false), Err(MapError { index, kind }));
+        assert_eq!(mmu.get_mapping().iter().count(), initial);
+    };
+    let regions = [(0x1000, 0x1000, rw.clone()), (0x3000, 0x1000, ro.clone())];
+    check_error(
+        &mut mmu,
+        &[regions[0].clone(), (0x2000, 0, ro.clone())],
+        1,
+        MapErrorKind::InvalidRange,
+    );
+    check_error(
+        &mut mmu,
+        &[regions[0].clone(), (u64::MAX, 2, ro.clone())],
+        1,
+        MapErrorKind::InvalidRange,
+    );
+    check_error(
+        &mut mmu,
+        &[regions[0].clone(), regions[1].clone(), (0x1800, 0x1000, ro.clone())],
+        2,
+        MapErrorKind::OverlapsRegion(0),
+    );
+    check_error(
+        &mut mmu,
+        &[(0x7800, 0x1000, ro.clone()), regions[1].clone()],
+        0,
+        MapErrorKind::OverlapsMapping,
+    );
+
+    mmu.wx_policy = crate::WxPolicy::Deny;
+    let rwx: MemoryMapping =
+        Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 }.into();
+    check_error(
+        &mut mmu,
+        &[regions[0].clone(), (0x2000, 0x10, rwx)],
+        1,
+        MapErrorKind::WriteExecViolation,
+    );
+
+    mmu.map_regions(&regions, false).unwrap();
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0x0));
+    assert_eq!(mmu.read_u8(0x3fff, perm::READ), Ok(0xaa));
+    assert_eq!(mmu.read_u8(0x2000, perm::READ), Err(MemError::Unmapped));
+
+    // Existing mappings are replaced if requested.
+    mmu.write_u8(0x8000, 0x1, perm::WRITE).unwrap();
+    mmu.map_regions(&[(0x7800, 0x1000, ro.clone())], true).unwrap();
+    assert_eq!(mmu.read_u8(0x8000, perm::READ), Ok(0xaa));
+    assert_eq!(mmu.read_u8(0x8800, perm::READ), Ok(0x0));
+}
+
 #[test]
 fn alloc_permissions() {
     let mut mmu = Mmu::new();
@@ -239,6 +618,175 @@ fn alloc_preferred_addr() {
     assert_eq!(alloc3, Ok(0x3000)); // alloc3 is aligned up
 }
 
+#[test]
+fn alloc_policy() {
+    use crate::AllocPolicy;
+
+    let mapping = Mapping { perm: perm::READ, value: 0x0 };
+    let layout = AllocLayout { addr: None, size: 0x1800, align: 0x1000 };
+
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x1000, mapping);
+    mmu.alloc_policy = AllocPolicy::TopDown { ceiling: 0x10000 };
+    assert_eq!(mmu.alloc_memory(layout, mapping), Ok(0xe000));
+    assert_eq!(mmu.alloc_memory(layout, mapping), Ok(0xc000));
+    // A free preferred address should still be used.
+    let preferred = AllocLayout { addr: Some(0x4000), ..layout };
+    assert_eq!(mmu.alloc_memory(preferred, mapping), Ok(0x4000));
+    assert_eq!(mmu.alloc_memory(preferred, mapping), Ok(0xa000));
+
+    // Random placement should be deterministic for a given seed.
+    let random_allocs = |seed| {
+        let mut mmu = Mmu::new();
+        mmu.alloc_policy = AllocPolicy::Random { seed, ceiling: 0x1_0000_0000 };
+        let mut allocs: Vec<u64> =
+            (0..16).map(|_| mmu.alloc_memory(layout, mapping).unwrap()).collect();
+        for addr in &allocs {
+            assert_eq!(addr % 0x1000, 0);
+            assert!(addr + 0x2000 <= 0x1_0000_0000);
+        }
+        let count = allocs.len();
+        allocs.sort_unstable();
+        allocs.dedup();
+        assert_eq!(allocs.len(), count);
+        allocs
+    };
+    assert_eq!(random_allocs(1), random_allocs(1));
+    assert_ne!(random_allocs(1), random_allocs(2));
+
+    // Finding free memory does not advance the generator, and restoring a snapshot replays the
+    // same sequence of allocations.
+    let mut mmu = Mmu::new();
+    mmu.alloc_policy = AllocPolicy::Random { seed: 1, ceiling: 0x1_0000_0000 };
+    mmu.alloc_memory(layout, mapping).unwrap();
+    let snapshot = mmu.snapshot();
+    let next = mmu.find_free_memory(layout).unwrap();
+    assert_eq!(mmu.find_free_memory(layout), Ok(next));
+    let allocs: Vec<_> = (0..4).map(|_| mmu.alloc_memory(layout, mapping).unwrap()).collect();
+    assert_eq!(allocs[0], next);
+    mmu.restore(snapshot).unwrap();
+    let replayed: Vec<_> = (0..4).map(|_| mmu.alloc_memory(layout, mapping).unwrap()).collect();
+    assert_eq!(allocs, replayed);
+
+    // When the space is nearly full the remaining free slot should be found.
+    let mut mmu = Mmu::new();
+    mmu.alloc_policy = AllocPolicy::Random { seed: 1, ceiling: 0x10000 };
+    mmu.map_memory_len(0x0, 0x7000, mapping);
+    mmu.map_memory_len(0x8800, 0x7800, mapping);
+    let layout = AllocLayout { addr: None, size: 0x800, align: 0x800 };
+    let mut allocs: Vec<_> = (0..3).map(|_| mmu.alloc_memory(layout, mapping).unwrap()).collect();
+    allocs.sort_unstable();
+    assert_eq!(allocs, [0x7000, 0x7800, 0x8000]);
+    assert_eq!(mmu.alloc_memory(layout, mapping), Err(MemError::OutOfMemory));
+
+    mmu.alloc_policy = AllocPolicy::TopDown { ceiling: 0x10000 };
+    assert_eq!(mmu.find_free_memory(layout), Err(MemError::OutOfMemory));
+
+    // Exhausting the address space should not overflow.
+    mmu.alloc_policy = AllocPolicy::BottomUp;
+    mmu.map_memory_len(0x10000, u64::MAX - 0xffff, mapping);
+    assert_eq!(mmu.find_free_memory(layout), Err(MemError::OutOfMemory));
+}
+
+#[test]
+fn address_space_limit() {
+    use crate::{AddressSpacePolicy, AllocPolicy, MapError, MapErrorKind};
+
+    let mapping = Mapping { perm: perm::READ, value: 0x0 };
+
+    let mut mmu = Mmu::new();
+    mmu.set_address_space_bits(32);
+    assert_eq!(mmu.address_space_end(), 0xffff_ffff);
+
+    // A layout that is larger than the entire address space can never be allocated.
+    let layout = AllocLayout { addr: None, size: 0x1_0000_0001, align: 0x1000 };
+    assert_eq!(mmu.find_free_memory(layout), Err(MemError::OutOfMemory));
+    let layout = AllocLayout { addr: None, size: 0x1_0000_0000, align: 0x1000 };
+    assert_eq!(mmu.find_free_memory(layout), Ok(0x0));
+
+    // Allocations should fail instead of being placed past the end of the address space.
+    mmu.map_memory_len(0x0, 0xffff_0000, mapping);
+    let layout = AllocLayout { addr: None, size: 0x2_0000, align: 0x1000 };
+    assert_eq!(mmu.alloc_memory(layout, mapping), Err(MemError::OutOfMemory));
+    let layout = AllocLayout { addr: None, size: 0x1000, align: 0x1000 };
+    assert_eq!(mmu.find_free_memory(layout), Ok(0xffff_0000));
+
+    // Aligning the preferred address should not push the allocation past the end.
+    let layout = AllocLayout { addr: Some(0xffff_f001), size: 0x1000, align: 0x1000 };
+    assert_eq!(mmu.find_free_memory(layout), Err(MemError::OutOfMemory));
+    mmu.alloc_policy = AllocPolicy::TopDown { ceiling: u64::MAX };
+    assert_eq!(mmu.find_free_memory(layout), Ok(0xffff_f000));
+    mmu.alloc_policy = AllocPolicy::Random { seed: 1, ceiling: u64::MAX };
+    let addr = mmu.find_free_memory(layout).unwrap();
+    assert!((0xffff_0000..=0xffff_f000).contains(&addr));
+
+    // Mappings past the end of the address space are only rejected if requested.
+    assert!(mmu.map_memory_len(0x1_0000_0000, 0x1000, mapping));
+    mmu.address_space_policy = AddressSpacePolicy::Deny;
+    assert!(!mmu.map_memory_len(0x1_0000_1000, 0x1000, mapping));
+    assert_eq!(
+        mmu.map_regions(
+            &[(0xffff_0000, 0x1000, mapping.into()), (0xffff_f000, 0x2000, mapping.into())],
+            false
+        ),
+        Err(MapError { index: 1, kind: MapErrorKind::OutOfAddressSpace })
+    );
+
+    // The size of the address space should be restored by snapshots.
+    let snapshot = mmu.snapshot();
+    mmu.set_address_space_bits(64);
+    let layout = AllocLayout { addr: None, size: 0x2_0000, align: 0x1000 };
+    mmu.alloc_policy = AllocPolicy::BottomUp;
+    assert_eq!(mmu.find_free_memory(layout), Ok(0x1_0000_1000));
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.address_space_end(), 0xffff_ffff);
+    assert_eq!(mmu.find_free_memory(layout), Err(MemError::OutOfMemory));
+}
+
+#[test]
+fn alloc_with_guards() {
+    let mapping = Mapping { perm: perm::READ | perm::WRITE, value: 0x0 };
+    let layout = AllocLayout { addr: Some(0x10000), size: 0x1804, align: 0x10 };
+
+    let mut mmu = Mmu::new();
+    let addr = mmu.alloc_memory_with_guards(layout, mapping, 1, 2).unwrap();
+    assert_eq!(addr, 0x10000);
+
+    // The entire payload should be accessible.
+    mmu.write_u8(addr, 0xaa, perm::WRITE).unwrap();
+    mmu.write_u32(addr + 0x1800, 0xbbbbbbbb, perm::WRITE).unwrap();
+
+    // Accesses that overflow (or underflow) the payload should fault.
+    assert_eq!(mmu.write_u8(addr + 0x1804, 0xcc, perm::WRITE), Err(MemError::Reserved));
+    assert_eq!(mmu.write_u32(addr + 0x1802, 0xcc, perm::WRITE), Err(MemError::Reserved));
+    assert_eq!(mmu.read_u8(addr - 1, perm::READ), Err(MemError::Reserved));
+    assert_eq!(mmu.update_perm(addr + 0x1804, 1, perm::READ), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_u8(0x13fff, perm::NONE), Err(MemError::Reserved));
+
+    // The guard regions should not be handed out to other allocations.
+    let small = AllocLayout { addr: Some(0x10000), size: 0x10, align: 0x10 };
+    assert_eq!(mmu.find_free_memory(small), Ok(0x14000));
+    let next = mmu.alloc_memory_with_guards(small, mapping, 1, 1).unwrap();
+    assert_eq!(next, 0x15000);
+
+    // Freeing the allocation should release the payload and both guards.
+    assert_eq!(mmu.free_memory_with_guards(addr, layout.size), Ok((0xf000, 0x13fff)));
+    let full = AllocLayout { addr: Some(0xf000), size: 0x5000, align: 0x1000 };
+    assert_eq!(mmu.find_free_memory(full), Ok(0xf000));
+    assert_eq!(mmu.free_memory_with_guards(addr, layout.size), Err(MemError::Unmapped));
+
+    // The guards of the other allocation should be unaffected.
+    assert_eq!(mmu.read_u8(next - 1, perm::NONE), Err(MemError::Reserved));
+    assert_eq!(mmu.find_free_memory(small), Ok(0x10000));
+    assert_eq!(mmu.free_memory_with_guards(next, 0x10), Ok((0x14000, 0x16fff)));
+
+    // Alignments larger than a page should extend the guard before the allocation.
+    let aligned = AllocLayout { addr: None, size: 0x1000, align: 0x4000 };
+    let addr = mmu.alloc_memory_with_guards(aligned, mapping, 1, 0).unwrap();
+    assert_eq!(addr, 0x4000);
+    assert_eq!(mmu.free_memory_with_guards(addr, 0x1000), Ok((0x0, 0x4fff)));
+}
+
 #[test]
 fn multiple_instances() {
     let mut instances = [Mmu::new(), Mmu::new(), Mmu::new()];
@@ -269,7 +817,7 @@ fn get_modified_pages() {
     mmu.write_bytes(0x5000, &[0x12, 0x34], perm::NONE).unwrap();
     mmu.write_bytes(0x6000, &[0x12, 0x34], perm::NONE).unwrap();
 
-    let mut modified: Vec<_> = mmu.modified.iter().copied().collect();
+    let mut modified: Vec<_> = mmu.dirty_pages().collect();
     modified.sort_unstable();
 
     eprintln!("modified: {:0x?}", modified);
@@ -298,10 +846,10 @@ fn snapshot_and_restore() {
     // Take a snapshot of the current memory state
     let snapshot1 = mmu.snapshot();
     eprintln!("snapshot1 created");
-    mmu.restore(snapshot1.clone());
+    mmu.restore(snapshot1.clone()).unwrap();
 
     eprintln!("Restore [snapshot1]");
-    mmu.restore(snapshot1.clone());
+    mmu.restore(snapshot1.clone()).unwrap();
     eprintln!("Restore [snapshot1] done");
 
     // Overwrite the values written
@@ -321,7 +869,7 @@ fn snapshot_and_restore() {
     eprintln!("snapshot2 created");
 
     // Restore snapshot1 and test whether the original values have been restored
-    mmu.restore(snapshot1.clone());
+    mmu.restore(snapshot1.clone()).unwrap();
     eprintln!("snapshot1 restored");
 
     eprintln!("after [snapshot1]={:#0x?}", mmu.get_mapping());
@@ -333,7 +881,7 @@ fn snapshot_and_restore() {
     }
 
     // Restore snapshot1 and test whether the original values have been restored
-    mmu.restore(snapshot2.clone());
+    mmu.restore(snapshot2.clone()).unwrap();
     eprintln!("snapshot2 restored");
 
     eprintln!("after [snapshot2]={:#0x?}", mmu.get_mapping());
@@ -361,7 +909,7 @@ fn snapshot_reset_and_restore() {
 
     let after = mmu.snapshot();
 
-    mmu.restore(before);
+    mmu.restore(before).unwrap();
 
     let mut out = [0; 6];
     mmu.read_bytes(0x1000, &mut out, perm::NONE).unwrap();
@@ -370,7 +918,7 @@ fn snapshot_reset_and_restore() {
     assert_eq!(&out, b"before");
 
     mmu.reset();
-    mmu.restore(after);
+    mmu.restore(after).unwrap();
 
     let mut out = [0; 6];
     mmu.read_bytes(0x1000, &mut out, perm::NONE).unwrap();
@@ -484,3 +1032,5369 @@ fn boundary_init() {
     let second = mmu.read::<1>(0x1001, perm::NONE).unwrap()[0];
     assert_eq!(second, 0xaa);
 }
+
+#[test]
+fn snapshot_and_restore_range() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x3000, Mapping { perm: perm::NONE, value: 0 });
+    mmu.write_bytes(0x1000, b"before", perm::NONE).unwrap();
+    mmu.write_bytes(0x2000, b"before", perm::NONE).unwrap();
+    mmu.write_bytes(0x3000, b"before", perm::NONE).unwrap();
+
+    let snapshot = mmu.snapshot_range(0x2000, 0x1000);
+
+    mmu.write_bytes(0x1000, b"after ", perm::NONE).unwrap();
+    mmu.write_bytes(0x2000, b"after ", perm::NONE).unwrap();
+    mmu.write_bytes(0x3000, b"after ", perm::NONE).unwrap();
+
+    mmu.restore_range(&snapshot).unwrap();
+
+    let mut out = [0; 6];
+    mmu.read_bytes(0x1000, &mut out, perm::NONE).unwrap();
+    assert_eq!(&out, b"after ");
+    mmu.read_bytes(0x2000, &mut out, perm::NONE).unwrap();
+    assert_eq!(&out, b"before");
+    mmu.read_bytes(0x3000, &mut out, perm::NONE).unwrap();
+    assert_eq!(&out, b"after ");
+
+    // Check that restoring again after the page has been restored works correctly.
+    mmu.write_bytes(0x2000, b"after ", perm::NONE).unwrap();
+    mmu.restore_range(&snapshot).unwrap();
+    mmu.read_bytes(0x2000, &mut out, perm::NONE).unwrap();
+    assert_eq!(&out, b"before");
+}
+
+#[test]
+fn snapshot_and_restore_sub_page_range() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0xaa });
+    mmu.write_bytes(0x1100, &[0x1; 0x10], perm::NONE).unwrap();
+
+    let snapshot = mmu.snapshot_range(0x1104, 0x4);
+    mmu.write_bytes(0x1100, &[0x2; 0x10], perm::NONE).unwrap();
+    mmu.update_perm(0x1100, 0x10, perm::READ).unwrap();
+    mmu.restore_range(&snapshot).unwrap();
+
+    let mut out = [0; 0x10];
+    mmu.read_bytes(0x1100, &mut out, perm::NONE).unwrap();
+    assert_eq!(out, [2, 2, 2, 2, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2]);
+
+    assert_eq!(mmu.get_perm(0x1103) & perm::WRITE, 0);
+    assert_ne!(mmu.get_perm(0x1104) & perm::WRITE, 0);
+    assert_eq!(mmu.get_perm(0x1108) & perm::WRITE, 0);
+
+    // Restoring a range that was never written to, should restore the original value.
+    let snapshot = mmu.snapshot_range(0x1800, 0x10);
+    mmu.write_bytes(0x1800, &[0x3; 0x10], perm::NONE).unwrap();
+    mmu.restore_range(&snapshot).unwrap();
+    mmu.read_bytes(0x1800, &mut out, perm::NONE).unwrap();
+    assert_eq!(out, [0xaa; 0x10]);
+}
+
+#[test]
+fn restore_range_after_unmap() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x2000, Mapping { perm: perm::NONE, value: 0 });
+    mmu.write_bytes(0x1000, b"before", perm::NONE).unwrap();
+
+    let snapshot = mmu.snapshot_range(0x1000, 0x2000);
+    mmu.write_bytes(0x1000, b"after ", perm::NONE).unwrap();
+    mmu.unmap_memory_len(0x2000, 0x1000);
+    assert_eq!(mmu.restore_range(&snapshot), Err(MemError::Unmapped));
+
+    // A failed restore should not modify memory.
+    let mut out = [0; 6];
+    mmu.read_bytes(0x1000, &mut out, perm::NONE).unwrap();
+    assert_eq!(&out, b"after ");
+
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x2000, 0x1000, io);
+    assert_eq!(mmu.restore_range(&snapshot), Err(MemError::Unmapped));
+}
+
+#[test]
+fn restore_range_over_code() {
+    let mut mmu = Mmu::new();
+    mmu.self_modifying_code = crate::SelfModifyingCode::Fault;
+    let rwx = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+    mmu.map_memory_len(0x1000, 0x2000, rwx);
+    mmu.write_bytes(0x1000, b"before", perm::NONE).unwrap();
+    mmu.write_bytes(0x2000, b"before", perm::NONE).unwrap();
+
+    let snapshot = mmu.snapshot_range(0x1000, 0x2000);
+    mmu.write_bytes(0x1000, b"after ", perm::NONE).unwrap();
+    mmu.write_bytes(0x2000, b"after ", perm::NONE).unwrap();
+    assert!(mmu.ensure_executable(0x2000, 6));
+
+    // Restoring fails because it would modify the translated code, and should not modify any other
+    // part of memory.
+    assert_eq!(mmu.restore_range(&snapshot), Err(MemError::SelfModifyingCode));
+    let mut out = [0; 6];
+    mmu.read_bytes(0x1000, &mut out, perm::NONE).unwrap();
+    assert_eq!(&out, b"after ");
+    mmu.read_bytes(0x2000, &mut out, perm::NONE).unwrap();
+    assert_eq!(&out, b"after ");
+}
+
+#[test]
+fn dirty_pages() {
+    fn dirty_addrs(mmu: &Mmu) -> Vec<(u64, bool)> {
+        let mut pages: Vec<_> =
+            mmu.iter_dirty_pages().map(|x| (x.addr, x.data.is_some())).collect();
+        pages.sort_unstable();
+        pages
+    }
+
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x4000, Mapping { perm: perm::NONE, value: 0 });
+
+    mmu.write_bytes(0x1000, b"first", perm::NONE).unwrap();
+    mmu.write_bytes(0x2000, b"first", perm::NONE).unwrap();
+    mmu.write_bytes(0x3000, b"first", perm::NONE).unwrap();
+    assert_eq!(dirty_addrs(&mmu), [(0x1000, true), (0x2000, true), (0x3000, true)]);
+
+    let _ = mmu.snapshot();
+    mmu.clear_page_modification_log();
+    assert_eq!(dirty_addrs(&mmu), []);
+
+    // Rewriting a page that was modified before the log was cleared should mark it as dirty again.
+    mmu.write_bytes(0x2000, b"second", perm::NONE).unwrap();
+    mmu.write_bytes(0x4000, b"second", perm::NONE).unwrap();
+    assert_eq!(dirty_addrs(&mmu), [(0x2000, true), (0x4000, true)]);
+
+    let page = mmu.iter_dirty_pages().find(|x| x.addr == 0x2000).unwrap();
+    assert_eq!(&page.data.unwrap().data[..6], b"second");
+
+    // Modified pages that are unmapped should still be reported.
+    mmu.unmap_memory_len(0x4000, 0x1000);
+    assert_eq!(dirty_addrs(&mmu), [(0x2000, true), (0x4000, false)]);
+
+    assert_eq!(mmu.drain_dirty_pages().count(), 2);
+    assert_eq!(dirty_addrs(&mmu), []);
+
+    mmu.write_bytes(0x2000, b"third", perm::NONE).unwrap();
+    assert_eq!(dirty_addrs(&mmu), [(0x2000, true)]);
+}
+
+#[test]
+fn bus_router_shared_region() {
+    let mut router = crate::BusRouter::new();
+    let app = router.add_domain("app");
+    let cop = router.add_domain("coprocessor");
+    assert_eq!(router.get_domain_id("coprocessor"), Some(cop));
+
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    router.domain_mut(app).map_memory_len(0x2000_0000, 0x4000, rw);
+    router.domain_mut(cop).map_memory_len(0x4000, 0x1000, rw);
+    router.domain_mut(app).write_bytes(0x2000_1000, b"initial", perm::NONE).unwrap();
+
+    assert_eq!(router.share_region(app, 0x2000_1000, app, 0x0, 0x2000), Err(MemError::Unsupported));
+    assert_eq!(
+        router.share_region(app, 0x2000_1000, cop, 0x4000, 0x2000),
+        Err(MemError::AlreadyMapped)
+    );
+
+    // Share the second and third pages of the application core's SRAM with the coprocessor.
+    router.share_region(app, 0x2000_1000, cop, 0x0, 0x2000).unwrap();
+
+    let mut out = [0; 7];
+    router.read_bytes(cop, 0x0, &mut out, perm::READ).unwrap();
+    assert_eq!(&out, b"initial");
+
+    // Writes from the application core should be visible to the coprocessor.
+    router.write_bytes(app, 0x2000_1ffe, b"crossing", perm::WRITE).unwrap();
+    let mut out = [0; 8];
+    router.read_bytes(cop, 0xffe, &mut out, perm::READ).unwrap();
+    assert_eq!(&out, b"crossing");
+
+    // Including writes made directly to the memory of a domain after it has been accessed.
+    assert_eq!(router.read::<4>(app, 0x2000_1000, perm::READ).unwrap(), *b"init");
+    router.domain_mut(cop).write_u32(0x0, 0x1234_5678, perm::WRITE).unwrap();
+    assert_eq!(router.domain_mut(app).read_u32(0x2000_1000, perm::READ), Ok(0x1234_5678));
+
+    // Memory outside of the shared region is not shared.
+    router.write_bytes(app, 0x2000_0000, b"private", perm::WRITE).unwrap();
+    router.write_bytes(app, 0x2000_3000, b"private", perm::WRITE).unwrap();
+    assert_eq!(router.read::<4>(cop, 0x0, perm::READ).unwrap(), 0x1234_5678_u32.to_le_bytes());
+    assert_eq!(router.read::<4>(cop, 0x4000, perm::READ).unwrap(), [0; 4]);
+
+    router.fill_mem(cop, 0x1000, 0x10, 0xff).unwrap();
+    assert_eq!(router.read::<4>(app, 0x2000_2000, perm::READ).unwrap(), [0xff; 4]);
+
+    // Each domain has its own permissions for the shared region.
+    router.domain_mut(cop).update_perm(0x0, 0x1000, perm::READ).unwrap();
+    assert_eq!(router.write::<4>(cop, 0x0, [1; 4], perm::WRITE), Err(MemError::WriteViolation));
+    router.write::<4>(app, 0x2000_1000, [2; 4], perm::WRITE).unwrap();
+    assert_eq!(router.read::<4>(cop, 0x0, perm::READ).unwrap(), [2; 4]);
+    assert_eq!(router.write::<4>(cop, 0x0, [1; 4], perm::WRITE), Err(MemError::WriteViolation));
+
+    router.domain_mut(app).update_perm(0x2000_2000, 0x1000, perm::NONE).unwrap();
+    assert!(router.read::<4>(app, 0x2000_2000, perm::READ).is_err());
+    assert_eq!(router.read::<4>(cop, 0x1000, perm::READ).unwrap(), [0xff; 4]);
+}
+
+#[test]
+fn bus_router_snapshot() {
+    let mut router = crate::BusRouter::new();
+    let a = router.add_domain("a");
+    let b = router.add_domain("b");
+
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    router.domain_mut(a).map_memory_len(0x1000, 0x1000, rw);
+    router.share_region(a, 0x1000, b, 0x8000, 0x1000).unwrap();
+
+    router.write_bytes(a, 0x1000, b"before", perm::WRITE).unwrap();
+    router.domain_mut(b).update_perm(0x8800, 0x10, perm::READ).unwrap();
+    let snapshot = router.snapshot();
+
+    // Restoring the memory of a single domain does not modify the data seen by the other domain.
+    let a_snapshot = router.domain_mut(a).snapshot();
+    router.write_bytes(b, 0x8000, b"single", perm::WRITE).unwrap();
+    router.domain_mut(a).restore(a_snapshot).unwrap();
+    let mut out = [0; 6];
+    router.read_bytes(b, 0x8000, &mut out, perm::READ).unwrap();
+    assert_eq!(&out, b"single");
+
+    router.write_bytes(b, 0x8000, b"after ", perm::WRITE).unwrap();
+    router.domain_mut(a).update_perm(0x1800, 0x10, perm::READ).unwrap();
+    router.read_bytes(a, 0x1000, &mut out, perm::READ).unwrap();
+    assert_eq!(&out, b"after ");
+
+    router.restore(&snapshot).unwrap();
+    router.read_bytes(a, 0x1000, &mut out, perm::READ).unwrap();
+    assert_eq!(&out, b"before");
+    router.read_bytes(b, 0x8000, &mut out, perm::READ).unwrap();
+    assert_eq!(&out, b"before");
+
+    // The permissions of each domain are restored independently.
+    assert_eq!(router.write::<1>(a, 0x1800, [0], perm::WRITE), Ok(()));
+    assert_eq!(router.write::<1>(b, 0x8800, [0], perm::WRITE), Err(MemError::WriteViolation));
+}
+
+/// A device that records the address and size of every access, backed by a simple buffer.
+struct RecordingDevice {
+    base: u64,
+    data: Vec<u8>,
+    accesses: Vec<(u64, usize)>,
+}
+
+impl crate::IoMemory for RecordingDevice {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> crate::MemResult<()> {
+        self.accesses.push((addr, buf.len()));
+        let offset = (addr - self.base) as usize;
+        buf.copy_from_slice(&self.data[offset..][..buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, value: &[u8]) -> crate::MemResult<()> {
+        self.accesses.push((addr, value.len()));
+        let offset = (addr - self.base) as usize;
+        self.data[offset..][..value.len()].copy_from_slice(value);
+        Ok(())
+    }
+}
+
+fn take_accesses(mmu: &mut Mmu, handler: crate::IoHandler) -> Vec<(u64, usize)> {
+    let device = mmu.get_io_memory_mut(handler).as_mut_any();
+    std::mem::take(&mut device.downcast_mut::<RecordingDevice>().unwrap().accesses)
+}
+
+fn check_io_boundary<const N: usize>(mmu: &mut Mmu, handler: crate::IoHandler, addr: u64) {
+    let io_range = 0x2000..0x3000;
+
+    let value: [u8; N] = std::array::from_fn(|i| i as u8 + 1);
+    mmu.write(addr, value, perm::WRITE).unwrap();
+    let expected: Vec<_> = match io_range.contains(&addr) {
+        true => vec![(addr, (0x3000 - addr) as usize)],
+        false => vec![(0x2000, (addr + N as u64 - 0x2000) as usize)],
+    };
+    assert_eq!(take_accesses(mmu, handler), expected, "write::<{N}> at {addr:#x}");
+
+    assert_eq!(mmu.read::<N>(addr, perm::READ).unwrap(), value, "read::<{N}> at {addr:#x}");
+    assert_eq!(take_accesses(mmu, handler), expected, "read::<{N}> at {addr:#x}");
+}
+
+#[test]
+fn access_across_io_boundary() {
+    let mut mmu = Mmu::new();
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    mmu.map_memory_len(0x1000, 0x1000, rw);
+    let handler = mmu.register_io_handler(RecordingDevice {
+        base: 0x2000,
+        data: vec![0; 0x1000],
+        accesses: vec![],
+    });
+    mmu.map_memory_len(0x2000, 0x1000, handler);
+    mmu.map_memory_len(0x3000, 0x1000, rw);
+
+    // Make sure that the RAM pages are allocated and in the TLB.
+    mmu.write::<4>(0x1ff0, [0; 4], perm::WRITE).unwrap();
+    mmu.write::<4>(0x3000, [0; 4], perm::WRITE).unwrap();
+
+    // RAM -> IO
+    check_io_boundary::<2>(&mut mmu, handler, 0x1fff);
+    check_io_boundary::<4>(&mut mmu, handler, 0x1ffd);
+    check_io_boundary::<8>(&mut mmu, handler, 0x1ffc);
+    check_io_boundary::<16>(&mut mmu, handler, 0x1ff9);
+
+    // IO -> RAM
+    check_io_boundary::<2>(&mut mmu, handler, 0x2fff);
+    check_io_boundary::<4>(&mut mmu, handler, 0x2ffe);
+    check_io_boundary::<8>(&mut mmu, handler, 0x2ffb);
+    check_io_boundary::<16>(&mut mmu, handler, 0x2ff3);
+
+    // The RAM parts of the accesses should have been written to memory.
+    assert_eq!(mmu.read::<4>(0x1ff9, perm::READ).unwrap(), [1, 2, 3, 4]);
+    assert_eq!(mmu.read::<2>(0x3000, perm::READ).unwrap(), [14, 15]);
+
+    // Unaligned accesses that are entirely inside of the device should not be split.
+    mmu.write::<4>(0x2101, [1; 4], perm::WRITE).unwrap();
+    assert_eq!(take_accesses(&mut mmu, handler), [(0x2101, 4)]);
+
+    // In fault mode, accesses that cross the boundary should fail without accessing the device.
+    mmu.fault_on_io_boundary = true;
+    assert_eq!(mmu.read::<4>(0x1ffe, perm::READ), Err(MemError::CrossesDeviceBoundary));
+    assert_eq!(mmu.write::<8>(0x2ffc, [0; 8], perm::WRITE), Err(MemError::CrossesDeviceBoundary));
+    assert_eq!(take_accesses(&mut mmu, handler), []);
+    mmu.fault_on_io_boundary = false;
+
+    // If the RAM part of a write is not writable, then the device should not be written to.
+    mmu.update_perm(0x3000, 0x1000, perm::READ).unwrap();
+    assert_eq!(mmu.write::<4>(0x2ffe, [0; 4], perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(take_accesses(&mut mmu, handler), []);
+}
+
+#[test]
+fn partial_fill_invalidates_tlb() {
+    let mut mmu = Mmu::new();
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    mmu.map_memory_len(0x10000, 0x1000, rw);
+    mmu.map_memory_len(0x12000, 0x1000, rw);
+    for page in [0x10000, 0x12000] {
+        mmu.write_u8(page, 1, perm::WRITE).unwrap();
+        assert_eq!(mmu.read_u8(page, perm::READ), Ok(1));
+        assert!(mmu.tlb.translate_read(page).is_some());
+    }
+
+    // The fill fails at the unmapped page between the two mapped pages, after one of them has
+    // already been filled. The filled page must not remain in the TLB.
+    assert_eq!(mmu.fill_mem(0x10000, 0x3000, 0xaa), Err(MemError::Unmapped));
+    let mut filled = 0;
+    for page in [0x10000, 0x12000] {
+        let cached =
+            mmu.tlb.translate_read(page).is_some() || mmu.tlb.translate_write(page).is_some();
+        if mmu.read_u8(page, perm::NONE) == Ok(0xaa) {
+            assert!(!cached, "stale TLB entry for {page:#x}");
+            filled += 1;
+        }
+    }
+    assert_eq!(filled, 1);
+}
+
+#[test]
+fn fill_across_io_region() {
+    let mut mmu = Mmu::new();
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    mmu.map_memory_len(0x1000, 0x1003, rw);
+    let handler = mmu.register_io_handler(RecordingDevice {
+        base: 0x2000,
+        data: vec![0; 0x1000],
+        accesses: vec![],
+    });
+    assert!(mmu.map_memory_len(0x2003, 0xd, handler));
+    mmu.map_memory_len(0x2010, 0xff0, rw);
+    mmu.write::<4>(0x1ff0, [0; 4], perm::WRITE).unwrap();
+
+    mmu.fill_mem(0x1ff0, 0x30, 0xab).unwrap();
+    assert_eq!(take_accesses(&mut mmu, handler), [(0x2003, 1), (0x2004, 4), (0x2008, 8)]);
+
+    let mut buf = [0; 0x30];
+    mmu.read_bytes(0x1ff0, &mut buf, perm::READ).unwrap();
+    assert_eq!(buf, [0xab; 0x30]);
+    assert_eq!(take_accesses(&mut mmu, handler), [(0x2003, 0xd)]);
+
+    // Handlers can refuse to be filled.
+    struct NoFill;
+    impl crate::IoMemory for NoFill {
+        fn read(&mut self, _: u64, _: &mut [u8]) -> crate::MemResult<()> {
+            Ok(())
+        }
+        fn write(&mut self, _: u64, _: &[u8]) -> crate::MemResult<()> {
+            Ok(())
+        }
+        fn fill(&mut self, _: u64, _: u64, _: u8) -> crate::MemResult<()> {
+            Err(MemError::Unsupported)
+        }
+    }
+    let handler = mmu.register_io_handler(NoFill);
+    assert!(mmu.map_memory_len(0x3000, 0x10, handler));
+    assert_eq!(mmu.fill_mem(0x2ff0, 0x20, 0), Err(MemError::Unsupported));
+}
+
+/// A device with a single register that holds a value which is captured by snapshots.
+struct Register(u8);
+
+impl crate::IoMemory for Register {
+    fn read(&mut self, _: u64, buf: &mut [u8]) -> crate::MemResult<()> {
+        buf.fill(self.0);
+        Ok(())
+    }
+
+    fn write(&mut self, _: u64, value: &[u8]) -> crate::MemResult<()> {
+        self.0 = value[0];
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> Box<dyn std::any::Any> {
+        Box::new(self.0)
+    }
+
+    fn restore(&mut self, snapshot: &Box<dyn std::any::Any>) {
+        self.0 = *snapshot.downcast_ref::<u8>().unwrap();
+    }
+
+    fn reset(&mut self) {
+        self.0 = 0;
+    }
+}
+
+#[test]
+fn unregister_io_handler() {
+    let mut mmu = Mmu::new();
+    let a = mmu.register_io_handler(Register(1));
+    let b = mmu.register_io_handler(Register(2));
+    mmu.map_memory_len(0x1000, 0x10, a);
+    mmu.map_memory_len(0x2000, 0x10, b);
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(1));
+    assert_eq!(mmu.read_u8(0x2000, perm::READ), Ok(2));
+    let snapshot = mmu.snapshot();
+
+    // Handlers that are still mapped can not be unregistered.
+    assert!(mmu.unregister_io_handler(a).is_none());
+    mmu.unmap_memory_len(0x1000, 0x10);
+    let device = mmu.unregister_io_handler(a).unwrap();
+    assert_eq!(device.as_any().downcast_ref::<Register>().unwrap().0, 1);
+    assert!(mmu.unregister_io_handler(a).is_none());
+
+    // Identifiers are not reused, and other handlers are unaffected.
+    let c = mmu.register_io_handler(Register(3));
+    mmu.map_memory_len(0x3000, 0x10, c);
+    mmu.write_u8(0x2000, 4, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u8(0x3000, perm::READ), Ok(3));
+
+    // Restoring a snapshot taken before the handler was unregistered.
+    mmu.restore(snapshot.clone()).unwrap();
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Err(MemError::Unmapped));
+    assert_eq!(mmu.write_u8(0x1000, 0, perm::WRITE), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_u8(0x2000, perm::READ), Ok(2));
+    assert_eq!(mmu.read_u8(0x3000, perm::READ), Err(MemError::Unmapped));
+    mmu.snapshot();
+
+    // Replaced handlers are used by existing mappings and restored from earlier snapshots.
+    let old = mmu.replace_io_handler(b, Register(5)).unwrap();
+    assert_eq!(old.as_any().downcast_ref::<Register>().unwrap().0, 2);
+    assert_eq!(mmu.read_u8(0x2000, perm::READ), Ok(5));
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u8(0x2000, perm::READ), Ok(2));
+}
+
+#[test]
+fn restore_validates_io_handlers() {
+    use crate::RestoreError;
+
+    let mut mmu = Mmu::new();
+    let a = mmu.register_io_handler(Register(1));
+    mmu.map_memory_len(0x1000, 0x10, a);
+    let snapshot = mmu.snapshot();
+
+    // Handlers registered after the snapshot was taken are reset.
+    let b = mmu.register_io_handler(Register(2));
+    mmu.map_memory_len(0x2000, 0x10, b);
+    mmu.write_u8(0x1000, 3, perm::WRITE).unwrap();
+    assert_eq!(mmu.try_restore(snapshot.clone()), Ok(()));
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(1));
+    assert_eq!(mmu.io[b.0].as_ref().unwrap().as_any().downcast_ref::<Register>().unwrap().0, 0);
+
+    // Handlers registered in a different order are rejected without modifying any state.
+    let mut other = Mmu::new();
+    other.register_io_handler(ReadCounter(0));
+    other.register_io_handler(Register(4));
+    other.map_memory_len(0x1000, 0x10, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    match other.try_restore(snapshot.clone()) {
+        Err(RestoreError::HandlerMismatch { handler, expected, found }) => {
+            assert_eq!(handler.0, 0);
+            assert_eq!(expected.type_id, std::any::TypeId::of::<Register>());
+            assert_eq!(found.type_id, std::any::TypeId::of::<ReadCounter>());
+        }
+        result => panic!("unexpected result: {result:?}"),
+    }
+    assert_eq!(other.read_u8(0x1000, perm::READ), Ok(0));
+
+    // Snapshots that contain handlers that were never registered are rejected.
+    let mut empty = Mmu::new();
+    assert_eq!(empty.try_restore(snapshot), Err(RestoreError::MissingHandler(a)));
+
+    // Handlers with the same type are distinguished by name.
+    let mut named = Mmu::new();
+    named.register_named_io_handler("uart", Register(5));
+    let snapshot = named.snapshot();
+    let mut renamed = Mmu::new();
+    renamed.register_named_io_handler("timer", Register(5));
+    let result = renamed.try_restore(snapshot.clone());
+    assert!(matches!(result, Err(RestoreError::HandlerMismatch { .. })));
+    named.restore(snapshot).unwrap();
+}
+
+#[test]
+fn io_snapshot_policy() {
+    use crate::{RestoreError, SnapshotPolicy};
+
+    let mut mmu = Mmu::new();
+    let timer = mmu.register_io_handler_with_policy(Register(1), SnapshotPolicy::Include);
+    let console = mmu.register_io_handler_with_policy(Register(2), SnapshotPolicy::Exclude);
+    let irq = mmu.register_io_handler(Register(3));
+    for (i, handler) in [timer, console, irq].into_iter().enumerate() {
+        mmu.map_memory_len(0x1000 * (i as u64 + 1), 0x10, handler);
+    }
+    let value = |mmu: &Mmu, handler: crate::IoHandler| {
+        mmu.io[handler.0].as_ref().unwrap().as_any().downcast_ref::<Register>().unwrap().0
+    };
+
+    let snapshot = mmu.snapshot();
+    assert!(snapshot.io[console.0].is_none());
+    for round in 0..3 {
+        mmu.write_u8(0x1000, 0x10 + round, perm::WRITE).unwrap();
+        mmu.write_u8(0x2000, 0x20 + round, perm::WRITE).unwrap();
+        mmu.write_u8(0x3000, 0x30 + round, perm::WRITE).unwrap();
+        mmu.restore(snapshot.clone()).unwrap();
+
+        // Only the excluded handler keeps the value written after the snapshot was taken.
+        assert_eq!([timer, console, irq].map(|x| value(&mmu, x)), [1, 0x20 + round, 3]);
+    }
+
+    // Handlers registered after the snapshot was taken do not change the position of the existing
+    // handlers, and excluded handlers are not reset.
+    let log = mmu.register_io_handler_with_policy(Register(4), SnapshotPolicy::Exclude);
+    let uart = mmu.register_io_handler(Register(5));
+    mmu.restore(snapshot.clone()).unwrap();
+    assert_eq!([timer, console, irq, log, uart].map(|x| value(&mmu, x)), [1, 0x22, 3, 4, 0]);
+
+    mmu.map_memory_len(0x4000, 0x10, log);
+    mmu.map_memory_len(0x5000, 0x10, uart);
+    let second = mmu.snapshot();
+    mmu.write_u8(0x2000, 0x23, perm::WRITE).unwrap();
+    mmu.write_u8(0x4000, 0x40, perm::WRITE).unwrap();
+    mmu.write_u8(0x5000, 0x50, perm::WRITE).unwrap();
+    mmu.restore(second).unwrap();
+    assert_eq!([timer, console, irq, log, uart].map(|x| value(&mmu, x)), [1, 0x23, 3, 0x40, 0]);
+
+    // Restoring to handlers registered with a different policy is rejected.
+    let mut other = Mmu::new();
+    other.register_io_handler(Register(1));
+    other.register_io_handler(Register(2));
+    other.register_io_handler(Register(3));
+    assert_eq!(
+        other.try_restore(snapshot),
+        Err(RestoreError::PolicyMismatch {
+            handler: console,
+            expected: SnapshotPolicy::Exclude,
+            found: SnapshotPolicy::Include,
+        })
+    );
+}
+
+/// A device where every read returns the number of reads that have been performed.
+struct ReadCounter(u8);
+
+impl crate::IoMemory for ReadCounter {
+    fn read(&mut self, _: u64, buf: &mut [u8]) -> crate::MemResult<()> {
+        self.0 += 1;
+        buf.fill(self.0);
+        Ok(())
+    }
+
+    fn write(&mut self, _: u64, _: &[u8]) -> crate::MemResult<()> {
+        Ok(())
+    }
+
+    fn peek(&mut self, _: u64, buf: &mut [u8]) -> crate::MemResult<()> {
+        buf.fill(self.0);
+        Ok(())
+    }
+}
+
+#[test]
+fn peek_bytes() {
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    mmu.map_memory_len(0x1000, 0x10, Mapping { perm: perm::NONE, value: 0xaa });
+    let counter = mmu.register_io_handler(ReadCounter(0));
+    mmu.map_memory_len(0x1010, 0x4, counter);
+    mmu.map_memory_len(0x1014, 0x1fec, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    mmu.write_u8(0x1015, 0x11, perm::WRITE).unwrap();
+    let null = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x4000, 0x10, null);
+
+    let mut buf = [0; 8];
+    mmu.peek_bytes(0x100e, &mut buf).unwrap();
+    assert_eq!(buf, [0xaa, 0xaa, 0, 0, 0, 0, 0, 0x11]);
+    mmu.peek_bytes(0x100e, &mut buf).unwrap();
+    assert_eq!(buf, [0xaa, 0xaa, 0, 0, 0, 0, 0, 0x11]);
+
+    // Peeking should not have modified any state.
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Err(MemError::ReadViolation));
+    assert_eq!(mmu.read_u8(0x1016, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    let mut buf = [0; 4];
+    mmu.read_bytes(0x1010, &mut buf, perm::READ).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+    mmu.peek_bytes(0x1010, &mut buf).unwrap();
+    assert_eq!(buf, [4; 4]);
+    mmu.read_bytes(0x1010, &mut buf, perm::READ).unwrap();
+    assert_eq!(buf, [5, 6, 7, 8]);
+
+    assert_eq!(mmu.peek_bytes(0x4000, &mut buf), Err(MemError::Unsupported));
+    assert_eq!(mmu.peek_bytes(0x3ffe, &mut buf), Err(MemError::Unmapped));
+    mmu.peek_bytes(0x2ffe, &mut buf[..2]).unwrap();
+    assert!(mmu.get_physical_index(0x2ffe).is_none());
+}
+
+#[test]
+fn io_trace() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    let device = RecordingDevice { base: 0x2000, data: vec![0; 0x1000], accesses: vec![] };
+    let handler = mmu.register_io_handler(device);
+    mmu.map_memory_len(0x2000, 0x1000, handler);
+    let untraced = mmu.register_io_handler(ReadCounter(0));
+    mmu.map_memory_len(0x3000, 0x10, untraced);
+
+    // Accesses are only traced after tracing is enabled.
+    mmu.write_u32(0x2000, 0x11223344, perm::WRITE).unwrap();
+    assert_eq!(mmu.drain_io_trace().count(), 0);
+
+    mmu.set_io_trace(handler, true);
+    mmu.write_u32(0x2000, 0x11223344, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u16(0x2002, perm::READ).unwrap(), 0x1122);
+    mmu.read_u32(0x3000, perm::READ).unwrap();
+    // Split across the boundary between RAM and the I/O region.
+    mmu.write_u32(0x1ffe, 0xaabbccdd, perm::WRITE).unwrap();
+    mmu.fill_mem(0x2010, 0x4, 0x55).unwrap();
+    let mut buf = [0; 4];
+    mmu.peek_bytes(0x2000, &mut buf).unwrap_err();
+
+    let event = |addr, size, value, is_write, seq| crate::IoTraceEvent {
+        handler,
+        addr,
+        size,
+        value,
+        is_write,
+        seq,
+    };
+    assert_eq!(mmu.drain_io_trace().collect::<Vec<_>>(), vec![
+        event(0x2000, 4, 0x11223344, true, 0),
+        event(0x2002, 2, 0x1122, false, 1),
+        event(0x2000, 2, 0xaabb, true, 2),
+        event(0x2010, 4, 0x55, true, 3),
+    ]);
+    assert_eq!(mmu.drain_io_trace().count(), 0);
+
+    // Only the most recent events are kept once the trace is full.
+    mmu.set_io_trace_capacity(2);
+    for i in 0..4 {
+        mmu.write_u8(0x2000 + i, i as u8, perm::WRITE).unwrap();
+    }
+    let events: Vec<_> = mmu.drain_io_trace().map(|event| (event.addr, event.seq)).collect();
+    assert_eq!(events, [(0x2002, 6), (0x2003, 7)]);
+
+    mmu.set_io_trace(handler, false);
+    mmu.write_u8(0x2000, 0, perm::WRITE).unwrap();
+    assert_eq!(mmu.drain_io_trace().count(), 0);
+}
+
+#[test]
+fn io_record_and_replay() {
+    use std::{
+        cell::RefCell,
+        io::{Cursor, Read, Seek, SeekFrom, Write},
+        rc::Rc,
+    };
+
+    use crate::{IoReplayError, ReplayWritePolicy};
+
+    /// A stream that can be inspected while it is owned by the MMU.
+    #[derive(Clone, Default)]
+    struct SharedStream(Rc<RefCell<Cursor<Vec<u8>>>>);
+
+    impl Write for SharedStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for SharedStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().read(buf)
+        }
+    }
+
+    impl Seek for SharedStream {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.borrow_mut().seek(pos)
+        }
+    }
+
+    // Record reads from a counter, including reads that are rolled back by restoring a snapshot.
+    let mut mmu = Mmu::new();
+    let counter = mmu.register_io_handler(ReadCounter(0));
+    mmu.map_memory_len(0x2000, 0x10, counter);
+    let stream = SharedStream::default();
+    mmu.start_io_recording(stream.clone()).unwrap();
+
+    let mut recorded = vec![];
+    recorded.push(mmu.read_u8(0x2000, perm::READ).unwrap());
+    recorded.push(mmu.read_u8(0x2000, perm::READ).unwrap());
+    let snapshot = mmu.snapshot();
+    mmu.read_u8(0x2004, perm::READ).unwrap();
+    mmu.read_u8(0x2004, perm::READ).unwrap();
+    assert_eq!(mmu.io_stream_position(), 4);
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.io_stream_position(), 2);
+    recorded.push(mmu.read_u8(0x2004, perm::READ).unwrap());
+    recorded.extend(mmu.read_u16(0x2008, perm::READ).unwrap().to_le_bytes());
+    recorded.push(mmu.read_u8(0x200c, perm::READ).unwrap());
+    assert_eq!(recorded, [1, 2, 5, 6, 6, 7]);
+    assert_eq!(mmu.stop_io_recording().unwrap(), 5 * 48);
+    assert!(mmu.stop_io_recording().is_err());
+
+    // Replay the reads against a device that returns garbage.
+    let mut mmu = Mmu::new();
+    let device = RecordingDevice { base: 0x2000, data: vec![0xee; 0x10], accesses: vec![] };
+    let handler = mmu.register_io_handler(device);
+    mmu.map_memory_len(0x2000, 0x10, handler);
+    stream.0.borrow_mut().set_position(0);
+    mmu.start_io_replay(stream.clone()).unwrap();
+
+    let mut replayed = vec![];
+    replayed.push(mmu.read_u8(0x2000, perm::READ).unwrap());
+    replayed.push(mmu.read_u8(0x2000, perm::READ).unwrap());
+    let snapshot = mmu.snapshot();
+    replayed.push(mmu.read_u8(0x2004, perm::READ).unwrap());
+    replayed.extend(mmu.read_u16(0x2008, perm::READ).unwrap().to_le_bytes());
+    replayed.push(mmu.read_u8(0x200c, perm::READ).unwrap());
+    assert_eq!(replayed, recorded);
+
+    // Writes still reach the device, but reads never do.
+    mmu.write_u8(0x2001, 0x12, perm::WRITE).unwrap();
+    assert_eq!(take_accesses(&mut mmu, handler), [(0x2001, 1)]);
+    mmu.replay_write_policy = ReplayWritePolicy::Discard;
+    mmu.write_u8(0x2001, 0x34, perm::WRITE).unwrap();
+    mmu.fill_mem(0x2000, 0x4, 0x56).unwrap();
+    assert_eq!(take_accesses(&mut mmu, handler), []);
+
+    // Reading past the end of the stream diverges.
+    assert_eq!(mmu.read_u8(0x200c, perm::READ), Err(MemError::ReplayDiverged));
+    let err = mmu.take_io_replay_error().unwrap();
+    assert!(matches!(err, IoReplayError::EndOfStream { seq: 5 }), "{err:?}");
+
+    // Restoring a snapshot moves the replay back to the position of the snapshot.
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u8(0x2004, perm::READ), Ok(5));
+
+    // A read that does not match the stream diverges, and every subsequent read fails.
+    assert_eq!(mmu.read_u16(0x2004, perm::READ), Err(MemError::ReplayDiverged));
+    assert_eq!(mmu.read_u16(0x2008, perm::READ), Err(MemError::ReplayDiverged));
+    let err = mmu.take_io_replay_error().unwrap();
+    let IoReplayError::Diverged { expected, handler: found, addr, size } = &err
+    else {
+        panic!("unexpected error: {err:?}");
+    };
+    assert_eq!(
+        (expected.seq, expected.addr, expected.size, expected.value),
+        (3, 0x2008, 2, 0x0606)
+    );
+    assert_eq!((*found, *addr, *size), (handler, 0x2004, 2));
+    assert!(err.to_string().contains("diverged at read 3"), "{err}");
+
+    // Once the replay is stopped reads are serviced by the device.
+    mmu.stop_io_replay();
+    assert_eq!(mmu.read_u8(0x2000, perm::READ), Ok(0xee));
+    assert_eq!(take_accesses(&mut mmu, handler), [(0x2000, 1)]);
+}
+
+#[test]
+#[allow(deprecated)]
+fn degenerate_ranges() {
+    use crate::MemResult;
+
+    const RWX: u8 = perm::READ | perm::WRITE | perm::EXEC | perm::INIT;
+    const TOP: u64 = u64::MAX - 0xfff;
+
+    fn ok_if(success: bool) -> MemResult<()> {
+        if success { Ok(()) } else { Err(MemError::Unknown) }
+    }
+
+    // Returns an Mmu with allocated memory at `0x1000..0x2000` and at the end of the address
+    // space, or an empty Mmu if `mapped` is false.
+    fn setup(mapped: bool) -> Mmu {
+        let mut mmu = Mmu::new();
+        if mapped {
+            for start in [0x1000, TOP] {
+                assert!(mmu.map_memory_len(start, 0x1000, Mapping { perm: RWX, value: 0xaa }));
+                mmu.write_bytes(start, &[0xaa; 0x1000], perm::NONE).unwrap();
+            }
+        }
+        mmu
+    }
+
+    type Op = fn(&mut Mmu, u64, u64) -> MemResult<()>;
+    let ops: &[(&str, bool, Op)] = &[
+        ("map_memory_len", false, |mmu, start, len| {
+            ok_if(mmu.map_memory_len(start, len, Mapping { perm: RWX, value: 0 }))
+        }),
+        ("unmap_memory_len", true, |mmu, start, len| ok_if(mmu.unmap_memory_len(start, len))),
+        ("update_perm", true, |mmu, start, len| mmu.update_perm(start, len, perm::READ)),
+        ("set_protection", true, |mmu, start, len| mmu.set_protection(start, len, perm::READ)),
+        ("fill_mem", true, |mmu, start, len| mmu.fill_mem(start, len, 0x11)),
+        ("move_region_len", true, |mmu, start, len| {
+            mmu.move_region_len(start, len, start.wrapping_sub(0x10_0000))
+        }),
+        ("ensure_executable", true, |mmu, start, len| ok_if(mmu.ensure_executable(start, len))),
+    ];
+
+    // (start, len, expected result for every operation)
+    let cases: &[(u64, u64, MemResult<()>)] = &[
+        (0x1000, 0, Ok(())),
+        (0x1800, 0, Ok(())),
+        (u64::MAX, 0, Ok(())),
+        (0x1000, 1, Ok(())),
+        (0x1fff, 1, Ok(())),
+        (u64::MAX, 1, Ok(())),
+        (TOP, 0x1000, Ok(())),
+    ];
+
+    for (name, mapped, op) in ops {
+        for (start, len, expected) in cases {
+            let mut mmu = setup(*mapped);
+            let before = mmu.get_mapping().clone();
+            let perm_before = mmu.get_perm(*start);
+            assert_eq!(op(&mut mmu, *start, *len), *expected, "{name}({start:#x}, {len:#x})");
+            if *len == 0 {
+                // Zero-length operations must not modify anything.
+                assert_eq!(mmu.get_perm(*start), perm_before, "{name}({start:#x}, 0)");
+                assert_eq!(
+                    format!("{:x?}", mmu.get_mapping()),
+                    format!("{before:x?}"),
+                    "{name}({start:#x}, 0)"
+                );
+            }
+        }
+    }
+
+    // Ranges that extend past the end of the address space are either mapped as two regions that
+    // wrap around to zero, or rejected.
+    let mut mmu = setup(false);
+    assert!(mmu.map_memory_len(u64::MAX, 2, Mapping { perm: RWX, value: 0 }));
+    assert_eq!(mmu.read_u8(u64::MAX, perm::READ), Ok(0));
+    assert_eq!(mmu.read_u8(0, perm::READ), Ok(0));
+    assert_eq!(mmu.update_perm(u64::MAX, 2, perm::READ), Err(MemError::AddressOverflow));
+    assert_eq!(mmu.fill_mem(u64::MAX, 2, 0), Err(MemError::AddressOverflow));
+    assert_eq!(mmu.move_region_len(u64::MAX, 2, 0x1000), Err(MemError::AddressOverflow));
+    assert!(!mmu.ensure_executable(u64::MAX, 2));
+    assert!(mmu.unmap_memory_len(u64::MAX, 2));
+    assert_unmapped!(mmu, u64::MAX);
+    assert_unmapped!(mmu, 0);
+
+    // Inverted ranges are rejected by the functions that take an end address.
+    let mut mmu = setup(true);
+    let before = format!("{:x?}", mmu.get_mapping());
+    assert!(!mmu.map_memory(0x3000, 0x2000, Mapping { perm: RWX, value: 0 }));
+    assert!(!mmu.unmap_memory(0x2000, 0x1000));
+    assert_eq!(mmu.move_region(0x2000, 0x1000, 0x5000), Err(MemError::InvalidRange));
+    assert_eq!(format!("{:x?}", mmu.get_mapping()), before);
+    assert!(mmu.map_memory(0x3000, 0x3000, Mapping { perm: RWX, value: 0 }));
+    assert!(mmu.unmap_memory(0x3000, 0x3000));
+
+    // Allocations must not be empty.
+    let layout = |size| AllocLayout { addr: Some(0x10000), size, align: 0x1000 };
+    assert_eq!(mmu.find_free_memory(layout(0)), Err(MemError::InvalidRange));
+    assert_eq!(
+        mmu.alloc_memory(layout(0), Mapping { perm: RWX, value: 0 }),
+        Err(MemError::InvalidRange)
+    );
+    let mapping = Mapping { perm: RWX, value: 0 };
+    assert_eq!(mmu.alloc_memory_with_guards(layout(0), mapping, 1, 1), Err(MemError::InvalidRange));
+    assert_eq!(mmu.free_memory_with_guards(0x1000, 0), Err(MemError::InvalidRange));
+    assert_eq!(mmu.alloc_memory(layout(1), Mapping { perm: RWX, value: 0 }), Ok(0x10000));
+    let huge_align = AllocLayout { addr: None, size: 1, align: u64::MAX };
+    assert_eq!(mmu.find_free_memory(huge_align), Err(MemError::OutOfMemory));
+
+    // Allocations that can not be mapped are reported as errors.
+    mmu.wx_policy = crate::WxPolicy::Deny;
+    let wx = Mapping { perm: perm::MAP | perm::WRITE | perm::EXEC, value: 0 };
+    assert_eq!(mmu.alloc_memory(layout(1), wx), Err(MemError::WriteExecViolation));
+
+    // A hook range where `end < start` wraps around the end of the address space, and a range
+    // where `end == start` is empty.
+    let hits = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    for (start, end) in [(TOP, 0x1001), (0x1000, 0x1000)] {
+        let hits = hits.clone();
+        let hook = move |_: &mut Mmu, addr: u64, _: &[u8]| hits.borrow_mut().push((start, addr));
+        assert!(mmu.add_write_hook(start, end, Box::new(hook)).is_some());
+    }
+    mmu.write_u8(0x1000, 0, perm::WRITE).unwrap();
+    mmu.write_u8(0x1001, 0, perm::WRITE).unwrap();
+    mmu.write_u8(u64::MAX, 0, perm::WRITE).unwrap();
+    assert_eq!(hits.borrow().as_slice(), [(TOP, 0x1000), (TOP, u64::MAX)]);
+}
+
+#[test]
+#[cfg(feature = "heap")]
+fn guest_heap_guard_pages() {
+    use crate::heap::GuestHeap;
+
+    let mut mmu = Mmu::new();
+    let layout = AllocLayout { addr: Some(0x10_0000), size: 0x10_0000, align: 0x1000 };
+    let mut heap = GuestHeap::new(&mut mmu, layout).unwrap();
+    heap.guard_pages = 1;
+    assert_eq!(heap.arena(), (0x10_0000, 0x1f_ffff));
+
+    // The arena is reserved until memory is allocated.
+    assert_eq!(mmu.read_u8(0x10_0000, perm::NONE), Err(MemError::Reserved));
+    assert_eq!(mmu.find_free_memory(layout), Ok(0x20_0000));
+
+    let a = heap.alloc(&mut mmu, 0x24, 8).unwrap();
+    assert_eq!(a, 0x10_1000);
+    assert_eq!(heap.usable_size(a), Some(0x24));
+    mmu.write_bytes(a, &[0x11; 0x24], perm::WRITE).unwrap();
+
+    // Overflowing (or underflowing) the chunk faults on the guard pages, even within the page.
+    assert_eq!(mmu.write_u8(a + 0x24, 0, perm::WRITE), Err(MemError::Reserved));
+    assert_eq!(mmu.write_u32(a + 0x22, 0, perm::WRITE), Err(MemError::Reserved));
+    assert_eq!(mmu.read_u8(a + 0x1000, perm::READ), Err(MemError::Reserved));
+    assert_eq!(mmu.read_u8(a - 1, perm::READ), Err(MemError::Reserved));
+
+    // Chunks are aligned and separated by guard pages.
+    let b = heap.alloc(&mut mmu, 0x1000, 0x4000).unwrap();
+    assert_eq!(b, 0x10_4000);
+    assert_eq!(mmu.read_u8(b - 1, perm::READ), Err(MemError::Reserved));
+    assert_eq!(heap.allocated_bytes(), 0x1024);
+
+    // Freed chunks are reused by later allocations, and newly allocated chunks are zeroed.
+    heap.free(&mut mmu, a).unwrap();
+    assert_eq!(heap.free(&mut mmu, a), Err(MemError::Unallocated));
+    assert_eq!(heap.free(&mut mmu, b + 8), Err(MemError::Unallocated));
+    assert_eq!(heap.alloc(&mut mmu, 0x10, 1), Ok(a));
+    assert_eq!(mmu.read_u32(a, perm::READ), Ok(0));
+    assert_eq!(mmu.read_u8(a + 0x10, perm::READ), Err(MemError::Reserved));
+
+    // Without guard pages, chunks are only separated by padding.
+    heap.guard_pages = 0;
+    let c = heap.alloc(&mut mmu, 0x18, 1).unwrap();
+    let d = heap.alloc(&mut mmu, 0x10, 1).unwrap();
+    assert_eq!((c, d), (0x10_6000, 0x10_6020));
+    assert_eq!(mmu.write_u8(c + 0x18, 0, perm::WRITE), Err(MemError::Reserved));
+
+    assert_eq!(heap.alloc(&mut mmu, 0, 1), Err(MemError::InvalidRange));
+    assert_eq!(heap.alloc(&mut mmu, 0x10_0000, 1), Err(MemError::OutOfMemory));
+}
+
+#[test]
+#[cfg(feature = "heap")]
+fn guest_heap_quarantine() {
+    use crate::heap::GuestHeap;
+
+    let mut mmu = Mmu::new();
+    let layout = AllocLayout { addr: Some(0x10_0000), size: 0x1_0000, align: 0x1000 };
+    let mut heap = GuestHeap::new(&mut mmu, layout).unwrap();
+    heap.poison = Some(0xdd);
+    heap.quarantine_size = 0x100;
+
+    let a = heap.alloc(&mut mmu, 0x80, 1).unwrap();
+    mmu.write_u64(a, 0x1122_3344_5566_7788, perm::WRITE).unwrap();
+    heap.free(&mut mmu, a).unwrap();
+
+    // Quarantined chunks are poisoned and inaccessible, and are not reused.
+    assert_eq!(mmu.read_u8(a, perm::READ), Err(MemError::ReadViolation));
+    assert_eq!(mmu.write_u8(a + 0x7f, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u64(a, perm::NONE), Ok(0xdddd_dddd_dddd_dddd));
+    let b = heap.alloc(&mut mmu, 0x80, 1).unwrap();
+    assert_ne!(a, b);
+
+    // Reallocating moves the data to a new chunk, quarantining the old chunk.
+    mmu.write_u64(b, 0x0102_0304_0506_0708, perm::WRITE).unwrap();
+    let c = heap.realloc(&mut mmu, b, 0x100).unwrap();
+    assert_eq!(mmu.read_u64(c, perm::READ), Ok(0x0102_0304_0506_0708));
+    assert_eq!(mmu.read_u64(c + 0xf8, perm::READ), Ok(0));
+    assert_eq!(mmu.read_u8(b, perm::READ), Err(MemError::ReadViolation));
+    assert_eq!(heap.realloc(&mut mmu, b, 0x10), Err(MemError::Unallocated));
+
+    // Once the quarantine is full the oldest chunks are released, and can be accessed again.
+    heap.free(&mut mmu, c).unwrap();
+    assert_eq!(mmu.read_u8(a, perm::READ), Ok(0xdd));
+    assert_eq!(mmu.read_u8(b, perm::READ), Ok(0xdd));
+    assert_eq!(mmu.read_u8(c, perm::READ), Err(MemError::ReadViolation));
+    heap.flush_quarantine(&mut mmu).unwrap();
+    assert_eq!(mmu.read_u8(c, perm::READ), Ok(0xdd));
+    assert_eq!(heap.alloc(&mut mmu, 0x200, 1), Ok(a));
+}
+
+#[test]
+#[cfg(feature = "heap")]
+fn guest_heap_snapshot() {
+    use crate::heap::GuestHeap;
+
+    let mut mmu = Mmu::new();
+    let layout = AllocLayout { addr: Some(0x10_0000), size: 0x1_0000, align: 0x1000 };
+    let mut heap = GuestHeap::new(&mut mmu, layout).unwrap();
+    heap.guard_pages = 1;
+    heap.quarantine_size = 0x1000;
+
+    let a = heap.alloc(&mut mmu, 0x100, 1).unwrap();
+    mmu.write_u32(a, 0xaabbccdd, perm::WRITE).unwrap();
+    let mmu_snapshot = mmu.snapshot();
+    let heap_snapshot = heap.snapshot();
+
+    heap.free(&mut mmu, a).unwrap();
+    let b = heap.alloc(&mut mmu, 0x2000, 1).unwrap();
+    let c = heap.alloc(&mut mmu, 0x10, 1).unwrap();
+    assert_eq!(mmu.read_u8(a, perm::READ), Err(MemError::ReadViolation));
+
+    mmu.restore(mmu_snapshot).unwrap();
+    heap.restore(&heap_snapshot);
+    assert_eq!(heap.usable_size(a), Some(0x100));
+    assert_eq!((heap.usable_size(b), heap.usable_size(c)), (None, None));
+    assert_eq!(heap.allocated_bytes(), 0x100);
+    assert_eq!(mmu.read_u32(a, perm::READ), Ok(0xaabbccdd));
+    assert_eq!(mmu.read_u8(b, perm::READ), Err(MemError::Reserved));
+
+    // The heap continues from the state of the snapshot.
+    assert_eq!(heap.alloc(&mut mmu, 0x2000, 1), Ok(b));
+    assert_eq!(heap.alloc(&mut mmu, 0x10, 1), Ok(c));
+    heap.free(&mut mmu, a).unwrap();
+    assert_eq!(heap.free(&mut mmu, a), Err(MemError::Unallocated));
+}
+
+#[test]
+fn copy_between_mmus() {
+    let rw = perm::READ | perm::WRITE;
+    let mut src = Mmu::new();
+    src.track_uninitialized = true;
+    src.map_memory_len(0x1000, 0x3000, Mapping { perm: rw, value: 0xaa });
+    src.map_memory_len(0x4000, 0x1000, Mapping { perm: perm::WRITE, value: 0x0 });
+    let payload: Vec<u8> = (0..0x20).collect();
+    src.write_bytes(0x1ff0, &payload, perm::WRITE).unwrap();
+
+    let mut dst = Mmu::new();
+    dst.track_uninitialized = true;
+    dst.map_memory_len(0x10000, 0x2000, Mapping { perm: rw | perm::INIT, value: 0x0 });
+    dst.map_memory_len(0x12000, 0x1000, Mapping { perm: perm::READ | perm::INIT, value: 0x0 });
+
+    let writes = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let writes_ref = writes.clone();
+    dst.add_write_hook(
+        0x10000,
+        0x11000,
+        Box::new(move |_: &mut Mmu, addr: u64, value: &[u8]| {
+            writes_ref.borrow_mut().push((addr, value.to_vec()))
+        }),
+    );
+
+    // The chunks are split at different offsets in each range.
+    dst.copy_from(0x10ff8, &mut src, 0x1ff0, 0x20).unwrap();
+    let mut output = [0; 0x20];
+    dst.read_bytes(0x10ff8, &mut output, perm::READ | perm::INIT).unwrap();
+    assert_eq!(&output[..], &payload[..]);
+    assert_eq!(*writes.borrow(), vec![(0x10ff8, payload[..8].to_vec())]);
+
+    // Uninitialized bytes remain uninitialized, and unallocated source memory is not allocated.
+    dst.copy_from(0x11800, &mut src, 0x3000, 0x10).unwrap();
+    assert!(src.get_physical_index(0x3000).is_none());
+    assert_eq!(dst.read_u8(0x11800, perm::READ), Ok(0xaa));
+    assert_eq!(dst.read_u8(0x11800, perm::INIT), Err(MemError::Uninitalized));
+
+    // Both ranges are checked before anything is copied.
+    assert_eq!(dst.copy_from(0x10000, &mut src, 0x4000, 0x10), Err(MemError::ReadViolation));
+    assert_eq!(dst.copy_from(0x11ff8, &mut src, 0x1000, 0x10), Err(MemError::WriteViolation));
+    assert_eq!(dst.read_u64(0x11ff8, perm::READ), Ok(0x0));
+    assert_eq!(dst.copy_from(0x10000, &mut src, 0x0, 0x10), Err(MemError::Unmapped));
+    assert_eq!(dst.copy_from(0x10000, &mut src, u64::MAX, 0x10), Err(MemError::AddressOverflow));
+    assert_eq!(dst.copy_from(0x10000, &mut src, 0x0, 0x0), Ok(()));
+}
+
+#[test]
+fn share_region_between_mmus() {
+    let rw = perm::READ | perm::WRITE;
+    let mut a = Mmu::new();
+    a.map_memory_len(0x1000, 0x2000, Mapping { perm: rw, value: 0x0 });
+    a.write_u32(0x1000, 0x1111_1111, perm::WRITE).unwrap();
+
+    let mut b = Mmu::new();
+    assert_eq!(b.share_region(0x8000, 0x2000, &mut a, 0x1100), Err(MemError::Unaligned));
+    assert_eq!(b.share_region(0x8000, 0x2000, &mut a, 0x2000), Err(MemError::Unmapped));
+
+    // Source pages are only converted to shared memory once the destination is known to be valid.
+    let is_shared =
+        |a: &Mmu| a.get_physical(a.get_physical_index(0x1000).unwrap()).is_shared_memory();
+    b.set_capacity(b.total_pages() + 1);
+    assert_eq!(b.share_region(0x8000, 0x2000, &mut a, 0x1000), Err(MemError::OutOfMemory));
+    b.set_capacity(usize::MAX);
+    b.set_address_space_bits(12);
+    b.address_space_policy = crate::AddressSpacePolicy::Deny;
+    assert_eq!(b.share_region(0x8000, 0x2000, &mut a, 0x1000), Err(MemError::AddressOverflow));
+    b.set_address_space_bits(64);
+    assert!(!is_shared(&a));
+    assert_unmapped!(b, 0x8000);
+
+    b.share_region(0x8000, 0x2000, &mut a, 0x1000).unwrap();
+    assert!(is_shared(&a));
+    assert_eq!(b.share_region(0x9000, 0x1000, &mut a, 0x1000), Err(MemError::AlreadyMapped));
+
+    // Writes through either MMU are visible through the other, including after the pages have
+    // been cached in the TLB.
+    assert_eq!(b.read_u32(0x8000, perm::READ), Ok(0x1111_1111));
+    a.write_u32(0x1000, 0x2222_2222, perm::WRITE).unwrap();
+    assert_eq!(b.read_u32(0x8000, perm::READ), Ok(0x2222_2222));
+    b.write_u32(0x9ffc, 0x3333_3333, perm::WRITE).unwrap();
+    assert_eq!(a.read_u32(0x2ffc, perm::READ), Ok(0x3333_3333));
+
+    // But permission changes are not.
+    a.update_perm(0x2000, 0x1000, perm::READ).unwrap();
+    assert_eq!(a.write_u32(0x2000, 0x0, perm::WRITE), Err(MemError::WriteViolation));
+    b.write_u32(0x9000, 0x7777_7777, perm::WRITE).unwrap();
+    assert_eq!(a.read_u32(0x2000, perm::READ), Ok(0x7777_7777));
+    a.update_perm(0x2000, 0x1000, rw).unwrap();
+
+    // Snapshots are not copied-on-write, and restoring a snapshot only writes the contents back
+    // in-place when requested.
+    let snapshot = b.snapshot();
+    b.write_u32(0x8000, 0x4444_4444, perm::WRITE).unwrap();
+    assert_eq!(a.read_u32(0x1000, perm::READ), Ok(0x4444_4444));
+    b.restore(snapshot.clone()).unwrap();
+    assert_eq!(a.read_u32(0x1000, perm::READ), Ok(0x4444_4444));
+    b.restore_shared_memory(&snapshot);
+    assert_eq!(a.read_u32(0x1000, perm::READ), Ok(0x2222_2222));
+    b.write_u32(0x8000, 0x5555_5555, perm::WRITE).unwrap();
+    assert_eq!(a.read_u32(0x1000, perm::READ), Ok(0x5555_5555));
+
+    // Unmapping the region from one MMU does not affect the other.
+    assert!(b.unmap_memory_len(0x8000, 0x2000));
+    assert_unmapped!(b, 0x8000);
+    assert_eq!(a.read_u32(0x1000, perm::READ), Ok(0x5555_5555));
+    a.write_u32(0x1000, 0x6666_6666, perm::WRITE).unwrap();
+    assert_eq!(a.read_u32(0x1000, perm::READ), Ok(0x6666_6666));
+}
+
+#[test]
+fn file_mapping() {
+    let mut data: Vec<u8> = (0..0x2800).map(|i| (i % 251) as u8 | 1).collect();
+    data[0x1000..0x2000].fill(0);
+    let backing = std::sync::Arc::new(data.clone());
+
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    let file = mmu.register_file(backing.clone());
+    let mapping = crate::FileMapping::new(file, 0x800, perm::READ | perm::WRITE);
+    assert!(mmu.map_memory_len(0x10800, 0x4000, mapping));
+    // Split the mapping to check that the rest of the mapping still refers to the correct offset.
+    mmu.update_perm(0x12400, 0x10, perm::READ).unwrap();
+    match mmu.get_mapping().get(0x12410) {
+        Some(crate::MemoryMapping::File(entry)) => assert_eq!(entry.file_offset(0x12410), 0x2410),
+        other => panic!("unexpected mapping: {other:?}"),
+    }
+
+    // Pages are only allocated once they are accessed.
+    let mut buf = [0; 4];
+    mmu.peek_bytes(0x10ffe, &mut buf).unwrap();
+    assert_eq!(buf, data[0xffe..0x1002]);
+    for addr in (0x10000..0x15000).step_by(0x1000) {
+        assert!(mmu.get_physical_index(addr).is_none());
+    }
+
+    assert_eq!(mmu.read_u8(0x10800, perm::READ | perm::INIT), Ok(data[0x800]));
+    assert_eq!(mmu.read_u8(0x12410, perm::READ | perm::INIT), Ok(data[0x2410]));
+    assert_eq!(mmu.read_u8(0x12480, perm::READ | perm::INIT), Ok(data[0x2480]));
+    assert!(mmu.get_physical_index(0x11000).is_none());
+
+    // Bytes past the end of the file are zero.
+    mmu.read_bytes(0x127fe, &mut buf, perm::READ | perm::INIT).unwrap();
+    assert_eq!(buf, [data[0x27fe], data[0x27ff], 0, 0]);
+
+    // Pages that only contain zeroes use the zero page.
+    assert_eq!(mmu.read_u32(0x11800, perm::READ | perm::INIT), Ok(0));
+    assert!(mmu.get_physical_index(0x11800).unwrap().is_zero_page());
+    assert_eq!(mmu.read_u32(0x13800, perm::READ | perm::INIT), Ok(0));
+    assert!(mmu.get_physical_index(0x13800).unwrap().is_zero_page());
+    assert!(mmu.get_physical_index(0x14000).is_none());
+
+    // Writes are private to the MMU.
+    mmu.write_u32(0x10800, 0xaabbccdd, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u8(0x10801, perm::READ), Ok(0xcc));
+    assert_eq!(backing[0x800..0x804], data[0x800..0x804]);
+
+    let snapshot = mmu.snapshot();
+    mmu.write_u32(0x10800, 0x11223344, perm::WRITE).unwrap();
+    mmu.write_u32(0x14000, 0x11223344, perm::WRITE).unwrap();
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u32(0x10800, perm::READ), Ok(0xaabbccdd));
+    assert_eq!(mmu.read_u32(0x14000, perm::READ | perm::INIT), Ok(0));
+    assert_eq!(mmu.read_u8(0x10804, perm::READ | perm::INIT), Ok(data[0x804]));
+}
+
+#[test]
+fn prereserved_pages() {
+    let config =
+        crate::MmuConfig { prefault_tlb: true, prereserve_pages: 16, ..Default::default() };
+    let mut reserved = Mmu::with_config(config);
+    let mut default = Mmu::new();
+
+    let stats = reserved.memory_stats();
+    assert_eq!(stats.reserved_pages, 16);
+    assert_eq!(stats.allocated_pages, default.memory_stats().allocated_pages);
+    assert_eq!(reserved.total_pages(), default.total_pages());
+
+    for mmu in [&mut reserved, &mut default] {
+        mmu.map_memory_len(0x1000, 0x10000, Mapping { perm: perm::NONE, value: 0xaa });
+        mmu.prepare_for_fork();
+        for i in 0..10 {
+            mmu.write_bytes(0x1000 + i * 0x1000, b"hello", perm::NONE).unwrap();
+        }
+        mmu.snapshot();
+        mmu.write_bytes(0x1000, b"world", perm::NONE).unwrap();
+    }
+
+    // Reserved pages should be handed out in the same order as they would be allocated normally.
+    for addr in (0x1000..0x11000).step_by(0x1000) {
+        assert_eq!(reserved.get_physical_addr(addr), default.get_physical_addr(addr));
+        let mut a = [0; 0x1000];
+        let mut b = [0; 0x1000];
+        reserved.read_bytes(addr, &mut a, perm::NONE).unwrap();
+        default.read_bytes(addr, &mut b, perm::NONE).unwrap();
+        assert_eq!(a, b);
+    }
+
+    let stats = reserved.memory_stats();
+    assert_eq!(stats.allocated_pages, default.memory_stats().allocated_pages);
+    assert_eq!(stats.reserved_pages, 16 - (stats.allocated_pages - 2));
+
+    // Reservations are limited by the page capacity.
+    let mut physical = crate::physical::PhysicalMemory::new(8);
+    assert_eq!(physical.reserve(usize::MAX), 6);
+    assert_eq!(physical.free_pages(), 6);
+    assert_eq!(physical.reserve(1), 0);
+}
+
+#[test]
+fn memory_stats() {
+    let mut mmu = Mmu::new();
+    let zeroed = Mapping { perm: perm::MAP | perm::READ | perm::WRITE | perm::INIT, value: 0x0 };
+    mmu.map_memory_len(0x10000, 0x4000, zeroed);
+    mmu.map_memory_len(0x14000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0xaa });
+    mmu.map_memory_len(0x30000, 0x1000, Mapping { perm: perm::READ, value: 0xaa });
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x20000, 0x100, io);
+
+    mmu.read_u8(0x10000, perm::READ).unwrap();
+    mmu.write_u8(0x11000, 0x1, perm::WRITE).unwrap();
+    mmu.write_u8(0x14000, 0x1, perm::WRITE).unwrap();
+
+    let stats = mmu.memory_stats();
+    assert_eq!(stats, crate::MemoryStats {
+        allocated_pages: 4,
+        reserved_pages: 0,
+        capacity: mmu.capacity(),
+        tlb_bytes: stats.tlb_bytes,
+        committed_host_pages: None,
+        zero_pages: 1,
+        shared_pages: 0,
+        mapped_bytes: 0x6100,
+        regions: 3,
+        io_regions: 1,
+        tlb_hits: 0,
+        tlb_misses: mmu.tlb_counters.misses(),
+    });
+    assert!(stats.tlb_misses > 0);
+
+    // Pages captured by a snapshot should be shared until they are modified.
+    let snapshot = mmu.snapshot();
+    assert_eq!(mmu.memory_stats().shared_pages, 2);
+    mmu.write_u8(0x11000, 0x2, perm::WRITE).unwrap();
+    assert_eq!(mmu.memory_stats().shared_pages, 1);
+    mmu.unmap_memory_len(0x14000, 0x1000);
+    assert_eq!(mmu.memory_stats().regions, 3);
+    assert_eq!(mmu.memory_stats().mapped_bytes, 0x5100);
+
+    mmu.restore(snapshot).unwrap();
+    let restored = mmu.memory_stats();
+    assert_eq!((restored.allocated_pages, restored.shared_pages), (4, 2));
+    assert_eq!((restored.mapped_bytes, restored.regions), (0x6100, 3));
+
+    mmu.reset_counters();
+    let stats = mmu.memory_stats();
+    assert_eq!((stats.tlb_hits, stats.tlb_misses), (0, 0));
+    assert_eq!(stats.tlb_hit_rate(), None);
+}
+
+#[test]
+fn tlb_counters() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0x0 });
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x2000, 0x100, io);
+
+    // Hits are not counted unless profiling is enabled.
+    mmu.read_u32(0x1000, perm::READ).unwrap();
+    mmu.read_u32(0x1004, perm::READ).unwrap();
+    assert_eq!(mmu.tlb_counters, crate::TlbCounters { read_misses: 1, ..Default::default() });
+    assert!(mmu.top_missing_pages(4).is_empty());
+
+    mmu.profile_tlb = true;
+    mmu.read_u32(0x1008, perm::READ).unwrap();
+    mmu.write_u32(0x1000, 1, perm::WRITE).unwrap();
+    mmu.write_u32(0x1004, 2, perm::WRITE).unwrap();
+    for _ in 0..3 {
+        mmu.read_u32(0x2000, perm::READ).unwrap();
+    }
+    assert_eq!(mmu.tlb_counters, crate::TlbCounters {
+        read_hits: 1,
+        read_misses: 4,
+        write_hits: 1,
+        write_misses: 1,
+    });
+    assert_eq!(mmu.top_missing_pages(4), [(0x2000, 3), (0x1000, 1)]);
+    assert_eq!(mmu.top_missing_pages(1), [(0x2000, 3)]);
+
+    // Counters are not captured by snapshots.
+    let snapshot = mmu.snapshot();
+    mmu.read_u32(0x2000, perm::READ).unwrap();
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.tlb_counters.read_misses, 5);
+    assert_eq!(mmu.top_missing_pages(1), [(0x2000, 4)]);
+
+    mmu.reset_counters();
+    assert_eq!(mmu.tlb_counters, crate::TlbCounters::default());
+    assert!(mmu.top_missing_pages(4).is_empty());
+}
+
+#[test]
+fn tlb_geometry() {
+    // Alternates reads between two pages that map to the same set, then reads from a third page
+    // in the same set, returning the number of TLB misses for each step.
+    fn conflict_misses(config: crate::TlbConfig) -> (u64, u64) {
+        let mut mmu = Mmu::with_config(crate::MmuConfig { tlb: config, ..Default::default() });
+        let stride = (config.sets * 0x1000) as u64;
+        let pages = [0x1000, 0x1000 + stride, 0x1000 + 2 * stride];
+        for addr in pages {
+            mmu.map_memory_len(addr, 0x1000, Mapping { perm: perm::READ | perm::INIT, value: 0 });
+        }
+        for i in 0..100 {
+            mmu.read_u32(pages[i % 2], perm::READ).unwrap();
+        }
+        let misses = mmu.tlb_counters.read_misses;
+
+        // The least recently used entry is evicted from a full set.
+        for addr in [pages[2], pages[1], pages[0]] {
+            mmu.read_u32(addr, perm::READ).unwrap();
+        }
+        (misses, mmu.tlb_counters.read_misses - misses)
+    }
+
+    let default = crate::TlbConfig::default();
+    assert_eq!(Mmu::new().tlb_config(), default);
+    assert_eq!(conflict_misses(default), (100, 3));
+    assert_eq!(conflict_misses(crate::TlbConfig { sets: 16, ways: 2 }), (2, 2));
+    assert_eq!(conflict_misses(crate::TlbConfig { sets: 1, ways: 4 }), (2, 1));
+
+    let config = crate::TlbConfig { sets: 16, ways: 2 };
+    let mut mmu = Mmu::with_config(crate::MmuConfig { tlb: config, ..Default::default() });
+    let entry_size = std::mem::size_of::<crate::tlb::TLBEntry>();
+    assert_eq!(mmu.memory_stats().tlb_bytes, (1 + 2 * 32) * entry_size);
+    mmu.map_memory_len(0x1000, 0x20000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    mmu.write_u32(0x1000, 1, perm::WRITE).unwrap();
+    mmu.write_u32(0x11000, 2, perm::WRITE).unwrap();
+    assert!(mmu.tlb.translate_write(0x1000).is_some());
+    assert!(mmu.tlb.translate_write(0x11000).is_some());
+
+    // Removing a page must remove it from every way of the set.
+    mmu.update_perm(0x1000, 0x1000, perm::READ).unwrap();
+    assert!(mmu.tlb.translate_write(0x1000).is_none());
+    assert!(mmu.tlb.translate_write(0x11000).is_some());
+    assert_eq!(mmu.write_u32(0x1000, 3, perm::WRITE), Err(MemError::WriteViolation));
+
+    // Ranges larger than the TLB clear every entry.
+    mmu.update_perm(0x1000, 0x20000, perm::READ | perm::WRITE).unwrap();
+    mmu.write_u32(0x11000, 2, perm::WRITE).unwrap();
+    mmu.tlb.remove_range(0x100000, 0x20000);
+    assert!(mmu.tlb.translate_write(0x11000).is_none());
+
+    // Snapshots clear the TLB regardless of its size.
+    mmu.write_u32(0x11000, 4, perm::WRITE).unwrap();
+    let snapshot = mmu.snapshot();
+    assert!(mmu.tlb.translate_write(0x11000).is_none());
+    mmu.write_u32(0x11000, 5, perm::WRITE).unwrap();
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u32(0x11000, perm::READ), Ok(4));
+}
+
+#[test]
+fn populate() {
+    use crate::PermRangeError;
+
+    const LEN: u64 = 16 * 1024 * 1024;
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    let mut mmu = Mmu::new();
+    mmu.profile_tlb = true;
+    let base = 0x1000_0000;
+    mmu.map_memory_len(base, LEN, rw);
+
+    assert_eq!(mmu.populate(base, LEN, true), Ok((LEN / 0x1000) as usize));
+    let pages: Vec<_> = (base..base + LEN).step_by(0x1000).collect();
+    let indices: Vec<_> = pages.iter().map(|page| mmu.get_physical_index(*page)).collect();
+    assert!(indices.iter().all(|index| index.is_some()));
+
+    // Accesses no longer fault in pages: the first access to each page fills the TLB, and the
+    // second access hits in it.
+    mmu.reset_counters();
+    for &page in &pages {
+        assert_eq!(mmu.read_u32(page, perm::READ), Ok(0));
+        assert_eq!(mmu.read_u32(page + 4, perm::READ), Ok(0));
+    }
+    assert_eq!(mmu.tlb_counters.read_hits, pages.len() as u64);
+    let after: Vec<_> = pages.iter().map(|page| mmu.get_physical_index(*page)).collect();
+    assert_eq!(after, indices);
+    assert_eq!(mmu.populate(base, LEN, true), Ok(0));
+
+    // Populating for reads maps zero-filled pages to the zero page, and the initialization state
+    // of bytes matches the fault path.
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    mmu.map_memory_len(0x10000, 0x2000, Mapping {
+        perm: rw.perm | perm::MAP | perm::INIT,
+        value: 0,
+    });
+    mmu.map_memory_len(0x12000, 0x2000, rw);
+    assert_eq!(mmu.populate(0x10000, 0x4000, false), Ok(2));
+    let zero_page = mmu.get_physical_index(0x10000).unwrap();
+    assert_eq!(mmu.get_physical_index(0x11000), Some(zero_page));
+    assert_ne!(mmu.get_physical_index(0x12000), Some(zero_page));
+    assert_eq!(mmu.read_u8(0x11000, perm::INIT), Ok(0));
+    assert_eq!(mmu.read_u8(0x12000, perm::INIT), Err(MemError::Uninitalized));
+    assert_eq!(mmu.populate(0x10800, 0x1000, true), Ok(0));
+    assert_eq!(mmu.get_physical_index(0x10000), Some(zero_page));
+
+    // Ranges that overlap I/O regions or unmapped memory fail without allocating any pages.
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x20000, 0x1000, rw);
+    mmu.map_memory_len(0x21000, 0x800, io);
+    mmu.map_memory_len(0x21800, 0x1800, rw);
+    mmu.map_memory_len(0x24000, 0x1000, rw);
+    let err = |addr, error| Err(PermRangeError { addr, error });
+    assert_eq!(mmu.populate(0x20000, 0x4000, true), err(0x21000, MemError::Unsupported));
+    assert_eq!(mmu.populate(0x21800, 0x3000, true), err(0x23000, MemError::Unmapped));
+    assert!(mmu.get_physical_index(0x20000).is_none());
+    assert!(mmu.get_physical_index(0x22000).is_none());
+    assert_eq!(mmu.populate(0x21800, 0x1800, true), Ok(2));
+    assert_eq!(mmu.populate(u64::MAX, 2, true), err(u64::MAX, MemError::AddressOverflow));
+    assert_eq!(mmu.populate(0x0, 0, true), Ok(0));
+}
+
+#[test]
+fn prefetch_translations() {
+    use crate::AccessKind;
+
+    let mut mmu = Mmu::new();
+    let code = Mapping { perm: perm::READ | perm::EXEC | perm::INIT, value: 0x90 };
+    mmu.map_memory_len(0x1000, 0x2000, code);
+    mmu.map_memory_len(0x10000, 0x4000, Mapping { perm: perm::READ | perm::WRITE, value: 0x0 });
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x20000, 0x1000, io);
+    mmu.write_u32(0x13ffc, 0x1234, perm::WRITE).unwrap();
+
+    let snapshot = mmu.snapshot();
+    mmu.write_u32(0x13ffc, 0x5678, perm::WRITE).unwrap();
+    mmu.restore(snapshot.clone()).unwrap();
+    mmu.modified.clear();
+
+    let working_set = [
+        (0x1000, 0x2000, AccessKind::Execute),
+        (0x12800, 0x1800, AccessKind::Write),
+        (0x12800, 0x1800, AccessKind::Read),
+        (0x20000, 0x1000, AccessKind::Read),
+    ];
+    assert_eq!(mmu.prefetch_translations(&working_set), 6);
+
+    // Prefetching does not modify any data.
+    assert_eq!(mmu.read_u32(0x13ffc, perm::READ), Ok(0x1234));
+    mmu.reset_counters();
+    mmu.read_u32(0x1ffc, perm::EXEC).unwrap();
+    mmu.read_u32(0x2000, perm::EXEC).unwrap();
+    mmu.write_u32(0x12800, 1, perm::WRITE).unwrap();
+    mmu.write_u32(0x13ffc, 2, perm::WRITE).unwrap();
+    mmu.read_u32(0x12800, perm::READ).unwrap();
+    assert_eq!(mmu.tlb_counters.misses(), 0);
+
+    // The snapshot must not be modified by writes to prefetched pages.
+    assert!(mmu.modified.contains(0x13000));
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u32(0x13ffc, perm::READ), Ok(0x1234));
+
+    // Pages without the required permissions, or that would not otherwise be cached, are skipped.
+    assert_eq!(mmu.prefetch_translations(&[(0x1000, 0x1000, AccessKind::Write)]), 0);
+    mmu.add_read_hook(0x10000, 0x10004, Box::new(|_: &mut Mmu, _: u64, _: u8| None));
+    assert_eq!(mmu.prefetch_translations(&[(0x10000, 0x2000, AccessKind::Read)]), 1);
+    assert!(mmu.tlb.translate_read(0x10000).is_none());
+    assert!(mmu.tlb.translate_read(0x11000).is_some());
+}
+
+#[test]
+fn address_spaces() {
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0x0 };
+    let mut mmu = Mmu::new();
+    mmu.profile_tlb = true;
+    let kernel = mmu.current_address_space();
+    assert_eq!(kernel, crate::Asid::DEFAULT);
+    let user = mmu.create_address_space();
+
+    mmu.map_memory_len(0x1000, 0x1000, rw);
+    mmu.map_memory_len(0x10000, 0x1000, rw);
+    mmu.write_u32(0x1000, 0xaa, perm::WRITE).unwrap();
+    mmu.write_u32(0x10000, 0x0, perm::WRITE).unwrap();
+    let shared = mmu.get_physical_index(0x10000).unwrap();
+
+    // The new address space does not contain any of the mappings from the previous one.
+    mmu.switch_address_space(user);
+    assert_eq!(mmu.read_u32(0x1000, perm::READ), Err(MemError::Unmapped));
+    mmu.map_memory_len(0x1000, 0x1000, rw);
+    mmu.write_u32(0x1000, 0xbb, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u32(0x1000, perm::READ), Ok(0xbb));
+    assert!(mmu.map_physical(0x20000, shared));
+    mmu.write_u32(0x20000, 0x1234, perm::WRITE).unwrap();
+
+    // Translations for each address space are kept when switching.
+    mmu.switch_address_space(kernel);
+    assert_eq!(mmu.read_u32(0x1000, perm::READ), Ok(0xaa));
+    assert_eq!(mmu.read_u32(0x10000, perm::READ), Ok(0x1234));
+    mmu.switch_address_space(user);
+    mmu.reset_counters();
+    assert_eq!(mmu.read_u32(0x1000, perm::READ), Ok(0xbb));
+    mmu.switch_address_space(kernel);
+    assert_eq!(mmu.read_u32(0x1000, perm::READ), Ok(0xaa));
+    assert_eq!(mmu.tlb_counters.misses(), 0);
+
+    // Pages shared between address spaces are never cached.
+    mmu.write_u32(0x10000, 0x5678, perm::WRITE).unwrap();
+    assert!(mmu.tlb.translate_write(0x10000).is_none());
+    mmu.switch_address_space(user);
+    assert_eq!(mmu.read_u32(0x20000, perm::READ), Ok(0x5678));
+
+    mmu.invalidate_asid(kernel);
+    assert!(mmu.tlb.translate_read(0x1000).is_some());
+    mmu.switch_address_space(kernel);
+    assert!(mmu.tlb.translate_read(0x1000).is_none());
+
+    // Pages only mapped by an inactive address space must not be collected.
+    mmu.unmap_memory_len(0x10000, 0x1000);
+    mmu.collect_garbage(crate::GcBudget::UNLIMITED);
+    mmu.switch_address_space(user);
+    assert_eq!(mmu.read_u32(0x1000, perm::READ), Ok(0xbb));
+    assert_eq!(mmu.read_u32(0x20000, perm::READ), Ok(0x5678));
+
+    // Snapshots capture every address space.
+    let snapshot = mmu.snapshot();
+    mmu.write_u32(0x1000, 0xcc, perm::WRITE).unwrap();
+    mmu.switch_address_space(kernel);
+    mmu.unmap_memory_len(0x1000, 0x1000);
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.current_address_space(), user);
+    assert_eq!(mmu.read_u32(0x1000, perm::READ), Ok(0xbb));
+    mmu.switch_address_space(kernel);
+    assert_eq!(mmu.read_u32(0x1000, perm::READ), Ok(0xaa));
+}
+
+#[test]
+fn page_table_walk() {
+    use crate::{AccessKind, PageFault, PageFaultReason, X86_64Walker as X};
+
+    const RAM: Mapping = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+    const TABLE: u64 = X::PRESENT | X::WRITABLE | X::USER;
+    let user_rw = TABLE;
+    let kernel_ro_nx = X::PRESENT | X::NO_EXECUTE;
+
+    // PML4 at 0x1000, PDPT at 0x2000, PD at 0x3000 and PT at 0x4000.
+    let mut mmu = Mmu::new();
+    mmu.profile_tlb = true;
+    mmu.map_memory_len(0x0, 0x40_0000, RAM);
+    mmu.write_u64(0x1000, 0x2000 | TABLE, perm::NONE).unwrap();
+    mmu.write_u64(0x2000, 0x3000 | TABLE, perm::NONE).unwrap();
+    mmu.write_u64(0x3000 + 8, 0x20_0000 | X::PAGE_SIZE | TABLE, perm::NONE).unwrap();
+    mmu.write_u64(0x3000 + 2 * 8, 0x4000 | TABLE, perm::NONE).unwrap();
+    mmu.write_u64(0x4000, 0x10000 | user_rw, perm::NONE).unwrap();
+    mmu.write_u64(0x4000 + 8, 0x11000 | kernel_ro_nx, perm::NONE).unwrap();
+    mmu.write_u64(0x4000 + 2 * 8, 0x4000 | X::PRESENT | X::WRITABLE, perm::NONE).unwrap();
+    mmu.write_u64(0x4000 + 3 * 8, 0x10000 | user_rw, perm::NONE).unwrap();
+    mmu.write_u32(0x11000, 0x1111, perm::NONE).unwrap();
+    mmu.write_u32(0x20_1234, 0x2222, perm::NONE).unwrap();
+
+    mmu.set_page_table_walker(Some(Box::new(X::default())));
+    mmu.set_page_table_root(0x1000);
+    assert_eq!(mmu.page_table_root(), Some(0x1000));
+
+    // Virtual addresses are translated using the page tables, including aliases and large pages.
+    mmu.write_u32(0x40_0010, 0xaaaa, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u32(0x40_3010, perm::READ), Ok(0xaaaa));
+    assert_eq!(mmu.read_u32(0x40_1000, perm::READ), Ok(0x1111));
+    assert_eq!(mmu.read_u32(0x20_1234, perm::READ), Ok(0x2222));
+    assert_eq!(mmu.read_u32(0x10010, perm::READ), Err(MemError::PageFault));
+    assert_eq!(
+        mmu.take_page_fault(),
+        Some(PageFault {
+            addr: 0x10010,
+            kind: AccessKind::Read,
+            user: false,
+            level: 2,
+            reason: PageFaultReason::NotPresent
+        })
+    );
+    assert_eq!(mmu.take_page_fault(), None);
+
+    // Permissions of the page tables are enforced.
+    assert_eq!(mmu.write_u32(0x40_1000, 0x0, perm::WRITE), Err(MemError::PageFault));
+    let fault = mmu.take_page_fault().unwrap();
+    assert_eq!((fault.level, fault.reason), (1, PageFaultReason::Protection));
+    assert_eq!(fault.x86_error_code(), 0b011);
+    assert!(mmu.ensure_executable(0x40_0000, 0x10));
+    assert!(!mmu.ensure_executable(0x40_1000, 0x10));
+    assert_eq!(mmu.take_page_fault().unwrap().x86_error_code(), 0b1_0001);
+
+    mmu.set_page_table_user_mode(true);
+    assert_eq!(mmu.read_u32(0x40_0010, perm::READ), Ok(0xaaaa));
+    assert_eq!(mmu.read_u32(0x40_1000, perm::READ), Err(MemError::PageFault));
+    assert_eq!(mmu.take_page_fault().unwrap().x86_error_code(), 0b101);
+    mmu.set_page_table_user_mode(false);
+
+    // Translations are cached, and can be invalidated individually.
+    assert_eq!(mmu.read_u32(0x40_0010, perm::READ), Ok(0xaaaa));
+    assert_eq!(mmu.read_u32(0x40_1000, perm::READ), Ok(0x1111));
+    mmu.invalidate_page(0x40_0000);
+    mmu.reset_counters();
+    assert_eq!(mmu.read_u32(0x40_0010, perm::READ), Ok(0xaaaa));
+    assert_eq!(mmu.read_u32(0x40_1000, perm::READ), Ok(0x1111));
+    assert_eq!(mmu.tlb_counters.read_misses, 1);
+
+    // Writing to a page table (mapped at 0x402000) invalidates the translations that use it.
+    mmu.write_u64(0x40_2000, 0x11000 | user_rw, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u32(0x40_0000, perm::READ), Ok(0x1111));
+
+    // Changing the root flushes all translations.
+    mmu.set_page_table_root(0x5000);
+    assert_eq!(mmu.read_u32(0x40_0000, perm::READ), Err(MemError::PageFault));
+    assert_eq!(mmu.take_page_fault().unwrap().level, 4);
+    mmu.set_page_table_root(0x1000);
+
+    // Accessed and dirty bits are updated, and the memory map refers to physical memory once the
+    // walker is removed.
+    mmu.set_page_table_walker(None);
+    assert_eq!(mmu.page_table_root(), None);
+    assert_eq!(mmu.read_u32(0x10010, perm::READ), Ok(0xaaaa));
+    let pte = mmu.read_u64(0x4000 + 3 * 8, perm::NONE).unwrap();
+    assert_eq!(pte & (X::ACCESSED | X::DIRTY), X::ACCESSED);
+    let pte = mmu.read_u64(0x4000 + 2 * 8, perm::NONE).unwrap();
+    assert_eq!(pte & (X::ACCESSED | X::DIRTY), X::ACCESSED | X::DIRTY);
+}
+
+#[test]
+fn unicorn_compat() {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::unicorn_compat::{
+        HookType, MemRegion, PROT_ALL, PROT_NONE, PROT_READ, PROT_WRITE, UcError, UnicornCompat,
+    };
+
+    let mut mmu = Mmu::new();
+    let rw = PROT_READ | PROT_WRITE;
+
+    // Regions must be page aligned, non-empty and have valid permissions.
+    assert_eq!(mmu.mem_map(0x1001, 0x1000, rw), Err(UcError::Arg));
+    assert_eq!(mmu.mem_map(0x1000, 0x1001, rw), Err(UcError::Arg));
+    assert_eq!(mmu.mem_map(0x1000, 0x0, rw), Err(UcError::Arg));
+    assert_eq!(mmu.mem_map(0x1000, 0x1000, PROT_ALL + 1), Err(UcError::Arg));
+    assert_eq!(mmu.mem_map(0xffff_ffff_ffff_f000, 0x2000, rw), Err(UcError::Arg));
+
+    mmu.mem_map(0x1000, 0x2000, rw).unwrap();
+    mmu.mem_map(0x3000, 0x1000, PROT_NONE).unwrap();
+    assert_eq!(mmu.mem_map(0x2000, 0x2000, rw), Err(UcError::Map));
+
+    // Reads and writes ignore permissions, and memory starts zeroed.
+    let mut buf = [0xff; 4];
+    mmu.mem_read(0x1ffe, &mut buf).unwrap();
+    assert_eq!(buf, [0; 4]);
+    mmu.mem_write(0x3000, &[1, 2, 3, 4]).unwrap();
+    mmu.mem_read(0x3000, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(mmu.read_u32(0x3000, perm::READ), Err(MemError::ReadViolation));
+    assert_eq!(mmu.mem_read(0x3ffe, &mut buf), Err(UcError::ReadUnmapped));
+    assert_eq!(mmu.mem_write(0x5000, &buf), Err(UcError::WriteUnmapped));
+
+    // Adjacent regions with the same permissions are merged.
+    let region = |begin, end, perms| MemRegion { begin, end, perms };
+    assert_eq!(mmu.mem_regions(), [region(0x1000, 0x2fff, rw), region(0x3000, 0x3fff, PROT_NONE)]);
+    mmu.mem_protect(0x3000, 0x1000, rw).unwrap();
+    mmu.mem_protect(0x1000, 0x1000, PROT_READ).unwrap();
+    assert_eq!(mmu.mem_regions(), [region(0x1000, 0x1fff, PROT_READ), region(0x2000, 0x3fff, rw)]);
+    assert_eq!(mmu.read_u32(0x3000, perm::READ), Ok(0x04030201));
+
+    assert_eq!(mmu.mem_protect(0x1800, 0x1000, rw), Err(UcError::Arg));
+    assert_eq!(mmu.mem_protect(0x1000, 0x1000, 0x10), Err(UcError::Arg));
+    assert_eq!(mmu.mem_protect(0x3000, 0x2000, rw), Err(UcError::NoMem));
+    assert_eq!(mmu.mem_unmap(0x3000, 0x2000), Err(UcError::NoMem));
+    mmu.mem_unmap(0x3000, 0x1000).unwrap();
+    assert_eq!(mmu.mem_regions(), [region(0x1000, 0x1fff, PROT_READ), region(0x2000, 0x2fff, rw)]);
+
+    // Changing permissions does not initialize memory.
+    mmu.track_uninitialized = true;
+    mmu.map_memory_len(0x10000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    mmu.write_u32(0x10000, 0x1234, perm::WRITE).unwrap();
+    mmu.mem_protect(0x10000, 0x1000, PROT_READ).unwrap();
+    assert_eq!(mmu.read_u32(0x10000, perm::READ), Ok(0x1234));
+    assert_eq!(mmu.read_u32(0x10004, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    assert_eq!(mmu.write_u32(0x10000, 0x0, perm::WRITE), Err(MemError::WriteViolation));
+
+    // Hooks use inclusive ranges, with `begin > end` covering the entire address space.
+    let events = Rc::new(RefCell::new(vec![]));
+    let hook = |tag: &'static str| {
+        let events = events.clone();
+        Box::new(move |_: &mut Mmu, addr: u64, size: usize, value: u64| {
+            events.borrow_mut().push((tag, addr, size, value))
+        })
+    };
+    let all = mmu.hook_add(HookType::MemWrite, 1, 0, hook("all"));
+    let last = mmu.hook_add(HookType::MemWrite, 0x2ffc, 0x2fff, hook("last"));
+    mmu.hook_add(HookType::MemReadAfter, 0x2000, 0x2fff, hook("read"));
+    mmu.write_u32(0x2ffc, 0xaa, perm::WRITE).unwrap();
+    mmu.write_u32(0x2000, 0xbb, perm::WRITE).unwrap();
+    mmu.read_u32(0x2000, perm::READ).unwrap();
+    assert_eq!(events.borrow().as_slice(), [
+        ("all", 0x2ffc, 4, 0xaa),
+        ("last", 0x2ffc, 4, 0xaa),
+        ("all", 0x2000, 4, 0xbb),
+        ("read", 0x2000, 4, 0xbb),
+    ]);
+
+    // Removed hooks are no longer called, and their slots can be reused.
+    events.borrow_mut().clear();
+    mmu.hook_del(all).unwrap();
+    mmu.hook_del(last).unwrap();
+    assert_eq!(mmu.hook_del(last), Ok(()));
+    mmu.hook_add(HookType::MemWrite, 0x2000, 0x2000, hook("first"));
+    mmu.write_u8(0x2000, 0xcc, perm::WRITE).unwrap();
+    mmu.write_u8(0x2001, 0xdd, perm::WRITE).unwrap();
+    assert_eq!(events.borrow().as_slice(), [("first", 0x2000, 1, 0xcc)]);
+}
+
+#[test]
+fn debug_memory() {
+    use crate::{
+        AccessKind,
+        debug::{DebugMemory, WatchKind},
+    };
+
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: perm::READ, value: 0x11 });
+
+    // Reads return the accessible prefix and do not initialize memory.
+    let mut buf = [0; 8];
+    assert_eq!(mmu.read_dbg(0x1ffc, &mut buf), 4);
+    assert_eq!(&buf[..4], &[0x11; 4]);
+    assert_eq!(mmu.read_dbg(0x3000, &mut buf), 0);
+    assert_eq!(mmu.get_perm(0x1ffc) & perm::INIT, 0);
+
+    // Reads stop at I/O regions where the handler does not support peeking, without reading from
+    // the handler.
+    let device = RecordingDevice { base: 0x9000, data: vec![0; 0x10], accesses: vec![] };
+    let handler = mmu.register_io_handler(device);
+    mmu.map_memory_len(0x9000, 0x10, handler);
+    mmu.map_memory_len(0x8ff0, 0x10, Mapping { perm: perm::READ, value: 0x22 });
+    assert_eq!(mmu.read_dbg(0x8ffc, &mut buf), 4);
+    assert_eq!(&buf[..4], &[0x22; 4]);
+    assert!(take_accesses(&mut mmu, handler).is_empty());
+
+    // Writes ignore permissions and stop at the first unmapped byte.
+    assert_eq!(mmu.write_dbg(0x1ffe, &[1, 2, 3, 4]), 2);
+    assert_eq!(mmu.read_u16(0x1ffe, perm::READ | perm::INIT), Ok(0x0201));
+
+    mmu.map_memory_len(0x2000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    mmu.write_u32(0x2000, 0xaabbccdd, perm::WRITE).unwrap();
+
+    let write = mmu.set_watchpoint(0x2002, 2, WatchKind::Write).unwrap();
+    let read = mmu.set_watchpoint(0x2100, 1, WatchKind::Read).unwrap();
+    assert_eq!(mmu.find_watchpoint(0x2002, 2, WatchKind::Write), Some(write));
+    assert_eq!(mmu.find_watchpoint(0x2002, 2, WatchKind::Read), None);
+
+    // Accesses that overlap the watched range are reported, including accesses that start before
+    // the range.
+    mmu.write_u32(0x2000, 0x11223344, perm::WRITE).unwrap();
+    mmu.write_u16(0x2004, 0x5566, perm::WRITE).unwrap();
+    mmu.write_u8(0x2003, 0x77, perm::WRITE).unwrap();
+    mmu.read_u32(0x2000, perm::READ).unwrap();
+    mmu.read_u64(0x20f8, perm::READ).unwrap();
+    mmu.read_u8(0x2100, perm::READ).unwrap();
+
+    let hits = mmu.take_watch_hits();
+    assert_eq!(hits.len(), 3);
+    assert_eq!(
+        (hits[0].id, hits[0].kind, hits[0].addr, hits[0].size),
+        (write, AccessKind::Write, 0x2000, 4)
+    );
+    assert_eq!(hits[0].old, vec![0xbb, 0xaa]);
+    assert_eq!(hits[0].new, vec![0x22, 0x11]);
+    assert_eq!(
+        (hits[1].addr, hits[1].old.clone(), hits[1].new.clone()),
+        (0x2003, vec![0x11], vec![0x77])
+    );
+    assert_eq!(
+        (hits[2].id, hits[2].watch, hits[2].kind, hits[2].addr),
+        (read, WatchKind::Read, AccessKind::Read, 0x2100)
+    );
+    assert!(mmu.take_watch_hits().is_empty());
+
+    // Debugger writes do not trigger watchpoints, but are reflected in the old value of later hits.
+    assert_eq!(mmu.write_dbg(0x2002, &[0x99]), 1);
+    mmu.write_u8(0x2002, 0x00, perm::WRITE).unwrap();
+    let hits = mmu.take_watch_hits();
+    assert_eq!(hits.len(), 1);
+    assert_eq!((hits[0].old.clone(), hits[0].new.clone()), (vec![0x99], vec![0x00]));
+
+    assert!(mmu.remove_watchpoint(write));
+    assert!(!mmu.remove_watchpoint(write));
+    mmu.write_u32(0x2000, 0, perm::WRITE).unwrap();
+    assert!(mmu.take_watch_hits().is_empty());
+}
+
+#[test]
+fn core_dump() {
+    use object::{
+        Endianness,
+        elf::{FileHeader64, PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE},
+        read::elf::{FileHeader, ProgramHeader},
+    };
+
+    let mut mmu = Mmu::new();
+    let rw = perm::READ | perm::WRITE;
+
+    // An allocated page followed by a zeroed page with the same permissions is merged into one
+    // segment, where only the allocated page is stored in the file.
+    mmu.map_memory_len(0x1000, 0x2000, Mapping { perm: rw, value: 0 });
+    mmu.write_u32(0x1ffc, 0x11223344, perm::WRITE).unwrap();
+    mmu.map_memory_len(0x3000, 0x1000, Mapping { perm: perm::READ | perm::EXEC, value: 0x90 });
+
+    // Segments are split wherever the permissions of a page change.
+    mmu.map_memory_len(0x5000, 0x1000, Mapping { perm: rw, value: 0x55 });
+    mmu.write_u8(0x5000, 0x1, perm::WRITE).unwrap();
+    mmu.update_perm(0x5800, 0x800, perm::READ).unwrap();
+
+    // I/O regions are skipped.
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x8000, 0x1000, io);
+
+    let data: Vec<u8> = (0..0x800).map(|i| i as u8).collect();
+    let file = mmu.register_file(std::sync::Arc::new(data.clone()));
+    mmu.map_memory_len(0x9000, 0x1000, crate::FileMapping::new(file, 0x100, perm::READ));
+
+    let mut out = vec![];
+    mmu.write_core_dump(&mut out).unwrap();
+
+    let header = FileHeader64::<Endianness>::parse(&*out).unwrap();
+    let endian = header.endian().unwrap();
+    let headers = header.program_headers(endian, &*out).unwrap();
+
+    let note = headers.iter().find(|x| x.p_type(endian) == PT_NOTE).unwrap();
+    let mut notes = note.notes(endian, &*out).unwrap().unwrap();
+    let file_note = notes.next().unwrap().unwrap();
+    assert_eq!(file_note.name(), b"CORE");
+    // count, page size, start, end, offset
+    let desc: Vec<u64> = file_note.desc()[..40]
+        .chunks(8)
+        .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+        .collect();
+    assert_eq!(desc, [1, 1, 0x9000, 0xa000, 0x100]);
+
+    let loads: Vec<_> = headers
+        .iter()
+        .filter(|x| x.p_type(endian) == PT_LOAD)
+        .map(|x| {
+            let data = x.data(endian, &*out).unwrap();
+            (x.p_vaddr(endian), x.p_memsz(endian), x.p_flags(endian), data)
+        })
+        .collect();
+    let layout: Vec<_> =
+        loads.iter().map(|(addr, len, flags, data)| (*addr, *len, *flags, data.len())).collect();
+    assert_eq!(layout, [
+        (0x1000, 0x2000, PF_R | PF_W, 0x1000),
+        (0x3000, 0x1000, PF_R | PF_X, 0x1000),
+        (0x5000, 0x800, PF_R | PF_W, 0x800),
+        (0x5800, 0x800, PF_R, 0x800),
+        (0x9000, 0x1000, PF_R, 0x1000),
+    ]);
+
+    assert_eq!(loads[0].3[0xffc..], [0x44, 0x33, 0x22, 0x11]);
+    assert!(loads[1].3.iter().all(|x| *x == 0x90));
+    assert_eq!(loads[2].3[..2], [0x1, 0x55]);
+    assert_eq!(loads[4].3[..0x700], data[0x100..]);
+    assert!(loads[4].3[0x700..].iter().all(|x| *x == 0));
+}
+
+#[test]
+fn load_image() {
+    use crate::image::{LoadError, LoadedSegment, Segment, write_manifest};
+
+    let text: Vec<u8> = (1..=0x10).collect();
+    let segments = [
+        Segment::new(".text", 0x1000, &text[..], perm::READ | perm::EXEC),
+        Segment {
+            size: 0x20,
+            ..Segment::new(".data", 0x1100, &[1, 2, 3, 4][..], perm::READ | perm::WRITE)
+        },
+        Segment::new(".empty", 0x5000, vec![], perm::READ),
+        Segment { size: 0x2000, ..Segment::new(".bss", 0x3000, vec![], perm::READ | perm::WRITE) },
+    ];
+
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    let report = mmu.load_image(&segments).unwrap();
+    let loaded = |name: &str, start, end, data_len| LoadedSegment {
+        name: name.into(),
+        start,
+        end,
+        data_len,
+    };
+    assert_eq!(report.segments, [
+        loaded(".text", 0x1000, 0x100f, 0x10),
+        loaded(".data", 0x1100, 0x111f, 0x4),
+        loaded(".bss", 0x3000, 0x4fff, 0x0),
+    ]);
+    assert_eq!(report.skipped, [".empty"]);
+    assert_eq!(report.padding, [(0x1010, 0x10ff), (0x1120, 0x1fff)]);
+
+    // Data is written regardless of the permissions of the segment, and bytes past the end of the
+    // data are zeroed.
+    let mut buf = [0; 0x10];
+    mmu.read_bytes(0x1000, &mut buf, perm::EXEC | perm::INIT).unwrap();
+    assert_eq!(buf[..], text[..]);
+    assert_eq!(mmu.write_u8(0x1000, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u64(0x1100, perm::READ | perm::INIT), Ok(0x04030201));
+    assert_eq!(mmu.read_u64(0x4ff8, perm::READ | perm::INIT), Ok(0));
+    assert_unmapped!(mmu, 0x5000);
+
+    // Padding is mapped, but can not be accessed.
+    assert_eq!(mmu.read_u8(0x1010, perm::READ), Err(MemError::ReadViolation));
+    assert_eq!(mmu.read_u8(0x1fff, perm::READ), Err(MemError::ReadViolation));
+
+    // Conflicts are detected before anything is mapped.
+    let mut mmu = Mmu::new();
+    let overlapping = [
+        Segment::new("a", 0x1000, vec![0; 0x10], perm::READ),
+        Segment::new("b", 0x2000, vec![0; 0x10], perm::READ),
+        Segment { size: 0x1000, ..Segment::new("c", 0x1008, vec![], perm::READ) },
+    ];
+    assert!(matches!(
+        mmu.load_image(&overlapping),
+        Err(LoadError::Overlap(a, c)) if a == "a" && c == "c"
+    ));
+    assert_unmapped!(mmu, 0x1000);
+    assert_unmapped!(mmu, 0x2000);
+
+    mmu.map_memory_len(0x2008, 0x8, Mapping { perm: perm::READ, value: 0 });
+    assert!(
+        matches!(mmu.load_image(&overlapping[..2]), Err(LoadError::AlreadyMapped(b)) if b == "b")
+    );
+    let bss_too_small =
+        [Segment { size: 0x1, ..Segment::new("x", 0x8000, vec![0; 2], perm::READ) }];
+    assert!(matches!(mmu.load_image(&bss_too_small), Err(LoadError::InvalidSegment(_))));
+    assert_unmapped!(mmu, 0x1000);
+
+    // Segments can be loaded from a manifest, where existing mappings in the same page are kept.
+    let mut manifest = vec![];
+    write_manifest(&mut manifest, &overlapping[..2]).unwrap();
+    mmu.unmap_memory_len(0x2008, 0x8);
+    mmu.map_memory_len(0x2800, 0x8, Mapping { perm: perm::READ, value: 0 });
+    let report = mmu.load_image_from_reader(&manifest[..]).unwrap();
+    assert_eq!(report.segments.len(), 2);
+    assert_eq!(report.padding, [(0x1010, 0x1fff), (0x2010, 0x27ff), (0x2808, 0x2fff)]);
+
+    assert!(matches!(
+        Mmu::new().load_image_from_reader(&manifest[..manifest.len() - 1]),
+        Err(LoadError::InvalidManifest(_))
+    ));
+    manifest[0] = 0;
+    assert!(matches!(
+        Mmu::new().load_image_from_reader(&manifest[..]),
+        Err(LoadError::InvalidManifest(_))
+    ));
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn serialize_mapping() {
+    use crate::VirtualMemoryMap;
+
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x2000, Mapping { perm: perm::READ | perm::WRITE, value: 0xaa });
+    mmu.write_u32(0x1000, 0x1234, perm::WRITE).unwrap();
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x4000, 0x100, io);
+    assert!(mmu.get_physical_index(0x1000).is_some());
+
+    let json = mmu.export_mapping_json();
+    let mapping: VirtualMemoryMap = serde_json::from_str(&json).unwrap();
+    assert!(mapping.iter().eq(mmu.get_mapping().iter()));
+
+    // The mapping can only be restored if every physical page and I/O handler it refers to exists.
+    assert_eq!(Mmu::new().try_restore_virtual_mapping(mapping.clone()), Err(MemError::Unallocated));
+    mmu.reset_virtual();
+    assert_unmapped!(mmu, 0x1000);
+    assert_eq!(mmu.try_restore_virtual_mapping(mapping), Ok(()));
+    assert_eq!(mmu.read_u32(0x1000, perm::READ), Ok(0x1234));
+    assert_eq!(mmu.read_u8(0x2000, perm::READ), Ok(0xaa));
+
+    let overlapping = r#"[
+        { "start": 0, "end": 15, "value": { "Reserved": 0 } },
+        { "start": 8, "end": 31, "value": { "Reserved": 0 } }
+    ]"#;
+    assert!(serde_json::from_str::<VirtualMemoryMap>(overlapping).is_err());
+}
+
+#[test]
+fn regions() {
+    use crate::{Region, RegionKind};
+
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    let rw = perm::READ | perm::WRITE;
+    mmu.map_memory_len(0x1000, 0x4000, Mapping { perm: rw, value: 0 });
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x5000, 0x1000, io);
+
+    // Allocating pages fragments the mapping, but regions with the same permissions are merged.
+    mmu.write_u8(0x1000, 1, perm::WRITE).unwrap();
+    mmu.write_u8(0x2000, 1, perm::WRITE).unwrap();
+    mmu.update_perm(0x2800, 0x10, perm::READ).unwrap();
+    assert_eq!(mmu.get_mapping().iter().count(), 4);
+
+    let region = |start, end, perm, kind| Region { start, end, perm, kind };
+    let expected = [
+        region(0x1000, 0x27ff, rw, RegionKind::Physical),
+        region(0x2800, 0x280f, perm::READ, RegionKind::Physical),
+        region(0x2810, 0x2fff, rw, RegionKind::Physical),
+        region(0x3000, 0x4fff, rw, RegionKind::Unallocated),
+        region(0x5000, 0x5fff, rw, RegionKind::Io(io)),
+    ];
+    assert_eq!(mmu.regions().collect::<Vec<_>>(), expected);
+
+    // Scanning regions does not allocate or initialize memory.
+    assert!(mmu.get_physical_index(0x3000).is_none());
+    assert_eq!(mmu.get_perm(0x2004) & perm::INIT, 0);
+}
+
+#[test]
+fn perm_range() {
+    use crate::{IoPermPolicy, PermRangeError};
+
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    let rw = perm::READ | perm::WRITE;
+    mmu.map_memory_len(0x1000, 0x2000, Mapping { perm: rw, value: 0 });
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x4000, 0x1000, io);
+
+    let error = |addr, error| Err(PermRangeError { addr, error });
+
+    assert_eq!(mmu.get_perm_range(0x1000, 0x2000), perm::MAP | rw);
+    assert_eq!(mmu.check_perm_range(0x1000, 0x2000, rw), Ok(()));
+    assert_eq!(
+        mmu.check_perm_range(0x1000, 0x2000, perm::INIT),
+        error(0x1000, MemError::Uninitalized)
+    );
+
+    // Ranges that span allocated pages check the permissions of each byte.
+    mmu.write_u32(0x1800, 0x1234, perm::WRITE).unwrap();
+    assert_eq!(mmu.get_perm_range(0x1800, 4), perm::MAP | perm::INIT | rw);
+    assert_eq!(
+        mmu.check_perm_range(0x17ff, 8, perm::READ | perm::INIT),
+        error(0x17ff, MemError::Uninitalized)
+    );
+    assert_eq!(
+        mmu.check_perm_range(0x1800, 8, perm::READ | perm::INIT),
+        error(0x1804, MemError::Uninitalized)
+    );
+
+    mmu.update_perm(0x1900, 0x10, perm::READ).unwrap();
+    mmu.update_perm(0x2100, 0x10, perm::READ).unwrap();
+    assert_eq!(mmu.get_perm_range(0x1000, 0x2000), perm::MAP | perm::READ);
+    assert_eq!(
+        mmu.check_perm_range(0x1000, 0x2000, perm::WRITE),
+        error(0x1900, MemError::WriteViolation)
+    );
+    assert_eq!(
+        mmu.check_perm_range(0x2000, 0x1000, perm::WRITE),
+        error(0x2100, MemError::WriteViolation)
+    );
+
+    // Unmapped bytes fail every check.
+    assert_eq!(mmu.get_perm_range(0x2ff0, 0x20), perm::NONE);
+    assert_eq!(mmu.check_perm_range(0x2ff0, 0x20, perm::NONE), error(0x3000, MemError::Unmapped));
+    assert_eq!(mmu.check_perm_range(0x2ff0, 0, perm::READ), Ok(()));
+
+    // I/O regions are handled according to the policy.
+    assert_eq!(mmu.check_perm_range(0x4000, 0x10, perm::READ), error(0x4000, MemError::Unmapped));
+    mmu.io_perm_policy = IoPermPolicy::Allow;
+    assert_eq!(mmu.check_perm_range(0x4000, 0x10, rw | perm::INIT), Ok(()));
+    assert_eq!(
+        mmu.check_perm_range(0x4000, 0x10, perm::EXEC),
+        error(0x4000, MemError::ExecViolation)
+    );
+
+    // Checking permissions never allocates memory.
+    assert!(mmu.get_physical_index(0x2000).is_none());
+}
+
+#[test]
+fn watchpoints() {
+    use crate::{
+        AccessKind,
+        watch::{WatchpointHit, WatchpointKind},
+    };
+
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x2000, Mapping {
+        perm: perm::READ | perm::WRITE | perm::INIT,
+        value: 0,
+    });
+    mmu.write_u64(0x1000, 0x8877_6655_4433_2211, perm::WRITE).unwrap();
+
+    assert_eq!(mmu.add_watchpoint(0x1000, 0, WatchpointKind::Write), None);
+    let write = mmu.add_watchpoint(0x1004, 2, WatchpointKind::Write).unwrap();
+    let access = mmu.add_watchpoint(0x1007, 1, WatchpointKind::Access).unwrap();
+
+    // Accesses to other bytes on the same page are not reported.
+    mmu.write_u32(0x1000, 0xaabbccdd, perm::WRITE).unwrap();
+    mmu.read_u8(0x1006, perm::READ).unwrap();
+    mmu.write_u8(0x1006, 0xee, perm::WRITE).unwrap();
+    assert!(mmu.take_watchpoint_hits().is_empty());
+
+    // Accesses that start before the watched range and end inside of it are reported, including
+    // unaligned accesses.
+    mmu.write_u64(0x1000, 0x0102_0304_0506_0708, perm::WRITE).unwrap();
+    mmu.write_u16(0x1005, 0xf0f1, perm::WRITE).unwrap();
+    mmu.read_u32(0x1006, perm::READ).unwrap();
+    let hits = mmu.take_watchpoint_hits();
+    assert_eq!(hits.len(), 4);
+    assert_eq!(hits[0], WatchpointHit {
+        seq: 0,
+        id: write,
+        watch: WatchpointKind::Write,
+        kind: AccessKind::Write,
+        addr: 0x1000,
+        size: 8,
+        start: 0x1004,
+        end: 0x1005,
+        old: vec![0x55, 0x66],
+        new: vec![0x04, 0x03],
+    });
+    assert_eq!((hits[1].id, hits[1].start, hits[1].end), (access, 0x1007, 0x1007));
+    assert_eq!((hits[1].old.clone(), hits[1].new.clone()), (vec![0x88], vec![0x01]));
+    assert_eq!((hits[2].id, hits[2].addr, hits[2].size), (write, 0x1005, 2));
+    assert_eq!((hits[2].start, hits[2].end, hits[2].old.clone()), (0x1005, 0x1005, vec![0x03]));
+    assert_eq!((hits[3].id, hits[3].kind, hits[3].size), (access, AccessKind::Read, 4));
+    assert_eq!((hits[3].start, hits[3].new.clone()), (0x1007, vec![0x01]));
+    assert!(hits.windows(2).all(|x| x[0].seq < x[1].seq));
+
+    // Accesses that ignore permissions are not reported.
+    mmu.write_u8(0x1004, 0, perm::NONE).unwrap();
+    mmu.read_u8(0x1007, perm::NONE).unwrap();
+    assert!(mmu.take_watchpoint_hits().is_empty());
+
+    // Watchpoints are kept (once) when a snapshot is restored, and report the restored value.
+    let snapshot = mmu.snapshot();
+    mmu.write_u8(0x1004, 0x11, perm::WRITE).unwrap();
+    mmu.restore(snapshot).unwrap();
+    mmu.write_u8(0x1004, 0x22, perm::WRITE).unwrap();
+    let hits = mmu.take_watchpoint_hits();
+    assert_eq!(hits.len(), 2);
+    assert_eq!((hits[1].old.clone(), hits[1].new.clone(), hits[1].seq), (vec![0], vec![0x22], 5));
+
+    assert!(mmu.remove_watchpoint(write));
+    assert!(!mmu.remove_watchpoint(write));
+    mmu.write_u8(0x1004, 0x33, perm::WRITE).unwrap();
+    assert!(mmu.take_watchpoint_hits().is_empty());
+}
+
+#[test]
+fn access_log() {
+    use crate::{AccessHistory, AccessKind, AccessRecord};
+
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x1000, Mapping {
+        perm: perm::READ | perm::WRITE | perm::INIT,
+        value: 0,
+    });
+
+    // The log is disabled by default.
+    mmu.write_u32(0x1000, 0x1234, perm::WRITE).unwrap();
+    assert_eq!(mmu.access_log(), AccessHistory::default());
+
+    mmu.enable_access_log(4);
+    mmu.set_access_context(0x40_0000);
+    mmu.write_u32(0x1000, 0xaabbccdd, perm::WRITE).unwrap();
+    mmu.set_access_context(0x40_0004);
+    assert_eq!(mmu.read_u16(0x1002, perm::READ), Ok(0xaabb));
+    assert_eq!(mmu.read_u8(0x3000, perm::READ), Err(MemError::Unmapped));
+    mmu.write_u8(0x1000, 0, perm::NONE).unwrap();
+
+    let log = mmu.access_log();
+    assert_eq!(log.dropped, 0);
+    assert_eq!(log.records, [
+        AccessRecord {
+            seq: 0,
+            context: 0x40_0000,
+            addr: 0x1000,
+            size: 4,
+            kind: AccessKind::Write,
+            value: 0xaabbccdd,
+            error: None,
+        },
+        AccessRecord {
+            seq: 1,
+            context: 0x40_0004,
+            addr: 0x1002,
+            size: 2,
+            kind: AccessKind::Read,
+            value: 0xaabb,
+            error: None,
+        },
+        AccessRecord {
+            seq: 2,
+            context: 0x40_0004,
+            addr: 0x3000,
+            size: 1,
+            kind: AccessKind::Read,
+            value: 0,
+            error: Some(MemError::Unmapped),
+        },
+    ]);
+
+    // Once the log is full, the oldest records are discarded. Accesses that hit the TLB are
+    // recorded the same as misses.
+    for i in 0..4 {
+        mmu.write_u8(0x1010 + i, i as u8, perm::WRITE).unwrap();
+    }
+    let log = mmu.access_log();
+    assert_eq!(log.dropped, 3);
+    assert_eq!(log.records.iter().map(|x| (x.seq, x.addr)).collect::<Vec<_>>(), [
+        (3, 0x1010),
+        (4, 0x1011),
+        (5, 0x1012),
+        (6, 0x1013)
+    ]);
+
+    // With sampling, only some successful accesses are recorded but failures are always recorded.
+    mmu.enable_access_log(8);
+    mmu.set_access_log_sampling(3);
+    for i in 0..6 {
+        mmu.read_u8(0x1000 + i, perm::READ).unwrap();
+    }
+    mmu.write_u8(0x3000, 0, perm::WRITE).unwrap_err();
+    let log = mmu.access_log();
+    assert_eq!(log.dropped, 4);
+    assert_eq!(log.records.iter().map(|x| (x.addr, x.error)).collect::<Vec<_>>(), [
+        (0x1000, None),
+        (0x1003, None),
+        (0x3000, Some(MemError::Unmapped))
+    ]);
+
+    // The log is cleared when a snapshot is restored.
+    let snapshot = mmu.snapshot();
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.access_log(), AccessHistory::default());
+
+    mmu.disable_access_log();
+    mmu.read_u8(0x1000, perm::READ).unwrap();
+    assert!(mmu.access_log().records.is_empty());
+}
+
+#[test]
+fn heatmap() {
+    use crate::PageHeat;
+
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x3000, Mapping {
+        perm: perm::READ | perm::WRITE | perm::INIT,
+        value: 0,
+    });
+    assert!(mmu.heatmap_top(4).is_empty());
+
+    mmu.enable_heatmap();
+    for i in 0..10 {
+        mmu.write_u32(0x2000 + i * 4, i as u32, perm::WRITE).unwrap();
+        mmu.read_u32(0x1000 + i * 4, perm::READ).unwrap();
+        mmu.read_u32(0x2000 + i * 4, perm::READ).unwrap();
+    }
+    mmu.read_u8(0x3fff, perm::READ).unwrap();
+    mmu.write_u8(0x3000, 0, perm::NONE).unwrap();
+
+    assert_eq!(mmu.heatmap_top(4), [
+        PageHeat { page: 0x2000, reads: 10, writes: 10 },
+        PageHeat { page: 0x1000, reads: 10, writes: 0 },
+        PageHeat { page: 0x3000, reads: 1, writes: 0 },
+    ]);
+    assert_eq!(mmu.heatmap_top(1).len(), 1);
+
+    // Counts are kept when a snapshot is restored.
+    let snapshot = mmu.snapshot();
+    mmu.write_u8(0x3000, 1, perm::WRITE).unwrap();
+    mmu.restore(snapshot).unwrap();
+    mmu.write_u8(0x3000, 1, perm::WRITE).unwrap();
+    assert_eq!(mmu.heatmap_top(4)[2], PageHeat { page: 0x3000, reads: 1, writes: 2 });
+
+    mmu.reset_heatmap();
+    assert!(mmu.heatmap_top(4).is_empty());
+    mmu.read_u8(0x1000, perm::READ).unwrap();
+    assert_eq!(mmu.heatmap_top(4), [PageHeat { page: 0x1000, reads: 1, writes: 0 }]);
+
+    mmu.disable_heatmap();
+    mmu.read_u8(0x1000, perm::READ).unwrap();
+    assert!(mmu.heatmap_top(4).is_empty());
+}
+
+#[test]
+fn fill_pages() {
+    let rw = perm::READ | perm::WRITE;
+    let mut mmu = Mmu::new();
+    let flash = 0x1000_0000;
+    let guard = 0x8000_0000;
+    assert!(mmu.map_fill_region(flash, 0x4000_0000, 0xff, rw));
+    assert!(mmu.map_fill_region(guard, 0x10_0000, 0xcc, perm::READ));
+    let allocated = mmu.memory_stats().allocated_pages;
+
+    // Reads are backed by a single shared page for each value and permission.
+    for i in 0..0x400 {
+        let addr = flash + i * 0x10_0123;
+        assert_eq!(mmu.read_u8(addr, perm::READ), Ok(0xff), "{addr:#x}");
+        assert_eq!(mmu.read_u32(guard + i * 0x321, perm::READ), Ok(0xcccc_cccc));
+    }
+    assert_eq!(mmu.memory_stats().allocated_pages, allocated + 2);
+    let fill_page = mmu.get_physical_index(flash).unwrap();
+    assert_eq!(mmu.get_physical_index(flash + 0x3ff4_8bdd), Some(fill_page));
+
+    // Writes copy the page.
+    mmu.write_u8(flash + 0x10, 0x12, perm::WRITE).unwrap();
+    assert_eq!(mmu.memory_stats().allocated_pages, allocated + 3);
+    assert_ne!(mmu.get_physical_index(flash), Some(fill_page));
+    assert_eq!(mmu.read_u8(flash + 0x10, perm::READ), Ok(0x12));
+    assert_eq!(mmu.read_u8(flash + 0x11, perm::READ), Ok(0xff));
+    assert_eq!(mmu.read_u8(flash + 0x1000, perm::READ), Ok(0xff));
+    assert_eq!(mmu.write_u8(guard, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u8(guard, perm::READ), Ok(0xcc));
+
+    // Filling or changing the permissions of part of a shared page (including the zero page) only
+    // affects the page that was modified.
+    let zero = 0x9000_0000;
+    mmu.map_memory_len(zero, 0x2000, Mapping { perm: perm::MAP | rw | perm::INIT, value: 0 });
+    for addr in [flash + 0x1000, zero] {
+        let value = mmu.read_u8(addr, perm::READ).unwrap();
+        mmu.read_u8(addr + 0x1000, perm::READ).unwrap();
+        mmu.fill_mem(addr + 0x100, 0x10, 0x34).unwrap();
+        mmu.update_perm(addr + 0x200, 0x10, perm::READ).unwrap();
+        assert_eq!(mmu.read_u8(addr + 0x100, perm::READ), Ok(0x34));
+        assert_eq!(mmu.read_u8(addr + 0x1100, perm::READ), Ok(value));
+        assert_eq!(mmu.write_u8(addr + 0x200, 0, perm::WRITE), Err(MemError::WriteViolation));
+        mmu.write_u8(addr + 0x1200, 0x56, perm::WRITE).unwrap();
+    }
+
+    // Whole pages are swapped to the fill page with the new permissions.
+    let allocated = mmu.memory_stats().allocated_pages;
+    mmu.read_u8(flash + 0x5000, perm::READ).unwrap();
+    mmu.fill_mem(flash + 0x5000, 0x1000, 0xff).unwrap();
+    mmu.update_perm(flash + 0x5000, 0x1000, perm::READ).unwrap();
+    assert_eq!(mmu.memory_stats().allocated_pages, allocated + 1);
+    assert_eq!(mmu.write_u8(flash + 0x5000, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u8(flash + 0x6000, perm::READ), Ok(0xff));
+    mmu.write_u8(flash + 0x6000, 0, perm::WRITE).unwrap();
+
+    // Fill pages are restored with snapshots.
+    let snapshot = mmu.snapshot();
+    mmu.write_u8(flash + 0x7000, 0x78, perm::WRITE).unwrap();
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u8(flash + 0x7000, perm::READ), Ok(0xff));
+    assert_eq!(mmu.get_physical_index(flash + 0x7000), Some(fill_page));
+}
+
+#[test]
+fn pointer_traversal() {
+    use crate::TraversalEnd;
+
+    let rw = perm::READ | perm::WRITE;
+    let mut mmu = Mmu::new();
+    assert!(mmu.map_memory_len(0x1000, 0x2000, Mapping { perm: rw, value: 0x0 }));
+
+    // Pointers are read using the current pointer size.
+    mmu.write_u64(0x1000, 0x1122_3344_5566_7788, perm::NONE).unwrap();
+    assert_eq!(mmu.pointer_size(), 8);
+    assert_eq!(mmu.read_ptr(0x1000, perm::READ), Ok(0x1122_3344_5566_7788));
+    mmu.set_address_mask(0xffff_ffff);
+    assert_eq!(mmu.pointer_size(), 4);
+    assert_eq!(mmu.read_ptr(0x1000, perm::READ), Ok(0x5566_7788));
+    mmu.set_pointer_size(8);
+    assert_eq!(mmu.read_ptr(0x1000, perm::READ), Ok(0x1122_3344_5566_7788));
+
+    // A pointer array whose terminator would be on an unmapped page.
+    let end = 0x3000;
+    for i in 1..=4 {
+        mmu.write_u64(end - i * 8, i, perm::NONE).unwrap();
+    }
+    assert_eq!(
+        mmu.read_ptr_array(end - 32, usize::MAX),
+        (vec![4, 3, 2, 1], TraversalEnd::Fault { addr: end, error: MemError::Unmapped })
+    );
+    assert_eq!(mmu.read_ptr_array(end - 32, 2), (vec![4, 3], TraversalEnd::Limit));
+    mmu.write_u64(end - 8, 0, perm::NONE).unwrap();
+    assert_eq!(mmu.read_ptr_array(end - 32, 3), (vec![4, 3, 2], TraversalEnd::Null));
+
+    // A linked list with the next pointer at offset 8 that loops back to its second node.
+    let nodes = [0x2000, 0x2100, 0x2200, 0x2300];
+    for (node, next) in nodes.iter().zip(nodes.iter().skip(1)) {
+        mmu.write_u64(node + 8, *next, perm::NONE).unwrap();
+    }
+    mmu.write_u64(0x2308, 0x2100, perm::NONE).unwrap();
+
+    let mut visited = vec![];
+    let end = mmu.follow_list(0x2000, 8, usize::MAX, |_, node| visited.push(node));
+    assert_eq!(end, TraversalEnd::Cycle(0x2100));
+    assert_eq!(visited, nodes);
+
+    visited.clear();
+    let end = mmu.follow_list(0x2000, 8, 2, |_, node| visited.push(node));
+    assert_eq!(end, TraversalEnd::Limit);
+    assert_eq!(visited, nodes[..2]);
+
+    mmu.write_u64(0x2308, 0, perm::NONE).unwrap();
+    assert_eq!(mmu.follow_list(0x2000, 8, usize::MAX, |_, _| {}), TraversalEnd::Null);
+    mmu.write_u64(0x2308, 0x8000, perm::NONE).unwrap();
+    assert_eq!(mmu.follow_list(0x2000, 8, usize::MAX, |_, _| {}), TraversalEnd::Fault {
+        addr: 0x8008,
+        error: MemError::Unmapped
+    });
+
+    // The traversal limit also applies to strings.
+    mmu.write_bytes(0x1800, b"hello\0", perm::NONE).unwrap();
+    let mut buf = vec![];
+    assert_eq!(mmu.read_cstr(0x1800, &mut buf), Ok(0x1805));
+    assert_eq!(buf, b"hello");
+    mmu.traversal_limit = 4;
+    buf.clear();
+    assert_eq!(mmu.read_cstr(0x1800, &mut buf), Err(MemError::LimitExceeded));
+    assert_eq!(buf, b"hell");
+    assert_eq!(mmu.follow_list(0x2000, 8, usize::MAX, |_, _| {}), TraversalEnd::Limit);
+}
+
+#[test]
+fn compare_memory() {
+    use crate::{IoPermPolicy, PermRangeError};
+
+    let rw = perm::READ | perm::WRITE;
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    assert!(mmu.map_memory_len(0x1000, 0x4000, Mapping { perm: rw, value: 0xaa }));
+    assert!(mmu.map_memory_len(0x8000, 0x2000, Mapping { perm: rw, value: 0xaa }));
+    mmu.write_bytes(0x2ffe, &[1, 2, 3, 4], perm::WRITE).unwrap();
+    let allocated = mmu.memory_stats().allocated_pages;
+    let perm_before = mmu.get_perm(0x1000);
+
+    // Compare a range that contains both allocated and unallocated pages.
+    let mut expected = vec![0xaa; 0x4000];
+    expected[0x1ffe..0x2002].copy_from_slice(&[1, 2, 3, 4]);
+    assert_eq!(mmu.compare_bytes(0x1000, &expected, perm::READ), Ok(None));
+    expected[0x2001] = 5;
+    assert_eq!(mmu.compare_bytes(0x1000, &expected, perm::READ), Ok(Some(0x3001)));
+    expected[0x10] = 0;
+    assert_eq!(mmu.compare_bytes(0x1000, &expected, perm::READ), Ok(Some(0x1010)));
+    assert_eq!(mmu.compare_bytes(0x1000, &[], perm::READ), Ok(None));
+
+    // Every byte is checked before comparing.
+    assert_eq!(
+        mmu.compare_bytes(0x4ff0, &[0; 0x20], perm::READ),
+        Err(PermRangeError { addr: 0x5000, error: MemError::Unmapped })
+    );
+    assert_eq!(
+        mmu.compare_bytes(0x2ffc, &[0; 8], perm::READ | perm::INIT),
+        Err(PermRangeError { addr: 0x2ffc, error: MemError::Uninitalized })
+    );
+    assert_eq!(mmu.compare_bytes(0x2ffe, &[1, 2, 3, 4], perm::READ | perm::INIT), Ok(None));
+
+    // Comparing has no side effects.
+    assert_eq!(mmu.memory_stats().allocated_pages, allocated);
+    assert_eq!(mmu.get_perm(0x1000), perm_before);
+
+    // Compare two guest ranges.
+    assert_eq!(mmu.compare_ranges(0x1000, 0x8000, 0x1000, perm::READ), Ok(None));
+    assert_eq!(mmu.compare_ranges(0x1000, 0x8000, 0x2000, perm::READ), Ok(Some(0x1ffe)));
+    mmu.write_bytes(0x8ffe, &[1, 2, 3, 4], perm::WRITE).unwrap();
+    assert_eq!(mmu.compare_ranges(0x2000, 0x8000, 0x2000, perm::READ), Ok(None));
+    mmu.write_u8(0x9800, 0, perm::WRITE).unwrap();
+    assert_eq!(mmu.compare_ranges(0x2000, 0x8000, 0x2000, perm::READ), Ok(Some(0x1800)));
+    assert_eq!(
+        mmu.compare_ranges(0x2000, 0x8000, 0x3000, perm::READ),
+        Err(PermRangeError { addr: 0xa000, error: MemError::Unmapped })
+    );
+
+    // File mappings are compared against the data of the file, followed by zeros.
+    let file = mmu.register_file(std::sync::Arc::new(vec![0x11_u8; 0x100]));
+    let mapping = crate::FileMapping::new(file, 0, perm::READ);
+    assert!(mmu.map_memory_len(0x10000, 0x1000, mapping));
+    let mut expected = vec![0; 0x200];
+    expected[..0x100].fill(0x11);
+    assert_eq!(mmu.compare_bytes(0x10000, &expected, perm::READ), Ok(None));
+    expected[0x100] = 0x11;
+    assert_eq!(mmu.compare_bytes(0x10000, &expected, perm::READ), Ok(Some(0x10100)));
+
+    // I/O regions are not supported.
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x20000, 0x1000, io);
+    assert_eq!(
+        mmu.compare_bytes(0x20000, &[0; 4], perm::READ),
+        Err(PermRangeError { addr: 0x20000, error: MemError::Unmapped })
+    );
+    mmu.io_perm_policy = IoPermPolicy::Allow;
+    assert_eq!(
+        mmu.compare_bytes(0x20000, &[0; 4], perm::READ),
+        Err(PermRangeError { addr: 0x20000, error: MemError::Unsupported })
+    );
+}
+
+#[test]
+fn soft_capacity_limit() {
+    use crate::AllocKind;
+
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x10000, 0x10000, rw);
+    let allocated = mmu.memory_stats().allocated_pages;
+
+    // Explicit allocations.
+    assert!(mmu.set_capacity_limits(allocated + 2, allocated + 8));
+    assert_eq!(mmu.soft_capacity(), allocated + 2);
+    mmu.alloc_physical(1).unwrap();
+    assert_eq!(mmu.take_capacity_events(), []);
+    mmu.alloc_physical(2).unwrap();
+    let events = mmu.take_capacity_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].kind, events[0].addr), (AllocKind::Explicit, None));
+    assert_eq!(events[0].soft_limit, allocated + 2);
+    assert_eq!(events[0].stats.allocated_pages, allocated + 3);
+    assert_eq!(events[0].stats.capacity, allocated + 8);
+
+    // Allocations above the soft limit do not generate more events.
+    mmu.alloc_physical(1).unwrap();
+    assert_eq!(mmu.take_capacity_events(), []);
+
+    // Lazily allocated pages.
+    let allocated = mmu.memory_stats().allocated_pages;
+    assert!(mmu.set_capacity_limits(allocated + 1, allocated + 8));
+    mmu.write_u32(0x10ffe, 0x1234, perm::WRITE).unwrap();
+    let events = mmu.take_capacity_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].kind, events[0].addr), (AllocKind::LazyFault, Some(0x10ffe)));
+    assert_eq!(events[0].stats.allocated_pages, allocated + 1);
+
+    // Copy-on-write pages (reads from initialized zero-filled memory map the shared zero page).
+    let init = perm::MAP | perm::READ | perm::WRITE | perm::INIT;
+    mmu.map_memory_len(0x30000, 0x1000, Mapping { perm: init, value: 0 });
+    assert_eq!(mmu.read_u8(0x30000, perm::READ), Ok(0));
+    let allocated = mmu.memory_stats().allocated_pages;
+    assert!(mmu.set_capacity_limits(allocated + 1, allocated + 8));
+    mmu.write_u8(0x30000, 0x1, perm::WRITE).unwrap();
+    let events = mmu.take_capacity_events();
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].kind, events[0].addr), (AllocKind::CopyOnWrite, Some(0x30000)));
+
+    // The hard limit still applies, and the soft limit is never above it.
+    assert!(mmu.set_capacity_limits(usize::MAX, allocated + 2));
+    assert_eq!(mmu.soft_capacity(), allocated + 2);
+    mmu.alloc_physical(1).unwrap();
+    assert_eq!(mmu.take_capacity_events()[0].kind, AllocKind::Explicit);
+    assert_eq!(mmu.alloc_physical(1), Err(MemError::OutOfMemory));
+}
+
+#[test]
+fn sub_page_mappings() {
+    let rw = perm::READ | perm::WRITE;
+    let mut mmu = Mmu::new();
+    mmu.profile_tlb = true;
+
+    // A page split into 1 KiB mappings with different permissions, and an unmapped hole at the end.
+    let base = 0x10000;
+    mmu.map_memory_len(base, 0x400, Mapping { perm: rw, value: 0x11 });
+    mmu.map_memory_len(base + 0x400, 0x400, Mapping { perm: perm::READ, value: 0x22 });
+    mmu.map_memory_len(base + 0x800, 0x400, Mapping { perm: rw, value: 0x33 });
+    assert_eq!(mmu.read_u8(base, perm::READ), Ok(0x11));
+    mmu.write_u8(base, 0x11, perm::WRITE).unwrap();
+
+    // Accesses to any of the mappings (including accesses that straddle the boundary between them)
+    // are serviced by the TLB.
+    mmu.reset_counters();
+    assert_eq!(mmu.read_u32(base + 0x3fc, perm::READ), Ok(0x1111_1111));
+    assert_eq!(mmu.read_u32(base + 0x3fe, perm::READ), Ok(0x2222_1111));
+    assert_eq!(mmu.read_u64(base + 0x7fd, perm::READ), Ok(0x3333_3333_3322_2222));
+    assert_eq!(mmu.read_u16(base + 0xbfe, perm::READ), Ok(0x3333));
+    mmu.write_u32(base + 0x3fc, 0x5555_5555, perm::WRITE).unwrap();
+    mmu.write_u32(base + 0x802, 0x6666_6666, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u64(base + 0x800, perm::READ), Ok(0x3333_6666_6666_3333));
+    let counters = mmu.tlb_counters;
+    assert_eq!((counters.read_misses, counters.write_misses), (0, 0), "{counters:?}");
+
+    // The permissions of each byte are still enforced.
+    assert_eq!(mmu.write_u16(base + 0x3fe, 0, perm::WRITE), Ok(()));
+    assert_eq!(mmu.write_u8(base + 0x400, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.write_u32(base + 0x3fe, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.write_u32(base + 0x7fe, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u8(base + 0x400, perm::READ), Ok(0x22));
+    assert_eq!(mmu.read_u16(base + 0xbfe, perm::READ), Ok(0x3333));
+    assert_eq!(mmu.read_u8(base + 0xc00, perm::READ), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_u32(base + 0xbfe, perm::READ), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_u64(base + 0xbf8, perm::READ), Ok(0x3333_3333_3333_3333));
+    assert_eq!(mmu.write_u64(base + 0xbfc, 0, perm::WRITE), Err(MemError::Unmapped));
+
+    // Aligned accesses that straddle an allocated mapping and an unallocated mapping merge the
+    // mappings into a single page.
+    let base = 0x20000;
+    mmu.map_memory_len(base, 0x804, Mapping { perm: rw, value: 0x11 });
+    mmu.write_u32(base + 0x800, 0x1234_5678, perm::WRITE).unwrap();
+    mmu.map_memory_len(base + 0x804, 0x7fc, Mapping { perm: perm::READ, value: 0x22 });
+    assert_ne!(mmu.get_physical_index(base + 0x804), mmu.get_physical_index(base));
+    assert_eq!(mmu.read_u64(base + 0x800, perm::READ), Ok(0x2222_2222_1234_5678));
+    assert_eq!(mmu.get_physical_index(base + 0x804), mmu.get_physical_index(base));
+    mmu.reset_counters();
+    assert_eq!(mmu.read_u64(base + 0x800, perm::READ), Ok(0x2222_2222_1234_5678));
+    assert_eq!(mmu.tlb_counters.read_misses, 0);
+    assert_eq!(mmu.write_u64(base + 0x800, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u64(base + 0x800, perm::READ), Ok(0x2222_2222_1234_5678));
+}
+
+#[test]
+fn runtime_config() {
+    use std::{cell::Cell, rc::Rc};
+
+    use crate::{MmuConfig, SelfModifyingCode};
+
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE | perm::INIT, value: 0 };
+    let setup = |config: MmuConfig| {
+        let mut mmu = Mmu::with_config(config);
+        mmu.map_memory_len(0x1000, 0x2000, rw);
+        mmu
+    };
+
+    // Reads from zeroed memory are mapped to a shared zero page only if the optimization is
+    // enabled.
+    let mut mmu = setup(MmuConfig::default());
+    mmu.read_u8(0x1000, perm::READ).unwrap();
+    assert!(mmu.get_physical_index(0x1000).unwrap().is_zero_page());
+
+    let mut mmu = setup(MmuConfig { zero_page_optimization: false, ..Default::default() });
+    mmu.read_u8(0x1000, perm::READ).unwrap();
+    mmu.read_u8(0x2000, perm::READ).unwrap();
+    let (a, b) = (mmu.get_physical_index(0x1000).unwrap(), mmu.get_physical_index(0x2000).unwrap());
+    assert!(!a.is_zero_page() && !b.is_zero_page() && a != b);
+
+    // Memory hooks are only called if they are enabled.
+    for enabled in [true, false] {
+        let mut mmu = setup(MmuConfig { memory_hooks: enabled, ..Default::default() });
+        let calls = Rc::new(Cell::new(0));
+        let calls_ = calls.clone();
+        mmu.add_write_hook(
+            0x1000,
+            0x2000,
+            Box::new(move |_: &mut Mmu, _: u64, _: &[u8]| calls_.set(calls_.get() + 1)),
+        );
+        mmu.write_u8(0x1000, 1, perm::WRITE).unwrap();
+        assert_eq!(calls.get(), enabled as u32);
+    }
+
+    // Writes to translated code are only detected if self-modifying code detection is enabled.
+    for detect in [true, false] {
+        let mut mmu = setup(MmuConfig { detect_self_modifying_code: detect, ..Default::default() });
+        let expected = match detect {
+            true => SelfModifyingCode::Fault,
+            false => SelfModifyingCode::Ignore,
+        };
+        assert_eq!(mmu.self_modifying_code, expected);
+
+        let rwx = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+        mmu.map_memory_len(0x4000, 0x1000, rwx);
+        mmu.write_bytes(0x4000, &[0x90; 4], perm::NONE).unwrap();
+        assert!(mmu.ensure_executable(0x4000, 4));
+        let result = mmu.write_u8(0x4000, 0xcc, perm::WRITE);
+        assert_eq!(result.is_err(), detect);
+    }
+}
+
+#[test]
+fn builder() {
+    use std::{cell::Cell, rc::Rc};
+
+    use crate::{BuildError, SelfModifyingCode};
+
+    let writes = Rc::new(Cell::new(0));
+    let writes_ = writes.clone();
+    let mut builder = Mmu::builder()
+        .capacity(16)
+        .track_uninitialized(true)
+        .self_modifying_code(SelfModifyingCode::Invalidate);
+    let a = builder.io_handler(Register(1));
+    let b = builder.io_handler(Register(2));
+    let mut mmu = builder
+        .region_with_data(0x1000, 0x2000, perm::READ | perm::WRITE, vec![0xaa; 0x1001])
+        .region(0x4000, 0x1000, perm::READ | perm::WRITE)
+        .io_region(0x5000, 0x10, a)
+        .io_region(0x5010, 0x10, b)
+        .write_hook(
+            0x4000,
+            0x5000,
+            Box::new(move |_: &mut Mmu, _: u64, _: &[u8]| writes_.set(writes_.get() + 1)),
+        )
+        .build()
+        .unwrap();
+
+    assert_eq!(mmu.capacity(), 16);
+    assert!(mmu.track_uninitialized);
+    assert_eq!(mmu.self_modifying_code, SelfModifyingCode::Invalidate);
+    assert_eq!(mmu.read_u8(0x2000, perm::READ | perm::INIT), Ok(0xaa));
+    assert_eq!(mmu.read_u8(0x2001, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    assert_eq!(mmu.read_u8(0x4000, perm::READ), Ok(0));
+    assert_eq!(mmu.read_u8(0x5000, perm::READ), Ok(1));
+    assert_eq!(mmu.read_u8(0x5010, perm::READ), Ok(2));
+    mmu.write_u8(0x4000, 0, perm::NONE).unwrap();
+    mmu.write_u8(0x4000, 0, perm::WRITE).unwrap();
+    assert_eq!(writes.get(), 1);
+
+    // The configuration is validated before the MMU is created.
+    let overlap =
+        Mmu::builder().region(0x1000, 0x1000, perm::READ).region(0x1800, 0x10, perm::READ);
+    assert_eq!(overlap.build().err(), Some(BuildError::Overlap(0x1000, 0x1800)));
+
+    let mut builder = Mmu::builder().region(0x1000, 0x1000, perm::READ);
+    let io = builder.io_handler(Register(0));
+    let overlap = builder.io_region(0x1ff0, 0x20, io);
+    assert_eq!(overlap.build().err(), Some(BuildError::Overlap(0x1000, 0x1ff0)));
+
+    let invalid = Mmu::builder().region_with_data(0x1000, 4, perm::READ, vec![0; 8]);
+    assert_eq!(invalid.build().err(), Some(BuildError::InvalidRegion(0x1000)));
+    let invalid = Mmu::builder().region(u64::MAX, 2, perm::READ);
+    assert_eq!(invalid.build().err(), Some(BuildError::InvalidRegion(u64::MAX)));
+
+    let small =
+        Mmu::builder().capacity(2).region_with_data(0xfff, 0x1002, perm::READ, vec![1; 0x1002]);
+    assert_eq!(
+        small.build().err(),
+        Some(BuildError::InsufficientCapacity { required: 3, capacity: 2 })
+    );
+}
+
+#[test]
+fn endianness() {
+    use crate::{Endianness, MmuConfig};
+
+    for endianness in [Endianness::Little, Endianness::Big] {
+        let mut mmu = Mmu::with_config(MmuConfig { endianness, ..Default::default() });
+        let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE | perm::INIT, value: 0 };
+        mmu.map_memory_len(0x1000, 0x2000, rw);
+
+        // Includes accesses that are unaligned and cross a page boundary.
+        for addr in [0x1000, 0x1801, 0x1fff, 0x1ffd] {
+            mmu.write_u16(addr, 0x0102, perm::WRITE).unwrap();
+            let expected = match endianness {
+                Endianness::Little => [0x02, 0x01],
+                Endianness::Big => [0x01, 0x02],
+            };
+            assert_eq!(mmu.read::<2>(addr, perm::READ).unwrap(), expected);
+            assert_eq!(mmu.read_u16(addr, perm::READ).unwrap(), 0x0102);
+
+            mmu.write_u32(addr, 0x0102_0304, perm::WRITE).unwrap();
+            let expected = match endianness {
+                Endianness::Little => [0x04, 0x03, 0x02, 0x01],
+                Endianness::Big => [0x01, 0x02, 0x03, 0x04],
+            };
+            assert_eq!(mmu.read::<4>(addr, perm::READ).unwrap(), expected);
+            assert_eq!(mmu.read_u32(addr, perm::READ).unwrap(), 0x0102_0304);
+
+            let value = 0x0102_0304_0506_0708;
+            mmu.write_u64(addr, value, perm::WRITE).unwrap();
+            assert_eq!(mmu.read::<8>(addr, perm::READ).unwrap(), match endianness {
+                Endianness::Little => u64::to_le_bytes(value),
+                Endianness::Big => u64::to_be_bytes(value),
+            });
+            assert_eq!(mmu.read_u64(addr, perm::READ).unwrap(), value);
+
+            // Accessors with an explicit byte order ignore the endianness of the guest.
+            mmu.write_u32_be(addr, 0x0102_0304, perm::WRITE).unwrap();
+            assert_eq!(mmu.read::<4>(addr, perm::READ).unwrap(), [0x01, 0x02, 0x03, 0x04]);
+            assert_eq!(mmu.read_u32_le(addr, perm::READ).unwrap(), 0x0403_0201);
+            mmu.write_u64_le(addr, value, perm::WRITE).unwrap();
+            assert_eq!(mmu.read_u64_be(addr, perm::READ).unwrap(), value.swap_bytes());
+            mmu.write_u16_le(addr, 0x0102, perm::WRITE).unwrap();
+            assert_eq!(mmu.read_u16_be(addr, perm::READ).unwrap(), 0x0201);
+        }
+
+        // Raw accesses are not affected by the endianness.
+        mmu.write(0x1000, [1, 2], perm::WRITE).unwrap();
+        assert_eq!(mmu.read::<2>(0x1000, perm::READ).unwrap(), [1, 2]);
+        assert_eq!(mmu.read_u8(0x1001, perm::READ).unwrap(), 2);
+    }
+}
+
+#[test]
+fn atomic_read_modify_write() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let mut mmu = Mmu::new();
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE | perm::INIT, value: 0 };
+    mmu.map_memory_len(0x1000, 0x2000, rw);
+    mmu.map_memory_len(0x3000, 0x1000, Mapping {
+        perm: perm::MAP | perm::READ | perm::INIT,
+        value: 0,
+    });
+    let io = mmu.register_io_handler(Register(5));
+    mmu.map_memory_len(0x4000, 0x10, io);
+
+    let accesses = Rc::new(RefCell::new(vec![]));
+    let (reads, writes) = (accesses.clone(), accesses.clone());
+    mmu.add_read_hook(
+        0x0,
+        0x5000,
+        Box::new(move |_: &mut Mmu, addr: u64, _: u8| {
+            reads.borrow_mut().push(("read", addr, vec![]));
+            None
+        }),
+    );
+    mmu.add_write_hook(
+        0x0,
+        0x5000,
+        Box::new(move |_: &mut Mmu, addr: u64, value: &[u8]| {
+            writes.borrow_mut().push(("write", addr, value.to_vec()))
+        }),
+    );
+
+    // Write hooks are called once with the final value, and read hooks are not called.
+    mmu.write_u32(0x1000, 10, perm::NONE).unwrap();
+    let old = mmu.fetch_update(0x1000, perm::READ, |x| (u32::from_le_bytes(x) + 5).to_le_bytes());
+    assert_eq!(old, Ok(10_u32.to_le_bytes()));
+    assert_eq!(mmu.read_u32(0x1000, perm::NONE), Ok(15));
+    assert_eq!(accesses.take(), vec![("write", 0x1000, 15_u32.to_le_bytes().to_vec())]);
+
+    // Unaligned accesses that cross a page boundary are treated as a single access.
+    mmu.write_u16(0x1fff, 0x1234, perm::NONE).unwrap();
+    let result = mmu.compare_exchange(0x1fff, 0x1234_u16.to_le_bytes(), [0xaa, 0xbb], perm::READ);
+    assert_eq!(result, Ok(Ok(0x1234_u16.to_le_bytes())));
+    assert_eq!(mmu.read::<2>(0x1fff, perm::NONE), Ok([0xaa, 0xbb]));
+    assert_eq!(accesses.take(), vec![("write", 0x1fff, vec![0xaa, 0xbb])]);
+
+    // A failed comparison returns the current value without writing to memory.
+    let result = mmu.compare_exchange(0x1000, [0; 4], [1; 4], perm::READ);
+    assert_eq!(result, Ok(Err(15_u32.to_le_bytes())));
+    assert_eq!(mmu.read_u32(0x1000, perm::NONE), Ok(15));
+    assert!(accesses.take().is_empty());
+
+    // The write permission is checked before memory is read.
+    let result = mmu.fetch_update(0x3000, perm::READ, |_| [1; 4]);
+    assert_eq!(result, Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u32(0x3000, perm::NONE), Ok(0));
+    assert!(accesses.take().is_empty());
+
+    // I/O regions are accessed using a separate read and write.
+    let old = mmu.fetch_update(0x4000, perm::READ, |x: [u8; 1]| [x[0] * 2]);
+    assert_eq!(old, Ok([5]));
+    assert_eq!(mmu.read_u8(0x4000, perm::NONE), Ok(10));
+    assert_eq!(accesses.take(), vec![("read", 0x4000, vec![]), ("write", 0x4000, vec![10])]);
+}
+
+#[test]
+fn set_protection() {
+    use crate::SelfModifyingCode;
+
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    mmu.map_memory_len(0x1000, 0x1000, Mapping {
+        perm: perm::MAP | perm::READ | perm::WRITE,
+        value: 0,
+    });
+    mmu.write_bytes(0x1000, &[0x11; 0x800], perm::WRITE).unwrap();
+
+    let init_pattern = |mmu: &Mmu| -> Vec<bool> {
+        (0x1000..0x2000).map(|addr| mmu.get_perm(addr) & perm::INIT != 0).collect()
+    };
+    let before = init_pattern(&mmu);
+
+    mmu.set_protection(0x1000, 0x1000, perm::READ).unwrap();
+    assert_eq!(mmu.write_u8(0x1000, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(init_pattern(&mmu), before);
+
+    mmu.set_protection(0x1000, 0x1000, perm::READ | perm::WRITE).unwrap();
+    assert_eq!(init_pattern(&mmu), before);
+    assert_eq!(mmu.read_u8(0x17ff, perm::READ | perm::INIT), Ok(0x11));
+    assert_eq!(mmu.read_u8(0x1800, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+
+    // Unallocated memory keeps its initialization state.
+    mmu.map_memory_len(0x2000, 0x1000, Mapping {
+        perm: perm::MAP | perm::READ | perm::INIT,
+        value: 0,
+    });
+    mmu.set_protection(0x2000, 0x1000, perm::READ | perm::WRITE).unwrap();
+    assert_eq!(mmu.get_perm(0x2000), perm::MAP | perm::READ | perm::WRITE | perm::INIT);
+    assert_eq!(mmu.read_u8(0x2000, perm::READ | perm::INIT), Ok(0));
+
+    // Making translated code writable fails if self-modifying code is not supported.
+    let rx = Mapping { perm: perm::READ | perm::EXEC, value: 0 };
+    mmu.map_memory_len(0x4000, 0x1000, rx);
+    mmu.write_bytes(0x4000, &[0x90; 4], perm::NONE).unwrap();
+    assert!(mmu.ensure_executable(0x4000, 4));
+    mmu.self_modifying_code = SelfModifyingCode::Fault;
+    let result = mmu.set_protection(0x4000, 0x1000, perm::READ | perm::WRITE | perm::EXEC);
+    assert_eq!(result, Err(MemError::SelfModifyingCode));
+    assert_eq!(mmu.get_perm(0x4000) & perm::WRITE, 0);
+
+    mmu.self_modifying_code = SelfModifyingCode::Invalidate;
+    mmu.set_protection(0x4000, 0x1000, perm::READ | perm::WRITE | perm::EXEC).unwrap();
+    assert_ne!(mmu.get_perm(0x4000) & perm::IN_CODE_CACHE, 0);
+
+    assert_eq!(mmu.set_protection(0x8000, 0x1000, perm::READ), Err(MemError::Unmapped));
+}
+
+#[test]
+fn mapping_change_callbacks() {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{MappingChange, MappingChangeKind};
+
+    let mut mmu = Mmu::new();
+    let changes = Rc::new(RefCell::new(vec![]));
+    let changes_ = changes.clone();
+    let id = mmu.on_mapping_change(Box::new(move |change: &MappingChange| {
+        changes_.borrow_mut().push((change.kind, change.start, change.end))
+    }));
+    let other = Rc::new(RefCell::new(0));
+    let other_ = other.clone();
+    mmu.on_mapping_change(Box::new(move |_: &MappingChange| *other_.borrow_mut() += 1));
+
+    let generation = mmu.mapping_generation();
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    assert!(mmu.map_memory_len(0x1000, 0x2000, rw));
+    mmu.update_perm(0x1000, 0x1000, perm::READ).unwrap();
+    mmu.set_protection(0x2000, 0x1000, perm::READ).unwrap();
+    mmu.move_region_len(0x2000, 0x1000, 0x5000).unwrap();
+    assert!(mmu.unmap_memory_len(0x1000, 0x1000));
+    let snapshot = mmu.snapshot();
+    mmu.restore(snapshot).unwrap();
+
+    assert_eq!(changes.take(), vec![
+        (MappingChangeKind::Mapped, 0x1000, 0x2fff),
+        (MappingChangeKind::PermissionChanged, 0x1000, 0x1fff),
+        (MappingChangeKind::PermissionChanged, 0x2000, 0x2fff),
+        (MappingChangeKind::Moved { dst: 0x5000 }, 0x2000, 0x2fff),
+        (MappingChangeKind::Unmapped, 0x1000, 0x1fff),
+        (MappingChangeKind::Replaced, 0x0, u64::MAX),
+    ]);
+    // Each consumer observes every change, regardless of the other consumers.
+    assert_eq!(*other.borrow(), 6);
+    assert_eq!(mmu.mapping_generation(), generation + 6);
+
+    assert!(mmu.remove_mapping_change_callback(id));
+    assert!(!mmu.remove_mapping_change_callback(id));
+    mmu.reset_virtual();
+    assert!(changes.borrow().is_empty());
+    assert_eq!(*other.borrow(), 7);
+}
+
+#[test]
+fn with_physical_page_mut() {
+    let mut mmu = Mmu::new();
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE | perm::INIT, value: 0 };
+    mmu.map_memory_len(0x1000, 0x1000, rw);
+    mmu.write_u8(0x1000, 0x11, perm::WRITE).unwrap();
+
+    // Cache translations for the page, then share the data of the page with a snapshot so the
+    // data is moved when it is modified.
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0x11));
+    let snapshot = mmu.snapshot();
+    mmu.clear_page_modification_log();
+
+    let index = mmu.get_physical_index(0x1000).unwrap();
+    mmu.with_physical_page_mut(index, |data| data.data[0] = 0x22).unwrap();
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0x22));
+    assert_eq!(mmu.dirty_pages().collect::<Vec<_>>(), vec![0x1000]);
+
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0x11));
+
+    // Pages shared copy-on-write with another address space are copied before they are modified.
+    let old_mapping = mmu.snapshot_virtual_mapping();
+    mmu.with_physical_page_mut(index, |data| data.data[0] = 0x33).unwrap();
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0x33));
+    assert_ne!(mmu.get_physical_index(0x1000), Some(index));
+    mmu.restore_virtual_mapping(old_mapping);
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0x11));
+
+    // The shared zero page can not be modified.
+    mmu.map_memory_len(0x2000, 0x1000, rw);
+    assert_eq!(mmu.read_u8(0x2000, perm::READ), Ok(0));
+    let zero_page = mmu.get_physical_index(0x2000).unwrap();
+    assert!(zero_page.is_zero_page());
+    assert_eq!(mmu.with_physical_page_mut(zero_page, |_| ()), Err(MemError::Unsupported));
+}
+
+#[test]
+fn virtual_addrs_of() {
+    let mut mmu = Mmu::new();
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    for addr in [0x5000, 0x1000, 0x3000] {
+        mmu.map_memory_len(addr, 0x1000, rw);
+        mmu.write_u8(addr, 0xaa, perm::WRITE).unwrap();
+    }
+
+    // Merge the pages so that a single physical page is mapped at every address.
+    assert_eq!(mmu.dedup_pages(), 2);
+    let index = mmu.get_physical_index(0x1000).unwrap();
+    assert_eq!(mmu.virtual_addrs_of(index).collect::<Vec<_>>(), vec![0x1000, 0x3000, 0x5000]);
+
+    // Cache translations for all of the addresses.
+    mmu.profile_tlb = true;
+    for addr in [0x1000, 0x3000, 0x5000] {
+        mmu.read_u8(addr, perm::READ).unwrap();
+        mmu.read_u8(addr, perm::READ).unwrap();
+    }
+    assert_eq!((mmu.tlb_counters.read_hits, mmu.tlb_counters.read_misses), (3, 3));
+
+    mmu.invalidate_tlb_for_physical(index);
+    for addr in [0x1000, 0x3000, 0x5000] {
+        mmu.read_u8(addr, perm::READ).unwrap();
+    }
+    assert_eq!((mmu.tlb_counters.read_hits, mmu.tlb_counters.read_misses), (3, 6));
+
+    // The index is kept up to date as the mapping changes.
+    assert!(mmu.unmap_memory_len(0x3000, 0x1000));
+    assert_eq!(mmu.virtual_addrs_of(index).collect::<Vec<_>>(), vec![0x1000, 0x5000]);
+    let snapshot = mmu.snapshot();
+    mmu.move_region_len(0x5000, 0x1000, 0x8000).unwrap();
+    assert_eq!(mmu.virtual_addrs_of(index).collect::<Vec<_>>(), vec![0x1000, 0x8000]);
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.virtual_addrs_of(index).collect::<Vec<_>>(), vec![0x1000, 0x5000]);
+
+    // Pages are added to the index when they are allocated.
+    mmu.map_memory_len(0x9000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    mmu.write_u8(0x9000, 1, perm::WRITE).unwrap();
+    let allocated = mmu.get_physical_index(0x9000).unwrap();
+    assert_eq!(mmu.virtual_addrs_of(allocated).collect::<Vec<_>>(), vec![0x9000]);
+}
+
+#[test]
+fn bulk_write_hooks() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let mut mmu = Mmu::new();
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE | perm::INIT, value: 0 };
+    mmu.map_memory_len(0x1000, 0x3000, rw);
+
+    let writes = Rc::new(RefCell::new(vec![]));
+    let hook_writes = writes.clone();
+    mmu.add_write_hook(
+        0x1ff0,
+        0x2010,
+        Box::new(move |_: &mut Mmu, addr: u64, value: &[u8]| {
+            hook_writes.borrow_mut().push((addr, value.to_vec()))
+        }),
+    );
+    let bytes_written = |writes: &RefCell<Vec<(u64, Vec<u8>)>>| -> Vec<(u64, u8)> {
+        let mut bytes = vec![];
+        for (addr, value) in writes.take() {
+            bytes.extend(value.into_iter().enumerate().map(|(i, x)| (addr + i as u64, x)));
+        }
+        bytes
+    };
+
+    // Scalar writes call the hook for every access.
+    for addr in 0x1fe0..0x2020 {
+        mmu.write_u8(addr, 0xaa, perm::WRITE).unwrap();
+    }
+    let expected = bytes_written(&writes);
+    assert_eq!(expected, (0x1ff0..0x2010).map(|addr| (addr, 0xaa)).collect::<Vec<_>>());
+
+    // Bulk writes call the hook once per page, observing the same bytes.
+    mmu.write_bytes(0x1fe0, &[0xaa; 0x40], perm::WRITE).unwrap();
+    assert_eq!(writes.borrow().len(), 2);
+    assert_eq!(bytes_written(&writes), expected);
+
+    mmu.fill_mem(0x1fe0, 0x40, 0xaa).unwrap();
+    assert_eq!(writes.borrow().len(), 2);
+    assert_eq!(bytes_written(&writes), expected);
+
+    // Writes that fail part way through report the bytes written by the accesses that completed
+    // before the failure.
+    mmu.update_perm(0x2008, 0x10, perm::READ).unwrap();
+    let result = mmu.write_bytes(0x1fe0, &[0xbb; 0x40], perm::WRITE);
+    assert_eq!(result, Err(MemError::WriteViolation));
+    let expected: Vec<_> = (0x1ff0..0x2000).map(|addr| (addr, 0xbb)).collect();
+    assert_eq!(bytes_written(&writes), expected);
+
+    // Hooks are not called for writes that ignore permissions.
+    mmu.write_bytes(0x1fe0, &[0xcc; 0x40], perm::NONE).unwrap();
+    assert!(writes.borrow().is_empty());
+}
+
+#[test]
+fn atomic_bulk_writes() {
+    use crate::PartialWriteError;
+
+    let rw = perm::MAP | perm::READ | perm::WRITE | perm::INIT;
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: rw, value: 0xaa });
+    mmu.map_memory_len(0x2000, 0x1000, Mapping {
+        perm: perm::MAP | perm::READ | perm::INIT,
+        value: 0,
+    });
+
+    // A write spanning a RW -> R-- boundary leaves the writable part untouched.
+    let data = [0x11; 0x200];
+    let mut buf = [0; 0x200];
+    assert_eq!(mmu.write_bytes_atomic(0x1f00, &data, perm::WRITE), Err(MemError::WriteViolation));
+    mmu.read_bytes(0x1f00, &mut buf[..0x100], perm::READ).unwrap();
+    assert!(buf[..0x100].iter().all(|x| *x == 0xaa));
+    assert_eq!(mmu.write_bytes_atomic(0x1f00, &data[..0x10], perm::WRITE), Ok(()));
+    mmu.write_bytes(0x1f00, &[0xaa; 0x10], perm::WRITE).unwrap();
+
+    // Unmapped memory is also detected before anything is written.
+    assert_eq!(mmu.write_bytes_atomic(0xf00, &data, perm::NONE), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0xaa));
+
+    // The non-atomic functions report how much was written.
+    for len in [0x10, 0x200] {
+        let result = mmu.write_bytes_partial(0x1f00, &data[..len], perm::WRITE);
+        match len {
+            0x10 => assert_eq!(result, Ok(())),
+            _ => assert_eq!(
+                result,
+                Err(PartialWriteError { written: 0x100, error: MemError::WriteViolation })
+            ),
+        }
+    }
+    assert_eq!(mmu.write_bytes_partial(0x1ff8, &data[..0x10], perm::WRITE).unwrap_err().written, 8);
+    mmu.write_bytes(0x1f00, &[0xaa; 0x100], perm::WRITE).unwrap();
+
+    // Failures that can not be detected ahead of time (e.g. page budgets) are rolled back.
+    mmu.map_memory_len(0x4000, 0x2000, Mapping { perm: rw, value: 0 });
+    let budget = mmu.create_page_budget(1);
+    mmu.assign_budget(0x4000, 0x2000, budget);
+    assert_eq!(mmu.write_bytes_atomic(0x4f00, &data, perm::WRITE), Err(MemError::OutOfMemory));
+    mmu.read_bytes(0x4f00, &mut buf, perm::READ).unwrap();
+    assert!(buf.iter().all(|x| *x == 0));
+    assert_eq!(mmu.write_bytes_atomic(0x4000, &data, perm::WRITE), Ok(()));
+    mmu.read_bytes(0x4000, &mut buf, perm::READ).unwrap();
+    assert_eq!(buf, data);
+
+    // I/O regions are rejected.
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x3000, 0x1000, io);
+    mmu.io_perm_policy = crate::IoPermPolicy::Allow;
+    assert_eq!(mmu.write_bytes_atomic(0x2ff0, &[0; 0x20], perm::NONE), Err(MemError::Unsupported));
+    mmu.read_bytes(0x2ff0, &mut buf[..0x10], perm::READ).unwrap();
+    assert!(buf[..0x10].iter().all(|x| *x == 0));
+}
+
+#[test]
+fn slice_access() {
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE, value: 0 };
+    mmu.map_memory_len(0x1000, 0x2000, rw);
+    mmu.map_memory_len(0x3000, 0x1000, Mapping { perm: perm::MAP | perm::READ, value: 0 });
+    let io = mmu.register_io_handler(Register(5));
+    mmu.map_memory_len(0x4000, 0x10, io);
+
+    // Writing through a slice allocates the page, marks the range as initialized and logs the
+    // page as modified.
+    mmu.write_u32(0x1000, 0x1234, perm::WRITE).unwrap();
+    let snapshot = mmu.snapshot();
+    mmu.clear_page_modification_log();
+    let result = mmu.with_slice_mut(0x1ff0, 0x10, perm::WRITE, |buf| {
+        buf.copy_from_slice(&[0xaa; 0x10]);
+        buf.len()
+    });
+    assert_eq!(result, Ok(0x10));
+    assert!(mmu.modified.contains(0x1000));
+    assert_eq!(mmu.read_u8(0x1fef, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    assert_eq!(mmu.read_u64(0x1ff8, perm::READ | perm::INIT), Ok(0xaaaa_aaaa_aaaa_aaaa));
+    let result = mmu.with_slice(0x1000, 4, perm::READ | perm::INIT, |buf| buf.to_vec());
+    assert_eq!(result, Ok(0x1234_u32.to_le_bytes().to_vec()));
+
+    // Pages shared with a snapshot are copied before they are modified.
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u8(0x1ff0, perm::NONE), Ok(0));
+
+    // Permissions are checked for every byte of the range.
+    let result = mmu.with_slice_mut(0x3000, 0x10, perm::WRITE, |buf| buf.fill(1));
+    assert_eq!(result, Err(MemError::WriteViolation));
+    assert_eq!(mmu.with_slice(0x3000, 0x10, perm::READ, |buf| buf.to_vec()), Ok(vec![0; 0x10]));
+    let result = mmu.with_slice(0x5000, 0x10, perm::READ, |_| ());
+    assert_eq!(result, Err(MemError::Unmapped));
+
+    // Ranges that are not stored contiguously must use the copying path.
+    let result = mmu.with_slice(0x1ff0, 0x20, perm::READ, |_| ());
+    assert_eq!(result, Err(MemError::NotContiguous));
+    let result = mmu.with_slice(0x4000, 0x4, perm::READ, |_| ());
+    assert_eq!(result, Err(MemError::NotContiguous));
+    mmu.add_write_hook(0x2800, 0x2900, Box::new(|_: &mut Mmu, _: u64, _: &[u8]| {}));
+    let result = mmu.with_slice_mut(0x2700, 0x200, perm::WRITE, |_| ());
+    assert_eq!(result, Err(MemError::NotContiguous));
+    assert_eq!(mmu.with_slice(0x2700, 0x200, perm::READ, |_| ()), Ok(()));
+}
+
+/// A device that records the attributes of every access, and rejects accesses to a hole in the
+/// middle of its window.
+#[derive(Default)]
+struct AccessRecorder(std::rc::Rc<std::cell::RefCell<Vec<crate::IoAccess>>>);
+
+impl AccessRecorder {
+    fn check(&self, access: &crate::IoAccess) -> crate::MemResult<()> {
+        self.0.borrow_mut().push(*access);
+        match access.addr & 0xff {
+            0x20..=0x2f => Err(MemError::Unmapped),
+            0x30..=0x3f => Err(MemError::ReadViolation),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl crate::IoMemory for AccessRecorder {
+    fn read(&mut self, _: u64, _: &mut [u8]) -> crate::MemResult<()> {
+        unreachable!()
+    }
+
+    fn write(&mut self, _: u64, _: &[u8]) -> crate::MemResult<()> {
+        unreachable!()
+    }
+
+    fn read_access(&mut self, access: &crate::IoAccess, buf: &mut [u8]) -> crate::MemResult<()> {
+        assert_eq!(access.size, buf.len());
+        buf.fill(access.size as u8);
+        self.check(access)
+    }
+
+    fn write_access(&mut self, access: &crate::IoAccess, value: &[u8]) -> crate::MemResult<()> {
+        assert_eq!(access.size, value.len());
+        self.check(access)
+    }
+}
+
+#[test]
+fn io_access_attributes() {
+    use crate::{AccessKind, IoAccess};
+
+    let mut mmu = Mmu::new();
+    let device = AccessRecorder::default();
+    let accesses = device.0.clone();
+    let io = mmu.register_io_handler(device);
+    mmu.map_memory_len(0x4000, 0x40, io.with_perm(perm::READ | perm::WRITE | perm::EXEC));
+
+    // Handlers observe the size and kind of every access.
+    mmu.set_io_context(0x1234);
+    assert_eq!(mmu.read_u8(0x4000, perm::READ), Ok(1));
+    assert_eq!(mmu.read_u16(0x4000, perm::READ), Ok(0x0202));
+    assert_eq!(mmu.read_u32(0x4000, perm::READ), Ok(0x0404_0404));
+    assert_eq!(mmu.read_u64(0x4000, perm::READ), Ok(0x0808_0808_0808_0808));
+    assert_eq!(mmu.read::<16>(0x4010, perm::READ), Ok([16; 16]));
+    assert_eq!(mmu.read::<4>(0x4004, perm::EXEC), Ok([4; 4]));
+    let expected: Vec<_> = [(0x4000, 1), (0x4000, 2), (0x4000, 4), (0x4000, 8), (0x4010, 16)]
+        .into_iter()
+        .map(|(addr, size)| IoAccess { addr, size, kind: AccessKind::Read, context: 0x1234 })
+        .chain([IoAccess { addr: 0x4004, size: 4, kind: AccessKind::Execute, context: 0x1234 }])
+        .collect();
+    assert_eq!(accesses.take(), expected);
+
+    mmu.set_io_context(0);
+    mmu.write_u8(0x4000, 0, perm::WRITE).unwrap();
+    mmu.write_u16(0x4000, 0, perm::WRITE).unwrap();
+    mmu.write_u32(0x4000, 0, perm::WRITE).unwrap();
+    mmu.write_u64(0x4000, 0, perm::WRITE).unwrap();
+    mmu.write::<16>(0x4010, [0; 16], perm::WRITE).unwrap();
+    let sizes: Vec<_> = accesses.take().iter().map(|x| (x.size, x.kind)).collect();
+    assert_eq!(sizes, [1, 2, 4, 8, 16].map(|size| (size, AccessKind::Write)));
+
+    // Errors returned by the handler are propagated without retrying the access.
+    assert_eq!(mmu.read_u32(0x4020, perm::READ), Err(MemError::Unmapped));
+    assert_eq!(mmu.write_u32(0x4024, 0, perm::WRITE), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_u32(0x4030, perm::READ), Err(MemError::ReadViolation));
+    let sizes: Vec<_> = accesses.take().iter().map(|x| (x.addr, x.size)).collect();
+    assert_eq!(sizes, [(0x4020, 4), (0x4024, 4), (0x4030, 4)]);
+}
+
+#[test]
+fn io_permissions() {
+    use crate::{AccessKind, IoPermPolicy};
+
+    let mut mmu = Mmu::new();
+    let device = AccessRecorder::default();
+    let accesses = device.0.clone();
+    let io = mmu.register_io_handler(device);
+    mmu.map_memory_len(0x4000, 0x100, io.with_perm(perm::READ));
+    mmu.map_memory_len(0x4100, 0x100, io.with_perm(perm::WRITE));
+    mmu.map_memory_len(0x4200, 0x100, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    assert_eq!(mmu.get_perm(0x4000), perm::READ);
+    assert_eq!(mmu.get_perm(0x4100), perm::WRITE);
+
+    // Accesses without the required permissions fault without reaching the handler.
+    assert_eq!(mmu.read_u32(0x4000, perm::READ), Ok(0x0404_0404));
+    assert_eq!(mmu.write_u32(0x4000, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.write_u32(0x40fe, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u32(0x4100, perm::READ), Err(MemError::ReadViolation));
+    assert_eq!(mmu.write_u32(0x4100, 0, perm::WRITE), Ok(()));
+    assert_eq!(mmu.read_u32(0x4000, perm::EXEC), Err(MemError::ExecViolation));
+    assert_eq!(mmu.read_u32(0x4100, perm::NONE), Ok(0x0404_0404));
+    let kinds: Vec<_> = accesses.take().iter().map(|x| (x.addr, x.kind)).collect();
+    let expected =
+        [(0x4000, AccessKind::Read), (0x4100, AccessKind::Write), (0x4100, AccessKind::Read)];
+    assert_eq!(kinds, expected);
+
+    // Permission queries report the permissions of the mapping.
+    assert!(!mmu.is_regular_region(0x4000, 0x100));
+    assert!(mmu.is_accessible_region(0x4000, 0x100, perm::READ));
+    assert!(!mmu.is_accessible_region(0x4000, 0x100, perm::WRITE));
+    assert!(mmu.is_accessible_region(0x4100, 0x200, perm::WRITE));
+    assert!(!mmu.is_accessible_region(0x4100, 0x300, perm::WRITE));
+    assert_eq!(mmu.get_perm_range(0x4000, 0x100), perm::NONE);
+    mmu.io_perm_policy = IoPermPolicy::Allow;
+    assert_eq!(mmu.get_perm_range(0x4000, 0x100), perm::READ | perm::MAP | perm::INIT);
+
+    // The permissions of I/O regions are changed in the same way as regular memory.
+    mmu.set_protection(0x4000, 0x80, perm::READ | perm::WRITE).unwrap();
+    assert_eq!(mmu.get_perm(0x4000), perm::READ | perm::WRITE);
+    assert_eq!(mmu.get_perm(0x4080), perm::READ);
+    assert_eq!(mmu.write_u32(0x4000, 0, perm::WRITE), Ok(()));
+    assert_eq!(mmu.write_u32(0x4080, 0, perm::WRITE), Err(MemError::WriteViolation));
+    mmu.update_perm(0x4000, 0x80, perm::NONE).unwrap();
+    assert_eq!(mmu.read_u32(0x4000, perm::READ), Err(MemError::ReadViolation));
+}
+
+#[test]
+fn move_region_overlap() {
+    let mut mmu = Mmu::new();
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE | perm::INIT, value: 0 };
+    let pattern: Vec<u8> = (0..0x2000).map(|i| (i % 251) as u8).collect();
+    let read = |mmu: &mut Mmu, addr: u64, len: usize| {
+        let mut buf = vec![0; len];
+        mmu.read_bytes(addr, &mut buf, perm::READ).map(|_| buf)
+    };
+
+    // Forward moves where the destination overlaps with the source.
+    mmu.map_memory_len(0x1000, 0x2000, rw);
+    mmu.write_bytes(0x1000, &pattern, perm::WRITE).unwrap();
+    mmu.move_region_len(0x1000, 0x2000, 0x2000).unwrap();
+    assert_eq!(read(&mut mmu, 0x2000, 0x2000), Ok(pattern.clone()));
+    assert_eq!(mmu.read_u8(0x1fff, perm::NONE), Err(MemError::Unmapped));
+
+    // Backward moves, including by an offset that is not page aligned.
+    mmu.move_region_len(0x2000, 0x2000, 0x1000).unwrap();
+    assert_eq!(read(&mut mmu, 0x1000, 0x2000), Ok(pattern.clone()));
+    mmu.move_region_len(0x1000, 0x2000, 0xff8).unwrap();
+    assert_eq!(read(&mut mmu, 0xff8, 0x2000), Ok(pattern.clone()));
+    assert_eq!(mmu.read_u8(0x2ff8, perm::NONE), Err(MemError::Unmapped));
+    mmu.move_region_len(0xff8, 0x2000, 0x1010).unwrap();
+    assert_eq!(read(&mut mmu, 0x1010, 0x2000), Ok(pattern.clone()));
+
+    // Moves into memory that is already mapped fail without modifying anything.
+    mmu.map_memory_len(0x8000, 0x1000, rw);
+    assert_eq!(mmu.move_region_len(0x1010, 0x2000, 0x7000), Err(MemError::AlreadyMapped));
+    assert_eq!(mmu.move_region_len(0x1000, 0x2000, 0x4000), Err(MemError::Unmapped));
+    assert_eq!(read(&mut mmu, 0x1010, 0x2000), Ok(pattern.clone()));
+    assert_eq!(mmu.move_region_len(0x8000, 0x1000, 0x1000), Err(MemError::AlreadyMapped));
+    assert_eq!(mmu.move_region_len(0x8000, 0, 0x1000), Ok(()));
+
+    // Moves near the end of the address space.
+    let top = u64::MAX - 0x1fff;
+    mmu.move_region_len(0x1010, 0x2000, top).unwrap();
+    assert_eq!(read(&mut mmu, top, 0x2000), Ok(pattern.clone()));
+    assert_eq!(mmu.move_region_len(top, 0x2000, top + 1), Err(MemError::AddressOverflow));
+    mmu.move_region_len(top, 0x2000, 0x1000).unwrap();
+    assert_eq!(read(&mut mmu, 0x1000, 0x2000), Ok(pattern.clone()));
+    assert_eq!(mmu.read_u8(u64::MAX, perm::NONE), Err(MemError::Unmapped));
+
+    // Running out of memory while copying regions leaves the source unchanged.
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x2000, rw);
+    mmu.write_bytes(0x1000, &pattern, perm::WRITE).unwrap();
+    let allocated = mmu.total_pages();
+    mmu.set_capacity(allocated + 3);
+    assert_eq!(mmu.move_region_len(0x1000, 0x2000, 0x5008), Err(MemError::OutOfMemory));
+    assert_eq!(mmu.total_pages(), allocated);
+    assert_eq!(read(&mut mmu, 0x1000, 0x2000), Ok(pattern.clone()));
+    assert_eq!(mmu.read_u8(0x5008, perm::NONE), Err(MemError::Unmapped));
+    mmu.set_capacity(allocated + 4);
+    mmu.move_region_len(0x1000, 0x2000, 0x5008).unwrap();
+    assert_eq!(read(&mut mmu, 0x5008, 0x2000), Ok(pattern));
+}
+
+#[test]
+#[cfg(all(unix, feature = "mmap"))]
+fn mmap_page_store() {
+    let config = crate::MmuConfig { page_store: crate::PageStore::Mmap, ..Default::default() };
+    let mut mmu = Mmu::with_config(config);
+    assert!(mmu.set_capacity(100_000));
+    assert_eq!(mmu.memory_stats().committed_host_pages, None);
+
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE | perm::INIT, value: 0 };
+    let len = 0x200_0000;
+    assert!(mmu.map_memory_len(0x100_0000, len, rw));
+    for addr in (0x100_0000..0x100_0000 + len).step_by(0x1000) {
+        mmu.write_u64(addr, addr, perm::WRITE).unwrap();
+    }
+    let dirty = mmu.memory_stats().committed_host_pages.unwrap();
+    assert!(dirty >= (len / 0x1000) as usize, "{dirty} host pages committed");
+
+    // Pages shared with a snapshot are copied before they are modified.
+    let snapshot = mmu.snapshot();
+    mmu.write_u64(0x100_0000, 0xdead, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u64(0x100_0000, perm::READ), Ok(0xdead));
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u64(0x100_0000, perm::READ), Ok(0x100_0000));
+    assert_eq!(mmu.read_u64(0x1ff_f000, perm::READ), Ok(0x1ff_f000));
+
+    mmu.clear();
+    let cleared = mmu.memory_stats().committed_host_pages.unwrap();
+    assert!(cleared < dirty / 2, "{cleared} host pages committed after clear, {dirty} before");
+
+    // Memory released to the host is zeroed when it is reused.
+    assert!(mmu.map_memory_len(0x1000, 0x1000, rw));
+    assert_eq!(mmu.read_u64(0x1000, perm::READ), Ok(0));
+}
+
+#[test]
+fn memory_cursor() {
+    use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+    use object::{Object, ObjectSection, read::ReadCache};
+
+    let mut elf = object::write::Object::new(
+        object::BinaryFormat::Elf,
+        object::Architecture::X86_64,
+        object::Endianness::Little,
+    );
+    let text = elf.section_id(object::write::StandardSection::Text);
+    elf.append_section_data(text, &[0x90; 0x20], 16);
+    let elf = elf.write().unwrap();
+
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE, value: 0 };
+    assert!(mmu.map_memory_len(0x10000, 0x4000, rw));
+
+    // Write the file across several pages through the cursor.
+    let mut cursor = mmu.cursor(0x10ff0, perm::WRITE);
+    cursor.write_all(&elf).unwrap();
+    assert_eq!(cursor.position(), elf.len() as u64);
+    assert_eq!(mmu.read_u8(0x10ff0, perm::READ | perm::INIT), Ok(elf[0]));
+    assert!(mmu.modified.contains(0x11000));
+
+    // Parse the file directly out of guest memory.
+    let cache = ReadCache::new(mmu.cursor(0x10ff0, perm::READ).with_limit(elf.len() as u64));
+    let file = object::File::parse(&cache).unwrap();
+    assert_eq!(file.architecture(), object::Architecture::X86_64);
+    let section = file.section_by_name(".text").unwrap();
+    assert_eq!(section.data().unwrap(), &[0x90; 0x20]);
+    drop(file);
+    drop(cache);
+
+    // Reads stop at the limit.
+    let mut cursor = mmu.cursor(0x10ff0, perm::READ).with_limit(4);
+    let mut buf = vec![];
+    assert_eq!(cursor.read_to_end(&mut buf).unwrap(), 4);
+    assert_eq!(buf, elf[..4]);
+    assert_eq!(cursor.write(&[0]).unwrap_err().kind(), ErrorKind::WriteZero);
+    assert_eq!(cursor.seek(SeekFrom::End(-1)).unwrap(), 3);
+    assert_eq!(cursor.seek(SeekFrom::Current(-4)).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    // A read that reaches unmapped memory returns the bytes before it, and the error is returned
+    // by the next read.
+    let mut cursor = mmu.cursor(0x13ff0, perm::READ);
+    let mut buf = [0xff; 0x20];
+    assert_eq!(cursor.read(&mut buf).unwrap(), 0x10);
+    assert_eq!(cursor.addr(), 0x14000);
+    let err = cursor.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert_eq!(err.get_ref().unwrap().downcast_ref::<MemError>(), Some(&MemError::Unmapped));
+
+    // Permission errors are preserved.
+    let err = mmu.cursor(0x13000, perm::READ | perm::INIT).read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(err.get_ref().unwrap().downcast_ref::<MemError>(), Some(&MemError::Uninitalized));
+    mmu.update_perm(0x13000, 0x1000, perm::READ).unwrap();
+    let err = mmu.cursor(0x13000, perm::WRITE).write(&buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+    let mut cursor = mmu.cursor(u64::MAX - 1, perm::READ);
+    assert!(cursor.seek(SeekFrom::Start(1)).is_ok());
+    assert_eq!(cursor.seek(SeekFrom::Start(2)).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn uninit_value_and_poison_on_unmap() {
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE | perm::INIT, value: 0 };
+
+    // Bytes of a page that are not part of any mapping are filled with the uninit value, which is
+    // visible to reads that do not check for initialization if the page is mapped again.
+    for uninit_value in [crate::UNINIT_VALUE, 0x00, 0xcc] {
+        let mut mmu = Mmu::new();
+        mmu.track_uninitialized = true;
+        mmu.uninit_value = uninit_value;
+        assert!(mmu.map_memory_len(0x1000, 0x800, rw));
+        mmu.write_u8(0x1000, 0x1, perm::WRITE).unwrap();
+        let index = mmu.get_physical_index(0x1000).unwrap();
+        assert!(mmu.map_physical(0x5000, index));
+        assert_eq!(mmu.read_u8(0x5800, perm::READ), Err(MemError::Unmapped));
+        mmu.update_perm(0x5800, 0x800, perm::READ).unwrap();
+        assert_eq!(mmu.read_u8(0x5800, perm::READ), Ok(uninit_value));
+        assert_eq!(mmu.read_u8(0x5800, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    }
+
+    for poison_on_unmap in [false, true] {
+        let mut mmu = Mmu::new();
+        mmu.track_uninitialized = true;
+        mmu.uninit_value = 0xcc;
+        mmu.poison_on_unmap = poison_on_unmap;
+        assert!(mmu.map_memory_len(0x1000, 0x2000, rw));
+        mmu.write_bytes(0x1000, &[0x41; 0x2000], perm::WRITE).unwrap();
+
+        // Unmap an entire page, then remap the same physical page at the same address.
+        let index = mmu.get_physical_index(0x1000).unwrap();
+        assert!(mmu.unmap_memory_len(0x1000, 0x1000));
+        assert!(mmu.map_physical(0x1000, index));
+        mmu.update_perm(0x1000, 0x1000, perm::READ).unwrap();
+        let expected = if poison_on_unmap { 0xcc } else { 0x41 };
+        assert_eq!(mmu.read_u8(0x1800, perm::READ), Ok(expected));
+        if poison_on_unmap {
+            assert_eq!(mmu.read_u8(0x1800, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+        }
+
+        // Unmap part of a page.
+        let index = mmu.get_physical_index(0x2000).unwrap();
+        assert!(mmu.unmap_memory_len(0x2800, 0x800));
+        assert!(mmu.map_physical(0x8000, index));
+        mmu.update_perm(0x8000, 0x1000, perm::READ).unwrap();
+        assert_eq!(mmu.read_u8(0x8800, perm::READ), Ok(expected));
+        assert_eq!(mmu.read_u8(0x27ff, perm::READ), Ok(0x41));
+    }
+
+    // Pages shared with a snapshot keep their data.
+    let mut mmu = Mmu::new();
+    mmu.poison_on_unmap = true;
+    assert!(mmu.map_memory_len(0x1000, 0x1000, rw));
+    mmu.write_bytes(0x1000, &[0x41; 0x1000], perm::WRITE).unwrap();
+    let snapshot = mmu.snapshot();
+    assert!(mmu.unmap_memory_len(0x1000, 0x1000));
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u8(0x1800, perm::READ), Ok(0x41));
+
+    // The zero page is never modified.
+    let mut mmu = Mmu::new();
+    mmu.poison_on_unmap = true;
+    assert!(mmu.map_memory_len(0x1000, 0x2000, rw));
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0));
+    assert!(mmu.unmap_memory_len(0x1000, 0x1000));
+    assert_eq!(mmu.read_u8(0x2000, perm::READ), Ok(0));
+}
+
+#[test]
+fn hooks_match_access_overlap() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let mut mmu = Mmu::new();
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE | perm::INIT, value: 0 };
+    assert!(mmu.map_memory_len(0x1000, 0x2000, rw));
+
+    // Hook the bytes in `0x1ff4..0x200c`, which straddles a page boundary.
+    let (start, end) = (0x1ff4, 0x200c);
+    let reads = Rc::new(RefCell::new(vec![]));
+    let read_after = Rc::new(RefCell::new(vec![]));
+    let writes = Rc::new(RefCell::new(vec![]));
+    let log = reads.clone();
+    mmu.add_read_hook(
+        start,
+        end,
+        Box::new(move |_: &mut Mmu, addr: u64, size: u8| {
+            log.borrow_mut().push((addr, size as usize));
+            None
+        }),
+    );
+    let log = read_after.clone();
+    mmu.add_read_after_hook(
+        start,
+        end,
+        Box::new(move |_: &mut Mmu, addr: u64, value: &[u8]| {
+            log.borrow_mut().push((addr, value.len()))
+        }),
+    );
+    let log = writes.clone();
+    mmu.add_write_hook(
+        start,
+        end,
+        Box::new(move |_: &mut Mmu, addr: u64, value: &[u8]| {
+            log.borrow_mut().push((addr, value.len()))
+        }),
+    );
+
+    // (address, size, expected to overlap)
+    let accesses = [
+        (0x1ff3, 1, false),
+        (0x1ff4, 1, true),
+        (0x200b, 1, true),
+        (0x200c, 1, false),
+        (0x1fe8, 8, false),
+        (0x1ff0, 8, true),
+        (0x2008, 8, true),
+        (0x2010, 8, false),
+        (0x1fe0, 16, false),
+        (0x1ff0, 16, true),
+        (0x2000, 16, true),
+        (0x2010, 16, false),
+    ];
+    for (addr, size, overlaps) in accesses {
+        reads.borrow_mut().clear();
+        read_after.borrow_mut().clear();
+        writes.borrow_mut().clear();
+        match size {
+            1 => {
+                mmu.write_u8(addr, 0, perm::WRITE).unwrap();
+                mmu.read_u8(addr, perm::READ).unwrap();
+            }
+            8 => {
+                mmu.write_u64(addr, 0, perm::WRITE).unwrap();
+                mmu.read_u64(addr, perm::READ).unwrap();
+            }
+            _ => {
+                mmu.write::<16>(addr, [0; 16], perm::WRITE).unwrap();
+                mmu.read::<16>(addr, perm::READ).unwrap();
+            }
+        }
+        let expected = if overlaps { vec![(addr, size)] } else { vec![] };
+        assert_eq!(*reads.borrow(), expected, "read of {size} bytes at {addr:#x}");
+        assert_eq!(*read_after.borrow(), expected, "read of {size} bytes at {addr:#x}");
+        assert_eq!(*writes.borrow(), expected, "write of {size} bytes at {addr:#x}");
+    }
+}
+
+#[test]
+fn peek_and_poke() {
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    mmu.profile_tlb = true;
+
+    // Peeking at a large unallocated region does not allocate any pages.
+    let allocated = mmu.memory_stats().allocated_pages;
+    let rw = Mapping { perm: perm::MAP | perm::READ | perm::WRITE, value: 0x55 };
+    assert!(mmu.map_memory_len(0x100_0000, 0x100_0000, rw));
+    let mut buf = vec![0; 0x10_0000];
+    mmu.peek_bytes(0x100_0000, &mut buf).unwrap();
+    assert!(buf.iter().all(|x| *x == 0x55));
+    assert_eq!(mmu.memory_stats().allocated_pages, allocated);
+
+    // Pokes do not initialize memory or record the page as modified.
+    let poke = mmu.poke_bytes(0x100_0ffe, &[1, 2, 3, 4], false).unwrap();
+    assert_eq!(poke, crate::Poke { addr: 0x100_0ffe, old: vec![0x55; 4] });
+    assert_eq!(mmu.read_u32(0x100_0ffe, perm::READ), Ok(0x04030201));
+    assert_eq!(mmu.read_u8(0x100_0ffe, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    assert!(!mmu.modified.contains(0x100_0000) && !mmu.modified.contains(0x100_1000));
+
+    mmu.undo_poke(&poke).unwrap();
+    assert_eq!(mmu.read_u32(0x100_0ffe, perm::READ), Ok(0x55555555));
+
+    // Read-only memory can only be modified when protection is ignored.
+    let code = Mapping { perm: perm::MAP | perm::READ | perm::EXEC | perm::INIT, value: 0x90 };
+    assert!(mmu.map_memory_len(0x1000, 0x1000, code));
+    mmu.read_u8(0x1000, perm::EXEC).unwrap();
+    assert_eq!(mmu.poke_bytes(0x1000, &[0xcc], false), Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0x90));
+    let poke = mmu.poke_bytes(0x1000, &[0xcc], true).unwrap();
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0xcc));
+    mmu.undo_poke(&poke).unwrap();
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0x90));
+
+    // Pokes to pages shared with a snapshot do not modify the snapshot.
+    let snapshot = mmu.snapshot();
+    mmu.poke_bytes(0x1000, &[0xcc], true).unwrap();
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0x90));
+
+    // Failed pokes do not modify memory.
+    assert_eq!(mmu.poke_bytes(0x1ffe, &[0; 4], true), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_u8(0x1ffe, perm::READ), Ok(0x90));
+    let io = mmu.register_io_handler(crate::NullMemory);
+    assert!(mmu.map_memory_len(0x3000, 0x1000, io));
+    assert_eq!(mmu.poke_bytes(0x3000, &[0], true), Err(MemError::Unsupported));
+
+    // Neither peek nor poke affect the TLB counters.
+    let counters = mmu.tlb_counters;
+    mmu.peek_bytes(0x100_0000, &mut buf).unwrap();
+    mmu.poke_bytes(0x100_0000, &[0], false).unwrap();
+    assert_eq!(mmu.tlb_counters, counters);
+}
+
+#[test]
+fn memory_tags() {
+    use crate::{AccessKind, tags::TagLabel};
+
+    let rw = perm::READ | perm::WRITE;
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x3000, Mapping { perm: rw, value: 0 });
+    mmu.map_memory_len(0x8000, 0x1000, Mapping { perm: perm::READ, value: 0 });
+
+    assert_eq!(mmu.tag_range(0x1000, 0, "empty"), None);
+    assert_eq!(mmu.tag_range(u64::MAX, 2, "overflow"), None);
+
+    let heap = mmu.tag_range(0x1000, 0x3000, "heap").unwrap();
+    let alloc = mmu.tag_range(0x1100, 0x20, 7).unwrap();
+    let guard = mmu.tag_range(0x1120, 0x10, "redzone".to_string()).unwrap();
+    assert!(heap < alloc && alloc < guard);
+
+    let labels = |mmu: &Mmu, addr| mmu.tags_at(addr).map(|x| x.label.clone()).collect::<Vec<_>>();
+    assert_eq!(labels(&mmu, 0x1000), [TagLabel::from("heap")]);
+    assert_eq!(labels(&mmu, 0x111f), [TagLabel::from("heap"), TagLabel::Value(7)]);
+    assert_eq!(labels(&mmu, 0x1120), [TagLabel::from("heap"), TagLabel::from("redzone")]);
+    assert_eq!(labels(&mmu, 0x4000), []);
+    assert_eq!(TagLabel::Value(7).to_string(), "0x7");
+
+    // Tags are reported for faults at the tagged address.
+    mmu.update_perm(0x1120, 0x10, rw | perm::GUARD).unwrap();
+    assert_eq!(mmu.write_u32(0x111e, 1, perm::WRITE), Err(MemError::GuardPage));
+    let fault = mmu.take_guard_fault().unwrap();
+    assert_eq!((fault.addr, fault.kind), (0x1120, AccessKind::Write));
+    assert_eq!(fault.tags.iter().map(|x| x.id).collect::<Vec<_>>(), [heap, guard]);
+    assert_eq!((fault.tags[1].start, fault.tags[1].end), (0x1120, 0x112f));
+
+    mmu.track_uninitialized = true;
+    mmu.uninit_diagnostics = true;
+    mmu.update_perm(0x1100, 0x20, rw).unwrap();
+    mmu.write_bytes(0x1100, &[0; 4], perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u64(0x1100, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    let report = mmu.take_uninit_report().unwrap();
+    assert_eq!(report.tags.iter().map(|x| x.id).collect::<Vec<_>>(), [heap, alloc]);
+
+    // Regions include every tag that overlaps with them.
+    let regions: Vec<_> = mmu
+        .regions_with_tags()
+        .map(|(region, tags)| (region.start, tags.iter().map(|x| x.id).collect::<Vec<_>>()))
+        .collect();
+    assert_eq!(regions, [
+        (0x1000, vec![heap, alloc, guard]),
+        (0x2000, vec![heap]),
+        (0x8000, vec![])
+    ]);
+
+    // Tags are kept when snapshots are restored and when memory is unmapped.
+    let snapshot = mmu.snapshot();
+    let stack = mmu.tag_range(0x8000, 0x1000, "stack").unwrap();
+    mmu.restore(snapshot).unwrap();
+    mmu.unmap_memory_len(0x8000, 0x1000);
+    assert_eq!(mmu.tags_at(0x8800).map(|x| x.id).collect::<Vec<_>>(), [stack]);
+
+    assert!(mmu.untag(alloc));
+    assert!(!mmu.untag(alloc));
+    assert_eq!(labels(&mmu, 0x1100), [TagLabel::from("heap")]);
+    assert_eq!(labels(&mmu, 0x1120), [TagLabel::from("heap"), TagLabel::from("redzone")]);
+
+    mmu.clear();
+    assert_eq!(mmu.tags_at(0x1000).count(), 0);
+}
+
+#[test]
+fn frozen_memory() {
+    use crate::{FrozenMemory, Region, RegionKind};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<FrozenMemory>();
+
+    let rw = perm::READ | perm::WRITE;
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x4000, Mapping { perm: rw, value: 0 });
+    mmu.map_memory_len(0x8000, 0x1000, Mapping { perm: perm::READ, value: 0x11 });
+    let file = mmu.register_file(std::sync::Arc::new(b"file\0data".to_vec()));
+    let mapping = crate::FileMapping::new(file, 0, perm::READ | perm::INIT);
+    assert!(mmu.map_memory_len(0x9000, 0x1000, mapping));
+
+    let pattern: Vec<u8> = (0..0x3000).map(|i| (i % 251) as u8 + 1).collect();
+    mmu.write_bytes(0x1000, &pattern, perm::WRITE).unwrap();
+    mmu.write_bytes(0x4000, b"hello\0", perm::WRITE).unwrap();
+    // Fill the TLB with a writable entry before freezing memory.
+    mmu.write_u8(0x1000, 1, perm::WRITE).unwrap();
+
+    let frozen = std::sync::Arc::new(mmu.freeze());
+    let expected_hash = frozen.hash_range(0x1000, 0x3000).unwrap();
+
+    let mut cstr = vec![];
+    assert_eq!(frozen.read_cstr(0x4000, &mut cstr), Ok(0x4005));
+    assert_eq!(cstr, b"hello");
+    cstr.clear();
+    assert_eq!(frozen.read_cstr(0x9000, &mut cstr), Ok(0x9004));
+    assert_eq!(cstr, b"file");
+
+    let mut buf = [0; 4];
+    frozen.read_bytes(0x8ffe, &mut buf, perm::READ).unwrap();
+    assert_eq!(buf, [0x11, 0x11, b'f', b'i']);
+    assert_eq!(frozen.read_bytes(0x8000, &mut buf, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(frozen.read_bytes(0x5ffe, &mut buf, perm::READ), Err(MemError::Unmapped));
+    assert_eq!(frozen.get_perm(0x8000), mmu.get_perm(0x8000));
+    assert_eq!(frozen.get_perm(0x1000), mmu.get_perm(0x1000));
+    assert_eq!(frozen.regions().collect::<Vec<_>>(), mmu.regions().collect::<Vec<_>>());
+    assert_eq!(
+        frozen.regions().next(),
+        Some(Region { start: 0x1000, end: 0x4fff, perm: rw, kind: RegionKind::Physical })
+    );
+
+    // Overwrite the same pages on the main thread while a worker reads from the view.
+    let worker = {
+        let frozen = frozen.clone();
+        let pattern = pattern.clone();
+        std::thread::spawn(move || {
+            let mut buf = vec![0; pattern.len()];
+            for _ in 0..100 {
+                frozen.read_bytes(0x1000, &mut buf, perm::READ).unwrap();
+                assert_eq!(buf, pattern);
+                assert_eq!(frozen.hash_range(0x1000, 0x3000), Ok(expected_hash));
+            }
+        })
+    };
+    for i in 0..100_u64 {
+        mmu.write_bytes(0x1000 + i * 0x40, &[0xff; 0x40], perm::WRITE).unwrap();
+        mmu.write_u64(0x3000 + i * 8, i, perm::WRITE).unwrap();
+    }
+    mmu.unmap_memory_len(0x2000, 0x1000);
+    worker.join().unwrap();
+
+    assert_eq!(mmu.read_u8(0x1000, perm::READ), Ok(0xff));
+    let mut buf = vec![0; pattern.len()];
+    frozen.read_bytes(0x1000, &mut buf, perm::READ).unwrap();
+    assert_eq!(buf, pattern);
+    assert_ne!(mmu.freeze().hash_range(0x1000, 0x1000), frozen.hash_range(0x1000, 0x1000));
+}
+
+#[test]
+fn read_hook_partial_results() {
+    use crate::{ReadHook, ReadHookResult};
+
+    struct Override(ReadHookResult);
+
+    impl ReadHook for Override {
+        fn read(&mut self, _: &mut Mmu, _: u64, _: u8) -> Option<u64> {
+            unreachable!()
+        }
+
+        fn read_partial(&mut self, _: &mut Mmu, _: u64, _: u8) -> ReadHookResult {
+            self.0
+        }
+
+        fn modify(&mut self, _: &mut Mmu, _: u64, value: &mut [u8]) {
+            value[0] ^= 0xff;
+        }
+    }
+
+    let rw = perm::READ | perm::WRITE;
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: rw, value: 0 });
+    mmu.write_bytes(0x1000, &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88], perm::WRITE)
+        .unwrap();
+
+    let seen = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let seen_ = seen.clone();
+    mmu.add_read_after_hook(
+        0x1000,
+        0x2000,
+        Box::new(move |_: &mut Mmu, addr: u64, value: &[u8]| {
+            seen_.borrow_mut().push((addr, value.to_vec()))
+        }),
+    );
+
+    // The hook overrides the middle 2 bytes of an 8-byte read.
+    let partial = ReadHookResult::Partial { value: 0xbbaa_0000_0000, mask: 0b0011_0000 };
+    let hook = mmu.add_read_hook(0x1000, 0x1008, Box::new(Override(partial))).unwrap();
+    assert_eq!(mmu.read_u64(0x1000, perm::READ | perm::INIT), Ok(0x8877_bbaa_4433_2211));
+    assert_eq!(
+        seen.borrow().last(),
+        Some(&(0x1000, vec![0x11, 0x22, 0x33, 0x44, 0xaa, 0xbb, 0x77, 0x88]))
+    );
+    assert_eq!(mmu.read_u64(0x1000, perm::NONE), Ok(0x8877_6655_4433_2211));
+
+    // Only the bytes read from memory are checked for initialization.
+    mmu.update_perm(0x1004, 2, rw).unwrap();
+    assert_eq!(mmu.read_u64(0x1000, perm::READ | perm::INIT), Ok(0x8877_bbaa_4433_2211));
+    mmu.update_perm(0x1006, 1, rw).unwrap();
+    assert_eq!(mmu.read_u64(0x1000, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    assert_eq!(mmu.read_u64(0x1000, perm::READ), Ok(0x8877_bbaa_4433_2211));
+    // The mask is relative to the start of each access.
+    assert_eq!(mmu.read_u32(0x1004, perm::READ), Ok(0x8877_6655));
+    mmu.remove_read_hook(hook);
+
+    // Hooks can modify the value read from memory.
+    mmu.add_read_hook(0x1000, 0x1008, Box::new(Override(ReadHookResult::Modify))).unwrap();
+    assert_eq!(mmu.read_u64(0x1000, perm::READ), Ok(0x8877_6655_4433_22ee));
+    assert_eq!(mmu.read_u64(0x1000, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    assert_eq!(
+        seen.borrow().last(),
+        Some(&(0x1000, vec![0xee, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]))
+    );
+}
+
+#[test]
+fn reserve_and_commit() {
+    use crate::RegionKind;
+
+    const GIB: u64 = 1 << 30;
+
+    let rw = perm::READ | perm::WRITE;
+    let mut mmu = Mmu::new();
+    let base = 0x1_0000_0000;
+    let initial_pages = mmu.total_pages();
+    assert!(mmu.reserve(base, GIB));
+    assert_eq!(mmu.total_pages(), initial_pages);
+
+    // Reserved memory can not be accessed, allocated or mapped.
+    assert_eq!(mmu.read_u8(base, perm::READ), Err(MemError::Reserved));
+    assert_eq!(mmu.read_u64(base + 0x1000, perm::NONE), Err(MemError::Reserved));
+    assert_eq!(mmu.write_u32(base + GIB - 4, 1, perm::WRITE), Err(MemError::Reserved));
+    let layout = AllocLayout { addr: Some(base), size: 0x1000, align: 0x1000 };
+    assert_eq!(mmu.find_free_memory(layout), Ok(base + GIB));
+    assert!(!mmu.map_memory_len(base + 0x1000, 0x1000, Mapping { perm: rw, value: 0 }));
+    assert!(!mmu.reserve(base + GIB - 1, 2));
+    assert_eq!(mmu.total_pages(), initial_pages);
+
+    // Commit a page in the middle of the reservation.
+    let page = base + GIB / 2;
+    assert_eq!(mmu.commit(page, 0x1000, rw), Ok(()));
+    mmu.write_u64(page + 8, 0x1234, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u64(page + 8, perm::READ), Ok(0x1234));
+    assert_eq!(mmu.read_u8(page - 1, perm::READ), Err(MemError::Reserved));
+    assert_eq!(mmu.read_u8(page + 0x1000, perm::READ), Err(MemError::Reserved));
+    assert_eq!(mmu.read_u8(base, perm::READ), Err(MemError::Reserved));
+    assert_eq!(mmu.total_pages(), initial_pages + 1);
+
+    let regions: Vec<_> = mmu.regions().map(|x| (x.start, x.end, x.kind)).collect();
+    assert_eq!(regions, [
+        (base, page - 1, RegionKind::Reserved),
+        (page, page + 0xfff, RegionKind::Physical),
+        (page + 0x1000, base + GIB - 1, RegionKind::Reserved),
+    ]);
+
+    // Only reserved memory can be committed.
+    assert_eq!(mmu.commit(page, 0x2000, rw), Err(MemError::AlreadyMapped));
+    assert_eq!(mmu.commit(base + GIB - 0x1000, 0x2000, rw), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_u8(base + GIB - 1, perm::READ), Err(MemError::Reserved));
+
+    // Unmapping releases the reservation.
+    mmu.unmap_memory_len(base, GIB);
+    assert_eq!(mmu.read_u8(base, perm::READ), Err(MemError::Unmapped));
+}
+
+#[test]
+fn page_budgets() {
+    let rw = perm::MAP | perm::READ | perm::WRITE | perm::INIT;
+    let mut mmu = Mmu::new();
+    let heap = 0x10_0000;
+    let scratch = 0x20_0000;
+    let other = 0x30_0000;
+    for addr in [heap, scratch, other] {
+        assert!(mmu.map_memory_len(addr, 0x10000, Mapping { perm: rw, value: 0 }));
+    }
+
+    let heap_budget = mmu.create_page_budget(2);
+    let scratch_budget = mmu.create_page_budget(4);
+    assert!(mmu.assign_budget(heap, 0x10000, heap_budget));
+    assert!(mmu.assign_budget(scratch, 0x10000, scratch_budget));
+    assert!(!mmu.assign_budget(heap, 0, heap_budget));
+
+    // Reads of unallocated memory are backed by the zero page, so they are not charged.
+    assert_eq!(mmu.read_u32(heap, perm::READ), Ok(0));
+    assert_eq!(mmu.budget_usage(heap_budget), Some(0));
+
+    mmu.write_u32(heap, 1, perm::WRITE).unwrap();
+    mmu.write_u32(heap + 4, 2, perm::WRITE).unwrap();
+    mmu.write_u32(heap + 0x1000, 3, perm::WRITE).unwrap();
+    assert_eq!(mmu.budget_usage(heap_budget), Some(2));
+
+    // The heap budget is exhausted, but other ranges can still allocate pages.
+    assert_eq!(mmu.write_u32(heap + 0x2000, 4, perm::WRITE), Err(MemError::OutOfMemory));
+    assert_eq!(mmu.read_u32(heap + 4, perm::READ), Ok(2));
+    mmu.write_u32(scratch, 5, perm::WRITE).unwrap();
+    mmu.write_u32(other, 6, perm::WRITE).unwrap();
+    assert_eq!(mmu.budget_usage(heap_budget), Some(2));
+    assert_eq!(mmu.budget_usage(scratch_budget), Some(1));
+
+    // Restoring a snapshot rolls back the usage of each budget.
+    let snapshot = mmu.snapshot();
+    mmu.write_u32(scratch + 0x1000, 7, perm::WRITE).unwrap();
+    mmu.write_u32(scratch + 0x2000, 8, perm::WRITE).unwrap();
+    assert_eq!(mmu.budget_usage(scratch_budget), Some(3));
+    mmu.restore(snapshot.clone()).unwrap();
+    assert_eq!(mmu.budget_usage(scratch_budget), Some(1));
+    assert_eq!(mmu.read_u32(scratch + 0x1000, perm::READ), Ok(0));
+
+    // Writing to a page captured by the snapshot copies it without charging it again.
+    mmu.write_u32(scratch, 9, perm::WRITE).unwrap();
+    assert_eq!(mmu.budget_usage(scratch_budget), Some(1));
+
+    // Unmapping pages refunds them.
+    assert!(mmu.unmap_memory_len(heap + 0x1000, 0x1000));
+    assert_eq!(mmu.budget_usage(heap_budget), Some(1));
+    mmu.write_u32(heap + 0x2000, 4, perm::WRITE).unwrap();
+    assert_eq!(mmu.budget_usage(heap_budget), Some(2));
+
+    // Raising the limit allows more pages to be allocated.
+    assert!(mmu.set_budget_limit(heap_budget, 3));
+    mmu.write_u32(heap + 0x3000, 10, perm::WRITE).unwrap();
+    assert_eq!(mmu.budget_usage(heap_budget), Some(3));
+}
+
+/// Copies `len` bytes from `src` to `dst` through a bounce buffer using page walkers, accessing
+/// chunks that can not be accessed directly using the regular access functions.
+fn walker_memcpy(mmu: &mut Mmu, src: u64, dst: u64, len: usize) -> crate::MemResult<()> {
+    use crate::PageChunk;
+
+    let mut buf = vec![0; 0x1000];
+    let mut offset = 0;
+    while offset < len {
+        let buf = &mut buf[..(len - offset).min(0x1000)];
+
+        let mut walker = mmu.walk_range(src + offset as u64, buf.len(), perm::READ | perm::INIT);
+        let mut pos = 0;
+        while let Some(chunk) = walker.next_chunk() {
+            match chunk {
+                PageChunk::Read { data, .. } => buf[pos..][..data.len()].copy_from_slice(data),
+                PageChunk::Slow { addr, len } => walker.mmu().read_bytes(
+                    addr,
+                    &mut buf[pos..][..len],
+                    perm::READ | perm::INIT,
+                )?,
+                PageChunk::Write { .. } => unreachable!(),
+            }
+            pos = buf.len() - walker.remaining();
+        }
+
+        let mut walker = mmu.walk_range(dst + offset as u64, buf.len(), perm::WRITE);
+        let mut pos = 0;
+        while let Some(chunk) = walker.next_chunk() {
+            match chunk {
+                PageChunk::Write { data, .. } => data.copy_from_slice(&buf[pos..][..data.len()]),
+                PageChunk::Slow { addr, len } => {
+                    walker.mmu().write_bytes(addr, &buf[pos..][..len], perm::WRITE)?
+                }
+                PageChunk::Read { .. } => unreachable!(),
+            }
+            pos = buf.len() - walker.remaining();
+        }
+
+        offset += buf.len();
+    }
+    Ok(())
+}
+
+#[test]
+fn page_walker() {
+    use crate::PageChunk;
+
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x1800, rw);
+    let handler = mmu.register_io_handler(RecordingDevice {
+        base: 0x2800,
+        data: vec![0; 0x800],
+        accesses: vec![],
+    });
+    mmu.map_memory_len(0x2800, 0x800, handler);
+    mmu.map_memory_len(0x3000, 0x1000, rw);
+
+    // Chunks are split at page boundaries and at the edges of the I/O window.
+    let data: Vec<u8> = (0..0x3000).map(|i| (i * 7) as u8).collect();
+    let mut chunks = vec![];
+    let mut walker = mmu.walk_range(0x1000, data.len(), perm::WRITE);
+    while let Some(chunk) = walker.next_chunk() {
+        match chunk {
+            PageChunk::Write { addr, data: out } => {
+                chunks.push((addr, out.len(), true));
+                out.copy_from_slice(&data[(addr - 0x1000) as usize..][..out.len()]);
+            }
+            PageChunk::Slow { addr, len } => {
+                chunks.push((addr, len, false));
+                let bytes = &data[(addr - 0x1000) as usize..][..len];
+                walker.mmu().write_bytes(addr, bytes, perm::WRITE).unwrap();
+            }
+            PageChunk::Read { .. } => unreachable!(),
+        }
+    }
+    assert_eq!(chunks, [
+        (0x1000, 0x1000, true),
+        (0x2000, 0x800, true),
+        (0x2800, 0x800, false),
+        (0x3000, 0x1000, true)
+    ]);
+
+    // Bytes written directly are marked as initialized and the pages are logged as modified.
+    let mut buf = vec![0; data.len()];
+    mmu.read_bytes(0x1000, &mut buf, perm::READ | perm::INIT).unwrap();
+    assert_eq!(buf, data);
+    let modified: Vec<_> = mmu.dirty_pages().collect();
+    assert!(modified.contains(&0x1000) && modified.contains(&0x3000));
+
+    buf.fill(0);
+    let mut chunks = vec![];
+    let mut walker = mmu.walk_range(0x1000, buf.len(), perm::READ | perm::INIT);
+    while let Some(chunk) = walker.next_chunk() {
+        match chunk {
+            PageChunk::Read { addr, data } => {
+                chunks.push((addr, data.len(), true));
+                buf[(addr - 0x1000) as usize..][..data.len()].copy_from_slice(data);
+            }
+            PageChunk::Slow { addr, len } => {
+                chunks.push((addr, len, false));
+                let out = &mut buf[(addr - 0x1000) as usize..][..len];
+                walker.mmu().read_bytes(addr, out, perm::READ | perm::INIT).unwrap();
+            }
+            PageChunk::Write { .. } => unreachable!(),
+        }
+    }
+    assert_eq!(buf, data);
+    assert_eq!(chunks.iter().filter(|(.., direct)| !direct).count(), 1);
+
+    // A protection change part way through a page makes the page use the slow path, which reports
+    // the error at the first byte that can not be written.
+    mmu.set_protection(0x3400, 0x100, perm::READ).unwrap();
+    let mut walker = mmu.walk_range(0x3000, 0x1000, perm::WRITE);
+    assert!(matches!(walker.next_chunk(), Some(PageChunk::Slow { addr: 0x3000, len: 0x1000 })));
+    assert!(walker.next_chunk().is_none());
+    assert_eq!(walker_memcpy(&mut mmu, 0x1000, 0x3000, 0x1000), Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u8(0x33ff, perm::READ), Ok(data[0x3ff]));
+    let mut walker = mmu.walk_range(0x3000, 0x1000, perm::READ | perm::INIT);
+    assert!(matches!(walker.next_chunk(), Some(PageChunk::Read { addr: 0x3000, .. })));
+
+    // Copies across the I/O window are handled by the slow path.
+    assert_eq!(walker_memcpy(&mut mmu, 0x1800, 0x1000, 0x1800), Ok(()));
+    mmu.read_bytes(0x1000, &mut buf[..0x1800], perm::READ | perm::INIT).unwrap();
+    assert_eq!(&buf[..0x1800], &data[0x800..0x2000]);
+}
+
+/// Compares the time taken by a 64 KiB guest memcpy using page walkers against a copy performed
+/// with an access for every byte. Run with `cargo test --release -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn page_walker_memcpy_benchmark() {
+    const LEN: usize = 64 * 1024;
+    const ITERATIONS: u32 = 20;
+
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    let mut mmu = Mmu::new();
+    let (src, dst) = (0x10_0000, 0x20_0000);
+    mmu.map_memory_len(src, LEN as u64, rw);
+    mmu.map_memory_len(dst, LEN as u64, rw);
+    let data: Vec<u8> = (0..LEN).map(|i| (i * 7) as u8).collect();
+    mmu.write_bytes(src, &data, perm::WRITE).unwrap();
+
+    let start = std::time::Instant::now();
+    for _ in 0..ITERATIONS {
+        for i in 0..LEN as u64 {
+            let byte = mmu.read_u8(src + i, perm::READ | perm::INIT).unwrap();
+            mmu.write_u8(dst + i, byte, perm::WRITE).unwrap();
+        }
+    }
+    let per_access = start.elapsed() / ITERATIONS;
+
+    let mut buf = vec![0; LEN];
+    mmu.read_bytes(dst, &mut buf, perm::READ | perm::INIT).unwrap();
+    assert_eq!(buf, data);
+    mmu.unmap_memory_len(dst, LEN as u64);
+    mmu.map_memory_len(dst, LEN as u64, rw);
+
+    let start = std::time::Instant::now();
+    for _ in 0..ITERATIONS {
+        walker_memcpy(&mut mmu, src, dst, LEN).unwrap();
+    }
+    let walker = start.elapsed() / ITERATIONS;
+
+    mmu.read_bytes(dst, &mut buf, perm::READ | perm::INIT).unwrap();
+    assert_eq!(buf, data);
+    eprintln!("64 KiB memcpy: per-access {per_access:?}, page walker {walker:?}");
+}
+
+#[test]
+#[ignore]
+fn fragmented_perm_change_benchmark() {
+    const LEN: u64 = 64 * 1024 * 1024;
+    const ITERATIONS: u32 = 10;
+
+    let mut mmu = Mmu::new();
+    let base = 0x1000_0000;
+    mmu.map_memory_len(base, LEN, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    // Lazily allocating every page fragments the memory map into one entry per page.
+    for page in (base..base + LEN).step_by(0x1000) {
+        mmu.write_u8(page, 1, perm::WRITE).unwrap();
+    }
+    let pages = (LEN / 0x1000) as usize;
+
+    let start = std::time::Instant::now();
+    for _ in 0..ITERATIONS {
+        mmu.update_perm(base, LEN, perm::READ).unwrap();
+        mmu.update_perm(base, LEN, perm::READ | perm::WRITE).unwrap();
+    }
+    let update_perm = start.elapsed() / (2 * ITERATIONS);
+
+    // Compare the cost of removing each entry from the TLB individually with a single coalesced
+    // removal.
+    let start = std::time::Instant::now();
+    for _ in 0..ITERATIONS {
+        for page in (base..base + LEN).step_by(0x1000) {
+            mmu.tlb.remove_range(page, 0x1000);
+        }
+    }
+    let per_entry = start.elapsed() / ITERATIONS;
+
+    let start = std::time::Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut removal = crate::tlb::PendingRemoval::default();
+        for page in (base..base + LEN).step_by(0x1000) {
+            removal.add(page, 0x1000);
+        }
+        removal.apply(&mut mmu.tlb);
+    }
+    let coalesced = start.elapsed() / ITERATIONS;
+
+    eprintln!(
+        "64 MiB ({pages} entries): update_perm {update_perm:?}, TLB removal: per-entry \
+         {per_entry:?}, coalesced {coalesced:?}"
+    );
+}
+
+#[test]
+#[ignore]
+fn compare_bytes_benchmark() {
+    const LEN: usize = 16 * 1024 * 1024;
+    const ITERATIONS: u32 = 10;
+
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+    let mut mmu = Mmu::new();
+    let addr = 0x100_0000;
+    mmu.map_memory_len(addr, LEN as u64, rw);
+    let data: Vec<u8> = (0..LEN).map(|i| (i * 7) as u8).collect();
+    mmu.write_bytes(addr, &data, perm::WRITE).unwrap();
+
+    let start = std::time::Instant::now();
+    let mut buf = vec![0; LEN];
+    for _ in 0..ITERATIONS {
+        mmu.read_bytes(addr, &mut buf, perm::READ).unwrap();
+        assert!(buf == data);
+    }
+    let read_and_compare = start.elapsed() / ITERATIONS;
+
+    let start = std::time::Instant::now();
+    for _ in 0..ITERATIONS {
+        assert_eq!(mmu.compare_bytes(addr, &data, perm::READ), Ok(None));
+    }
+    let compare = start.elapsed() / ITERATIONS;
+
+    eprintln!("16 MiB compare: read and compare {read_and_compare:?}, compare_bytes {compare:?}");
+}
+
+#[test]
+#[ignore]
+fn sub_page_mapping_benchmark() {
+    const ITERATIONS: u64 = 1_000_000;
+
+    // A page split into four 1 KiB mappings.
+    let mut mmu = Mmu::new();
+    mmu.profile_tlb = true;
+    let base = 0x10000;
+    let perms = [perm::READ | perm::WRITE, perm::READ, perm::READ | perm::WRITE, perm::READ];
+    for (i, perm) in perms.into_iter().enumerate() {
+        mmu.map_memory_len(base + i as u64 * 0x400, 0x400, Mapping { perm, value: i as u8 });
+    }
+    mmu.read_u8(base, perm::READ).unwrap();
+    mmu.write_u8(base, 0, perm::WRITE).unwrap();
+    mmu.reset_counters();
+
+    let start = std::time::Instant::now();
+    let mut sum = 0_u64;
+    for i in 0..ITERATIONS {
+        let offset = (i * 0x3f8) & 0xff8;
+        sum = sum.wrapping_add(mmu.read_u64(base + offset, perm::READ).unwrap());
+        // Unaligned accesses that straddle the boundary between two of the mappings.
+        let boundary = ((i & 3) + 1) * 0x400 - 2;
+        if boundary < 0x1000 - 4 {
+            sum = sum.wrapping_add(mmu.read_u32(base + boundary, perm::READ).unwrap() as u64);
+        }
+        mmu.write_u32(base + 0x3f9 + (i & 1) * 0x412, i as u32, perm::WRITE).unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    let counters = mmu.tlb_counters;
+    eprintln!(
+        "sub-page mappings: {:?} per iteration, {} hits, {} misses (sum={sum:#x})",
+        elapsed / ITERATIONS as u32,
+        counters.hits(),
+        counters.read_misses + counters.write_misses
+    );
+    assert_eq!(counters.read_misses + counters.write_misses, 0);
+}
+
+#[test]
+fn unmap_executed_code() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let mut mmu = Mmu::new();
+    let rwx = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+    mmu.map_memory_len(0x1000, 0x2000, rwx);
+    mmu.write_bytes(0x1ffe, &[0x90; 4], perm::NONE).unwrap();
+    assert!(mmu.ensure_executable(0x1ffe, 4));
+    assert_eq!(mmu.write_bytes(0x1fff, &[0xcc], perm::NONE), Err(MemError::SelfModifyingCode));
+
+    // Unmapping cached code should queue the range for invalidation.
+    assert!(mmu.unmap_memory_len(0x1000, 0x2000));
+    assert_eq!(mmu.take_invalidated_code_ranges(), [(0x1000, 0x2fff)]);
+    assert_eq!(mmu.take_invalidated_code_ranges(), []);
+
+    // New code mapped at the same address should be executable and protected.
+    mmu.map_memory_len(0x1000, 0x2000, rwx);
+    mmu.write_bytes(0x1ffe, &[0xcc; 4], perm::NONE).unwrap();
+    assert!(mmu.ensure_executable(0x1ffe, 4));
+    assert_eq!(mmu.read::<4>(0x1ffe, perm::EXEC).unwrap(), [0xcc; 4]);
+    assert_eq!(mmu.write_bytes(0x1fff, &[0x90], perm::NONE), Err(MemError::SelfModifyingCode));
+
+    // Unmapping part of an executed page should notify the registered handler.
+    let invalidated = Rc::new(RefCell::new(vec![]));
+    let handler_invalidated = invalidated.clone();
+    mmu.set_code_invalidation_handler(Box::new(move |start, end| {
+        handler_invalidated.borrow_mut().push((start, end))
+    }));
+    assert!(mmu.unmap_memory_len(0x2000, 0x800));
+    assert_eq!(invalidated.borrow().as_slice(), [(0x2000, 0x27ff)]);
+    assert_eq!(mmu.take_invalidated_code_ranges(), []);
+
+    // The code in the part of the page that is still mapped should remain protected.
+    assert_eq!(mmu.write_bytes(0x1fff, &[0x90], perm::NONE), Err(MemError::SelfModifyingCode));
+    mmu.map_memory_len(0x2000, 0x800, rwx);
+    mmu.write_bytes(0x2000, &[0x90; 2], perm::NONE).unwrap();
+}
+
+#[test]
+fn code_patching() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let mut mmu = Mmu::new();
+    let rwx = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+    mmu.map_memory_len(0x1000, 0x2000, rwx);
+    mmu.write_bytes(0x1ffd, &[0x90, 0x90, 0x0f, 0x05, 0x90, 0x90], perm::NONE).unwrap();
+    assert!(mmu.ensure_executable(0x1ffd, 6));
+
+    let invalidated = Rc::new(RefCell::new(vec![]));
+    let handler_invalidated = invalidated.clone();
+    mmu.set_code_invalidation_handler(Box::new(move |start, end| {
+        handler_invalidated.borrow_mut().push((start, end))
+    }));
+
+    // Patch the instruction that straddles the page boundary.
+    let patch = mmu
+        .with_code_patching(0x1fff, 2, |mmu| {
+            mmu.write_bytes(0x1fff, &[0x0f, 0x0b], perm::WRITE)?;
+            // Code outside of the patched region is still protected.
+            mmu.write_bytes(0x1ffe, &[0x00], perm::WRITE)
+        })
+        .unwrap();
+    assert_eq!(patch.result, Err(MemError::SelfModifyingCode));
+    assert_eq!(patch.modified, [(0x2000, 0x2000)]);
+    assert_eq!(invalidated.borrow().as_slice(), [(0x1fff, 0x2000)]);
+    assert_eq!(mmu.read::<2>(0x1fff, perm::EXEC).unwrap(), [0x0f, 0x0b]);
+
+    // Unchanged bytes are still protected, but the modified byte can be written to.
+    assert_eq!(mmu.write_bytes(0x1fff, &[0x00], perm::WRITE), Err(MemError::SelfModifyingCode));
+    mmu.write_bytes(0x2000, &[0x05], perm::WRITE).unwrap();
+    assert!(mmu.ensure_executable(0x1fff, 2));
+
+    // Nested patching across both pages.
+    invalidated.borrow_mut().clear();
+    let patch = mmu
+        .with_code_patching(0x1ffd, 6, |mmu| {
+            mmu.write_bytes(0x2001, &[0xcc], perm::WRITE).unwrap();
+            mmu.with_code_patching(0x1ffd, 1, |mmu| mmu.write_bytes(0x1ffd, &[0xcc], perm::WRITE))
+        })
+        .unwrap();
+    let inner = patch.result.unwrap();
+    assert_eq!(inner.result, Ok(()));
+    assert_eq!(inner.modified, []);
+    assert_eq!(patch.modified, [(0x1ffd, 0x1ffd), (0x2001, 0x2001)]);
+    assert_eq!(invalidated.borrow().as_slice(), [(0x1ffd, 0x2002)]);
+
+    assert_eq!(mmu.write_bytes(0x2002, &[0x00], perm::WRITE), Err(MemError::SelfModifyingCode));
+    assert_eq!(mmu.write_bytes(0x1ffe, &[0x00], perm::WRITE), Err(MemError::SelfModifyingCode));
+    mmu.write_bytes(0x1ffd, &[0x90], perm::WRITE).unwrap();
+    mmu.write_bytes(0x2001, &[0x90], perm::WRITE).unwrap();
+}
+
+#[test]
+fn self_modifying_code_invalidation() {
+    let mut mmu = Mmu::new();
+    mmu.self_modifying_code = crate::SelfModifyingCode::Invalidate;
+    let rwx = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+    mmu.map_memory_len(0x1000, 0x2000, rwx);
+    mmu.write_bytes(0x1ff0, &[0x90; 0x20], perm::NONE).unwrap();
+    assert!(mmu.ensure_executable(0x1ff0, 0x20));
+
+    // Writes that do not change any code should not cause any invalidations.
+    mmu.write_bytes(0x1ff8, &[0x90; 4], perm::WRITE).unwrap();
+    assert_eq!(mmu.take_invalidated_code_ranges(), []);
+
+    // Overwriting code should succeed and report the page that was modified.
+    mmu.write_bytes(0x1ff8, &[0x0f, 0x0b], perm::WRITE).unwrap();
+    assert_eq!(mmu.read::<2>(0x1ff8, perm::READ).unwrap(), [0x0f, 0x0b]);
+    assert_eq!(mmu.take_invalidated_code_ranges(), [(0x1000, 0x1fff)]);
+
+    // The modified bytes are no longer considered code, but the rest of the page still is.
+    mmu.write_bytes(0x1ff8, &[0xcc, 0xcc], perm::WRITE).unwrap();
+    assert_eq!(mmu.take_invalidated_code_ranges(), []);
+    mmu.write_bytes(0x1ff0, &[0xcc], perm::WRITE).unwrap();
+    assert_eq!(mmu.take_invalidated_code_ranges(), [(0x1000, 0x1fff)]);
+
+    mmu.fill_mem(0x1ffe, 4, 0xcc).unwrap();
+    assert_eq!(mmu.read::<4>(0x1ffe, perm::READ).unwrap(), [0xcc; 4]);
+    assert_eq!(mmu.take_invalidated_code_ranges(), [(0x1000, 0x1fff), (0x2000, 0x2fff)]);
+
+    // Translating the code again should re-mark the bytes.
+    assert!(mmu.ensure_executable(0x1ff8, 2));
+    mmu.self_modifying_code = crate::SelfModifyingCode::Fault;
+    assert_eq!(mmu.write_bytes(0x1ff8, &[0x90], perm::WRITE), Err(MemError::SelfModifyingCode));
+    mmu.write_bytes(0x1ff0, &[0x90], perm::WRITE).unwrap();
+}
+
+#[test]
+fn flush_code_range() {
+    let mut mmu = Mmu::new();
+    let rwx = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+    mmu.map_memory_len(0x1000, 0x2000, rwx);
+    mmu.write_bytes(0x1ff0, &[0x90; 0x20], perm::NONE).unwrap();
+    assert!(mmu.ensure_executable(0x1ff0, 0x20));
+
+    // Flushing memory that does not contain code should not invalidate anything.
+    mmu.flush_code_range(0x1000, 0x100).unwrap();
+    assert_eq!(mmu.take_invalidated_code_ranges(), []);
+    assert!(!mmu.invalidate_icache);
+
+    mmu.flush_code_range(0x1ff8, 0x10).unwrap();
+    assert_eq!(mmu.take_invalidated_code_ranges(), [(0x1ff8, 0x2007)]);
+    assert!(mmu.invalidate_icache);
+
+    // The flushed code can now be modified, but the rest of the code is still protected.
+    mmu.write_bytes(0x1ff8, &[0xcc; 0x10], perm::WRITE).unwrap();
+    assert_eq!(mmu.write_bytes(0x1ff7, &[0xcc], perm::WRITE), Err(MemError::SelfModifyingCode));
+    assert_eq!(mmu.write_bytes(0x2008, &[0xcc], perm::WRITE), Err(MemError::SelfModifyingCode));
+
+    // Pages are no longer considered executed once all code has been flushed from them.
+    let index = mmu.get_physical_index(0x2000).unwrap();
+    assert!(mmu.get_physical(index).executed);
+    mmu.flush_code_range(0x2000, 0x1000).unwrap();
+    assert!(!mmu.get_physical(index).executed);
+    mmu.write_bytes(0x2008, &[0xcc], perm::WRITE).unwrap();
+}
+
+#[test]
+fn translated_code_pages() {
+    let mut mmu = Mmu::new();
+    mmu.self_modifying_code = crate::SelfModifyingCode::Invalidate;
+    let rwx = Mapping { perm: perm::READ | perm::WRITE | perm::EXEC, value: 0 };
+    mmu.map_memory_len(0x1000, 0x5000, rwx);
+    mmu.write_bytes(0x1000, &[0x90; 0x5000], perm::NONE).unwrap();
+    for addr in (0x1000..0x6000).step_by(0x1000) {
+        assert!(mmu.ensure_executable(addr, 0x10));
+    }
+    let pages = |mmu: &Mmu| mmu.translated_code_pages().map(|(addr, _)| addr).collect::<Vec<_>>();
+    assert_eq!(pages(&mmu), [0x1000, 0x2000, 0x3000, 0x4000, 0x5000]);
+    let index = mmu.get_physical_index(0x1000).unwrap();
+    assert_eq!(mmu.translated_code_pages().next(), Some((0x1000, index)));
+
+    // Modifying code invalidates the page until it is translated again.
+    let snapshot = mmu.snapshot();
+    mmu.write_bytes(0x1000, &[0xcc], perm::WRITE).unwrap();
+    assert_eq!(mmu.take_invalidated_code_ranges(), [(0x1000, 0x1fff)]);
+    assert_eq!(pages(&mmu), [0x2000, 0x3000, 0x4000, 0x5000]);
+    assert!(mmu.ensure_executable(0x1000, 0x10));
+
+    // Writes that do not modify code do not invalidate the page, but restoring a snapshot
+    // invalidates every page with different contents in the snapshot.
+    mmu.write_bytes(0x2100, &[0xcc], perm::WRITE).unwrap();
+    assert!(mmu.take_invalidated_code_ranges().is_empty());
+    mmu.restore(snapshot.clone()).unwrap();
+    assert_eq!(mmu.take_invalidated_code_ranges(), [(0x1000, 0x2fff)]);
+    assert_eq!(pages(&mmu), [0x3000, 0x4000, 0x5000]);
+    mmu.restore(snapshot).unwrap();
+    assert!(mmu.take_invalidated_code_ranges().is_empty());
+
+    // Changing permissions, moving and unmapping code also invalidate the pages.
+    mmu.update_perm(0x3000, 0x1000, perm::READ | perm::EXEC).unwrap();
+    mmu.move_region_len(0x4000, 0x1000, 0x8000).unwrap();
+    assert!(mmu.unmap_memory_len(0x5000, 0x1000));
+    assert_eq!(mmu.take_invalidated_code_ranges(), [
+        (0x3000, 0x3fff),
+        (0x4000, 0x4fff),
+        (0x5000, 0x5fff)
+    ]);
+    assert!(pages(&mmu).is_empty());
+}
+
+/// Creates an MMU where 16 pages are no longer mapped and 16 pages only contain zeroes.
+fn gc_test_state() -> Mmu {
+    let mapping = Mapping { perm: perm::READ | perm::WRITE, value: 0xaa };
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x10000, 0x20000, mapping);
+    mmu.map_memory_len(0x40000, 0x10000, mapping);
+    let fill_pages = |mmu: &mut Mmu, range: std::ops::Range<u64>| {
+        for i in range {
+            mmu.write_bytes(0x10000 + i * 0x1000, &[i as u8 + 1; 16], perm::NONE).unwrap();
+        }
+    };
+    fill_pages(&mut mmu, 0x0..0x20);
+    fill_pages(&mut mmu, 0x30..0x40);
+
+    // Pages that can be replaced by the zero page.
+    mmu.fill_mem(0x10000, 0x10000, 0).unwrap();
+
+    // A zeroed page that contains a region with different permissions should not be replaced.
+    mmu.fill_mem(0x20000, 0x1000, 0).unwrap();
+    mmu.update_perm(0x20800, 0x10, perm::READ).unwrap();
+
+    // Pages that are unreachable (unmapping would free the pages, so instead restore a mapping
+    // from before the pages were allocated).
+    let old_mapping = mmu.get_mapping().clone();
+    mmu.map_memory_len(0x30000, 0x10000, mapping);
+    fill_pages(&mut mmu, 0x20..0x30);
+    mmu.restore_virtual_mapping(old_mapping);
+
+    mmu
+}
+
+fn read_gc_test_state(mmu: &mut Mmu) -> Vec<u8> {
+    let mut buf = vec![0; 0x20000];
+    mmu.read_bytes(0x10000, &mut buf, perm::READ).unwrap();
+    let mut upper = vec![0; 0x10000];
+    mmu.read_bytes(0x40000, &mut upper, perm::READ).unwrap();
+    buf.extend(upper);
+    buf
+}
+
+#[test]
+fn collect_garbage_incrementally() {
+    let mut expected = gc_test_state();
+    let expected_data = read_gc_test_state(&mut expected);
+    let full = expected.collect_garbage(crate::GcBudget::UNLIMITED);
+    assert!(full.cycle_complete);
+    assert_eq!((full.unreachable_pages, full.zero_pages), (16, 16));
+    assert_eq!(read_gc_test_state(&mut expected), expected_data);
+
+    let mut mmu = gc_test_state();
+    let initial_pages = mmu.total_pages();
+    let budget = crate::GcBudget { max_millis: u64::MAX, max_pages_examined: 5 };
+    let mut total = crate::GcReport::default();
+    let mut calls = 0;
+    loop {
+        let report = mmu.collect_garbage(budget);
+        assert!(report.pages_examined <= 5);
+        total.unreachable_pages += report.unreachable_pages;
+        total.zero_pages += report.zero_pages;
+        calls += 1;
+
+        // Memory should be unchanged after every step.
+        assert_eq!(read_gc_test_state(&mut mmu), expected_data);
+        assert_eq!(mmu.total_pages(), initial_pages - total.reclaimed_pages());
+
+        if report.cycle_complete {
+            break;
+        }
+        assert!(calls < 100, "garbage collection did not converge");
+    }
+    assert!(calls > 10);
+    assert_eq!((total.unreachable_pages, total.zero_pages), (16, 16));
+    assert_eq!(mmu.total_pages(), expected.total_pages());
+
+    // There should be nothing left to collect.
+    let report = mmu.collect_garbage(crate::GcBudget::UNLIMITED);
+    assert_eq!(report.reclaimed_pages(), 0);
+    assert!(report.cycle_complete);
+
+    // An empty budget should not make any progress.
+    let report = mmu.collect_garbage(crate::GcBudget { max_millis: 0, max_pages_examined: 10 });
+    assert_eq!(report, crate::GcReport::default());
+}
+
+#[test]
+fn collect_garbage_preserves_state() {
+    let mut mmu = gc_test_state();
+    let expected = read_gc_test_state(&mut mmu);
+    let pages = mmu.total_pages();
+
+    // Pages referenced by a detached mapping must not be freed (but unreachable pages that are not
+    // part of the detached mapping can be).
+    let mapping = mmu.take_virtual_mapping();
+    let report = mmu.collect_garbage(crate::GcBudget::UNLIMITED);
+    assert_eq!((report.unreachable_pages, report.zero_pages), (16, 0));
+    mmu.restore_virtual_mapping(mapping);
+
+    // Including mappings that share pages with the current mapping.
+    let clone = mmu.clone_virtual_mapping();
+    mmu.restore_virtual_mapping(crate::VirtualMemoryMap::default());
+    let report = mmu.collect_garbage(crate::GcBudget::UNLIMITED);
+    assert_eq!(report.reclaimed_pages(), 0);
+    mmu.restore_virtual_mapping(clone);
+
+    let report = mmu.collect_garbage(crate::GcBudget::UNLIMITED);
+    assert_eq!((report.unreachable_pages, report.zero_pages), (0, 16));
+    assert_eq!(read_gc_test_state(&mut mmu), expected);
+
+    // Writing to a page replaced by the zero page should not affect any other page.
+    mmu.write_u8(0x10000, 0x12, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u8(0x10000, perm::READ), Ok(0x12));
+    assert_eq!(mmu.read_u8(0x11000, perm::READ), Ok(0x00));
+    assert_eq!(mmu.total_pages(), pages - 31);
+
+    // Freed pages should be reused for new allocations.
+    mmu.map_memory_len(0x80000, 0x10000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    for addr in (0x80000..0x90000).step_by(0x1000) {
+        mmu.write_u8(addr, 0x1, perm::WRITE).unwrap();
+    }
+    assert_eq!(mmu.total_pages(), pages - 15);
+    assert_eq!(mmu.read_u8(0x40000, perm::READ), Ok(0x31));
+
+    // A mapping that is dropped without being restored should only prevent its own pages from
+    // being reclaimed.
+    drop(mmu.take_virtual_mapping());
+    mmu.map_memory_len(0x20000, 0x800, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    mmu.write_u8(0x20000, 0x1, perm::WRITE).unwrap();
+    mmu.map_memory_len(0x20800, 0x800, Mapping { perm: perm::READ, value: 0 });
+    mmu.read_u8(0x20800, perm::READ).unwrap();
+    mmu.unmap_memory_len(0x20000, 0x1000);
+    let report = mmu.collect_garbage(crate::GcBudget::UNLIMITED);
+    assert_eq!(report.unreachable_pages, 1);
+
+    // Pages allocated with `alloc_physical` are owned by the caller, even before they are mapped.
+    let index = mmu.alloc_physical(1).unwrap()[0];
+    mmu.get_physical_mut(index).data_mut().data[0] = 0x11;
+    let report = mmu.collect_garbage(crate::GcBudget::UNLIMITED);
+    assert_eq!(report.reclaimed_pages(), 0);
+    mmu.map_memory_len(0x30000, 0x1000, Mapping { perm: perm::READ | perm::WRITE, value: 0 });
+    mmu.write_u8(0x30000, 0x22, perm::WRITE).unwrap();
+    assert_ne!(mmu.get_physical_index(0x30000), Some(index));
+    assert!(mmu.map_physical(0x31000, index));
+    assert_eq!(mmu.get_physical_index(0x31000), Some(index));
+    assert_eq!(mmu.get_physical(index).data().data[0], 0x11);
+}
+
+#[test]
+fn collect_garbage_after_restore() {
+    let mapping = Mapping { perm: perm::READ | perm::WRITE, value: 0xaa };
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x10000, 0x8000, mapping);
+    for addr in (0x10000..0x18000).step_by(0x1000) {
+        mmu.write_u8(addr, 0x1, perm::WRITE).unwrap();
+    }
+    let snapshot = mmu.snapshot();
+    let initial_pages = mmu.total_pages();
+
+    for i in 0..500_u64 {
+        // Switching between detached mappings leaves copy-on-write pages that are no longer
+        // referenced by the current mapping.
+        let old = mmu.snapshot_virtual_mapping();
+        for addr in (0x10000..0x18000).step_by(0x1000) {
+            mmu.write_u64(addr + 0x8, i, perm::WRITE).unwrap();
+        }
+        let new = mmu.snapshot_virtual_mapping();
+        mmu.restore_virtual_mapping(old);
+        mmu.restore_virtual_mapping(new);
+
+        // Partially remapping a page replaces the page that was previously mapped there.
+        mmu.map_memory_len(0x20000, 0x800, mapping);
+        mmu.write_u8(0x20000, 0x1, perm::WRITE).unwrap();
+        mmu.map_memory_len(0x20800, 0x800, Mapping { perm: perm::READ, value: 0 });
+        mmu.read_u8(0x20800, perm::READ).unwrap();
+        mmu.unmap_memory_len(0x20000, 0x1000);
+
+        mmu.collect_garbage(crate::GcBudget::UNLIMITED);
+        assert_eq!(mmu.total_pages(), initial_pages, "pages leaked after {i} iterations");
+        assert_eq!(mmu.read_u64(0x17008, perm::READ), Ok(i));
+
+        // Pages that are no longer shared should be modified in-place.
+        mmu.write_u64(0x17008, i, perm::WRITE).unwrap();
+        assert_eq!(mmu.total_pages(), initial_pages);
+
+        // Pages shared with a detached mapping should remain copy-on-write.
+        let detached = mmu.snapshot_virtual_mapping();
+        mmu.collect_garbage(crate::GcBudget::UNLIMITED);
+        mmu.write_u64(0x17008, i + 1, perm::WRITE).unwrap();
+        mmu.restore_virtual_mapping(detached);
+        assert_eq!(mmu.read_u64(0x17008, perm::READ), Ok(i));
+
+        if i % 100 == 99 {
+            mmu.restore(snapshot.clone()).unwrap();
+            assert_eq!(mmu.total_pages(), initial_pages);
+            assert_eq!(mmu.read_u64(0x17008, perm::READ), Ok(0xaaaa_aaaa_aaaa_aaaa));
+        }
+    }
+}
+
+#[test]
+fn dedup_pages() {
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x10000, 0x5000, Mapping { perm: perm::READ | perm::WRITE, value: 0xaa });
+    for addr in (0x10000..0x15000).step_by(0x1000) {
+        mmu.write_bytes(addr, b"hello", perm::WRITE).unwrap();
+    }
+    mmu.write_u8(0x12000, b'j', perm::WRITE).unwrap();
+    mmu.update_perm(0x13800, 0x10, perm::READ).unwrap();
+    let mut expected = vec![0; 0x5000];
+    mmu.read_bytes(0x10000, &mut expected, perm::READ).unwrap();
+
+    // Pages referenced by a detached mapping must not be freed.
+    let mapping = mmu.take_virtual_mapping();
+    assert_eq!(mmu.dedup_pages(), 0);
+    mmu.restore_virtual_mapping(mapping);
+
+    let pages = mmu.total_pages();
+    assert_eq!(mmu.dedup_pages(), 2);
+    assert_eq!(mmu.total_pages(), pages - 2);
+    assert_eq!(mmu.get_physical_index(0x10000), mmu.get_physical_index(0x11000));
+    assert_eq!(mmu.get_physical_index(0x10000), mmu.get_physical_index(0x14000));
+    assert_ne!(mmu.get_physical_index(0x10000), mmu.get_physical_index(0x13000));
+
+    let mut buf = vec![0; 0x5000];
+    mmu.read_bytes(0x10000, &mut buf, perm::READ).unwrap();
+    assert_eq!(buf, expected);
+
+    // Writing to a merged page should not affect any other page.
+    mmu.write_u8(0x11000, b'c', perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u8(0x10000, perm::READ), Ok(b'h'));
+    assert_eq!(mmu.read_u8(0x11000, perm::READ), Ok(b'c'));
+    assert_eq!(mmu.read_u8(0x14000, perm::READ), Ok(b'h'));
+
+    // Shared pages are never merged.
+    assert_eq!(mmu.dedup_pages(), 0);
+}
+
+#[test]
+fn read_allow_uninit() {
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    mmu.map_memory_len(0x1000, 0x2000, Mapping { perm: perm::READ | perm::WRITE, value: 0xaa });
+    mmu.map_memory_len(0x3000, 0x1000, Mapping { perm: perm::WRITE, value: 0xaa });
+    mmu.write::<4>(0x1004, [1, 2, 3, 4], perm::WRITE).unwrap();
+    mmu.write_u8(0x100f, 5, perm::WRITE).unwrap();
+
+    let strict_perm = perm::READ | perm::INIT;
+    let expected =
+        [0xaa, 0xaa, 0xaa, 0xaa, 1, 2, 3, 4, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 5];
+    let expected_mask = 0b1000_0000_1111_0000;
+
+    // Check both the slow path (empty TLB) and the fast path.
+    mmu.clear_tlb();
+    for _ in 0..2 {
+        assert_eq!(mmu.read::<16>(0x1000, strict_perm), Err(MemError::Uninitalized));
+        assert_eq!(mmu.read_allow_uninit::<16>(0x1000, strict_perm), Ok((expected, expected_mask)));
+        // Relaxed reads should not affect strict reads.
+        assert_eq!(mmu.read::<16>(0x1000, strict_perm), Err(MemError::Uninitalized));
+        assert_eq!(mmu.read::<4>(0x1004, strict_perm), Ok([1, 2, 3, 4]));
+    }
+
+    // Unaligned reads and reads that cross a page boundary.
+    assert_eq!(
+        mmu.read_allow_uninit::<8>(0x100a, strict_perm),
+        Ok(([0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 5, 0xaa, 0xaa], 0b0010_0000))
+    );
+    mmu.write::<4>(0x2000, [6, 7, 8, 9], perm::WRITE).unwrap();
+    let (value, mask) = mmu.read_allow_uninit::<16>(0x1ff8, strict_perm).unwrap();
+    assert_eq!(value[8..12], [6, 7, 8, 9]);
+    assert_eq!(mask, 0b0000_1111_0000_0000);
+
+    // Other permissions should still be checked.
+    assert_eq!(mmu.read_allow_uninit::<16>(0x3000, strict_perm), Err(MemError::ReadViolation));
+    assert_eq!(mmu.read_allow_uninit::<16>(0x4000, strict_perm), Err(MemError::Unmapped));
+    assert_eq!(mmu.read_allow_uninit::<16>(0x2ff8, strict_perm), Err(MemError::ReadViolation));
+}
+
+#[test]
+fn uninit_diagnostics() {
+    let mut mmu = Mmu::new();
+    mmu.track_uninitialized = true;
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0xaa };
+    mmu.map_memory_len(0x1000, 0x1000, rw);
+    mmu.map_memory_len(0x3000, 0x1000, rw);
+    mmu.write::<4>(0x1000, [1; 4], perm::WRITE).unwrap();
+    mmu.write_u8(0x1008, 2, perm::WRITE).unwrap();
+
+    // Nothing should be recorded unless diagnostics are enabled.
+    assert_eq!(mmu.read::<8>(0x1000, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    assert_eq!(mmu.take_uninit_report(), None);
+
+    let reports = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let handler_reports = reports.clone();
+    mmu.uninit_diagnostics = true;
+    mmu.set_uninit_handler(Box::new(move |mmu, report| {
+        // The handler should be able to inspect memory.
+        let value = mmu.read_u8(report.addr, perm::NONE).unwrap();
+        handler_reports.borrow_mut().push((report.addr, value));
+    }));
+
+    // Only the first failure should be recorded (unaligned reads are reported as a single access).
+    assert_eq!(mmu.read::<8>(0x1002, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    assert_eq!(mmu.read::<8>(0x1008, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    let init = perm::MAP | perm::READ | perm::WRITE | perm::INIT;
+    let uninit = init & !perm::INIT;
+    assert_eq!(
+        mmu.take_uninit_report(),
+        Some(crate::UninitReport {
+            addr: 0x1002,
+            size: 8,
+            page_mapped_at: Some(0x1000),
+            perm_bits: vec![init, init, uninit, uninit, uninit, uninit, init, uninit],
+            tags: vec![],
+        })
+    );
+    assert_eq!(*reports.borrow(), [(0x1002, 1)]);
+    assert_eq!(mmu.take_uninit_report(), None);
+
+    // A new report should be recorded after the previous one was taken.
+    assert_eq!(mmu.read::<4>(0x1008, perm::READ | perm::INIT), Err(MemError::Uninitalized));
+    assert_eq!(mmu.take_uninit_report().map(|x| (x.addr, x.size)), Some((0x1008, 4)));
+    assert_eq!(*reports.borrow(), [(0x1002, 1), (0x1008, 2)]);
+
+    assert_eq!(mmu.uninit_ranges(0x1000, 0x1000), [(0x1004, 0x1007), (0x1009, 0x1fff)]);
+    assert_eq!(mmu.uninit_ranges(0x1ff0, 0x1020), [(0x1ff0, 0x1fff), (0x3000, 0x300f)]);
+    assert_eq!(mmu.uninit_ranges(0x1000, 0x4), []);
+    assert_eq!(mmu.uninit_ranges(0x1000, 0), []);
+}
+
+#[test]
+fn shadow_memory() {
+    let rw = Mapping { perm: perm::READ | perm::WRITE, value: 0 };
+
+    // Labels should be ignored until the shadow plane is enabled.
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0x1000, 0x2000, rw);
+    mmu.write_with_shadow::<4>(0x1000, [1, 2, 3, 4], [1; 4], perm::WRITE).unwrap();
+    assert_eq!(mmu.read_with_shadow::<4>(0x1000, perm::READ), Ok(([1, 2, 3, 4], [0; 4])));
+
+    mmu.enable_shadow();
+    mmu.write_with_shadow::<4>(0x1000, [1, 2, 3, 4], [0x10, 0x11, 0, 0x13], perm::WRITE).unwrap();
+    assert_eq!(
+        mmu.read_with_shadow::<8>(0x1000, perm::READ),
+        Ok(([1, 2, 3, 4, 0, 0, 0, 0], [0x10, 0x11, 0, 0x13, 0, 0, 0, 0]))
+    );
+
+    // Regular writes do not modify labels.
+    mmu.write_u8(0x1000, 5, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_with_shadow::<1>(0x1000, perm::READ), Ok(([5], [0x10])));
+
+    // Labels across a page boundary, including a page that has not been allocated yet.
+    mmu.set_shadow_range(0x1ff8, 0x10, 7).unwrap();
+    assert_eq!(mmu.read_with_shadow::<16>(0x1ff8, perm::READ), Ok(([0; 16], [7; 16])));
+    assert_eq!(mmu.set_shadow_range(0x2ff8, 0x10, 7), Err(MemError::Unmapped));
+
+    // Labels should be captured by snapshots and preserved by copy-on-write copies.
+    let snapshot = mmu.snapshot();
+    mmu.write_with_shadow::<1>(0x1002, [6], [0x20], perm::WRITE).unwrap();
+    assert_eq!(
+        mmu.read_with_shadow::<4>(0x1000, perm::READ),
+        Ok(([5, 2, 6, 4], [0x10, 0x11, 0x20, 0x13]))
+    );
+    mmu.set_shadow_range(0x1ff8, 0x10, 0).unwrap();
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(
+        mmu.read_with_shadow::<4>(0x1000, perm::READ),
+        Ok(([5, 2, 3, 4], [0x10, 0x11, 0, 0x13]))
+    );
+    assert_eq!(mmu.read_with_shadow::<16>(0x1ff8, perm::READ), Ok(([0; 16], [7; 16])));
+
+    let range_snapshot = mmu.snapshot_range(0x1000, 0x10);
+    mmu.set_shadow_range(0x1000, 0x10, 0x30).unwrap();
+    mmu.restore_range(&range_snapshot).unwrap();
+    assert_eq!(
+        mmu.read_with_shadow::<4>(0x1000, perm::READ),
+        Ok(([5, 2, 3, 4], [0x10, 0x11, 0, 0x13]))
+    );
+
+    // Labels should move with the data.
+    mmu.move_region_len(0x1000, 0x10, 0x5008).unwrap();
+    assert_eq!(
+        mmu.read_with_shadow::<4>(0x5008, perm::READ),
+        Ok(([5, 2, 3, 4], [0x10, 0x11, 0, 0x13]))
+    );
+}
+
+#[test]
+fn guard_pages() {
+    use crate::{AccessKind, GcBudget, GuardFault};
+
+    let rw = perm::READ | perm::WRITE;
+    let mut mmu = Mmu::new();
+    mmu.map_memory_len(0xf000, 0x11000, Mapping { perm: rw, value: 0 });
+    mmu.update_perm(0xf000, 0x1000, rw | perm::GUARD).unwrap();
+
+    // Guarded pages must not share the zero page.
+    let index = mmu.get_physical_index(0xf000).unwrap();
+    assert!(!index.is_zero_page());
+    mmu.collect_garbage(GcBudget::UNLIMITED);
+    assert_eq!(mmu.get_physical_index(0xf000), Some(index));
+
+    // Without a handler, accesses fail and the fault is recorded.
+    mmu.write_u64(0x10000, 0x1234, perm::WRITE).unwrap();
+    assert_eq!(mmu.write_u64(0xfff8, 0x1234, perm::WRITE), Err(MemError::GuardPage));
+    assert_eq!(
+        mmu.take_guard_fault(),
+        Some(GuardFault { addr: 0xfff8, kind: AccessKind::Write, tags: vec![] })
+    );
+    assert_eq!(mmu.take_guard_fault(), None);
+    assert_eq!(mmu.read_u32(0xfffe, perm::READ), Err(MemError::GuardPage));
+    assert_eq!(
+        mmu.take_guard_fault(),
+        Some(GuardFault { addr: 0xfffe, kind: AccessKind::Read, tags: vec![] })
+    );
+    assert_eq!(mmu.read_u8(0xf000, perm::NONE), Err(MemError::GuardPage));
+    assert_eq!(mmu.read_u8(0xf000, perm::EXEC), Err(MemError::GuardPage));
+    assert_eq!(mmu.take_guard_fault().unwrap().kind, AccessKind::Execute);
+    assert_eq!(mmu.read_u16(0xffff, perm::READ), Err(MemError::GuardPage));
+    assert_eq!(mmu.read_u64(0x10000, perm::READ), Ok(0x1234));
+
+    // Guard state is restored by snapshots.
+    let snapshot = mmu.snapshot();
+    mmu.update_perm(0xf000, 0x1000, rw).unwrap();
+    mmu.write_u64(0xfff8, 0x1234, perm::WRITE).unwrap();
+    mmu.restore(snapshot.clone()).unwrap();
+    assert_eq!(mmu.write_u64(0xfff8, 0x1234, perm::WRITE), Err(MemError::GuardPage));
+    assert!(perm::is_guard(mmu.get_perm(0xf000)));
+    assert!(mmu.take_guard_fault().is_some());
+
+    // Grow the stack whenever the guard page is touched.
+    let faults = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let faults_ = faults.clone();
+    mmu.set_guard_handler(Box::new(move |mmu: &mut Mmu, fault: &GuardFault| {
+        faults_.borrow_mut().push(fault.clone());
+        let guard = fault.addr & !0xfff;
+        mmu.update_perm(guard, 0x1000, rw).unwrap();
+        mmu.map_memory_len(guard - 0x1000, 0x1000, Mapping { perm: rw, value: 0 });
+        mmu.update_perm(guard - 0x1000, 0x1000, rw | perm::GUARD).unwrap();
+        true
+    }));
+
+    mmu.write_u64(0xfff8, 0x5678, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u64(0xfff8, perm::READ), Ok(0x5678));
+    assert_eq!(*faults.borrow(), [GuardFault {
+        addr: 0xfff8,
+        kind: AccessKind::Write,
+        tags: vec![]
+    }]);
+    assert_eq!(mmu.take_guard_fault(), None);
+    assert!(perm::is_guard(mmu.get_perm(0xe000)));
+
+    // An access that spans the guard page and the stack completes after growing the stack.
+    mmu.write_u64(0xeffc, 0x1122334455667788, perm::WRITE).unwrap();
+    assert_eq!(mmu.read_u64(0xeffc, perm::READ), Ok(0x1122334455667788));
+    assert_eq!(faults.borrow().len(), 2);
+    assert!(perm::is_guard(mmu.get_perm(0xdfff)));
+
+    // A handler that does not remove the guard does not cause the access to be retried.
+    mmu.set_guard_handler(Box::new(|_: &mut Mmu, _: &GuardFault| true));
+    assert_eq!(mmu.read_u8(0xd000, perm::READ), Err(MemError::GuardPage));
+    assert_eq!(
+        mmu.take_guard_fault(),
+        Some(GuardFault { addr: 0xd000, kind: AccessKind::Read, tags: vec![] })
+    );
+
+    mmu.restore(snapshot).unwrap();
+    assert_eq!(mmu.read_u8(0xe000, perm::NONE), Err(MemError::Unmapped));
+    assert!(perm::is_guard(mmu.get_perm(0xf000)));
+}
+
+#[test]
+fn wx_policy() {
+    use crate::WxPolicy;
+
+    let rw = perm::READ | perm::WRITE;
+    let rx = perm::READ | perm::EXEC;
+    let rwx = rw | perm::EXEC;
+
+    let mut mmu = Mmu::new();
+    mmu.wx_policy = WxPolicy::Deny;
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: rw, value: 0 });
+    mmu.write_bytes(0x1000, &[0x90; 0x1000], perm::NONE).unwrap();
+
+    // Only part of the page is made executable.
+    mmu.update_perm(0x1100, 0x10, rx).unwrap();
+    assert_eq!(mmu.update_perm(0x1200, 0x10, rwx), Err(MemError::WriteExecViolation));
+    assert_eq!(mmu.get_perm(0x1200) & perm::EXEC, 0);
+    assert_ne!(mmu.get_perm(0x1200) & perm::WRITE, 0);
+    assert!(mmu.ensure_executable(0x1100, 0x10));
+    assert!(!mmu.ensure_executable(0x10ff, 0x10));
+    assert!(!mmu.map_memory_len(0x2000, 0x1000, Mapping { perm: rwx, value: 0 }));
+    assert_eq!(mmu.read_u8(0x2000, perm::NONE), Err(MemError::Unmapped));
+
+    // Memory made writable and executable while it was allowed can not be executed.
+    mmu.wx_policy = WxPolicy::Allow;
+    mmu.update_perm(0x1200, 0x10, rwx).unwrap();
+    mmu.wx_policy = WxPolicy::Deny;
+    assert!(!mmu.ensure_executable(0x1200, 0x10));
+    mmu.wx_policy = WxPolicy::StripWrite;
+    assert!(mmu.ensure_executable(0x1200, 0x10));
+    assert_eq!(mmu.get_perm(0x1200) & perm::WRITE, 0);
+    assert_eq!(mmu.write_u8(0x120f, 0x90, perm::WRITE), Err(MemError::WriteViolation));
+    mmu.write_u8(0x1210, 0, perm::WRITE).unwrap();
+
+    mmu.update_perm(0x1300, 0x8, rwx).unwrap();
+    assert_eq!(mmu.get_perm(0x1300) & (perm::WRITE | perm::EXEC), perm::EXEC);
+    assert_ne!(mmu.get_perm(0x1308) & perm::WRITE, 0);
+    assert!(mmu.map_memory_len(0x2000, 0x1000, Mapping { perm: rwx, value: 0 }));
+    assert_eq!(mmu.write_u8(0x2000, 0, perm::WRITE), Err(MemError::WriteViolation));
+    assert_eq!(mmu.read_u8(0x2000, perm::EXEC), Ok(0));
+}
+
+#[test]
+fn alignment_policy() {
+    use crate::AlignmentPolicy;
+
+    /// Performs an unaligned read and write of `N` bytes at `addr`, returning whether each access
+    /// was allowed by the policy.
+    fn unaligned_access<const N: usize>(mmu: &mut Mmu, addr: u64) -> (bool, bool) {
+        let mut before = [0; N];
+        mmu.read_bytes(addr, &mut before, perm::NONE).unwrap();
+        let mut after = [0; N];
+        let read = match mmu.read::<N>(addr, perm::READ) {
+            Ok(value) => {
+                assert_eq!(value, before);
+                true
+            }
+            Err(MemError::Unaligned) => false,
+            Err(e) => panic!("unexpected error reading {N} bytes at {addr:#x}: {e:?}"),
+        };
+        let write = match mmu.write::<N>(addr, [0x11; N], perm::WRITE) {
+            Ok(()) => {
+                mmu.read_bytes(addr, &mut after, perm::NONE).unwrap();
+                assert_eq!(after, [0x11; N]);
+                true
+            }
+            Err(MemError::Unaligned) => {
+                // Faulting accesses must not modify memory.
+                mmu.read_bytes(addr, &mut after, perm::NONE).unwrap();
+                assert_eq!(after, before);
+                false
+            }
+            Err(e) => panic!("unexpected error writing {N} bytes at {addr:#x}: {e:?}"),
+        };
+        (read, write)
+    }
+
+    fn check(policy: AlignmentPolicy, allowed: [bool; 4]) {
+        let mut mmu = Mmu::new();
+        mmu.alignment_policy = policy;
+        let rw_init = perm::MAP | perm::READ | perm::WRITE | perm::INIT;
+        mmu.map_memory_len(0x1000, 0x2000, Mapping { perm: rw_init, value: 0xaa });
+
+        for (i, &addr) in [0x1001, 0x1103, 0x1ffd].iter().enumerate() {
+            // The first round of accesses is handled by the TLB miss path, later rounds by the fast
+            // path.
+            for _ in 0..2 {
+                let results = [
+                    unaligned_access::<2>(&mut mmu, addr),
+                    unaligned_access::<4>(&mut mmu, addr),
+                    unaligned_access::<8>(&mut mmu, addr),
+                    unaligned_access::<16>(&mut mmu, addr),
+                ];
+                for (result, allowed) in results.iter().zip(allowed) {
+                    assert_eq!(*result, (allowed, allowed), "{policy:?} at {addr:#x} ({i})");
+                }
+                mmu.read::<8>(addr & !0x7, perm::READ).unwrap();
+            }
+        }
+
+        // Aligned accesses and bulk operations are never affected by the policy.
+        mmu.write_u64(0x1008, 0x1122334455667788, perm::WRITE).unwrap();
+        assert_eq!(mmu.read_u64(0x1008, perm::READ), Ok(0x1122334455667788));
+        mmu.write_bytes(0x1203, b"hello world\0", perm::WRITE).unwrap();
+        let mut buf = [0; 11];
+        mmu.read_bytes(0x1203, &mut buf, perm::READ).unwrap();
+        assert_eq!(&buf, b"hello world");
+        let mut cstr = vec![];
+        assert_eq!(mmu.read_cstr(0x1203, &mut cstr), Ok(0x120e));
+        assert_eq!(cstr, b"hello world");
+    }
+
+    check(AlignmentPolicy::Transparent, [true, true, true, true]);
+    check(AlignmentPolicy::Fault, [false, false, false, false]);
+    check(AlignmentPolicy::FaultAbove(4), [true, true, false, false]);
+    check(AlignmentPolicy::FaultAbove(8), [true, true, true, false]);
+}
+
+#[test]
+fn update_perm_is_atomic() {
+    let rw = perm::READ | perm::WRITE;
+    let mut mmu = Mmu::new();
+    let io = mmu.register_io_handler(crate::NullMemory);
+    mmu.map_memory_len(0x1000, 0x1000, Mapping { perm: rw, value: 0 });
+    mmu.map_memory_len(0x2000, 0x100, io);
+    mmu.map_memory_len(0x2100, 0xf00, Mapping { perm: rw, value: 0 });
+    mmu.map_memory_len(0x4000, 0x1000, Mapping { perm: rw, value: 0 });
+    mmu.write_u8(0x1000, 1, perm::WRITE).unwrap();
+
+    // Ranges that overlap unmapped memory (or guard ranges that overlap I/O regions) are rejected
+    // without being modified.
+    assert_eq!(mmu.update_perm(0x1000, 0x2000, perm::GUARD), Err(MemError::Unsupported));
+    assert_eq!(mmu.update_perm(0x2100, 0x2000, perm::READ), Err(MemError::Unmapped));
+    assert_eq!(mmu.update_perm(0x1000, 0x1000, perm::READ | perm::GUARD), Ok(()));
+    assert_eq!(mmu.update_perm(0x1000, 0x1001, perm::GUARD), Err(MemError::Unsupported));
+    assert!(perm::is_guard(mmu.get_perm(0x1fff)));
+    mmu.update_perm(0x1000, 0x1000, rw).unwrap();
+    mmu.write_u8(0x1fff, 1, perm::WRITE).unwrap();
+    mmu.write_u8(0x2100, 1, perm::WRITE).unwrap();
+    mmu.write_u8(0x2fff, 1, perm::WRITE).unwrap();
+    mmu.write_u8(0x2000, 1, perm::WRITE).unwrap();
+
+    mmu.update_perm(0x2100, 0xf00, perm::READ).unwrap();
+    assert_eq!(mmu.write_u8(0x2100, 1, perm::WRITE), Err(MemError::WriteViolation));
+}
+
+/// Tests for operations on regions of memory that are not page aligned.
+///
+/// Every operation is checked against the same operation performed one byte at a time on an
+/// identical MMU.
+mod sub_page_mappings {
+    use super::*;
+    use crate::MemResult;
+
+    const RW: u8 = perm::READ | perm::WRITE;
+    const RWX: u8 = perm::READ | perm::WRITE | perm::EXEC;
+
+    /// Creates an MMU with several small adjacent mappings of different kinds within a single page,
+    /// with the first byte of each region being the `value` of the region.
+    fn adversarial_layout() -> Mmu {
+        let mut mmu = Mmu::new();
+
+        let regions: &[(u64, u64, u8, u8)] = &[
+            // A single byte region
+            (0x1000, 0x1, RW, 0x11),
+            // A 3-byte region in the middle of the first 16-byte chunk
+            (0x1001, 0x3, perm::READ, 0x22),
+            // A region ending in the middle of the second 16-byte chunk
+            (0x1004, 0x15, RWX, 0x33),
+            // (gap at 0x1019)
+            (0x101a, 0x3, RW, 0x44),
+            // A region spanning the page boundary
+            (0x1020, 0xff0, RW, 0x55),
+        ];
+        for &(start, len, perm, value) in regions {
+            assert!(mmu.map_memory_len(start, len, Mapping { perm, value }));
+        }
+
+        // An I/O region between two regular regions.
+        let device = RecordingDevice { base: 0x101d, data: vec![0x99; 3], accesses: vec![] };
+        let handler = mmu.register_io_handler(device);
+        assert!(mmu.map_memory_len(0x101d, 0x3, handler));
+
+        // Allocate physical memory for some of the regions.
+        mmu.write_bytes(0x1004, &[0x33; 0x15], perm::NONE).unwrap();
+        mmu.write_bytes(0x1ff0, &[0x55; 0x20], perm::NONE).unwrap();
+
+        // A region that was moved from a page that is shared with another region.
+        mmu.map_memory_len(0x8000, 0x10, Mapping { perm: RW, value: 0x66 });
+        mmu.map_memory_len(0x8010, 0x10, Mapping { perm: RW, value: 0x77 });
+        mmu.write_bytes(0x8000, &[0x66; 0x10], perm::NONE).unwrap();
+        mmu.write_bytes(0x8010, &[0x77; 0x10], perm::NONE).unwrap();
+        mmu.move_region_len(0x8000, 0x10, 0x3000).unwrap();
+        mmu.map_memory_len(0x3010, 0x10, Mapping { perm: RW, value: 0x88 });
+
+        mmu
+    }
+
+    /// Returns the value and permission of every byte in the regions of interest.
+    fn dump(mmu: &mut Mmu) -> Vec<(u64, MemResult<u8>, u8)> {
+        let ranges = [0x1000..0x1040, 0x1fe0..0x2020, 0x3000..0x3030, 0x8000..0x8030];
+        ranges
+            .into_iter()
+            .flatten()
+            .map(|addr| (addr, mmu.read_u8(addr, perm::NONE), mmu.get_perm(addr)))
+            .collect()
+    }
+
+    /// Runs `bulk` on one MMU and `bytewise` on the other, checking that the results match. Memory
+    /// is only compared if the operation succeeded, since a failing operation may stop at any
+    /// point.
+    fn check<T: PartialEq + std::fmt::Debug>(
+        name: &str,
+        bulk: impl FnOnce(&mut Mmu) -> MemResult<T>,
+        bytewise: impl FnOnce(&mut Mmu) -> MemResult<T>,
+    ) {
+        let (mut a, mut b) = (adversarial_layout(), adversarial_layout());
+        let result = bulk(&mut a);
+        assert_eq!(result, bytewise(&mut b), "{name}: result");
+        if result.is_err() {
+            return;
+        }
+        for (x, y) in dump(&mut a).into_iter().zip(dump(&mut b)) {
+            assert_eq!(x, y, "{name}: memory state");
+        }
+    }
+
+    fn bytewise<T>(
+        start: u64,
+        len: u64,
+        mut f: impl FnMut(&mut Mmu, u64) -> MemResult<T>,
+    ) -> impl FnOnce(&mut Mmu) -> MemResult<Vec<T>> {
+        move |mmu| (start..start + len).map(|addr| f(mmu, addr)).collect()
+    }
+
+    #[test]
+    fn read_bytes() {
+        for (start, len) in [(0x1000, 0x19), (0x1004, 0x15), (0x1002, 0x30), (0x1ff0, 0x20)] {
+            for perm in [perm::NONE, perm::READ] {
+                check(
+                    &format!("read_bytes({start:#x}, {len:#x}, {perm:#x})"),
+                    |mmu| {
+                        let mut buf = vec![0; len as usize];
+                        mmu.read_bytes(start, &mut buf, perm).map(|_| buf)
+                    },
+                    bytewise(start, len, |mmu, addr| mmu.read_u8(addr, perm)),
+                );
+            }
+        }
+        for (start, len) in [(0x3000, 0x20), (0x3008, 0x10)] {
+            check(
+                &format!("read_bytes({start:#x}, {len:#x})"),
+                |mmu| {
+                    let mut buf = vec![0; len as usize];
+                    mmu.read_bytes(start, &mut buf, perm::READ).map(|_| buf)
+                },
+                bytewise(start, len, |mmu, addr| mmu.read_u8(addr, perm::READ)),
+            );
+        }
+    }
+
+    #[test]
+    fn write_bytes() {
+        let cases = [
+            (0x1004, 0x15, perm::WRITE),
+            (0x1000, 0x19, perm::NONE),
+            (0x1004, 0x20, perm::NONE),
+            (0x101a, 0x30, perm::WRITE),
+            (0x1ff8, 0x20, perm::WRITE),
+            (0x3000, 0x20, perm::WRITE),
+            (0x3004, 0x10, perm::WRITE),
+        ];
+        for (start, len, perm) in cases {
+            let data: Vec<u8> = (0..len as u8).map(|x| x ^ 0xf0).collect();
+            check(
+                &format!("write_bytes({start:#x}, {len:#x}, {perm:#x})"),
+                |mmu| mmu.write_bytes(start, &data, perm).map(|_| vec![(); len as usize]),
+                bytewise(start, len, |mmu, addr| {
+                    mmu.write_u8(addr, data[(addr - start) as usize], perm)
+                }),
+            );
+        }
+    }
+
+    #[test]
+    fn fill_mem() {
+        for (start, len) in [(0x1000, 0x19), (0x1002, 0x10), (0x1ff0, 0x20), (0x3008, 0x10)] {
+            check(
+                &format!("fill_mem({start:#x}, {len:#x})"),
+                |mmu| mmu.fill_mem(start, len, 0xcc),
+                |mmu| (start..start + len).try_for_each(|addr| mmu.fill_mem(addr, 1, 0xcc)),
+            );
+        }
+    }
+
+    #[test]
+    fn update_perm() {
+        for (start, len) in [(0x1000, 0x19), (0x1002, 0x10), (0x1ff0, 0x20), (0x3008, 0x10)] {
+            check(
+                &format!("update_perm({start:#x}, {len:#x})"),
+                |mmu| mmu.update_perm(start, len, perm::READ | perm::EXEC),
+                |mmu| {
+                    (start..start + len)
+                        .try_for_each(|addr| mmu.update_perm(addr, 1, perm::READ | perm::EXEC))
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn ensure_executable() {
+        for (start, len) in [(0x1004, 0x15), (0x1005, 0x4), (0x1000, 0x8)] {
+            check(
+                &format!("ensure_executable({start:#x}, {len:#x})"),
+                |mmu| Ok(mmu.ensure_executable(start, len)),
+                |mmu| Ok((start..start + len).all(|addr| mmu.ensure_executable(addr, 1))),
+            );
+        }
+
+        // Code in sub-page regions should be protected.
+        let mut mmu = adversarial_layout();
+        assert!(mmu.ensure_executable(0x1004, 0x15));
+        assert_eq!(
+            mmu.write_bytes(0x1000, &[0; 0x20], perm::NONE),
+            Err(MemError::SelfModifyingCode)
+        );
+        mmu.write_bytes(0x101a, &[0; 0x6], perm::WRITE).unwrap();
+    }
+
+    #[test]
+    fn move_region() {
+        let mut mmu = adversarial_layout();
+
+        // The moved region should not be able to access the page it was moved from.
+        assert_eq!(mmu.read::<16>(0x3000, perm::READ).unwrap(), [0x66; 16]);
+        assert_eq!(mmu.read::<16>(0x3010, perm::READ).unwrap(), [0x88; 16]);
+        assert_eq!(mmu.read_u8(0x8000, perm::NONE), Err(MemError::Unmapped));
+        mmu.write::<16>(0x3010, [0x99; 16], perm::WRITE).unwrap();
+        assert_eq!(mmu.read::<16>(0x8010, perm::READ).unwrap(), [0x77; 16]);
+        assert_eq!(mmu.read_u8(0x8000, perm::NONE), Err(MemError::Unmapped));
+        mmu.write::<16>(0x8010, [0xaa; 16], perm::WRITE).unwrap();
+        assert_eq!(mmu.read::<16>(0x3010, perm::READ).unwrap(), [0x99; 16]);
+
+        // Move a sub-page region next to a region backed by a different page.
+        mmu.move_region_len(0x101a, 0x3, 0x3020).unwrap();
+        assert_eq!(mmu.read_u8(0x101a, perm::NONE), Err(MemError::Unmapped));
+        mmu.write::<4>(0x301e, [1, 2, 3, 4], perm::WRITE).unwrap();
+        assert_eq!(mmu.read::<4>(0x301e, perm::READ).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(mmu.read::<4>(0x3020, perm::READ), Err(MemError::Unmapped));
+        assert_eq!(mmu.read_u8(0x1004, perm::READ), Ok(0x33));
+        mmu.write_bytes(0x1004, &[0xbb; 0x15], perm::WRITE).unwrap();
+
+        // Move a sub-page region by an offset that is not page aligned.
+        mmu.move_region_len(0x1ff0, 0x10, 0x5ff8).unwrap();
+        assert_eq!(mmu.read::<16>(0x5ff8, perm::READ).unwrap(), [0x55; 16]);
+        assert_eq!(mmu.read_u8(0x1ff0, perm::NONE), Err(MemError::Unmapped));
+        assert_eq!(mmu.read::<16>(0x1fe0, perm::READ).unwrap(), [0x55; 16]);
+
+        // Move part of a page that is backed by the zero page.
+        let zero = Mapping { perm: perm::READ | perm::INIT, value: 0 };
+        mmu.map_memory_len(0x7000, 0x1000, zero);
+        assert_eq!(mmu.read::<8>(0x7ff8, perm::READ).unwrap(), [0; 8]);
+        mmu.move_region_len(0x7800, 0x800, 0xa800).unwrap();
+        assert_eq!(mmu.read::<8>(0xaff8, perm::READ).unwrap(), [0; 8]);
+        assert_eq!(mmu.read::<8>(0x7ff8, perm::NONE), Err(MemError::Unmapped));
+        assert_eq!(mmu.read::<8>(0x77f8, perm::READ).unwrap(), [0; 8]);
+    }
+
+    #[test]
+    fn snapshot_range() {
+        let mut mmu = adversarial_layout();
+        let snapshot = mmu.snapshot_range(0x1000, 0x40);
+        let before = dump(&mut mmu);
+
+        mmu.fill_mem(0x1004, 0x15, 0).unwrap();
+        mmu.write_bytes(0x1020, &[0xff; 0x20], perm::NONE).unwrap();
+        mmu.restore_range(&snapshot).unwrap();
+        assert_eq!(dump(&mut mmu), before);
+    }
+}
@@ -7,39 +7,113 @@ use crate::{
     MemError, MemResult,
 };
 
-/// The number of bits required to represent any address.
-pub const ADDRESS_BITS: usize = 64;
-
-/// The number of bits required use for indexing into the TLB. The more bits, the more storage is
-/// required but the fewer number of potential collections.
+/// The number of bits used for indexing into the TLB in the default configuration. The more bits,
+/// the more storage is required but the fewer number of potential collisions.
 pub const TLB_INDEX_BITS: usize = 10;
 pub const TLB_ENTRIES: usize = 1 << TLB_INDEX_BITS;
 
-pub const TLB_TAG_BITS: usize = ADDRESS_BITS - (OFFSET_BITS + TLB_INDEX_BITS);
+/// The maximum number of address space identifiers that can be stored in the tag of an entry.
+pub const MAX_ASIDS: usize = PAGE_MASK as usize - 1;
+
+/// The address space identifier used for entries that cache guest physical addresses while
+/// translations are performed using guest page tables (see [crate::Mmu::set_page_table_walker]).
+pub const PHYSICAL_ASID: u64 = MAX_ASIDS as u64;
+
+/// The geometry of a [TranslationCache].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TlbConfig {
+    /// The number of sets in the cache, each page is cached in the set selected by the low bits of
+    /// the page number. Must be a power of two.
+    pub sets: usize,
+
+    /// The number of entries in each set. Must be at least one.
+    pub ways: usize,
+}
+
+impl Default for TlbConfig {
+    fn default() -> Self {
+        Self { sets: TLB_ENTRIES, ways: 1 }
+    }
+}
+
+impl TlbConfig {
+    /// The total number of entries used for each kind of access.
+    pub fn entries(&self) -> usize {
+        self.sets * self.ways
+    }
+
+    /// The mask applied to the page number of an address to select a set.
+    pub fn index_mask(&self) -> u64 {
+        (self.sets - 1) as u64
+    }
 
-/// A direct-mapped cache for keeping track of known translation addresses (TLB). Addresses for
+    /// Extracts the index of the set in the translation cache to lookup the address at in the
+    /// address space identified by `asid`.
+    ///
+    /// The page number is combined with the ASID, so that address spaces that map pages at the same
+    /// virtual addresses do not always evict each other's entries.
+    #[inline(always)]
+    pub fn index(&self, addr: u64, asid: u64) -> usize {
+        (((addr >> OFFSET_BITS) ^ asid) & self.index_mask()) as usize
+    }
+
+    /// The offset (in bytes) from the start of the cache to the entries used for reads.
+    pub fn read_offset(&self) -> usize {
+        std::mem::size_of::<TLBEntry>()
+    }
+
+    /// The offset (in bytes) from the start of the cache to the entries used for writes.
+    pub fn write_offset(&self) -> usize {
+        self.read_offset() + self.entries() * std::mem::size_of::<TLBEntry>()
+    }
+
+    /// The number of bytes of storage used by the cache.
+    pub fn size_in_bytes(&self) -> usize {
+        self.write_offset() + self.entries() * std::mem::size_of::<TLBEntry>()
+    }
+}
+
+/// A set-associative cache for keeping track of known translation addresses (TLB). Addresses for
 /// reading/writing are translated separately to allow efficient tracking of modified pages.
-#[repr(C)]
+///
+/// Entries are stored contiguously: a header entry, followed by the read entries (starting at
+/// [TlbConfig::read_offset]) and the write entries (starting at [TlbConfig::write_offset]). Within
+/// each half, the entries are stored way-major, i.e., way `w` of set `i` is at `w * sets + i`. The
+/// most recently used entry of each set is always kept in way 0, so code that only checks the first
+/// `sets` entries (e.g. the JIT) behaves like a direct-mapped cache with the same number of sets.
+///
+/// The tag of each entry is the page address combined with the address space identifier (ASID)
+/// that was active when the entry was inserted, so entries from multiple address spaces can be
+/// cached at the same time. The tag of the header entry holds the current ASID, so the expected tag
+/// for an address is `(addr & TLBEntry::tag_mask()) | header.tag` and the set is selected by
+/// [TlbConfig::index].
 pub struct TranslationCache {
-    pub read: [TLBEntry; TLB_ENTRIES],
-    pub write: [TLBEntry; TLB_ENTRIES],
+    entries: Box<[TLBEntry]>,
+    config: TlbConfig,
+    asid: u64,
+
+    /// Set if the addresses passed to the `remove*` methods can not be used to find the entries
+    /// that refer to them (see [TranslationCache::set_flush_on_remove]).
+    flush_on_remove: bool,
 }
 
 impl Default for TranslationCache {
     fn default() -> Self {
-        Self { read: [TLBEntry::default(); TLB_ENTRIES], write: [TLBEntry::default(); TLB_ENTRIES] }
+        Self::with_config(TlbConfig::default())
     }
 }
 
 impl std::fmt::Debug for TranslationCache {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "asid: {}", self.asid)?;
+        let (read, write) = self.entries[Self::READ_BASE..].split_at(self.config.entries());
         writeln!(f, "read:")?;
-        for (i, entry) in self.read.iter().enumerate() {
+        for (i, entry) in read.iter().enumerate() {
             fmt_tlb_entry(i, entry, f)?;
         }
 
         writeln!(f, "write:")?;
-        for (i, entry) in self.write.iter().enumerate() {
+        for (i, entry) in write.iter().enumerate() {
             fmt_tlb_entry(i, entry, f)?;
         }
 
@@ -54,33 +128,128 @@ fn fmt_tlb_entry(
 ) -> Result<(), std::fmt::Error> {
     match entry.tag == u64::MAX {
         true => write!(f, "\ttag=<INVALID_TAG>, ")?,
-        false => write!(f, "\ttag={:#013x}, ", entry.tag & ((1 << TLB_TAG_BITS) - 1))?,
+        false => write!(f, "\ttag={:#018x}, ", entry.tag)?,
     }
-    match entry.get_page(0) {
+    match entry.get_page(entry.tag, entry.tag) {
         Some(page) => writeln!(f, "index={index:#05x}, page={:p}", page.ptr),
         _ => writeln!(f, "index={index:#05x}, page=null"),
     }
 }
 
 impl TranslationCache {
+    /// The position of the first read entry (after the header).
+    const READ_BASE: usize = 1;
+
     pub fn new() -> Self {
         Self::default()
     }
 
-    /// Extracts the offset within the translation cache to lookup the address at.
+    pub fn with_config(config: TlbConfig) -> Self {
+        assert!(config.sets.is_power_of_two(), "TLB sets must be a power of two: {config:?}");
+        assert!(config.ways != 0, "TLB must have at least one way: {config:?}");
+        let mut entries = vec![TLBEntry::default(); Self::READ_BASE + 2 * config.entries()];
+        entries[0] = TLBEntry { tag: 0, guest_to_host_offset: 0 };
+        Self { entries: entries.into(), config, asid: 0, flush_on_remove: false }
+    }
+
+    /// Gets the address space identifier used for new and looked up entries.
+    pub fn asid(&self) -> u64 {
+        self.asid
+    }
+
+    /// Sets the address space identifier used for new and looked up entries. Entries inserted with
+    /// a different identifier are kept, but are not visible until the identifier is restored.
+    pub fn set_asid(&mut self, asid: u64) {
+        assert!(asid <= PHYSICAL_ASID, "ASID out of range: {asid}");
+        self.asid = asid;
+        self.entries[0].tag = asid;
+    }
+
+    /// Removes all entries that were inserted while `asid` was active.
+    pub fn invalidate_asid(&mut self, asid: u64) {
+        tracing::trace!("Clearing ASID {asid} in TLB");
+        for entry in &mut self.entries[Self::READ_BASE..] {
+            if entry.tag != u64::MAX && entry.tag & PAGE_MASK == asid {
+                *entry = TLBEntry::default();
+            }
+        }
+    }
+
+    /// Controls whether removing any entry flushes the entire cache.
+    ///
+    /// This is required when entries are tagged with guest virtual addresses that are translated
+    /// by guest page tables, since the (guest physical) addresses passed to the `remove*` methods
+    /// when the memory map is modified can not be mapped back to the entries that refer to them.
+    /// Entries for a virtual address can still be removed individually using
+    /// [TranslationCache::invalidate].
+    pub fn set_flush_on_remove(&mut self, flush_on_remove: bool) {
+        self.flush_on_remove = flush_on_remove;
+    }
+
+    /// Removes the read and write entries for `addr` in the current address space, even if
+    /// [TranslationCache::set_flush_on_remove] is enabled.
+    pub fn invalidate(&mut self, addr: u64) {
+        self.remove_entries(Self::READ_BASE, addr);
+        self.remove_entries(self.write_base(), addr);
+    }
+
+    /// Gets the position of the first write entry.
+    #[inline(always)]
+    fn write_base(&self) -> usize {
+        Self::READ_BASE + self.config.entries()
+    }
+
+    /// Gets the tag that an entry for `addr` in the current address space has.
     #[inline(always)]
-    pub fn index(addr: u64) -> usize {
-        ((addr >> OFFSET_BITS) & ((1 << TLB_INDEX_BITS) - 1)).try_into().unwrap()
+    fn tag(&self, addr: u64) -> u64 {
+        TLBEntry::tag(addr) | self.asid
+    }
+
+    /// Gets the geometry of the cache.
+    pub fn config(&self) -> TlbConfig {
+        self.config
     }
 
+    /// Returns a pointer to the first entry of the cache (see [TranslationCache] for the layout).
+    pub fn as_ptr(&self) -> *const TLBEntry {
+        self.entries.as_ptr()
+    }
+
+    /// Returns a mutable pointer to the first entry of the cache (see [TranslationCache] for the
+    /// layout).
+    pub fn as_mut_ptr(&mut self) -> *mut TLBEntry {
+        self.entries.as_mut_ptr()
+    }
+
+    /// Extracts the index of the set in the translation cache to lookup the address at in the
+    /// current address space.
+    #[inline(always)]
+    pub fn index(&self, addr: u64) -> usize {
+        self.config.index(addr, self.asid)
+    }
+
+    /// Removes all entries from every address space.
     pub fn clear(&mut self) {
         tracing::trace!("Clearing TLB");
-        self.read.fill(TLBEntry::default());
-        self.write.fill(TLBEntry::default());
+        self.entries[Self::READ_BASE..].fill(TLBEntry::default());
+    }
+
+    /// Touches every entry of the cache so that all of its storage is resident in host memory.
+    ///
+    /// The entries are rewritten with their current values, so this does not change the contents
+    /// of the cache.
+    pub fn prefault(&mut self) {
+        for entry in self.entries.iter_mut() {
+            let ptr: *mut TLBEntry = entry;
+            // Safety: `ptr` is derived from a valid mutable reference. Volatile operations are used
+            // to ensure that the write is not optimized away.
+            unsafe { ptr.write_volatile(ptr.read_volatile()) };
+        }
     }
 
     pub fn clear_write(&mut self) {
-        self.write.fill(TLBEntry::default());
+        let start = self.write_base();
+        self.entries[start..].fill(TLBEntry::default());
     }
 
     #[inline]
@@ -91,12 +260,12 @@ impl TranslationCache {
 
     #[inline]
     pub fn remove_read(&mut self, addr: u64) {
-        self.read[Self::index(addr)].clear(addr);
+        self.remove_from(Self::READ_BASE, addr);
     }
 
     #[inline]
     pub fn remove_write(&mut self, addr: u64) {
-        self.write[Self::index(addr)].clear(addr);
+        self.remove_from(self.write_base(), addr);
     }
 
     pub fn remove_range(&mut self, start: u64, len: u64) {
@@ -113,7 +282,7 @@ impl TranslationCache {
         // If that is the case, perform a single optimized clear of the entire TLB (this avoids
         // performance issues where we end up iterating over the entire TLB address space
         // multiple times for extremely large address space changes).
-        if (len >> OFFSET_BITS) > TLB_ENTRIES as u64 {
+        if self.flush_on_remove || (len >> OFFSET_BITS) > self.config.sets as u64 {
             self.clear();
             return;
         }
@@ -125,22 +294,97 @@ impl TranslationCache {
 
     #[inline]
     pub fn insert_read(&mut self, addr: u64, page: PageRef) {
-        self.read[Self::index(addr)].set(addr, page);
+        self.insert_into(Self::READ_BASE, addr, page);
     }
 
     #[inline]
     pub fn insert_write(&mut self, addr: u64, page: PageRef) {
-        self.write[Self::index(addr)].set(addr, page);
+        self.insert_into(self.write_base(), addr, page);
     }
 
+    /// Translates `addr` for reading without updating the replacement order of the set.
     #[inline]
     pub fn translate_read(&self, addr: u64) -> Option<PageRef> {
-        self.read[Self::index(addr)].get_page(addr)
+        self.find(Self::READ_BASE, addr).map(|(_, page)| page)
     }
 
+    /// Translates `addr` for writing without updating the replacement order of the set.
     #[inline]
     pub fn translate_write(&self, addr: u64) -> Option<PageRef> {
-        self.write[Self::index(addr)].get_page(addr)
+        self.find(self.write_base(), addr).map(|(_, page)| page)
+    }
+
+    /// Gets the position of the entry for `way` of the set that `addr` maps to, where `base` is the
+    /// position of the first read or write entry.
+    #[inline(always)]
+    fn slot(&self, base: usize, way: usize, addr: u64) -> usize {
+        base + way * self.config.sets + self.index(addr)
+    }
+
+    /// Finds the way containing `addr` returning the way and the page it is mapped to.
+    #[inline(always)]
+    fn find(&self, base: usize, addr: u64) -> Option<(usize, PageRef)> {
+        let tag = self.tag(addr);
+        (0..self.config.ways).find_map(|way| {
+            Some((way, self.entries[self.slot(base, way, addr)].get_page(tag, addr)?))
+        })
+    }
+
+    /// Translates `addr` moving the entry to the front of the set if it was found.
+    #[inline(always)]
+    fn lookup(&mut self, base: usize, addr: u64) -> Option<PageRef> {
+        if let Some(page) = self.entries[self.slot(base, 0, addr)].get_page(self.tag(addr), addr) {
+            return Some(page);
+        }
+        if self.config.ways == 1 {
+            return None;
+        }
+        self.lookup_slow(base, addr)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn lookup_slow(&mut self, base: usize, addr: u64) -> Option<PageRef> {
+        let (way, page) = self.find(base, addr)?;
+        self.move_to_front(base, way, addr);
+        Some(page)
+    }
+
+    /// Moves the entry at `way` to way 0 of the set `addr` maps to, shifting the more recently used
+    /// entries back by one.
+    fn move_to_front(&mut self, base: usize, way: usize, addr: u64) {
+        for way in (1..=way).rev() {
+            let (dst, src) = (self.slot(base, way, addr), self.slot(base, way - 1, addr));
+            self.entries.swap(dst, src);
+        }
+    }
+
+    fn insert_into(&mut self, base: usize, addr: u64, page: PageRef) {
+        // Reuse the existing entry for this page if there is one, otherwise evict the least
+        // recently used entry.
+        let tag = self.tag(addr);
+        let way = (0..self.config.ways)
+            .find(|way| self.entries[self.slot(base, *way, addr)].tag == tag)
+            .unwrap_or(self.config.ways - 1);
+        self.move_to_front(base, way, addr);
+        let slot = self.slot(base, 0, addr);
+        self.entries[slot].set(tag, addr, page);
+    }
+
+    fn remove_from(&mut self, base: usize, addr: u64) {
+        if self.flush_on_remove {
+            self.clear();
+            return;
+        }
+        self.remove_entries(base, addr);
+    }
+
+    fn remove_entries(&mut self, base: usize, addr: u64) {
+        let tag = self.tag(addr);
+        for way in 0..self.config.ways {
+            let slot = self.slot(base, way, addr);
+            self.entries[slot].clear(tag);
+        }
     }
 
     /// Attempt to read from the virtual address `addr` with `perm` using a pre-translated address.
@@ -149,13 +393,31 @@ impl TranslationCache {
     ///
     /// The underlying memory referenced by the translated address must be valid.
     #[inline]
-    pub unsafe fn read<const N: usize>(&self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
-        match self.translate_read(addr) {
+    pub unsafe fn read<const N: usize>(&mut self, addr: u64, perm: u8) -> MemResult<[u8; N]> {
+        match self.lookup(Self::READ_BASE, addr) {
             Some(page) => page.read(addr, perm),
             None => Err(MemError::Unmapped),
         }
     }
 
+    /// Attempt to read from the virtual address `addr` with `perm` using a pre-translated address,
+    /// without requiring the bytes to be initialized (see [PageData::read_allow_uninit]).
+    ///
+    /// # Safety
+    ///
+    /// The underlying memory referenced by the translated address must be valid.
+    #[inline]
+    pub unsafe fn read_allow_uninit<const N: usize>(
+        &mut self,
+        addr: u64,
+        perm: u8,
+    ) -> MemResult<([u8; N], u64)> {
+        match self.lookup(Self::READ_BASE, addr) {
+            Some(page) => page.read_allow_uninit(addr, perm),
+            None => Err(MemError::Unmapped),
+        }
+    }
+
     /// Attempt to write `value` to the virtual address `addr` with `perm` using a pre-translated
     /// address.
     ///
@@ -169,11 +431,70 @@ impl TranslationCache {
         value: [u8; N],
         perm: u8,
     ) -> MemResult<()> {
-        match self.translate_write(addr) {
+        match self.lookup(self.write_base(), addr) {
             Some(mut page) => page.write(addr, value, perm),
             None => Err(MemError::Unmapped),
         }
     }
+
+    /// Attempt to read the bytes at `addr` into `buf` with `perm` using a pre-translated address.
+    /// `addr` does not need to be aligned, but the bytes must be within a single page.
+    ///
+    /// # Safety
+    ///
+    /// The underlying memory referenced by the translated address must be valid.
+    #[inline]
+    pub unsafe fn read_slice(&mut self, addr: u64, buf: &mut [u8], perm: u8) -> MemResult<()> {
+        match self.lookup(Self::READ_BASE, addr) {
+            Some(page) => page.read_slice(addr, buf, perm),
+            None => Err(MemError::Unmapped),
+        }
+    }
+
+    /// Attempt to write `value` to `addr` with `perm` using a pre-translated address (see
+    /// [TranslationCache::read_slice]).
+    ///
+    /// # Safety
+    ///
+    /// The underlying memory referenced by the translated address must be valid.
+    #[inline]
+    pub unsafe fn write_slice(&mut self, addr: u64, value: &[u8], perm: u8) -> MemResult<()> {
+        match self.lookup(self.write_base(), addr) {
+            Some(mut page) => page.write_slice(addr, value, perm),
+            None => Err(MemError::Unmapped),
+        }
+    }
+}
+
+/// Accumulates the ranges of memory that need to be removed from a [TranslationCache] while the
+/// memory map is being modified, so that the cache is updated with a single call to
+/// [TranslationCache::remove_range] once the modification is complete (instead of once for each
+/// region that was modified, which is slow when the memory map is fragmented).
+#[derive(Debug, Default)]
+pub struct PendingRemoval {
+    /// The (inclusive) span covering every range that has been added.
+    span: Option<(u64, u64)>,
+}
+
+impl PendingRemoval {
+    /// Adds the `len` bytes starting at `start` to the ranges to remove.
+    pub fn add(&mut self, start: u64, len: u64) {
+        let Some(end) = len.checked_sub(1).map(|last| start.saturating_add(last))
+        else {
+            return;
+        };
+        self.span = Some(match self.span {
+            Some((span_start, span_end)) => (span_start.min(start), span_end.max(end)),
+            None => (start, end),
+        });
+    }
+
+    /// Removes the span covering every range that was added from `tlb`.
+    pub fn apply(self, tlb: &mut TranslationCache) {
+        if let Some((start, end)) = self.span {
+            tlb.remove_range(start, (end - start).saturating_add(1));
+        }
+    }
 }
 
 #[repr(C)]
@@ -190,8 +511,11 @@ impl Default for TLBEntry {
 }
 
 impl TLBEntry {
+    /// The mask applied to an address to obtain its tag. The full page address is used as the tag,
+    /// so the tag does not depend on the geometry of the cache, leaving the low bits free for the
+    /// address space identifier.
     pub const fn tag_mask() -> u64 {
-        ((1_u64 << TLB_TAG_BITS) - 1) << (OFFSET_BITS + TLB_INDEX_BITS)
+        !PAGE_MASK
     }
 
     #[inline(always)]
@@ -200,25 +524,25 @@ impl TLBEntry {
     }
 
     #[inline(always)]
-    fn clear(&mut self, addr: u64) {
-        if Self::tag(addr) == self.tag {
+    fn clear(&mut self, tag: u64) {
+        if tag == self.tag {
             self.tag = u64::MAX;
             self.guest_to_host_offset = 0;
         }
     }
 
     #[inline(always)]
-    fn set(&mut self, addr: u64, page: PageRef) {
-        self.tag = Self::tag(addr);
+    fn set(&mut self, tag: u64, addr: u64, page: PageRef) {
+        self.tag = tag;
         let base = addr & !PAGE_MASK;
         self.guest_to_host_offset = (page.ptr.as_ptr() as u64).wrapping_sub(base);
     }
 
     /// Get the page data associated the address at this TLB entry, returning `None` if the entry is
-    /// invalid or matches a different page.
+    /// invalid or does not match `tag`.
     #[inline(always)]
-    fn get_page(&self, addr: u64) -> Option<PageRef> {
-        if Self::tag(addr) == self.tag {
+    fn get_page(&self, tag: u64, addr: u64) -> Option<PageRef> {
+        if tag == self.tag {
             let base = addr & !PAGE_MASK;
             let page = PageRef::new(NonNull::new(
                 base.wrapping_add(self.guest_to_host_offset) as *mut PageData
@@ -232,7 +556,7 @@ impl TLBEntry {
 #[allow(dead_code)]
 fn debug_tlb_lookup(addr: u64) {
     let tag = TLBEntry::tag(addr);
-    let index = TranslationCache::index(addr);
+    let index = TlbConfig::default().index(addr, 0);
     let offset = addr & PAGE_MASK;
     eprintln!("tag={tag:#0x}, index={index:#0x}, offset={offset:#0x}");
 }
@@ -0,0 +1,318 @@
+//! A memory API that follows the semantics of Unicorn's `uc_mem_*` and memory hook functions, to
+//! simplify porting harnesses written for Unicorn.
+//!
+//! Regions mapped using this API are always initialized (Unicorn does not track uninitialized
+//! memory), and [UnicornCompat::mem_read] and [UnicornCompat::mem_write] ignore permissions and do
+//! not call memory hooks.
+
+use crate::{Mapping, MemError, MemoryMapping, Mmu, RegionKind, perm};
+
+/// The region is not accessible.
+pub const PROT_NONE: u32 = 0;
+
+/// The region is readable.
+pub const PROT_READ: u32 = 1;
+
+/// The region is writable.
+pub const PROT_WRITE: u32 = 2;
+
+/// The region is executable.
+pub const PROT_EXEC: u32 = 4;
+
+/// The region is readable, writable and executable.
+pub const PROT_ALL: u32 = PROT_READ | PROT_WRITE | PROT_EXEC;
+
+/// The required alignment of the address and size of regions passed to [UnicornCompat::mem_map],
+/// [UnicornCompat::mem_unmap] and [UnicornCompat::mem_protect].
+pub const ALIGNMENT: u64 = 0x1000;
+
+/// The subset of Unicorn's `uc_err` codes that can be returned by the memory API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum UcError {
+    /// Out of memory, or the range is not mapped (`UC_ERR_NOMEM`).
+    NoMem = 1,
+
+    /// Reading from unmapped memory (`UC_ERR_READ_UNMAPPED`).
+    ReadUnmapped = 6,
+
+    /// Writing to unmapped memory (`UC_ERR_WRITE_UNMAPPED`).
+    WriteUnmapped = 7,
+
+    /// The region overlaps with memory that is already mapped (`UC_ERR_MAP`).
+    Map = 11,
+
+    /// An invalid argument, e.g. an unaligned address or size (`UC_ERR_ARG`).
+    Arg = 15,
+}
+
+pub type UcResult<T> = Result<T, UcError>;
+
+/// A mapped region returned by [UnicornCompat::mem_regions] (equivalent to `uc_mem_region`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemRegion {
+    /// The first address of the region.
+    pub begin: u64,
+
+    /// The last address of the region (inclusive).
+    pub end: u64,
+
+    /// The permissions of the region, a combination of the `PROT_*` flags.
+    pub perms: u32,
+}
+
+/// The kinds of memory hooks that can be registered using [UnicornCompat::hook_add].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookType {
+    /// Called before memory is read (`UC_HOOK_MEM_READ`). The value is always zero.
+    MemRead,
+
+    /// Called after memory is read (`UC_HOOK_MEM_READ_AFTER`), with the value that was read.
+    MemReadAfter,
+
+    /// Called when memory is written (`UC_HOOK_MEM_WRITE`), with the value being written.
+    MemWrite,
+}
+
+/// A handle to a hook registered using [UnicornCompat::hook_add] (equivalent to `uc_hook`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UcHook {
+    kind: HookType,
+    id: u32,
+}
+
+/// The callback for a memory hook, called with the address, size and value of the access.
+pub type MemHookCallback = Box<dyn FnMut(&mut Mmu, u64, usize, u64)>;
+
+/// Converts Unicorn protection flags to permission bits.
+pub fn prot_to_perm(prot: u32) -> u8 {
+    let mut perm = perm::NONE;
+    if prot & PROT_READ != 0 {
+        perm |= perm::READ;
+    }
+    if prot & PROT_WRITE != 0 {
+        perm |= perm::WRITE;
+    }
+    if prot & PROT_EXEC != 0 {
+        perm |= perm::EXEC;
+    }
+    perm
+}
+
+/// Converts permission bits to Unicorn protection flags.
+pub fn perm_to_prot(perm: u8) -> u32 {
+    let mut prot = PROT_NONE;
+    if perm & perm::MAP == 0 {
+        return prot;
+    }
+    if perm & perm::READ != 0 {
+        prot |= PROT_READ;
+    }
+    if perm & perm::WRITE != 0 {
+        prot |= PROT_WRITE;
+    }
+    if perm & perm::EXEC != 0 {
+        prot |= PROT_EXEC;
+    }
+    prot
+}
+
+/// Unicorn compatible memory functions.
+pub trait UnicornCompat {
+    /// Maps `size` bytes of zeroed memory at `addr` with the permissions in `perms` (equivalent to
+    /// `uc_mem_map`).
+    ///
+    /// Fails with [UcError::Arg] if `size` is zero, `addr` or `size` are not aligned to
+    /// [ALIGNMENT] or `perms` contains unknown flags, and with [UcError::Map] if the region
+    /// overlaps with memory that is already mapped.
+    fn mem_map(&mut self, addr: u64, size: u64, perms: u32) -> UcResult<()>;
+
+    /// Unmaps `size` bytes at `addr` (equivalent to `uc_mem_unmap`).
+    ///
+    /// Fails with [UcError::Arg] if the region is not aligned, and with [UcError::NoMem] if any
+    /// part of the region is not mapped.
+    fn mem_unmap(&mut self, addr: u64, size: u64) -> UcResult<()>;
+
+    /// Sets the permissions of `size` bytes at `addr` to `perms` (equivalent to `uc_mem_protect`).
+    /// The initialization state of memory is not modified.
+    ///
+    /// Fails with [UcError::Arg] if the region is not aligned or `perms` contains unknown flags,
+    /// and with [UcError::NoMem] if any part of the region is not mapped.
+    fn mem_protect(&mut self, addr: u64, size: u64, perms: u32) -> UcResult<()>;
+
+    /// Reads `buf.len()` bytes from `addr` ignoring permissions (equivalent to `uc_mem_read`).
+    fn mem_read(&mut self, addr: u64, buf: &mut [u8]) -> UcResult<()>;
+
+    /// Writes `buf` to `addr` ignoring permissions (equivalent to `uc_mem_write`).
+    fn mem_write(&mut self, addr: u64, buf: &[u8]) -> UcResult<()>;
+
+    /// Returns the mapped regions ordered by address, where adjacent regions with the same
+    /// permissions are merged (equivalent to `uc_mem_regions`).
+    fn mem_regions(&self) -> Vec<MemRegion>;
+
+    /// Registers `callback` to be called for accesses of `kind` to addresses in `begin..=end`
+    /// (equivalent to `uc_hook_add`). If `begin` is greater than `end` (e.g. Unicorn's default of
+    /// `1, 0`) the hook applies to the entire address space, except for accesses that only touch
+    /// the last byte of the address space.
+    fn hook_add(
+        &mut self,
+        kind: HookType,
+        begin: u64,
+        end: u64,
+        callback: MemHookCallback,
+    ) -> UcHook;
+
+    /// Removes a hook registered using [UnicornCompat::hook_add] (equivalent to `uc_hook_del`).
+    fn hook_del(&mut self, hook: UcHook) -> UcResult<()>;
+}
+
+/// Checks that the region is valid for `mem_map`, `mem_unmap` and `mem_protect`, returning the
+/// last address of the region.
+fn check_region(addr: u64, size: u64) -> UcResult<u64> {
+    if size == 0 || !addr.is_multiple_of(ALIGNMENT) || !size.is_multiple_of(ALIGNMENT) {
+        return Err(UcError::Arg);
+    }
+    addr.checked_add(size - 1).ok_or(UcError::Arg)
+}
+
+fn check_perms(perms: u32) -> UcResult<u8> {
+    if perms & !PROT_ALL != 0 {
+        return Err(UcError::Arg);
+    }
+    Ok(prot_to_perm(perms))
+}
+
+/// Returns whether every byte between `start` and `end` (inclusive) is mapped.
+fn is_fully_mapped(mmu: &Mmu, start: u64, end: u64) -> bool {
+    mmu.mapping
+        .overlapping_iter(start..=end)
+        .all(|(_, _, entry)| !matches!(entry, None | Some(MemoryMapping::Reserved(_))))
+}
+
+impl UnicornCompat for Mmu {
+    fn mem_map(&mut self, addr: u64, size: u64, perms: u32) -> UcResult<()> {
+        let end = check_region(addr, size)?;
+        let perm = check_perms(perms)?;
+        if self.mapping.overlapping_iter(addr..=end).any(|(_, _, entry)| entry.is_some()) {
+            return Err(UcError::Map);
+        }
+        match self.map_memory_len(addr, size, Mapping { perm: perm | perm::INIT, value: 0 }) {
+            true => Ok(()),
+            false => Err(UcError::Map),
+        }
+    }
+
+    fn mem_unmap(&mut self, addr: u64, size: u64) -> UcResult<()> {
+        let end = check_region(addr, size)?;
+        if !is_fully_mapped(self, addr, end) {
+            return Err(UcError::NoMem);
+        }
+        match self.unmap_memory_len(addr, size) {
+            true => Ok(()),
+            false => Err(UcError::NoMem),
+        }
+    }
+
+    fn mem_protect(&mut self, addr: u64, size: u64, perms: u32) -> UcResult<()> {
+        let end = check_region(addr, size)?;
+        let perm = check_perms(perms)?;
+        if !is_fully_mapped(self, addr, end) {
+            return Err(UcError::NoMem);
+        }
+        self.set_perm(addr, size, perm, perm::INIT).map_err(|e| match e {
+            MemError::Unmapped => UcError::NoMem,
+            _ => UcError::Arg,
+        })
+    }
+
+    fn mem_read(&mut self, addr: u64, buf: &mut [u8]) -> UcResult<()> {
+        self.read_bytes(addr, buf, perm::NONE).map_err(|e| match e {
+            MemError::OutOfMemory => UcError::NoMem,
+            _ => UcError::ReadUnmapped,
+        })
+    }
+
+    fn mem_write(&mut self, addr: u64, buf: &[u8]) -> UcResult<()> {
+        self.write_bytes(addr, buf, perm::NONE).map_err(|e| match e {
+            MemError::OutOfMemory => UcError::NoMem,
+            _ => UcError::WriteUnmapped,
+        })
+    }
+
+    fn mem_regions(&self) -> Vec<MemRegion> {
+        let mut regions: Vec<MemRegion> = vec![];
+        let mut push = |begin: u64, end: u64, perms: u32| match regions.last_mut() {
+            Some(last) if last.end.checked_add(1) == Some(begin) && last.perms == perms => {
+                last.end = end;
+            }
+            _ => regions.push(MemRegion { begin, end, perms }),
+        };
+
+        for region in self.regions() {
+            if region.kind != RegionKind::Reserved {
+                push(region.start, region.end, perm_to_prot(region.perm | perm::MAP));
+            }
+        }
+        regions
+    }
+
+    fn hook_add(
+        &mut self,
+        kind: HookType,
+        begin: u64,
+        end: u64,
+        mut callback: MemHookCallback,
+    ) -> UcHook {
+        // Hooks registered with the MMU use an exclusive end, so a single hook can not cover every
+        // address. Hooks for the entire address space exclude the last byte instead.
+        let (start, end) = match begin > end || (begin == 0 && end == u64::MAX) {
+            true => (0, u64::MAX),
+            false => (begin, end.wrapping_add(1)),
+        };
+        let id = match kind {
+            HookType::MemRead => self.add_read_hook(
+                start,
+                end,
+                Box::new(move |mmu: &mut Mmu, addr: u64, size: u8| {
+                    callback(mmu, addr, size as usize, 0);
+                    None
+                }),
+            ),
+            HookType::MemReadAfter => self.add_read_after_hook(
+                start,
+                end,
+                Box::new(move |mmu: &mut Mmu, addr: u64, value: &[u8]| {
+                    callback(mmu, addr, value.len(), value_of(value))
+                }),
+            ),
+            HookType::MemWrite => self.add_write_hook(
+                start,
+                end,
+                Box::new(move |mmu: &mut Mmu, addr: u64, value: &[u8]| {
+                    callback(mmu, addr, value.len(), value_of(value))
+                }),
+            ),
+        };
+        UcHook { kind, id: id.expect("failed to add hook") }
+    }
+
+    fn hook_del(&mut self, hook: UcHook) -> UcResult<()> {
+        let removed = match hook.kind {
+            HookType::MemRead => self.remove_read_hook(hook.id),
+            HookType::MemReadAfter => self.remove_read_after_hook(hook.id),
+            HookType::MemWrite => self.remove_write_hook(hook.id),
+        };
+        match removed {
+            true => Ok(()),
+            false => Err(UcError::Arg),
+        }
+    }
+}
+
+/// Gets the value of an access as an integer (values larger than 8 bytes are truncated).
+fn value_of(value: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    let len = value.len().min(8);
+    bytes[..len].copy_from_slice(&value[..len]);
+    u64::from_le_bytes(bytes)
+}
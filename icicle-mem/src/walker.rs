@@ -0,0 +1,83 @@
+//! A walker that splits a range of guest memory into chunks that can be accessed directly, for
+//! implementing bulk operations such as `memcpy` without performing an access for every byte (see
+//! [crate::Mmu::walk_range]).
+
+use crate::{Mmu, perm};
+
+/// A chunk of the range visited by a [PageWalker]. Chunks never cross a page boundary.
+#[derive(Debug)]
+pub enum PageChunk<'a> {
+    /// Bytes that can be read directly. The permissions of every byte have already been checked.
+    Read { addr: u64, data: &'a [u8] },
+
+    /// Bytes that can be written directly. The permissions of every byte have already been
+    /// checked, and the range has been treated as if it was written to by [Mmu::write_bytes]
+    /// (i.e. the page is copied if it is shared, the range is marked as initialized and the page is
+    /// logged as modified).
+    Write { addr: u64, data: &'a mut [u8] },
+
+    /// Bytes that can not be accessed directly, e.g. because they are mapped to an I/O region,
+    /// observed by hooks or watchpoints, contain translated code, or do not have the same
+    /// permissions as the rest of the page. The bytes must be accessed using the regular access
+    /// functions (see [PageWalker::mmu]), which also report any error for the range.
+    Slow { addr: u64, len: usize },
+}
+
+/// Visits the chunks of a range of guest memory, returned by [Mmu::walk_range].
+pub struct PageWalker<'a> {
+    mmu: &'a mut Mmu,
+    addr: u64,
+    remaining: usize,
+    perm: u8,
+    is_write: bool,
+}
+
+impl<'a> PageWalker<'a> {
+    pub(crate) fn new(mmu: &'a mut Mmu, addr: u64, len: usize, perm: u8) -> Self {
+        Self { mmu, addr, remaining: len, perm, is_write: perm & perm::WRITE != 0 }
+    }
+
+    /// Returns the next chunk of the range, or `None` once the entire range has been visited.
+    pub fn next_chunk(&mut self) -> Option<PageChunk<'_>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let addr = self.addr & self.mmu.address_mask();
+        let page_size = self.mmu.page_size();
+        let mut len = (self.remaining as u64).min(page_size - (addr & (page_size - 1)));
+        if let Some(end) = self.mmu.mapping_end(addr) {
+            len = len.min((end - addr).saturating_add(1));
+        }
+        let len = len as usize;
+        self.addr = addr.wrapping_add(len as u64);
+        self.remaining -= len;
+
+        let slow = PageChunk::Slow { addr, len };
+        Some(match self.is_write {
+            true => self
+                .mmu
+                .slice_mut(addr, len, self.perm)
+                .map_or(slow, |data| PageChunk::Write { addr, data }),
+            false => self
+                .mmu
+                .slice(addr, len, self.perm)
+                .map_or(slow, |data| PageChunk::Read { addr, data }),
+        })
+    }
+
+    /// Returns the MMU, for accessing [PageChunk::Slow] chunks.
+    pub fn mmu(&mut self) -> &mut Mmu {
+        self.mmu
+    }
+
+    /// Gets the address of the next chunk.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Gets the number of bytes that have not been visited yet.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+}
@@ -0,0 +1,188 @@
+//! Watchpoints that report the exact bytes touched by accesses to watched memory (see
+//! [crate::Mmu::add_watchpoint]).
+
+use crate::AccessKind;
+
+/// The kind of accesses that trigger a watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchpointKind {
+    /// Triggered by reads.
+    Read,
+
+    /// Triggered by writes.
+    Write,
+
+    /// Triggered by both reads and writes.
+    Access,
+}
+
+impl WatchpointKind {
+    fn matches(self, kind: AccessKind) -> bool {
+        match self {
+            Self::Read => kind == AccessKind::Read,
+            Self::Write => kind == AccessKind::Write,
+            Self::Access => kind != AccessKind::Execute,
+        }
+    }
+}
+
+/// A handle to a watchpoint added using [crate::Mmu::add_watchpoint].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WatchpointId(pub(crate) u32);
+
+/// An access that triggered a watchpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchpointHit {
+    /// The position of the hit in the sequence of hits reported by the MMU. Sequence numbers are
+    /// never reused, so they can be used to order hits across calls to
+    /// [crate::Mmu::take_watchpoint_hits].
+    pub seq: u64,
+
+    /// The watchpoint that was triggered.
+    pub id: WatchpointId,
+
+    /// The kind of the watchpoint that was triggered.
+    pub watch: WatchpointKind,
+
+    /// The kind of access that triggered the watchpoint, either [AccessKind::Read] or
+    /// [AccessKind::Write].
+    pub kind: AccessKind,
+
+    /// The address of the access, which may be before the watched range.
+    pub addr: u64,
+
+    /// The size of the access in bytes.
+    pub size: usize,
+
+    /// The first byte of the access that is inside of the watched range.
+    pub start: u64,
+
+    /// The last byte of the access that is inside of the watched range (inclusive).
+    pub end: u64,
+
+    /// The value of the bytes between `start` and `end` before the access. For writes to I/O
+    /// regions that do not support [crate::IoMemory::peek], the previous value is unknown and this
+    /// is empty.
+    pub old: Vec<u8>,
+
+    /// The value of the bytes between `start` and `end` after the access.
+    pub new: Vec<u8>,
+}
+
+struct Watchpoint {
+    start: u64,
+    end: u64,
+    kind: WatchpointKind,
+}
+
+/// The watchpoints added to an [crate::Mmu], and the hits that have not been taken yet.
+#[derive(Default)]
+pub(crate) struct Watchpoints {
+    entries: Vec<Option<Watchpoint>>,
+    hits: Vec<WatchpointHit>,
+    next_seq: u64,
+
+    /// Set while an access to a watched address is being performed, so that the smaller accesses
+    /// it is split into are not reported separately.
+    pub(crate) suspended: bool,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, start: u64, end: u64, kind: WatchpointKind) -> WatchpointId {
+        let entry = Some(Watchpoint { start, end, kind });
+        let id = match self.entries.iter().position(|x| x.is_none()) {
+            Some(id) => {
+                self.entries[id] = entry;
+                id
+            }
+            None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            }
+        };
+        WatchpointId(id.try_into().expect("too many watchpoints"))
+    }
+
+    pub fn remove(&mut self, id: WatchpointId) -> bool {
+        self.entries.get_mut(id.0 as usize).and_then(|x| x.take()).is_some()
+    }
+
+    /// Returns the watchpoint that covers exactly `start..=end` for accesses of `kind`.
+    pub fn find(&self, start: u64, end: u64, kind: WatchpointKind) -> Option<WatchpointId> {
+        let id = self.entries.iter().position(
+            |entry| matches!(entry, Some(x) if x.start == start && x.end == end && x.kind == kind),
+        )?;
+        Some(WatchpointId(id as u32))
+    }
+
+    pub fn take_hits(&mut self) -> Vec<WatchpointHit> {
+        std::mem::take(&mut self.hits)
+    }
+
+    fn active(&self) -> impl Iterator<Item = (WatchpointId, &Watchpoint)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(id, entry)| Some((WatchpointId(id as u32), entry.as_ref()?)))
+    }
+
+    /// Returns whether any watchpoint overlaps with the page containing `addr`, in which case
+    /// accesses to the page must not be cached in the TLB.
+    pub fn overlaps_page(&self, addr: u64, page_size: u64) -> bool {
+        if self.entries.is_empty() {
+            return false;
+        }
+        let page_start = addr & !(page_size - 1);
+        let page_end = page_start | (page_size - 1);
+        self.active().any(|(_, x)| x.start <= page_end && page_start <= x.end)
+    }
+
+    /// Returns whether an access of `kind` to `size` bytes at `addr` should be checked for
+    /// watchpoint hits.
+    #[inline]
+    pub fn should_check(&self, addr: u64, size: usize, kind: AccessKind) -> bool {
+        if self.entries.is_empty() || self.suspended {
+            return false;
+        }
+        let last = access_end(addr, size);
+        self.active().any(|(_, x)| x.kind.matches(kind) && x.start <= last && addr <= x.end)
+    }
+
+    /// Records a hit for every watchpoint that overlaps with the access of `kind` at `addr`, where
+    /// `old` is the value of the accessed bytes before the access (if known) and `new` is the
+    /// value after the access.
+    pub fn record(&mut self, kind: AccessKind, addr: u64, old: Option<&[u8]>, new: &[u8]) {
+        let last = access_end(addr, new.len());
+        let mut hits = vec![];
+        for (id, entry) in self.active() {
+            if !entry.kind.matches(kind) || last < entry.start || entry.end < addr {
+                continue;
+            }
+            let (start, end) = (addr.max(entry.start), last.min(entry.end));
+            let bytes = (start - addr) as usize..=(end - addr) as usize;
+            hits.push(WatchpointHit {
+                seq: 0,
+                id,
+                watch: entry.kind,
+                kind,
+                addr,
+                size: new.len(),
+                start,
+                end,
+                old: old.map_or(vec![], |old| old[bytes.clone()].to_vec()),
+                new: new[bytes].to_vec(),
+            });
+        }
+        for mut hit in hits {
+            hit.seq = self.next_seq;
+            self.next_seq += 1;
+            self.hits.push(hit);
+        }
+    }
+}
+
+/// Returns the last address of an access, treating accesses that wrap around the end of the
+/// address space as ending at the last address.
+fn access_end(addr: u64, size: usize) -> u64 {
+    addr.saturating_add(size as u64 - 1)
+}
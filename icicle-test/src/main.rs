@@ -1,7 +1,7 @@
 use std::path::Path;
 
 use anyhow::Context;
-use icicle_vm::cpu::mem::perm;
+use icicle_vm::cpu::mem::{perm, SelfModifyingCode};
 
 use crate::tester::Tester;
 
@@ -84,7 +84,7 @@ fn run() -> anyhow::Result<()> {
         if config.dump_il {
             vm.jit.il_dump = Some(String::new());
         }
-        vm.cpu.mem.detect_self_modifying_code = false;
+        vm.cpu.mem.self_modifying_code = SelfModifyingCode::Ignore;
 
         let result = match std::panic::catch_unwind::<_, anyhow::Result<_>>(
             std::panic::AssertUnwindSafe(|| run_test_and_print(&mut vm, &config)),
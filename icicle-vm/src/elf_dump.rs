@@ -16,7 +16,9 @@ pub fn dump_elf(vm: &mut Vm, path: impl AsRef<std::path::Path>) -> anyhow::Resul
     let entry = vm.env.entry_point();
 
     let mut regions: Vec<MemoryRegion> = vec![];
-    for (start, len, entry) in vm.cpu.mem.get_mapping().iter() {
+    // File-backed regions are read through the MMU, so iterate over a copy of the mapping.
+    let mapping = vm.cpu.mem.get_mapping().clone();
+    for (start, len, entry) in mapping.iter() {
         let len = len as usize;
         match entry {
             MemoryMapping::Physical(entry) => {
@@ -77,7 +79,20 @@ pub fn dump_elf(vm: &mut Vm, path: impl AsRef<std::path::Path>) -> anyhow::Resul
                     file_offset: 0,
                 });
             }
-            MemoryMapping::Io(_) => continue,
+            MemoryMapping::File(entry) => {
+                let perm = entry.perm;
+                let mut data = vec![0; len];
+                vm.cpu.mem.peek_bytes(start, &mut data)?;
+                regions.push(MemoryRegion {
+                    addr: start,
+                    len,
+                    writeable: perm & perm::WRITE != 0,
+                    executable: perm & perm::EXEC != 0,
+                    data: Some(data),
+                    file_offset: 0,
+                });
+            }
+            MemoryMapping::Io(_) | MemoryMapping::Reserved(_) => continue,
         };
     }
 
@@ -860,7 +860,12 @@ impl Vm {
         if self.cpu.icount() > target {
             // Find and restore a snapshot that was created before the target offset
             match self.snapshots.range(..target).rev().next() {
-                Some((_, snapshot)) => self.restore(&snapshot.clone()),
+                Some((_, snapshot)) => {
+                    if let Err(e) = self.restore(&snapshot.clone()) {
+                        tracing::error!("failed to restore snapshot: {e}");
+                        return None;
+                    }
+                }
                 None => return None,
             }
             tracing::debug!("Restored snapshot icount={} and stepping forward", self.cpu.icount());
@@ -975,9 +980,12 @@ impl Vm {
         }
     }
 
-    pub fn restore(&mut self, snapshot: &Snapshot) {
+    /// Restores the state of the VM from `snapshot`, failing without modifying the VM if the
+    /// snapshot of memory is not compatible with the I/O handlers registered with the VM (see
+    /// [mem::Mmu::restore]).
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), mem::RestoreError> {
+        self.cpu.mem.restore(snapshot.mem.clone())?;
         self.cpu.restore(&snapshot.cpu);
-        self.cpu.mem.restore(snapshot.mem.clone());
         self.env.restore(&snapshot.env);
         self.update_context();
 
@@ -987,6 +995,7 @@ impl Vm {
             self.cpu.block_id,
             self.cpu.block_offset
         );
+        Ok(())
     }
 }
 
@@ -7,7 +7,7 @@ use crate::{
     cpu::{
         debug_info::DebugInfo,
         elf::ElfLoader,
-        mem::{perm, IoMemory, IoMemoryAny, Mapping, MemError, MemResult},
+        mem::{perm, IoAccess, IoMemory, IoMemoryAny, Mapping, MemError, MemResult},
         utils::XorShiftRng,
         Cpu, Environment, Exception, ExceptionCode, ValueSource,
     },
@@ -477,6 +477,14 @@ impl IoMemory for UnknownPeripheralHandler {
         self.inner.borrow_mut().write(addr, value)
     }
 
+    fn read_access(&mut self, access: &IoAccess, buf: &mut [u8]) -> MemResult<()> {
+        self.inner.borrow_mut().read_access(access, buf)
+    }
+
+    fn write_access(&mut self, access: &IoAccess, value: &[u8]) -> MemResult<()> {
+        self.inner.borrow_mut().write_access(access, value)
+    }
+
     fn snapshot(&mut self) -> Box<dyn std::any::Any> {
         self.inner.borrow_mut().snapshot()
     }
@@ -4,7 +4,7 @@ use std::{
 };
 
 use crate::cpu::{
-    mem::{IoMemory, MemError, MemResult},
+    mem::{AccessKind, IoAccess, IoMemory, MemError, MemResult},
     utils::get_u64,
 };
 
@@ -95,6 +95,17 @@ impl<T: IoMemory> Peripherals<T> {
 
 impl<T: IoMemory + 'static> IoMemory for Peripherals<T> {
     fn read(&mut self, addr: u64, buf: &mut [u8]) -> MemResult<()> {
+        let access = IoAccess { addr, size: buf.len(), kind: AccessKind::Read, context: 0 };
+        self.read_access(&access, buf)
+    }
+
+    fn write(&mut self, addr: u64, value: &[u8]) -> MemResult<()> {
+        let access = IoAccess { addr, size: value.len(), kind: AccessKind::Write, context: 0 };
+        self.write_access(&access, value)
+    }
+
+    fn read_access(&mut self, access: &IoAccess, buf: &mut [u8]) -> MemResult<()> {
+        let addr = access.addr;
         if buf.len() > 8 {
             return Err(MemError::Unaligned);
         }
@@ -108,7 +119,7 @@ impl<T: IoMemory + 'static> IoMemory for Peripherals<T> {
             return Ok(());
         }
 
-        self.unknown_handler.read(addr, buf)?;
+        self.unknown_handler.read_access(access, buf)?;
 
         // If the read overlaps with register that is used for enabling interrupts -- make sure we
         // read the correct values of those bits.
@@ -128,7 +139,8 @@ impl<T: IoMemory + 'static> IoMemory for Peripherals<T> {
         Ok(())
     }
 
-    fn write(&mut self, addr: u64, value: &[u8]) -> MemResult<()> {
+    fn write_access(&mut self, access: &IoAccess, value: &[u8]) -> MemResult<()> {
+        let addr = access.addr;
         if value.len() > 8 {
             return Err(MemError::Unaligned);
         }
@@ -156,7 +168,7 @@ impl<T: IoMemory + 'static> IoMemory for Peripherals<T> {
             }
         }
         else {
-            self.unknown_handler.write(addr, value)?;
+            self.unknown_handler.write_access(access, value)?;
         }
 
         self.debug_write(addr, value);